@@ -0,0 +1,381 @@
+//! What each command does, and what it reports back
+
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use dmp_core::{
+    config::Config,
+    music_storage::{
+        cancel::CancelToken,
+        duplicates::DuplicateGroup,
+        gain_analysis::AnalyzeScope,
+        integrity::{self, VerifyReport},
+        library::Tag,
+        organize::sanitize_component,
+        scan_exclusions::ExclusionRules,
+        scan_report::ScanReport,
+        search::SearchCandidate,
+    },
+};
+use serde::Serialize;
+
+use crate::{library::Library, Report};
+
+/// Writes a length of time like `3h 25m`, or `2m 5s` when it's short
+fn write_duration(f: &mut Formatter<'_>, duration: Duration) -> fmt::Result {
+    let secs = duration.as_secs();
+    match secs >= 3600 {
+        true => write!(f, "{}h {}m", secs / 3600, secs % 3600 / 60),
+        false => write!(f, "{}m {}s", secs / 60, secs % 60),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Scanned {
+    pub folder: PathBuf,
+    #[serde(flatten)]
+    pub report: ScanReport,
+    /// How many songs are in the library after the scan
+    pub songs: usize,
+}
+
+impl Display for Scanned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Added {} songs from {}, the library has {} now",
+            self.report.added,
+            self.folder.display(),
+            self.songs
+        )?;
+        if self.report.error_count() > 0 {
+            write!(
+                f,
+                "\n\n{} files couldn't be added:",
+                self.report.error_count()
+            )?;
+        }
+        for error in &self.report.errors {
+            write!(f, "\n  {}: {}", error.path.display(), error.message)?;
+        }
+        for (kind, count) in &self.report.omitted {
+            write!(f, "\n  and {count} more {kind:?} errors")?;
+        }
+        Ok(())
+    }
+}
+
+impl Report for Scanned {}
+
+/// Adds the songs in `folder` to the library, leaving out the files the
+/// library's exclusions rule out
+pub fn scan(config: &Config, folder: &Path) -> Result<Scanned, Box<dyn Error>> {
+    if !folder.is_dir() {
+        return Err(format!("{} isn't a folder", folder.display()).into());
+    }
+    let mut library = Library::open(config)?;
+    let exclusions = ExclusionRules::new(&library.settings.exclusions)?;
+    let report = library
+        .library
+        .scan_folder(folder, &exclusions, &CancelToken::new())?;
+    library.save()?;
+
+    Ok(Scanned {
+        folder: folder.to_path_buf(),
+        report,
+        songs: library.library.len_tracks(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub songs: usize,
+    pub albums: usize,
+    pub artists: usize,
+    pub playlists: usize,
+    /// How long all the songs are together, in seconds
+    pub duration: u64,
+    pub plays: i64,
+    /// How long the library was listened to, in seconds
+    pub play_time: u64,
+    /// The songs which were never played
+    pub unplayed: usize,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Songs:       {}", self.songs)?;
+        writeln!(f, "Albums:      {}", self.albums)?;
+        writeln!(f, "Artists:     {}", self.artists)?;
+        writeln!(f, "Playlists:   {}", self.playlists)?;
+        write!(f, "Length:      ")?;
+        write_duration(f, Duration::from_secs(self.duration))?;
+        writeln!(f, "\nPlays:       {}", self.plays)?;
+        write!(f, "Played for:  ")?;
+        write_duration(f, Duration::from_secs(self.play_time))?;
+        write!(f, "\nUnplayed:    {}", self.unplayed)
+    }
+}
+
+impl Report for Stats {}
+
+pub fn stats(config: &Config) -> Result<Stats, Box<dyn Error>> {
+    let library = Library::read(config)?.library;
+    let songs = &library.library;
+    let distinct = |tag: Tag| {
+        let values: BTreeSet<&String> =
+            songs.iter().filter_map(|song| song.get_tag(&tag)).collect();
+        values.len()
+    };
+
+    Ok(Stats {
+        songs: songs.len(),
+        // Counted by their titles, the way the player groups albums
+        albums: distinct(Tag::Album),
+        artists: distinct(Tag::Artist),
+        playlists: library.playlists.lists_recursive().len(),
+        duration: songs
+            .iter()
+            .map(|song| song.duration)
+            .sum::<Duration>()
+            .as_secs(),
+        plays: songs.iter().map(|song| song.plays as i64).sum(),
+        play_time: songs
+            .iter()
+            .map(|song| song.play_time)
+            .sum::<Duration>()
+            .as_secs(),
+        unplayed: songs.iter().filter(|song| song.plays == 0).count(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Found {
+    pub query: String,
+    /// The songs which matched, best first
+    pub songs: Vec<SearchCandidate>,
+}
+
+impl Display for Found {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.songs.is_empty() {
+            return write!(f, "Nothing matched '{}'", self.query);
+        }
+        let unknown = String::from("?");
+        for (i, song) in self.songs.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{} - {}",
+                song.artist.as_ref().unwrap_or(&unknown),
+                song.title.as_ref().unwrap_or(&unknown)
+            )?;
+            if let Some(album) = &song.album {
+                write!(f, " ({album})")?;
+            }
+            write!(f, "  {}", song.uuid)?;
+        }
+        Ok(())
+    }
+}
+
+impl Report for Found {}
+
+/// Searches titles, artists and albums the way the player's search does
+pub fn search(config: &Config, query: &str) -> Result<Found, Box<dyn Error>> {
+    let library = Library::read(config)?.library;
+    Ok(Found {
+        query: query.to_string(),
+        songs: library.search_candidates(query),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedPlaylist {
+    pub title: String,
+    pub path: PathBuf,
+    pub tracks: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Exported {
+    pub playlists: Vec<ExportedPlaylist>,
+}
+
+impl Display for Exported {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Exported {} playlists", self.playlists.len())?;
+        for playlist in &self.playlists {
+            write!(
+                f,
+                "\n  {} ({} songs) to {}",
+                playlist.title,
+                playlist.tracks,
+                playlist.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Report for Exported {}
+
+/// Writes each playlist to an m3u file named after it in `dir`. Playlists
+/// with the same name get a number after it.
+pub fn export_playlists(config: &Config, dir: &Path) -> Result<Exported, Box<dyn Error>> {
+    let library = Library::read(config)?.library;
+    let playlists: Vec<_> = library
+        .playlists
+        .lists_recursive()
+        .into_iter()
+        .cloned()
+        .collect();
+    fs::create_dir_all(dir)?;
+
+    let library = Arc::new(RwLock::new(library));
+    let mut names = BTreeSet::new();
+    let mut exported = Vec::new();
+    for mut playlist in playlists {
+        let name = sanitize_component(playlist.title());
+        let mut file = format!("{name}.m3u");
+        for n in 2.. {
+            if names.insert(file.to_lowercase()) {
+                break;
+            }
+            file = format!("{name} ({n}).m3u");
+        }
+
+        let path = dir.join(file);
+        playlist.to_m3u(Arc::clone(&library), &path)?;
+        exported.push(ExportedPlaylist {
+            title: playlist.title().clone(),
+            path,
+            tracks: playlist.tracks().len(),
+        });
+    }
+
+    Ok(Exported {
+        playlists: exported,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Verified {
+    #[serde(flatten)]
+    pub report: VerifyReport,
+}
+
+impl Display for Verified {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let report = &self.report;
+        write!(
+            f,
+            "Checked {} files: {} unchanged, {} checked for the first time, {} retagged",
+            report.unchanged
+                + report.hashed
+                + report.retagged.len()
+                + report.corrupt.len()
+                + report.failed.len(),
+            report.unchanged,
+            report.hashed,
+            report.retagged.len()
+        )?;
+        if report.skipped > 0 {
+            write!(f, "\n{} songs have no local file", report.skipped)?;
+        }
+        for (title, problems) in [("Damaged", &report.corrupt), ("Unreadable", &report.failed)] {
+            if !problems.is_empty() {
+                write!(f, "\n\n{title}:")?;
+            }
+            for problem in problems {
+                write!(f, "\n  {}: {}", problem.path.display(), problem.message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Report for Verified {
+    fn success(&self) -> bool {
+        self.report.corrupt.is_empty() && self.report.failed.is_empty()
+    }
+}
+
+/// Checks the library's files for damage, storing the hashes of new and
+/// retagged files for the next check
+pub fn verify(config: &Config, deep: bool) -> Result<Verified, Box<dyn Error>> {
+    let mut library = Library::open(config)?;
+    let (targets, skipped) = library.library.verify_targets(&AnalyzeScope::WholeLibrary);
+    let mut report = integrity::verify(&targets, deep, |_| true)?;
+    report.skipped = skipped;
+
+    if !report.hashes.is_empty() {
+        library.library.store_hashes(&report.hashes);
+        library.save()?;
+    }
+    Ok(Verified { report })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Deduped {
+    pub dry_run: bool,
+    pub groups: Vec<DuplicateGroup>,
+    /// How many songs were merged into another, or would be
+    pub merged: usize,
+}
+
+impl Display for Deduped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.groups.is_empty() {
+            return write!(f, "No songs were added more than once");
+        }
+        for group in &self.groups {
+            writeln!(
+                f,
+                "{}: {} copies merged into {}",
+                group.location,
+                group.duplicates.len(),
+                group.kept
+            )?;
+        }
+        match self.dry_run {
+            true => write!(f, "\n{} songs would be merged", self.merged),
+            false => write!(f, "\nMerged {} songs", self.merged),
+        }
+    }
+}
+
+impl Report for Deduped {}
+
+/// Merges songs which were added more than once for the same file. With
+/// `dry_run` they're only found, which works while the player is open.
+pub fn dedupe(config: &Config, dry_run: bool) -> Result<Deduped, Box<dyn Error>> {
+    let mut library = match dry_run {
+        true => Library::read(config)?,
+        false => Library::open(config)?,
+    };
+    let groups = library.library.find_duplicates();
+    let merged = match dry_run || groups.is_empty() {
+        true => groups.iter().map(|group| group.duplicates.len()).sum(),
+        false => {
+            let merged = library.library.merge_duplicates(&groups);
+            library.save()?;
+            merged
+        }
+    };
+
+    Ok(Deduped {
+        dry_run,
+        groups,
+        merged,
+    })
+}
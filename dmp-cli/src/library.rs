@@ -0,0 +1,86 @@
+//! Finding the config the player uses, and opening the library it points at
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use dmp_core::{
+    config::{
+        data_dir::{DataDir, PORTABLE_ENV},
+        Config, ConfigLibrary,
+    },
+    music_storage::{library::MusicLibrary, library_guard::LibraryLock},
+};
+
+/// Reads the config at `path`, or the player's own config without one
+pub fn read_config(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+    let (path, config) = match path {
+        Some(path) => (path.to_path_buf(), Config::read_file(path.to_path_buf())),
+        None => {
+            let exe = std::env::current_exe().ok();
+            let requested = DataDir::requested(std::env::args(), std::env::var_os(PORTABLE_ENV));
+            let installed = directories::ProjectDirs::from("", "Dangoware", "dmp")
+                .map(|dir| dir.config_dir().to_path_buf());
+            let dir = DataDir::resolve(exe.as_deref(), requested, installed)
+                .ok_or("There is no folder for the config on this system")?;
+            (dir.path().join("config"), dir.read_config())
+        }
+    };
+    config.map_err(|e| format!("Couldn't read the config at {}: {e}", path.display()).into())
+}
+
+fn default_library(config: &Config) -> Result<ConfigLibrary, Box<dyn Error>> {
+    match config.libraries.get_default() {
+        Ok(library) => Ok(library.clone()),
+        Err(_) => Err("The config has no library yet, open the player to set one up".into()),
+    }
+}
+
+/// The default library of the config, as read from its file
+pub struct Library {
+    pub settings: ConfigLibrary,
+    pub library: MusicLibrary,
+    /// Held while the library is open to be changed
+    lock: Option<LibraryLock>,
+}
+
+impl Library {
+    /// Reads the library to look at it, which works while the player has it
+    /// open
+    pub fn read(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let settings = default_library(config)?;
+        // Reading a library which isn't there would make an empty one
+        if !settings.path.is_file() {
+            return Err(format!("There is no library at {}", settings.path.display()).into());
+        }
+
+        let library = MusicLibrary::init(settings.path.clone(), settings.uuid)?;
+        Ok(Library {
+            settings,
+            library,
+            lock: None,
+        })
+    }
+
+    /// Locks the library, then reads it to change it. Nothing can be saved
+    /// over it between reading and saving, so no changes are lost.
+    pub fn open(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let lock = LibraryLock::acquire(&default_library(config)?.path)?;
+        let mut library = Self::read(config)?;
+        library.lock = Some(lock);
+        Ok(library)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.settings.path
+    }
+
+    /// Saves the changes, which only a library from [`Library::open`] can
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if self.lock.is_none() {
+            return Err("The library was only read, so it can't be saved".into());
+        }
+        self.library.save(self.path().clone())
+    }
+}
@@ -0,0 +1,160 @@
+//! `dmp`, for looking after the library from a terminal or a script, like on
+//! a server without the player's window. Everything works on the library
+//! file of the default library in the config, which is only written while
+//! the player doesn't have it open.
+
+mod commands;
+mod library;
+
+use std::{error::Error, fmt::Display, path::PathBuf, process::ExitCode};
+
+use dmp_core::config::data_dir::PORTABLE_ARG;
+use serde::Serialize;
+
+const USAGE: &str = "\
+Usage: dmp [--config <file>] [--json] <command>
+
+Commands:
+  scan <folder>           Add the songs in a folder to the library
+  stats                   Show the size of the library and how much it was played
+  search <query>          Find songs by their title, artist or album
+  export-playlists <dir>  Write every playlist to an m3u file in a folder
+  verify [--deep]         Check the library's files for damage
+  dedupe [--dry-run]      Merge songs which were added more than once for a file
+
+Options:
+  --config <file>  The config file to use instead of the player's
+  --json           Print the result as JSON
+  --portable       Use the config of a portable player next to this program";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Scan(PathBuf),
+    Stats,
+    Search(String),
+    ExportPlaylists(PathBuf),
+    Verify { deep: bool },
+    Dedupe { dry_run: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Args {
+    config: Option<PathBuf>,
+    json: bool,
+    command: Command,
+}
+
+impl Args {
+    /// Reads the arguments after the program name. Options can go before or
+    /// after the command.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = None;
+        let mut json = false;
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => match args.next() {
+                    Some(path) => config = Some(PathBuf::from(path)),
+                    None => return Err("--config needs the path to a config file".to_string()),
+                },
+                "--json" => json = true,
+                // Picked up when the data folder is found
+                PORTABLE_ARG => (),
+                flag if flag.starts_with("--") => flags.push(arg),
+                _ => positional.push(arg),
+            }
+        }
+
+        let Some((name, rest)) = positional.split_first() else {
+            return Err("no command was given".to_string());
+        };
+        let mut flag = |name: &str| {
+            let found = flags.iter().position(|flag| flag == name);
+            found.map(|i| flags.remove(i)).is_some()
+        };
+        let command = match (name.as_str(), rest) {
+            ("scan", [folder]) => Command::Scan(PathBuf::from(folder)),
+            ("stats", []) => Command::Stats,
+            ("search", [_, ..]) => Command::Search(rest.join(" ")),
+            ("export-playlists", [dir]) => Command::ExportPlaylists(PathBuf::from(dir)),
+            ("verify", []) => Command::Verify {
+                deep: flag("--deep"),
+            },
+            ("dedupe", []) => Command::Dedupe {
+                dry_run: flag("--dry-run"),
+            },
+            ("scan", _) => return Err("scan takes the folder to scan".to_string()),
+            ("search", _) => return Err("search takes what to search for".to_string()),
+            ("export-playlists", _) => {
+                return Err("export-playlists takes the folder to write to".to_string())
+            }
+            ("stats" | "verify" | "dedupe", _) => return Err(format!("{name} takes no arguments")),
+            _ => return Err(format!("'{name}' isn't a command")),
+        };
+        if let Some(unknown) = flags.first() {
+            return Err(format!("'{unknown}' isn't an option of {name}"));
+        }
+
+        Ok(Args {
+            config,
+            json,
+            command,
+        })
+    }
+}
+
+/// The result of a command, printed for people or as JSON
+pub trait Report: Serialize + Display {
+    /// Whether the command found everything in order. When it didn't, like
+    /// when `verify` finds damaged files, `dmp` exits with status 3.
+    fn success(&self) -> bool {
+        true
+    }
+}
+
+fn print(report: &impl Report, json: bool) -> Result<bool, Box<dyn Error>> {
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(report)?),
+        false => println!("{report}"),
+    }
+    Ok(report.success())
+}
+
+fn run(args: &Args) -> Result<bool, Box<dyn Error>> {
+    let config = library::read_config(args.config.as_deref())?;
+    let json = args.json;
+    match &args.command {
+        Command::Scan(folder) => print(&commands::scan(&config, folder)?, json),
+        Command::Stats => print(&commands::stats(&config)?, json),
+        Command::Search(query) => print(&commands::search(&config, query)?, json),
+        Command::ExportPlaylists(dir) => print(&commands::export_playlists(&config, dir)?, json),
+        Command::Verify { deep } => print(&commands::verify(&config, *deep)?, json),
+        Command::Dedupe { dry_run } => print(&commands::dedupe(&config, *dry_run)?, json),
+    }
+}
+
+fn main() -> ExitCode {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(3),
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
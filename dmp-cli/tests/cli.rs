@@ -0,0 +1,360 @@
+//! Runs `dmp` against libraries made in a temporary folder
+
+use std::{
+    collections::BTreeMap,
+    f32::consts::TAU,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    time::Duration,
+};
+
+use dmp_core::{
+    config::{Config, ConfigLibrary},
+    music_storage::{
+        library::{MusicLibrary, Song, Tag, URI},
+        library_guard::LibraryLock,
+        playlist::{Playlist, PlaylistFolderItem},
+    },
+};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A config with a library, and a folder of songs for it
+struct TestLibrary {
+    dir: PathBuf,
+    config: PathBuf,
+    library: PathBuf,
+}
+
+impl TestLibrary {
+    /// An empty library
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("dmp-cli-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("music")).unwrap();
+        let library = dir.join("library.dlib");
+        let uuid = Uuid::new_v4();
+        MusicLibrary::init(library.clone(), uuid).unwrap();
+
+        let mut config = Config {
+            path: dir.join("config"),
+            ..Default::default()
+        };
+        config.push_library(ConfigLibrary::new(
+            library.clone(),
+            String::from("Library"),
+            Some(vec![dir.join("music")]),
+            Some(uuid),
+        ));
+        config.write_file().unwrap();
+
+        TestLibrary {
+            config: config.path,
+            library,
+            dir,
+        }
+    }
+
+    fn fill(&self, songs: Vec<Song>, playlists: Vec<Playlist>) {
+        let mut library = self.read();
+        // Pushed directly, so the same file can be in it twice
+        library.library = songs;
+        for playlist in playlists {
+            library.push_playlist(PlaylistFolderItem::List(playlist));
+        }
+        library.save(self.library.clone()).unwrap();
+    }
+
+    fn dmp(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_dmp"))
+            .arg("--config")
+            .arg(&self.config)
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs a command with `--json`, which has to succeed
+    fn json(&self, args: &[&str]) -> Value {
+        let output = self.dmp(&[&["--json"], args].concat());
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+
+    fn read(&self) -> MusicLibrary {
+        MusicLibrary::from_path(&self.library).unwrap()
+    }
+
+    /// Writes a file to the music folder, which is a second of a tone
+    /// unless it's given as `contents`
+    fn file(&self, name: &str, contents: Option<&[u8]>) -> PathBuf {
+        let path = self.dir.join("music").join(name);
+        fs::write(&path, contents.map_or_else(wav, <[u8]>::to_vec)).unwrap();
+        path
+    }
+}
+
+/// A second of a quiet tone as a WAV file
+fn wav() -> Vec<u8> {
+    let rate = 44_100u32;
+    let data_len = rate * 2;
+    let mut wav = Vec::new();
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(rate.to_le_bytes());
+    wav.extend((rate * 2).to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    for i in 0..rate {
+        let sample = 0.1 * (TAU * 440.0 * i as f32 / rate as f32).sin();
+        wav.extend(((sample * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    wav
+}
+
+fn song(path: &Path, title: &str, artist: &str, album: &str, plays: i32) -> Song {
+    Song {
+        uuid: Uuid::new_v4(),
+        location: vec![URI::Local(path.to_path_buf())],
+        plays,
+        duration: Duration::from_secs(200),
+        play_time: Duration::from_secs(plays as u64 * 200),
+        tags: BTreeMap::from([
+            (Tag::Title, title.to_string()),
+            (Tag::Artist, artist.to_string()),
+            (Tag::Album, album.to_string()),
+        ]),
+        ..Default::default()
+    }
+}
+
+/// Three songs on two albums by two artists, in files which are only
+/// there to be found
+fn songs(test: &TestLibrary) -> Vec<Song> {
+    let file = |name: &str| test.file(name, Some(b"fLaC"));
+    vec![
+        song(&file("a.flac"), "Blue Sky", "Haru", "Seasons", 3),
+        song(&file("b.flac"), "Red Leaves", "Haru", "Seasons", 0),
+        song(&file("c.flac"), "Night Train", "Kumo", "Lines", 2),
+    ]
+}
+
+#[test]
+fn stats() {
+    let test = TestLibrary::new();
+    test.fill(songs(&test), vec![Playlist::new()]);
+    let stats = test.json(&["stats"]);
+    assert_eq!(stats["songs"], 3);
+    assert_eq!(stats["albums"], 2);
+    assert_eq!(stats["artists"], 2);
+    assert_eq!(stats["playlists"], 1);
+    assert_eq!(stats["duration"], 600);
+    assert_eq!(stats["plays"], 5);
+    assert_eq!(stats["play_time"], 1000);
+    assert_eq!(stats["unplayed"], 1);
+
+    let output = test.dmp(&["stats"]);
+    assert!(output.status.success());
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("Songs:       3"));
+    assert!(text.contains("Length:      10m 0s"));
+}
+
+#[test]
+fn search() {
+    let test = TestLibrary::new();
+    let songs = songs(&test);
+    let night_train = songs[2].uuid;
+    test.fill(songs, Vec::new());
+
+    let found = test.json(&["search", "night", "train"]);
+    assert_eq!(found["query"], "night train");
+    assert_eq!(found["songs"][0]["uuid"], night_train.to_string());
+    assert_eq!(found["songs"][0]["score"], 1.0);
+
+    // Every song by the artist
+    let found = test.json(&["search", "haru"]);
+    assert_eq!(found["songs"].as_array().unwrap().len(), 2);
+
+    let output = test.dmp(&["search", "nothing like this"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("Nothing matched"));
+}
+
+#[test]
+fn scan() {
+    let test = TestLibrary::new();
+    test.file("tone.wav", None);
+    test.file("notes.txt", Some(b"not a song"));
+    let music = test.dir.join("music");
+
+    let scanned = test.json(&["scan", music.to_str().unwrap()]);
+    assert_eq!(scanned["added"], 1);
+    assert_eq!(scanned["songs"], 1);
+    assert_eq!(test.read().library.len(), 1);
+
+    // Files already in the library aren't added again
+    let scanned = test.json(&["scan", music.to_str().unwrap()]);
+    assert_eq!(scanned["added"], 0);
+    assert_eq!(test.read().library.len(), 1);
+
+    let output = test.dmp(&["scan", test.dir.join("nowhere").to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn export_playlists() {
+    let test = TestLibrary::new();
+    let songs = songs(&test);
+    let mut playlist = Playlist::new();
+    playlist.add_tracks(songs.iter().map(|song| song.uuid));
+    test.fill(songs, vec![playlist.clone(), playlist]);
+
+    let out = test.dir.join("exported");
+    let exported = test.json(&["export-playlists", out.to_str().unwrap()]);
+    let playlists = exported["playlists"].as_array().unwrap();
+    assert_eq!(playlists.len(), 2);
+    assert_eq!(playlists[0]["tracks"], 3);
+
+    // Both are untitled, so the second one is numbered
+    let mut files: Vec<String> = fs::read_dir(&out)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["_ (2).m3u", "_.m3u"]);
+    let m3u = fs::read_to_string(out.join("_.m3u")).unwrap();
+    assert!(m3u.contains("a.flac") && m3u.contains("Night Train"));
+}
+
+#[test]
+fn verify() {
+    let test = TestLibrary::new();
+    let damaged = test.file("a.wav", None);
+    let songs = vec![
+        song(&damaged, "A", "Haru", "Seasons", 0),
+        song(&test.file("b.wav", None), "B", "Haru", "Seasons", 0),
+    ];
+    test.fill(songs, Vec::new());
+
+    let verified = test.json(&["verify"]);
+    assert_eq!(verified["hashed"], 2);
+    assert!(test
+        .read()
+        .library
+        .iter()
+        .all(|song| song.file_hash.is_some()));
+
+    let verified = test.json(&["verify"]);
+    assert_eq!(verified["unchanged"], 2);
+
+    // A file which can't be read fails the check
+    fs::remove_file(&damaged).unwrap();
+    let output = test.dmp(&["--json", "verify"]);
+    assert_eq!(output.status.code(), Some(3));
+    let verified: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(verified["failed"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn dedupe() {
+    let test = TestLibrary::new();
+    let mut songs = songs(&test);
+    let mut again = songs[0].clone();
+    again.uuid = Uuid::new_v4();
+    again.plays = 4;
+    songs.push(again.clone());
+    let kept = songs[0].uuid;
+    test.fill(songs, Vec::new());
+
+    let deduped = test.json(&["dedupe", "--dry-run"]);
+    assert_eq!(deduped["merged"], 1);
+    assert_eq!(deduped["groups"][0]["kept"], kept.to_string());
+    assert_eq!(
+        deduped["groups"][0]["duplicates"][0],
+        again.uuid.to_string()
+    );
+    assert_eq!(test.read().library.len(), 4);
+
+    let deduped = test.json(&["dedupe"]);
+    assert_eq!(deduped["merged"], 1);
+    let library = test.read();
+    assert_eq!(library.library.len(), 3);
+    assert_eq!(library.query_uuid(&kept).unwrap().0.plays, 7);
+
+    let deduped = test.json(&["dedupe"]);
+    assert_eq!(deduped["merged"], 0);
+}
+
+#[test]
+fn refuses_to_write_while_open() {
+    let test = TestLibrary::new();
+    let mut songs = songs(&test);
+    let mut again = songs[0].clone();
+    again.uuid = Uuid::new_v4();
+    songs.push(again);
+    test.fill(songs, Vec::new());
+
+    // The player, which is this test here, has the library open
+    let lock = LibraryLock::acquire(&test.library).unwrap();
+    let music = test.dir.join("music");
+    for args in [
+        vec!["dedupe"],
+        vec!["verify"],
+        vec!["scan", music.to_str().unwrap()],
+    ] {
+        let output = test.dmp(&args);
+        assert_eq!(output.status.code(), Some(1));
+        let error = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            error.contains(&format!("open in another process ({})", std::process::id())),
+            "{error}"
+        );
+    }
+    assert_eq!(test.read().library.len(), 4);
+
+    // Looking at the library still works
+    assert_eq!(test.json(&["stats"])["songs"], 4);
+    assert_eq!(test.json(&["dedupe", "--dry-run"])["merged"], 1);
+
+    // And so does changing it once the player is closed
+    drop(lock);
+    assert_eq!(test.json(&["dedupe"])["merged"], 1);
+    assert!(!LibraryLock::lock_path(&test.library).exists());
+}
+
+#[test]
+fn bad_arguments() {
+    let test = TestLibrary::new();
+    for args in [
+        vec![],
+        vec!["play"],
+        vec!["scan"],
+        vec!["search"],
+        vec!["stats", "extra"],
+        vec!["dedupe", "--force"],
+    ] {
+        assert_eq!(test.dmp(&args).status.code(), Some(2), "{args:?}");
+    }
+
+    let missing = Command::new(env!("CARGO_BIN_EXE_dmp"))
+        .args(["--config", "/nowhere/config", "stats"])
+        .output()
+        .unwrap();
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8(missing.stderr)
+        .unwrap()
+        .contains("Couldn't read the config"));
+}
@@ -0,0 +1,254 @@
+//! Where the config, player state and caches are kept. That's the system's
+//! config folder, unless DMP runs in portable mode, where everything is kept
+//! in a `data` folder next to the executable so it can be run from a USB
+//! stick on any computer.
+
+use std::{
+    io::Error,
+    path::{Component, Path, PathBuf},
+};
+
+use super::Config;
+
+/// A file next to the executable which turns on portable mode
+pub const PORTABLE_MARKER: &str = "portable";
+/// The argument which turns on portable mode
+pub const PORTABLE_ARG: &str = "--portable";
+/// The environment variable which turns on portable mode when set to
+/// anything but `0`
+pub const PORTABLE_ENV: &str = "DMP_PORTABLE";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataDir {
+    path: PathBuf,
+    portable: bool,
+}
+
+impl DataDir {
+    /// Picks the data folder for the executable at `exe`. It's portable if
+    /// `requested` or there is a [`PORTABLE_MARKER`] next to it, and
+    /// `installed` otherwise.
+    pub fn resolve(
+        exe: Option<&Path>,
+        requested: bool,
+        installed: Option<PathBuf>,
+    ) -> Option<Self> {
+        let exe_dir = exe.and_then(Path::parent);
+        if let Some(exe_dir) = exe_dir {
+            if requested || exe_dir.join(PORTABLE_MARKER).is_file() {
+                return Some(DataDir {
+                    path: exe_dir.join("data"),
+                    portable: true,
+                });
+            }
+        }
+        installed.map(|path| DataDir {
+            path,
+            portable: false,
+        })
+    }
+
+    /// Whether portable mode was asked for with [`PORTABLE_ARG`] or
+    /// [`PORTABLE_ENV`]
+    pub fn requested(
+        mut args: impl Iterator<Item = String>,
+        env: Option<std::ffi::OsString>,
+    ) -> bool {
+        args.any(|arg| arg == PORTABLE_ARG) || env.is_some_and(|value| value != "0")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_portable(&self) -> bool {
+        self.portable
+    }
+
+    /// Where caches which don't outlive DMP are kept. Portable mode keeps
+    /// them with the rest of its data rather than on the computer.
+    pub fn temp_dir(&self) -> PathBuf {
+        match self.portable {
+            true => self.path.join("cache"),
+            false => std::env::temp_dir(),
+        }
+    }
+
+    /// A new config kept in this folder, which hasn't been written yet
+    pub fn new_config(&self) -> Config {
+        let mut config = Config {
+            path: self.path.join("config"),
+            state_path: self.path.join("state"),
+            ..Default::default()
+        };
+        if self.portable {
+            config.make_portable(&self.path);
+        }
+        config
+    }
+
+    /// Reads the config kept in this folder
+    pub fn read_config(&self) -> Result<Config, Error> {
+        let mut config = Config::read_file(self.path.join("config"))?;
+        if config.state_path == PathBuf::default() {
+            config.state_path = self.path.join("state");
+        }
+        if self.portable {
+            config.make_portable(&self.path);
+        }
+        Ok(config)
+    }
+}
+
+/// `path` relative to `base`, going up out of `base` if needed. Paths which
+/// are already relative, on another drive, or only share the root with
+/// `base` (so aren't on the same stick) are kept as they are.
+pub fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    if path.is_relative() {
+        return path.to_path_buf();
+    }
+    let (mut path_parts, mut base_parts) = (path.components(), base.components());
+    // The root, and the drive on Windows, has to be the same
+    let root = roots(&path_parts);
+    if root != roots(&base_parts) {
+        return path.to_path_buf();
+    }
+
+    let mut shared = 0;
+    loop {
+        let (rest_path, rest_base) = (path_parts.clone(), base_parts.clone());
+        match (path_parts.next(), base_parts.next()) {
+            (Some(a), Some(b)) if a == b => shared += 1,
+            _ if shared <= root.len() => return path.to_path_buf(),
+            _ => {
+                let mut relative: PathBuf = rest_base.map(|_| Component::ParentDir).collect();
+                relative.extend(rest_path);
+                return match relative.as_os_str().is_empty() {
+                    true => PathBuf::from("."),
+                    false => relative,
+                };
+            }
+        }
+    }
+}
+
+/// The drive and root a path starts with
+fn roots<'a>(parts: &std::path::Components<'a>) -> Vec<Component<'a>> {
+    parts
+        .clone()
+        .take_while(|part| matches!(part, Component::Prefix(_) | Component::RootDir))
+        .collect()
+}
+
+/// Turns a path stored relative to `base` back into a full one. Empty paths
+/// stay empty, as they mean the path wasn't set.
+pub fn absolute(path: &Path, base: &Path) -> PathBuf {
+    if path.is_absolute() || path.as_os_str().is_empty() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{absolute, relative_to, DataDir, PORTABLE_MARKER};
+    use crate::config::{Config, ConfigLibrary};
+
+    #[test]
+    fn relative_paths() {
+        let base = PathBuf::from("/media/usb/dmp/data");
+        let relative = |path: &str| relative_to(&PathBuf::from(path), &base);
+        assert_eq!(
+            relative("/media/usb/dmp/data/config"),
+            PathBuf::from("config")
+        );
+        assert_eq!(relative("/media/usb/dmp/data"), PathBuf::from("."));
+        assert_eq!(
+            relative("/media/usb/Music/Kalafina"),
+            PathBuf::from("../../Music/Kalafina")
+        );
+        assert_eq!(relative("music/ClariS"), PathBuf::from("music/ClariS"));
+
+        for path in ["config", "../../Music/Kalafina", "/home/user/Music"] {
+            let path = PathBuf::from(path);
+            assert_eq!(relative_to(&absolute(&path, &base), &base), path);
+        }
+        assert_eq!(absolute(&PathBuf::new(), &base), PathBuf::new());
+    }
+
+    #[test]
+    fn data_dir_resolution() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("dango-music-player");
+        let installed = Some(PathBuf::from("/home/user/.config/dmp"));
+
+        let data = DataDir::resolve(Some(&exe), false, installed.clone()).unwrap();
+        assert!(!data.is_portable());
+        assert_eq!(data.path(), PathBuf::from("/home/user/.config/dmp"));
+        assert_eq!(data.temp_dir(), std::env::temp_dir());
+
+        // Asked for, or with the marker next to the executable
+        let data = DataDir::resolve(Some(&exe), true, installed.clone()).unwrap();
+        assert!(data.is_portable());
+        assert_eq!(data.path(), dir.join("data"));
+        fs::write(dir.join(PORTABLE_MARKER), "").unwrap();
+        let data = DataDir::resolve(Some(&exe), false, installed.clone()).unwrap();
+        assert!(data.is_portable());
+        assert_eq!(data.temp_dir(), dir.join("data").join("cache"));
+
+        assert_eq!(DataDir::resolve(None, true, None), None);
+        assert!(DataDir::requested(
+            ["dmp", "--portable"].map(String::from).into_iter(),
+            None
+        ));
+        assert!(DataDir::requested(std::iter::empty(), Some("1".into())));
+        assert!(!DataDir::requested(std::iter::empty(), Some("0".into())));
+        assert!(!DataDir::requested(std::iter::empty(), None));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn portable_config_is_relative() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let data = DataDir::resolve(Some(&dir.join("dmp")), true, None).unwrap();
+        fs::create_dir_all(data.path()).unwrap();
+
+        let mut config = data.new_config();
+        config.push_library(ConfigLibrary::new(
+            data.path().join("library.dlib"),
+            String::from("Library"),
+            Some(vec![dir.join("Music")]),
+            None,
+        ));
+        config.write_file().unwrap();
+
+        // Nothing written points at where the stick was plugged in
+        let written: Config =
+            serde_json::from_str(&fs::read_to_string(data.path().join("config")).unwrap()).unwrap();
+        assert_eq!(written.path, PathBuf::from("config"));
+        assert_eq!(written.state_path, PathBuf::from("state"));
+        let library = written.libraries.get_default().unwrap();
+        assert_eq!(library.path, PathBuf::from("library.dlib"));
+        assert_eq!(library.scan_folders, Some(vec![PathBuf::from("../Music")]));
+
+        // And reading it back finds everything where it is now
+        let read = data.read_config().unwrap();
+        assert_eq!(read.path, data.path().join("config"));
+        assert_eq!(read.state_path, data.path().join("state"));
+        let library = read.libraries.get_default().unwrap();
+        assert_eq!(library.path, data.path().join("library.dlib"));
+        assert_eq!(
+            library.scan_folders,
+            Some(vec![data.path().join("../Music")])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
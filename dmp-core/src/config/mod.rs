@@ -1,13 +1,25 @@
+pub mod data_dir;
+pub mod settings;
+
 use std::{
+    collections::BTreeMap,
     fs::{self, File, OpenOptions},
     io::{Error, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
 use thiserror::Error;
 use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::music_storage::{
+    gain_staging::{PlaybackProfile, DEFAULT_TARGET_LUFS},
+    scan_exclusions::ScanExclusions,
+    scan_priority::ScanConflicts,
+    silence::{DEFAULT_MAX_SILENCE_SKIP_SECS, DEFAULT_SILENCE_THRESHOLD_DB},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigLibrary {
@@ -15,6 +27,26 @@ pub struct ConfigLibrary {
     pub path: PathBuf,
     pub uuid: Uuid,
     pub scan_folders: Option<Vec<PathBuf>>,
+    /// Playback settings used while playing from this library, unless a
+    /// playlist has its own
+    #[serde(default)]
+    pub profile: Option<PlaybackProfile>,
+    /// Files to leave out when scanning `scan_folders`
+    #[serde(default)]
+    pub exclusions: ScanExclusions,
+    /// How the files are named, like `{artist} - {title}`, which fills in
+    /// the tags of scanned songs with no title or artist. See
+    /// [`FilenamePattern`](crate::music_storage::filename_pattern::FilenamePattern).
+    #[serde(default)]
+    pub filename_pattern: Option<String>,
+    /// The priority of each of `scan_folders`, higher first. Folders which
+    /// aren't here have a priority of 0.
+    #[serde(default)]
+    pub folder_priorities: BTreeMap<PathBuf, i32>,
+    /// What a scan does with files which look like songs already in the
+    /// library
+    #[serde(default)]
+    pub scan_conflicts: ScanConflicts,
 }
 
 impl Default for ConfigLibrary {
@@ -24,6 +56,11 @@ impl Default for ConfigLibrary {
             path: PathBuf::default(),
             uuid: Uuid::new_v4(),
             scan_folders: None,
+            profile: None,
+            exclusions: ScanExclusions::default(),
+            filename_pattern: None,
+            folder_priorities: BTreeMap::new(),
+            scan_conflicts: ScanConflicts::default(),
         }
     }
 }
@@ -40,6 +77,11 @@ impl ConfigLibrary {
             path,
             uuid: uuid.unwrap_or(Uuid::new_v4()),
             scan_folders,
+            profile: None,
+            exclusions: ScanExclusions::default(),
+            filename_pattern: None,
+            folder_priorities: BTreeMap::new(),
+            scan_conflicts: ScanConflicts::default(),
         }
     }
 
@@ -95,6 +137,334 @@ impl ConfigLibraries {
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct ConfigConnections {
     pub listenbrainz_token: Option<String>,
+    /// Last.fm API credentials. When unset, the `LAST_FM_API_KEY` and
+    /// `LAST_FM_API_SECRET` environment variables are used, at runtime and
+    /// then at build time.
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    /// Stops reading listen counts back from ListenBrainz
+    #[serde(default)]
+    pub disable_listen_counts: bool,
+    /// Scrobbles the tracks internet radio stations play, as they name them
+    #[serde(default)]
+    pub scrobble_radio: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastFmCredentials {
+    pub key: String,
+    pub secret: String,
+}
+
+impl ConfigConnections {
+    /// The Last.fm credentials to use, if any are configured
+    pub fn lastfm_credentials(&self) -> Option<LastFmCredentials> {
+        self.lastfm_credentials_with(|name| match std::env::var(name) {
+            Ok(value) => Some(value),
+            Err(_) => match name {
+                "LAST_FM_API_KEY" => option_env!("LAST_FM_API_KEY").map(str::to_string),
+                "LAST_FM_API_SECRET" => option_env!("LAST_FM_API_SECRET").map(str::to_string),
+                _ => None,
+            },
+        })
+    }
+
+    fn lastfm_credentials_with(
+        &self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Option<LastFmCredentials> {
+        // Keys are only used as a pair, so an incomplete config doesn't get
+        // mixed with the environment
+        let (key, secret) = match (&self.lastfm_api_key, &self.lastfm_api_secret) {
+            (Some(key), Some(secret)) => (key.clone(), secret.clone()),
+            _ => (env("LAST_FM_API_KEY")?, env("LAST_FM_API_SECRET")?),
+        };
+
+        match key.trim().is_empty() || secret.trim().is_empty() {
+            true => None,
+            false => Some(LastFmCredentials { key, secret }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigAutoPlaylists {
+    /// The maximum number of tracks in each generated playlist
+    pub track_limit: usize,
+    /// How many of the most common genres get their own playlist
+    pub top_genres: usize,
+}
+
+impl Default for ConfigAutoPlaylists {
+    fn default() -> Self {
+        ConfigAutoPlaylists {
+            track_limit: 100,
+            top_genres: 5,
+        }
+    }
+}
+
+/// How songs whose files went missing are removed from the library
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigMissingFiles {
+    /// How long to wait before checking missing files a second time, so
+    /// files which were only briefly unreachable are kept
+    pub recheck_delay_ms: u64,
+    /// The largest share of the library, in percent, which can be removed
+    /// at once. Finding more missing than this aborts the removal.
+    pub max_removal_percent: f32,
+}
+
+impl Default for ConfigMissingFiles {
+    fn default() -> Self {
+        ConfigMissingFiles {
+            recheck_delay_ms: 2000,
+            max_removal_percent: 10.0,
+        }
+    }
+}
+
+/// How tags are written back to the files, like when cleaning them up
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConfigTagWriting {
+    /// The version ID3v2 tags are saved as. 2.3 is what older players and
+    /// car stereos understand.
+    pub id3_version: Id3Version,
+    /// Whether files which can have an ID3v1 tag get one kept in step with
+    /// their main tag
+    pub update_id3v1: bool,
+    /// Whether to leave room after tags, so later edits don't have to
+    /// rewrite the whole file
+    pub pad_tags: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Id3Version {
+    V23,
+    #[default]
+    V24,
+}
+
+impl Default for ConfigTagWriting {
+    fn default() -> Self {
+        ConfigTagWriting {
+            id3_version: Id3Version::V24,
+            update_id3v1: false,
+            pad_tags: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigPlayback {
+    /// Whether to resume playback when an audio device which was
+    /// unplugged comes back. Headphones never resume onto speakers.
+    pub resume_on_device_return: bool,
+    /// How many played items the queue keeps to go back to
+    pub played_history_limit: usize,
+    /// Whether played items past the limit are kept as just their song's
+    /// uuid rather than forgotten, so going back can reach the start of a
+    /// session. The songs are found in the library again when needed.
+    pub pin_played_history: bool,
+    /// The most played items kept at all when they're pinned, which wins
+    /// over the limit if it's lower
+    pub played_history_cap: usize,
+    /// Whether songs added to the queue by hand keep their places when
+    /// shuffling the rest of the queue
+    pub shuffle_keeps_manual: bool,
+    /// The playback settings used wherever a playlist or library doesn't
+    /// set its own
+    pub profile: PlaybackProfile,
+    /// How far into a song Previous restarts it rather than going back a
+    /// song. 0 always goes back.
+    pub prev_restart_threshold_ms: u64,
+    /// The loudness in LUFS ReplayGain brings songs to
+    pub normalization_target_lufs: f32,
+    /// Whether silence longer than a second at the start and end of songs
+    /// is skipped
+    pub skip_silence: bool,
+    /// How quiet in dBFS counts as silence
+    pub silence_threshold_db: f32,
+    /// The most silence skipped at either end of a song, in seconds
+    pub max_silence_skip_secs: u32,
+    /// Whether to take the output device for the player alone
+    pub output_mode: OutputMode,
+    /// Whether the old `now_playing_change`, `playing` and `paused` events
+    /// are sent alongside `now_playing`, for frontends which haven't moved
+    /// to it yet. They'll be removed in a later release.
+    pub legacy_player_events: bool,
+    /// The loudest the player goes, from 0 to 1 of the output after the
+    /// volume curve. Changed only through the settings, which need the
+    /// filter PIN for it when one is set.
+    pub volume_limit: Option<f32>,
+}
+
+/// How the player shares the audio output with the rest of the system
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+pub enum OutputMode {
+    /// Played through the system mixer, which resamples as needed
+    #[default]
+    Shared,
+    /// The device is held by the player alone and fed the file's samples
+    /// unchanged, where the platform allows it
+    Exclusive,
+}
+
+impl Default for ConfigPlayback {
+    fn default() -> Self {
+        ConfigPlayback {
+            resume_on_device_return: false,
+            played_history_limit: 100,
+            pin_played_history: false,
+            played_history_cap: 10_000,
+            shuffle_keeps_manual: false,
+            profile: PlaybackProfile::default(),
+            prev_restart_threshold_ms: 3000,
+            normalization_target_lufs: DEFAULT_TARGET_LUFS,
+            skip_silence: false,
+            silence_threshold_db: DEFAULT_SILENCE_THRESHOLD_DB,
+            max_silence_skip_secs: DEFAULT_MAX_SILENCE_SKIP_SECS,
+            output_mode: OutputMode::Shared,
+            legacy_player_events: true,
+            volume_limit: None,
+        }
+    }
+}
+
+/// The remote control page served to other devices on the network
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigWebRemote {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for ConfigWebRemote {
+    fn default() -> Self {
+        ConfigWebRemote {
+            enabled: false,
+            port: 8642,
+        }
+    }
+}
+
+/// Files kept up to date with the playing song, for stream overlays like
+/// OBS to read. Read when the player starts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigNowPlayingFile {
+    pub enabled: bool,
+    /// Where the song is written as text, filled in from `template`
+    pub text_path: Option<PathBuf>,
+    /// Filled in with the song's `{title}`, `{artist}` and `{album}`, and
+    /// its `{position}` in the queue
+    pub template: String,
+    pub json_path: Option<PathBuf>,
+    /// Where the playing song's cover art is copied to
+    pub art_path: Option<PathBuf>,
+}
+
+impl Default for ConfigNowPlayingFile {
+    fn default() -> Self {
+        ConfigNowPlayingFile {
+            enabled: false,
+            text_path: None,
+            template: String::from("{title} - {artist}"),
+            json_path: None,
+            art_path: None,
+        }
+    }
+}
+
+/// A program run whenever the song changes or playback starts, pauses or
+/// stops. It's only set in the config file, so nothing the interface sends
+/// can choose what gets run. Read when the player starts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigTrackChangeHook {
+    pub enabled: bool,
+    /// The program to run, or a command line for the shell in `shell` mode
+    pub command: String,
+    /// Passed to the program as they are, with the song's `{title}`,
+    /// `{artist}`, `{album}` and `{uuid}`, and the player's `{state}`
+    /// filled in
+    pub args: Vec<String>,
+    /// Runs `command` with `sh -c`, or `cmd /C` on Windows, instead. The
+    /// song is only given to it in the `DMP_TITLE`, `DMP_ARTIST`,
+    /// `DMP_ALBUM`, `DMP_UUID` and `DMP_STATE` variables, which the program
+    /// always gets, so tags can't be run as commands, and `args` is left out.
+    pub shell: bool,
+    /// How long it can run before it's killed, in milliseconds
+    pub timeout_ms: u64,
+}
+
+impl Default for ConfigTrackChangeHook {
+    fn default() -> Self {
+        ConfigTrackChangeHook {
+            enabled: false,
+            command: String::new(),
+            args: Vec::new(),
+            shell: false,
+            timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Filtered mode, which keeps explicit songs from being queued up
+/// automatically, shuffled in, or found by searching
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigContentFilter {
+    pub enabled: bool,
+    /// The salted hash of the PIN needed to turn filtered mode on or off,
+    /// as `salt:hash`. This keeps the PIN itself out of the file, but isn't
+    /// meant to hold up against someone who can edit the config.
+    pin_hash: Option<String>,
+}
+
+impl ConfigContentFilter {
+    pub fn has_pin(&self) -> bool {
+        self.pin_hash.is_some()
+    }
+
+    /// Whether `pin` unlocks the filter. Anything does when no PIN is set.
+    pub fn check_pin(&self, pin: Option<&str>) -> bool {
+        let Some((salt, _)) = self
+            .pin_hash
+            .as_deref()
+            .and_then(|hash| hash.split_once(':'))
+        else {
+            return true;
+        };
+        pin.is_some_and(|pin| self.pin_hash.as_deref() == Some(&hash_pin(salt, pin)))
+    }
+
+    /// Sets the PIN needed to change filtered mode, `None` removing it
+    pub fn set_pin(&mut self, pin: Option<&str>) {
+        self.pin_hash = pin
+            .filter(|pin| !pin.is_empty())
+            .map(|pin| hash_pin(&Uuid::new_v4().simple().to_string(), pin));
+    }
+}
+
+fn hash_pin(salt: &str, pin: &str) -> String {
+    format!(
+        "{salt}:{:032x}",
+        xxh3_128(format!("{salt}{pin}").as_bytes())
+    )
+}
+
+/// The last known placement of an auxiliary window, in physical pixels
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ConfigWindow {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -105,6 +475,20 @@ pub struct Config {
     pub libraries: ConfigLibraries,
     pub connections: ConfigConnections,
     pub state_path: PathBuf,
+    pub auto_playlists: ConfigAutoPlaylists,
+    pub missing_files: ConfigMissingFiles,
+    pub tag_writing: ConfigTagWriting,
+    pub playback: ConfigPlayback,
+    pub web_remote: ConfigWebRemote,
+    pub content_filter: ConfigContentFilter,
+    pub now_playing_file: ConfigNowPlayingFile,
+    pub track_change_hook: ConfigTrackChangeHook,
+    /// Window placements keyed by window label
+    pub windows: BTreeMap<String, ConfigWindow>,
+    /// The data folder in portable mode, which the paths in the config are
+    /// written relative to
+    #[serde(skip)]
+    pub portable_dir: Option<PathBuf>,
 }
 
 impl Config {
@@ -131,7 +515,14 @@ impl Config {
             .read(true)
             .write(true)
             .open(&writer)?;
-        let config = to_string_pretty(self)?;
+        let config = match &self.portable_dir {
+            Some(dir) => {
+                let mut config = self.clone();
+                config.map_paths(|path| data_dir::relative_to(path, dir));
+                to_string_pretty(&config)?
+            }
+            None => to_string_pretty(self)?,
+        };
         // dbg!(&config);
 
         file.write_all(config.as_bytes())?;
@@ -169,6 +560,33 @@ impl Config {
         Ok(config)
     }
 
+    /// Resolves paths which were written relative to the portable data
+    /// folder `dir`, and writes them relative to it from now on
+    pub fn make_portable(&mut self, dir: &Path) {
+        self.map_paths(|path| data_dir::absolute(path, dir));
+        self.portable_dir = Some(dir.to_path_buf());
+    }
+
+    /// Changes every path to a file or folder in the config
+    fn map_paths(&mut self, map: impl Fn(&Path) -> PathBuf) {
+        self.path = map(&self.path);
+        self.state_path = map(&self.state_path);
+        if let Some(backup) = &mut self.backup_folder {
+            *backup = map(backup);
+        }
+        for library in &mut self.libraries.libraries {
+            library.path = map(&library.path);
+            for folder in library.scan_folders.iter_mut().flatten() {
+                *folder = map(folder);
+            }
+            library.folder_priorities = library
+                .folder_priorities
+                .iter()
+                .map(|(folder, priority)| (map(folder), *priority))
+                .collect();
+        }
+    }
+
     pub fn push_library(&mut self, lib: ConfigLibrary) {
         if self.libraries.libraries.is_empty() {
             self.libraries.default_library = lib.uuid;
@@ -192,8 +610,10 @@ pub enum ConfigError {
 
 #[cfg(test)]
 pub mod tests {
-    use super::{Config, ConfigLibrary};
-    use crate::music_storage::library::MusicLibrary;
+    use super::{Config, ConfigConnections, ConfigContentFilter, ConfigLibrary, LastFmCredentials};
+    use crate::music_storage::{
+        cancel::CancelToken, library::MusicLibrary, scan_exclusions::ExclusionRules,
+    };
     use std::path::PathBuf;
 
     pub fn new_config_lib() -> (Config, MusicLibrary) {
@@ -217,7 +637,12 @@ pub mod tests {
             dbg!(config.libraries.default_library),
         )
         .unwrap();
-        lib.scan_folder("test-config/music/").unwrap();
+        lib.scan_folder(
+            "test-config/music/",
+            &ExclusionRules::default(),
+            &CancelToken::new(),
+        )
+        .unwrap();
         lib.save(config.libraries.get_default().unwrap().path.clone())
             .unwrap();
 
@@ -235,7 +660,12 @@ pub mod tests {
         )
         .unwrap();
 
-        lib.scan_folder("test-config/music/").unwrap();
+        lib.scan_folder(
+            "test-config/music/",
+            &ExclusionRules::default(),
+            &CancelToken::new(),
+        )
+        .unwrap();
 
         lib.save(config.libraries.get_default().unwrap().path.clone())
             .unwrap();
@@ -251,4 +681,81 @@ pub mod tests {
 
         dbg!(config);
     }
+
+    #[test]
+    fn lastfm_credentials_missing() {
+        let connections = ConfigConnections::default();
+        assert_eq!(connections.lastfm_credentials_with(|_| None), None);
+
+        // A key without a secret can't be used
+        let connections = ConfigConnections {
+            lastfm_api_key: Some(String::from("key")),
+            ..Default::default()
+        };
+        assert_eq!(connections.lastfm_credentials_with(|_| None), None);
+
+        let blank = |_: &str| Some(String::from(" "));
+        assert_eq!(
+            ConfigConnections::default().lastfm_credentials_with(blank),
+            None
+        );
+
+        // Older configs without the fields still load
+        let connections: ConfigConnections =
+            serde_json::from_str(r#"{"listenbrainz_token":null}"#).unwrap();
+        assert_eq!(connections.lastfm_api_key, None);
+    }
+
+    #[test]
+    fn lastfm_credentials_sources() {
+        let env = |name: &str| Some(format!("env {name}"));
+        assert_eq!(
+            ConfigConnections::default().lastfm_credentials_with(env),
+            Some(LastFmCredentials {
+                key: String::from("env LAST_FM_API_KEY"),
+                secret: String::from("env LAST_FM_API_SECRET"),
+            })
+        );
+
+        let connections = ConfigConnections {
+            lastfm_api_key: Some(String::from("key")),
+            lastfm_api_secret: Some(String::from("secret")),
+            ..Default::default()
+        };
+        assert_eq!(
+            connections.lastfm_credentials_with(env),
+            Some(LastFmCredentials {
+                key: String::from("key"),
+                secret: String::from("secret"),
+            })
+        );
+    }
+
+    #[test]
+    fn content_filter_pin() {
+        let mut filter = ConfigContentFilter::default();
+        assert!(!filter.has_pin());
+        assert!(filter.check_pin(None));
+
+        filter.set_pin(Some("1234"));
+        assert!(filter.has_pin());
+        assert!(filter.check_pin(Some("1234")));
+        assert!(!filter.check_pin(Some("4321")));
+        assert!(!filter.check_pin(None));
+
+        // The PIN survives saving without being written out
+        let saved = serde_json::to_string(&filter).unwrap();
+        assert!(!saved.contains("1234"));
+        let filter: ConfigContentFilter = serde_json::from_str(&saved).unwrap();
+        assert!(filter.check_pin(Some("1234")));
+
+        // The same PIN is salted differently each time it's set
+        let mut other = ConfigContentFilter::default();
+        other.set_pin(Some("1234"));
+        assert_ne!(serde_json::to_string(&other).unwrap(), saved);
+
+        let mut filter = filter;
+        filter.set_pin(None);
+        assert!(filter.check_pin(None));
+    }
 }
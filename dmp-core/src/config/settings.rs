@@ -0,0 +1,733 @@
+//! The config split into sections for the settings screen, so it never has
+//! to be sent around whole. Changes come in as patches of a single section,
+//! which are checked before any of them are applied, and secrets like API
+//! tokens are only ever sent back masked.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use crate::music_storage::gain_staging::PlaybackProfile;
+
+use super::{
+    Config, ConfigAutoPlaylists, ConfigMissingFiles, ConfigNowPlayingFile, ConfigTagWriting,
+    ConfigWebRemote,
+};
+
+/// What a secret which is set is sent out as. Sending it back in a patch
+/// leaves the secret as it was.
+pub const MASKED: &str = "********";
+
+const MAX_HISTORY_LIMIT: usize = 10_000;
+const MAX_HISTORY_CAP: usize = 100_000;
+const MAX_RESTART_THRESHOLD_MS: u64 = 60_000;
+const PREAMP_RANGE: (f32, f32) = (-24.0, 24.0);
+const TARGET_LUFS_RANGE: (f32, f32) = (-30.0, -10.0);
+const SILENCE_THRESHOLD_RANGE: (f32, f32) = (-90.0, -20.0);
+const MAX_SILENCE_SKIP_SECS: u32 = 60;
+const MAX_CROSSFADE_MS: u32 = 20_000;
+const MAX_AUTO_PLAYLIST_TRACKS: usize = 10_000;
+const MAX_TOP_GENRES: usize = 50;
+const MAX_RECHECK_DELAY_MS: u64 = 60_000;
+/// Ports below this need special permissions on most systems
+const MIN_PORT: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SettingsSection {
+    Playback,
+    Library,
+    Connections,
+    Interface,
+}
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Invalid value for {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+    #[error("Invalid settings: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Couldn't save the settings: {0}")]
+    Write(#[from] std::io::Error),
+    #[error("Wrong PIN")]
+    WrongPin,
+}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> SettingsError {
+    SettingsError::Invalid {
+        field,
+        reason: reason.into(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaybackSettings {
+    pub resume_on_device_return: bool,
+    pub played_history_limit: usize,
+    pub pin_played_history: bool,
+    pub played_history_cap: usize,
+    pub shuffle_keeps_manual: bool,
+    pub profile: PlaybackProfile,
+    pub prev_restart_threshold_ms: u64,
+    pub normalization_target_lufs: f32,
+    pub skip_silence: bool,
+    pub silence_threshold_db: f32,
+    pub max_silence_skip_secs: u32,
+    pub volume_limit: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibrarySettings {
+    pub auto_playlists: ConfigAutoPlaylists,
+    pub missing_files: ConfigMissingFiles,
+    pub tag_writing: ConfigTagWriting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionsSettings {
+    /// [`MASKED`] when set
+    pub listenbrainz_token: Option<String>,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub disable_listen_counts: bool,
+    pub scrobble_radio: bool,
+    pub web_remote: ConfigWebRemote,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceSettings {
+    pub now_playing_file: ConfigNowPlayingFile,
+}
+
+/// One section of the settings, as shown to the user
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "section")]
+pub enum Settings {
+    Playback(PlaybackSettings),
+    Library(LibrarySettings),
+    Connections(ConnectionsSettings),
+    Interface(InterfaceSettings),
+}
+
+/// Changes to the playback settings. Anything left out stays as it is.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaybackPatch {
+    pub resume_on_device_return: Option<bool>,
+    pub played_history_limit: Option<usize>,
+    pub pin_played_history: Option<bool>,
+    pub played_history_cap: Option<usize>,
+    pub shuffle_keeps_manual: Option<bool>,
+    pub profile: Option<PlaybackProfile>,
+    pub prev_restart_threshold_ms: Option<u64>,
+    pub normalization_target_lufs: Option<f32>,
+    pub skip_silence: Option<bool>,
+    pub silence_threshold_db: Option<f32>,
+    pub max_silence_skip_secs: Option<u32>,
+    /// Set with a value and removed with `null`
+    #[serde(default, deserialize_with = "double_option")]
+    pub volume_limit: Option<Option<f32>>,
+    /// The filter PIN, needed to change the volume limit when one is set
+    pub pin: Option<String>,
+}
+
+/// Changes to the library settings, each part of which is replaced whole
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryPatch {
+    pub auto_playlists: Option<ConfigAutoPlaylists>,
+    pub missing_files: Option<ConfigMissingFiles>,
+    pub tag_writing: Option<ConfigTagWriting>,
+}
+
+/// Changes to the connections. Secrets are set with a value, removed with
+/// `null` and kept by leaving them out or sending [`MASKED`] back.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionsPatch {
+    #[serde(default, deserialize_with = "double_option")]
+    pub listenbrainz_token: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub lastfm_api_key: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub lastfm_api_secret: Option<Option<String>>,
+    pub disable_listen_counts: Option<bool>,
+    pub scrobble_radio: Option<bool>,
+    pub web_remote: Option<ConfigWebRemote>,
+}
+
+/// Changes to the interface settings, each part of which is replaced whole
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfacePatch {
+    pub now_playing_file: Option<ConfigNowPlayingFile>,
+}
+
+/// Tells a field which is `null` apart from one which was left out
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone)]
+pub enum SettingsPatch {
+    Playback(PlaybackPatch),
+    Library(LibraryPatch),
+    Connections(ConnectionsPatch),
+    Interface(InterfacePatch),
+}
+
+impl SettingsPatch {
+    /// Reads a patch of `section` sent by the frontend. Fields which don't
+    /// belong to the section are refused rather than ignored.
+    pub fn parse(
+        section: SettingsSection,
+        patch: serde_json::Value,
+    ) -> Result<Self, SettingsError> {
+        Ok(match section {
+            SettingsSection::Playback => SettingsPatch::Playback(serde_json::from_value(patch)?),
+            SettingsSection::Library => SettingsPatch::Library(serde_json::from_value(patch)?),
+            SettingsSection::Connections => {
+                SettingsPatch::Connections(serde_json::from_value(patch)?)
+            }
+            SettingsSection::Interface => SettingsPatch::Interface(serde_json::from_value(patch)?),
+        })
+    }
+
+    /// Checks every value in the patch, so a patch is either applied whole
+    /// or not at all
+    fn validate(&self) -> Result<(), SettingsError> {
+        match self {
+            SettingsPatch::Playback(patch) => {
+                if patch
+                    .played_history_limit
+                    .is_some_and(|limit| limit > MAX_HISTORY_LIMIT)
+                {
+                    return Err(invalid(
+                        "played_history_limit",
+                        format!("can be at most {MAX_HISTORY_LIMIT}"),
+                    ));
+                }
+                if patch
+                    .played_history_cap
+                    .is_some_and(|cap| cap > MAX_HISTORY_CAP)
+                {
+                    return Err(invalid(
+                        "played_history_cap",
+                        format!("can be at most {MAX_HISTORY_CAP}"),
+                    ));
+                }
+                if patch
+                    .prev_restart_threshold_ms
+                    .is_some_and(|ms| ms > MAX_RESTART_THRESHOLD_MS)
+                {
+                    return Err(invalid(
+                        "prev_restart_threshold_ms",
+                        format!("can be at most {MAX_RESTART_THRESHOLD_MS}"),
+                    ));
+                }
+                if patch.normalization_target_lufs.is_some_and(|lufs| {
+                    !(lufs.is_finite()
+                        && (TARGET_LUFS_RANGE.0..=TARGET_LUFS_RANGE.1).contains(&lufs))
+                }) {
+                    return Err(invalid(
+                        "normalization_target_lufs",
+                        format!(
+                            "must be from {} to {} LUFS",
+                            TARGET_LUFS_RANGE.0, TARGET_LUFS_RANGE.1
+                        ),
+                    ));
+                }
+                if patch.silence_threshold_db.is_some_and(|db| {
+                    !(db.is_finite()
+                        && (SILENCE_THRESHOLD_RANGE.0..=SILENCE_THRESHOLD_RANGE.1).contains(&db))
+                }) {
+                    return Err(invalid(
+                        "silence_threshold_db",
+                        format!(
+                            "must be from {} to {} dB",
+                            SILENCE_THRESHOLD_RANGE.0, SILENCE_THRESHOLD_RANGE.1
+                        ),
+                    ));
+                }
+                if patch
+                    .max_silence_skip_secs
+                    .is_some_and(|secs| secs > MAX_SILENCE_SKIP_SECS)
+                {
+                    return Err(invalid(
+                        "max_silence_skip_secs",
+                        format!("can be at most {MAX_SILENCE_SKIP_SECS}"),
+                    ));
+                }
+                if patch
+                    .volume_limit
+                    .flatten()
+                    .is_some_and(|limit| !(limit.is_finite() && limit > 0.0 && limit <= 1.0))
+                {
+                    return Err(invalid("volume_limit", "must be more than 0 and at most 1"));
+                }
+                if let Some(profile) = &patch.profile {
+                    validate_profile(profile)?;
+                }
+            }
+            SettingsPatch::Library(patch) => {
+                if let Some(auto) = &patch.auto_playlists {
+                    if !(1..=MAX_AUTO_PLAYLIST_TRACKS).contains(&auto.track_limit) {
+                        return Err(invalid(
+                            "auto_playlists.track_limit",
+                            format!("must be from 1 to {MAX_AUTO_PLAYLIST_TRACKS}"),
+                        ));
+                    }
+                    if auto.top_genres > MAX_TOP_GENRES {
+                        return Err(invalid(
+                            "auto_playlists.top_genres",
+                            format!("can be at most {MAX_TOP_GENRES}"),
+                        ));
+                    }
+                }
+                if let Some(missing) = &patch.missing_files {
+                    if !(0.0..=100.0).contains(&missing.max_removal_percent) {
+                        return Err(invalid(
+                            "missing_files.max_removal_percent",
+                            "must be a percentage",
+                        ));
+                    }
+                    if missing.recheck_delay_ms > MAX_RECHECK_DELAY_MS {
+                        return Err(invalid(
+                            "missing_files.recheck_delay_ms",
+                            format!("can be at most {MAX_RECHECK_DELAY_MS}"),
+                        ));
+                    }
+                }
+            }
+            SettingsPatch::Connections(patch) => {
+                if patch
+                    .web_remote
+                    .as_ref()
+                    .is_some_and(|remote| remote.port < MIN_PORT)
+                {
+                    return Err(invalid(
+                        "web_remote.port",
+                        format!("must be from {MIN_PORT} to 65535"),
+                    ));
+                }
+            }
+            SettingsPatch::Interface(patch) => {
+                if let Some(file) = &patch.now_playing_file {
+                    let paths = [&file.text_path, &file.json_path, &file.art_path];
+                    if paths.iter().any(|path| is_relative(path)) {
+                        return Err(invalid("now_playing_file", "paths must be absolute"));
+                    }
+                    if file.enabled && paths.iter().all(|path| path.is_none()) {
+                        return Err(invalid(
+                            "now_playing_file",
+                            "needs a file to write to when enabled",
+                        ));
+                    }
+                    if file.text_path.is_some() && file.template.trim().is_empty() {
+                        return Err(invalid("now_playing_file.template", "can't be empty"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_relative(path: &Option<PathBuf>) -> bool {
+    path.as_ref().is_some_and(|path| path.is_relative())
+}
+
+fn validate_profile(profile: &PlaybackProfile) -> Result<(), SettingsError> {
+    let in_range =
+        |value: f32, (min, max): (f32, f32)| value.is_finite() && (min..=max).contains(&value);
+    if profile
+        .preamp
+        .is_some_and(|preamp| !in_range(preamp, PREAMP_RANGE))
+    {
+        return Err(invalid(
+            "profile.preamp",
+            format!("must be from {} to {} dB", PREAMP_RANGE.0, PREAMP_RANGE.1),
+        ));
+    }
+    if profile.crossfade.is_some_and(|ms| ms > MAX_CROSSFADE_MS) {
+        return Err(invalid(
+            "profile.crossfade",
+            format!("can be at most {MAX_CROSSFADE_MS} ms"),
+        ));
+    }
+    Ok(())
+}
+
+fn mask(secret: &Option<String>) -> Option<String> {
+    secret.as_ref().map(|_| MASKED.to_string())
+}
+
+/// Sets a secret from a patch, where blank values remove it
+fn patch_secret(secret: &mut Option<String>, patch: Option<Option<String>>) -> bool {
+    let new = match patch {
+        None => return false,
+        Some(Some(value)) if value == MASKED => return false,
+        Some(value) => value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty()),
+    };
+    let changed = *secret != new;
+    *secret = new;
+    changed
+}
+
+/// What applying a patch did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct SettingsChange {
+    /// Some of the changes only take effect once the player is restarted
+    pub needs_restart: bool,
+    /// The web remote has to be started again with its new settings
+    pub web_remote_changed: bool,
+}
+
+impl Config {
+    pub fn settings(&self, section: SettingsSection) -> Settings {
+        match section {
+            SettingsSection::Playback => Settings::Playback(PlaybackSettings {
+                resume_on_device_return: self.playback.resume_on_device_return,
+                played_history_limit: self.playback.played_history_limit,
+                pin_played_history: self.playback.pin_played_history,
+                played_history_cap: self.playback.played_history_cap,
+                shuffle_keeps_manual: self.playback.shuffle_keeps_manual,
+                profile: self.playback.profile,
+                prev_restart_threshold_ms: self.playback.prev_restart_threshold_ms,
+                normalization_target_lufs: self.playback.normalization_target_lufs,
+                skip_silence: self.playback.skip_silence,
+                silence_threshold_db: self.playback.silence_threshold_db,
+                max_silence_skip_secs: self.playback.max_silence_skip_secs,
+                volume_limit: self.playback.volume_limit,
+            }),
+            SettingsSection::Library => Settings::Library(LibrarySettings {
+                auto_playlists: self.auto_playlists.clone(),
+                missing_files: self.missing_files.clone(),
+                tag_writing: self.tag_writing.clone(),
+            }),
+            SettingsSection::Connections => Settings::Connections(ConnectionsSettings {
+                listenbrainz_token: mask(&self.connections.listenbrainz_token),
+                lastfm_api_key: mask(&self.connections.lastfm_api_key),
+                lastfm_api_secret: mask(&self.connections.lastfm_api_secret),
+                disable_listen_counts: self.connections.disable_listen_counts,
+                scrobble_radio: self.connections.scrobble_radio,
+                web_remote: self.web_remote.clone(),
+            }),
+            SettingsSection::Interface => Settings::Interface(InterfaceSettings {
+                now_playing_file: self.now_playing_file.clone(),
+            }),
+        }
+    }
+
+    /// Checks `patch` and merges it into the config. Nothing is changed if
+    /// any of it is invalid. The config isn't written.
+    pub fn apply_settings(
+        &mut self,
+        patch: SettingsPatch,
+    ) -> Result<SettingsChange, SettingsError> {
+        patch.validate()?;
+        if let SettingsPatch::Playback(PlaybackPatch {
+            volume_limit: Some(_),
+            pin,
+            ..
+        }) = &patch
+        {
+            if !self.content_filter.check_pin(pin.as_deref()) {
+                return Err(SettingsError::WrongPin);
+            }
+        }
+
+        let mut change = SettingsChange::default();
+        match patch {
+            SettingsPatch::Playback(patch) => {
+                let playback = &mut self.playback;
+                if let Some(resume) = patch.resume_on_device_return {
+                    playback.resume_on_device_return = resume;
+                }
+                if let Some(limit) = patch.played_history_limit {
+                    playback.played_history_limit = limit;
+                }
+                if let Some(pin) = patch.pin_played_history {
+                    playback.pin_played_history = pin;
+                }
+                if let Some(cap) = patch.played_history_cap {
+                    playback.played_history_cap = cap;
+                }
+                if let Some(keep) = patch.shuffle_keeps_manual {
+                    playback.shuffle_keeps_manual = keep;
+                }
+                if let Some(profile) = patch.profile {
+                    playback.profile = profile;
+                }
+                if let Some(threshold) = patch.prev_restart_threshold_ms {
+                    playback.prev_restart_threshold_ms = threshold;
+                }
+                if let Some(lufs) = patch.normalization_target_lufs {
+                    playback.normalization_target_lufs = lufs;
+                }
+                if let Some(skip) = patch.skip_silence {
+                    playback.skip_silence = skip;
+                }
+                if let Some(threshold) = patch.silence_threshold_db {
+                    playback.silence_threshold_db = threshold;
+                }
+                if let Some(secs) = patch.max_silence_skip_secs {
+                    playback.max_silence_skip_secs = secs;
+                }
+                if let Some(limit) = patch.volume_limit {
+                    playback.volume_limit = limit;
+                }
+            }
+            SettingsPatch::Library(patch) => {
+                if let Some(auto) = patch.auto_playlists {
+                    self.auto_playlists = auto;
+                }
+                if let Some(missing) = patch.missing_files {
+                    self.missing_files = missing;
+                }
+                if let Some(tag_writing) = patch.tag_writing {
+                    self.tag_writing = tag_writing;
+                }
+            }
+            SettingsPatch::Connections(patch) => {
+                let connections = &mut self.connections;
+                // ListenBrainz is connected to when the player starts
+                change.needs_restart |= patch_secret(
+                    &mut connections.listenbrainz_token,
+                    patch.listenbrainz_token,
+                );
+                patch_secret(&mut connections.lastfm_api_key, patch.lastfm_api_key);
+                patch_secret(&mut connections.lastfm_api_secret, patch.lastfm_api_secret);
+                if let Some(disable) = patch.disable_listen_counts {
+                    connections.disable_listen_counts = disable;
+                }
+                if let Some(scrobble) = patch.scrobble_radio {
+                    connections.scrobble_radio = scrobble;
+                }
+                if let Some(remote) = patch.web_remote {
+                    change.web_remote_changed = remote.enabled != self.web_remote.enabled
+                        || remote.port != self.web_remote.port;
+                    self.web_remote = remote;
+                }
+            }
+            SettingsPatch::Interface(patch) => {
+                if let Some(file) = patch.now_playing_file {
+                    // The files are set up when the player starts
+                    change.needs_restart = true;
+                    self.now_playing_file = file;
+                }
+            }
+        }
+        Ok(change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Settings, SettingsError, SettingsPatch, SettingsSection, MASKED};
+    use crate::config::Config;
+
+    fn apply(
+        config: &mut Config,
+        section: SettingsSection,
+        patch: serde_json::Value,
+    ) -> Result<super::SettingsChange, SettingsError> {
+        config.apply_settings(SettingsPatch::parse(section, patch)?)
+    }
+
+    #[test]
+    fn patches_are_merged() {
+        let mut config = Config::default();
+        apply(
+            &mut config,
+            SettingsSection::Playback,
+            json!({ "played_history_limit": 50, "profile": { "crossfade": 3000 } }),
+        )
+        .unwrap();
+        assert_eq!(config.playback.played_history_limit, 50);
+        assert_eq!(config.playback.profile.crossfade, Some(3000));
+        // Left alone
+        assert_eq!(config.playback.prev_restart_threshold_ms, 3000);
+
+        apply(
+            &mut config,
+            SettingsSection::Library,
+            json!({ "missing_files": { "max_removal_percent": 25.0 } }),
+        )
+        .unwrap();
+        assert_eq!(config.missing_files.max_removal_percent, 25.0);
+        assert_eq!(config.missing_files.recheck_delay_ms, 2000);
+    }
+
+    #[test]
+    fn invalid_patches_change_nothing() {
+        let mut config = Config::default();
+        let rejected = [
+            (
+                SettingsSection::Playback,
+                json!({ "played_history_limit": 5, "profile": { "preamp": 90.0 } }),
+            ),
+            (
+                SettingsSection::Playback,
+                json!({ "pin_played_history": true, "played_history_cap": 1_000_000 }),
+            ),
+            (
+                SettingsSection::Playback,
+                json!({ "normalization_target_lufs": -5.0 }),
+            ),
+            (
+                SettingsSection::Playback,
+                json!({ "silence_threshold_db": 0.0 }),
+            ),
+            (
+                SettingsSection::Playback,
+                json!({ "profile": { "replay_gain_mode": "Loudest" } }),
+            ),
+            (
+                SettingsSection::Library,
+                json!({ "auto_playlists": { "track_limit": 0 } }),
+            ),
+            (
+                SettingsSection::Library,
+                json!({ "missing_files": { "max_removal_percent": 150.0 } }),
+            ),
+            (
+                SettingsSection::Connections,
+                json!({ "scrobble_radio": true, "web_remote": { "enabled": true, "port": 80 } }),
+            ),
+            (
+                SettingsSection::Connections,
+                json!({ "web_remote": { "port": 70000 } }),
+            ),
+            (
+                SettingsSection::Interface,
+                json!({ "now_playing_file": { "enabled": true } }),
+            ),
+            (
+                SettingsSection::Interface,
+                json!({ "now_playing_file": { "text_path": "song.txt" } }),
+            ),
+            // Fields from another section
+            (SettingsSection::Playback, json!({ "scrobble_radio": true })),
+        ];
+        for (section, patch) in rejected {
+            assert!(
+                apply(&mut config, section, patch.clone()).is_err(),
+                "{patch}"
+            );
+        }
+
+        let defaults = Config::default();
+        assert_eq!(config.playback.played_history_limit, 100);
+        assert!(!config.playback.pin_played_history);
+        assert_eq!(config.playback.profile, defaults.playback.profile);
+        assert!(!config.connections.scrobble_radio);
+        assert!(!config.web_remote.enabled);
+        assert!(!config.now_playing_file.enabled);
+    }
+
+    #[test]
+    fn secrets_are_write_only() {
+        let mut config = Config::default();
+        let change = apply(
+            &mut config,
+            SettingsSection::Connections,
+            json!({ "listenbrainz_token": " token ", "lastfm_api_key": "key" }),
+        )
+        .unwrap();
+        assert!(change.needs_restart);
+        assert_eq!(
+            config.connections.listenbrainz_token.as_deref(),
+            Some("token")
+        );
+
+        let Settings::Connections(settings) = config.settings(SettingsSection::Connections) else {
+            unreachable!()
+        };
+        assert_eq!(settings.listenbrainz_token.as_deref(), Some(MASKED));
+        assert_eq!(settings.lastfm_api_secret, None);
+        let shown = serde_json::to_string(&config.settings(SettingsSection::Connections)).unwrap();
+        assert!(!shown.contains("\"token\"") && !shown.contains("\"key\""));
+
+        // Sending the masked values back keeps the secrets
+        let change = apply(
+            &mut config,
+            SettingsSection::Connections,
+            json!({ "listenbrainz_token": MASKED, "lastfm_api_key": MASKED }),
+        )
+        .unwrap();
+        assert!(!change.needs_restart);
+        assert_eq!(
+            config.connections.listenbrainz_token.as_deref(),
+            Some("token")
+        );
+        assert_eq!(config.connections.lastfm_api_key.as_deref(), Some("key"));
+
+        // Null or blank removes them
+        apply(
+            &mut config,
+            SettingsSection::Connections,
+            json!({ "listenbrainz_token": null, "lastfm_api_key": "  " }),
+        )
+        .unwrap();
+        assert_eq!(config.connections.listenbrainz_token, None);
+        assert_eq!(config.connections.lastfm_api_key, None);
+    }
+
+    #[test]
+    fn web_remote_changes_are_reported() {
+        let mut config = Config::default();
+        let patch = json!({ "web_remote": { "enabled": true, "port": 9000 } });
+        let change = apply(&mut config, SettingsSection::Connections, patch.clone()).unwrap();
+        assert!(change.web_remote_changed);
+        assert_eq!(config.web_remote.port, 9000);
+
+        let change = apply(&mut config, SettingsSection::Connections, patch).unwrap();
+        assert!(!change.web_remote_changed);
+    }
+
+    #[test]
+    fn volume_limit_needs_the_pin() {
+        let mut config = Config::default();
+        let playback = SettingsSection::Playback;
+        apply(&mut config, playback, json!({ "volume_limit": 0.5 })).unwrap();
+        assert_eq!(config.playback.volume_limit, Some(0.5));
+        for limit in [0.0, 1.5, -0.2] {
+            assert!(apply(&mut config, playback, json!({ "volume_limit": limit })).is_err());
+        }
+
+        config.content_filter.set_pin(Some("1234"));
+        for patch in [
+            json!({ "volume_limit": null }),
+            json!({ "volume_limit": 0.8, "pin": "4321" }),
+        ] {
+            assert!(matches!(
+                apply(&mut config, playback, patch),
+                Err(SettingsError::WrongPin)
+            ));
+        }
+        assert_eq!(config.playback.volume_limit, Some(0.5));
+
+        // Other playback settings don't need it
+        apply(&mut config, playback, json!({ "skip_silence": true })).unwrap();
+        apply(
+            &mut config,
+            playback,
+            json!({ "volume_limit": null, "pin": "1234" }),
+        )
+        .unwrap();
+        assert_eq!(config.playback.volume_limit, None);
+        let Settings::Playback(settings) = config.settings(playback) else {
+            unreachable!()
+        };
+        assert_eq!(settings.volume_limit, None);
+    }
+}
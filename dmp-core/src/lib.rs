@@ -1,23 +1,77 @@
 #![allow(while_true)]
 pub mod music_storage {
+    pub mod album_index;
+    pub mod art_fallback;
+    pub mod art_store;
+    pub mod auto_playlist;
+    pub mod cancel;
+    pub mod content_filter;
+    mod decode;
+    pub mod duplicates;
+    pub mod filename_pattern;
+    pub mod gain_analysis;
+    pub mod gain_staging;
+    pub mod gapless;
+    pub mod integrity;
     pub mod library;
+    pub mod library_guard;
     pub mod music_collection;
+    pub mod organize;
+    pub mod os_path;
+    pub mod play_history;
     pub mod playlist;
+    pub mod radio;
+    pub mod scan_candidates;
+    pub mod scan_exclusions;
+    pub mod scan_priority;
+    pub mod scan_report;
+    pub mod search;
+    pub mod silence;
+    pub mod song_details;
+    pub mod song_links;
+    pub mod song_problems;
+    pub mod tag_cleanup;
+    pub mod tag_keys;
+    pub mod tag_writing;
+    pub mod tombstones;
     mod utils;
+    pub mod waveform;
 
     #[allow(dead_code)]
     pub mod db_reader;
 }
 
+#[cfg(feature = "playback")]
 pub mod music_controller {
+    pub mod audio_device;
     pub mod connections;
+    pub mod continue_listening;
     pub mod controller;
     pub mod controller_handle;
+    pub mod controller_state;
+    pub mod cue_playback;
+    pub mod icy;
+    pub mod jobs;
     pub mod library_command;
+    pub mod listen_counts;
+    pub mod listen_time;
+    pub mod now_playing;
+    mod now_playing_file;
+    pub mod output_mode;
     pub mod player_command;
     pub mod player_monitor;
     pub mod queue;
     pub mod queue_command;
+    pub mod queue_log;
+    pub mod queue_store;
+    pub mod remote_source;
+    pub mod save_scheduler;
+    #[cfg(feature = "connections")]
+    pub mod scrobble_clock;
+    pub mod scrobbles;
+    mod track_change_hook;
+    pub mod ui_state;
+    pub mod web_remote;
 }
 
 pub mod config;
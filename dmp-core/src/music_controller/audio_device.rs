@@ -0,0 +1,358 @@
+//! Watching for audio output devices coming and going, and deciding how
+//! playback should react when they do
+
+use std::{collections::VecDeque, time::Duration};
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use crossbeam_channel::Sender;
+use serde::Serialize;
+
+use super::{
+    controller::{Controller, PlayerCommand, PlayerResponse},
+    controller_handle::PlayerCommandInput,
+};
+
+/// Words which show up in the names of headphone outputs
+const HEADPHONE_NAMES: [&str; 5] = ["headphone", "headset", "earbud", "earphone", "airpods"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Serialize)]
+pub struct OutputDevice {
+    pub name: String,
+    pub headphones: bool,
+}
+
+impl OutputDevice {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        let lowercase = name.to_lowercase();
+        OutputDevice {
+            headphones: HEADPHONE_NAMES.iter().any(|h| lowercase.contains(h)),
+            name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Serialize)]
+pub enum DeviceEvent {
+    /// The device being played through went away
+    Disconnected(OutputDevice),
+    /// A device which went away came back
+    Connected(OutputDevice),
+    /// The system default output changed to another device
+    DefaultChanged(OutputDevice),
+}
+
+/// What the player did in response to a [`DeviceEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize)]
+pub enum DeviceAction {
+    Paused,
+    Reopened { resumed: bool },
+}
+
+/// Something which produces [`DeviceEvent`]s, blocking until the next one
+/// happens. Returns `None` once no more events will be produced.
+pub trait DeviceEventSource: Send {
+    fn next_event(&mut self) -> Option<DeviceEvent>;
+}
+
+/// Lists the audio outputs of the system
+pub trait DeviceLister: Send {
+    fn default_output(&mut self) -> Option<String>;
+    fn outputs(&mut self) -> Vec<String>;
+}
+
+/// Lists outputs through the default audio host of the platform
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemDevices;
+
+impl DeviceLister for SystemDevices {
+    fn default_output(&mut self) -> Option<String> {
+        cpal::default_host().default_output_device()?.name().ok()
+    }
+
+    fn outputs(&mut self) -> Vec<String> {
+        cpal::default_host()
+            .output_devices()
+            .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Turns changes in the lists of a [`DeviceLister`] into [`DeviceEvent`]s by
+/// checking it periodically
+pub struct PollingDeviceSource<L: DeviceLister> {
+    lister: L,
+    interval: Duration,
+    current: Option<String>,
+    missing: Option<String>,
+    pending: VecDeque<DeviceEvent>,
+}
+
+impl<L: DeviceLister> PollingDeviceSource<L> {
+    pub fn new(mut lister: L, interval: Duration) -> Self {
+        PollingDeviceSource {
+            current: lister.default_output(),
+            lister,
+            interval,
+            missing: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn poll(&mut self) {
+        let outputs = self.lister.outputs();
+        let default = self.lister.default_output();
+
+        if let Some(current) = self.current.take_if(|c| !outputs.contains(c)) {
+            self.pending
+                .push_back(DeviceEvent::Disconnected(OutputDevice::new(&current)));
+            self.missing = Some(current);
+        }
+
+        let mut reconnected = None;
+        if let Some(missing) = self.missing.take_if(|m| outputs.contains(m)) {
+            self.pending
+                .push_back(DeviceEvent::Connected(OutputDevice::new(&missing)));
+            reconnected = Some(missing);
+        }
+
+        if default != self.current {
+            if let Some(default) = default.as_ref().filter(|d| reconnected.as_ref() != Some(d)) {
+                self.pending
+                    .push_back(DeviceEvent::DefaultChanged(OutputDevice::new(default)));
+            }
+            self.current = default;
+        }
+    }
+}
+
+impl Default for PollingDeviceSource<SystemDevices> {
+    fn default() -> Self {
+        PollingDeviceSource::new(SystemDevices, Duration::from_secs(1))
+    }
+}
+
+impl<L: DeviceLister> DeviceEventSource for PollingDeviceSource<L> {
+    fn next_event(&mut self) -> Option<DeviceEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            std::thread::sleep(self.interval);
+            self.poll();
+        }
+    }
+}
+
+/// Decides how to react to device changes, remembering whether playback was
+/// interrupted by a device going away
+#[derive(Debug, Default)]
+pub struct InterruptionHandler {
+    interrupted: bool,
+    headphones_lost: bool,
+}
+
+impl InterruptionHandler {
+    pub fn handle(
+        &mut self,
+        event: &DeviceEvent,
+        playing: bool,
+        auto_resume: bool,
+    ) -> DeviceAction {
+        match event {
+            DeviceEvent::Disconnected(device) => {
+                self.interrupted |= playing;
+                self.headphones_lost |= device.headphones;
+                DeviceAction::Paused
+            }
+            DeviceEvent::Connected(device) | DeviceEvent::DefaultChanged(device) => {
+                let resumed = if self.interrupted {
+                    // Never move from headphones to anything else on its own
+                    auto_resume && (!self.headphones_lost || device.headphones)
+                } else {
+                    playing
+                };
+
+                // Stay interrupted until the headphones come back or
+                // something else resumes playback
+                if resumed || !self.headphones_lost {
+                    self.interrupted = false;
+                    self.headphones_lost = false;
+                }
+                DeviceAction::Reopened { resumed }
+            }
+        }
+    }
+}
+
+/// Sent out whenever the player reacted to a device change
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceNotification {
+    pub event: DeviceEvent,
+    pub action: DeviceAction,
+}
+
+impl DeviceNotification {
+    pub fn message(&self) -> String {
+        match &self.event {
+            DeviceEvent::Disconnected(_) => "Audio device disconnected".to_string(),
+            DeviceEvent::Connected(device) => format!("Audio device reconnected: {}", device.name),
+            DeviceEvent::DefaultChanged(device) => {
+                format!("Audio output changed to {}", device.name)
+            }
+        }
+    }
+}
+
+impl Controller {
+    pub(super) fn device_monitor_loop(
+        mut source: Box<dyn DeviceEventSource>,
+        player_mail: async_channel::Sender<PlayerCommandInput>,
+        notify_device: Sender<DeviceNotification>,
+    ) {
+        println!("Device monitor started");
+        while let Some(event) = source.next_event() {
+            let (command, tx) =
+                PlayerCommandInput::command(PlayerCommand::DeviceEvent(event.clone()));
+            player_mail.send_blocking(command).unwrap();
+            let PlayerResponse::Device(res) = tx.recv_blocking().unwrap() else {
+                unreachable!()
+            };
+
+            match res {
+                Ok(action) => _ = notify_device.send(DeviceNotification { event, action }),
+                Err(e) => println!("Could not handle device change: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        DeviceAction, DeviceEvent, DeviceLister, InterruptionHandler, OutputDevice,
+        PollingDeviceSource,
+    };
+
+    /// A lister which reports whatever the test sets
+    struct MockDevices {
+        default: Option<String>,
+        outputs: Vec<String>,
+    }
+
+    impl DeviceLister for MockDevices {
+        fn default_output(&mut self) -> Option<String> {
+            self.default.clone()
+        }
+
+        fn outputs(&mut self) -> Vec<String> {
+            self.outputs.clone()
+        }
+    }
+
+    fn source(default: &str, outputs: &[&str]) -> PollingDeviceSource<MockDevices> {
+        PollingDeviceSource::new(
+            MockDevices {
+                default: Some(default.to_string()),
+                outputs: outputs.iter().map(|o| o.to_string()).collect(),
+            },
+            Duration::ZERO,
+        )
+    }
+
+    fn set(source: &mut PollingDeviceSource<MockDevices>, default: &str, outputs: &[&str]) {
+        source.lister.default = Some(default.to_string());
+        source.lister.outputs = outputs.iter().map(|o| o.to_string()).collect();
+        source.poll();
+    }
+
+    #[test]
+    fn headphone_names() {
+        assert!(OutputDevice::new("USB Headset").headphones);
+        assert!(OutputDevice::new("Jack's AirPods Pro").headphones);
+        assert!(!OutputDevice::new("Built-in Speakers").headphones);
+    }
+
+    #[test]
+    fn unplug_and_replug() {
+        let mut source = source("USB Headphones", &["USB Headphones", "Speakers"]);
+        source.poll();
+        assert!(source.pending.is_empty());
+
+        set(&mut source, "Speakers", &["Speakers"]);
+        assert_eq!(
+            source.pending.drain(..).collect::<Vec<_>>(),
+            [
+                DeviceEvent::Disconnected(OutputDevice::new("USB Headphones")),
+                DeviceEvent::DefaultChanged(OutputDevice::new("Speakers")),
+            ]
+        );
+
+        set(
+            &mut source,
+            "USB Headphones",
+            &["USB Headphones", "Speakers"],
+        );
+        assert_eq!(
+            source.pending.drain(..).collect::<Vec<_>>(),
+            [DeviceEvent::Connected(OutputDevice::new("USB Headphones"))]
+        );
+    }
+
+    #[test]
+    fn headphones_never_resume_on_speakers() {
+        let headphones = OutputDevice::new("USB Headphones");
+        let speakers = OutputDevice::new("Speakers");
+        let mut handler = InterruptionHandler::default();
+
+        let events = [
+            DeviceEvent::Disconnected(headphones.clone()),
+            DeviceEvent::DefaultChanged(speakers),
+            DeviceEvent::Connected(headphones),
+        ];
+        let actions = events
+            .iter()
+            .scan(true, |playing, event| {
+                let action = handler.handle(event, *playing, true);
+                *playing = action == DeviceAction::Reopened { resumed: true };
+                Some(action)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            actions,
+            [
+                DeviceAction::Paused,
+                DeviceAction::Reopened { resumed: false },
+                DeviceAction::Reopened { resumed: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_follows_config() {
+        let dac = OutputDevice::new("USB DAC");
+        for auto_resume in [true, false] {
+            let mut handler = InterruptionHandler::default();
+            assert_eq!(
+                handler.handle(&DeviceEvent::Disconnected(dac.clone()), true, auto_resume),
+                DeviceAction::Paused
+            );
+            assert_eq!(
+                handler.handle(&DeviceEvent::Connected(dac.clone()), false, auto_resume),
+                DeviceAction::Reopened {
+                    resumed: auto_resume
+                }
+            );
+        }
+
+        // Nothing was interrupted, so a new default just carries on as it was
+        let mut handler = InterruptionHandler::default();
+        assert_eq!(
+            handler.handle(&DeviceEvent::DefaultChanged(dac), true, false),
+            DeviceAction::Reopened { resumed: true }
+        );
+    }
+}
@@ -1,26 +1,51 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+#[cfg(feature = "connections")]
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    collections::HashMap,
     thread::sleep,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use chrono::TimeDelta;
-use crossbeam::{scope, select};
+use crossbeam::scope;
 use crossbeam_channel::{unbounded, Receiver};
-use discord_presence::Client;
-use listenbrainz::ListenBrainz;
 use parking_lot::RwLock;
 use prismriver::State as PrismState;
+use serde::Serialize;
 
-use crate::{
-    config::Config,
-    music_storage::library::{Song, Tag},
+#[cfg(feature = "connections")]
+use crossbeam::select;
+#[cfg(feature = "connections")]
+use discord_presence::Client;
+#[cfg(feature = "connections")]
+use listenbrainz::{
+    raw::{
+        request::{ListenType, Payload, SubmitListens, TrackMetadata},
+        Client as ListenBrainzClient,
+    },
+    ListenBrainz,
 };
+#[cfg(feature = "connections")]
+use serde_json::Value;
+
+#[cfg(feature = "connections")]
+use crate::music_storage::library::Tag;
+use crate::{config::Config, music_storage::library::Song};
 
-use super::controller::Controller;
+use super::{
+    controller::{Controller, NowPlayingChange},
+    now_playing_file::{write_now_playing, NowPlayingEvent},
+    scrobbles::ScrobbleCache,
+    track_change_hook::run_track_change_hook,
+};
+#[cfg(feature = "connections")]
+use super::{
+    scrobble_clock::{ScrobbleClock, NOW_PLAYING_EXPIRES},
+    scrobbles::Scrobble,
+};
 
 #[derive(Debug, Clone)]
 pub(super) enum ConnectionsNotification {
@@ -29,7 +54,9 @@ pub(super) enum ConnectionsNotification {
         duration: Option<TimeDelta>,
     },
     StateChange(PrismState),
-    SongChange(Song),
+    SongChange(Box<NowPlayingChange>),
+    /// The playing song went back to its start
+    Restarted,
     AboutToFinish,
     EOS,
 }
@@ -42,12 +69,59 @@ pub struct ConnectionsInput {
 pub(super) struct ControllerConnections {
     pub notifications_tx: Receiver<ConnectionsNotification>,
     pub inner: ConnectionsInput,
+    pub scrobbles: Arc<RwLock<ScrobbleCache>>,
 }
 
+/// The name ListenBrainz shows listens as coming from
+#[cfg(feature = "connections")]
+const LISTENBRAINZ_CLIENT: &str = "Dango Music Player";
+
+/// How long a track on the radio has to be listened to before it's scrobbled,
+/// since there's no way to tell how long the whole track is
+#[cfg(feature = "connections")]
+const RADIO_SCROBBLE_AFTER: i64 = 30;
+
+/// The most bytes Discord takes in each text of an activity
+#[cfg(feature = "connections")]
+const DISCORD_TEXT_LIMIT: usize = 128;
+
+/// How long to wait after Discord says the activity is set too often,
+/// doubling each time it says so again
+#[cfg(feature = "connections")]
+const DISCORD_BACKOFF: Duration = Duration::from_secs(5);
+#[cfg(feature = "connections")]
+const DISCORD_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 static DC_ACTIVE: AtomicBool = AtomicBool::new(false);
 static LB_ACTIVE: AtomicBool = AtomicBool::new(false);
 
+/// Which services are set up and running, so the frontend can disable
+/// features for the ones which aren't
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConnectionStatus {
+    pub listenbrainz_configured: bool,
+    pub listenbrainz_active: bool,
+    pub lastfm_configured: bool,
+    pub discord_active: bool,
+}
+
+impl ConnectionStatus {
+    pub fn new(config: &Config) -> Self {
+        ConnectionStatus {
+            listenbrainz_configured: config.connections.listenbrainz_token.is_some(),
+            listenbrainz_active: LB_ACTIVE.load(Ordering::Relaxed),
+            lastfm_configured: config.connections.lastfm_credentials().is_some(),
+            discord_active: DC_ACTIVE.load(Ordering::Relaxed),
+        }
+    }
+}
+
 impl Controller {
+    /// Passes what the player is doing on to Discord and ListenBrainz, and
+    /// to the now playing files and track change hook when they're enabled.
+    /// Built without the `connections` feature only those are kept up to
+    /// date.
+    #[cfg_attr(not(feature = "connections"), allow(unused_variables))]
     pub(super) fn handle_connections(
         config: Arc<RwLock<Config>>,
         ControllerConnections {
@@ -55,13 +129,23 @@ impl Controller {
             inner: ConnectionsInput {
                 discord_rpc_client_id,
             },
+            scrobbles,
         }: ControllerConnections,
     ) {
         let (dc_state_rx, dc_state_tx) = unbounded::<PrismState>();
         let (dc_song_rx, dc_song_tx) = unbounded::<Song>();
         let (lb_song_rx, lb_song_tx) = unbounded::<Song>();
+        let (lb_radio_rx, lb_radio_tx) = unbounded::<Song>();
         let (lb_abt_fin_rx, lb_abt_fn_tx) = unbounded::<()>();
         let (lb_eos_rx, lb_eos_tx) = unbounded::<()>();
+        let (lb_restart_rx, lb_restart_tx) = unbounded::<()>();
+        let (lb_state_rx, lb_state_tx) = unbounded::<PrismState>();
+        let (file_rx, file_tx) = unbounded::<NowPlayingEvent>();
+        let now_playing_file = config.read().now_playing_file.clone();
+        let file_enabled = now_playing_file.enabled;
+        let (hook_rx, hook_tx) = unbounded::<NowPlayingEvent>();
+        let track_change_hook = config.read().track_change_hook.clone();
+        let hook_enabled = track_change_hook.enabled && !track_change_hook.command.is_empty();
 
         scope(|s| {
             s.builder()
@@ -75,13 +159,39 @@ impl Controller {
                                 if DC_ACTIVE.load(Ordering::Relaxed) {
                                     dc_state_rx.send(state.clone()).unwrap();
                                 }
+                                if LB_ACTIVE.load(Ordering::Relaxed) {
+                                    lb_state_rx.send(state.clone()).unwrap();
+                                }
+                                if hook_enabled {
+                                    hook_rx.send(NowPlayingEvent::State(state.clone())).unwrap();
+                                }
+                                if file_enabled {
+                                    file_rx.send(NowPlayingEvent::State(state)).unwrap();
+                                }
                             }
-                            SongChange(song) => {
+                            SongChange(change) => {
+                                if file_enabled {
+                                    file_rx.send(NowPlayingEvent::Song(change.clone())).unwrap();
+                                }
+                                if hook_enabled {
+                                    hook_rx.send(NowPlayingEvent::Song(change.clone())).unwrap();
+                                }
                                 if DC_ACTIVE.load(Ordering::Relaxed) {
-                                    dc_song_rx.send(song.clone()).unwrap();
+                                    dc_song_rx.send(change.shown_song()).unwrap();
+                                }
+                                if LB_ACTIVE.load(Ordering::Relaxed) {
+                                    // Tracks on the radio are only scrobbled
+                                    // when asked for
+                                    if change.stream_title.is_none() {
+                                        lb_song_rx.send(Song::clone(&change.song)).unwrap();
+                                    } else if config.read().connections.scrobble_radio {
+                                        lb_radio_rx.send(change.shown_song()).unwrap();
+                                    }
                                 }
+                            }
+                            Restarted => {
                                 if LB_ACTIVE.load(Ordering::Relaxed) {
-                                    lb_song_rx.send(song).unwrap();
+                                    lb_restart_rx.send(()).unwrap();
                                 }
                             }
                             EOS => {
@@ -99,6 +209,21 @@ impl Controller {
                 })
                 .unwrap();
 
+            if file_enabled {
+                s.builder()
+                    .name("Now Playing File".to_string())
+                    .spawn(move |_| write_now_playing(now_playing_file, file_tx))
+                    .unwrap();
+            }
+
+            if hook_enabled {
+                s.builder()
+                    .name("Track Change Hook".to_string())
+                    .spawn(move |_| run_track_change_hook(track_change_hook, hook_tx))
+                    .unwrap();
+            }
+
+            #[cfg(feature = "connections")]
             if let Some(client_id) = discord_rpc_client_id {
                 s.builder()
                     .name("Discord RPC Handler".to_string())
@@ -108,11 +233,21 @@ impl Controller {
                     .unwrap();
             };
 
+            #[cfg(feature = "connections")]
             if let Some(token) = config.read().connections.listenbrainz_token.clone() {
                 s.builder()
                     .name("ListenBrainz Handler".to_string())
                     .spawn(move |_| {
-                        Controller::listenbrainz_scrobble(&token, lb_song_tx, lb_abt_fn_tx, lb_eos_tx);
+                        Controller::listenbrainz_scrobble(
+                            &token,
+                            scrobbles,
+                            lb_song_tx,
+                            lb_radio_tx,
+                            lb_abt_fn_tx,
+                            lb_eos_tx,
+                            lb_restart_tx,
+                            lb_state_tx,
+                        );
                     })
                     .unwrap();
             }
@@ -120,8 +255,9 @@ impl Controller {
         .unwrap();
     }
 
+    #[cfg(feature = "connections")]
     fn discord_rpc(client_id: u64, song_tx: Receiver<Song>, state_tx: Receiver<PrismState>) {
-        // TODO: Handle seeking position change and pause
+        // TODO: Handle seeking position change
         let mut client =
             discord_presence::Client::with_error_config(client_id, Duration::from_secs(5), None);
         client.start();
@@ -132,10 +268,11 @@ impl Controller {
 
         let mut state = "Started".to_string();
         let mut song: Option<Song> = None;
-        let mut now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards?")
-            .as_secs();
+        let mut clock = ScrobbleClock::default();
+        // The activity is only set again when something changed, or once
+        // Discord takes it again after saying it was set too often
+        let mut changed = false;
+        let mut backoff: Option<(Instant, Duration)> = None;
         DC_ACTIVE.store(true, Ordering::Relaxed);
 
         while true {
@@ -150,97 +287,190 @@ impl Controller {
                             PrismState::Stopped => "Stopped",
                             _ => "I'm Scared, Boss"
                         }.to_string();
+                        clock.state(&state_, now());
+                        changed = true;
                     }
                 },
                 recv(song_tx) -> res => {
                     if let Ok(song_) = res {
                         *song = Some(song_);
-                        now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards?").as_secs();
+                        clock.song(now());
+                        changed = true;
                     }
                 },
                 default(Duration::from_millis(99)) => ()
             }
 
-            client
-                .set_activity(|activity| {
-                    let a = activity
-                        .state(song.as_ref().map_or(String::new(), |s| {
-                            format!(
-                                "{}{}{}",
-                                s.get_tag(&Tag::Artist)
-                                    .map_or(String::new(), |album| album.clone()),
-                                if s.get_tag(&Tag::Album).is_some()
-                                    && s.get_tag(&Tag::Artist).is_some()
-                                {
-                                    " - "
-                                } else {
-                                    ""
-                                },
-                                s.get_tag(&Tag::Album)
-                                    .map_or(String::new(), |album| album.clone())
-                            )
-                        }))
-                        ._type(discord_presence::models::ActivityType::Listening)
-                        .details(if let Some(song) = song {
-                            song.get_tag(&Tag::Title)
-                                .map_or(String::from("Unknown Title"), |title| title.clone())
-                        } else {
-                            String::new()
-                        });
-                    if let Some(s) = song {
-                        if state.as_str() == "Playing" {
-                            a.timestamps(|timestamps| {
-                                timestamps.start(now).end(now + s.duration.as_secs())
-                            })
-                        } else {
-                            a
-                        }
-                    } else {
-                        a
+            let Some(s) = song.as_ref().filter(|_| changed) else {
+                continue;
+            };
+            if backoff.is_some_and(|(until, _)| Instant::now() < until) {
+                continue;
+            }
+
+            let artist_album = [s.get_tag(&Tag::Artist), s.get_tag(&Tag::Album)]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" - ");
+            let title = s
+                .get_tag(&Tag::Title)
+                .map_or("Unknown Title", |title| title);
+            let res = client.set_activity(|activity| {
+                let a = activity
+                    .state(presence_text(&artist_album))
+                    ._type(discord_presence::models::ActivityType::Listening)
+                    .details(presence_text(title));
+                // Leaving the timestamps out while paused stops the
+                // progress bar where it is
+                match clock.started() {
+                    Some(started) => {
+                        let started = started as u64;
+                        a.timestamps(|timestamps| {
+                            timestamps
+                                .start(started)
+                                .end(started + s.duration.as_secs())
+                        })
                     }
-                    .assets(|a| a.large_text(state.clone()))
-                    .instance(true)
-                })
-                .unwrap();
+                    None => a,
+                }
+                .assets(|a| a.large_text(presence_text(state)))
+                .instance(true)
+            });
+            match res {
+                Ok(_) => {
+                    changed = false;
+                    backoff = None;
+                }
+                // Discord keeps refusing activities which are set too often
+                // for a while, so wait longer each time it does
+                Err(e) if e.to_string().to_lowercase().contains("rate limit") => {
+                    let wait = backoff.map_or(DISCORD_BACKOFF, |(_, wait)| {
+                        (wait * 2).min(DISCORD_MAX_BACKOFF)
+                    });
+                    println!("Discord is rate limiting the activity, waiting {wait:?}");
+                    backoff = Some((Instant::now() + wait, wait));
+                }
+                Err(e) => {
+                    println!("Couldn't set the Discord activity: {e}");
+                    changed = false;
+                }
+            }
         }
         DC_ACTIVE.store(false, Ordering::Relaxed);
     }
 
-    fn listenbrainz_scrobble(token: &str, song_tx: Receiver<Song>, abt_fn_tx: Receiver<()>, eos_tx: Receiver<()>) {
+    #[cfg(feature = "connections")]
+    #[allow(clippy::too_many_arguments)]
+    fn listenbrainz_scrobble(
+        token: &str,
+        scrobbles: Arc<RwLock<ScrobbleCache>>,
+        song_tx: Receiver<Song>,
+        radio_tx: Receiver<Song>,
+        abt_fn_tx: Receiver<()>,
+        eos_tx: Receiver<()>,
+        restart_tx: Receiver<()>,
+        state_tx: Receiver<PrismState>,
+    ) {
         let mut client = ListenBrainz::new();
         client.authenticate(token).unwrap();
         if !client.is_authenticated() {
             return;
         }
+        // The simple client can't send additional info, so listens go
+        // through the raw API
+        let client = ListenBrainzClient::new();
+
+        // Submit anything left over from the last session
+        // Submitting can take a while offline, which the cache isn't kept
+        // locked for
+        let flush = |client: &ListenBrainzClient| {
+            ScrobbleCache::flush(&scrobbles, |s| {
+                submit_listen(client, token, ListenType::Import, s)
+            });
+            _ = scrobbles.read().save();
+        };
+        flush(&client);
+
+        // Radio tracks never finish, so they're scrobbled once the next one
+        // starts if they were listened to for long enough
+        let finish_radio =
+            |client: &ListenBrainzClient, radio: &mut Option<Scrobble>, clock: &ScrobbleClock| {
+                if let Some(listen) = radio.take() {
+                    if clock.listened(now()) >= RADIO_SCROBBLE_AFTER {
+                        scrobbles.write().push(listen);
+                        flush(client);
+                    }
+                }
+            };
 
         let mut song: Option<Song> = None;
         let mut last_song: Option<Song> = None;
+        let mut radio: Option<Scrobble> = None;
+        let mut clock = ScrobbleClock::default();
         LB_ACTIVE.store(true, Ordering::Relaxed);
         println!("ListenBrainz connected");
 
         while true {
             let song = &mut song;
             let last_song = &mut last_song;
+            let radio = &mut radio;
+            let clock = &mut clock;
 
             let client = &client;
             select! {
                 recv(song_tx) -> res => {
                     if let Ok(_song) = res {
-                        let artist = if let Some(tag) = _song.get_tag(&Tag::Artist) {
-                            tag.as_str()
-                        } else {
+                        finish_radio(client, radio, clock);
+                        clock.song(now());
+                        let Some(listen) = scrobble_of(&_song, now()) else {
                             continue
                         };
-                        let title = if let Some(tag) = _song.get_tag(&Tag::Title) {
-                            tag.as_str()
-                        } else {
+
+                        submit_listen(client, token, ListenType::PlayingNow, &listen).unwrap();
+                        println!("Song Listening = {} - {}", listen.artist, listen.title);
+                        *song = Some(_song);
+                    }
+                },
+                recv(radio_tx) -> res => {
+                    if let Ok(track) = res {
+                        finish_radio(client, radio, clock);
+                        clock.song(now());
+                        let Some(listen) = scrobble_of(&track, now()) else {
                             continue
                         };
-                        let release = _song.get_tag(&Tag::Key(String::from("MusicBrainzReleaseId"))).map(|id| id.as_str());
 
-                        client.playing_now(artist, title, release).unwrap();
-                        println!("Song Listening = {artist} - {title}");
-                        *song = Some(_song);
+                        submit_listen(client, token, ListenType::PlayingNow, &listen).unwrap();
+                        *radio = Some(listen);
+                    }
+                },
+                // The play which was cut short isn't scrobbled, even when
+                // it was about to finish, and the new one starts fresh
+                recv(restart_tx) -> _ => {
+                    clock.song(now());
+                    if let Some(restarted) = last_song.take() {
+                        *song = Some(restarted);
+                    }
+                    if let Some(listen) = song.as_ref().and_then(|song| scrobble_of(song, now())) {
+                        submit_listen(client, token, ListenType::PlayingNow, &listen).unwrap();
+                    }
+                },
+                // Playing now runs out while paused for long, so it's sent
+                // again on resume
+                recv(state_tx) -> res => {
+                    let Ok(state) = res else {
+                        continue
+                    };
+                    let Some(paused) = clock.state(&state, now()) else {
+                        continue
+                    };
+                    if paused <= NOW_PLAYING_EXPIRES {
+                        continue
+                    }
+                    let playing = song.as_ref().and_then(|song| scrobble_of(song, now()));
+                    if let Some(listen) = playing.or_else(|| radio.clone()) {
+                        _ = submit_listen(client, token, ListenType::PlayingNow, &listen);
                     }
                 },
                 recv(abt_fn_tx) -> _ => {
@@ -249,19 +479,13 @@ impl Controller {
                 },
                 recv(eos_tx) -> _ => {
                     if let Some(song) = last_song {
-                        let artist = if let Some(tag) = song.get_tag(&Tag::Artist) {
-                            tag.as_str()
-                        } else {
+                        let Some(listen) = scrobble_of(song, now()) else {
                             continue
                         };
-                        let title = if let Some(tag) = song.get_tag(&Tag::Title) {
-                            tag.as_str()
-                        } else {
-                            continue
-                        };
-                        let release = song.get_tag(&Tag::Key(String::from("MusicBrainzReleaseId"))).map(|id| id.as_str());
 
-                        client.listen(artist, title, release).unwrap();
+                        // Scrobbles go through the cache so they survive being offline
+                        scrobbles.write().push(listen);
+                        flush(client);
                         println!("Song Scrobbled");
                     }
                 }
@@ -269,4 +493,144 @@ impl Controller {
         }
         LB_ACTIVE.store(false, Ordering::Relaxed);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "connections")]
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards?")
+        .as_secs() as i64
+}
+
+/// Makes `text` something Discord takes in an activity. Control characters
+/// are taken out, and text over the limit is cut off between characters and
+/// ended with an ellipsis. Discord doesn't take empty text either.
+#[cfg(feature = "connections")]
+fn presence_text(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim();
+    if text.is_empty() {
+        return String::from("Unknown");
+    }
+    if text.len() <= DISCORD_TEXT_LIMIT {
+        return text.to_string();
+    }
+    let mut end = DISCORD_TEXT_LIMIT - '…'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", text[..end].trim_end())
+}
+
+/// Makes a scrobble of a song listened to at `listened_at`, if it has the
+/// artist and title needed to submit it
+#[cfg(feature = "connections")]
+fn scrobble_of(song: &Song, listened_at: i64) -> Option<Scrobble> {
+    Some(Scrobble {
+        song: song.uuid,
+        artist: song.get_tag(&Tag::Artist)?.clone(),
+        title: song.get_tag(&Tag::Title)?.clone(),
+        release: song
+            .get_tag(&Tag::Key(String::from("MusicBrainzReleaseId")))
+            .cloned(),
+        listened_at,
+        duration_ms: Some(song.duration.as_millis() as u64).filter(|ms| *ms > 0),
+        track_number: song.get_tag(&Tag::Track).cloned(),
+    })
+}
+
+/// The metadata sent with a listen on top of the artist, title and release
+#[cfg(feature = "connections")]
+fn additional_info(scrobble: &Scrobble) -> HashMap<String, Value> {
+    let mut info = HashMap::from([
+        ("media_player".to_string(), Value::from(LISTENBRAINZ_CLIENT)),
+        (
+            "submission_client".to_string(),
+            Value::from(LISTENBRAINZ_CLIENT),
+        ),
+        (
+            "submission_client_version".to_string(),
+            Value::from(env!("CARGO_PKG_VERSION")),
+        ),
+    ]);
+    if let Some(duration_ms) = scrobble.duration_ms {
+        info.insert("duration_ms".to_string(), Value::from(duration_ms));
+    }
+    if let Some(track_number) = &scrobble.track_number {
+        info.insert(
+            "tracknumber".to_string(),
+            Value::from(track_number.as_str()),
+        );
+    }
+    info
+}
+
+#[cfg(feature = "connections")]
+fn submit_listen(
+    client: &ListenBrainzClient,
+    token: &str,
+    listen_type: ListenType,
+    scrobble: &Scrobble,
+) -> Result<(), listenbrainz::Error> {
+    let payload = [Payload {
+        listened_at: match listen_type {
+            ListenType::PlayingNow => None,
+            _ => Some(scrobble.listened_at),
+        },
+        track_metadata: TrackMetadata {
+            track_name: scrobble.title.as_str(),
+            artist_name: scrobble.artist.as_str(),
+            release_name: scrobble.release.as_deref(),
+            additional_info: Some(additional_info(scrobble)),
+        },
+    }];
+
+    client.submit_listens(
+        token,
+        SubmitListens {
+            listen_type,
+            payload: &payload,
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "connections"))]
+mod tests {
+    use super::{presence_text, DISCORD_TEXT_LIMIT};
+
+    #[test]
+    fn presence_text_fits() {
+        assert_eq!(presence_text("Kalafina"), "Kalafina");
+        assert_eq!(presence_text(" \u{7}\n"), "Unknown");
+        assert_eq!(presence_text("to\u{0}ri\tno"), "torino");
+
+        // 42 three byte characters are exactly at the limit
+        let exact = "夢".repeat(42);
+        assert_eq!(exact.len(), 126);
+        assert_eq!(presence_text(&exact), exact);
+        let at_limit = format!("{exact}ab");
+        assert_eq!(presence_text(&at_limit), at_limit);
+
+        // One byte over, where the cut would land inside a character
+        for over in [
+            format!("{exact}abc"),
+            "夢".repeat(43),
+            format!("a{}", "夢".repeat(43)),
+        ] {
+            let text = presence_text(&over);
+            assert!(text.len() <= DISCORD_TEXT_LIMIT, "{text}");
+            assert!(text.ends_with('…'));
+            assert!(over.starts_with(text.trim_end_matches('…')));
+        }
+        assert_eq!(
+            presence_text(&"夢".repeat(43)),
+            format!("{}…", "夢".repeat(41))
+        );
+
+        // Four byte characters aren't split either
+        let emoji = "🎵".repeat(40);
+        assert_eq!(presence_text(&emoji), format!("{}…", "🎵".repeat(31)));
+    }
+}
@@ -0,0 +1,308 @@
+//! Suggestions for picking up where listening was left off, for the home
+//! screen: albums and playlists stopped partway through, long songs with a
+//! saved position, and where songs were recently played from.
+//!
+//! Everything comes from the songs' last played times and what the player
+//! remembers, in a couple of passes over the library, so it stays quick on
+//! big libraries without keeping an index around.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{serde::ts_milliseconds_option, DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::music_storage::library::{MusicLibrary, Song, Tag};
+
+use super::controller::PlayerLocation;
+
+/// The longest gap between two tracks of an album for them to count as
+/// played in one go, rather than picked out separately
+const ALBUM_SESSION_GAP: TimeDelta = TimeDelta::hours(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ContinueKind {
+    /// An album stopped partway through
+    Album,
+    /// A playlist stopped partway through
+    Playlist,
+    /// A long song with a saved position
+    Song,
+    /// Somewhere songs were recently played from, to jump back into
+    Recent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContinueEntry {
+    pub kind: ContinueKind,
+    pub name: String,
+    /// The song whose art is shown for the entry
+    pub art: Uuid,
+    /// The song to play to carry on, and where in it to start
+    pub resume: Uuid,
+    pub position: Duration,
+    pub location: PlayerLocation,
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_played: Option<DateTime<Utc>>,
+}
+
+/// What the player knows about recent listening which the library doesn't
+#[derive(Debug, Clone, Default, PartialEq, PartialOrd)]
+pub struct ListeningHints {
+    /// Songs played recently and where they were played from, most recent
+    /// first
+    pub recent: Vec<(Uuid, PlayerLocation)>,
+    /// Songs with a position saved to resume from
+    pub saved_positions: Vec<(Uuid, Duration)>,
+}
+
+/// Up to `limit` suggestions, the most recently played first
+pub fn continue_listening(
+    library: &MusicLibrary,
+    hints: &ListeningHints,
+    limit: usize,
+) -> Vec<ContinueEntry> {
+    let songs: HashMap<Uuid, &Song> = library
+        .library
+        .iter()
+        .map(|song| (song.uuid, song))
+        .collect();
+    let mut entries = unfinished_albums(library, limit);
+
+    // Only the last time each place was played from counts
+    let mut seen = Vec::new();
+    for (uuid, location) in &hints.recent {
+        if seen.contains(location) {
+            continue;
+        }
+        seen.push(*location);
+        if let Some(song) = songs.get(uuid) {
+            entries.extend(recent_entry(library, song, *location));
+        }
+    }
+
+    entries.extend(hints.saved_positions.iter().filter_map(|(uuid, position)| {
+        let song = songs.get(uuid)?;
+        Some(ContinueEntry {
+            kind: ContinueKind::Song,
+            name: title(song),
+            art: song.uuid,
+            resume: song.uuid,
+            position: *position,
+            location: PlayerLocation::Library,
+            last_played: song.last_played,
+        })
+    }));
+
+    entries.sort_by_key(|entry| Reverse(entry.last_played));
+    let mut resumed = HashSet::new();
+    entries.retain(|entry| resumed.insert(entry.resume));
+    entries.truncate(limit);
+    entries
+}
+
+/// Albums where the most recently played track was played right after the
+/// one before it, but isn't the last one
+fn unfinished_albums(library: &MusicLibrary, limit: usize) -> Vec<ContinueEntry> {
+    // The most recently played track of each album
+    let mut latest: HashMap<&String, &Song> = HashMap::new();
+    for song in &library.library {
+        let (Some(album), Some(_)) = (song.get_tag(&Tag::Album), song.last_played) else {
+            continue;
+        };
+        match latest.get(album) {
+            Some(last) if last.last_played >= song.last_played => (),
+            _ => {
+                latest.insert(album, song);
+            }
+        }
+    }
+
+    let mut tracks: HashMap<&String, Vec<&Song>> = HashMap::new();
+    for song in &library.library {
+        if let Some(album) = song.get_tag(&Tag::Album).filter(|a| latest.contains_key(a)) {
+            tracks.entry(album).or_default().push(song);
+        }
+    }
+
+    let mut candidates: Vec<(&String, &Song)> = latest.into_iter().collect();
+    candidates.sort_by_key(|(_, last)| Reverse(last.last_played));
+
+    let mut entries = Vec::new();
+    for (album, last) in candidates {
+        if entries.len() >= limit {
+            break;
+        }
+        let Some(mut tracks) = tracks.remove(album) else {
+            continue;
+        };
+        tracks.sort_by_key(|song| {
+            (
+                song.disc_number().unwrap_or(1),
+                song.track_number().unwrap_or_default(),
+            )
+        });
+        let Some(index) = tracks.iter().position(|song| song.uuid == last.uuid) else {
+            continue;
+        };
+        let Some(next) = tracks.get(index + 1) else {
+            continue;
+        };
+        let played_through = index
+            .checked_sub(1)
+            .and_then(|previous| tracks[previous].last_played)
+            .zip(last.last_played)
+            .is_some_and(|(previous, last)| {
+                previous <= last && last - previous <= ALBUM_SESSION_GAP
+            });
+        if !played_through {
+            continue;
+        }
+
+        entries.push(ContinueEntry {
+            kind: ContinueKind::Album,
+            name: album.clone(),
+            art: tracks[0].uuid,
+            resume: next.uuid,
+            position: Duration::ZERO,
+            location: PlayerLocation::Album,
+            last_played: last.last_played,
+        });
+    }
+    entries
+}
+
+/// Going back to where `song` was played from. A playlist stopped partway
+/// carries on from the next track, and otherwise starts over.
+fn recent_entry(
+    library: &MusicLibrary,
+    song: &Song,
+    location: PlayerLocation,
+) -> Option<ContinueEntry> {
+    let (kind, name, resume) = match location {
+        PlayerLocation::Playlist(uuid) => {
+            let playlist = library.playlists.query_uuid(&uuid)?;
+            let tracks = playlist.tracks();
+            let next = tracks
+                .iter()
+                .position(|track| *track == song.uuid)
+                .and_then(|index| tracks.get(index + 1));
+            match next {
+                Some(next) => (ContinueKind::Playlist, playlist.title.clone(), *next),
+                None => (
+                    ContinueKind::Recent,
+                    playlist.title.clone(),
+                    *tracks.first()?,
+                ),
+            }
+        }
+        PlayerLocation::Library => (ContinueKind::Recent, "Library".to_string(), song.uuid),
+        // Albums are found from the library, and the rest aren't places to
+        // go back to
+        _ => return None,
+    };
+
+    Some(ContinueEntry {
+        kind,
+        name,
+        art: resume,
+        resume,
+        position: Duration::ZERO,
+        location,
+        last_played: song.last_played,
+    })
+}
+
+fn title(song: &Song) -> String {
+    song.get_tag(&Tag::Title)
+        .cloned()
+        .unwrap_or_else(|| "Unknown Title".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use uuid::Uuid;
+
+    use super::{continue_listening, ContinueKind, ListeningHints};
+    use crate::{
+        music_controller::controller::PlayerLocation,
+        music_storage::{
+            library::{MusicLibrary, Song, Tag},
+            playlist::{Playlist, PlaylistFolderItem},
+        },
+    };
+
+    fn track(album: &str, number: u16, played: Option<DateTime<Utc>>) -> Song {
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            last_played: played,
+            ..Default::default()
+        };
+        song.set_tag(Tag::Album, album.to_string());
+        song.set_tag(Tag::Title, format!("{album} {number}"));
+        song.set_tag(Tag::Track, number.to_string());
+        song
+    }
+
+    #[test]
+    fn picks_up_where_it_stopped() {
+        let now = Utc::now();
+        let ago = |minutes| Some(now - TimeDelta::minutes(minutes));
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            // Stopped after the second track
+            track("Fantasia", 3, None),
+            track("Fantasia", 1, ago(20)),
+            track("Fantasia", 2, ago(16)),
+            // Played all the way through
+            track("Finished", 1, ago(300)),
+            track("Finished", 2, ago(296)),
+            // Only one song was picked out of it
+            track("Single", 4, ago(30)),
+            track("Single", 5, None),
+            track("Podcast", 1, ago(600)),
+        ];
+        let fantasia = lib.library[0].uuid;
+        let podcast = lib.library[7].uuid;
+
+        let mut playlist = Playlist::new();
+        playlist.title = String::from("Drive");
+        let finished = lib.library[4].uuid;
+        playlist.set_tracks(vec![lib.library[3].uuid, finished, lib.library[5].uuid]);
+        let drive = playlist.uuid;
+        lib.playlists.items.push(PlaylistFolderItem::List(playlist));
+
+        let hints = ListeningHints {
+            recent: vec![
+                (lib.library[2].uuid, PlayerLocation::Album),
+                (finished, PlayerLocation::Playlist(drive)),
+                (lib.library[3].uuid, PlayerLocation::Playlist(drive)),
+            ],
+            saved_positions: vec![(podcast, Duration::from_secs(1200))],
+        };
+
+        let entries = continue_listening(&lib, &hints, 10);
+        let kinds: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.kind, entry.name.as_str(), entry.resume))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (ContinueKind::Album, "Fantasia", fantasia),
+                (ContinueKind::Playlist, "Drive", lib.library[5].uuid),
+                (ContinueKind::Song, "Podcast 1", podcast),
+            ]
+        );
+        assert_eq!(entries[2].position, Duration::from_secs(1200));
+
+        assert_eq!(continue_listening(&lib, &hints, 1).len(), 1);
+    }
+}
@@ -7,26 +7,66 @@ use crossbeam::atomic::AtomicCell;
 use crossbeam_channel::{Receiver, Sender};
 use kushi::Queue;
 use kushi::{QueueError, QueueItem};
-use parking_lot::RwLock;
-use prismriver::{Error as PrismError, Prismriver};
+use parking_lot::{Mutex, RwLock};
+use prismriver::{Error as PrismError, Prismriver, State as PrismState};
 use serde::{Deserialize, Serialize};
-use serde_json::to_string_pretty;
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::config::ConfigError;
-use crate::music_storage::library::Song;
-use crate::music_storage::playlist::{ExternalPlaylist, Playlist};
+use crate::config::{ConfigError, OutputMode};
+use crate::music_storage::album_index::AlbumSummary;
+use crate::music_storage::cancel::CancelToken;
+use crate::music_storage::db_reader::extern_library::{ExternalSource, ImportSummary};
+use crate::music_storage::gain_analysis::{AnalyzeScope, GainAnalysis, GainProgress, GainValues};
+use crate::music_storage::gain_staging::{
+    ActiveProfile, AppliedPlayback, PlaybackProfile, SongGain,
+};
+use crate::music_storage::integrity::{FileHash, VerifyProgress, VerifyReport};
+use crate::music_storage::library::{
+    AlbumKey, RemoveMissingError, RemoveMissingOptions, Service, Song, Tag, URI,
+};
+use crate::music_storage::library_guard::{ConflictResolution, LibraryConflict, StatDelta};
+use crate::music_storage::organize::OrganizeReport;
+use crate::music_storage::play_history::OnThisDayYear;
+use crate::music_storage::playlist::{ExternalPlaylist, MissingResolution, Playlist, SortOrder};
+use crate::music_storage::scan_priority::{FolderPriorities, MultiLocationSong};
+use crate::music_storage::scan_report::{ScanError, ScanProgress, ScanReport};
+use crate::music_storage::search::{QueueMode, SearchCandidate, SearchMatch};
+use crate::music_storage::silence::SilenceBounds;
+use crate::music_storage::song_details::{RefreshReport, SongDetails};
+use crate::music_storage::song_links::LinkGroup;
+use crate::music_storage::song_problems::{FlaggedSong, ProblemReport, SongProblem};
+use crate::music_storage::tag_cleanup::{CleanRule, TagChange, TagCleanup};
+use crate::music_storage::tombstones::DanglingTracks;
 use crate::{config::Config, music_storage::library::MusicLibrary};
 
+use super::audio_device::{
+    DeviceAction, DeviceEvent, DeviceEventSource, DeviceNotification, PollingDeviceSource,
+};
 use super::connections::{ConnectionsInput, ConnectionsNotification, ControllerConnections};
+use super::continue_listening::{ContinueEntry, ListeningHints};
 use super::controller_handle::{LibraryCommandInput, PlayerCommandInput, QueueCommandInput};
-use super::queue::{QueueAlbum, QueueSong};
+use super::controller_state::{self, ControllerState, PlaybackModes};
+use super::icy::StreamTitle;
+use super::jobs::JobRegistry;
+use super::listen_counts::ListenCounts;
+use super::listen_time::ListenTimer;
+use super::now_playing::NowPlayingTracker;
+use super::output_mode::{NegotiatedOutput, OutputError, OutputSwitch};
+use super::player_command::SongChangeNotifier;
+use super::player_monitor::{SeekPosition, TrackDuration};
+use super::queue::{QueueAlbum, QueueInfo, QueueSong, Transition};
+use super::queue_log::QueueOp;
+use super::queue_store::{self, QueueStore};
+use super::remote_source::{RemoteError, RemoteSources};
+use super::save_scheduler;
+use super::scrobbles::ScrobbleCache;
 
 pub struct Controller();
 
@@ -34,11 +74,11 @@ type QueueItem_ = QueueItem<QueueSong, QueueAlbum>;
 
 #[derive(Error, Debug)]
 pub enum ControllerError {
-    #[error("{0:?}")]
+    #[error("{0}")]
     QueueError(#[from] QueueError),
-    #[error("{0:?}")]
+    #[error("{0}")]
     PlayerError(#[from] prismriver::Error),
-    #[error("{0:?}")]
+    #[error("{0}")]
     ConfigError(#[from] ConfigError),
 }
 
@@ -49,6 +89,7 @@ pub enum PlayerLocation {
     Test,
     Library,
     Playlist(Uuid),
+    Album,
     File,
     Custom,
 }
@@ -79,20 +120,138 @@ impl<Tx: Send, Rx: Send> MailMan<Tx, Rx> {
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum PlayerCommand {
     NextSong,
+    /// Moves on to the next song because the playing one ended, like
+    /// [`PlayerCommand::NextSong`] without the user asking for it
+    TrackFinished,
     PrevSong,
     Pause,
     Play,
     Stop,
-    Seek(i64),
-    Enqueue(usize),
+    /// Seeks to `time` milliseconds into the track which was playing when
+    /// the track epoch was `epoch`
+    Seek {
+        time: i64,
+        epoch: u64,
+    },
+    /// Shows `time` milliseconds as the position while the seekbar is being
+    /// dragged, without seeking until the [`PlayerCommand::Seek`] at the end
+    SeekPreview {
+        time: i64,
+        epoch: u64,
+    },
+    /// Plays the queue item at `index`, as long as the queue is still at
+    /// the `expected` revision
+    Enqueue {
+        index: usize,
+        expected: Option<u64>,
+    },
     SetVolume(f32),
-    PlayNow(Uuid, PlayerLocation),
+    /// Moves the volume by this many steps, down if negative
+    VolumeStep(i32),
+    /// Gives the player the volume limit from the config after it changed
+    SetVolumeLimit(Option<f32>),
+    /// Gets the volume, playback modes and other settings the player was
+    /// started with, along with the current song
+    GetState,
+    /// Writes any changes to the player's state straight away
+    FlushState,
+    /// Where songs were recently played from and the saved positions, for
+    /// suggesting what to continue listening to
+    ListeningHints,
+    /// Plays a song, followed by the songs after it where it's played from.
+    /// With `shuffle_remaining` those are shuffled, and the songs before it
+    /// are left out.
+    PlayNow {
+        uuid: Uuid,
+        location: PlayerLocation,
+        shuffle_remaining: bool,
+    },
+    /// Plays an explicit song in filtered mode, once the user confirmed it
+    PlayNowConfirmed {
+        uuid: Uuid,
+        location: PlayerLocation,
+        shuffle_remaining: bool,
+    },
+    PlayAlbum {
+        key: AlbumKey,
+        starting_track: Option<(u16, u16)>,
+    },
+    /// Plays an album from a cue sheet as the single file it comes from,
+    /// instead of loading each of its tracks separately
+    PlayCueAlbum(AlbumKey),
+    /// Sent by the player monitor when the position in a cue album crossed
+    /// from the track at index `from` into the track at index `to`
+    CueTrackChange {
+        from: usize,
+        to: usize,
+    },
+    /// Sent once the silence around the song which was playing when the
+    /// track epoch was `epoch` was found, the first time it played
+    SilenceFound {
+        epoch: u64,
+        uuid: Uuid,
+        silence: SilenceBounds,
+    },
+    PlayArtist(String),
+    PlayGenre(String),
+    DeviceEvent(DeviceEvent),
+    /// Jumps back to a played item, `1` being the previous one
+    PlayPlayed(usize),
+    /// Searches the library, queueing the result if there is a clear one
+    SearchAndQueue {
+        query: String,
+        mode: QueueMode,
+    },
+    GetPlaybackModes,
+    /// Sets the shuffle and repeat modes, picking the songs coming up again
+    /// if they changed
+    SetPlaybackModes(PlaybackModes),
+    /// Adds the tracks after the current one on its album, after the songs
+    /// added by hand
+    EnqueueRestOfAlbum,
+    /// Adds up to this many shuffled songs by the current song's artist
+    /// which aren't queued yet, after the songs added by hand
+    EnqueueMoreByArtist(usize),
+    /// Inserts an album's tracks right after the current song, kept
+    /// together even while shuffling
+    EnqueueAlbumNext(AlbumKey),
+    /// Switches between shared and exclusive output, reopening the output
+    /// for the playing song
+    SetOutputMode(OutputMode),
+    /// Queues every song matching a library filter, without sending the
+    /// songs back and forth
+    QueueFromFilter {
+        query: String,
+        target_tags: Vec<Tag>,
+        sort: Vec<Tag>,
+        mode: QueueMode,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum PlayerResponse {
     Empty(Result<(), PlayerError>),
-    NowPlaying(Result<Song, QueueError>),
+    NowPlaying(Result<Song, PlayerError>),
+    Device(Result<DeviceAction, PlayerError>),
+    SearchAndQueue(Result<SearchMatch, PlayerError>),
+    PlaybackModes(PlaybackModes),
+    /// The volume after a step
+    Volume(f32),
+    State(PlayerState),
+    ListeningHints(ListeningHints),
+    /// How many songs were added to the queue
+    Enqueued(Result<usize, PlayerError>),
+    OutputMode(Result<OutputSwitch, PlayerError>),
+}
+
+/// Why a queue command made against an older revision of the queue wasn't
+/// done
+#[derive(Error, Debug, PartialEq, Clone)]
+#[error("The queue has changed, it's at revision {revision} now")]
+pub struct QueueConflict {
+    pub revision: u64,
+    /// The queue as it is now, with the current item first
+    pub items: Vec<QueueItem_>,
 }
 
 #[derive(Error, Debug, PartialEq, Clone)]
@@ -100,43 +259,526 @@ pub enum PlayerError {
     #[error("{0}")]
     QueueError(#[from] QueueError),
     #[error("{0}")]
+    QueueConflict(#[from] QueueConflict),
+    #[error("{0}")]
     Prismriver(#[from] PrismError),
+    #[error("No songs found for {0}")]
+    NoSongsFound(String),
+    #[error("Can't play '{}': unsupported format", file_name(.path))]
+    UnsupportedFormat { path: PathBuf, details: String },
+    #[error("Can't play '{}': the file is missing", file_name(.path))]
+    FileMissing { path: PathBuf },
+    #[error("The audio device is unavailable: {0}")]
+    DeviceUnavailable(String),
+    #[error("Can't seek to {requested}ms, the song is only {duration}ms long")]
+    SeekOutOfRange { requested: i64, duration: i64 },
+    #[error("The command was meant for an earlier track")]
+    StaleCommand { issued: u64, current: u64 },
+    #[error("Nothing is playing")]
+    NothingPlaying,
+    #[error("The song playing has no {0} tag")]
+    MissingTag(String),
+    #[error("'{0}' isn't a cue sheet album of a single file")]
+    NotCueAlbum(String),
+    #[error("{0}")]
+    Remote(#[from] RemoteError),
+    #[error("The song {0} isn't in the library")]
+    SongNotFound(Uuid),
+    #[error("The song is explicit, so playing it has to be confirmed")]
+    NeedsConfirmation(Uuid),
+    #[error("{0}")]
+    Output(#[from] OutputError),
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+impl PlayerError {
+    /// Picks the most specific error for a song at `path` which failed to
+    /// load, using the details given by the player
+    pub fn from_load(path: &Path, details: String) -> Self {
+        if !path.try_exists().unwrap_or(false) {
+            return PlayerError::FileMissing {
+                path: path.to_path_buf(),
+            };
+        }
+
+        let lowercase = details.to_lowercase();
+        if ["audio device", "audio sink", "output device"]
+            .iter()
+            .any(|device| lowercase.contains(device))
+        {
+            PlayerError::DeviceUnavailable(details)
+        } else {
+            PlayerError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                details,
+            }
+        }
+    }
+
+    /// Checks that a seek to `requested` milliseconds lands inside the song
+    pub fn check_seek(requested: i64, duration: Option<TimeDelta>) -> Result<(), Self> {
+        let duration = duration.map(|d| d.num_milliseconds());
+        match duration {
+            Some(duration) if requested < 0 || requested > duration => {
+                Err(PlayerError::SeekOutOfRange {
+                    requested,
+                    duration,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a command issued during track epoch `issued` is still
+    /// meant for the current track
+    pub fn check_epoch(issued: u64, current: u64) -> Result<(), Self> {
+        match issued == current {
+            true => Ok(()),
+            false => Err(PlayerError::StaleCommand { issued, current }),
+        }
+    }
+}
+
+/// A queued song which was skipped because its file disappeared before it
+/// could play
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedSong {
+    pub uuid: Uuid,
+    pub title: String,
+    #[serde(with = "crate::music_storage::os_path")]
+    pub path: PathBuf,
+}
+
+impl SkippedSong {
+    pub fn new(song: &Song, path: PathBuf) -> Self {
+        SkippedSong {
+            uuid: song.uuid,
+            title: song
+                .get_tag(&Tag::Title)
+                .cloned()
+                .unwrap_or_else(|| file_name(&path)),
+            path,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        format!("Skipped '{}' \u{2014} file not found", self.title)
+    }
+}
+
+/// A song which couldn't be loaded, along with what the player was left
+/// doing afterwards
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoadFailure {
+    pub uuid: Uuid,
+    pub title: String,
+    pub message: String,
+    pub state: PlaybackState,
+}
+
+impl LoadFailure {
+    pub fn new(song: &Song, error: &PlayerError, state: PlaybackState) -> Self {
+        LoadFailure {
+            uuid: song.uuid,
+            title: song
+                .get_tag(&Tag::Title)
+                .cloned()
+                .or_else(|| song.location.first().map(|uri| file_name(&uri.path())))
+                .unwrap_or_default(),
+            message: error.to_string(),
+            state,
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum LibraryCommand {
     Song(Uuid),
+    /// The song whose art is shown for a song, see
+    /// [`AlbumArtIndex`](crate::music_storage::art_fallback::AlbumArtIndex)
+    ArtSource(Uuid),
+    /// A summary of every album for the album grid, see
+    /// [`AlbumIndex`](crate::music_storage::album_index::AlbumIndex)
+    Albums,
+    /// Many songs at once, in the same order, leaving out ones which aren't
+    /// in the library
+    SongsBulk(Vec<Uuid>),
     AllSongs,
+    /// The uuids of every song, in library order
+    AllUuids,
+    /// The uuids and durations of the songs matching a library filter,
+    /// sorted by `sort`
+    QueryUuids {
+        query: String,
+        target_tags: Vec<Tag>,
+        sort: Vec<Tag>,
+        filtered: bool,
+    },
+    /// The uuids of a playlist's songs, in its sort order
+    PlaylistUuids(Uuid),
     GetLibrary,
     ExternalPlaylist(Uuid),
     Playlist(Uuid),
     ImportM3UPlayList(PathBuf),
+    /// Adds the songs and playlists from another player's library
+    ImportExternal(ExternalSource, PathBuf),
+    /// Marks the library as changed, saving it within a few seconds
     Save,
+    /// Saves the library right away
+    Flush,
+    /// Saves the library if changes have waited long enough
+    SaveIfDue,
+    ResolveConflict(ConflictResolution),
+    UpdateStats(Uuid, StatDelta),
+    /// The songs first or heavily played on this calendar day in years
+    /// before this one, see [`MusicLibrary::on_this_day`]
+    OnThisDay {
+        month: u32,
+        day: u32,
+    },
+    /// Adds to how long a playlist has been listened to
+    AddPlaylistTime(Uuid, Duration),
+    /// Removes songs whose files are missing, or only finds them if
+    /// `dry_run` is set
+    RemoveMissing {
+        dry_run: bool,
+    },
+    /// Removes the songs at locations found missing, sent by `RemoveMissing`
+    /// once it has checked them again
+    RemoveFoundMissing(Vec<URI>, RemoveMissingOptions),
+    /// Puts back the songs taken out by the last `RemoveMissing`
+    UndoRemoveMissing,
+    /// Removes the songs the library's scan exclusions would have left out,
+    /// or only finds them if `dry_run`. They're put back by
+    /// `UndoRemoveMissing`.
+    RemoveExcluded {
+        dry_run: bool,
+    },
+    /// Finds the songs in playlists which aren't in the library, taking
+    /// them out if `prune`
+    RepairPlaylists {
+        prune: bool,
+    },
     Playlists,
+    GenerateAutoPlaylists,
+    PinAutoPlaylist(Uuid),
+    PlaylistSetSortOrder {
+        uuid: Uuid,
+        order: SortOrder,
+    },
+    PlaylistProfile(Uuid),
+    /// Sets the playback settings used while playing from a playlist
+    SetPlaylistProfile(Uuid, Option<PlaybackProfile>),
+    /// Replaces a track which couldn't be found when a playlist was
+    /// imported
+    ResolveMissingTrack {
+        playlist: Uuid,
+        index: usize,
+        to: MissingResolution,
+    },
+    MissingTrackMatches {
+        playlist: Uuid,
+        index: usize,
+    },
+    AlbumTracks(AlbumKey, Option<(u16, u16)>),
+    ArtistSongs(String),
+    GenreSongs(String),
+    Waveform {
+        uuid: Uuid,
+        buckets: usize,
+    },
+    ScanReport,
+    RetryScan(PathBuf),
+    /// Scans the library's folders for new songs, unless a scan of it is
+    /// already running
+    Scan,
+    /// Adds the songs a scan found, sent by the scan itself. With the
+    /// priorities of the scan folders, songs already in the library are
+    /// added to as another location.
+    AddScanned(Vec<Song>, Option<FolderPriorities>),
+    MultiLocationSongs,
+    DropLocation(Uuid, URI),
+    CleanTags {
+        rules: Vec<CleanRule>,
+        dry_run: bool,
+        write_back: bool,
+    },
+    /// Fills in the songs' tags from their file names, see
+    /// [`FilenamePattern`](crate::music_storage::filename_pattern::FilenamePattern)
+    ApplyFilenamePattern {
+        uuids: Vec<Uuid>,
+        pattern: String,
+        overwrite: bool,
+        dry_run: bool,
+    },
+    AnalyzeGain {
+        scope: AnalyzeScope,
+        force: bool,
+    },
+    ApplyGain {
+        values: Vec<(Uuid, GainValues)>,
+        write_back: bool,
+    },
+    /// Checks the files of the songs in `scope` against their stored
+    /// hashes, also checking the audio against the file's own checksum with
+    /// `deep`
+    VerifyFiles {
+        scope: AnalyzeScope,
+        deep: bool,
+    },
+    StoreHashes(Vec<(Uuid, FileHash)>),
+    /// Checks the songs in `scope` for being silent or having a broken
+    /// length
+    CheckSongs(AnalyzeScope),
+    SetProblems(Vec<(Uuid, Option<SongProblem>)>),
+    FlaggedSongs,
+    SongDetails(Uuid),
+    RereadSong(Uuid),
+    /// Reads songs from their files again, after their tags were changed
+    /// by another program
+    RefreshSongs(Vec<Uuid>),
+    /// Reads every song in an album from its file again
+    RefreshAlbum(AlbumKey),
+    /// Saves the tags of a song's file again in the configured format
+    RetagFileFormat(Uuid),
+    Search(String),
+    /// Picks which of a song's images to show, `None` going back to the
+    /// front cover
+    SetPreferredArt(Uuid, Option<usize>),
+    /// Sets where a song starts and ends playing, `None` playing all of it
+    SetTrim(Uuid, Option<(Duration, Duration)>),
+    /// Keeps where the sound in a song starts and ends, found by the player
+    /// the first time it played the song
+    SetSilence(Uuid, SilenceBounds),
+    /// Marks a song as explicit or not, `None` going by its tags
+    SetExplicit(Uuid, Option<bool>),
+    /// Suggestions for picking up where listening was left off
+    ContinueListening {
+        limit: usize,
+        hints: ListeningHints,
+    },
+    /// Rates the songs in an album which weren't rated on their own
+    SetAlbumRating {
+        key: AlbumKey,
+        rating: Option<u8>,
+    },
+    /// Favorites the songs in an album which weren't favorited on their own
+    SetAlbumFavorite {
+        key: AlbumKey,
+        favorited: bool,
+    },
+    /// Marks an album to always play without crossfading between its tracks
+    SetAlbumGapless {
+        key: AlbumKey,
+        gapless: bool,
+    },
+    /// Adds a song played from a [`Service`], with tags looked up by its
+    /// [`RemoteSource`](super::remote_source::RemoteSource)
+    AddRemoteSong {
+        service: Service,
+        location: String,
+        tags: BTreeMap<Tag, String>,
+    },
+    AddRadioStation {
+        name: String,
+        url: String,
+        genre: Option<String>,
+    },
+    RadioStations,
+    /// Finds songs which look like other versions of a song, like its
+    /// instrumental, without linking them
+    DetectLinkedVersions,
+    /// Links the versions in each group to their main song
+    LinkVersions(Vec<LinkGroup>),
+    /// Notes that a song's file couldn't be found when it was about to play
+    MarkMissing(Uuid),
+    /// Where a song is in the songs it's being played from, for the library
+    /// and playlists which are queued a few songs at a time
+    SourcePosition(Uuid, PlayerLocation),
+    /// Moves song files into the library folder following `pattern`, or
+    /// only shows where they would go with `dry_run`
+    OrganizeFiles {
+        pattern: String,
+        scope: AnalyzeScope,
+        dry_run: bool,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub enum LibraryResponse {
     Ok,
-    Song(Song, usize),
+    /// The song and its index, if it's in the library
+    Song(Option<(Song, usize)>),
+    ArtSource(Option<Song>),
+    Albums(Arc<Vec<AlbumSummary>>),
+    SongsBulk(Vec<Arc<Song>>),
     AllSongs(Vec<Song>),
+    AllUuids(Vec<Uuid>),
+    QueryUuids(Vec<(Uuid, Duration)>),
+    PlaylistUuids(Option<Vec<Uuid>>),
     Library(MusicLibrary),
     ExternalPlaylist(ExternalPlaylist),
     Playlist(Playlist),
     ImportM3UPlayList(Uuid, String),
+    ImportExternal(Result<ImportSummary, String>),
     Playlists(Vec<(Uuid, String)>),
+    AutoPlaylists(Vec<(Uuid, String)>),
+    PinAutoPlaylist(Result<(), String>),
+    ResolveConflict(Result<(), String>),
+    OnThisDay(Vec<OnThisDayYear>),
+    /// The songs which were removed, or would be for a dry run
+    RemoveMissing(Result<Vec<Song>, RemoveMissingError>),
+    /// How many songs were put back
+    UndoRemoveMissing(usize),
+    RemoveExcluded(Result<Vec<Song>, String>),
+    RepairPlaylists(Vec<DanglingTracks>),
+    /// The UUID of the added song
+    AddRemoteSong(Result<Uuid, String>),
+    /// The UUID of the station's song
+    AddRadioStation(Result<Uuid, String>),
+    RadioStations(Vec<Song>),
+    DetectLinkedVersions(Vec<LinkGroup>),
+    /// How many versions were linked
+    LinkVersions(usize),
+    PlaylistSetSortOrder(Result<(), String>),
+    PlaylistProfile(Option<PlaybackProfile>),
+    SetPlaylistProfile(Result<(), String>),
+    ResolveMissingTrack(Result<Uuid, String>),
+    MissingTrackMatches(Vec<SearchCandidate>),
+    AlbumTracks(Vec<Song>),
+    Songs(Vec<Song>),
+    /// Sent any number of times before [`LibraryResponse::Waveform`]
+    WaveformProgress(f32),
+    Waveform(Result<Vec<u8>, String>),
+    ScanReport(ScanReport),
+    RetryScan(Result<ScanReport, ScanError>),
+    /// Sent first for a scan. `attached` is set when a scan was already
+    /// running, and nothing else is sent.
+    ScanStarted {
+        job: Uuid,
+        attached: bool,
+    },
+    /// Sent after each file is looked at, before [`LibraryResponse::Scan`]
+    ScanProgress(ScanProgress),
+    Scan(Result<ScanReport, String>),
+    /// The number of songs added, and the number added as locations
+    AddScanned(usize, usize),
+    MultiLocationSongs(Vec<MultiLocationSong>),
+    DropLocation(Result<(), String>),
+    CleanTags(TagCleanup),
+    ApplyFilenamePattern(Result<Vec<TagChange>, String>),
+    /// Sent after each song is measured, before [`LibraryResponse::AnalyzeGain`]
+    GainProgress(GainProgress),
+    AnalyzeGain(Result<GainAnalysis, String>),
+    ApplyGain(Vec<(PathBuf, String)>),
+    /// Sent after each file is checked, before [`LibraryResponse::VerifyFiles`]
+    VerifyProgress(VerifyProgress),
+    VerifyFiles(Result<VerifyReport, String>),
+    StoreHashes,
+    /// Sent after each song is checked, before [`LibraryResponse::CheckSongs`]
+    CheckProgress(VerifyProgress),
+    CheckSongs(Result<ProblemReport, String>),
+    SetProblems,
+    FlaggedSongs(Vec<FlaggedSong>),
+    SongDetails(Result<Box<SongDetails>, String>),
+    RereadSong(Result<Song, String>),
+    RefreshSongs(RefreshReport),
+    RefreshAlbum(Result<RefreshReport, String>),
+    RetagFileFormat(Result<(), String>),
+    Search(SearchMatch),
+    ContinueListening(Vec<ContinueEntry>),
+    SetPreferredArt(Result<(), String>),
+    SetTrim(Result<(), String>),
+    SetExplicit(Result<(), String>),
+    SetAlbumRating(Result<(), String>),
+    SetAlbumFavorite(Result<(), String>),
+    SetAlbumGapless(Result<(), String>),
+    OrganizeFiles(Result<OrganizeReport, String>),
+    /// The index of the song and how many songs there are
+    SourcePosition(Option<(usize, usize)>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum QueueCommand {
     Append(QueueItem_, bool),
+    /// Inserts an item to play right after the current one
+    AppendNext(QueueItem_),
+    /// Inserts items to play right after the current one, which stay
+    /// together in order even when the queue is shuffled
+    AppendBlockNext(Vec<QueueItem_>),
     Next,
     Prev,
     GetIndex(usize),
     NowPlaying,
     Get,
+    /// The most recently played items, oldest first
+    GetPlayed(usize),
+    Back(usize),
     Clear,
     Remove(usize),
+    /// Moves the item at `from` so it ends up at `to`
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// Randomly reorders everything after the current item and the songs
+    /// waiting to be queued up, once
+    ShuffleRemaining,
+    /// Replaces the items coming up which weren't added by hand
+    ReplaceUpNext(Vec<QueueItem_>),
+    Counts,
+    /// Sets how long the songs which haven't been queued up yet take to
+    /// play. Items appended without being added by hand are taken out of it.
+    SetPool(Duration),
+    /// Adds songs waiting to be queued up, which are used before anything
+    /// else when the queue needs another song. `pool` is how long they take
+    /// to play. [`QueueMode::PlayNow`] replaces the ones already waiting,
+    /// [`QueueMode::PlayNext`] puts them in front and [`QueueMode::Append`]
+    /// after. Clearing the queue drops them.
+    SetPending {
+        uuids: Vec<Uuid>,
+        pool: Duration,
+        mode: QueueMode,
+    },
+    /// Takes the next of the songs waiting to be queued up
+    TakePending,
+    /// How long the queue takes to play
+    Info,
+    /// Sets how the song before the item at `index` goes into it, or goes
+    /// back to the profile's crossfade with `None`
+    SetTransition {
+        index: usize,
+        transition: Option<Transition>,
+    },
+    /// The queue's revision, and what changed since the given one
+    Changes(u64),
+    /// Does `command` only if the queue is still at `revision`, and
+    /// responds with [`QueueResponse::Conflict`] otherwise. For commands
+    /// made against a view of the queue which may be out of date, like an
+    /// index the user clicked just as the queue moved on.
+    Expect {
+        revision: u64,
+        command: Box<QueueCommand>,
+    },
+}
+
+impl QueueCommand {
+    /// The command, checked against `revision` if there is one
+    pub fn expecting(self, revision: Option<u64>) -> Self {
+        match revision {
+            Some(revision) => QueueCommand::Expect {
+                revision,
+                command: Box::new(self),
+            },
+            None => self,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -144,6 +786,24 @@ pub enum QueueResponse {
     Empty(Result<(), QueueError>),
     Item(Result<QueueItem_, QueueError>),
     GetAll(Vec<QueueItem_>),
+    /// How many items have been played, how many are in the queue counting
+    /// the current one, and how many of those coming up weren't added by hand
+    Counts {
+        played: usize,
+        queued: usize,
+        automatic: usize,
+    },
+    Info(QueueInfo),
+    Pending(Option<Uuid>),
+    /// `None` if the changes were forgotten, so the whole queue has to be
+    /// fetched again
+    Changes {
+        revision: u64,
+        ops: Option<Vec<QueueOp>>,
+    },
+    /// Sent instead of a command's own response when the queue wasn't at
+    /// the revision it expected
+    Conflict(QueueConflict),
 }
 
 pub struct ControllerInput {
@@ -162,37 +822,107 @@ pub struct ControllerInput {
     library: MusicLibrary,
     config: Arc<RwLock<Config>>,
     playback_info: Arc<AtomicCell<PlaybackInfo>>,
-    notify_next_song: Sender<Song>,
+    track_epoch: Arc<AtomicU64>,
+    notify_next_song: Sender<PlayerNotification>,
     connections: Option<ConnectionsInput>,
+    scrobbles: Arc<RwLock<ScrobbleCache>>,
+    device_events: Option<Box<dyn DeviceEventSource>>,
+    notify_device: Sender<DeviceNotification>,
+    notify_conflict: Sender<LibraryConflict>,
+    notify_modes: Sender<PlaybackModes>,
+    notify_skipped: Sender<SkippedSong>,
+    remote_sources: Arc<RwLock<RemoteSources>>,
+    state: Arc<Mutex<ControllerState>>,
+    cancel: CancelToken,
+    jobs: JobRegistry,
+    now_playing: NowPlayingTracker,
+}
+
+impl ControllerInput {
+    /// Replaces where audio device changes come from, which is the system's
+    /// outputs by default. `None` stops watching for changes at all.
+    pub fn with_device_events(mut self, source: Option<Box<dyn DeviceEventSource>>) -> Self {
+        self.device_events = source;
+        self
+    }
 }
 
+#[derive(Clone)]
 pub struct ControllerHandle {
     pub(super) lib_mail_rx: async_channel::Sender<LibraryCommandInput>,
     pub(super) player_mail_rx: async_channel::Sender<PlayerCommandInput>,
     pub(super) queue_mail_rx: async_channel::Sender<QueueCommandInput>,
+    pub(super) scrobbles: Arc<RwLock<ScrobbleCache>>,
+    #[cfg_attr(not(feature = "connections"), allow(dead_code))]
+    pub(super) listen_counts: Arc<Mutex<ListenCounts>>,
+    pub(super) config: Arc<RwLock<Config>>,
+    pub(super) remote_sources: Arc<RwLock<RemoteSources>>,
+    pub(super) state: Arc<Mutex<ControllerState>>,
+    /// Stops the library's long jobs when the player is closing
+    pub(super) cancel: CancelToken,
+    pub(super) jobs: JobRegistry,
+    pub(super) now_playing: NowPlayingTracker,
 }
 
 impl ControllerHandle {
+    /// Sets up a controller for `library`. `cancel` stops its long jobs,
+    /// and should be the one used for anything done to the library before.
+    #[allow(clippy::type_complexity)]
     pub fn new(
         library: MusicLibrary,
         config: Arc<RwLock<Config>>,
         connections: Option<ConnectionsInput>,
+        cancel: CancelToken,
     ) -> (
         Self,
         ControllerInput,
         Arc<AtomicCell<PlaybackInfo>>,
-        Receiver<Song>,
+        Receiver<PlayerNotification>,
+        Receiver<DeviceNotification>,
+        Receiver<LibraryConflict>,
+        Receiver<PlaybackModes>,
+        Receiver<SkippedSong>,
     ) {
         let (lib_mail_rx, lib_mail_tx) = async_channel::unbounded();
         let (player_mail_rx, player_mail_tx) = async_channel::unbounded();
         let (queue_mail_rx, queue_mail_tx) = async_channel::unbounded();
         let playback_info = Arc::new(AtomicCell::new(PlaybackInfo::default()));
-        let notify_next_song = crossbeam::channel::unbounded::<Song>();
+        let track_epoch = Arc::new(AtomicU64::new(0));
+        let notify_next_song = crossbeam::channel::unbounded::<PlayerNotification>();
+        let notify_device = crossbeam::channel::unbounded::<DeviceNotification>();
+        let notify_conflict = crossbeam::channel::unbounded::<LibraryConflict>();
+        let notify_modes = crossbeam::channel::unbounded::<PlaybackModes>();
+        let notify_skipped = crossbeam::channel::unbounded::<SkippedSong>();
+        let scrobbles = Arc::new(RwLock::new(ScrobbleCache::load(
+            config.read().path.with_file_name("scrobble_cache.json"),
+        )));
+        let remote_sources = Arc::new(RwLock::new(RemoteSources::default()));
+        let mut state = {
+            let path = &config.read().state_path;
+            if let Ok(state) = ControllerState::read_file(path) {
+                state
+            } else {
+                ControllerState::new(path.clone())
+            }
+        };
+        let now_playing = NowPlayingTracker::new(state.playback_modes());
+        // The saved volume may be over a limit set since
+        state.set_volume_limit(config.read().playback.volume_limit);
+        let state = Arc::new(Mutex::new(state));
+        let jobs = JobRegistry::new();
         (
             ControllerHandle {
                 lib_mail_rx: lib_mail_rx.clone(),
                 player_mail_rx: player_mail_rx.clone(),
                 queue_mail_rx: queue_mail_rx.clone(),
+                scrobbles: Arc::clone(&scrobbles),
+                listen_counts: Arc::new(Mutex::new(ListenCounts::default())),
+                config: Arc::clone(&config),
+                remote_sources: Arc::clone(&remote_sources),
+                state: Arc::clone(&state),
+                cancel: cancel.clone(),
+                jobs: jobs.clone(),
+                now_playing: now_playing.clone(),
             },
             ControllerInput {
                 player_mail: (player_mail_rx, player_mail_tx),
@@ -201,48 +931,31 @@ impl ControllerHandle {
                 library,
                 config,
                 playback_info: Arc::clone(&playback_info),
+                track_epoch,
                 notify_next_song: notify_next_song.0,
                 connections,
+                scrobbles,
+                device_events: Some(Box::new(PollingDeviceSource::default())),
+                notify_device: notify_device.0,
+                notify_conflict: notify_conflict.0,
+                notify_modes: notify_modes.0,
+                notify_skipped: notify_skipped.0,
+                remote_sources,
+                state,
+                cancel,
+                jobs,
+                now_playing,
             },
             playback_info,
             notify_next_song.1,
+            notify_device.1,
+            notify_conflict.1,
+            notify_modes.1,
+            notify_skipped.1,
         )
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct ControllerState {
-    pub(super) path: PathBuf,
-    pub(super) volume: f32,
-    pub(super) now_playing: Uuid,
-}
-
-impl ControllerState {
-    pub(super) fn new(path: PathBuf) -> Self {
-        ControllerState {
-            path,
-            volume: 0.35,
-            ..Default::default()
-        }
-    }
-
-    pub(super) fn write_file(&self) -> Result<(), std::io::Error> {
-        OpenOptions::new()
-            .truncate(true)
-            .create(true)
-            .write(true)
-            .open(&self.path)
-            .unwrap()
-            .write_all(&to_string_pretty(self)?.into_bytes())?;
-        Ok(())
-    }
-
-    pub(super) fn read_file(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
-        let state = serde_json::from_str(&std::fs::read_to_string(path)?)?;
-        Ok(state)
-    }
-}
-
 #[allow(unused_variables)]
 impl Controller {
     pub async fn start(
@@ -253,25 +966,38 @@ impl Controller {
             mut library,
             config,
             playback_info,
+            track_epoch,
             notify_next_song,
             connections,
+            scrobbles,
+            device_events,
+            notify_device,
+            notify_conflict,
+            notify_modes,
+            notify_skipped,
+            remote_sources,
+            state,
+            cancel,
+            jobs,
+            now_playing,
         }: ControllerInput,
     ) -> Result<(), Box<dyn Error>> {
-        let queue: Queue<QueueSong, QueueAlbum> = Queue {
-            items: Vec::new(),
-            played: Vec::new(),
-            loop_: false,
-            shuffle: None,
-        };
+        // The queue is picked up where it was left, with whichever songs
+        // are still in the library
+        let queue_store = QueueStore::new(config.read().path.with_file_name("queue"));
+        let queue: Queue<QueueSong, QueueAlbum> = queue_store
+            .load()
+            .map(|saved| saved.restore(&library))
+            .unwrap_or_else(|| Queue::new(false, None));
+        let (saved_queue_tx, saved_queue) = crossbeam_channel::unbounded();
 
-        let state = {
-            let path = &config.read().state_path;
-            if let Ok(state) = ControllerState::read_file(path) {
-                state
-            } else {
-                ControllerState::new(path.clone())
-            }
-        };
+        let track_duration = Arc::new(Mutex::new(TrackDuration::default()));
+        let applied = Arc::new(Mutex::new(AppliedPlayback::default()));
+        let queue_position = Arc::new(Mutex::new(None));
+        let cue_session = Arc::new(Mutex::new(None));
+        let seek_position = Arc::new(Mutex::new(SeekPosition::default()));
+        let output = Arc::new(Mutex::new(NegotiatedOutput::default()));
+        let listen_timer = Arc::new(Mutex::new(ListenTimer::default()));
 
         std::thread::scope(|scope| {
             let player = Prismriver::new();
@@ -282,10 +1008,30 @@ impl Controller {
             let (notifications_rx, notifications_tx) =
                 crossbeam_channel::unbounded::<ConnectionsNotification>();
 
+            let saver_mail = lib_mail.0.clone();
+            let queue_lib_mail = lib_mail.0.clone();
             let a = scope.spawn({
                 let queue_mail = queue_mail.clone();
-                let _notifications_rx = notifications_rx.clone();
                 let _config = config.clone();
+                let player_epoch = track_epoch.clone();
+                let state = Arc::clone(&state);
+                let player_duration = Arc::clone(&track_duration);
+                let player_applied = Arc::clone(&applied);
+                let player_cue = Arc::clone(&cue_session);
+                let player_seek = Arc::clone(&seek_position);
+                let player_output = Arc::clone(&output);
+                let song_changes = SongChangeNotifier {
+                    connections: notifications_rx.clone(),
+                    next_song: notify_next_song.clone(),
+                    player_state: Arc::clone(&player_state),
+                    position: Arc::clone(&queue_position),
+                    track_epoch: Arc::clone(&track_epoch),
+                    listen_timer: Arc::clone(&listen_timer),
+                    current: Arc::default(),
+                    config: config.clone(),
+                    player_mail: player_mail.0.clone(),
+                    now_playing: now_playing.clone(),
+                };
                 move || {
                     futures::executor::block_on(async {
                         moro::async_scope!(|scope| {
@@ -293,22 +1039,42 @@ impl Controller {
 
                             let _lib_mail = lib_mail.0.clone();
                             let _queue_mail = queue_mail.0.clone();
+                            let player_config = _config.clone();
                             scope.spawn(async move {
                                 Controller::player_command_loop(
                                     player,
                                     player_mail.1,
                                     _queue_mail,
                                     _lib_mail,
-                                    _notifications_rx,
+                                    song_changes,
                                     state,
+                                    player_config,
+                                    player_epoch,
+                                    player_duration,
+                                    notify_modes,
+                                    notify_skipped,
+                                    player_applied,
+                                    player_cue,
+                                    remote_sources,
+                                    player_seek,
+                                    player_output,
                                 )
                                 .await
                                 .unwrap();
                             });
+                            let own_mail = lib_mail.0.clone();
                             scope.spawn(async {
-                                Controller::library_loop(lib_mail.1, &mut library, _config)
-                                    .await
-                                    .unwrap();
+                                Controller::library_loop(
+                                    lib_mail.1,
+                                    own_mail,
+                                    &mut library,
+                                    _config,
+                                    notify_conflict,
+                                    cancel,
+                                    jobs,
+                                )
+                                .await
+                                .unwrap();
                             });
                         })
                         .await;
@@ -316,12 +1082,31 @@ impl Controller {
                 }
             });
 
+            let queue_config = config.clone();
             let b = scope.spawn(|| {
                 futures::executor::block_on(async {
-                    Controller::queue_loop(queue, queue_mail.1).await;
+                    Controller::queue_loop(
+                        queue,
+                        queue_mail.1,
+                        queue_config,
+                        Some(queue_lib_mail),
+                        Some(saved_queue_tx),
+                    )
+                    .await;
                 })
             });
 
+            scope.spawn(move || controller_state::state_saver_loop(state));
+            scope.spawn(move || queue_store::queue_saver_loop(queue_store, saved_queue));
+            scope.spawn(move || save_scheduler::library_saver_loop(saver_mail));
+
+            if let Some(source) = device_events {
+                let player_mail = player_mail.0.clone();
+                scope.spawn(move || {
+                    Controller::device_monitor_loop(source, player_mail, notify_device);
+                });
+            }
+
             let c = scope.spawn(|| {
                 Controller::player_monitor_loop(
                     player_state,
@@ -329,9 +1114,19 @@ impl Controller {
                     about_to_finish_tx,
                     finished_tx,
                     player_mail.0,
-                    notify_next_song,
+                    queue_mail.0,
                     notifications_rx,
+                    notify_next_song,
                     playback_info,
+                    track_epoch,
+                    track_duration,
+                    applied,
+                    queue_position,
+                    cue_session,
+                    seek_position,
+                    output,
+                    listen_timer,
+                    now_playing,
                 )
                 .unwrap();
             });
@@ -344,6 +1139,7 @@ impl Controller {
                         ControllerConnections {
                             notifications_tx,
                             inner,
+                            scrobbles,
                         },
                     );
                 });
@@ -357,8 +1153,205 @@ impl Controller {
     }
 }
 
+/// The player's settings and current song, for a frontend to show before
+/// anything has changed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerState {
+    /// The position of the volume slider, from 0 to 1
+    pub volume: f32,
+    /// The furthest the slider goes, below 1 when the volume is limited
+    pub max_volume: f32,
+    pub muted: bool,
+    pub modes: PlaybackModes,
+    pub now_playing: Option<Song>,
+}
+
+impl PlayerState {
+    pub fn new(state: &ControllerState, now_playing: Option<Song>) -> Self {
+        PlayerState {
+            volume: state.volume(),
+            max_volume: state.max_volume(),
+            muted: state.muted(),
+            modes: state.playback_modes(),
+            now_playing,
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Clone)]
 pub struct PlaybackInfo {
     pub position: Option<TimeDelta>,
     pub duration: Option<TimeDelta>,
+    /// The track epoch this position belongs to, for sending with seeks
+    pub epoch: u64,
+    /// The playlist or library profile being applied, if any
+    pub profile: Option<ActiveProfile>,
+    /// How much the playing song's volume is changed, for debugging
+    pub gain: Option<SongGain>,
+    pub queue_position: Option<QueuePosition>,
+    /// The output mode in use and the rate the device runs at
+    pub output: NegotiatedOutput,
+    /// How long the playing song has been listened to, leaving out pauses
+    /// and the parts skipped by seeking
+    pub listened: TimeDelta,
+}
+
+/// Where the playing song is in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QueuePosition {
+    /// The index of the song among the played and queued songs
+    pub index: usize,
+    /// How many songs there are in total, counting the songs from the
+    /// library or a playlist which haven't been queued up yet
+    pub total: usize,
+    pub location: PlayerLocation,
+}
+
+/// Why a different song started playing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TrackChangeReason {
+    /// The last one ended
+    Natural,
+    UserNext,
+    UserPrev,
+    /// The user picked a song from the queue or what was played before
+    UserJump,
+    /// Something new was played, replacing the queue
+    PlayNow,
+    /// Songs which couldn't be played were skipped to get to it
+    Recovered,
+}
+
+impl TrackChangeReason {
+    /// Whether the user changed the song, rather than the player
+    pub fn by_user(self) -> bool {
+        !matches!(self, Self::Natural | Self::Recovered)
+    }
+}
+
+/// Whether the player is playing, as shown by the play button. Buffering
+/// counts as playing, since the song carries on once it has buffered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    #[default]
+    Stopped,
+}
+
+impl From<&PrismState> for PlaybackState {
+    fn from(state: &PrismState) -> Self {
+        match state {
+            PrismState::Playing | PrismState::Buffering(_) => PlaybackState::Playing,
+            PrismState::Paused => PlaybackState::Paused,
+            _ => PlaybackState::Stopped,
+        }
+    }
+}
+
+/// What the frontend is told about the player, so it never has to guess
+/// whether something is playing
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerNotification {
+    /// A song started, with what the player was doing once it had loaded
+    SongChange(Box<NowPlayingChange>, PlaybackState),
+    /// The player started, paused or stopped
+    State(PlaybackState),
+    /// A song couldn't be loaded, so it isn't playing
+    LoadFailed(LoadFailure),
+}
+
+/// Sent whenever a different song starts playing, or a radio station
+/// starts playing a different track
+#[derive(Debug, Clone, PartialEq)]
+pub struct NowPlayingChange {
+    pub song: Arc<Song>,
+    /// The song which played before it, unless nothing was playing
+    pub previous: Option<Arc<Song>>,
+    pub reason: TrackChangeReason,
+    pub position: QueuePosition,
+    /// The track `song` is playing, when it's a radio station which names it
+    pub stream_title: Option<StreamTitle>,
+}
+
+impl NowPlayingChange {
+    /// The song as it's shown to other services. A station playing a named
+    /// track is shown as the track, with the station as its album.
+    pub fn shown_song(&self) -> Song {
+        let mut song = Song::clone(&self.song);
+        if let Some(stream) = &self.stream_title {
+            let station = song.get_tag(&Tag::Title).cloned();
+            song.tags.remove(&Tag::Artist);
+            song.set_tag(Tag::Title, stream.title.clone());
+            if let Some(artist) = &stream.artist {
+                song.set_tag(Tag::Artist, artist.clone());
+            }
+            if let Some(station) = station {
+                song.set_tag(Tag::Album, station);
+            }
+        }
+        song
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::TimeDelta;
+
+    use super::PlayerError;
+
+    #[test]
+    fn load_error_mapping() {
+        let existing = PathBuf::from("Cargo.toml");
+        let missing = PathBuf::from("missing/x.ape");
+
+        let table = [
+            (
+                &missing,
+                "no such file",
+                "Can't play 'x.ape': the file is missing",
+            ),
+            (
+                &existing,
+                "no decoder available for type 'audio/x-ape'",
+                "Can't play 'Cargo.toml': unsupported format",
+            ),
+            (
+                &existing,
+                "Could not open audio device for playback",
+                "The audio device is unavailable: Could not open audio device for playback",
+            ),
+        ];
+
+        for (path, details, message) in table {
+            let error = PlayerError::from_load(path, details.to_string());
+            assert_eq!(error.to_string(), message);
+        }
+
+        assert_eq!(
+            PlayerError::from_load(&existing, String::from("bad data")),
+            PlayerError::UnsupportedFormat {
+                path: existing.clone(),
+                details: String::from("bad data"),
+            }
+        );
+    }
+
+    #[test]
+    fn seek_range() {
+        let duration = Some(TimeDelta::seconds(10));
+        assert!(PlayerError::check_seek(0, duration).is_ok());
+        assert!(PlayerError::check_seek(10_000, duration).is_ok());
+        assert!(PlayerError::check_seek(50_000, None).is_ok());
+        assert_eq!(
+            PlayerError::check_seek(10_001, duration),
+            Err(PlayerError::SeekOutOfRange {
+                requested: 10_001,
+                duration: 10_000
+            })
+        );
+        assert!(PlayerError::check_seek(-1, duration).is_err());
+    }
 }
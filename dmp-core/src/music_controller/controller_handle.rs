@@ -1,45 +1,761 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_channel::{Receiver, Sender};
 use kushi::{QueueError, QueueItem};
 use uuid::Uuid;
 
-use crate::music_storage::{library::Song, playlist::ExternalPlaylist};
+use crate::config::{
+    settings::{Settings, SettingsChange, SettingsError, SettingsPatch, SettingsSection},
+    ConfigContentFilter, ConfigError, OutputMode,
+};
+use crate::music_storage::{
+    album_index::AlbumSummary,
+    art_store::ArtStore,
+    db_reader::extern_library::{ExternalSource, ImportSummary},
+    gain_analysis::{AnalyzeScope, GainAnalysis, GainProgress},
+    gain_staging::PlaybackProfile,
+    integrity::{VerifyProgress, VerifyReport},
+    library::{AlbumKey, RemoveMissingError, Service, Song, Tag, URI},
+    library_guard::{ConflictResolution, StatDelta},
+    organize::OrganizeReport,
+    play_history::OnThisDayYear,
+    playlist::{ExternalPlaylist, MissingResolution, SortOrder},
+    scan_priority::MultiLocationSong,
+    scan_report::{ScanError, ScanProgress, ScanReport},
+    search::{QueueMode, SearchCandidate, SearchMatch},
+    song_details::{RefreshReport, SongDetails},
+    song_links::LinkGroup,
+    song_problems::{FlaggedSong, ProblemReport},
+    tag_cleanup::{CleanRule, TagChange, TagCleanup},
+    tombstones::DanglingTracks,
+};
 
 use super::{
+    connections::ConnectionStatus,
+    continue_listening::ContinueEntry,
     controller::{
         ControllerHandle, LibraryCommand, LibraryResponse, PlayerCommand, PlayerError,
-        PlayerLocation, PlayerResponse, QueueCommand, QueueResponse,
+        PlayerLocation, PlayerResponse, PlayerState, QueueCommand, QueueResponse,
     },
-    queue::{QueueAlbum, QueueSong},
+    controller_state::PlaybackModes,
+    jobs::{JobStatus, ScanOutcome},
+    now_playing::{NowPlaying, NowPlayingTracker},
+    output_mode::OutputSwitch,
+    queue::{QueueAlbum, QueueInfo, QueueSong, Transition},
+    queue_log::QueueOp,
+    remote_source::RemoteSource,
+    scrobbles::{ScrobbleCacheError, ScrobbleCorrection, ScrobbleEntry, ScrobbleId},
+    ui_state::UiStateError,
 };
 
+/// The most songs [`ControllerHandle::lib_get_songs`] fetches at once
+pub const MAX_SONG_BATCH: usize = 500;
+
 impl ControllerHandle {
+    /// Which connected services are configured and running
+    pub fn connection_status(&self) -> ConnectionStatus {
+        ConnectionStatus::new(&self.config.read())
+    }
+
+    /// Sets what plays the songs of `service`, replacing anything before
+    pub fn register_remote_source(&self, service: Service, source: Arc<dyn RemoteSource>) {
+        self.remote_sources.write().register(service, source);
+    }
+
     // The Library Section
-    pub async fn lib_get_song(&self, uuid: Uuid) -> (Song, usize) {
+    /// The song and its index in the library, or `None` for a uuid which
+    /// isn't in it, like one of a song which was removed
+    pub async fn lib_get_song(&self, uuid: Uuid) -> Option<(Song, usize)> {
         let (command, tx) = LibraryCommandInput::command(LibraryCommand::Song(uuid));
         self.lib_mail_rx.send(command).await.unwrap();
-        let LibraryResponse::Song(song, index) = tx.recv().await.unwrap() else {
+        let LibraryResponse::Song(song) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        song
+    }
+
+    /// The song whose art is shown for `uuid`, which is another song on its
+    /// album when it has no art of its own
+    /// The songs with these uuids, in the same order, with `None` for the
+    /// ones which aren't in the library. At most [`MAX_SONG_BATCH`] songs
+    /// can be fetched at once.
+    pub async fn lib_get_songs(&self, uuids: Vec<Uuid>) -> Result<Vec<Option<Arc<Song>>>, String> {
+        if uuids.len() > MAX_SONG_BATCH {
+            return Err(format!(
+                "Can't fetch {} songs at once, the most is {MAX_SONG_BATCH}",
+                uuids.len()
+            ));
+        }
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SongsBulk(uuids.clone()));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SongsBulk(songs) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        let songs: HashMap<Uuid, Arc<Song>> =
+            songs.into_iter().map(|song| (song.uuid, song)).collect();
+        Ok(uuids.iter().map(|uuid| songs.get(uuid).cloned()).collect())
+    }
+
+    pub async fn lib_art_source(&self, uuid: Uuid) -> Option<Song> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::ArtSource(uuid));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ArtSource(song) = tx.recv().await.unwrap() else {
             unreachable!()
         };
-        (song, index)
+        song
+    }
+
+    /// What the album grid shows about each album. It's only worked out
+    /// again after the library changes, so this is cheap to ask for.
+    pub async fn lib_albums(&self) -> Arc<Vec<AlbumSummary>> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::Albums);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::Albums(albums) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        albums
+    }
+
+    /// The data of the art shown for `uuid`, with the uuid of the song it's
+    /// from. It's read through the shared [`ArtStore`], so showing it again
+    /// doesn't go back to the files.
+    pub async fn lib_cover_art(&self, uuid: Uuid) -> Option<(Uuid, Vec<u8>)> {
+        let source = self.lib_art_source(uuid).await?;
+        let art = ArtStore::shared().front_cover(&source).ok()??;
+        Some((source.uuid, art))
     }
 
     pub async fn lib_get_all(&self) -> Vec<Song> {
         let (command, tx) = LibraryCommandInput::command(LibraryCommand::AllSongs);
         self.lib_mail_rx.send(command).await.unwrap();
-        let LibraryResponse::AllSongs(songs) = tx.recv().await.unwrap() else {
-            unreachable!("It has been reached")
+        let LibraryResponse::AllSongs(songs) = tx.recv().await.unwrap() else {
+            unreachable!("It has been reached")
+        };
+        songs
+    }
+
+    /// Marks the library as changed, so it's saved within a few seconds
+    pub async fn lib_save(&self) {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::Save);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+    }
+
+    /// Saves any changes to the library right away, like before exiting
+    pub async fn lib_flush(&self) {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::Flush);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+    }
+
+    /// Stops the library's long jobs, like scans and organizing, and the web
+    /// remote, waiting up to `timeout` for the jobs to stop at their next
+    /// file, then saves the library and the player's state. Returns whether every job stopped in
+    /// time.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.cancel.cancel();
+        let stopped = self.cancel.wait(timeout);
+        self.lib_flush().await;
+
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::FlushState);
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Empty(_) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        stopped
+    }
+
+    /// Settles a conflict with the library file being changed by another
+    /// program, after it was reported by a save
+    pub async fn lib_resolve_conflict(&self, resolution: ConflictResolution) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::ResolveConflict(resolution));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ResolveConflict(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Adds to the play count and other stats of a song, keeping track of the
+    /// change in case the library file has to be merged
+    pub async fn lib_update_stats(&self, uuid: Uuid, delta: StatDelta) {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::UpdateStats(uuid, delta));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+    }
+
+    /// The songs first or heavily played on `month`/`day` in years before
+    /// this one, newest year first
+    pub async fn lib_on_this_day(&self, month: u32, day: u32) -> Vec<OnThisDayYear> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::OnThisDay { month, day });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::OnThisDay(years) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        years
+    }
+
+    /// Removes songs whose files are missing, returning them. With `dry_run`
+    /// nothing is removed, only found.
+    pub async fn lib_remove_missing(&self, dry_run: bool) -> Result<Vec<Song>, RemoveMissingError> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RemoveMissing { dry_run });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RemoveMissing(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Puts back the songs removed by the last
+    /// [`ControllerHandle::lib_remove_missing`], returning how many there were
+    pub async fn lib_undo_remove_missing(&self) -> usize {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::UndoRemoveMissing);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::UndoRemoveMissing(restored) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        restored
+    }
+
+    /// Removes the songs the default library's scan exclusions would have
+    /// left out, returning them. With `dry_run` nothing is removed, only
+    /// found. They can be put back with
+    /// [`ControllerHandle::lib_undo_remove_missing`].
+    pub async fn lib_remove_excluded(&self, dry_run: bool) -> Result<Vec<Song>, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::RemoveExcluded { dry_run });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RemoveExcluded(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Finds the songs in playlists which have been removed from the
+    /// library, taking them out of the playlists if `prune`
+    pub async fn lib_repair_playlists(&self, prune: bool) -> Vec<DanglingTracks> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RepairPlaylists { prune });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RepairPlaylists(dangling) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        dangling
+    }
+
+    /// Adds a song played from `service`, like an internet radio station,
+    /// with its tags looked up by the service's [`RemoteSource`]
+    pub async fn lib_add_remote_song(
+        &self,
+        service: Service,
+        location: String,
+    ) -> Result<Uuid, String> {
+        let uri = URI::Remote(service, location.clone());
+        let sources = self.remote_sources.read().clone();
+        // Looking a song up can wait on a server, so it's done off this task
+        let (tags_tx, tags_rx) = async_channel::bounded(1);
+        std::thread::spawn(move || {
+            _ = tags_tx.send_blocking(sources.metadata(&uri));
+        });
+        let tags = tags_rx.recv().await.unwrap().map_err(|e| e.to_string())?;
+
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::AddRemoteSong {
+            service,
+            location,
+            tags,
+        });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::AddRemoteSong(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Adds an internet radio station, returning the UUID of its song.
+    /// Stations play like any other song, showing the tracks they name.
+    pub async fn lib_add_radio_station(
+        &self,
+        name: String,
+        url: String,
+        genre: Option<String>,
+    ) -> Result<Uuid, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::AddRadioStation { name, url, genre });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::AddRadioStation(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Every radio station in the library, by name
+    pub async fn lib_radio_stations(&self) -> Vec<Song> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RadioStations);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RadioStations(stations) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        stations
+    }
+
+    /// Finds songs which look like other versions of another song, like its
+    /// instrumental or TV size cut, to be confirmed before linking them
+    pub async fn lib_detect_linked_versions(&self) -> Vec<LinkGroup> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::DetectLinkedVersions);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::DetectLinkedVersions(groups) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        groups
+    }
+
+    /// Links the confirmed groups from
+    /// [`ControllerHandle::lib_detect_linked_versions`], returning how many
+    /// versions were linked
+    pub async fn lib_link_versions(&self, groups: Vec<LinkGroup>) -> usize {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::LinkVersions(groups));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::LinkVersions(linked) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        linked
+    }
+
+    /// Generates or fetches the cached waveform of a song, calling `progress`
+    /// as it is generated. Returning `false` from `progress` cancels generation.
+    pub async fn lib_waveform(
+        &self,
+        uuid: Uuid,
+        buckets: usize,
+        mut progress: impl FnMut(f32) -> bool,
+    ) -> Result<Vec<u8>, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::Waveform { uuid, buckets });
+        self.lib_mail_rx.send(command).await.unwrap();
+        loop {
+            match tx.recv().await.unwrap() {
+                LibraryResponse::WaveformProgress(fraction) => {
+                    if !progress(fraction) {
+                        return Err("Waveform generation was cancelled".to_string());
+                    }
+                }
+                LibraryResponse::Waveform(res) => return res,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Returns the report of the most recent library scan
+    pub async fn lib_scan_report(&self) -> ScanReport {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::ScanReport);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ScanReport(report) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        report
+    }
+
+    /// Scans the library's folders for new songs, calling `progress` with
+    /// the scan's job as files are looked at. If the library is already
+    /// being scanned, the running scan's job is returned right away.
+    pub async fn lib_scan(
+        &self,
+        mut progress: impl FnMut(Uuid, ScanProgress),
+    ) -> Result<ScanOutcome, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::Scan);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let job = match tx.recv().await.unwrap() {
+            LibraryResponse::ScanStarted {
+                job,
+                attached: true,
+            } => return Ok(ScanOutcome::Running { job }),
+            LibraryResponse::ScanStarted { job, .. } => job,
+            LibraryResponse::Scan(Err(e)) => return Err(e),
+            _ => unreachable!(),
+        };
+        loop {
+            match tx.recv().await.unwrap() {
+                LibraryResponse::ScanProgress(done) => progress(job, done),
+                LibraryResponse::Scan(res) => {
+                    return res.map(|report| ScanOutcome::Finished { job, report })
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// The long jobs running on the library, like scans
+    pub fn lib_active_jobs(&self) -> Vec<JobStatus> {
+        self.jobs.active()
+    }
+
+    /// Tries adding a file from the scan report again, returning the updated report
+    pub async fn lib_retry_scan(&self, path: PathBuf) -> Result<ScanReport, ScanError> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RetryScan(path));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RetryScan(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// The songs which have more than one location, like copies of them
+    /// found under scan folders of different priorities
+    pub async fn lib_multi_location_songs(&self) -> Vec<MultiLocationSong> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::MultiLocationSongs);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::MultiLocationSongs(songs) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        songs
+    }
+
+    /// Removes one of a song's locations, as long as it has another
+    pub async fn lib_drop_location(&self, uuid: Uuid, uri: URI) -> Result<(), String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::DropLocation(uuid, uri));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::DropLocation(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Cleans up the tags of the library, or only previews the changes
+    /// when `dry_run` is set
+    pub async fn lib_clean_tags(
+        &self,
+        rules: Vec<CleanRule>,
+        dry_run: bool,
+        write_back: bool,
+    ) -> TagCleanup {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::CleanTags {
+            rules,
+            dry_run,
+            write_back,
+        });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::CleanTags(cleanup) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        cleanup
+    }
+
+    /// Fills in the tags of the songs from their file names with `pattern`,
+    /// or only previews the changes when `dry_run` is set. Tags which
+    /// aren't empty are only replaced when `overwrite` is set.
+    pub async fn lib_apply_filename_pattern(
+        &self,
+        uuids: Vec<Uuid>,
+        pattern: String,
+        overwrite: bool,
+        dry_run: bool,
+    ) -> Result<Vec<TagChange>, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::ApplyFilenamePattern {
+            uuids,
+            pattern,
+            overwrite,
+            dry_run,
+        });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ApplyFilenamePattern(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Measures the songs in `scope` and stores their ReplayGain values,
+    /// calling `progress` as each song is measured. Returning `false` from
+    /// `progress` cancels the analysis without changing anything.
+    pub async fn lib_analyze_gain(
+        &self,
+        scope: AnalyzeScope,
+        force: bool,
+        write_back: bool,
+        mut progress: impl FnMut(GainProgress) -> bool,
+    ) -> Result<GainAnalysis, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::AnalyzeGain { scope, force });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let mut analysis = loop {
+            match tx.recv().await.unwrap() {
+                LibraryResponse::GainProgress(done) => {
+                    if !progress(done) {
+                        return Err("Gain analysis was cancelled".to_string());
+                    }
+                }
+                LibraryResponse::AnalyzeGain(res) => break res?,
+                _ => unreachable!(),
+            }
+        };
+
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::ApplyGain {
+            values: analysis.values.clone(),
+            write_back,
+        });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ApplyGain(failed_writes) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        analysis.failed_writes = failed_writes;
+        Ok(analysis)
+    }
+
+    /// Checks the files of the songs in `scope` for damage, calling
+    /// `progress` as each file is checked. The hashes of files seen for the
+    /// first time or changed on purpose are stored for the next check.
+    /// Returning `false` from `progress` cancels it without storing anything.
+    pub async fn lib_verify_files(
+        &self,
+        scope: AnalyzeScope,
+        deep: bool,
+        mut progress: impl FnMut(VerifyProgress) -> bool,
+    ) -> Result<VerifyReport, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::VerifyFiles { scope, deep });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let mut report = loop {
+            match tx.recv().await.unwrap() {
+                LibraryResponse::VerifyProgress(done) => {
+                    if !progress(done) {
+                        return Err("Verifying files was cancelled".to_string());
+                    }
+                }
+                LibraryResponse::VerifyFiles(res) => break res?,
+                _ => unreachable!(),
+            }
+        };
+
+        let hashes = std::mem::take(&mut report.hashes);
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::StoreHashes(hashes));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::StoreHashes = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        Ok(report)
+    }
+
+    /// Checks the songs in `scope` for being silent or having a broken
+    /// length, marking the ones which are so they aren't queued up
+    /// automatically and clearing the mark from ones which are fine now.
+    /// Returning `false` from `progress` cancels it without changing
+    /// anything.
+    pub async fn lib_check_songs(
+        &self,
+        scope: AnalyzeScope,
+        mut progress: impl FnMut(VerifyProgress) -> bool,
+    ) -> Result<ProblemReport, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::CheckSongs(scope));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let mut report = loop {
+            match tx.recv().await.unwrap() {
+                LibraryResponse::CheckProgress(done) => {
+                    if !progress(done) {
+                        return Err("Checking songs was cancelled".to_string());
+                    }
+                }
+                LibraryResponse::CheckSongs(res) => break res?,
+                _ => unreachable!(),
+            }
+        };
+
+        let problems = std::mem::take(&mut report.problems);
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SetProblems(problems));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetProblems = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        Ok(report)
+    }
+
+    /// The songs marked as silent or broken
+    pub async fn lib_flagged_songs(&self) -> Vec<FlaggedSong> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::FlaggedSongs);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::FlaggedSongs(songs) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        songs
+    }
+
+    /// Up to `limit` suggestions for picking up where listening was left
+    /// off, like albums stopped partway through
+    pub async fn lib_continue_listening(&self, limit: usize) -> Vec<ContinueEntry> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::ListeningHints);
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::ListeningHints(hints) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::ContinueListening { limit, hints });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ContinueListening(entries) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        entries
+    }
+
+    /// Returns everything known about a song, for showing its properties
+    pub async fn lib_song_details(&self, uuid: Uuid) -> Result<SongDetails, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SongDetails(uuid));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SongDetails(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res.map(|details| *details)
+    }
+
+    /// Saves the tags of a song's file again in the format set in the
+    /// config, without changing their values
+    pub async fn lib_retag_file_format(&self, uuid: Uuid) -> Result<(), String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RetagFileFormat(uuid));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RetagFileFormat(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Refreshes the tags of a song from its file
+    pub async fn lib_reread_song(&self, uuid: Uuid) -> Result<Song, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RereadSong(uuid));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RereadSong(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Refreshes the tags, art and length of songs from their files
+    pub async fn lib_refresh_songs(&self, uuids: Vec<Uuid>) -> RefreshReport {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RefreshSongs(uuids));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RefreshSongs(report) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        report
+    }
+
+    /// Refreshes every song in an album from its file
+    pub async fn lib_refresh_album(&self, key: AlbumKey) -> Result<RefreshReport, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::RefreshAlbum(key));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::RefreshAlbum(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Picks which of a song's images is shown for it
+    pub async fn lib_set_preferred_art(
+        &self,
+        uuid: Uuid,
+        index: Option<usize>,
+    ) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetPreferredArt(uuid, index));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetPreferredArt(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Marks a song as explicit or not, `None` going back to what its tags say
+    pub async fn lib_set_explicit(&self, uuid: Uuid, explicit: Option<bool>) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetExplicit(uuid, explicit));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetExplicit(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Sets where a song starts and ends playing, `None` playing all of it.
+    /// Takes effect the next time the song is loaded.
+    pub async fn lib_set_trim(
+        &self,
+        uuid: Uuid,
+        trim: Option<(Duration, Duration)>,
+    ) -> Result<(), String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SetTrim(uuid, trim));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetTrim(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    pub async fn lib_set_album_rating(
+        &self,
+        key: AlbumKey,
+        rating: Option<u8>,
+    ) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetAlbumRating { key, rating });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetAlbumRating(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    pub async fn lib_set_album_favorite(
+        &self,
+        key: AlbumKey,
+        favorited: bool,
+    ) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetAlbumFavorite { key, favorited });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetAlbumFavorite(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Marks an album to always play without crossfading between its
+    /// tracks, rather than only when they're found to run into each other
+    pub async fn lib_set_album_gapless(&self, key: AlbumKey, gapless: bool) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetAlbumGapless { key, gapless });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetAlbumGapless(res) = tx.recv().await.unwrap() else {
+            unreachable!()
         };
-        songs
+        res
     }
 
-    pub async fn lib_save(&self) {
-        let (command, tx) = LibraryCommandInput::command(LibraryCommand::Save);
+    /// Moves the files of the songs in `scope` into the library folder
+    /// following `pattern`, or only plans the moves with `dry_run`
+    pub async fn lib_organize_files(
+        &self,
+        pattern: String,
+        scope: AnalyzeScope,
+        dry_run: bool,
+    ) -> Result<OrganizeReport, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::OrganizeFiles {
+            pattern,
+            scope,
+            dry_run,
+        });
         self.lib_mail_rx.send(command).await.unwrap();
-        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+        let LibraryResponse::OrganizeFiles(res) = tx.recv().await.unwrap() else {
             unreachable!()
         };
+        res
     }
 
     // The Playlist Section
@@ -62,6 +778,21 @@ impl ControllerHandle {
         lists
     }
 
+    /// Imports another player's library, merging songs already in this one
+    pub async fn lib_import_external(
+        &self,
+        source: ExternalSource,
+        path: PathBuf,
+    ) -> Result<ImportSummary, String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::ImportExternal(source, path));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ImportExternal(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
     pub async fn playlist_import_m3u(&self, path: PathBuf) -> Result<(Uuid, String), ()> {
         let (command, tx) = LibraryCommandInput::command(LibraryCommand::ImportM3UPlayList(path));
         self.lib_mail_rx.send(command).await.unwrap();
@@ -71,6 +802,244 @@ impl ControllerHandle {
         Ok((uuid, name))
     }
 
+    /// Regenerates the auto playlists, returning the Uuid and name of each
+    pub async fn playlist_generate_auto(&self) -> Vec<(Uuid, String)> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::GenerateAutoPlaylists);
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::AutoPlaylists(lists) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        lists
+    }
+
+    pub async fn playlist_pin_auto(&self, uuid: Uuid) -> Result<(), String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::PinAutoPlaylist(uuid));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::PinAutoPlaylist(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Changes the order a playlist's tracks are shown and played in
+    pub async fn playlist_set_sort_order(
+        &self,
+        uuid: Uuid,
+        order: SortOrder,
+    ) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::PlaylistSetSortOrder { uuid, order });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::PlaylistSetSortOrder(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Sets the playback settings used while playing from a playlist, which
+    /// take effect from the next song
+    pub async fn playlist_set_profile(
+        &self,
+        uuid: Uuid,
+        profile: Option<PlaybackProfile>,
+    ) -> Result<(), String> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::SetPlaylistProfile(uuid, profile));
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::SetPlaylistProfile(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Points the missing track at `index` of a playlist at a song, or a
+    /// file which is added to the library
+    pub async fn playlist_resolve_missing(
+        &self,
+        playlist: Uuid,
+        index: usize,
+        to: MissingResolution,
+    ) -> Result<Uuid, String> {
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::ResolveMissingTrack {
+            playlist,
+            index,
+            to,
+        });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::ResolveMissingTrack(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Songs which could be the missing track at `index` of a playlist
+    pub async fn playlist_missing_matches(
+        &self,
+        playlist: Uuid,
+        index: usize,
+    ) -> Vec<SearchCandidate> {
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::MissingTrackMatches { playlist, index });
+        self.lib_mail_rx.send(command).await.unwrap();
+        let LibraryResponse::MissingTrackMatches(matches) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        matches
+    }
+
+    /// Sets the playback settings used while playing from the default
+    /// library, saving them to the config
+    pub fn library_set_profile(&self, profile: Option<PlaybackProfile>) -> Result<(), String> {
+        let mut config = self.config.write();
+        let default = config.libraries.default_library;
+        let library = config
+            .libraries
+            .libraries
+            .iter_mut()
+            .find(|library| library.uuid == default)
+            .ok_or_else(|| ConfigError::NoDefaultLibrary.to_string())?;
+        library.profile = profile.filter(|profile| !profile.is_empty());
+        config.write_file().map_err(|e| e.to_string())
+    }
+
+    /// One section of the settings, with secrets masked
+    pub fn settings(&self, section: SettingsSection) -> Settings {
+        self.config.read().settings(section)
+    }
+
+    /// Checks a patch of one section of the settings and saves it. Most
+    /// settings are read from the config as they're used, so they take
+    /// effect right away. Nothing changes if the patch is invalid or the
+    /// config can't be written.
+    pub async fn update_settings(
+        &self,
+        section: SettingsSection,
+        patch: serde_json::Value,
+    ) -> Result<(Settings, SettingsChange), String> {
+        let patch = SettingsPatch::parse(section, patch).map_err(|e| e.to_string())?;
+        let (settings, change, limit) = {
+            let mut config = self.config.write();
+            let mut updated = config.clone();
+            let change = updated.apply_settings(patch).map_err(|e| e.to_string())?;
+            updated
+                .write_file()
+                .map_err(|e| SettingsError::from(e).to_string())?;
+            let limit = updated.playback.volume_limit;
+            let changed = limit != config.playback.volume_limit;
+            *config = updated;
+            (config.settings(section), change, changed.then_some(limit))
+        };
+
+        // The player lowers its volume right away when it's over the limit
+        if let Some(limit) = limit {
+            let (command, tx) = PlayerCommandInput::command(PlayerCommand::SetVolumeLimit(limit));
+            self.player_mail_rx.send(command).await.unwrap();
+            let PlayerResponse::Empty(Ok(())) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+        }
+        Ok((settings, change))
+    }
+
+    /// Everything the interface stored to be restored when it opens again
+    pub fn ui_state(&self) -> BTreeMap<String, serde_json::Value> {
+        self.state.lock().ui_state().values()
+    }
+
+    /// Stores a key of the interface's state, removing it if `value` is
+    /// null. It's written along with the rest of the player's state.
+    pub fn set_ui_state(&self, key: &str, value: serde_json::Value) -> Result<(), UiStateError> {
+        self.state.lock().set_ui_state(key, value)
+    }
+
+    pub fn content_filter(&self) -> ConfigContentFilter {
+        self.config.read().content_filter.clone()
+    }
+
+    /// Turns filtered mode on or off, saving it to the config. Needs the
+    /// PIN when one is set. Songs already in the queue stay there.
+    pub fn set_filtered_mode(&self, enabled: bool, pin: Option<String>) -> Result<(), String> {
+        let mut config = self.config.write();
+        if !config.content_filter.check_pin(pin.as_deref()) {
+            return Err("Wrong PIN".to_string());
+        }
+        config.content_filter.enabled = enabled;
+        config.write_file().map_err(|e| e.to_string())
+    }
+
+    /// Sets the PIN needed to change filtered mode, or removes it with
+    /// `None`. Needs the current PIN when one is set.
+    pub fn set_filter_pin(
+        &self,
+        current: Option<String>,
+        pin: Option<String>,
+    ) -> Result<(), String> {
+        let mut config = self.config.write();
+        if !config.content_filter.check_pin(current.as_deref()) {
+            return Err("Wrong PIN".to_string());
+        }
+        config.content_filter.set_pin(pin.as_deref());
+        config.write_file().map_err(|e| e.to_string())
+    }
+
+    /// Returns the recently submitted and pending scrobbles, oldest first
+    pub fn scrobbles_get(&self) -> Vec<ScrobbleEntry> {
+        self.scrobbles.read().entries()
+    }
+
+    /// Returns how many times each song was listened to according to
+    /// ListenBrainz, or `None` where that isn't known
+    #[cfg(feature = "connections")]
+    pub async fn listen_counts(&self, uuids: Vec<Uuid>) -> Vec<Option<u64>> {
+        let token = {
+            let connections = &self.config.read().connections;
+            match &connections.listenbrainz_token {
+                Some(token) if !connections.disable_listen_counts => token.clone(),
+                _ => return vec![None; uuids.len()],
+            }
+        };
+
+        let mut songs: HashMap<Uuid, Arc<Song>> = HashMap::new();
+        for batch in uuids.chunks(MAX_SONG_BATCH) {
+            // Batches are never too large to fetch
+            let found = self.lib_get_songs(batch.to_vec()).await.unwrap_or_default();
+            songs.extend(found.into_iter().flatten().map(|song| (song.uuid, song)));
+        }
+
+        // Fetching can take a while, so it's kept off of the async runtime
+        let listen_counts = Arc::clone(&self.listen_counts);
+        let (res_rx, res_tx) = async_channel::bounded(1);
+        rayon::spawn(move || {
+            let mut counts = listen_counts.lock();
+            // Failing to fetch leaves the old counts, if there are any
+            _ = counts.refresh_from_listenbrainz(&token);
+            let res: Vec<Option<u64>> = uuids
+                .iter()
+                .map(|uuid| songs.get(uuid).and_then(|song| counts.get(song)))
+                .collect();
+            _ = res_rx.send_blocking(res);
+        });
+        res_tx.recv().await.unwrap()
+    }
+
+    /// Without ListenBrainz support no listen counts are known
+    #[cfg(not(feature = "connections"))]
+    pub async fn listen_counts(&self, uuids: Vec<Uuid>) -> Vec<Option<u64>> {
+        vec![None; uuids.len()]
+    }
+
+    /// Corrects a scrobble which has not been submitted yet
+    pub fn scrobble_correct(
+        &self,
+        id: ScrobbleId,
+        correction: ScrobbleCorrection,
+    ) -> Result<(), ScrobbleCacheError> {
+        let mut scrobbles = self.scrobbles.write();
+        scrobbles.correct(id, correction)?;
+        _ = scrobbles.save();
+        Ok(())
+    }
+
     // The Queue Section
     pub async fn queue_append(
         &self,
@@ -84,16 +1053,39 @@ impl ControllerHandle {
         res
     }
 
+    /// Removes the item at `index`, as long as the queue is still at the
+    /// `expected` revision
     pub async fn queue_remove(
         &self,
         index: usize,
-    ) -> Result<QueueItem<QueueSong, QueueAlbum>, QueueError> {
-        let (command, tx) = QueueCommandInput::command(QueueCommand::Remove(index));
+        expected: Option<u64>,
+    ) -> Result<QueueItem<QueueSong, QueueAlbum>, PlayerError> {
+        let command = QueueCommand::Remove(index).expecting(expected);
+        let (command, tx) = QueueCommandInput::command(command);
         self.queue_mail_rx.send(command).await.unwrap();
-        let QueueResponse::Item(res) = tx.recv().await.unwrap() else {
-            unreachable!()
-        };
-        res
+        match tx.recv().await.unwrap() {
+            QueueResponse::Item(res) => Ok(res?),
+            QueueResponse::Conflict(conflict) => Err(conflict.into()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Moves the item at `from` so it ends up at `to`, as long as the queue
+    /// is still at the `expected` revision
+    pub async fn queue_move(
+        &self,
+        from: usize,
+        to: usize,
+        expected: Option<u64>,
+    ) -> Result<(), PlayerError> {
+        let command = QueueCommand::Move { from, to }.expecting(expected);
+        let (command, tx) = QueueCommandInput::command(command);
+        self.queue_mail_rx.send(command).await.unwrap();
+        match tx.recv().await.unwrap() {
+            QueueResponse::Empty(res) => Ok(res?),
+            QueueResponse::Conflict(conflict) => Err(conflict.into()),
+            _ => unreachable!(),
+        }
     }
 
     pub async fn queue_get_all(&self) -> Vec<QueueItem<QueueSong, QueueAlbum>> {
@@ -105,9 +1097,236 @@ impl ControllerHandle {
         queue
     }
 
+    /// How long the rest of the queue takes to play, and how long has been
+    /// played since it was last replaced
+    pub async fn queue_info(&self) -> QueueInfo {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Info);
+        self.queue_mail_rx.send(command).await.unwrap();
+        let QueueResponse::Info(info) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        info
+    }
+
+    /// The queue's revision, and the changes made to it since `since`, or
+    /// `None` if they were forgotten and the whole queue has to be fetched
+    pub async fn queue_changes(&self, since: u64) -> (u64, Option<Vec<QueueOp>>) {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Changes(since));
+        self.queue_mail_rx.send(command).await.unwrap();
+        let QueueResponse::Changes { revision, ops } = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        (revision, ops)
+    }
+
+    /// The queue's revision, which changes whenever the queue does
+    pub async fn queue_revision(&self) -> u64 {
+        // Nothing is newer than the newest revision, so no changes are sent
+        self.queue_changes(u64::MAX).await.0
+    }
+
+    /// Randomly reorders the rest of the queue, keeping the current song
+    pub async fn queue_shuffle_remaining(&self) -> Result<(), QueueError> {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::ShuffleRemaining);
+        self.queue_mail_rx.send(command).await.unwrap();
+        let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Sets how the song before the item at `index` goes into it, `None`
+    /// going back to the profile's crossfade
+    pub async fn queue_set_transition(
+        &self,
+        index: usize,
+        transition: Option<Transition>,
+    ) -> Result<(), QueueError> {
+        let (command, tx) =
+            QueueCommandInput::command(QueueCommand::SetTransition { index, transition });
+        self.queue_mail_rx.send(command).await.unwrap();
+        let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Returns up to `limit` of the most recently played items, oldest first
+    pub async fn queue_get_played(&self, limit: usize) -> Vec<QueueItem<QueueSong, QueueAlbum>> {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::GetPlayed(limit));
+        self.queue_mail_rx.send(command).await.unwrap();
+        let QueueResponse::GetAll(played) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        played
+    }
+
     // The Player Section
-    pub async fn play_now(&self, uuid: Uuid, location: PlayerLocation) -> Result<Song, QueueError> {
-        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayNow(uuid, location));
+    /// Everything about the song playing right now, for showing it when a
+    /// window opens before any changes have come through
+    pub fn now_playing(&self) -> NowPlaying {
+        self.now_playing.get()
+    }
+
+    /// Follows [`ControllerHandle::now_playing`] as it changes
+    pub fn now_playing_tracker(&self) -> NowPlayingTracker {
+        self.now_playing.clone()
+    }
+
+    /// Plays a song, queueing up the songs after it at `location`, shuffled
+    /// if `shuffle_remaining`
+    pub async fn play_now(
+        &self,
+        uuid: Uuid,
+        location: PlayerLocation,
+        shuffle_remaining: bool,
+    ) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayNow {
+            uuid,
+            location,
+            shuffle_remaining,
+        });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Plays a song like [`play_now`](Self::play_now), even when it's
+    /// explicit in filtered mode, for after the user confirmed it
+    pub async fn play_now_confirmed(
+        &self,
+        uuid: Uuid,
+        location: PlayerLocation,
+        shuffle_remaining: bool,
+    ) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayNowConfirmed {
+            uuid,
+            location,
+            shuffle_remaining,
+        });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Replaces the queue with songs by the artist, shuffled if the queue is shuffled
+    pub async fn play_artist(&self, artist: String) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayArtist(artist));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Searches the library and queues the best result with `mode`, all in
+    /// one go. When there isn't a clear best result, nothing is queued and
+    /// the candidates are returned instead.
+    pub async fn search_and_queue(
+        &self,
+        query: String,
+        mode: QueueMode,
+    ) -> Result<SearchMatch, PlayerError> {
+        let (command, tx) =
+            PlayerCommandInput::command(PlayerCommand::SearchAndQueue { query, mode });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::SearchAndQueue(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Queues every song matching `query` in `target_tags`, sorted by `sort`,
+    /// returning how many songs matched. Only the first few songs are queued
+    /// straight away, the rest are queued as songs play.
+    pub async fn queue_add_from_filter(
+        &self,
+        query: String,
+        target_tags: Vec<Tag>,
+        sort: Vec<Tag>,
+        mode: QueueMode,
+    ) -> Result<usize, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::QueueFromFilter {
+            query,
+            target_tags,
+            sort,
+            mode,
+        });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Enqueued(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Adds the rest of the playing song's album after the songs added by
+    /// hand, returning how many songs were added
+    pub async fn enqueue_rest_of_album(&self) -> Result<usize, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::EnqueueRestOfAlbum);
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Enqueued(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Adds up to `count` more songs by the playing song's artist, returning
+    /// how many songs were added
+    pub async fn enqueue_more_by_artist(&self, count: usize) -> Result<usize, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::EnqueueMoreByArtist(count));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Enqueued(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Inserts an album's tracks right after the playing song, where they
+    /// stay together even if the queue is shuffled, returning how many
+    /// songs were added
+    pub async fn enqueue_album_next(&self, key: AlbumKey) -> Result<usize, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::EnqueueAlbumNext(key));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Enqueued(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Replaces the queue with shuffled songs from the genre
+    pub async fn play_genre(&self, genre: String) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayGenre(genre));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Replaces the queue with the album, optionally starting from a `(disc, track)`
+    pub async fn play_album(
+        &self,
+        key: AlbumKey,
+        starting_track: Option<(u16, u16)>,
+    ) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayAlbum {
+            key,
+            starting_track,
+        });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Plays an album from a cue sheet as the one file its tracks come from
+    pub async fn play_cue_album(&self, key: AlbumKey) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayCueAlbum(key));
         self.player_mail_rx.send(command).await.unwrap();
         let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
             unreachable!()
@@ -142,8 +1361,22 @@ impl ControllerHandle {
         res
     }
 
-    pub async fn seek(&self, time: i64) -> Result<(), PlayerError> {
-        let (command, tx) = PlayerCommandInput::command(PlayerCommand::Seek(time));
+    /// Seeks within the track which was playing during track `epoch`, as
+    /// given by [`PlaybackInfo`](super::controller::PlaybackInfo), failing if another track has started since
+    pub async fn seek(&self, time: i64, epoch: u64) -> Result<(), PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::Seek { time, epoch });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Shows `time` as the position of track `epoch` while the seekbar is
+    /// dragged, leaving the actual seek to [`ControllerHandle::seek`]
+    /// once it's let go
+    pub async fn seek_preview(&self, time: i64, epoch: u64) -> Result<(), PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::SeekPreview { time, epoch });
         self.player_mail_rx.send(command).await.unwrap();
         let PlayerResponse::Empty(res) = tx.recv().await.unwrap() else {
             unreachable!()
@@ -159,7 +1392,68 @@ impl ControllerHandle {
         };
     }
 
-    pub async fn next(&self) -> Result<Song, QueueError> {
+    /// Moves the volume by `steps`, down if negative, returning the new
+    /// position of the volume slider
+    pub async fn volume_step(&self, steps: i32) -> f32 {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::VolumeStep(steps));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Volume(volume) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        volume
+    }
+
+    /// The volume, playback modes and current song, which the player has
+    /// even while the library is loading
+    pub async fn get_player_state(&self) -> PlayerState {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::GetState);
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::State(state) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        state
+    }
+
+    pub async fn get_playback_modes(&self) -> PlaybackModes {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::GetPlaybackModes);
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::PlaybackModes(modes) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        modes
+    }
+
+    /// Switches between shared and exclusive output, saving the mode to the
+    /// config. Exclusive output which can't be had falls back to shared
+    /// output, saying why, and is tried again on the next start.
+    pub async fn set_output_mode(&self, mode: OutputMode) -> Result<OutputSwitch, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::SetOutputMode(mode));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::OutputMode(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        let switch = res?;
+
+        let mut config = self.config.write();
+        config.playback.output_mode = mode;
+        if let Err(e) = config.write_file() {
+            println!("Could not save the output mode: {e}");
+        }
+        Ok(switch)
+    }
+
+    /// Sets the shuffle and repeat modes, which also picks the songs coming
+    /// up again if needed
+    pub async fn set_playback_modes(&self, modes: PlaybackModes) -> Result<(), PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::SetPlaybackModes(modes));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    pub async fn next(&self) -> Result<Song, PlayerError> {
         let (command, tx) = PlayerCommandInput::command(PlayerCommand::NextSong);
         self.player_mail_rx.send(command).await.unwrap();
         let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
@@ -168,7 +1462,7 @@ impl ControllerHandle {
         res
     }
 
-    pub async fn prev(&self) -> Result<Song, QueueError> {
+    pub async fn prev(&self) -> Result<Song, PlayerError> {
         let (command, tx) = PlayerCommandInput::command(PlayerCommand::PrevSong);
         self.player_mail_rx.send(command).await.unwrap();
         let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
@@ -176,6 +1470,31 @@ impl ControllerHandle {
         };
         res
     }
+
+    /// Plays the queue item at `index`, as long as the queue is still at
+    /// the `expected` revision
+    pub async fn play_queue_index(
+        &self,
+        index: usize,
+        expected: Option<u64>,
+    ) -> Result<(), PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::Enqueue { index, expected });
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
+
+    /// Jumps back to a played item, `1` being the previous one
+    pub async fn play_played(&self, steps: usize) -> Result<Song, PlayerError> {
+        let (command, tx) = PlayerCommandInput::command(PlayerCommand::PlayPlayed(steps));
+        self.player_mail_rx.send(command).await.unwrap();
+        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res
+    }
 }
 
 pub(super) struct LibraryCommandInput {
@@ -0,0 +1,603 @@
+//! Playback settings and state which are restored when the player starts
+//! again, like the volume and the last played song
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use chrono::{TimeDelta, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use uuid::Uuid;
+
+use super::{
+    controller::PlayerLocation,
+    ui_state::{UiState, UiStateError},
+};
+
+/// The version of the state file written by this version of the player
+pub const STATE_VERSION: u32 = 1;
+
+/// How long the state has to go unchanged before it's written, so dragging
+/// the volume slider doesn't write the file for every step
+pub const STATE_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest a change can wait to be written while the state keeps
+/// changing
+pub const STATE_MAX_SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the saver thread checks whether the state needs writing
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+const DEFAULT_VOLUME: f32 = 0.35;
+
+/// How far one step of the volume moves the slider, like one notch of a
+/// scroll wheel
+pub const VOLUME_STEP: f32 = 0.05;
+
+/// How the position of the volume slider maps to the output volume
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VolumeCurve {
+    #[default]
+    Linear,
+    /// Closer to how loudness is heard, giving finer control at low volumes
+    Cubic,
+}
+
+impl VolumeCurve {
+    pub fn apply(&self, volume: f32) -> f32 {
+        let volume = volume.clamp(0.0, 1.0);
+        match self {
+            VolumeCurve::Linear => volume,
+            VolumeCurve::Cubic => volume.powi(3),
+        }
+    }
+
+    /// The slider position giving the output volume `output`
+    pub fn invert(&self, output: f32) -> f32 {
+        let output = output.clamp(0.0, 1.0);
+        match self {
+            VolumeCurve::Linear => output,
+            VolumeCurve::Cubic => output.cbrt(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Starts the queue over after the last item
+    All,
+    /// Plays the current item again instead of moving on
+    One,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    On,
+}
+
+/// How the songs after the current one are picked
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct PlaybackModes {
+    pub shuffle: ShuffleMode,
+    pub repeat: RepeatMode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerState {
+    /// Files written before versioning are read as version 0
+    #[serde(default)]
+    version: u32,
+    volume: f32,
+    /// The volume to go back to when unmuting, set while muted
+    pre_mute_volume: Option<f32>,
+    volume_curve: VolumeCurve,
+    repeat: RepeatMode,
+    shuffle: ShuffleMode,
+    now_playing: Uuid,
+    location: Option<PlayerLocation>,
+    /// Positions to resume songs from in milliseconds, for long songs like
+    /// podcasts and audiobooks
+    saved_positions: HashMap<Uuid, i64>,
+    /// What the interface wants back when it opens again
+    ui_state: UiState,
+
+    /// The most output volume allowed, from the config, which the slider
+    /// stops short of going past
+    #[serde(skip)]
+    volume_limit: Option<f32>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// When the first change which hasn't been written was made
+    #[serde(skip)]
+    dirty_since: Option<Instant>,
+    #[serde(skip)]
+    changed_at: Option<Instant>,
+}
+
+impl Default for ControllerState {
+    fn default() -> Self {
+        ControllerState {
+            version: STATE_VERSION,
+            volume: DEFAULT_VOLUME,
+            pre_mute_volume: None,
+            volume_curve: VolumeCurve::default(),
+            repeat: RepeatMode::default(),
+            shuffle: ShuffleMode::default(),
+            now_playing: Uuid::nil(),
+            location: None,
+            saved_positions: HashMap::new(),
+            ui_state: UiState::default(),
+            volume_limit: None,
+            path: PathBuf::new(),
+            dirty_since: None,
+            changed_at: None,
+        }
+    }
+}
+
+impl ControllerState {
+    pub fn new(path: PathBuf) -> Self {
+        ControllerState {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the state from `path`, upgrading it if it was written by an
+    /// older version of the player
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let mut state: ControllerState = serde_json::from_str(&fs::read_to_string(path.as_ref())?)?;
+        state.path = path.as_ref().to_path_buf();
+        state.migrate(Instant::now());
+        if state.ui_state.prune(Utc::now()) {
+            state.mark_dirty(Instant::now());
+        }
+        Ok(state)
+    }
+
+    fn migrate(&mut self, now: Instant) {
+        if self.version >= STATE_VERSION {
+            return;
+        }
+
+        // Version 0 only stored the volume and the playing song, with the
+        // volume unchecked
+        if !self.volume.is_finite() {
+            self.volume = DEFAULT_VOLUME;
+        }
+        self.volume = self.volume.clamp(0.0, 1.0);
+
+        self.version = STATE_VERSION;
+        self.mark_dirty(now);
+    }
+
+    /// Writes the state to a temporary file, then moves it over the old one
+    /// so a crash while writing never leaves a broken state file
+    pub fn write_file(&mut self) -> Result<(), io::Error> {
+        let mut writer = self.path.clone();
+        writer.set_extension("tmp");
+        File::create(&writer)?.write_all(to_string_pretty(self)?.as_bytes())?;
+        fs::rename(writer, &self.path)?;
+
+        self.dirty_since = None;
+        self.changed_at = None;
+        Ok(())
+    }
+
+    /// Whether there are changes which haven't been written yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_since.is_some()
+    }
+
+    /// Whether the changes should be written at `now`, either because
+    /// nothing has changed for [`STATE_SAVE_DELAY`] or because the oldest
+    /// change has waited for [`STATE_MAX_SAVE_DELAY`]
+    pub fn save_due(&self, now: Instant) -> bool {
+        let (Some(dirty_since), Some(changed_at)) = (self.dirty_since, self.changed_at) else {
+            return false;
+        };
+        now >= changed_at + STATE_SAVE_DELAY || now >= dirty_since + STATE_MAX_SAVE_DELAY
+    }
+
+    /// Writes the state if [`ControllerState::save_due`], returning whether
+    /// it was written
+    pub fn save_if_due(&mut self, now: Instant) -> Result<bool, io::Error> {
+        if !self.save_due(now) {
+            return Ok(false);
+        }
+        self.write_file()?;
+        Ok(true)
+    }
+
+    /// Writes any changes straight away, for when the player is closing
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if self.is_dirty() {
+            self.write_file()?;
+        }
+        Ok(())
+    }
+
+    fn mark_dirty(&mut self, now: Instant) {
+        self.dirty_since.get_or_insert(now);
+        self.changed_at = Some(now);
+    }
+
+    /// Sets a field, only marking the state as changed if the value is new
+    fn update<T: PartialEq>(&mut self, field: impl FnOnce(&mut Self) -> &mut T, value: T) {
+        let field = field(self);
+        if *field != value {
+            *field = value;
+            self.mark_dirty(Instant::now());
+        }
+    }
+
+    /// The position of the volume slider, from 0 to 1
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    /// Sets the volume, unmuting if it was muted. It's kept within
+    /// [`ControllerState::max_volume`].
+    pub fn set_volume(&mut self, volume: f32) {
+        let max = self.max_volume();
+        self.update(|s| &mut s.pre_mute_volume, None);
+        self.update(|s| &mut s.volume, volume.clamp(0.0, max));
+    }
+
+    /// Moves the volume by `steps` of [`VOLUME_STEP`], starting from the
+    /// volume before muting if it was muted, and returns the new volume
+    pub fn step_volume(&mut self, steps: i32) -> f32 {
+        let volume = self.pre_mute_volume.unwrap_or(self.volume);
+        self.set_volume(volume + steps as f32 * VOLUME_STEP);
+        self.volume
+    }
+
+    /// The volume to give to the player, with the volume curve applied
+    pub fn output_volume(&self) -> f32 {
+        let output = self.volume_curve.apply(self.volume);
+        self.volume_limit.map_or(output, |limit| output.min(limit))
+    }
+
+    /// The furthest the volume slider goes, which is where the output
+    /// reaches the volume limit
+    pub fn max_volume(&self) -> f32 {
+        self.volume_limit
+            .map_or(1.0, |limit| self.volume_curve.invert(limit))
+    }
+
+    /// Sets the most output volume allowed, turning the volume down to it
+    /// if it's over. The volume to go back to when unmuting is too.
+    pub fn set_volume_limit(&mut self, limit: Option<f32>) {
+        self.volume_limit = limit;
+        let max = self.max_volume();
+        if self.volume > max {
+            self.update(|s| &mut s.volume, max);
+        }
+        if self.pre_mute_volume.is_some_and(|volume| volume > max) {
+            self.update(|s| &mut s.pre_mute_volume, Some(max));
+        }
+    }
+
+    pub fn muted(&self) -> bool {
+        self.pre_mute_volume.is_some()
+    }
+
+    /// Mutes by setting the volume to 0, remembering the volume to go back
+    /// to when unmuting
+    pub fn set_muted(&mut self, muted: bool) {
+        match (muted, self.pre_mute_volume) {
+            (true, None) => {
+                let volume = self.volume;
+                self.update(|s| &mut s.pre_mute_volume, Some(volume));
+                self.update(|s| &mut s.volume, 0.0);
+            }
+            (false, Some(volume)) => {
+                self.update(|s| &mut s.pre_mute_volume, None);
+                self.update(|s| &mut s.volume, volume);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn volume_curve(&self) -> VolumeCurve {
+        self.volume_curve
+    }
+
+    /// Sets the volume curve, which moves where the slider reaches the
+    /// volume limit
+    pub fn set_volume_curve(&mut self, curve: VolumeCurve) {
+        self.update(|s| &mut s.volume_curve, curve);
+        self.set_volume_limit(self.volume_limit);
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.update(|s| &mut s.repeat, repeat);
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: ShuffleMode) {
+        self.update(|s| &mut s.shuffle, shuffle);
+    }
+
+    pub fn playback_modes(&self) -> PlaybackModes {
+        PlaybackModes {
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+        }
+    }
+
+    pub fn set_playback_modes(&mut self, modes: PlaybackModes) {
+        self.set_shuffle(modes.shuffle);
+        self.set_repeat(modes.repeat);
+    }
+
+    pub fn now_playing(&self) -> Uuid {
+        self.now_playing
+    }
+
+    /// Where the last played song was played from
+    pub fn location(&self) -> Option<PlayerLocation> {
+        self.location
+    }
+
+    pub fn set_now_playing(&mut self, uuid: Uuid, location: PlayerLocation) {
+        self.update(|s| &mut s.now_playing, uuid);
+        self.update(|s| &mut s.location, Some(location));
+    }
+
+    /// Where to resume a song from, if it was stopped partway through
+    pub fn saved_position(&self, uuid: Uuid) -> Option<TimeDelta> {
+        self.saved_positions
+            .get(&uuid)
+            .map(|ms| TimeDelta::milliseconds(*ms))
+    }
+
+    pub fn save_position(&mut self, uuid: Uuid, position: TimeDelta) {
+        let ms = position.num_milliseconds();
+        if self.saved_positions.insert(uuid, ms) != Some(ms) {
+            self.mark_dirty(Instant::now());
+        }
+    }
+
+    /// Every song with a saved position
+    pub fn saved_positions(&self) -> Vec<(Uuid, TimeDelta)> {
+        self.saved_positions
+            .iter()
+            .map(|(uuid, ms)| (*uuid, TimeDelta::milliseconds(*ms)))
+            .collect()
+    }
+
+    pub fn clear_position(&mut self, uuid: Uuid) {
+        if self.saved_positions.remove(&uuid).is_some() {
+            self.mark_dirty(Instant::now());
+        }
+    }
+
+    pub fn ui_state(&self) -> &UiState {
+        &self.ui_state
+    }
+
+    /// Sets a key of the interface's state, see [`UiState::set`]
+    pub fn set_ui_state(
+        &mut self,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<(), UiStateError> {
+        if self.ui_state.set(key, value, Utc::now())? {
+            self.mark_dirty(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+/// Writes the state whenever it's due, for as long as the player runs
+pub(super) fn state_saver_loop(state: Arc<Mutex<ControllerState>>) {
+    loop {
+        sleep(SAVE_CHECK_INTERVAL);
+        if let Err(e) = state.lock().save_if_due(Instant::now()) {
+            println!("Couldn't save the player state: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Instant};
+
+    use uuid::Uuid;
+
+    use super::{
+        ControllerState, RepeatMode, VolumeCurve, STATE_MAX_SAVE_DELAY, STATE_SAVE_DELAY,
+        STATE_VERSION, VOLUME_STEP,
+    };
+    use crate::music_controller::controller::PlayerLocation;
+
+    fn state_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dmp-state-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn debounce_writes() {
+        let path = state_path("state");
+        let mut state = ControllerState::new(path.clone());
+        let start = Instant::now();
+        assert!(!state.save_due(start + STATE_MAX_SAVE_DELAY));
+
+        state.mark_dirty(start);
+        assert!(!state.save_if_due(start).unwrap());
+        assert!(!path.exists());
+
+        // Changes keep pushing the write back...
+        let later = start + STATE_SAVE_DELAY / 2;
+        state.mark_dirty(later);
+        assert!(!state.save_due(start + STATE_SAVE_DELAY));
+        assert!(state.save_due(later + STATE_SAVE_DELAY));
+
+        // ...but only for so long
+        let mut now = start;
+        while now < start + STATE_MAX_SAVE_DELAY {
+            state.mark_dirty(now);
+            now += STATE_SAVE_DELAY / 2;
+        }
+        assert!(state.save_if_due(now).unwrap());
+        assert!(path.exists());
+        assert!(!state.is_dirty());
+        assert!(!state.save_due(now + STATE_MAX_SAVE_DELAY));
+    }
+
+    #[test]
+    fn setters_mark_changes() {
+        let mut state = ControllerState::new(state_path("state"));
+
+        // Setting a value to what it already is isn't a change
+        state.set_repeat(RepeatMode::Off);
+        assert!(!state.is_dirty());
+
+        state.set_repeat(RepeatMode::All);
+        assert!(state.is_dirty());
+        state.flush().unwrap();
+        assert!(!state.is_dirty());
+
+        let uuid = Uuid::new_v4();
+        state.set_now_playing(uuid, PlayerLocation::Library);
+        state.set_volume_curve(VolumeCurve::Cubic);
+        state.save_position(uuid, chrono::TimeDelta::seconds(90));
+        state.flush().unwrap();
+        state.set_ui_state("library.view", "Albums".into()).unwrap();
+        assert!(state.is_dirty());
+        state.flush().unwrap();
+
+        let read = ControllerState::read_file(&state.path).unwrap();
+        assert_eq!(read, state);
+        assert_eq!(read.location(), Some(PlayerLocation::Library));
+        assert_eq!(read.ui_state().get("library.view"), Some(&"Albums".into()));
+        assert_eq!(
+            read.saved_position(uuid),
+            Some(chrono::TimeDelta::seconds(90))
+        );
+    }
+
+    #[test]
+    fn mute() {
+        let mut state = ControllerState::new(state_path("state"));
+        state.set_volume(0.5);
+        state.set_muted(true);
+        assert!(state.muted());
+        assert_eq!(state.volume(), 0.0);
+
+        state.set_muted(false);
+        assert_eq!(state.volume(), 0.5);
+
+        // Changing the volume while muted unmutes
+        state.set_muted(true);
+        state.set_volume(0.2);
+        assert!(!state.muted());
+        state.set_muted(false);
+        assert_eq!(state.volume(), 0.2);
+    }
+
+    #[test]
+    fn step_volume() {
+        let mut state = ControllerState::new(state_path("state"));
+        state.set_volume(0.5);
+        assert_eq!(state.step_volume(2), 0.5 + 2.0 * VOLUME_STEP);
+        assert_eq!(state.step_volume(-100), 0.0);
+        assert_eq!(state.step_volume(100), 1.0);
+
+        // Stepping while muted starts from the volume before muting
+        state.set_volume(0.5);
+        state.set_muted(true);
+        assert_eq!(state.step_volume(-1), 0.5 - VOLUME_STEP);
+        assert!(!state.muted());
+    }
+
+    #[test]
+    fn volume_limit() {
+        let mut state = ControllerState::new(state_path("state"));
+        state.set_volume_limit(Some(0.6));
+        assert_eq!(state.max_volume(), 0.6);
+        state.set_volume(0.9);
+        assert_eq!(state.volume(), 0.6);
+        assert_eq!(state.step_volume(-1), 0.6 - VOLUME_STEP);
+        assert_eq!(state.step_volume(100), 0.6);
+
+        // The limit is on the output, so the slider goes further with the
+        // cubic curve, but the output still stops at it
+        state.set_volume_curve(VolumeCurve::Cubic);
+        assert!((state.max_volume() - 0.6f32.cbrt()).abs() < 1e-6);
+        state.step_volume(100);
+        assert_eq!(state.volume(), state.max_volume());
+        assert!(state.output_volume() <= 0.6);
+        state.set_volume(1.0);
+        assert!(state.output_volume() <= 0.6);
+
+        // Lowering the limit turns down the volume unmuting goes back to
+        state.set_muted(true);
+        state.set_volume_limit(Some(0.125));
+        assert_eq!(state.volume(), 0.0);
+        state.set_muted(false);
+        assert!((state.volume() - 0.5).abs() < 1e-6);
+
+        state.set_volume_limit(None);
+        state.set_volume(1.0);
+        assert_eq!(state.output_volume(), 1.0);
+    }
+
+    #[test]
+    fn volume_limit_on_restore() {
+        let mut state = ControllerState::new(state_path("state"));
+        state.set_volume(1.0);
+        state.flush().unwrap();
+
+        // The limit isn't saved with the state, and the controller sets it
+        // from the config once the state is read
+        let mut read = ControllerState::read_file(&state.path).unwrap();
+        assert_eq!(read.volume(), 1.0);
+        assert!(!read.is_dirty());
+        read.set_volume_limit(Some(0.3));
+        assert_eq!(read.volume(), 0.3);
+        assert!(read.is_dirty());
+    }
+
+    #[test]
+    fn migrate_old_state() {
+        let path = state_path("state");
+        let uuid = Uuid::new_v4();
+        fs::write(
+            &path,
+            format!(r#"{{ "path": "/somewhere/else", "volume": 1.5, "now_playing": "{uuid}" }}"#),
+        )
+        .unwrap();
+
+        let state = ControllerState::read_file(&path).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.path, path);
+        assert_eq!(state.now_playing(), uuid);
+        assert_eq!(state.volume(), 1.0);
+        assert_eq!(state.repeat(), RepeatMode::Off);
+        // Upgraded files are written again in the new format
+        assert!(state.is_dirty());
+    }
+}
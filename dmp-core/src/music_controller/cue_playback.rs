@@ -0,0 +1,226 @@
+//! Playing the tracks of a cue sheet as the single file they come from.
+//! The file is loaded once, and track changes are found from the playback
+//! position crossing the start of the next track instead of by loading and
+//! seeking for every track.
+
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+
+use crate::music_storage::library::{Song, URI};
+
+use super::controller::PlayerError;
+
+#[derive(Debug, Clone, PartialEq)]
+struct CueTrack {
+    song: Song,
+    start: TimeDelta,
+    end: TimeDelta,
+}
+
+/// The tracks of a cue album being played as one continuous file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueSession {
+    file: PathBuf,
+    tracks: Vec<CueTrack>,
+    current: usize,
+    /// The track epoch of the current track. Loading anything else changes
+    /// the epoch, which ends the session.
+    epoch: u64,
+}
+
+impl CueSession {
+    /// Builds a session out of an album's songs in playback order, which
+    /// all have to be tracks of the same file
+    pub fn new(title: &str, songs: Vec<Song>) -> Result<Self, PlayerError> {
+        if songs.is_empty() {
+            return Err(PlayerError::NoSongsFound(title.to_string()));
+        }
+
+        let mut file = None;
+        let mut tracks = Vec::with_capacity(songs.len());
+        for song in songs {
+            let Some(URI::Cue {
+                location,
+                start,
+                end,
+                ..
+            }) = song.location.first()
+            else {
+                return Err(PlayerError::NotCueAlbum(title.to_string()));
+            };
+            if file.get_or_insert_with(|| location.clone()) != location {
+                return Err(PlayerError::NotCueAlbum(title.to_string()));
+            }
+            tracks.push(CueTrack {
+                start: TimeDelta::from_std(*start).unwrap_or_default(),
+                end: TimeDelta::from_std(*end).unwrap_or_default(),
+                song,
+            });
+        }
+
+        Ok(CueSession {
+            file: file.unwrap(),
+            tracks,
+            current: 0,
+            epoch: 0,
+        })
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn songs(&self) -> Vec<Song> {
+        self.tracks.iter().map(|track| track.song.clone()).collect()
+    }
+
+    pub fn song(&self, index: usize) -> Option<&Song> {
+        self.tracks.get(index).map(|track| &track.song)
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Whether the session is still playing, which it isn't once a track
+    /// from somewhere else was loaded
+    pub fn is_active(&self, epoch: u64) -> bool {
+        self.epoch == epoch
+    }
+
+    /// Notes the track epoch given to the current track
+    pub fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    /// How many tracks come after the current one
+    pub fn remaining(&self) -> usize {
+        self.tracks.len() - self.current - 1
+    }
+
+    /// The position in the file where the track at `index` starts
+    pub fn start_of(&self, index: usize) -> Option<TimeDelta> {
+        self.tracks.get(index).map(|track| track.start)
+    }
+
+    /// The index of the track playing at `position` in the file. Anything
+    /// before the first track, like a hidden pregap, counts as the first.
+    pub fn track_at(&self, position: TimeDelta) -> usize {
+        self.tracks
+            .iter()
+            .rposition(|track| track.start <= position)
+            .unwrap_or(0)
+    }
+
+    /// Moves the session to wherever `position` in the file is, returning
+    /// the indexes of the old and new track if that crossed into another one
+    pub fn update(&mut self, position: TimeDelta) -> Option<(usize, usize)> {
+        let track = self.track_at(position);
+        if track == self.current {
+            return None;
+        }
+        let from = std::mem::replace(&mut self.current, track);
+        Some((from, track))
+    }
+
+    /// Turns a position in the file into a position in the current track
+    pub fn relative(&self, position: TimeDelta) -> TimeDelta {
+        (position - self.tracks[self.current].start).max(TimeDelta::zero())
+    }
+
+    /// Turns a position in the current track into a position in the file
+    pub fn absolute(&self, position: TimeDelta) -> TimeDelta {
+        self.tracks[self.current].start + position
+    }
+
+    pub fn track_duration(&self) -> TimeDelta {
+        let track = &self.tracks[self.current];
+        track.end - track.start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use chrono::TimeDelta;
+    use uuid::Uuid;
+
+    use super::CueSession;
+    use crate::music_controller::controller::PlayerError;
+    use crate::music_storage::library::{Song, URI};
+
+    /// The tracks of `test-data/cue/album.cue`, two seconds each
+    fn two_tracks() -> Vec<Song> {
+        (0..2)
+            .map(|index| Song {
+                location: vec![URI::Cue {
+                    location: "test-data/cue/album.wav".into(),
+                    index,
+                    start: Duration::from_secs(index as u64 * 2),
+                    end: Duration::from_secs(index as u64 * 2 + 2),
+                }],
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boundaries_from_cue_sheet() {
+        let songs = Song::from_cue(Path::new("test-data/cue/album.cue"))
+            .unwrap()
+            .into_iter()
+            .map(|(song, _)| song)
+            .collect();
+        let session = CueSession::new("Cue Album", songs).unwrap();
+        assert_eq!(session.songs().len(), 2);
+        assert!(session.file().ends_with("album.wav"));
+        assert_eq!(session.start_of(1), Some(TimeDelta::seconds(2)));
+        assert_eq!(session.track_duration(), TimeDelta::seconds(2));
+    }
+
+    #[test]
+    fn crossing_boundaries() {
+        let mut session = CueSession::new("Cue Album", two_tracks()).unwrap();
+        assert_eq!(session.track_at(TimeDelta::milliseconds(1999)), 0);
+        assert_eq!(session.track_at(TimeDelta::seconds(10)), 1);
+
+        assert_eq!(session.update(TimeDelta::milliseconds(1900)), None);
+        assert_eq!(
+            session.relative(TimeDelta::milliseconds(1900)),
+            TimeDelta::milliseconds(1900)
+        );
+
+        assert_eq!(session.update(TimeDelta::milliseconds(2100)), Some((0, 1)));
+        assert_eq!(session.current(), 1);
+        assert_eq!(session.remaining(), 0);
+        assert_eq!(
+            session.relative(TimeDelta::milliseconds(2100)),
+            TimeDelta::milliseconds(100)
+        );
+        assert_eq!(
+            session.absolute(TimeDelta::milliseconds(500)),
+            TimeDelta::milliseconds(2500)
+        );
+        assert_eq!(session.track_duration(), TimeDelta::seconds(2));
+
+        // Seeking back into the first track
+        assert_eq!(session.update(TimeDelta::seconds(1)), Some((1, 0)));
+    }
+
+    #[test]
+    fn only_tracks_of_one_file() {
+        let mut songs = two_tracks();
+        songs[1].location = vec![URI::Local("other.flac".into())];
+        assert_eq!(
+            CueSession::new("Cue Album", songs),
+            Err(PlayerError::NotCueAlbum("Cue Album".to_string()))
+        );
+        assert_eq!(
+            CueSession::new("Nothing", Vec::new()),
+            Err(PlayerError::NoSongsFound("Nothing".to_string()))
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Reading the names of the tracks internet radio stations are playing.
+//! Asked with `Icy-MetaData: 1`, a station puts a block of metadata after
+//! every `icy-metaint` bytes of audio, which names the track as its
+//! `StreamTitle`.
+
+use std::io::{self, Read};
+
+use serde::Serialize;
+
+use super::remote_source::{RemoteError, REQUEST_TIMEOUT};
+
+/// The track a station says it's playing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamTitle {
+    /// The title as the station sent it
+    pub raw: String,
+    pub artist: Option<String>,
+    pub title: String,
+}
+
+impl StreamTitle {
+    /// Splits a title like "Artist - Title", which is what most stations
+    /// send. Anything else is all title.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return None;
+        }
+        let (artist, title) = match raw.split_once(" - ") {
+            Some((artist, title)) if !artist.trim().is_empty() && !title.trim().is_empty() => {
+                (Some(artist.trim().to_string()), title.trim().to_string())
+            }
+            _ => (None, raw.to_string()),
+        };
+        Some(StreamTitle {
+            raw: raw.to_string(),
+            artist,
+            title,
+        })
+    }
+}
+
+/// Pulls the `StreamTitle` out of a metadata block like
+/// `StreamTitle='Artist - Title';StreamUrl='';`, padded with zeroes
+fn block_title(block: &[u8]) -> Option<String> {
+    let end = block.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let block = &block[..end];
+    // Stations which don't send UTF-8 almost always send Latin-1
+    let text = match std::str::from_utf8(block) {
+        Ok(text) => text.to_string(),
+        Err(_) => block.iter().map(|b| *b as char).collect(),
+    };
+
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+    // Titles can have quotes in them, so only a quote which ends the field
+    // ends the title
+    let end = rest.find("';").or_else(|| rest.rfind('\''))?;
+    Some(rest[..end].to_string())
+}
+
+/// Reads the metadata blocks out of a station's stream, skipping the audio
+/// in between
+struct IcyReader<R> {
+    inner: R,
+    metaint: u64,
+}
+
+impl<R: Read> IcyReader<R> {
+    /// Reads up to the end of the next metadata block, returning the title
+    /// it names. Empty blocks mean the title hasn't changed.
+    fn next_title(&mut self) -> io::Result<Option<String>> {
+        let skipped = io::copy(&mut (&mut self.inner).take(self.metaint), &mut io::sink())?;
+        if skipped < self.metaint {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut length = [0];
+        self.inner.read_exact(&mut length)?;
+        let mut block = vec![0; length[0] as usize * 16];
+        self.inner.read_exact(&mut block)?;
+        Ok(block_title(&block))
+    }
+}
+
+/// Follows the titles of the station streaming from `url`, calling
+/// `on_title` each time a different track starts. Stops once `playing`
+/// returns false, which is checked after each block, or the stream ends.
+/// Stations which don't send titles return right away.
+pub(super) fn watch_station(
+    url: &str,
+    playing: impl Fn() -> bool,
+    mut on_title: impl FnMut(StreamTitle),
+) -> Result<(), RemoteError> {
+    let error = |details: String| RemoteError::Request {
+        url: url.to_string(),
+        details,
+    };
+    let response = attohttpc::get(url)
+        .header("Icy-MetaData", "1")
+        .connect_timeout(REQUEST_TIMEOUT)
+        .read_timeout(REQUEST_TIMEOUT)
+        .send()
+        .map_err(|e| error(e.to_string()))?;
+    if !response.is_success() {
+        return Err(RemoteError::Status {
+            url: url.to_string(),
+            status: response.status().as_u16(),
+        });
+    }
+
+    let (_, headers, body) = response.split();
+    let Some(metaint) = headers
+        .get("icy-metaint")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|metaint| *metaint > 0)
+    else {
+        return Ok(());
+    };
+
+    let mut reader = IcyReader {
+        inner: body,
+        metaint,
+    };
+    let mut last = None;
+    while playing() {
+        let title = match reader.next_title() {
+            Ok(title) => title,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(error(e.to_string())),
+        };
+        let Some(title) = title.as_deref().and_then(StreamTitle::parse) else {
+            continue;
+        };
+        if last.as_ref() != Some(&title.raw) {
+            last = Some(title.raw.clone());
+            // The station may have moved on while waiting for the block
+            if playing() {
+                on_title(title);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::{block_title, watch_station, StreamTitle};
+
+    /// A metadata block as a station would send it, with its length first
+    fn block(text: &str) -> Vec<u8> {
+        let mut data = text.as_bytes().to_vec();
+        data.resize(text.len().div_ceil(16) * 16, 0);
+        let mut block = vec![(data.len() / 16) as u8];
+        block.extend(data);
+        block
+    }
+
+    /// Serves a stream with a block of metadata every 64 bytes of "audio",
+    /// one for each of `blocks`, and returns its URL
+    fn icy_station(blocks: &'static [&'static str], metaint: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut asked = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                asked |= line.to_lowercase().starts_with("icy-metadata: 1");
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+            assert!(asked, "Metadata wasn't asked for");
+
+            let mut head =
+                "HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\nicy-name: Test FM\r\n".to_string();
+            if metaint {
+                head += "icy-metaint: 64\r\n";
+            }
+            head += "Connection: close\r\n\r\n";
+            // The player may hang up before the end
+            _ = stream.write_all(head.as_bytes());
+            for text in blocks {
+                _ = stream.write_all(&[0x55; 64]);
+                _ = stream.write_all(&block(text));
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn stream_titles() {
+        assert_eq!(
+            block_title(b"StreamTitle='ClariS - CONNECT';StreamUrl='';\0\0\0"),
+            Some("ClariS - CONNECT".to_string())
+        );
+        assert_eq!(
+            block_title(b"StreamTitle='Rock 'n' Roll';"),
+            Some("Rock 'n' Roll".to_string())
+        );
+        // Latin-1 is read as such rather than thrown out
+        assert_eq!(
+            block_title(b"StreamTitle='Beyonc\xe9 - Halo';"),
+            Some("Beyoncé - Halo".to_string())
+        );
+        assert_eq!(block_title(b"StreamUrl='https://a.example';"), None);
+        assert_eq!(block_title(&[0; 16]), None);
+
+        let title = StreamTitle::parse("Kalafina - Lacrimosa").unwrap();
+        assert_eq!(title.artist.as_deref(), Some("Kalafina"));
+        assert_eq!(title.title, "Lacrimosa");
+        let title = StreamTitle::parse("Station ID").unwrap();
+        assert_eq!(title.artist, None);
+        assert_eq!(title.title, "Station ID");
+        assert_eq!(StreamTitle::parse("  "), None);
+    }
+
+    #[test]
+    fn titles_from_icy_station() {
+        let url = icy_station(
+            &[
+                "StreamTitle='ClariS - CONNECT';",
+                "",
+                "StreamTitle='ClariS - CONNECT';",
+                "StreamTitle='Kalafina - Lacrimosa';StreamUrl='';",
+            ],
+            true,
+        );
+        let mut titles = Vec::new();
+        watch_station(&url, || true, |title| titles.push(title.raw)).unwrap();
+        // A title repeated in the next block isn't a new track
+        assert_eq!(titles, ["ClariS - CONNECT", "Kalafina - Lacrimosa"]);
+    }
+
+    #[test]
+    fn stops_when_station_changes() {
+        let url = icy_station(
+            &[
+                "StreamTitle='One';",
+                "StreamTitle='Two';",
+                "StreamTitle='Three';",
+            ],
+            true,
+        );
+        let blocks = AtomicUsize::new(0);
+        let mut titles = Vec::new();
+        watch_station(
+            &url,
+            || blocks.fetch_add(1, Ordering::SeqCst) < 2,
+            |title| titles.push(title.raw),
+        )
+        .unwrap();
+        assert_eq!(titles, ["One"]);
+    }
+
+    #[test]
+    fn station_without_titles() {
+        let url = icy_station(&["StreamTitle='Unseen';"], false);
+        let mut titles = Vec::new();
+        watch_station(&url, || true, |title| titles.push(title.raw)).unwrap();
+        assert!(titles.is_empty());
+    }
+}
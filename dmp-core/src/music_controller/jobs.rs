@@ -0,0 +1,160 @@
+//! The long jobs the library is running, like scans and gain analysis, so
+//! the interface can show what's going on and the same scan isn't started
+//! twice. The registry is shared with the handle, which reads it without
+//! waiting on the library loop.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::music_storage::scan_report::ScanReport;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobKind {
+    Scan,
+    GainAnalysis,
+    Organize,
+    Verify,
+    CheckSongs,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub id: Uuid,
+    pub kind: JobKind,
+    /// The library the job works on
+    pub library: Uuid,
+    pub started: DateTime<Utc>,
+    /// How many files are done
+    pub done: usize,
+    /// How many files there are to do, when it's known
+    pub total: Option<usize>,
+}
+
+/// What asking for a scan did
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ScanOutcome {
+    Finished {
+        job: Uuid,
+        report: ScanReport,
+    },
+    /// A scan of the library was already running, so there wasn't another.
+    /// Its progress is in [`JobRegistry::active`].
+    Running {
+        job: Uuid,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<Vec<JobStatus>>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a job of `kind` unless one is already running on the
+    /// library, in which case the running job's id is returned instead
+    pub fn start(&self, kind: JobKind, library: Uuid) -> Result<Job, Uuid> {
+        let mut jobs = self.jobs.lock();
+        if let Some(running) = jobs
+            .iter()
+            .find(|job| job.kind == kind && job.library == library)
+        {
+            return Err(running.id);
+        }
+        Ok(self.push(&mut jobs, kind, library))
+    }
+
+    /// Registers a job to show its progress, even if another of its kind is
+    /// running. Used for jobs whose caller gives up on an older one to start
+    /// again, which stops the older one shortly after.
+    pub fn track(&self, kind: JobKind, library: Uuid) -> Job {
+        self.push(&mut self.jobs.lock(), kind, library)
+    }
+
+    fn push(&self, jobs: &mut Vec<JobStatus>, kind: JobKind, library: Uuid) -> Job {
+        let id = Uuid::new_v4();
+        jobs.push(JobStatus {
+            id,
+            kind,
+            library,
+            started: Utc::now(),
+            done: 0,
+            total: None,
+        });
+        Job {
+            id,
+            jobs: Arc::clone(&self.jobs),
+        }
+    }
+
+    /// The running jobs, oldest first
+    pub fn active(&self) -> Vec<JobStatus> {
+        self.jobs.lock().clone()
+    }
+}
+
+/// A running job, which is taken out of the registry when dropped
+#[derive(Debug)]
+pub struct Job {
+    id: Uuid,
+    jobs: Arc<Mutex<Vec<JobStatus>>>,
+}
+
+impl Job {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn progress(&self, done: usize, total: Option<usize>) {
+        if let Some(job) = self.jobs.lock().iter_mut().find(|job| job.id == self.id) {
+            job.done = done;
+            job.total = total;
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.jobs.lock().retain(|job| job.id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{JobKind, JobRegistry};
+
+    #[test]
+    fn one_scan_per_library() {
+        let jobs = JobRegistry::new();
+        let (library, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let scan = jobs.start(JobKind::Scan, library).unwrap();
+        assert_eq!(jobs.start(JobKind::Scan, library).unwrap_err(), scan.id());
+        // Other jobs and other libraries aren't held up
+        let other_scan = jobs.start(JobKind::Scan, other).unwrap();
+        let verify = jobs.start(JobKind::Verify, library).unwrap();
+        let gain = [
+            jobs.track(JobKind::GainAnalysis, library),
+            jobs.track(JobKind::GainAnalysis, library),
+        ];
+
+        scan.progress(10, Some(40));
+        let active = jobs.active();
+        assert_eq!(active.len(), 5);
+        assert_eq!((active[0].done, active[0].total), (10, Some(40)));
+
+        drop(scan);
+        let scan = jobs.start(JobKind::Scan, library).unwrap();
+        drop((scan, other_scan, verify, gain));
+        assert!(jobs.active().is_empty());
+    }
+}
@@ -1,34 +1,143 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use chrono::{Datelike, Local, Utc};
+use crossbeam_channel::Sender;
 use parking_lot::RwLock;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use uuid::Uuid;
 
 use crate::{
     config::Config,
     music_storage::{
-        library::MusicLibrary,
+        album_index::AlbumIndex,
+        art_fallback::AlbumArtIndex,
+        cancel::CancelToken,
+        filename_pattern::FilenamePattern,
+        gain_analysis::{self, GainAnalysis},
+        integrity::{self, VerifyReport},
+        library::{find_missing, InternalTag, MusicLibrary, RemoveMissingOptions, Song, URI},
+        library_guard::{LibraryConflict, LibraryGuard, LibrarySaveError},
+        organize::{PathTemplate, ORGANIZE_JOURNAL_FILE},
         playlist::{ExternalPlaylist, Playlist, PlaylistFolderItem},
+        scan_candidates::ScanCandidates,
+        scan_exclusions::ExclusionRules,
+        scan_priority::{FolderPriorities, ScanConflicts},
+        scan_report::{ScanProgress, ScanReport, SCAN_REPORT_FILE},
+        song_details::SongDetails,
+        song_problems,
+        waveform::{self, WaveformCache},
     },
 };
 
 use super::{
-    controller::{Controller, LibraryCommand, LibraryResponse},
+    continue_listening::continue_listening,
+    controller::{Controller, LibraryCommand, LibraryResponse, PlayerLocation},
     controller_handle::LibraryCommandInput,
+    jobs::{Job, JobKind, JobRegistry},
+    save_scheduler::{LibraryChange, SaveScheduler},
 };
 
 impl Controller {
+    /// Runs the library's commands. `own_mail` sends to the loop itself,
+    /// for jobs running off it to hand their results back.
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn library_loop(
         lib_mail: async_channel::Receiver<LibraryCommandInput>,
+        own_mail: async_channel::Sender<LibraryCommandInput>,
         library: &mut MusicLibrary,
         config: Arc<RwLock<Config>>,
+        notify_conflict: Sender<LibraryConflict>,
+        cancel: CancelToken,
+        jobs: JobRegistry,
     ) -> Result<(), ()> {
+        let mut guard = LibraryGuard::new(
+            config
+                .read()
+                .libraries
+                .get_library(&library.uuid)
+                .unwrap()
+                .path
+                .clone(),
+        );
+        // Only notify once per conflict, rather than on every save after it
+        let mut conflicted = false;
+        let mut scheduler = SaveScheduler::default();
+        let mut art_index = AlbumArtIndex::default();
+        let mut album_index = AlbumIndex::default();
+
+        // Stats from a session which ended without saving
+        match guard.replay(library) {
+            Ok(true) => scheduler.mark(LibraryChange::Structural, Instant::now()),
+            Ok(false) => (),
+            Err(e) => println!("Could not replay the stat journal: {e}"),
+        }
+
+        // Organizing files was interrupted, so finish moving them before
+        // anything else looks at the library
+        let organize_journal = config.read().path.with_file_name(ORGANIZE_JOURNAL_FILE);
+        match library.resume_organize(&organize_journal) {
+            Ok(Some(_)) => match guard.save(library) {
+                Ok(()) => _ = fs::remove_file(&organize_journal),
+                Err(e) => println!("Could not save the organized library: {e}"),
+            },
+            Ok(None) => (),
+            Err(e) => println!("Could not finish organizing files: {e}"),
+        }
+
+        // The auto playlists were generated when the player started
+        let mut auto_playlists_at = scheduler.revision();
         while true {
             let LibraryCommandInput { res_rx, command } = lib_mail.recv().await.unwrap();
             match command {
                 LibraryCommand::Song(uuid) => {
-                    let (song, i) = library.query_uuid(&uuid).unwrap();
+                    let song = library.query_uuid(&uuid).map(|(song, i)| (song.clone(), i));
+                    res_rx.send(LibraryResponse::Song(song)).await.unwrap();
+                }
+                LibraryCommand::ArtSource(uuid) => {
+                    let source = art_index
+                        .art_source(library, &uuid, scheduler.revision())
+                        .cloned();
+                    res_rx
+                        .send(LibraryResponse::ArtSource(source))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::Albums => {
+                    let albums = album_index.albums(library, scheduler.revision());
+                    res_rx.send(LibraryResponse::Albums(albums)).await.unwrap();
+                }
+                LibraryCommand::SourcePosition(uuid, location) => {
+                    let position = match location {
+                        PlayerLocation::Library => library
+                            .query_uuid(&uuid)
+                            .map(|(_, i)| (i, library.library.len())),
+                        PlayerLocation::Playlist(list) => {
+                            library.query_playlist_uuid(&list).and_then(|list| {
+                                let i = list.tracks.iter().position(|track| track.uuid == uuid)?;
+                                Some((i, list.tracks.len()))
+                            })
+                        }
+                        _ => None,
+                    };
+                    res_rx
+                        .send(LibraryResponse::SourcePosition(position))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SongsBulk(uuids) => {
+                    let songs = uuids
+                        .iter()
+                        .filter_map(|uuid| library.query_uuid(uuid))
+                        .map(|(song, _)| Arc::new(song.clone()))
+                        .collect();
                     res_rx
-                        .send(LibraryResponse::Song(song.clone(), i))
+                        .send(LibraryResponse::SongsBulk(songs))
                         .await
                         .unwrap();
                 }
@@ -38,6 +147,31 @@ impl Controller {
                         .await
                         .unwrap();
                 }
+                LibraryCommand::AllUuids => {
+                    let uuids = library.library.iter().map(|song| song.uuid).collect();
+                    res_rx.send(LibraryResponse::AllUuids(uuids)).await.unwrap();
+                }
+                LibraryCommand::QueryUuids {
+                    query,
+                    target_tags,
+                    sort,
+                    filtered,
+                } => {
+                    let uuids = library.query_uuids(&query, &target_tags, &sort, filtered);
+                    res_rx
+                        .send(LibraryResponse::QueryUuids(uuids))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::PlaylistUuids(uuid) => {
+                    let uuids = library
+                        .query_playlist_uuid(&uuid)
+                        .map(|playlist| playlist.sorted_uuids(library));
+                    res_rx
+                        .send(LibraryResponse::PlaylistUuids(uuids))
+                        .await
+                        .unwrap();
+                }
                 LibraryCommand::ExternalPlaylist(uuid) => {
                     let playlist = library.query_playlist_uuid(&uuid).unwrap();
                     res_rx
@@ -61,19 +195,177 @@ impl Controller {
                         .await
                         .unwrap();
                 }
+                LibraryCommand::ImportExternal(source, path) => {
+                    let res = source
+                        .read(&path)
+                        .map(|import| library.import_external(import))
+                        .map_err(|e| e.to_string());
+                    res_rx
+                        .send(LibraryResponse::ImportExternal(res))
+                        .await
+                        .unwrap();
+                }
                 LibraryCommand::Save => {
-                    library
-                        .save(
-                            config
-                                .read()
+                    scheduler.mark(LibraryChange::Structural, Instant::now());
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::Flush => {
+                    if scheduler.is_dirty() {
+                        refresh_auto_playlists(
+                            library,
+                            &config,
+                            scheduler.revision(),
+                            &mut auto_playlists_at,
+                        );
+                        save(
+                            library,
+                            &mut guard,
+                            &mut scheduler,
+                            &mut conflicted,
+                            &notify_conflict,
+                        );
+                    }
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::SaveIfDue => {
+                    if scheduler.save_due(Instant::now()) {
+                        refresh_auto_playlists(
+                            library,
+                            &config,
+                            scheduler.revision(),
+                            &mut auto_playlists_at,
+                        );
+                        save(
+                            library,
+                            &mut guard,
+                            &mut scheduler,
+                            &mut conflicted,
+                            &notify_conflict,
+                        );
+                    }
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::ResolveConflict(resolution) => {
+                    let res = guard
+                        .resolve(library, resolution)
+                        .map_err(|e| e.to_string());
+                    if res.is_ok() {
+                        conflicted = false;
+                        scheduler.saved();
+                    }
+                    res_rx
+                        .send(LibraryResponse::ResolveConflict(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::UpdateStats(uuid, delta) => {
+                    if let Some((_, i)) = library.query_uuid(&uuid) {
+                        let song = &mut library.library[i];
+                        song.plays += delta.plays;
+                        song.skips += delta.skips;
+                        song.play_time += delta.play_time;
+                        song.last_played = song.last_played.max(delta.last_played);
+                        let played_at = delta.last_played.unwrap_or_else(Utc::now);
+                        for _ in 0..delta.plays {
+                            library.record_play(uuid, played_at);
+                        }
+                        guard.record(uuid, &delta);
+                        scheduler.mark(LibraryChange::Stats, Instant::now());
+                    }
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::OnThisDay { month, day } => {
+                    let years = library.on_this_day(month, day, Local::now().year(), &Local);
+                    res_rx
+                        .send(LibraryResponse::OnThisDay(years))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::AddPlaylistTime(uuid, time) => {
+                    if let Some(playlist) = library.playlists.query_uuid_mut(&uuid) {
+                        playlist.play_time += time;
+                        scheduler.mark(LibraryChange::Stats, Instant::now());
+                    }
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::RemoveMissing { dry_run } => {
+                    let options = {
+                        let config = config.read();
+                        RemoveMissingOptions {
+                            recheck_delay: Duration::from_millis(
+                                config.missing_files.recheck_delay_ms,
+                            ),
+                            max_percent: config.missing_files.max_removal_percent,
+                            dry_run,
+                            scan_folders: config
                                 .libraries
                                 .get_library(&library.uuid)
-                                .unwrap()
-                                .path
-                                .clone(),
-                        )
+                                .ok()
+                                .and_then(|lib| lib.scan_folders)
+                                .unwrap_or_default(),
+                        }
+                    };
+                    let locations = library.locations();
+
+                    // Checking every file and waiting to check again would
+                    // hold up the library, so only the removal is done here
+                    let own_mail = own_mail.clone();
+                    std::thread::spawn(move || {
+                        let missing = find_missing(locations, options.recheck_delay);
+                        let (command, res) = LibraryCommandInput::command(
+                            LibraryCommand::RemoveFoundMissing(missing, options),
+                        );
+                        let removed = match own_mail.send_blocking(command) {
+                            Ok(()) => res.recv_blocking().ok(),
+                            Err(_) => None,
+                        };
+                        if let Some(removed) = removed {
+                            _ = res_rx.send_blocking(removed);
+                        }
+                    });
+                }
+                LibraryCommand::RemoveFoundMissing(missing, options) => {
+                    let res = library.remove_found_missing(missing, &options);
+                    if matches!(&res, Ok(removed) if !options.dry_run && !removed.is_empty()) {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::RemoveMissing(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::UndoRemoveMissing => {
+                    let restored = library.undo_remove_missing();
+                    if restored > 0 {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::UndoRemoveMissing(restored))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RemoveExcluded { dry_run } => {
+                    let res = exclusion_rules(&config.read()).map(|rules| {
+                        let removed = library.remove_excluded(&rules, dry_run);
+                        if !dry_run && !removed.is_empty() {
+                            scheduler.mark(LibraryChange::Structural, Instant::now());
+                        }
+                        removed
+                    });
+                    res_rx
+                        .send(LibraryResponse::RemoveExcluded(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RepairPlaylists { prune } => {
+                    let dangling = library.repair_playlists(prune);
+                    if prune && !dangling.is_empty() {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::RepairPlaylists(dangling))
+                        .await
                         .unwrap();
-                    res_rx.send(LibraryResponse::Ok).await.unwrap();
                 }
                 LibraryCommand::Playlists => {
                     let mut lists = vec![];
@@ -89,6 +381,645 @@ impl Controller {
                         .await
                         .unwrap();
                 }
+                LibraryCommand::GenerateAutoPlaylists => {
+                    let auto = config.read().auto_playlists.clone();
+                    let lists = library.generate_auto_playlists(auto.track_limit, auto.top_genres);
+                    res_rx
+                        .send(LibraryResponse::AutoPlaylists(lists))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::PinAutoPlaylist(uuid) => {
+                    let res = library.pin_auto_playlist(&uuid).map_err(|e| e.to_string());
+                    res_rx
+                        .send(LibraryResponse::PinAutoPlaylist(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::PlaylistSetSortOrder { uuid, order } => {
+                    let res = match library.query_playlist_uuid_mut(&uuid) {
+                        Some(playlist) => {
+                            playlist.set_sort_order(order);
+                            Ok(())
+                        }
+                        None => Err("Playlist not found".to_string()),
+                    };
+                    res_rx
+                        .send(LibraryResponse::PlaylistSetSortOrder(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::AddRemoteSong {
+                    service,
+                    location,
+                    tags,
+                } => {
+                    let song = Song {
+                        location: vec![URI::Remote(service, location)],
+                        uuid: Uuid::new_v4(),
+                        date_added: Some(Utc::now()),
+                        tags,
+                        ..Default::default()
+                    };
+                    let uuid = song.uuid;
+                    let res = library
+                        .add_song(song)
+                        .map(|_| uuid)
+                        .map_err(|e| e.to_string());
+                    if res.is_ok() {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::AddRemoteSong(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::AddRadioStation { name, url, genre } => {
+                    let res = library.add_radio_station(&name, &url, genre.as_deref());
+                    if res.is_ok() {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::AddRadioStation(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RadioStations => {
+                    let stations = library.radio_stations().into_iter().cloned().collect();
+                    res_rx
+                        .send(LibraryResponse::RadioStations(stations))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::DetectLinkedVersions => {
+                    let groups = library.detect_linked_versions();
+                    res_rx
+                        .send(LibraryResponse::DetectLinkedVersions(groups))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::LinkVersions(groups) => {
+                    let linked = library.link_versions(&groups);
+                    if linked > 0 {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::LinkVersions(linked))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::MarkMissing(uuid) => {
+                    if let Some(song) = library.library.iter_mut().find(|s| s.uuid == uuid) {
+                        if !song.internal_tags.contains(&InternalTag::Missing) {
+                            song.internal_tags.push(InternalTag::Missing);
+                        }
+                    }
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::PlaylistProfile(uuid) => {
+                    let profile = library
+                        .query_playlist_uuid(&uuid)
+                        .and_then(|playlist| playlist.profile());
+                    res_rx
+                        .send(LibraryResponse::PlaylistProfile(profile))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetPlaylistProfile(uuid, profile) => {
+                    let res = match library.query_playlist_uuid_mut(&uuid) {
+                        Some(playlist) => {
+                            playlist.set_profile(profile);
+                            Ok(())
+                        }
+                        None => Err("Playlist not found".to_string()),
+                    };
+                    res_rx
+                        .send(LibraryResponse::SetPlaylistProfile(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::ResolveMissingTrack {
+                    playlist,
+                    index,
+                    to,
+                } => {
+                    let res = library
+                        .resolve_missing_track(&playlist, index, to)
+                        .map_err(|e| e.to_string());
+                    res_rx
+                        .send(LibraryResponse::ResolveMissingTrack(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::MissingTrackMatches { playlist, index } => {
+                    res_rx
+                        .send(LibraryResponse::MissingTrackMatches(
+                            library.missing_track_matches(&playlist, index),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::AlbumTracks(key, starting_track) => {
+                    let songs = match library.query_album(&key) {
+                        Some(album) => album
+                            .play_order(starting_track)
+                            .iter()
+                            .filter_map(|track| library.query_uuid(track.uuid()))
+                            .map(|(song, _)| song.clone())
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                    res_rx
+                        .send(LibraryResponse::AlbumTracks(songs))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::ArtistSongs(artist) => {
+                    let songs = library.artist_songs(&artist).into_iter().cloned().collect();
+                    res_rx.send(LibraryResponse::Songs(songs)).await.unwrap();
+                }
+                LibraryCommand::GenreSongs(genre) => {
+                    let songs = library.genre_songs(&genre).into_iter().cloned().collect();
+                    res_rx.send(LibraryResponse::Songs(songs)).await.unwrap();
+                }
+                LibraryCommand::Waveform { uuid, buckets } => {
+                    let (path, range) = match library.query_uuid(&uuid) {
+                        Some((song, _)) => match song.primary_uri() {
+                            Ok((URI::Local(path), _)) => (path.clone(), None),
+                            Ok((
+                                URI::Cue {
+                                    location,
+                                    start,
+                                    end,
+                                    ..
+                                },
+                                _,
+                            )) => (location.clone(), Some((*start, *end))),
+                            _ => {
+                                res_rx
+                                    .send(LibraryResponse::Waveform(Err(
+                                        "Song has no local file".to_string()
+                                    )))
+                                    .await
+                                    .unwrap();
+                                continue;
+                            }
+                        },
+                        None => {
+                            res_rx
+                                .send(LibraryResponse::Waveform(Err("Song not found".to_string())))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                    };
+
+                    let cache = WaveformCache::new(config.read().path.with_file_name("waveforms"));
+                    if let Some(cached) = cache.get(&path, range, buckets) {
+                        res_rx
+                            .send(LibraryResponse::Waveform(Ok(cached)))
+                            .await
+                            .unwrap();
+                        continue;
+                    }
+
+                    // Decoding can take a while, so don't hold up the library.
+                    // Once the receiver is dropped progress can't be sent, which
+                    // cancels generation.
+                    rayon::spawn(move || {
+                        let res = waveform::generate(&path, buckets, range, |progress| {
+                            res_rx
+                                .send_blocking(LibraryResponse::WaveformProgress(progress))
+                                .is_ok()
+                        });
+                        if let Ok(waveform) = &res {
+                            _ = cache.insert(&path, range, buckets, waveform);
+                        }
+                        _ = res_rx.send_blocking(LibraryResponse::Waveform(
+                            res.map_err(|e| e.to_string()),
+                        ));
+                    });
+                }
+                LibraryCommand::ScanReport => {
+                    let path = config.read().path.with_file_name(SCAN_REPORT_FILE);
+                    let report = ScanReport::read_file(path).unwrap_or_default();
+                    res_rx
+                        .send(LibraryResponse::ScanReport(report))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RetryScan(target) => {
+                    let path = config.read().path.with_file_name(SCAN_REPORT_FILE);
+                    let mut report = ScanReport::read_file(&path).unwrap_or_default();
+                    report.remove(&target);
+
+                    // A bad pattern can't rule anything out
+                    let rules = exclusion_rules(&config.read()).unwrap_or_default();
+                    let res = match library.scan_file(&target, &rules) {
+                        Ok((added, errors)) => {
+                            report.added += added;
+                            errors.into_iter().for_each(|error| report.push(error));
+                            Ok(report.clone())
+                        }
+                        Err(error) => {
+                            report.push(error.clone());
+                            Err(error)
+                        }
+                    };
+                    _ = report.write_file(&path);
+                    res_rx.send(LibraryResponse::RetryScan(res)).await.unwrap();
+                }
+                LibraryCommand::Scan => {
+                    let library_config = config.read().libraries.get_library(&library.uuid).ok();
+                    let folders = library_config
+                        .as_ref()
+                        .and_then(|lib| lib.scan_folders.clone())
+                        .unwrap_or_default();
+                    if folders.is_empty() {
+                        res_rx
+                            .send(LibraryResponse::Scan(Err(
+                                "The library has no folders to scan".to_string(),
+                            )))
+                            .await
+                            .unwrap();
+                        continue;
+                    }
+                    let job = match jobs.start(JobKind::Scan, library.uuid) {
+                        Ok(job) => job,
+                        Err(running) => {
+                            res_rx
+                                .send(LibraryResponse::ScanStarted {
+                                    job: running,
+                                    attached: true,
+                                })
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                    };
+                    res_rx
+                        .send(LibraryResponse::ScanStarted {
+                            job: job.id(),
+                            attached: false,
+                        })
+                        .await
+                        .unwrap();
+
+                    // Reading every new file takes a long time, so it's done
+                    // on a copy, and only the songs found are added back
+                    let priorities = library_config
+                        .as_ref()
+                        .filter(|lib| lib.scan_conflicts == ScanConflicts::AddLocation)
+                        .map(|lib| FolderPriorities::new(&folders, &lib.folder_priorities));
+                    let scan = ScanJob {
+                        job,
+                        folders,
+                        priorities,
+                        rules: exclusion_rules(&config.read()).unwrap_or_default(),
+                        pattern: library_config
+                            .and_then(|lib| filename_pattern(&lib.filename_pattern?)),
+                        report_path: config.read().path.with_file_name(SCAN_REPORT_FILE),
+                        copy: library.scan_copy(),
+                    };
+                    let (own_mail, cancel, running) =
+                        (own_mail.clone(), cancel.clone(), cancel.job());
+                    std::thread::spawn(move || {
+                        let _running = running;
+                        let report = scan.run(&own_mail, &cancel, |progress| {
+                            // Nobody may be following the scan anymore, but
+                            // it still finishes
+                            _ = res_rx.send_blocking(LibraryResponse::ScanProgress(progress));
+                        });
+                        _ = res_rx.send_blocking(LibraryResponse::Scan(Ok(report)));
+                    });
+                }
+                LibraryCommand::AddScanned(songs, priorities) => {
+                    let (added, merged) = match priorities {
+                        Some(priorities) => library.add_scanned_by_priority(songs, &priorities),
+                        None => (library.add_scanned(songs), 0),
+                    };
+                    if added + merged > 0 {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::AddScanned(added, merged))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::MultiLocationSongs => {
+                    res_rx
+                        .send(LibraryResponse::MultiLocationSongs(
+                            library.multi_location_songs(),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::DropLocation(uuid, uri) => {
+                    let res = library.drop_location(&uuid, &uri);
+                    if res.is_ok() {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::DropLocation(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::CleanTags {
+                    rules,
+                    dry_run,
+                    write_back,
+                } => {
+                    let options = config.read().tag_writing.clone();
+                    let cleanup =
+                        library.clean_tags(&rules, dry_run, write_back.then_some(&options));
+                    res_rx
+                        .send(LibraryResponse::CleanTags(cleanup))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::ApplyFilenamePattern {
+                    uuids,
+                    pattern,
+                    overwrite,
+                    dry_run,
+                } => {
+                    let res = FilenamePattern::parse(&pattern)
+                        .map(|pattern| {
+                            library.apply_filename_pattern(&uuids, &pattern, overwrite, dry_run)
+                        })
+                        .map_err(|e| e.to_string());
+                    if !dry_run && res.as_ref().is_ok_and(|changes| !changes.is_empty()) {
+                        scheduler.mark(LibraryChange::Structural, Instant::now());
+                    }
+                    res_rx
+                        .send(LibraryResponse::ApplyFilenamePattern(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::AnalyzeGain { scope, force } => {
+                    let (groups, skipped) = library.gain_groups(&scope, force);
+                    let silence_threshold_db = config.read().playback.silence_threshold_db;
+
+                    // Measuring takes a long time, so run it on its own pool.
+                    // Once the receiver is dropped progress can't be sent,
+                    // which cancels the analysis, as does closing the player.
+                    let (cancel, job) = (cancel.clone(), cancel.job());
+                    let tracked = jobs.track(JobKind::GainAnalysis, library.uuid);
+                    std::thread::spawn(move || {
+                        let _job = job;
+                        let res =
+                            gain_analysis::analyze(&groups, silence_threshold_db, |progress| {
+                                tracked.progress(progress.done, Some(progress.total));
+                                res_rx
+                                    .send_blocking(LibraryResponse::GainProgress(progress))
+                                    .is_ok()
+                                    && !cancel.is_cancelled()
+                            });
+                        _ = res_rx.send_blocking(LibraryResponse::AnalyzeGain(
+                            res.map(|analysis| GainAnalysis {
+                                skipped,
+                                ..analysis
+                            })
+                            .map_err(|e| e.to_string()),
+                        ));
+                    });
+                }
+                LibraryCommand::VerifyFiles { scope, deep } => {
+                    let (targets, skipped) = library.verify_targets(&scope);
+
+                    // Reading every file takes a long time, so it's done off
+                    // the library like gain analysis, and cancelled the same
+                    let (cancel, job) = (cancel.clone(), cancel.job());
+                    let tracked = jobs.track(JobKind::Verify, library.uuid);
+                    std::thread::spawn(move || {
+                        let _job = job;
+                        let res = integrity::verify(&targets, deep, |progress| {
+                            tracked.progress(progress.done, Some(progress.total));
+                            res_rx
+                                .send_blocking(LibraryResponse::VerifyProgress(progress))
+                                .is_ok()
+                                && !cancel.is_cancelled()
+                        });
+                        _ = res_rx.send_blocking(LibraryResponse::VerifyFiles(
+                            res.map(|report| VerifyReport { skipped, ..report })
+                                .map_err(|e| e.to_string()),
+                        ));
+                    });
+                }
+                LibraryCommand::StoreHashes(hashes) => {
+                    library.store_hashes(&hashes);
+                    res_rx.send(LibraryResponse::StoreHashes).await.unwrap();
+                }
+                LibraryCommand::CheckSongs(scope) => {
+                    let songs = library.songs_in_scope(&scope);
+                    let (cancel, job) = (cancel.clone(), cancel.job());
+                    let tracked = jobs.track(JobKind::CheckSongs, library.uuid);
+                    std::thread::spawn(move || {
+                        let _job = job;
+                        let report = song_problems::check_songs(&songs, |progress| {
+                            tracked.progress(progress.done, Some(progress.total));
+                            res_rx
+                                .send_blocking(LibraryResponse::CheckProgress(progress))
+                                .is_ok()
+                                && !cancel.is_cancelled()
+                        });
+                        _ = res_rx.send_blocking(LibraryResponse::CheckSongs(
+                            report.ok_or_else(|| "Checking songs was cancelled".to_string()),
+                        ));
+                    });
+                }
+                LibraryCommand::SetSilence(uuid, silence) => {
+                    library.set_silence(&uuid, silence);
+                    scheduler.mark(LibraryChange::Stats, Instant::now());
+                    res_rx.send(LibraryResponse::Ok).await.unwrap();
+                }
+                LibraryCommand::SetProblems(problems) => {
+                    library.set_problems(&problems);
+                    res_rx.send(LibraryResponse::SetProblems).await.unwrap();
+                }
+                LibraryCommand::FlaggedSongs => {
+                    res_rx
+                        .send(LibraryResponse::FlaggedSongs(library.flagged_songs()))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::ApplyGain { values, write_back } => {
+                    let options = config.read().tag_writing.clone();
+                    let failed_writes = library.apply_gain(&values, write_back.then_some(&options));
+                    res_rx
+                        .send(LibraryResponse::ApplyGain(failed_writes))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SongDetails(uuid) => {
+                    let Some((song, _)) = library.query_uuid(&uuid) else {
+                        res_rx
+                            .send(LibraryResponse::SongDetails(Err(
+                                "Song not found".to_string()
+                            )))
+                            .await
+                            .unwrap();
+                        continue;
+                    };
+
+                    // Reading art and file properties touches the disk, so
+                    // don't hold up the library while it happens
+                    let song = song.clone();
+                    rayon::spawn(move || {
+                        let details = SongDetails::read(&song);
+                        _ = res_rx
+                            .send_blocking(LibraryResponse::SongDetails(Ok(Box::new(details))));
+                    });
+                }
+                LibraryCommand::Search(query) => {
+                    // Explicit songs aren't found in filtered mode
+                    let filtered = config.read().content_filter.enabled;
+                    res_rx
+                        .send(LibraryResponse::Search(
+                            library.search_best_where(&query, |song| {
+                                !(filtered && song.is_explicit())
+                            }),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetPreferredArt(uuid, index) => {
+                    let res = match library.query_uuid(&uuid) {
+                        Some((song, _))
+                            if index.is_some_and(|index| index >= song.album_art.len()) =>
+                        {
+                            Err("The song has no image at that index".to_string())
+                        }
+                        Some((_, i)) => {
+                            library.library[i].preferred_art = index;
+                            Ok(())
+                        }
+                        None => Err("Song not found".to_string()),
+                    };
+                    res_rx
+                        .send(LibraryResponse::SetPreferredArt(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::ContinueListening { limit, hints } => {
+                    let entries = continue_listening(library, &hints, limit);
+                    res_rx
+                        .send(LibraryResponse::ContinueListening(entries))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetExplicit(uuid, explicit) => {
+                    res_rx
+                        .send(LibraryResponse::SetExplicit(
+                            library.set_explicit(&uuid, explicit),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetTrim(uuid, trim) => {
+                    res_rx
+                        .send(LibraryResponse::SetTrim(library.set_trim(&uuid, trim)))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetAlbumRating { key, rating } => {
+                    res_rx
+                        .send(LibraryResponse::SetAlbumRating(
+                            library.set_album_rating(&key, rating),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetAlbumFavorite { key, favorited } => {
+                    res_rx
+                        .send(LibraryResponse::SetAlbumFavorite(
+                            library.set_album_favorited(&key, favorited),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::SetAlbumGapless { key, gapless } => {
+                    res_rx
+                        .send(LibraryResponse::SetAlbumGapless(
+                            library.set_album_gapless(&key, gapless),
+                        ))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::OrganizeFiles {
+                    pattern,
+                    scope,
+                    dry_run,
+                } => {
+                    let root = config
+                        .read()
+                        .libraries
+                        .get_library(&library.uuid)
+                        .ok()
+                        .and_then(|lib| lib.scan_folders?.into_iter().next());
+                    let res = match (PathTemplate::parse(&pattern), root) {
+                        (Err(e), _) => Err(e.to_string()),
+                        (_, None) => Err("The library has no folder to organize into".to_string()),
+                        (Ok(template), Some(root)) => {
+                            let plan = library.plan_organize(&template, &root, &scope);
+                            match dry_run {
+                                true => Ok(plan),
+                                false => {
+                                    let _job = cancel.job();
+                                    let _tracked = jobs.track(JobKind::Organize, library.uuid);
+                                    library
+                                        .organize_files(plan, &root, &organize_journal, &cancel)
+                                        .map_err(|e| e.to_string())
+                                }
+                            }
+                        }
+                    };
+                    // The journal is kept until the moved locations are saved
+                    let res = match res {
+                        Ok(report) if !dry_run => match guard.save(library) {
+                            Ok(()) => {
+                                scheduler.saved();
+                                _ = fs::remove_file(&organize_journal);
+                                Ok(report)
+                            }
+                            Err(e) => Err(format!(
+                                "Files were moved, but the library could not be saved: {e}"
+                            )),
+                        },
+                        res => res,
+                    };
+                    res_rx
+                        .send(LibraryResponse::OrganizeFiles(res))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RereadSong(uuid) => {
+                    res_rx
+                        .send(LibraryResponse::RereadSong(library.reread_song(&uuid)))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RefreshSongs(uuids) => {
+                    res_rx
+                        .send(LibraryResponse::RefreshSongs(library.refresh_songs(&uuids)))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RefreshAlbum(key) => {
+                    res_rx
+                        .send(LibraryResponse::RefreshAlbum(library.refresh_album(&key)))
+                        .await
+                        .unwrap();
+                }
+                LibraryCommand::RetagFileFormat(uuid) => {
+                    let options = config.read().tag_writing.clone();
+                    res_rx
+                        .send(LibraryResponse::RetagFileFormat(
+                            library.retag_file_format(&uuid, &options),
+                        ))
+                        .await
+                        .unwrap();
+                }
                 _ => {
                     todo!()
                 }
@@ -97,3 +1028,449 @@ impl Controller {
         Ok(())
     }
 }
+
+/// A scan running off the library loop, on a copy of the library
+struct ScanJob {
+    job: Job,
+    folders: Vec<PathBuf>,
+    /// Set when copies of songs under other folders are added as locations
+    priorities: Option<FolderPriorities>,
+    rules: ExclusionRules,
+    /// Fills in the tags of untagged songs from their file names
+    pattern: Option<FilenamePattern>,
+    report_path: PathBuf,
+    copy: MusicLibrary,
+}
+
+impl ScanJob {
+    /// Scans the folders, then adds the songs found through the library
+    /// loop. The job is registered until they're in, so another scan can't
+    /// start and find the same files.
+    fn run(
+        mut self,
+        own_mail: &async_channel::Sender<LibraryCommandInput>,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(ScanProgress),
+    ) -> ScanReport {
+        let known: BTreeSet<Uuid> = self.copy.library.iter().map(|song| song.uuid).collect();
+        let mut report = ScanReport::default();
+        // Every folder is counted first, so the total covers all of them
+        let mut candidates = ScanCandidates::new();
+        for folder in &self.folders {
+            let before = candidates.len();
+            candidates.find(folder, &self.rules, cancel, &mut report, |found| {
+                progress(ScanProgress {
+                    total_candidates: before + found,
+                    ..Default::default()
+                })
+            });
+            if report.cancelled {
+                break;
+            }
+        }
+
+        if !report.cancelled {
+            let imported = self
+                .copy
+                .import_candidates(candidates, &self.rules, cancel, |now| {
+                    self.job.progress(now.processed, Some(now.total_candidates));
+                    progress(now);
+                });
+            report.extend(imported);
+        }
+
+        let mut songs: Vec<Song> = self
+            .copy
+            .library
+            .into_iter()
+            .filter(|song| !known.contains(&song.uuid))
+            .collect();
+        if let Some(pattern) = &self.pattern {
+            for song in &mut songs {
+                song.fill_from_filename(pattern);
+            }
+        }
+        let (command, res) =
+            LibraryCommandInput::command(LibraryCommand::AddScanned(songs, self.priorities));
+        let added = match own_mail.send_blocking(command) {
+            Ok(()) => res.recv_blocking().ok(),
+            Err(_) => None,
+        };
+        // Files added some other way during the scan aren't counted
+        (report.added, report.merged) = match added {
+            Some(LibraryResponse::AddScanned(added, merged)) => (added as i32, merged),
+            _ => (0, 0),
+        };
+        _ = report.write_file(&self.report_path);
+        report
+    }
+}
+
+/// The scan exclusions of the default library
+fn exclusion_rules(config: &Config) -> Result<ExclusionRules, String> {
+    match config.libraries.get_default() {
+        Ok(library) => ExclusionRules::new(&library.exclusions).map_err(|e| e.to_string()),
+        Err(_) => Ok(ExclusionRules::default()),
+    }
+}
+
+/// The library's file name pattern, which is ignored if it's invalid so the
+/// scan still runs
+fn filename_pattern(pattern: &str) -> Option<FilenamePattern> {
+    FilenamePattern::parse(pattern)
+        .inspect_err(|e| eprintln!("Ignoring the file name pattern {pattern:?}: {e}"))
+        .ok()
+}
+
+/// Saves the library, notifying about a conflict with the library file the
+/// first time it's found. If saving fails it's tried again after a delay,
+/// rather than every time the saver thread checks.
+/// Regenerates the auto playlists when anything but stats changed since
+/// they last were, so they're saved along with the change
+fn refresh_auto_playlists(
+    library: &mut MusicLibrary,
+    config: &RwLock<Config>,
+    revision: u64,
+    generated_at: &mut u64,
+) {
+    if *generated_at != revision {
+        let auto = config.read().auto_playlists.clone();
+        library.generate_auto_playlists(auto.track_limit, auto.top_genres);
+        *generated_at = revision;
+    }
+}
+
+fn save(
+    library: &MusicLibrary,
+    guard: &mut LibraryGuard,
+    scheduler: &mut SaveScheduler,
+    conflicted: &mut bool,
+    notify_conflict: &Sender<LibraryConflict>,
+) {
+    match guard.save(library) {
+        Ok(()) => {
+            *conflicted = false;
+            scheduler.saved();
+        }
+        Err(e) => {
+            match e {
+                LibrarySaveError::Conflict if !*conflicted => {
+                    *conflicted = true;
+                    _ = notify_conflict.send(LibraryConflict {
+                        path: guard.path().to_path_buf(),
+                    });
+                }
+                LibrarySaveError::Conflict => (),
+                e => println!("Could not save the library: {e}"),
+            }
+            scheduler.saved();
+            scheduler.mark(LibraryChange::Structural, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use futures::executor::block_on;
+    use parking_lot::RwLock;
+    use uuid::Uuid;
+
+    use crate::{
+        config::{Config, ConfigLibrary},
+        music_controller::{
+            controller::{Controller, ControllerHandle, LibraryCommand, LibraryResponse},
+            controller_handle::{LibraryCommandInput, MAX_SONG_BATCH},
+            jobs::JobRegistry,
+        },
+        music_storage::{
+            cancel::CancelToken,
+            library::{MusicLibrary, Song, Tag, URI},
+        },
+    };
+
+    /// A library loop running on its own thread, for a library of `songs`
+    /// which scans `dir/music`
+    struct TestLoop {
+        mail: async_channel::Sender<LibraryCommandInput>,
+        jobs: JobRegistry,
+    }
+
+    impl TestLoop {
+        fn start(dir: &Path, songs: Vec<Song>) -> Self {
+            let uuid = Uuid::new_v4();
+            let mut config = Config {
+                path: dir.join("config"),
+                ..Default::default()
+            };
+            config.push_library(ConfigLibrary::new(
+                dir.join("library.dlib"),
+                String::from("Library"),
+                Some(vec![dir.join("music")]),
+                Some(uuid),
+            ));
+            let config = Arc::new(RwLock::new(config));
+            let mut library = MusicLibrary::init(dir.join("library.dlib"), uuid).unwrap();
+            library.library = songs;
+
+            let jobs = JobRegistry::new();
+            let (mail, inbox) = async_channel::unbounded();
+            let (notify_conflict, _) = crossbeam_channel::unbounded();
+            {
+                let (mail, jobs) = (mail.clone(), jobs.clone());
+                std::thread::spawn(move || {
+                    futures::executor::block_on(Controller::library_loop(
+                        inbox,
+                        mail,
+                        &mut library,
+                        config,
+                        notify_conflict,
+                        CancelToken::new(),
+                        jobs,
+                    ))
+                });
+            }
+            TestLoop { mail, jobs }
+        }
+
+        fn send(&self, command: LibraryCommand) -> async_channel::Receiver<LibraryResponse> {
+            let (command, res) = LibraryCommandInput::command(command);
+            self.mail.send_blocking(command).unwrap();
+            res
+        }
+    }
+
+    /// Half a second of silence as a WAV file
+    fn write_wav(path: &Path) {
+        let data_len = 8000u32;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        wav.resize(44 + data_len as usize, 0);
+        fs::write(path, wav).unwrap();
+    }
+
+    #[test]
+    fn concurrent_scans_run_once() {
+        let dir = std::env::temp_dir().join(format!("dmp-scan-{}", Uuid::new_v4()));
+        let music = dir.join("music");
+        fs::create_dir_all(&music).unwrap();
+        for i in 0..20 {
+            write_wav(&music.join(format!("{i}.wav")));
+        }
+
+        let test = TestLoop::start(&dir, Vec::new());
+        let first = test.send(LibraryCommand::Scan);
+        let second = test.send(LibraryCommand::Scan);
+
+        let LibraryResponse::ScanStarted {
+            job,
+            attached: false,
+        } = first.recv_blocking().unwrap()
+        else {
+            panic!("The first scan didn't start");
+        };
+        assert!(matches!(
+            second.recv_blocking().unwrap(),
+            LibraryResponse::ScanStarted { job: running, attached: true } if running == job
+        ));
+        assert_eq!(test.jobs.active().len(), 1);
+
+        let report = loop {
+            match first.recv_blocking().unwrap() {
+                LibraryResponse::ScanProgress(_) => (),
+                LibraryResponse::Scan(report) => break report.unwrap(),
+                res => panic!("Unexpected response {res:?}"),
+            }
+        };
+        assert_eq!(report.added, 20);
+        assert!(test.jobs.active().is_empty());
+
+        // Every file was added, and only once
+        let LibraryResponse::AllSongs(songs) =
+            test.send(LibraryCommand::AllSongs).recv_blocking().unwrap()
+        else {
+            panic!("Unexpected response");
+        };
+        assert_eq!(songs.len(), 20);
+    }
+
+    #[test]
+    fn unknown_songs() {
+        let dir = std::env::temp_dir().join(format!("dmp-unknown-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(dir.join("a.flac"))],
+            ..Default::default()
+        };
+        let test = TestLoop::start(&dir, vec![song.clone()]);
+        let missing = Uuid::new_v4();
+
+        let res = test.send(LibraryCommand::Song(missing)).recv_blocking();
+        assert!(matches!(res, Ok(LibraryResponse::Song(None))));
+        let res = test
+            .send(LibraryCommand::ArtSource(missing))
+            .recv_blocking();
+        assert!(matches!(res, Ok(LibraryResponse::ArtSource(None))));
+        let res = test
+            .send(LibraryCommand::SongsBulk(vec![missing, song.uuid]))
+            .recv_blocking();
+        assert!(matches!(res, Ok(LibraryResponse::SongsBulk(songs)) if songs.len() == 1));
+
+        // The loop is still running
+        let Ok(LibraryResponse::Song(Some((found, 0)))) =
+            test.send(LibraryCommand::Song(song.uuid)).recv_blocking()
+        else {
+            panic!("The song wasn't found");
+        };
+        assert_eq!(found, song);
+    }
+
+    #[test]
+    fn removing_missing_songs_waits_off_the_loop() {
+        let dir = std::env::temp_dir().join(format!("dmp-missing-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let song_at = |name: &str| Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(dir.join(name))],
+            ..Default::default()
+        };
+        // Enough songs are left that one is under the limit which can be
+        // removed at once, and the folder isn't taken for an unplugged drive
+        let mut songs: Vec<Song> = (0..10).map(|i| song_at(&format!("{i}.flac"))).collect();
+        for kept in &songs {
+            fs::write(kept.location[0].path(), []).unwrap();
+        }
+        let song = song_at("gone.flac");
+        songs.push(song.clone());
+        let test = TestLoop::start(&dir, songs);
+
+        let removing = test.send(LibraryCommand::RemoveMissing { dry_run: false });
+        // The library answers while the file waits to be checked again
+        let res = test.send(LibraryCommand::Song(song.uuid)).recv_blocking();
+        assert!(matches!(res, Ok(LibraryResponse::Song(Some(_)))));
+        assert!(removing.is_empty());
+
+        let Ok(LibraryResponse::RemoveMissing(Ok(removed))) = removing.recv_blocking() else {
+            panic!("The missing song wasn't removed");
+        };
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].uuid, song.uuid);
+        let res = test.send(LibraryCommand::Song(song.uuid)).recv_blocking();
+        assert!(matches!(res, Ok(LibraryResponse::Song(None))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn auto_playlists_follow_changes() {
+        let dir = std::env::temp_dir().join(format!("dmp-auto-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(dir.join("a.flac"))],
+            tags: [(Tag::Genre, String::from("Jazz"))].into(),
+            ..Default::default()
+        };
+        let test = TestLoop::start(&dir, vec![song]);
+        let titles = || {
+            let Ok(LibraryResponse::Playlists(lists)) =
+                test.send(LibraryCommand::Playlists).recv_blocking()
+            else {
+                panic!("Unexpected response");
+            };
+            lists
+                .into_iter()
+                .map(|(_, title)| title)
+                .collect::<Vec<_>>()
+        };
+
+        // Saving without changes leaves them alone
+        test.send(LibraryCommand::Flush).recv_blocking().unwrap();
+        assert!(titles().is_empty());
+
+        test.send(LibraryCommand::Save).recv_blocking().unwrap();
+        test.send(LibraryCommand::Flush).recv_blocking().unwrap();
+        assert!(titles().contains(&String::from("Jazz")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn songs_fetched_in_one_batch() {
+        let dir = std::env::temp_dir().join(format!("dmp-batch-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let songs: Vec<Song> = (0..300)
+            .map(|i| Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(dir.join(format!("{i}.flac")))],
+                ..Default::default()
+            })
+            .collect();
+        let test = TestLoop::start(&dir, songs.clone());
+
+        // The handle's library commands are counted on their way to the loop
+        let config = Config {
+            path: dir.join("config"),
+            state_path: dir.join("state"),
+            ..Default::default()
+        };
+        let (mut handle, ..) = ControllerHandle::new(
+            MusicLibrary::new(String::new(), Uuid::new_v4()),
+            Arc::new(RwLock::new(config)),
+            None,
+            CancelToken::new(),
+        );
+        let (mail, inbox) = async_channel::unbounded::<LibraryCommandInput>();
+        handle.lib_mail_rx = mail;
+        let sent = Arc::new(AtomicUsize::new(0));
+        {
+            let (sent, test_mail) = (Arc::clone(&sent), test.mail.clone());
+            std::thread::spawn(move || {
+                while let Ok(command) = inbox.recv_blocking() {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    test_mail.send_blocking(command).unwrap();
+                }
+            });
+        }
+
+        // The rows on screen, in the order they're shown, with one which
+        // was removed meanwhile
+        let missing = Uuid::new_v4();
+        let mut rows: Vec<Uuid> = songs[50..249].iter().rev().map(|s| s.uuid).collect();
+        rows.insert(100, missing);
+        let fetched = block_on(handle.lib_get_songs(rows.clone())).unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert_eq!(fetched.len(), 200);
+        for (uuid, song) in rows.iter().zip(&fetched) {
+            match song {
+                Some(song) => assert_eq!(song.uuid, *uuid),
+                None => assert_eq!(*uuid, missing),
+            }
+        }
+
+        // Too many at once are turned away without asking the library
+        let too_many = vec![songs[0].uuid; MAX_SONG_BATCH + 1];
+        assert!(block_on(handle.lib_get_songs(too_many)).is_err());
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,286 @@
+//! How many times the user has listened to songs according to ListenBrainz,
+//! which counts listens from every device rather than just this library
+
+use std::{
+    collections::HashMap,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "connections")]
+use listenbrainz::{raw::Client, ListenBrainz};
+
+use crate::music_storage::library::{Song, Tag};
+
+/// How long fetched counts are used before fetching them again
+pub const LISTEN_COUNT_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long to wait before trying again after fetching failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+/// The time between requests for pages of counts
+const PAGE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most recordings ListenBrainz returns per request
+const PAGE_SIZE: u64 = 1000;
+
+/// Stops fetching at some point for users with huge histories, the
+/// recordings after this are listened to too rarely to matter
+const MAX_PAGES: u64 = 20;
+
+/// A recording along with the number of times it was listened to
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingCount {
+    pub mbid: Option<String>,
+    pub artist: String,
+    pub title: String,
+    pub count: u64,
+}
+
+/// A daily cache of the user's listen counts, fetched all at once so that
+/// looking up songs never makes a request per song
+#[derive(Debug, Default)]
+pub struct ListenCounts {
+    by_mbid: HashMap<String, u64>,
+    by_name: HashMap<(String, String), u64>,
+    fetched_at: Option<Instant>,
+    failed_at: Option<Instant>,
+    #[cfg_attr(not(feature = "connections"), allow(dead_code))]
+    user: Option<String>,
+}
+
+fn name_key(artist: &str, title: &str) -> (String, String) {
+    (artist.trim().to_lowercase(), title.trim().to_lowercase())
+}
+
+impl ListenCounts {
+    /// Whether the counts should be fetched again at `now`
+    pub fn is_stale(&self, now: Instant) -> bool {
+        let expired = |at: Option<Instant>, after| at.is_none_or(|at| now >= at + after);
+        expired(self.fetched_at, LISTEN_COUNT_TTL) && expired(self.failed_at, RETRY_INTERVAL)
+    }
+
+    /// Replaces the cached counts with newly fetched ones
+    pub fn replace(&mut self, recordings: Vec<RecordingCount>, now: Instant) {
+        self.by_mbid.clear();
+        self.by_name.clear();
+        for recording in recordings {
+            if let Some(mbid) = recording.mbid {
+                *self.by_mbid.entry(mbid).or_default() += recording.count;
+            }
+            // The same song can be listed under several recordings
+            *self
+                .by_name
+                .entry(name_key(&recording.artist, &recording.title))
+                .or_default() += recording.count;
+        }
+        self.fetched_at = Some(now);
+        self.failed_at = None;
+    }
+
+    /// Fetches the counts page by page with `fetch`, which is given the
+    /// offset and size of each page. Does nothing if the cache is fresh, or
+    /// if it failed too recently to try again.
+    pub fn refresh<E>(
+        &mut self,
+        now: Instant,
+        mut fetch: impl FnMut(u64, u64) -> Result<Vec<RecordingCount>, E>,
+    ) -> Result<(), E> {
+        if !self.is_stale(now) {
+            return Ok(());
+        }
+
+        let mut recordings = Vec::new();
+        for page in 0..MAX_PAGES {
+            if page > 0 {
+                sleep(PAGE_INTERVAL);
+            }
+            let fetched = match fetch(page * PAGE_SIZE, PAGE_SIZE) {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    self.failed_at = Some(now);
+                    return Err(e);
+                }
+            };
+
+            let last_page = (fetched.len() as u64) < PAGE_SIZE;
+            recordings.extend(fetched);
+            if last_page {
+                break;
+            }
+        }
+
+        self.replace(recordings, now);
+        Ok(())
+    }
+
+    /// Fetches the counts of the user `token` belongs to from ListenBrainz
+    #[cfg(feature = "connections")]
+    pub fn refresh_from_listenbrainz(&mut self, token: &str) -> Result<(), listenbrainz::Error> {
+        if !self.is_stale(Instant::now()) {
+            return Ok(());
+        }
+
+        if self.user.is_none() {
+            let mut client = ListenBrainz::new();
+            if let Err(e) = client.authenticate(token) {
+                self.failed_at = Some(Instant::now());
+                return Err(e);
+            }
+            self.user = client.authenticated_user().map(str::to_string);
+        }
+        let Some(user) = self.user.clone() else {
+            return Err(listenbrainz::Error::NotAuthenticated);
+        };
+
+        let client = Client::new();
+        self.refresh(Instant::now(), |offset, count| {
+            let Some(response) =
+                client.stats_user_recordings(&user, Some(count), Some(offset), Some("all_time"))?
+            else {
+                // Stats haven't been calculated for this user yet
+                return Ok(Vec::new());
+            };
+
+            Ok(response
+                .payload
+                .recordings
+                .into_iter()
+                .map(|recording| RecordingCount {
+                    mbid: recording.recording_mbid,
+                    artist: recording.artist_name,
+                    title: recording.track_name,
+                    count: recording.listen_count,
+                })
+                .collect())
+        })
+    }
+
+    /// Returns the listen count of a song, matching it by its recording
+    /// MBID if it has one, then by artist and title
+    pub fn get(&self, song: &Song) -> Option<u64> {
+        if let Some(count) = song
+            .get_tag(&Tag::Key(String::from("MusicBrainzRecordingId")))
+            .and_then(|mbid| self.by_mbid.get(mbid))
+        {
+            return Some(*count);
+        }
+
+        let artist = song.get_tag(&Tag::Artist)?;
+        let title = song.get_tag(&Tag::Title)?;
+        self.by_name.get(&name_key(artist, title)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+
+    use super::{ListenCounts, RecordingCount, LISTEN_COUNT_TTL, PAGE_SIZE, RETRY_INTERVAL};
+    use crate::music_storage::library::{Song, Tag};
+
+    fn recording(mbid: Option<&str>, artist: &str, title: &str, count: u64) -> RecordingCount {
+        RecordingCount {
+            mbid: mbid.map(str::to_string),
+            artist: artist.to_string(),
+            title: title.to_string(),
+            count,
+        }
+    }
+
+    fn song(mbid: Option<&str>, artist: &str, title: &str) -> Song {
+        let mut song = Song::default();
+        song.set_tag(Tag::Artist, artist.to_string());
+        song.set_tag(Tag::Title, title.to_string());
+        if let Some(mbid) = mbid {
+            song.set_tag(
+                Tag::Key(String::from("MusicBrainzRecordingId")),
+                mbid.to_string(),
+            );
+        }
+        song
+    }
+
+    #[test]
+    fn match_songs() {
+        let mut counts = ListenCounts::default();
+        counts.replace(
+            vec![
+                recording(Some("mbid-1"), "Artist", "Renamed", 10),
+                recording(None, "Artist", "Title", 4),
+                recording(None, "artist", "title ", 1),
+            ],
+            Instant::now(),
+        );
+
+        assert_eq!(
+            counts.get(&song(Some("mbid-1"), "Artist", "Title")),
+            Some(10)
+        );
+        // Songs without an MBID, or with one which wasn't listened to, match by name
+        assert_eq!(counts.get(&song(None, "ARTIST", "Title")), Some(5));
+        assert_eq!(
+            counts.get(&song(Some("mbid-2"), "Artist", "Title")),
+            Some(5)
+        );
+        assert_eq!(counts.get(&song(None, "Artist", "Other")), None);
+    }
+
+    #[test]
+    fn fetch_in_pages_and_cache() {
+        let mut counts = ListenCounts::default();
+        let now = Instant::now();
+
+        let mut requests = Vec::new();
+        counts
+            .refresh::<()>(now, |offset, count| {
+                requests.push(offset);
+                // One full page, then a partial one
+                let len = if offset == 0 { count } else { 1 };
+                Ok((0..len)
+                    .map(|i| recording(None, "Artist", &format!("{offset} {i}"), 1))
+                    .collect())
+            })
+            .unwrap();
+        assert_eq!(requests, [0, PAGE_SIZE]);
+        assert_eq!(counts.get(&song(None, "Artist", "1000 0")), Some(1));
+
+        // Fresh counts aren't fetched again until they expire
+        let fetched = Cell::new(false);
+        let mut fetch = |_, _| {
+            fetched.set(true);
+            Ok::<_, ()>(Vec::new())
+        };
+        counts
+            .refresh(now + Duration::from_secs(60), &mut fetch)
+            .unwrap();
+        assert!(!fetched.get());
+        counts.refresh(now + LISTEN_COUNT_TTL, &mut fetch).unwrap();
+        assert!(fetched.get());
+    }
+
+    #[test]
+    fn failures_are_rate_limited() {
+        let mut counts = ListenCounts::default();
+        let now = Instant::now();
+        assert!(counts.refresh(now, |_, _| Err(())).is_err());
+        assert_eq!(counts.get(&song(None, "Artist", "Title")), None);
+
+        // Trying again straight away doesn't make a request
+        let fetched = Cell::new(false);
+        let mut fetch = |_, _| {
+            fetched.set(true);
+            Ok::<_, ()>(vec![recording(None, "Artist", "Title", 3)])
+        };
+        counts
+            .refresh(now + Duration::from_secs(1), &mut fetch)
+            .unwrap();
+        assert!(!fetched.get());
+
+        counts.refresh(now + RETRY_INTERVAL, &mut fetch).unwrap();
+        assert_eq!(counts.get(&song(None, "Artist", "Title")), Some(3));
+    }
+}
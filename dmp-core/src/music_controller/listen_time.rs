@@ -0,0 +1,162 @@
+//! Measuring how long songs are actually listened to, for their play time.
+//! Only the time the player spends playing counts, so pauses don't add
+//! anything and neither does the part of a song skipped by seeking.
+
+use chrono::TimeDelta;
+use uuid::Uuid;
+
+use super::controller::PlayerLocation;
+
+/// The furthest two positions reported while playing can be apart and still
+/// count as listening between them. The player reports positions several
+/// times a second, so anything further is a seek.
+pub const MAX_LISTEN_STEP: TimeDelta = TimeDelta::seconds(2);
+
+/// How long a song was listened to while it played from `location`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub song: Uuid,
+    pub location: PlayerLocation,
+    pub listened: TimeDelta,
+}
+
+/// Adds up the time the playing song is listened to from the positions
+/// the player reports
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct ListenTimer {
+    /// The track epoch positions are counted for
+    epoch: u64,
+    current: Option<Listen>,
+    /// The last position reported while playing
+    last: Option<TimeDelta>,
+}
+
+impl ListenTimer {
+    /// Starts timing `song` as track `epoch`, returning how long the song
+    /// before it was listened to, if at all
+    pub(super) fn start(
+        &mut self,
+        epoch: u64,
+        song: Uuid,
+        location: PlayerLocation,
+    ) -> Option<Listen> {
+        let ended = self.stop();
+        self.epoch = epoch;
+        self.current = Some(Listen {
+            song,
+            location,
+            listened: TimeDelta::zero(),
+        });
+        ended
+    }
+
+    /// Stops timing, returning how long the song was listened to, if at all
+    pub(super) fn stop(&mut self) -> Option<Listen> {
+        self.last = None;
+        self.current
+            .take()
+            .filter(|listen| listen.listened > TimeDelta::zero())
+    }
+
+    /// Counts the time since the last position reported for track `epoch`
+    /// if the player was playing the whole time. Positions from other
+    /// tracks are left out, as they can still come in around song changes.
+    pub(super) fn position(&mut self, epoch: u64, position: Option<TimeDelta>, playing: bool) {
+        let Some(listen) = self.current.as_mut().filter(|_| epoch == self.epoch) else {
+            return;
+        };
+        let position = position.filter(|_| playing);
+        if let (Some(last), Some(position)) = (self.last, position) {
+            let step = position - last;
+            if step > TimeDelta::zero() && step <= MAX_LISTEN_STEP {
+                listen.listened += step;
+            }
+        }
+        self.last = position;
+    }
+
+    /// How long the playing song has been listened to so far
+    pub(super) fn listened(&self) -> TimeDelta {
+        self.current
+            .as_ref()
+            .map_or(TimeDelta::zero(), |listen| listen.listened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use uuid::Uuid;
+
+    use super::{Listen, ListenTimer};
+    use crate::music_controller::controller::PlayerLocation;
+
+    /// Reports the positions from `from` to `to` seconds every half second
+    fn play(timer: &mut ListenTimer, epoch: u64, from: i64, to: i64, playing: bool) {
+        for ms in (from * 1000..=to * 1000).step_by(500) {
+            timer.position(epoch, Some(TimeDelta::milliseconds(ms)), playing);
+        }
+    }
+
+    #[test]
+    fn pauses_and_seeks_left_out() {
+        let mut timer = ListenTimer::default();
+        let song = Uuid::new_v4();
+        assert_eq!(timer.start(1, song, PlayerLocation::Library), None);
+
+        play(&mut timer, 1, 0, 30, true);
+        assert_eq!(timer.listened(), TimeDelta::seconds(30));
+
+        // Paused for a long time, with the position still being reported
+        for _ in 0..1000 {
+            timer.position(1, Some(TimeDelta::seconds(30)), false);
+        }
+        play(&mut timer, 1, 30, 40, true);
+        assert_eq!(timer.listened(), TimeDelta::seconds(40));
+
+        // Seeking forward skips the time in between, and seeking back
+        // doesn't take anything away
+        play(&mut timer, 1, 100, 110, true);
+        play(&mut timer, 1, 5, 10, true);
+        assert_eq!(timer.listened(), TimeDelta::seconds(55));
+
+        // Nothing is reported for a moment while buffering
+        timer.position(1, None, true);
+        play(&mut timer, 1, 11, 12, true);
+        assert_eq!(timer.listened(), TimeDelta::seconds(56));
+    }
+
+    #[test]
+    fn attributed_on_song_change() {
+        let mut timer = ListenTimer::default();
+        let first = Uuid::new_v4();
+        let playlist = PlayerLocation::Playlist(Uuid::new_v4());
+        timer.start(1, first, playlist);
+        play(&mut timer, 1, 0, 20, true);
+
+        // Positions from the next track before it's announced don't count
+        // for either song
+        play(&mut timer, 2, 0, 5, true);
+        let second = Uuid::new_v4();
+        assert_eq!(
+            timer.start(2, second, PlayerLocation::Library),
+            Some(Listen {
+                song: first,
+                location: playlist,
+                listened: TimeDelta::seconds(20),
+            })
+        );
+        assert_eq!(timer.listened(), TimeDelta::zero());
+
+        // A stray position from the first song
+        timer.position(1, Some(TimeDelta::seconds(21)), true);
+        play(&mut timer, 2, 5, 8, true);
+        assert_eq!(timer.listened(), TimeDelta::seconds(3));
+
+        // Songs which were never listened to aren't given anything
+        assert!(timer.start(3, first, playlist).is_some());
+        play(&mut timer, 3, 0, 10, false);
+        assert_eq!(timer.stop(), None);
+        assert_eq!(timer.listened(), TimeDelta::zero());
+    }
+}
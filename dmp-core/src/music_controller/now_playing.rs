@@ -0,0 +1,331 @@
+//! Everything shown about the playing song, kept together in one place.
+//! The song, whether it's playing and how far along it is used to be sent
+//! separately and could arrive in any order, so the title of one song could
+//! be shown with the length of the next. Every change goes through
+//! [`NowPlayingTracker`] instead, which only takes a position for the song
+//! it's showing, and says when anything changed.
+
+use std::sync::Arc;
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::music_storage::library::SongRef;
+
+use super::{
+    controller::{
+        LoadFailure, NowPlayingChange, PlaybackInfo, PlaybackState, PlayerLocation, QueuePosition,
+        TrackChangeReason,
+    },
+    controller_state::PlaybackModes,
+    icy::StreamTitle,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NowPlaying {
+    /// Goes up with every change, so an older one can be told apart
+    pub revision: u64,
+    pub song: Option<SongRef>,
+    /// The track `song` is playing, when it's a radio station which names it
+    pub stream_title: Option<StreamTitle>,
+    /// Where the song is played from
+    pub source: Option<PlayerLocation>,
+    /// The name of the playlist it's played from
+    pub source_name: Option<String>,
+    pub state: PlaybackState,
+    pub position_ms: Option<i64>,
+    pub duration_ms: Option<i64>,
+    /// The track epoch of the song, for sending with seeks
+    pub epoch: u64,
+    /// The rate the output device runs at, if it's known
+    pub sample_rate: Option<u32>,
+    pub modes: PlaybackModes,
+    /// What to fetch the song's art with, if it has any
+    pub art: Option<Uuid>,
+    pub queue_position: Option<QueuePosition>,
+    /// Why the song started playing
+    pub reason: Option<TrackChangeReason>,
+}
+
+/// Keeps the [`NowPlaying`] up to date as the player changes, shared by
+/// everything which changes it. Changes are coalesced, so whoever's
+/// following them is told once for any number of changes made since they
+/// last looked.
+#[derive(Debug, Clone)]
+pub struct NowPlayingTracker {
+    now_playing: Arc<Mutex<NowPlaying>>,
+    changed: (Sender<()>, Receiver<()>),
+}
+
+impl Default for NowPlayingTracker {
+    fn default() -> Self {
+        NowPlayingTracker {
+            now_playing: Arc::default(),
+            changed: crossbeam_channel::bounded(1),
+        }
+    }
+}
+
+impl NowPlayingTracker {
+    pub fn new(modes: PlaybackModes) -> Self {
+        let tracker = NowPlayingTracker::default();
+        tracker.now_playing.lock().modes = modes;
+        tracker
+    }
+
+    pub fn get(&self) -> NowPlaying {
+        self.now_playing.lock().clone()
+    }
+
+    /// Receives once after any number of changes, after which the latest
+    /// is read with [`NowPlayingTracker::get`]
+    pub fn changes(&self) -> Receiver<()> {
+        self.changed.1.clone()
+    }
+
+    /// A different song started, or a station named another track. What's
+    /// known of the last song's position goes with it.
+    pub(super) fn song_changed(
+        &self,
+        change: &NowPlayingChange,
+        state: PlaybackState,
+        epoch: u64,
+        source_name: Option<String>,
+    ) {
+        self.update(|now| {
+            if now.epoch != epoch
+                || now.song.as_ref().map(|song| song.uuid) != Some(change.song.uuid)
+            {
+                now.position_ms = None;
+                now.duration_ms = None;
+            }
+            now.song = Some(SongRef::from(&*change.song));
+            now.stream_title = change.stream_title.clone();
+            now.source = Some(change.position.location);
+            now.source_name = source_name;
+            now.state = state;
+            now.epoch = epoch;
+            now.art = (!change.song.album_art.is_empty()).then_some(change.song.uuid);
+            now.queue_position = Some(change.position);
+            now.reason = Some(change.reason);
+        });
+    }
+
+    /// Nothing is playing anymore. `epoch` is the one after the last song,
+    /// so the last of its positions aren't taken for anything.
+    pub(super) fn stopped(&self, epoch: u64) {
+        self.update(|now| {
+            *now = NowPlaying {
+                revision: now.revision,
+                modes: now.modes,
+                sample_rate: now.sample_rate,
+                epoch,
+                ..Default::default()
+            }
+        });
+    }
+
+    pub(super) fn state_changed(&self, state: PlaybackState) {
+        self.update(|now| now.state = state);
+    }
+
+    /// A song couldn't be loaded, and the player is left as `failure` says
+    pub(super) fn load_failed(&self, failure: &LoadFailure) {
+        self.update(|now| now.state = failure.state);
+    }
+
+    pub(super) fn modes_changed(&self, modes: PlaybackModes) {
+        self.update(|now| now.modes = modes);
+    }
+
+    /// Where the player is in the song. Positions from any other song than
+    /// the one shown are left out, as the player moves on before the song
+    /// change comes through.
+    pub(super) fn playback(&self, info: &PlaybackInfo) {
+        self.update(|now| {
+            if now.song.is_none() || info.epoch != now.epoch {
+                return;
+            }
+            now.position_ms = info.position.map(|position| position.num_milliseconds());
+            now.duration_ms = info.duration.map(|duration| duration.num_milliseconds());
+            now.sample_rate = info.output.sample_rate;
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut NowPlaying)) {
+        let mut now = self.now_playing.lock();
+        let before = now.clone();
+        change(&mut now);
+        if *now == before {
+            return;
+        }
+        now.revision += 1;
+        // A change already waiting to be seen covers this one too
+        if let Err(TrySendError::Disconnected(_)) = self.changed.0.try_send(()) {
+            unreachable!("the tracker keeps its own receiver")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+    use chrono::TimeDelta;
+    use uuid::Uuid;
+
+    use super::NowPlayingTracker;
+    use crate::{
+        music_controller::{
+            controller::{
+                LoadFailure, NowPlayingChange, PlaybackInfo, PlaybackState, PlayerLocation,
+                QueuePosition, TrackChangeReason,
+            },
+            controller_state::{PlaybackModes, RepeatMode},
+            icy::StreamTitle,
+        },
+        music_storage::library::{AlbumArt, Song, Tag, URI},
+    };
+
+    fn change(title: &str, secs: u64) -> NowPlayingChange {
+        NowPlayingChange {
+            song: Arc::new(Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(PathBuf::from(format!("/music/{title}.flac")))],
+                duration: Duration::from_secs(secs),
+                album_art: vec![AlbumArt::Embedded(0)],
+                tags: BTreeMap::from([(Tag::Title, title.to_string())]),
+                ..Default::default()
+            }),
+            previous: None,
+            reason: TrackChangeReason::Natural,
+            position: QueuePosition {
+                index: 0,
+                total: 2,
+                location: PlayerLocation::Library,
+            },
+            stream_title: None,
+        }
+    }
+
+    fn info(epoch: u64, position: i64, duration: i64) -> PlaybackInfo {
+        PlaybackInfo {
+            position: Some(TimeDelta::seconds(position)),
+            duration: Some(TimeDelta::seconds(duration)),
+            epoch,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn positions_follow_the_song_shown() {
+        let tracker = NowPlayingTracker::default();
+        let first = change("Oblivious", 263);
+        tracker.song_changed(&first, PlaybackState::Playing, 1, None);
+        tracker.playback(&info(1, 10, 263));
+        let now = tracker.get();
+        assert_eq!(now.song.unwrap().title.as_deref(), Some("Oblivious"));
+        assert_eq!(
+            (now.position_ms, now.duration_ms),
+            (Some(10_000), Some(263_000))
+        );
+        assert_eq!(now.art, Some(first.song.uuid));
+        assert_eq!(now.reason, Some(TrackChangeReason::Natural));
+
+        // The player moves on to the next song before the change arrives,
+        // and the old song isn't shown with its length
+        tracker.playback(&info(2, 0, 180));
+        let now = tracker.get();
+        assert_eq!(now.song.unwrap().uuid, first.song.uuid);
+        assert_eq!(now.duration_ms, Some(263_000));
+
+        let mut second = change("Sprinkler", 180);
+        second.position.index = 1;
+        second.position.location = PlayerLocation::Playlist(Uuid::new_v4());
+        tracker.song_changed(
+            &second,
+            PlaybackState::Playing,
+            2,
+            Some(String::from("Favourites")),
+        );
+        let now = tracker.get();
+        assert_eq!(now.song.unwrap().uuid, second.song.uuid);
+        assert_eq!((now.position_ms, now.duration_ms), (None, None));
+        assert_eq!(now.source, Some(second.position.location));
+        assert_eq!(now.source_name.as_deref(), Some("Favourites"));
+        assert_eq!(now.queue_position, Some(second.position));
+        // A late position from the song before is left out
+        tracker.playback(&info(1, 262, 263));
+        assert_eq!(tracker.get().duration_ms, None);
+        tracker.playback(&info(2, 1, 180));
+        assert_eq!(tracker.get().duration_ms, Some(180_000));
+
+        // A station naming its next track keeps the position
+        let mut titled = second.clone();
+        titled.stream_title = StreamTitle::parse("Kalafina - Magia");
+        tracker.song_changed(
+            &titled,
+            PlaybackState::Playing,
+            2,
+            Some(String::from("Favourites")),
+        );
+        let now = tracker.get();
+        assert_eq!(now.position_ms, Some(1_000));
+        assert_eq!(now.stream_title.unwrap().title, "Magia");
+    }
+
+    #[test]
+    fn every_change_is_seen() {
+        let modes = PlaybackModes {
+            repeat: RepeatMode::All,
+            ..Default::default()
+        };
+        let tracker = NowPlayingTracker::new(modes);
+        let changes = tracker.changes();
+        assert_eq!(tracker.get().modes, modes);
+        assert!(changes.try_recv().is_err());
+
+        let song = change("Oblivious", 263);
+        tracker.song_changed(&song, PlaybackState::Paused, 1, None);
+        assert!(changes.try_recv().is_ok());
+        let revision = tracker.get().revision;
+
+        tracker.state_changed(PlaybackState::Playing);
+        assert_eq!(tracker.get().state, PlaybackState::Playing);
+        tracker.modes_changed(PlaybackModes::default());
+        assert_eq!(tracker.get().modes, PlaybackModes::default());
+        tracker.playback(&info(1, 5, 263));
+        tracker.load_failed(&LoadFailure {
+            uuid: Uuid::new_v4(),
+            title: String::from("Missing"),
+            message: String::new(),
+            state: PlaybackState::Paused,
+        });
+        assert_eq!(tracker.get().state, PlaybackState::Paused);
+
+        // Four changes are told about once, and each one counted
+        assert!(changes.try_recv().is_ok());
+        assert!(changes.try_recv().is_err());
+        assert_eq!(tracker.get().revision, revision + 4);
+
+        // Nothing changing isn't a change
+        tracker.state_changed(PlaybackState::Paused);
+        tracker.playback(&info(1, 5, 263));
+        assert!(changes.try_recv().is_err());
+        assert_eq!(tracker.get().revision, revision + 4);
+
+        // Stopping clears the song but keeps the modes
+        tracker.stopped(2);
+        let now = tracker.get();
+        assert!(changes.try_recv().is_ok());
+        assert_eq!(now.song, None);
+        assert_eq!(now.reason, None);
+        assert_eq!((now.state, now.position_ms), (PlaybackState::Stopped, None));
+        assert_eq!(now.modes, PlaybackModes::default());
+        tracker.playback(&info(1, 6, 263));
+        tracker.playback(&info(2, 0, 0));
+        assert_eq!(tracker.get().position_ms, None);
+    }
+}
@@ -0,0 +1,358 @@
+//! Writing the playing song to files for stream overlays like OBS, which
+//! show whatever a text file has in it. The files are rewritten on every
+//! song change, pause and resume, and emptied when playback stops.
+
+use std::{fs, io, path::Path, sync::Arc, time::Duration};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use prismriver::State as PrismState;
+use serde::Serialize;
+
+use crate::config::ConfigNowPlayingFile;
+use crate::music_storage::library::{Song, Tag};
+
+use super::controller::NowPlayingChange;
+
+/// How long to wait for things to settle before writing, so skipping
+/// through songs doesn't write each of them
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+pub(super) enum NowPlayingEvent {
+    Song(Box<NowPlayingChange>),
+    State(PrismState),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+struct FilePosition {
+    /// From 1
+    index: usize,
+    total: usize,
+}
+
+/// What's written to the JSON file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct NowPlayingInfo {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    position: FilePosition,
+    playing: bool,
+}
+
+impl NowPlayingInfo {
+    fn new(change: &NowPlayingChange) -> Self {
+        let song = change.shown_song();
+        NowPlayingInfo {
+            title: song.get_tag(&Tag::Title).cloned(),
+            artist: song.get_tag(&Tag::Artist).cloned(),
+            album: song.get_tag(&Tag::Album).cloned(),
+            position: FilePosition {
+                index: change.position.index + 1,
+                total: change.position.total,
+            },
+            playing: true,
+        }
+    }
+}
+
+/// Fills in the placeholders of `template`. Tags the song doesn't have are
+/// left blank, and anything else in braces is kept as it is.
+fn render(template: &str, info: &NowPlayingInfo) -> String {
+    let position = format!("{}/{}", info.position.index, info.position.total);
+    let values = [
+        ("{title}", info.title.as_deref()),
+        ("{artist}", info.artist.as_deref()),
+        ("{album}", info.album.as_deref()),
+        ("{position}", Some(position.as_str())),
+    ];
+    fill_placeholders(template, &values)
+}
+
+/// Puts the values in place of their placeholders in `template`, leaving
+/// the ones with no value blank
+pub(super) fn fill_placeholders(template: &str, values: &[(&str, Option<&str>)]) -> String {
+    // Filled in as it's read, so a tag with braces in it is left as it is
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            Some((placeholder, value)) => {
+                text.push_str(value.unwrap_or_default());
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Replaces the file at `path` all at once, so it's never read half written
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
+
+struct NowPlayingFiles {
+    config: ConfigNowPlayingFile,
+}
+
+impl NowPlayingFiles {
+    fn write(&self, info: &NowPlayingInfo) -> io::Result<()> {
+        if let Some(path) = &self.config.text_path {
+            replace(path, render(&self.config.template, info).as_bytes())?;
+        }
+        if let Some(path) = &self.config.json_path {
+            replace(path, &serde_json::to_vec_pretty(info)?)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the song's cover art, removing the last one if it has none
+    fn copy_art(&self, song: &Song) -> io::Result<()> {
+        let Some(path) = &self.config.art_path else {
+            return Ok(());
+        };
+        match song.front_cover() {
+            Ok(Some(art)) => replace(path, &art),
+            _ => remove(path),
+        }
+    }
+
+    /// Empties the files, for when nothing is playing
+    fn clear(&self) -> io::Result<()> {
+        for path in [&self.config.text_path, &self.config.json_path]
+            .into_iter()
+            .flatten()
+        {
+            fs::write(path, [])?;
+        }
+        match &self.config.art_path {
+            Some(path) => remove(path),
+            None => Ok(()),
+        }
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Keeps the files up to date with `events` until they stop coming, then
+/// empties them
+pub(super) fn write_now_playing(config: ConfigNowPlayingFile, events: Receiver<NowPlayingEvent>) {
+    let files = NowPlayingFiles { config };
+    let report = |res: io::Result<()>| {
+        if let Err(e) = res {
+            eprintln!("Couldn't write the now playing file: {e}");
+        }
+    };
+    report(files.clear());
+
+    let mut info: Option<NowPlayingInfo> = None;
+    let mut playing = false;
+    while let Ok(event) = events.recv() {
+        let mut event = Some(event);
+        let mut changed = false;
+        let mut new_song: Option<Arc<Song>> = None;
+        loop {
+            match event.take() {
+                Some(NowPlayingEvent::Song(change)) => {
+                    info = Some(NowPlayingInfo::new(&change));
+                    new_song = Some(change.song);
+                    changed = true;
+                }
+                // Stopping empties the files right away, and drops any
+                // change which was waiting to be written
+                Some(NowPlayingEvent::State(PrismState::Stopped)) => {
+                    playing = false;
+                    info = None;
+                    new_song = None;
+                    changed = false;
+                    report(files.clear());
+                }
+                Some(NowPlayingEvent::State(state)) => {
+                    playing = state == PrismState::Playing;
+                    changed |= info.is_some();
+                }
+                None => (),
+            }
+
+            match events.recv_timeout(DEBOUNCE) {
+                Ok(next) => event = Some(next),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    report(files.clear());
+                    return;
+                }
+            }
+        }
+
+        if let (true, Some(info)) = (changed, &mut info) {
+            info.playing = playing;
+            report(files.write(info));
+        }
+        if let Some(song) = new_song {
+            report(files.copy_art(&song));
+        }
+    }
+    report(files.clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use prismriver::State as PrismState;
+    use uuid::Uuid;
+
+    use super::{render, write_now_playing, FilePosition, NowPlayingEvent, NowPlayingInfo};
+    use crate::config::ConfigNowPlayingFile;
+    use crate::music_controller::controller::{
+        NowPlayingChange, PlayerLocation, QueuePosition, TrackChangeReason,
+    };
+    use crate::music_storage::library::{Song, Tag};
+
+    fn info() -> NowPlayingInfo {
+        NowPlayingInfo {
+            title: Some(String::from("Lacrimosa")),
+            artist: Some(String::from("Kalafina")),
+            album: None,
+            position: FilePosition {
+                index: 3,
+                total: 12,
+            },
+            playing: true,
+        }
+    }
+
+    #[test]
+    fn template_rendering() {
+        let info = info();
+        assert_eq!(render("{title} - {artist}", &info), "Lacrimosa - Kalafina");
+        assert_eq!(
+            render("Now playing: {title} ({position})", &info),
+            "Now playing: Lacrimosa (3/12)"
+        );
+        // Missing tags are blank, and unknown placeholders are left alone
+        assert_eq!(
+            render("{album}|{year}|{title}{title}", &info),
+            "|{year}|LacrimosaLacrimosa"
+        );
+
+        let braces = NowPlayingInfo {
+            title: Some(String::from("{artist}")),
+            ..info
+        };
+        assert_eq!(render("{title} - {artist}", &braces), "{artist} - Kalafina");
+    }
+
+    /// Waits for the file at `path` to have `expected` in it
+    fn wait_for(path: &PathBuf, expected: &str) {
+        let start = Instant::now();
+        while fs::read_to_string(path).unwrap_or_default() != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Expected {expected:?}, found {:?}",
+                fs::read_to_string(path)
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn stop_clears_files() {
+        let dir = std::env::temp_dir().join(format!("dmp-now-playing-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = ConfigNowPlayingFile {
+            enabled: true,
+            text_path: Some(dir.join("now_playing.txt")),
+            json_path: Some(dir.join("now_playing.json")),
+            ..Default::default()
+        };
+        let (text, json) = (dir.join("now_playing.txt"), dir.join("now_playing.json"));
+
+        let (events, rx) = crossbeam_channel::unbounded();
+        let writer = thread::spawn(move || write_now_playing(config, rx));
+        let change = |title: &str| {
+            let mut song = Song::default();
+            song.set_tag(Tag::Title, title.to_string());
+            song.set_tag(Tag::Artist, String::from("ClariS"));
+            NowPlayingEvent::Song(Box::new(NowPlayingChange {
+                song: Arc::new(song),
+                previous: None,
+                reason: TrackChangeReason::UserNext,
+                position: QueuePosition {
+                    index: 0,
+                    total: 2,
+                    location: PlayerLocation::Library,
+                },
+                stream_title: None,
+            }))
+        };
+
+        // Only the last of a burst of changes is written
+        events.send(change("irony")).unwrap();
+        events.send(change("CONNECT")).unwrap();
+        events
+            .send(NowPlayingEvent::State(PrismState::Playing))
+            .unwrap();
+        wait_for(&text, "CONNECT - ClariS");
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(written["title"], "CONNECT");
+        assert_eq!(written["position"]["index"], 1);
+        assert_eq!(written["playing"], true);
+
+        events
+            .send(NowPlayingEvent::State(PrismState::Paused))
+            .unwrap();
+        let start = Instant::now();
+        while !fs::read_to_string(&json)
+            .unwrap()
+            .contains("\"playing\": false")
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Stopping empties the files, even with a change waiting to be written
+        events.send(change("Alive")).unwrap();
+        events
+            .send(NowPlayingEvent::State(PrismState::Stopped))
+            .unwrap();
+        wait_for(&text, "");
+        wait_for(&json, "");
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(fs::read_to_string(&text).unwrap(), "");
+
+        // And so does shutting down
+        events.send(change("Alive")).unwrap();
+        wait_for(&text, "Alive - ClariS");
+        drop(events);
+        writer.join().unwrap();
+        assert_eq!(fs::read_to_string(&text).unwrap(), "");
+        assert_eq!(fs::read_to_string(&json).unwrap(), "");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
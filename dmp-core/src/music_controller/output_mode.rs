@@ -0,0 +1,272 @@
+//! Switching between playing through the system mixer and holding the output
+//! device alone, so songs play at their own sample rate without being
+//! resampled on the way out.
+//!
+//! The stream itself belongs to the player, which opens it again when the
+//! song is reloaded. An [`OutputSink`] only finds out whether the device can
+//! be had for the player alone, and at which rates.
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::OutputMode;
+
+/// The rates looked for on devices which support a range of them
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const COMMON_RATES: [u32; 8] = [
+    44_100, 48_000, 88_200, 96_000, 176_400, 192_000, 352_800, 384_000,
+];
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum OutputError {
+    #[error("The output device is being used by another program")]
+    Busy,
+    #[error("Exclusive output isn't available: {0}")]
+    Unavailable(String),
+    #[error(
+        "The output device can't play {rate} Hz without resampling, it only supports {}",
+        list_rates(.supported)
+    )]
+    UnsupportedRate { rate: u32, supported: Vec<u32> },
+}
+
+fn list_rates(rates: &[u32]) -> String {
+    match rates.is_empty() {
+        true => "no known rates".to_string(),
+        false => rates
+            .iter()
+            .map(|rate| format!("{rate} Hz"))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+/// The output the player is using
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NegotiatedOutput {
+    pub mode: OutputMode,
+    /// The rate the device runs at, if it's known
+    pub sample_rate: Option<u32>,
+}
+
+/// The result of [`switch_output`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputSwitch {
+    pub output: NegotiatedOutput,
+    /// Why exclusive output couldn't be had, if it fell back to shared
+    /// output instead
+    pub fallback: Option<String>,
+}
+
+/// An audio output which can be taken for the player alone
+pub trait OutputSink: Send {
+    /// Takes the device for the player alone, returning the sample rates it
+    /// can be opened at
+    fn acquire_exclusive(&mut self) -> Result<Vec<u32>, OutputError>;
+    /// Gives the device back to the system mixer, if it was taken
+    fn release(&mut self);
+    /// The rate the system mixer runs the device at
+    fn shared_rate(&mut self) -> Option<u32>;
+}
+
+/// Switches `sink` to `mode` for a song at `rate`. When the device can't be
+/// had alone, this falls back to shared output with the reason in
+/// [`OutputSwitch::fallback`]. A device which can't play the song's rate is
+/// an error instead, since falling back would resample it.
+pub fn switch_output(
+    sink: &mut dyn OutputSink,
+    mode: OutputMode,
+    rate: Option<u32>,
+) -> Result<OutputSwitch, OutputError> {
+    let fallback = match mode {
+        OutputMode::Shared => None,
+        OutputMode::Exclusive => match sink.acquire_exclusive() {
+            Ok(supported) => {
+                if let Some(rate) = rate.filter(|rate| !supported.contains(rate)) {
+                    sink.release();
+                    return Err(OutputError::UnsupportedRate { rate, supported });
+                }
+                return Ok(OutputSwitch {
+                    output: NegotiatedOutput {
+                        mode,
+                        sample_rate: rate,
+                    },
+                    fallback: None,
+                });
+            }
+            Err(e) => Some(e.to_string()),
+        },
+    };
+
+    sink.release();
+    Ok(OutputSwitch {
+        output: NegotiatedOutput {
+            mode: OutputMode::Shared,
+            sample_rate: sink.shared_rate(),
+        },
+        fallback,
+    })
+}
+
+/// The default output of the platform. Exclusive output goes through the
+/// ALSA hardware devices on Linux, which skip the mixer. The audio backend
+/// doesn't offer it anywhere else yet, so it always falls back to shared
+/// output there.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemOutput;
+
+impl OutputSink for SystemOutput {
+    #[cfg(target_os = "linux")]
+    fn acquire_exclusive(&mut self) -> Result<Vec<u32>, OutputError> {
+        use cpal::{
+            traits::{DeviceTrait, HostTrait},
+            SupportedStreamConfigsError,
+        };
+
+        let device = cpal::default_host()
+            .output_devices()
+            .into_iter()
+            .flatten()
+            .find(|device| device.name().is_ok_and(|name| name.starts_with("hw:")))
+            .ok_or_else(|| {
+                OutputError::Unavailable("no hardware output device was found".to_string())
+            })?;
+        let configs = device.supported_output_configs().map_err(|e| match e {
+            SupportedStreamConfigsError::DeviceNotAvailable => OutputError::Busy,
+            e => OutputError::Unavailable(e.to_string()),
+        })?;
+
+        let mut rates: Vec<u32> = configs
+            .flat_map(|range| {
+                let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+                COMMON_RATES
+                    .into_iter()
+                    .filter(move |rate| (min..=max).contains(rate))
+                    .chain([min, max])
+            })
+            .collect();
+        rates.sort_unstable();
+        rates.dedup();
+        Ok(rates)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn acquire_exclusive(&mut self) -> Result<Vec<u32>, OutputError> {
+        Err(OutputError::Unavailable(
+            "it isn't supported on this platform".to_string(),
+        ))
+    }
+
+    // The player's stream is what holds the device, so nothing is kept here
+    fn release(&mut self) {}
+
+    fn shared_rate(&mut self) -> Option<u32> {
+        use cpal::traits::{DeviceTrait, HostTrait};
+
+        let device = cpal::default_host().default_output_device()?;
+        Some(device.default_output_config().ok()?.sample_rate().0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{switch_output, NegotiatedOutput, OutputError, OutputSink};
+    use crate::config::OutputMode;
+
+    /// A sink which is exclusive while acquired, and fails to be acquired
+    /// with `error` if one is set
+    struct MockSink {
+        rates: Vec<u32>,
+        error: Option<OutputError>,
+        exclusive: bool,
+    }
+
+    impl MockSink {
+        fn new(rates: &[u32], error: Option<OutputError>) -> Self {
+            MockSink {
+                rates: rates.to_vec(),
+                error,
+                exclusive: false,
+            }
+        }
+    }
+
+    impl OutputSink for MockSink {
+        fn acquire_exclusive(&mut self) -> Result<Vec<u32>, OutputError> {
+            if let Some(e) = self.error.clone() {
+                return Err(e);
+            }
+            self.exclusive = true;
+            Ok(self.rates.clone())
+        }
+
+        fn release(&mut self) {
+            self.exclusive = false;
+        }
+
+        fn shared_rate(&mut self) -> Option<u32> {
+            Some(48_000)
+        }
+    }
+
+    #[test]
+    fn exclusive_at_the_song_rate() {
+        let mut sink = MockSink::new(&[44_100, 48_000, 96_000], None);
+        let switch = switch_output(&mut sink, OutputMode::Exclusive, Some(96_000)).unwrap();
+        assert_eq!(
+            switch.output,
+            NegotiatedOutput {
+                mode: OutputMode::Exclusive,
+                sample_rate: Some(96_000),
+            }
+        );
+        assert_eq!(switch.fallback, None);
+        assert!(sink.exclusive);
+
+        // Going back hands the device to the mixer again
+        let switch = switch_output(&mut sink, OutputMode::Shared, Some(96_000)).unwrap();
+        assert_eq!(
+            switch.output,
+            NegotiatedOutput {
+                mode: OutputMode::Shared,
+                sample_rate: Some(48_000),
+            }
+        );
+        assert!(!sink.exclusive);
+    }
+
+    #[test]
+    fn falls_back_when_not_acquired() {
+        for error in [
+            OutputError::Busy,
+            OutputError::Unavailable("it isn't supported on this platform".to_string()),
+        ] {
+            let mut sink = MockSink::new(&[44_100], Some(error.clone()));
+            let switch = switch_output(&mut sink, OutputMode::Exclusive, Some(44_100)).unwrap();
+            assert_eq!(switch.output.mode, OutputMode::Shared);
+            assert_eq!(switch.output.sample_rate, Some(48_000));
+            assert_eq!(switch.fallback, Some(error.to_string()));
+            assert!(!sink.exclusive);
+        }
+    }
+
+    #[test]
+    fn unsupported_rate_is_an_error() {
+        let mut sink = MockSink::new(&[44_100, 48_000], None);
+        let error = switch_output(&mut sink, OutputMode::Exclusive, Some(192_000)).unwrap_err();
+        assert_eq!(
+            error,
+            OutputError::UnsupportedRate {
+                rate: 192_000,
+                supported: vec![44_100, 48_000],
+            }
+        );
+        assert!(error.to_string().ends_with("44100 Hz, 48000 Hz"));
+        assert!(!sink.exclusive);
+
+        // Without a rate to check, such as for a stream, any rate will do
+        let switch = switch_output(&mut sink, OutputMode::Exclusive, None).unwrap();
+        assert_eq!(switch.output.mode, OutputMode::Exclusive);
+        assert_eq!(switch.output.sample_rate, None);
+    }
+}
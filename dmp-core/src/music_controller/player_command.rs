@@ -1,35 +1,148 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
 use chrono::TimeDelta;
 use crossbeam_channel::Sender;
-use kushi::{QueueItem, QueueItemType};
-use prismriver::{Prismriver, Volume};
+use kushi::{QueueError, QueueItem, QueueItemType};
+use parking_lot::{Mutex, RwLock};
+use prismriver::{utils::Url, Error as PrismError, Prismriver, State as PrismState, Volume};
+
+use rand::seq::SliceRandom;
+use uuid::Uuid;
 
+use crate::config::{Config, OutputMode};
 use crate::music_controller::{
-    controller::{LibraryCommand, LibraryResponse},
+    controller::{
+        LibraryCommand, LibraryResponse, LoadFailure, NowPlayingChange, PlaybackState, PlayerError,
+        PlayerNotification, QueuePosition, SkippedSong, TrackChangeReason,
+    },
     queue::QueueSong,
 };
+use crate::music_storage::{
+    gain_staging::{ActiveProfile, AppliedPlayback, ProfileSource, ResolvedProfile},
+    library::{AlbumKey, BannedType, Service, Song, Tag, URI},
+    library_guard::StatDelta,
+    search::{QueueMode, SearchMatch},
+    silence::{detect_silence, SilenceBounds},
+};
 
 use super::{
+    audio_device::{DeviceAction, InterruptionHandler},
     connections::ConnectionsNotification,
+    continue_listening::ListeningHints,
     controller::{
-        Controller, ControllerState, PlayerCommand, PlayerLocation, PlayerResponse, QueueCommand,
+        Controller, PlayerCommand, PlayerLocation, PlayerResponse, PlayerState, QueueCommand,
         QueueResponse,
     },
     controller_handle::{LibraryCommandInput, PlayerCommandInput, QueueCommandInput},
+    controller_state::{ControllerState, PlaybackModes, RepeatMode, ShuffleMode},
+    cue_playback::CueSession,
+    icy,
+    listen_time::{Listen, ListenTimer},
+    now_playing::NowPlayingTracker,
+    output_mode::{switch_output, NegotiatedOutput, OutputSink, OutputSwitch, SystemOutput},
+    player_monitor::{SeekPosition, TrackDuration},
+    remote_source::{Playable, RemoteSources},
 };
 
+/// What the player loop needs from the player, which is [`Prismriver`]
+/// outside of tests
+pub(super) trait PlayerBackend {
+    fn load_new(&mut self, uri: &Url) -> Result<(), PrismError>;
+    fn play(&mut self);
+    fn pause(&mut self);
+    fn stop(&mut self);
+    fn seek_to(&mut self, time: TimeDelta) -> Result<(), PrismError>;
+    fn position(&mut self) -> Option<TimeDelta>;
+    fn duration(&mut self) -> Option<TimeDelta>;
+    fn set_volume(&mut self, volume: Volume);
+    fn state(&mut self) -> PrismState;
+}
+
+impl PlayerBackend for Prismriver {
+    fn load_new(&mut self, uri: &Url) -> Result<(), PrismError> {
+        Prismriver::load_new(self, uri)
+    }
+
+    fn play(&mut self) {
+        Prismriver::play(self)
+    }
+
+    fn pause(&mut self) {
+        Prismriver::pause(self)
+    }
+
+    fn stop(&mut self) {
+        Prismriver::stop(self)
+    }
+
+    fn seek_to(&mut self, time: TimeDelta) -> Result<(), PrismError> {
+        Prismriver::seek_to(self, time)
+    }
+
+    fn position(&mut self) -> Option<TimeDelta> {
+        Prismriver::position(self)
+    }
+
+    fn duration(&mut self) -> Option<TimeDelta> {
+        Prismriver::duration(self)
+    }
+
+    fn set_volume(&mut self, volume: Volume) {
+        Prismriver::set_volume(self, volume)
+    }
+
+    fn state(&mut self) -> PrismState {
+        Prismriver::state(self)
+    }
+}
+
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn player_command_loop(
-        mut player: Prismriver,
+        mut player: impl PlayerBackend,
         player_mail: async_channel::Receiver<PlayerCommandInput>,
         queue_mail: async_channel::Sender<QueueCommandInput>,
         lib_mail: async_channel::Sender<LibraryCommandInput>,
-        notify_connections_: Sender<ConnectionsNotification>,
-        mut state: ControllerState,
+        song_changes: SongChangeNotifier,
+        state: Arc<Mutex<ControllerState>>,
+        config: Arc<RwLock<Config>>,
+        track_epoch: Arc<AtomicU64>,
+        track_duration: Arc<Mutex<TrackDuration>>,
+        notify_modes: Sender<PlaybackModes>,
+        notify_skipped: Sender<SkippedSong>,
+        applied: Arc<Mutex<AppliedPlayback>>,
+        cue_session: Arc<Mutex<Option<CueSession>>>,
+        remote_sources: Arc<RwLock<RemoteSources>>,
+        seek_position: Arc<Mutex<SeekPosition>>,
+        output: Arc<Mutex<NegotiatedOutput>>,
     ) -> Result<(), ()> {
-        player.set_volume(Volume::new(state.volume));
+        player.set_volume(Volume::new(state.lock().output_volume()));
+        let mut output_sink = SystemOutput;
+        let mode = config.read().playback.output_mode;
+        match switch_output(&mut output_sink, mode, None) {
+            Ok(switch) => {
+                if let Some(reason) = switch.fallback {
+                    println!("Using shared output: {reason}");
+                }
+                *output.lock() = switch.output;
+            }
+            Err(e) => println!("Could not set up the output: {e}"),
+        }
+        // The gain of the playing song, which volume changes are scaled by
+        let mut song_gain = 1.0;
+        let mut interruptions = InterruptionHandler::default();
+        let mut player_mail = PlayerMailbox::new(player_mail);
         'outer: while true {
             let _mail = player_mail.recv().await;
             if let Ok(PlayerCommandInput { res_rx, command }) = _mail {
+                let reason = change_reason(&command);
                 match command {
                     PlayerCommand::Play => {
                         player.play();
@@ -43,75 +156,244 @@ impl Controller {
 
                     PlayerCommand::Stop => {
                         player.stop();
+                        // Nothing is playing anymore, which also stops
+                        // following a station's titles
+                        track_epoch.fetch_add(1, Ordering::SeqCst);
+                        song_changes.stopped(&lib_mail).await;
+                        res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
+                    }
+
+                    PlayerCommand::Seek { time, epoch } => {
+                        let current = track_epoch.load(Ordering::SeqCst);
+                        let (target, duration) = seek_target(
+                            &mut player,
+                            &cue_session,
+                            &track_duration,
+                            current,
+                            TimeDelta::milliseconds(time),
+                        );
+                        let res = PlayerError::check_epoch(epoch, current)
+                            .and_then(|_| PlayerError::check_seek(time, duration))
+                            .and_then(|_| player.seek_to(target).map_err(|e| e.into()));
+                        match res {
+                            Ok(_) => seek_position.lock().seeked(
+                                epoch,
+                                TimeDelta::milliseconds(time),
+                                Instant::now(),
+                            ),
+                            Err(_) => seek_position.lock().clear(),
+                        }
+                        res_rx.send(PlayerResponse::Empty(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::SeekPreview { time, epoch } => {
+                        let res =
+                            PlayerError::check_epoch(epoch, track_epoch.load(Ordering::SeqCst));
+                        if res.is_ok() {
+                            seek_position
+                                .lock()
+                                .preview(epoch, TimeDelta::milliseconds(time.max(0)));
+                        }
+                        res_rx.send(PlayerResponse::Empty(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::SetVolume(volume) => {
+                        let output = {
+                            let mut state = state.lock();
+                            state.set_volume(volume);
+                            state.output_volume()
+                        };
+                        player.set_volume(Volume::new((output * song_gain).min(1.0)));
+                        res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
+                    }
+
+                    PlayerCommand::VolumeStep(steps) => {
+                        let (volume, output) = {
+                            let mut state = state.lock();
+                            (state.step_volume(steps), state.output_volume())
+                        };
+                        player.set_volume(Volume::new((output * song_gain).min(1.0)));
+                        res_rx.send(PlayerResponse::Volume(volume)).await.unwrap();
+                    }
+
+                    PlayerCommand::SetVolumeLimit(limit) => {
+                        let output = {
+                            let mut state = state.lock();
+                            state.set_volume_limit(limit);
+                            state.output_volume()
+                        };
+                        player.set_volume(Volume::new((output * song_gain).min(1.0)));
                         res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
                     }
 
-                    PlayerCommand::Seek(time) => {
-                        let res = player.seek_to(TimeDelta::milliseconds(time));
+                    PlayerCommand::GetState => {
+                        // Only the queue is asked, so this works before the
+                        // library has finished loading
+                        let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+                        queue_mail.send(command).await.unwrap();
+                        let now_playing = match tx.recv().await.unwrap() {
+                            QueueResponse::Item(Ok(QueueItem {
+                                item: QueueItemType::Single(song),
+                                ..
+                            })) => Some(song.song),
+                            _ => None,
+                        };
+                        let player_state = PlayerState::new(&state.lock(), now_playing);
                         res_rx
-                            .send(PlayerResponse::Empty(res.map_err(|e| e.into())))
+                            .send(PlayerResponse::State(player_state))
                             .await
                             .unwrap();
                     }
+                    PlayerCommand::ListeningHints => {
+                        let (now_playing, location, saved_positions) = {
+                            let state = state.lock();
+                            (
+                                state.now_playing(),
+                                state.location(),
+                                state.saved_positions(),
+                            )
+                        };
+                        let (command, tx) = QueueCommandInput::command(QueueCommand::GetPlayed(
+                            config.read().playback.played_history_limit,
+                        ));
+                        queue_mail.send(command).await.unwrap();
+                        let QueueResponse::GetAll(played) = tx.recv().await.unwrap() else {
+                            unreachable!()
+                        };
 
-                    PlayerCommand::SetVolume(volume) => {
-                        player.set_volume(Volume::new(volume));
+                        // The song the player stopped on comes first, as the
+                        // played songs are forgotten on restart
+                        let recent = location
+                            .map(|location| (now_playing, location))
+                            .into_iter()
+                            .chain(played.into_iter().rev().filter_map(|item| match item.item {
+                                QueueItemType::Single(song) => {
+                                    Some((song.song.uuid, song.location))
+                                }
+                                _ => None,
+                            }))
+                            .collect();
+                        let saved_positions = saved_positions
+                            .into_iter()
+                            .filter_map(|(uuid, position)| Some((uuid, position.to_std().ok()?)))
+                            .collect();
+                        res_rx
+                            .send(PlayerResponse::ListeningHints(ListeningHints {
+                                recent,
+                                saved_positions,
+                            }))
+                            .await
+                            .unwrap();
+                    }
+                    PlayerCommand::FlushState => {
+                        if let Err(e) = state.lock().flush() {
+                            println!("Couldn't save the player state: {e}");
+                        }
                         res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
-
-                        // make this async or something
-                        state.volume = volume;
-                        _ = state.write_file()
                     }
 
-                    PlayerCommand::NextSong => {
+                    PlayerCommand::NextSong | PlayerCommand::TrackFinished => {
+                        let by_user = command == PlayerCommand::NextSong;
+                        let epoch = track_epoch.load(Ordering::SeqCst);
+                        if by_user {
+                            if let Some(res) = cue_step(&mut player, &cue_session, epoch, 1) {
+                                res_rx.send(PlayerResponse::NowPlaying(res)).await.unwrap();
+                                continue;
+                            }
+                        } else {
+                            // The file ended before the last tracks were noticed
+                            let remaining = match &*cue_session.lock() {
+                                Some(session) if session.is_active(epoch) => session.remaining(),
+                                _ => 0,
+                            };
+                            for _ in 0..remaining {
+                                let (command, tx) = QueueCommandInput::command(QueueCommand::Next);
+                                queue_mail.send(command).await.unwrap();
+                                tx.recv().await.unwrap();
+                            }
+                        }
                         let (command, tx) = QueueCommandInput::command(QueueCommand::Next);
                         queue_mail.send(command).await.unwrap();
 
                         match tx.recv().await.unwrap() {
                             QueueResponse::Item(Ok(item)) => {
-                                let uri = match &item.item {
-                                    QueueItemType::Single(song) => {
-                                        song.song.primary_uri().unwrap().0
-                                    }
-                                    _ => unimplemented!(),
-                                };
-
-                                let prism_uri =
-                                    prismriver::utils::path_to_uri(&uri.as_path().unwrap())
-                                        .unwrap();
-                                println!("Playing song at path: {:?}", prism_uri);
-
-                                // handle error here for unknown formats
-                                player.load_new(&prism_uri).unwrap();
-                                player.play();
-
                                 let QueueItemType::Single(np_song) = item.item else {
                                     panic!("This is temporary, handle queueItemTypes at some point")
                                 };
+                                let requested = np_song.song.uuid;
 
-                                let (command, tx) =
-                                    LibraryCommandInput::command(LibraryCommand::AllSongs);
-                                // Append next song in library
-                                lib_mail.send(command).await.unwrap();
-                                let LibraryResponse::AllSongs(songs) = tx.recv().await.unwrap()
-                                else {
-                                    continue;
+                                let np_song = match play_skipping_missing(
+                                    |song| {
+                                        load_and_play(
+                                            &mut player,
+                                            &track_duration,
+                                            &remote_sources,
+                                            &config,
+                                            song,
+                                        )
+                                        // Missing files are skipped over,
+                                        // and reported as such
+                                        .inspect_err(|e| {
+                                            if !matches!(e, PlayerError::FileMissing { .. }) {
+                                                song_changes.load_failed(song, e)
+                                            }
+                                        })
+                                    },
+                                    &queue_mail,
+                                    &lib_mail,
+                                    &notify_skipped,
+                                    np_song,
+                                )
+                                .await
+                                {
+                                    Ok(np_song) => np_song,
+                                    Err(e) => {
+                                        res_rx
+                                            .send(PlayerResponse::NowPlaying(Err(e)))
+                                            .await
+                                            .unwrap();
+                                        continue;
+                                    }
                                 };
+                                track_epoch.fetch_add(1, Ordering::SeqCst);
 
-                                let (command, tx) = LibraryCommandInput::command(
-                                    LibraryCommand::Song(np_song.song.uuid),
-                                );
-                                lib_mail.send(command).await.unwrap();
-                                let LibraryResponse::Song(_, i) = tx.recv().await.unwrap() else {
-                                    unreachable!()
-                                };
-                                if let Some(song) = songs.get(i + 49) {
+                                // Append the next song waiting from a filter, or
+                                // the next song in the library
+                                let filtered = config.read().content_filter.enabled;
+                                let next =
+                                    match next_pending(&queue_mail, &lib_mail, filtered).await {
+                                        Some(song) => Some((song, PlayerLocation::Custom)),
+                                        None => {
+                                            let uuids =
+                                                location_uuids(&lib_mail, PlayerLocation::Library)
+                                                    .await
+                                                    .unwrap_or_default();
+                                            let candidates = match uuids
+                                                .iter()
+                                                .position(|uuid| *uuid == np_song.song.uuid)
+                                            {
+                                                Some(i) => next_up_next(
+                                                    &uuids,
+                                                    i,
+                                                    state.lock().playback_modes(),
+                                                ),
+                                                None => Vec::new(),
+                                            };
+                                            fetch_playable(&lib_mail, &candidates, 1, filtered)
+                                                .await
+                                                .into_iter()
+                                                .next()
+                                                .map(|song| (song, np_song.location))
+                                        }
+                                    };
+                                if let Some((song, location)) = next {
                                     let (command, tx) =
                                         QueueCommandInput::command(QueueCommand::Append(
                                             QueueItem::from_item_type(QueueItemType::Single(
                                                 QueueSong {
-                                                    song: song.clone(),
-                                                    location: np_song.location,
+                                                    song,
+                                                    location,
+                                                    transition: None,
                                                 },
                                             )),
                                             false,
@@ -130,13 +412,37 @@ impl Controller {
                                     .await
                                     .unwrap();
 
-                                state.now_playing = np_song.song.uuid;
-                                _ = state.write_file();
-                                notify_connections_
-                                    .send(ConnectionsNotification::SongChange(np_song.song))
-                                    .unwrap();
+                                state
+                                    .lock()
+                                    .set_now_playing(np_song.song.uuid, np_song.location);
+                                song_gain = apply_profile(
+                                    &mut player,
+                                    &state,
+                                    &config,
+                                    &lib_mail,
+                                    &applied,
+                                    &np_song.song,
+                                    np_song.location,
+                                )
+                                .await;
+                                let reason = after_skipping(reason, requested, np_song.song.uuid);
+                                song_changes
+                                    .announce(
+                                        &queue_mail,
+                                        &lib_mail,
+                                        np_song.song,
+                                        np_song.location,
+                                        reason,
+                                    )
+                                    .await;
                             }
                             QueueResponse::Item(Err(e)) => {
+                                // A trimmed song's file would otherwise keep
+                                // playing past the end of the trim
+                                if !by_user {
+                                    player.stop();
+                                    song_changes.stopped(&lib_mail).await;
+                                }
                                 res_rx
                                     .send(PlayerResponse::NowPlaying(Err(e.into())))
                                     .await
@@ -146,37 +452,93 @@ impl Controller {
                         }
                     }
 
-                    PlayerCommand::PrevSong => {
-                        let (command, tx) = QueueCommandInput::command(QueueCommand::Prev);
+                    PlayerCommand::PrevSong | PlayerCommand::PlayPlayed(_) => {
+                        if command == PlayerCommand::PrevSong {
+                            let epoch = track_epoch.load(Ordering::SeqCst);
+                            let threshold = TimeDelta::milliseconds(
+                                config.read().playback.prev_restart_threshold_ms as i64,
+                            );
+                            let position = seek_position.lock().last_reported(epoch);
+                            if prev_restarts(position, threshold) {
+                                let res = restart(
+                                    &mut player,
+                                    &queue_mail,
+                                    &cue_session,
+                                    &track_duration,
+                                    epoch,
+                                )
+                                .await;
+                                if let Ok(song) = &res {
+                                    seek_position.lock().seeked(
+                                        epoch,
+                                        TimeDelta::zero(),
+                                        Instant::now(),
+                                    );
+                                    song_changes.restarted(&lib_mail, song).await;
+                                }
+                                res_rx.send(PlayerResponse::NowPlaying(res)).await.unwrap();
+                                continue;
+                            }
+                            if let Some(res) = cue_step(&mut player, &cue_session, epoch, -1) {
+                                res_rx.send(PlayerResponse::NowPlaying(res)).await.unwrap();
+                                continue;
+                            }
+                        }
+                        let command = match command {
+                            PlayerCommand::PlayPlayed(steps) => QueueCommand::Back(steps),
+                            _ => QueueCommand::Prev,
+                        };
+                        let (command, tx) = QueueCommandInput::command(command);
                         queue_mail.send(command).await.unwrap();
                         match tx.recv().await.unwrap() {
                             QueueResponse::Item(Ok(item)) => {
-                                let uri = match &item.item {
-                                    QueueItemType::Single(song) => {
-                                        song.song.primary_uri().unwrap().0
-                                    }
-                                    _ => unimplemented!(),
+                                let QueueItemType::Single(np_song) = item.item else {
+                                    panic!("This is temporary, handle queueItemTypes at some point")
                                 };
 
-                                let prism_uri =
-                                    prismriver::utils::path_to_uri(&uri.as_path().unwrap())
+                                if let Err(e) = load_and_play(
+                                    &mut player,
+                                    &track_duration,
+                                    &remote_sources,
+                                    &config,
+                                    &np_song.song,
+                                ) {
+                                    song_changes.load_failed(&np_song.song, &e);
+                                    res_rx
+                                        .send(PlayerResponse::NowPlaying(Err(e)))
+                                        .await
                                         .unwrap();
-                                player.load_new(&prism_uri).unwrap();
-                                player.play();
+                                    continue;
+                                }
+                                track_epoch.fetch_add(1, Ordering::SeqCst);
 
-                                let QueueItemType::Single(np_song) = item.item else {
-                                    panic!("This is temporary, handle queueItemTypes at some point")
-                                };
                                 res_rx
                                     .send(PlayerResponse::NowPlaying(Ok(np_song.song.clone())))
                                     .await
                                     .unwrap();
 
-                                state.now_playing = np_song.song.uuid;
-                                _ = state.write_file();
-                                notify_connections_
-                                    .send(ConnectionsNotification::SongChange(np_song.song))
-                                    .unwrap();
+                                state
+                                    .lock()
+                                    .set_now_playing(np_song.song.uuid, np_song.location);
+                                song_gain = apply_profile(
+                                    &mut player,
+                                    &state,
+                                    &config,
+                                    &lib_mail,
+                                    &applied,
+                                    &np_song.song,
+                                    np_song.location,
+                                )
+                                .await;
+                                song_changes
+                                    .announce(
+                                        &queue_mail,
+                                        &lib_mail,
+                                        np_song.song,
+                                        np_song.location,
+                                        reason,
+                                    )
+                                    .await;
                             }
                             QueueResponse::Item(Err(e)) => {
                                 res_rx
@@ -188,32 +550,52 @@ impl Controller {
                         }
                     }
 
-                    PlayerCommand::Enqueue(index) => {
-                        let (command, tx) =
-                            QueueCommandInput::command(QueueCommand::GetIndex(index));
+                    PlayerCommand::Enqueue { index, expected } => {
+                        let command = QueueCommand::GetIndex(index).expecting(expected);
+                        let (command, tx) = QueueCommandInput::command(command);
                         queue_mail.send(command).await.unwrap();
                         match tx.recv().await.unwrap() {
                             QueueResponse::Item(Ok(item)) => {
                                 match item.item {
                                     QueueItemType::Single(np_song) => {
-                                        let prism_uri = prismriver::utils::path_to_uri(
-                                            &np_song
-                                                .song
-                                                .primary_uri()
-                                                .unwrap()
-                                                .0
-                                                .as_path()
-                                                .unwrap(),
-                                        )
-                                        .unwrap();
-                                        player.load_new(&prism_uri).unwrap();
-                                        player.play();
+                                        if let Err(e) = load_and_play(
+                                            &mut player,
+                                            &track_duration,
+                                            &remote_sources,
+                                            &config,
+                                            &np_song.song,
+                                        ) {
+                                            song_changes.load_failed(&np_song.song, &e);
+                                            res_rx
+                                                .send(PlayerResponse::Empty(Err(e)))
+                                                .await
+                                                .unwrap();
+                                            continue;
+                                        }
+                                        track_epoch.fetch_add(1, Ordering::SeqCst);
 
-                                        state.now_playing = np_song.song.uuid;
-                                        _ = state.write_file();
-                                        notify_connections_
-                                            .send(ConnectionsNotification::SongChange(np_song.song))
-                                            .unwrap();
+                                        state
+                                            .lock()
+                                            .set_now_playing(np_song.song.uuid, np_song.location);
+                                        song_gain = apply_profile(
+                                            &mut player,
+                                            &state,
+                                            &config,
+                                            &lib_mail,
+                                            &applied,
+                                            &np_song.song,
+                                            np_song.location,
+                                        )
+                                        .await;
+                                        song_changes
+                                            .announce(
+                                                &queue_mail,
+                                                &lib_mail,
+                                                np_song.song,
+                                                np_song.location,
+                                                reason,
+                                            )
+                                            .await;
                                     }
                                     _ => unimplemented!(),
                                 }
@@ -225,19 +607,58 @@ impl Controller {
                                     .await
                                     .unwrap();
                             }
+                            QueueResponse::Conflict(conflict) => {
+                                res_rx
+                                    .send(PlayerResponse::Empty(Err(conflict.into())))
+                                    .await
+                                    .unwrap();
+                            }
                             _ => continue,
                         }
                     }
 
-                    PlayerCommand::PlayNow(uuid, location) => {
+                    PlayerCommand::PlayNow {
+                        uuid,
+                        location,
+                        shuffle_remaining,
+                    }
+                    | PlayerCommand::PlayNowConfirmed {
+                        uuid,
+                        location,
+                        shuffle_remaining,
+                    } => {
+                        let confirmed = matches!(command, PlayerCommand::PlayNowConfirmed { .. });
                         // TODO: This assumes the uuid doesn't point to an album. we've been over this.
                         let (command, tx) =
                             LibraryCommandInput::command(LibraryCommand::Song(uuid));
                         lib_mail.send(command).await.unwrap();
-                        let LibraryResponse::Song(np_song, index) = tx.recv().await.unwrap() else {
-                            unreachable!()
+                        let np_song = match tx.recv().await.unwrap() {
+                            LibraryResponse::Song(Some((song, _))) => song,
+                            LibraryResponse::Song(None) => {
+                                res_rx
+                                    .send(PlayerResponse::NowPlaying(Err(
+                                        PlayerError::SongNotFound(uuid),
+                                    )))
+                                    .await
+                                    .unwrap();
+                                continue;
+                            }
+                            _ => unreachable!(),
                         };
 
+                        // Explicit songs can still be picked out by hand in
+                        // filtered mode, once the user confirms
+                        let filtered = config.read().content_filter.enabled;
+                        if filtered && !confirmed && np_song.is_explicit() {
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(
+                                    PlayerError::NeedsConfirmation(np_song.uuid),
+                                )))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+
                         let (command, tx) = QueueCommandInput::command(QueueCommand::Clear);
                         queue_mail.send(command).await.unwrap();
                         match tx.recv().await.unwrap() {
@@ -256,6 +677,7 @@ impl Controller {
                             QueueItem::from_item_type(QueueItemType::Single(QueueSong {
                                 song: np_song.clone(),
                                 location,
+                                transition: None,
                             })),
                             true,
                         ));
@@ -272,91 +694,3300 @@ impl Controller {
                             _ => unreachable!(),
                         }
 
-                        // TODO: Handle non Local URIs here, and whenever `load_new()` or `load_gapless()` is called
-                        let prism_uri = prismriver::utils::path_to_uri(
-                            &np_song.primary_uri().unwrap().0.as_path().unwrap(),
-                        )
-                        .unwrap();
-                        player.load_new(&prism_uri).unwrap();
-                        player.play();
+                        if let Err(e) = load_and_play(
+                            &mut player,
+                            &track_duration,
+                            &remote_sources,
+                            &config,
+                            &np_song,
+                        ) {
+                            song_changes.load_failed(&np_song, &e);
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e)))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                        track_epoch.fetch_add(1, Ordering::SeqCst);
 
-                        // how grab all the songs in a certain subset of the library, I reckon?
-                        // ...
-                        // let's just pretend I figured that out already
-
-                        let (songs, index) = match location {
-                            PlayerLocation::Library => {
-                                let (command, tx) =
-                                    LibraryCommandInput::command(LibraryCommand::AllSongs);
-                                lib_mail.send(command).await.unwrap();
-                                let LibraryResponse::AllSongs(songs) = tx.recv().await.unwrap()
-                                else {
-                                    unreachable!()
-                                };
-                                (songs, index)
+                        // The songs after it are looked up together, rather
+                        // than cloning the whole library or one at a time
+                        let (up_next, rest) = location_up_next(
+                            &lib_mail,
+                            location,
+                            np_song.uuid,
+                            shuffle_remaining,
+                            filtered,
+                        )
+                        .await;
+                        for song in up_next.iter().cloned() {
+                            let (command, tx) = QueueCommandInput::command(QueueCommand::Append(
+                                QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                                    song,
+                                    location,
+                                    transition: None,
+                                })),
+                                false,
+                            ));
+                            queue_mail.send(command).await.unwrap();
+                            match tx.recv().await.unwrap() {
+                                QueueResponse::Empty(Ok(())) => (),
+                                QueueResponse::Empty(Err(e)) => {
+                                    res_rx
+                                        .send(PlayerResponse::NowPlaying(Err(e.into())))
+                                        .await
+                                        .unwrap();
+                                    continue 'outer;
+                                }
+                                _ => unreachable!(),
                             }
-                            PlayerLocation::Playlist(uuid) => {
-                                let (command, tx) = LibraryCommandInput::command(
-                                    LibraryCommand::ExternalPlaylist(uuid),
-                                );
-                                lib_mail.send(command).await.unwrap();
-                                let LibraryResponse::ExternalPlaylist(list) =
-                                    tx.recv().await.unwrap()
-                                else {
-                                    unreachable!()
-                                };
-                                let index = list.get_index(np_song.uuid).unwrap();
-                                (list.tracks, index)
+                        }
+                        let res = match shuffle_remaining {
+                            // The shuffled songs are queued up in the same
+                            // order later, rather than by where they are
+                            true => {
+                                set_pending(&queue_mail, &lib_mail, &rest, &up_next, filtered).await
+                            }
+                            false => {
+                                set_pool(&queue_mail, &lib_mail, &rest, &up_next, filtered).await
                             }
-                            _ => todo!("Got Location other than Library or Playlist"),
                         };
+                        if let Err(e) = res {
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e.into())))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                        res_rx
+                            .send(PlayerResponse::NowPlaying(Ok(np_song.clone())))
+                            .await
+                            .unwrap();
 
-                        for i in index + 1..(index + 50) {
-                            if let Some(song) = songs.get(i) {
-                                let (command, tx) =
-                                    QueueCommandInput::command(QueueCommand::Append(
-                                        QueueItem::from_item_type(QueueItemType::Single(
-                                            QueueSong {
-                                                song: song.clone(),
-                                                location,
-                                            },
-                                        )),
-                                        false,
-                                    ));
-                                queue_mail.send(command).await.unwrap();
-                                match tx.recv().await.unwrap() {
-                                    QueueResponse::Empty(Ok(())) => (),
-                                    QueueResponse::Empty(Err(e)) => {
-                                        res_rx
-                                            .send(PlayerResponse::NowPlaying(Err(e.into())))
-                                            .await
-                                            .unwrap();
-                                        continue 'outer;
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            } else {
-                                println!("End of Library / Playlist");
-                                break;
+                        state.lock().set_now_playing(np_song.uuid, location);
+                        song_gain = apply_profile(
+                            &mut player,
+                            &state,
+                            &config,
+                            &lib_mail,
+                            &applied,
+                            &np_song,
+                            location,
+                        )
+                        .await;
+                        song_changes
+                            .announce(&queue_mail, &lib_mail, np_song, location, reason)
+                            .await;
+                    }
+
+                    PlayerCommand::PlayAlbum {
+                        key,
+                        starting_track,
+                    } => {
+                        let title = key.title.clone();
+                        let (command, tx) = LibraryCommandInput::command(
+                            LibraryCommand::AlbumTracks(key, starting_track),
+                        );
+                        lib_mail.send(command).await.unwrap();
+                        let LibraryResponse::AlbumTracks(songs) = tx.recv().await.unwrap() else {
+                            unreachable!()
+                        };
+
+                        let Some(np_song) = songs.first().cloned() else {
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(PlayerError::NoSongsFound(
+                                    title,
+                                ))))
+                                .await
+                                .unwrap();
+                            continue;
+                        };
+
+                        if let Err(e) =
+                            replace_queue(&queue_mail, songs, PlayerLocation::Album).await
+                        {
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e.into())))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+
+                        if let Err(e) = load_and_play(
+                            &mut player,
+                            &track_duration,
+                            &remote_sources,
+                            &config,
+                            &np_song,
+                        ) {
+                            song_changes.load_failed(&np_song, &e);
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e)))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                        track_epoch.fetch_add(1, Ordering::SeqCst);
+
+                        res_rx
+                            .send(PlayerResponse::NowPlaying(Ok(np_song.clone())))
+                            .await
+                            .unwrap();
+
+                        state
+                            .lock()
+                            .set_now_playing(np_song.uuid, PlayerLocation::Album);
+                        song_gain = apply_profile(
+                            &mut player,
+                            &state,
+                            &config,
+                            &lib_mail,
+                            &applied,
+                            &np_song,
+                            PlayerLocation::Album,
+                        )
+                        .await;
+                        song_changes
+                            .announce(
+                                &queue_mail,
+                                &lib_mail,
+                                np_song,
+                                PlayerLocation::Album,
+                                reason,
+                            )
+                            .await;
+                    }
+
+                    PlayerCommand::PlayCueAlbum(key) => {
+                        let title = key.title.clone();
+                        let (command, tx) =
+                            LibraryCommandInput::command(LibraryCommand::AlbumTracks(key, None));
+                        lib_mail.send(command).await.unwrap();
+                        let LibraryResponse::AlbumTracks(songs) = tx.recv().await.unwrap() else {
+                            unreachable!()
+                        };
+
+                        let mut session = match CueSession::new(&title, songs) {
+                            Ok(session) => session,
+                            Err(e) => {
+                                res_rx
+                                    .send(PlayerResponse::NowPlaying(Err(e)))
+                                    .await
+                                    .unwrap();
+                                continue;
+                            }
+                        };
+                        let np_song = session.song(0).unwrap().clone();
+
+                        if let Err(e) =
+                            replace_queue(&queue_mail, session.songs(), PlayerLocation::Album).await
+                        {
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e.into())))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+
+                        // The whole file is loaded once, from the start,
+                        // so a trim of the first track doesn't apply to it
+                        let file = Song {
+                            trim: None,
+                            ..np_song.clone()
+                        };
+                        if let Err(e) = load_and_play(
+                            &mut player,
+                            &track_duration,
+                            &remote_sources,
+                            &config,
+                            &file,
+                        ) {
+                            song_changes.load_failed(&file, &e);
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e)))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                        session.set_epoch(track_epoch.fetch_add(1, Ordering::SeqCst) + 1);
+                        *cue_session.lock() = Some(session);
+
+                        res_rx
+                            .send(PlayerResponse::NowPlaying(Ok(np_song.clone())))
+                            .await
+                            .unwrap();
+
+                        state
+                            .lock()
+                            .set_now_playing(np_song.uuid, PlayerLocation::Album);
+                        song_gain = apply_profile(
+                            &mut player,
+                            &state,
+                            &config,
+                            &lib_mail,
+                            &applied,
+                            &np_song,
+                            PlayerLocation::Album,
+                        )
+                        .await;
+                        song_changes
+                            .announce(
+                                &queue_mail,
+                                &lib_mail,
+                                np_song,
+                                PlayerLocation::Album,
+                                reason,
+                            )
+                            .await;
+                    }
+
+                    PlayerCommand::CueTrackChange { from, to } => {
+                        // Something else may have been loaded since
+                        let epoch = track_epoch.load(Ordering::SeqCst);
+                        if !cue_session
+                            .lock()
+                            .as_ref()
+                            .is_some_and(|session| session.is_active(epoch))
+                        {
+                            res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
+                            continue;
+                        }
+
+                        let (command, times) = match to > from {
+                            true => (QueueCommand::Next, to - from),
+                            false => (QueueCommand::Back(from - to), 1),
+                        };
+                        let mut res = Ok(());
+                        for _ in 0..times {
+                            let (command, tx) = QueueCommandInput::command(command.clone());
+                            queue_mail.send(command).await.unwrap();
+                            let QueueResponse::Item(item) = tx.recv().await.unwrap() else {
+                                unreachable!()
+                            };
+                            res = item.map(|_| ());
+                        }
+                        if let Err(e) = res {
+                            res_rx
+                                .send(PlayerResponse::Empty(Err(e.into())))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+
+                        // A new epoch for the new track, which the session
+                        // carries on into
+                        let np_song = {
+                            let mut session = cue_session.lock();
+                            let session = session.as_mut().unwrap();
+                            session.set_epoch(track_epoch.fetch_add(1, Ordering::SeqCst) + 1);
+                            session.song(to).unwrap().clone()
+                        };
+                        res_rx.send(PlayerResponse::Empty(Ok(()))).await.unwrap();
+
+                        state
+                            .lock()
+                            .set_now_playing(np_song.uuid, PlayerLocation::Album);
+                        song_gain = apply_profile(
+                            &mut player,
+                            &state,
+                            &config,
+                            &lib_mail,
+                            &applied,
+                            &np_song,
+                            PlayerLocation::Album,
+                        )
+                        .await;
+                        song_changes
+                            .announce(
+                                &queue_mail,
+                                &lib_mail,
+                                np_song,
+                                PlayerLocation::Album,
+                                reason,
+                            )
+                            .await;
+                    }
+
+                    PlayerCommand::PlayArtist(ref name) | PlayerCommand::PlayGenre(ref name) => {
+                        let (lib_command, shuffle) = match &command {
+                            PlayerCommand::PlayArtist(_) => (
+                                LibraryCommand::ArtistSongs(name.clone()),
+                                state.lock().playback_modes().shuffle == ShuffleMode::On,
+                            ),
+                            _ => (LibraryCommand::GenreSongs(name.clone()), true),
+                        };
+                        let np_song = match queue_songs_of(
+                            &queue_mail,
+                            &lib_mail,
+                            &config,
+                            lib_command,
+                            name,
+                            shuffle,
+                        )
+                        .await
+                        {
+                            Ok(song) => song,
+                            Err(e) => {
+                                res_rx
+                                    .send(PlayerResponse::NowPlaying(Err(e)))
+                                    .await
+                                    .unwrap();
+                                continue;
                             }
+                        };
+
+                        if let Err(e) = load_and_play(
+                            &mut player,
+                            &track_duration,
+                            &remote_sources,
+                            &config,
+                            &np_song,
+                        ) {
+                            song_changes.load_failed(&np_song, &e);
+                            res_rx
+                                .send(PlayerResponse::NowPlaying(Err(e)))
+                                .await
+                                .unwrap();
+                            continue;
                         }
-                        // ^ This be my solution for now ^
+                        track_epoch.fetch_add(1, Ordering::SeqCst);
+
                         res_rx
                             .send(PlayerResponse::NowPlaying(Ok(np_song.clone())))
                             .await
                             .unwrap();
 
-                        state.now_playing = np_song.uuid;
-                        _ = state.write_file();
-                        notify_connections_
-                            .send(ConnectionsNotification::SongChange(np_song))
+                        state
+                            .lock()
+                            .set_now_playing(np_song.uuid, PlayerLocation::Custom);
+                        song_gain = apply_profile(
+                            &mut player,
+                            &state,
+                            &config,
+                            &lib_mail,
+                            &applied,
+                            &np_song,
+                            PlayerLocation::Custom,
+                        )
+                        .await;
+                        song_changes
+                            .announce(
+                                &queue_mail,
+                                &lib_mail,
+                                np_song,
+                                PlayerLocation::Custom,
+                                reason,
+                            )
+                            .await;
+                    }
+
+                    PlayerCommand::GetPlaybackModes => {
+                        let modes = state.lock().playback_modes();
+                        res_rx
+                            .send(PlayerResponse::PlaybackModes(modes))
+                            .await
                             .unwrap();
                     }
-                }
-            } else {
-                return Err(());
-            }
-        }
-        Ok(())
+
+                    PlayerCommand::SetPlaybackModes(modes) => {
+                        let old = {
+                            let mut state = state.lock();
+                            let old = state.playback_modes();
+                            state.set_playback_modes(modes);
+                            old
+                        };
+                        if modes != old {
+                            song_changes.now_playing.modes_changed(modes);
+                            _ = notify_modes.send(modes);
+                        }
+
+                        // Turning on repeat at the end of the queue wraps
+                        // around, so the next button works again
+                        let res = if modes.shuffle != old.shuffle
+                            || modes.repeat == RepeatMode::All && old.repeat != RepeatMode::All
+                        {
+                            let filtered = config.read().content_filter.enabled;
+                            plan_up_next(&queue_mail, &lib_mail, modes, filtered).await
+                        } else {
+                            Ok(())
+                        };
+                        res_rx.send(PlayerResponse::Empty(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::SearchAndQueue { query, mode } => {
+                        let (command, tx) =
+                            LibraryCommandInput::command(LibraryCommand::Search(query));
+                        lib_mail.send(command).await.unwrap();
+                        let LibraryResponse::Search(found) = tx.recv().await.unwrap() else {
+                            unreachable!()
+                        };
+
+                        // Ambiguous results are left for the user to pick from
+                        let SearchMatch::Found(song) = &found else {
+                            res_rx
+                                .send(PlayerResponse::SearchAndQueue(Ok(found)))
+                                .await
+                                .unwrap();
+                            continue;
+                        };
+
+                        let res = match mode {
+                            QueueMode::PlayNow => replace_queue(
+                                &queue_mail,
+                                vec![*song.clone()],
+                                PlayerLocation::Custom,
+                            )
+                            .await
+                            .map_err(PlayerError::from)
+                            .and_then(|_| {
+                                load_and_play(
+                                    &mut player,
+                                    &track_duration,
+                                    &remote_sources,
+                                    &config,
+                                    song,
+                                )
+                                .inspect_err(|e| song_changes.load_failed(song, e))
+                            }),
+                            QueueMode::PlayNext | QueueMode::Append => {
+                                let item =
+                                    QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                                        song: *song.clone(),
+                                        location: PlayerLocation::Custom,
+                                        transition: None,
+                                    }));
+                                let (command, tx) = QueueCommandInput::command(match mode {
+                                    QueueMode::PlayNext => QueueCommand::AppendNext(item),
+                                    _ => QueueCommand::Append(item, true),
+                                });
+                                queue_mail.send(command).await.unwrap();
+                                let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+                                    unreachable!()
+                                };
+                                res.map_err(PlayerError::from)
+                            }
+                        };
+
+                        if res.is_ok() && mode == QueueMode::PlayNow {
+                            track_epoch.fetch_add(1, Ordering::SeqCst);
+                            state
+                                .lock()
+                                .set_now_playing(song.uuid, PlayerLocation::Custom);
+                            song_gain = apply_profile(
+                                &mut player,
+                                &state,
+                                &config,
+                                &lib_mail,
+                                &applied,
+                                song,
+                                PlayerLocation::Custom,
+                            )
+                            .await;
+                            song_changes
+                                .announce(
+                                    &queue_mail,
+                                    &lib_mail,
+                                    *song.clone(),
+                                    PlayerLocation::Custom,
+                                    reason,
+                                )
+                                .await;
+                        }
+                        res_rx
+                            .send(PlayerResponse::SearchAndQueue(res.map(|_| found)))
+                            .await
+                            .unwrap();
+                    }
+
+                    PlayerCommand::QueueFromFilter {
+                        query,
+                        target_tags,
+                        sort,
+                        mode,
+                    } => {
+                        let filtered = config.read().content_filter.enabled;
+                        let res = queue_from_filter(
+                            &queue_mail,
+                            &lib_mail,
+                            &query,
+                            target_tags,
+                            sort,
+                            mode,
+                            filtered,
+                        )
+                        .await;
+                        let res = match res {
+                            Ok((count, Some(song))) => {
+                                match load_and_play(
+                                    &mut player,
+                                    &track_duration,
+                                    &remote_sources,
+                                    &config,
+                                    &song,
+                                ) {
+                                    Ok(()) => {
+                                        track_epoch.fetch_add(1, Ordering::SeqCst);
+                                        state
+                                            .lock()
+                                            .set_now_playing(song.uuid, PlayerLocation::Custom);
+                                        song_gain = apply_profile(
+                                            &mut player,
+                                            &state,
+                                            &config,
+                                            &lib_mail,
+                                            &applied,
+                                            &song,
+                                            PlayerLocation::Custom,
+                                        )
+                                        .await;
+                                        song_changes
+                                            .announce(
+                                                &queue_mail,
+                                                &lib_mail,
+                                                song,
+                                                PlayerLocation::Custom,
+                                                reason,
+                                            )
+                                            .await;
+                                        Ok(count)
+                                    }
+                                    Err(e) => {
+                                        song_changes.load_failed(&song, &e);
+                                        Err(e)
+                                    }
+                                }
+                            }
+                            res => res.map(|(count, _)| count),
+                        };
+                        res_rx.send(PlayerResponse::Enqueued(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::EnqueueRestOfAlbum | PlayerCommand::EnqueueMoreByArtist(_) => {
+                        let filtered = config.read().content_filter.enabled;
+                        let res = enqueue_related(&queue_mail, &lib_mail, &command, filtered).await;
+                        res_rx.send(PlayerResponse::Enqueued(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::EnqueueAlbumNext(key) => {
+                        let filtered = config.read().content_filter.enabled;
+                        let res = enqueue_album_next(&queue_mail, &lib_mail, key, filtered).await;
+                        res_rx.send(PlayerResponse::Enqueued(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::DeviceEvent(ref event) => {
+                        let playing = player.state() == PrismState::Playing;
+                        let auto_resume = config.read().playback.resume_on_device_return;
+
+                        let res = match interruptions.handle(event, playing, auto_resume) {
+                            DeviceAction::Paused => {
+                                player.pause();
+                                Ok(DeviceAction::Paused)
+                            }
+                            action @ DeviceAction::Reopened { resumed } => reopen_output(
+                                &mut player,
+                                &track_duration,
+                                &remote_sources,
+                                &config,
+                                &queue_mail,
+                                resumed,
+                            )
+                            .await
+                            .map(|_| action),
+                        };
+                        res_rx.send(PlayerResponse::Device(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::SilenceFound {
+                        epoch,
+                        uuid,
+                        silence,
+                    } => {
+                        let (command, tx) =
+                            LibraryCommandInput::command(LibraryCommand::SetSilence(uuid, silence));
+                        lib_mail.send(command).await.unwrap();
+                        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+                            unreachable!()
+                        };
+
+                        // Another song may have started while it was found
+                        let res = match epoch == track_epoch.load(Ordering::SeqCst) {
+                            true => {
+                                skip_found_silence(
+                                    &mut player,
+                                    &track_duration,
+                                    &config,
+                                    &queue_mail,
+                                    uuid,
+                                    silence,
+                                )
+                                .await
+                            }
+                            false => Ok(()),
+                        };
+                        res_rx.send(PlayerResponse::Empty(res)).await.unwrap();
+                    }
+
+                    PlayerCommand::SetOutputMode(mode) => {
+                        let res = set_output_mode(
+                            &mut player,
+                            &mut output_sink,
+                            &output,
+                            mode,
+                            &track_duration,
+                            &remote_sources,
+                            &config,
+                            &queue_mail,
+                        )
+                        .await;
+                        res_rx.send(PlayerResponse::OutputMode(res)).await.unwrap();
+                    }
+                }
+            } else {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Clears the queue and fills it with `songs` in order, with only the
+/// first song counting as being added by a human
+async fn replace_queue(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    songs: Vec<Song>,
+    location: PlayerLocation,
+) -> Result<(), QueueError> {
+    let (command, tx) = QueueCommandInput::command(QueueCommand::Clear);
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res?;
+
+    for (i, song) in songs.into_iter().enumerate() {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Append(
+            QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                song,
+                location,
+                transition: None,
+            })),
+            i == 0,
+        ));
+        queue_mail.send(command).await.unwrap();
+        let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res?;
+    }
+    Ok(())
+}
+
+/// Replaces the queue with the first of `songs` like [`replace_queue`], and
+/// keeps the rest waiting to be queued up as it plays
+async fn replace_queue_pending(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    mut songs: Vec<Song>,
+    location: PlayerLocation,
+) -> Result<(), QueueError> {
+    let rest = songs.split_off(UP_NEXT_LEN.min(songs.len()));
+    replace_queue(queue_mail, songs, location).await?;
+
+    let (command, tx) = QueueCommandInput::command(QueueCommand::SetPending {
+        uuids: rest.iter().map(|song| song.uuid).collect(),
+        pool: rest.iter().map(|song| song.duration).sum(),
+        mode: QueueMode::PlayNow,
+    });
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res
+}
+
+/// Replaces the queue with the songs `lib_command` finds for an artist or
+/// genre called `name`, returning the one to play first. The queue is left
+/// alone if there is nothing to play.
+async fn queue_songs_of(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    config: &Arc<RwLock<Config>>,
+    lib_command: LibraryCommand,
+    name: &str,
+    shuffle: bool,
+) -> Result<Song, PlayerError> {
+    let (command, tx) = LibraryCommandInput::command(lib_command);
+    lib_mail.send(command).await.unwrap();
+    let LibraryResponse::Songs(mut songs) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    if config.read().content_filter.enabled {
+        songs.retain(|song| !song.is_explicit());
+    }
+    if songs.is_empty() {
+        return Err(PlayerError::NoSongsFound(name.to_string()));
+    }
+    if shuffle {
+        songs.shuffle(&mut rand::thread_rng());
+    }
+    let first = songs[0].clone();
+    replace_queue_pending(queue_mail, songs, PlayerLocation::Custom).await?;
+    Ok(first)
+}
+
+/// Adds the time a song was listened to to its play time, and to its
+/// playlist's if it was played from one
+async fn record_listen(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    listen: Option<Listen>,
+) {
+    let Some(Listen {
+        song,
+        location,
+        listened,
+    }) = listen
+    else {
+        return;
+    };
+    let Ok(play_time) = listened.to_std() else {
+        return;
+    };
+
+    let delta = StatDelta {
+        play_time,
+        ..Default::default()
+    };
+    let mut commands = vec![LibraryCommand::UpdateStats(song, delta)];
+    if let PlayerLocation::Playlist(playlist) = location {
+        commands.push(LibraryCommand::AddPlaylistTime(playlist, play_time));
+    }
+    for command in commands {
+        let (command, tx) = LibraryCommandInput::command(command);
+        lib_mail.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+    }
+}
+
+/// Queues the songs matching a library filter for `mode`. Only the songs
+/// queued straight away are looked up, the rest wait in the queue as uuids
+/// and are queued up one at a time as songs play, before anything from the
+/// library. Returns how many songs matched, and the song to play for
+/// [`QueueMode::PlayNow`].
+async fn queue_from_filter(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    query: &str,
+    target_tags: Vec<Tag>,
+    sort: Vec<Tag>,
+    mode: QueueMode,
+    filtered: bool,
+) -> Result<(usize, Option<Song>), PlayerError> {
+    let (command, tx) = LibraryCommandInput::command(LibraryCommand::QueryUuids {
+        query: query.to_string(),
+        target_tags,
+        sort,
+        filtered,
+    });
+    lib_mail.send(command).await.unwrap();
+    let LibraryResponse::QueryUuids(found) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+
+    let uuids: Vec<Uuid> = found.iter().map(|(uuid, _)| *uuid).collect();
+    let songs = fetch_playable(lib_mail, &uuids, UP_NEXT_LEN, filtered).await;
+    let Some(last) = songs.last() else {
+        return Err(PlayerError::NoSongsFound(query.to_string()));
+    };
+    let taken = uuids.iter().position(|uuid| *uuid == last.uuid).unwrap() + 1;
+    let first = songs[0].clone();
+
+    let item = |song| {
+        QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+            song,
+            location: PlayerLocation::Custom,
+            transition: None,
+        }))
+    };
+    let commands: Vec<QueueCommand> = match mode {
+        QueueMode::PlayNow => {
+            replace_queue(queue_mail, songs, PlayerLocation::Custom).await?;
+            Vec::new()
+        }
+        // Inserted backwards so they end up in order
+        QueueMode::PlayNext => songs
+            .into_iter()
+            .rev()
+            .map(|song| QueueCommand::AppendNext(item(song)))
+            .collect(),
+        QueueMode::Append => songs
+            .into_iter()
+            .map(|song| QueueCommand::Append(item(song), true))
+            .collect(),
+    };
+    for command in commands
+        .into_iter()
+        // The rest of the songs take over from the ones which were coming up
+        .chain((mode != QueueMode::PlayNow).then_some(QueueCommand::ReplaceUpNext(Vec::new())))
+        .chain([QueueCommand::SetPending {
+            uuids: uuids[taken..].to_vec(),
+            pool: found[taken..].iter().map(|(_, duration)| *duration).sum(),
+            mode,
+        }])
+    {
+        let (command, tx) = QueueCommandInput::command(command);
+        queue_mail.send(command).await.unwrap();
+        let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res?;
+    }
+
+    let play = (mode == QueueMode::PlayNow).then_some(first);
+    Ok((uuids.len(), play))
+}
+
+/// Takes songs waiting in the queue until one can be played
+async fn next_pending(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    filtered: bool,
+) -> Option<Song> {
+    loop {
+        let (command, tx) = QueueCommandInput::command(QueueCommand::TakePending);
+        queue_mail.send(command).await.unwrap();
+        let QueueResponse::Pending(uuid) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        if let Some(song) = fetch_playable(lib_mail, &[uuid?], 1, filtered).await.pop() {
+            return Some(song);
+        }
+    }
+}
+
+/// Applies the playback profile of where `song` is played from along with
+/// the song's own gain, returning the gain
+async fn apply_profile(
+    player: &mut impl PlayerBackend,
+    state: &Mutex<ControllerState>,
+    config: &RwLock<Config>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    applied: &Mutex<AppliedPlayback>,
+    song: &Song,
+    location: PlayerLocation,
+) -> f32 {
+    let playlist = match location {
+        PlayerLocation::Playlist(uuid) => {
+            let (command, tx) = LibraryCommandInput::command(LibraryCommand::PlaylistProfile(uuid));
+            lib_mail.send(command).await.unwrap();
+            let LibraryResponse::PlaylistProfile(profile) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            profile.map(|profile| (uuid, profile))
+        }
+        _ => None,
+    };
+    let (library, global, target_lufs) = {
+        let config = config.read();
+        let library = config
+            .libraries
+            .get_default()
+            .ok()
+            .and_then(|library| library.profile);
+        (
+            library,
+            config.playback.profile,
+            config.playback.normalization_target_lufs,
+        )
+    };
+
+    let settings =
+        ResolvedProfile::resolve(&[playlist.map(|(_, profile)| profile), library], global);
+    let source = match (playlist, library) {
+        (Some((uuid, _)), _) => Some(ProfileSource::Playlist(uuid)),
+        (None, Some(_)) => Some(ProfileSource::Library),
+        (None, None) => None,
+    };
+    let gain = settings.gain_at(song, target_lufs);
+    *applied.lock() = AppliedPlayback {
+        profile: source.map(|source| ActiveProfile { source, settings }),
+        gain: Some(gain),
+    };
+
+    let output = state.lock().output_volume();
+    player.set_volume(Volume::new((output * gain.factor).min(1.0)));
+    gain.factor
+}
+
+/// Sends out song changes along with where the song is in the queue
+#[derive(Clone)]
+pub(super) struct SongChangeNotifier {
+    pub(super) connections: Sender<ConnectionsNotification>,
+    pub(super) next_song: Sender<PlayerNotification>,
+    /// Sent along with song changes, so the frontend knows whether the song
+    /// is actually playing
+    pub(super) player_state: Arc<std::sync::RwLock<PrismState>>,
+    /// Read by the player monitor for [`PlaybackInfo`](super::controller::PlaybackInfo)
+    pub(super) position: Arc<Mutex<Option<QueuePosition>>>,
+    /// Radio station titles are followed for as long as this doesn't change
+    pub(super) track_epoch: Arc<AtomicU64>,
+    /// Fed by the player monitor, and given to the library as songs change
+    pub(super) listen_timer: Arc<Mutex<ListenTimer>>,
+    /// The song last announced, sent along as the previous one with the
+    /// next change
+    pub(super) current: Arc<Mutex<Option<Arc<Song>>>>,
+    pub(super) config: Arc<RwLock<Config>>,
+    /// Hands the silence found around a song back to the player
+    pub(super) player_mail: async_channel::Sender<PlayerCommandInput>,
+    pub(super) now_playing: NowPlayingTracker,
+}
+
+impl SongChangeNotifier {
+    /// Works out where `song` is in the queue and tells everything waiting
+    /// for song changes about it
+    async fn announce(
+        &self,
+        queue_mail: &async_channel::Sender<QueueCommandInput>,
+        lib_mail: &async_channel::Sender<LibraryCommandInput>,
+        song: Song,
+        location: PlayerLocation,
+        reason: TrackChangeReason,
+    ) {
+        let epoch = self.track_epoch.load(Ordering::SeqCst);
+        let listen = self.listen_timer.lock().start(epoch, song.uuid, location);
+        record_listen(lib_mail, listen).await;
+
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Counts);
+        queue_mail.send(command).await.unwrap();
+        let QueueResponse::Counts {
+            played,
+            queued,
+            automatic,
+        } = tx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+
+        let source = match location {
+            PlayerLocation::Library | PlayerLocation::Playlist(_) => {
+                let (command, tx) = LibraryCommandInput::command(LibraryCommand::SourcePosition(
+                    song.uuid, location,
+                ));
+                lib_mail.send(command).await.unwrap();
+                let LibraryResponse::SourcePosition(source) = tx.recv().await.unwrap() else {
+                    unreachable!()
+                };
+                source
+            }
+            _ => None,
+        };
+        let source_name = match location {
+            PlayerLocation::Playlist(uuid) => playlist_name(lib_mail, uuid).await,
+            _ => None,
+        };
+
+        let position = QueuePosition {
+            index: played,
+            total: played + queued + unqueued(source, automatic),
+            location,
+        };
+        *self.position.lock() = Some(position);
+        let station = match song.location.first() {
+            Some(URI::Remote(Service::InternetRadio, url)) => Some(url.clone()),
+            _ => None,
+        };
+        let song = Arc::new(song);
+        let previous = self.current.lock().replace(Arc::clone(&song));
+        self.send(
+            NowPlayingChange {
+                song: Arc::clone(&song),
+                previous,
+                reason,
+                position,
+                stream_title: None,
+            },
+            source_name,
+        );
+
+        if let Some(url) = station {
+            self.watch_station(song, url);
+        } else if song.needs_silence_detection(&self.config.read().playback) {
+            self.find_silence(&song);
+        }
+    }
+
+    /// Gives the library how long the last song was listened to once
+    /// nothing is playing anymore
+    async fn stopped(&self, lib_mail: &async_channel::Sender<LibraryCommandInput>) {
+        *self.current.lock() = None;
+        self.now_playing
+            .stopped(self.track_epoch.load(Ordering::SeqCst));
+        let listen = self.listen_timer.lock().stop();
+        record_listen(lib_mail, listen).await;
+    }
+
+    /// Lets everything waiting for song changes know `song` started over.
+    /// The play it cut short counts as a skip.
+    async fn restarted(&self, lib_mail: &async_channel::Sender<LibraryCommandInput>, song: &Song) {
+        let delta = StatDelta {
+            skips: 1,
+            ..Default::default()
+        };
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::UpdateStats(song.uuid, delta));
+        lib_mail.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        self.connections
+            .send(ConnectionsNotification::Restarted)
+            .unwrap();
+    }
+
+    /// Tells the frontend `song` couldn't be loaded
+    fn load_failed(&self, song: &Song, error: &PlayerError) {
+        let failure = LoadFailure::new(song, error, self.state());
+        self.now_playing.load_failed(&failure);
+        _ = self.next_song.send(PlayerNotification::LoadFailed(failure));
+    }
+
+    fn state(&self) -> PlaybackState {
+        PlaybackState::from(&*self.player_state.read().unwrap())
+    }
+
+    fn send(&self, change: NowPlayingChange, source_name: Option<String>) {
+        let state = self.state();
+        self.now_playing.song_changed(
+            &change,
+            state,
+            self.track_epoch.load(Ordering::SeqCst),
+            source_name,
+        );
+        _ = self.next_song.send(PlayerNotification::SongChange(
+            Box::new(change.clone()),
+            state,
+        ));
+        self.connections
+            .send(ConnectionsNotification::SongChange(Box::new(change)))
+            .unwrap();
+    }
+
+    /// Sends out a change each time the station `song` starts another
+    /// track, until a different song starts playing or playback stops
+    fn watch_station(&self, song: Arc<Song>, url: String) {
+        let epoch = self.track_epoch.load(Ordering::SeqCst);
+        let notifier = self.clone();
+        std::thread::Builder::new()
+            .name("ICY Metadata".to_string())
+            .spawn(move || {
+                let playing = || notifier.track_epoch.load(Ordering::SeqCst) == epoch;
+                let result = icy::watch_station(&url, playing, |title| {
+                    let Some(position) = *notifier.position.lock() else {
+                        return;
+                    };
+                    // The station is the same song throughout, so there's
+                    // no other one which played before
+                    let source_name = notifier.now_playing.get().source_name;
+                    notifier.send(
+                        NowPlayingChange {
+                            song: Arc::clone(&song),
+                            previous: None,
+                            reason: TrackChangeReason::Natural,
+                            position,
+                            stream_title: Some(title),
+                        },
+                        source_name,
+                    );
+                });
+                if let Err(e) = result {
+                    eprintln!("Couldn't read the titles of {url}: {e}");
+                }
+            })
+            .unwrap();
+    }
+
+    /// Finds the silence around `song` off the player, so it can start being
+    /// skipped while the song plays
+    fn find_silence(&self, song: &Song) {
+        let Some(URI::Local(path)) = song.location.first().cloned() else {
+            return;
+        };
+        let epoch = self.track_epoch.load(Ordering::SeqCst);
+        let uuid = song.uuid;
+        let threshold_db = self.config.read().playback.silence_threshold_db;
+        let player_mail = self.player_mail.clone();
+        std::thread::Builder::new()
+            .name("Silence Detection".to_string())
+            .spawn(move || match detect_silence(&path, threshold_db) {
+                Ok(silence) => {
+                    let (command, tx) = PlayerCommandInput::command(PlayerCommand::SilenceFound {
+                        epoch,
+                        uuid,
+                        silence,
+                    });
+                    if player_mail.send_blocking(command).is_ok() {
+                        _ = tx.recv_blocking();
+                    }
+                }
+                Err(e) => eprintln!("Couldn't find the silence in {}: {e}", path.display()),
+            })
+            .unwrap();
+    }
+}
+
+/// The name of the playlist `uuid`, shown as where songs are played from
+async fn playlist_name(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    uuid: Uuid,
+) -> Option<String> {
+    let (command, tx) = LibraryCommandInput::command(LibraryCommand::Playlists);
+    lib_mail.send(command).await.unwrap();
+    let LibraryResponse::Playlists(playlists) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    playlists
+        .into_iter()
+        .find_map(|(id, name)| (id == uuid).then_some(name))
+}
+
+/// Starts skipping the silence just found around the playing song. The
+/// silence at its start is only skipped if it hasn't played past it yet.
+async fn skip_found_silence(
+    player: &mut impl PlayerBackend,
+    track_duration: &Mutex<TrackDuration>,
+    config: &RwLock<Config>,
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    uuid: Uuid,
+    silence: SilenceBounds,
+) -> Result<(), PlayerError> {
+    let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Item(Ok(QueueItem {
+        item: QueueItemType::Single(current),
+        ..
+    })) = tx.recv().await.unwrap()
+    else {
+        return Ok(());
+    };
+    if current.song.uuid != uuid {
+        return Ok(());
+    }
+
+    let song = Song {
+        silence: Some(silence),
+        ..current.song
+    };
+    let Some((start, end)) = song.playback_range(&config.read().playback) else {
+        return Ok(());
+    };
+    let passed = player
+        .position()
+        .zip(TimeDelta::from_std(start).ok())
+        .is_some_and(|(position, start)| position >= start);
+    let start = if passed { Duration::ZERO } else { start };
+    if let Some(start) = track_duration.lock().trim(Some((start, end))) {
+        if start > TimeDelta::zero() {
+            player.seek_to(start)?;
+        }
+    }
+    Ok(())
+}
+
+/// Why the song `command` plays started, for the commands which change it
+fn change_reason(command: &PlayerCommand) -> TrackChangeReason {
+    match command {
+        PlayerCommand::TrackFinished | PlayerCommand::CueTrackChange { .. } => {
+            TrackChangeReason::Natural
+        }
+        PlayerCommand::NextSong => TrackChangeReason::UserNext,
+        PlayerCommand::PrevSong => TrackChangeReason::UserPrev,
+        PlayerCommand::PlayPlayed(_) | PlayerCommand::Enqueue { .. } => TrackChangeReason::UserJump,
+        _ => TrackChangeReason::PlayNow,
+    }
+}
+
+/// The reason a song started when `played` had to be played instead of the
+/// `requested` one, because that couldn't be
+fn after_skipping(reason: TrackChangeReason, requested: Uuid, played: Uuid) -> TrackChangeReason {
+    match requested == played {
+        true => reason,
+        false => TrackChangeReason::Recovered,
+    }
+}
+
+/// How many songs are left to be queued up from the library or a playlist,
+/// given where the playing song is in it and how many songs were queued up
+/// from it already
+fn unqueued(source: Option<(usize, usize)>, automatic: usize) -> usize {
+    source.map_or(0, |(index, len)| len.saturating_sub(index + 1 + automatic))
+}
+
+/// Plays `song`, the current item of the queue. Songs whose files went
+/// missing after they were queued are skipped: they're marked as missing in
+/// the library and taken out of the queue, and the item after them is played
+/// instead. Returns the song which ended up playing.
+async fn play_skipping_missing(
+    mut load: impl FnMut(&Song) -> Result<(), PlayerError>,
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    notify_skipped: &Sender<SkippedSong>,
+    mut song: QueueSong,
+) -> Result<QueueSong, PlayerError> {
+    loop {
+        let path = match load(&song.song) {
+            Ok(()) => return Ok(song),
+            Err(PlayerError::FileMissing { path }) => path,
+            Err(e) => return Err(e),
+        };
+        _ = notify_skipped.send(SkippedSong::new(&song.song, path.clone()));
+
+        let (command, tx) =
+            LibraryCommandInput::command(LibraryCommand::MarkMissing(song.song.uuid));
+        lib_mail.send(command).await.unwrap();
+        let LibraryResponse::Ok = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Remove(0));
+        queue_mail.send(command).await.unwrap();
+        let QueueResponse::Item(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res?;
+
+        let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+        queue_mail.send(command).await.unwrap();
+        song = match tx.recv().await.unwrap() {
+            QueueResponse::Item(Ok(QueueItem {
+                item: QueueItemType::Single(next),
+                ..
+            })) => next,
+            // Nothing is left to play
+            _ => return Err(PlayerError::FileMissing { path }),
+        };
+    }
+}
+
+/// Whether a song can be queued up automatically, which songs whose files
+/// have gone missing or which were found to be silent or broken can't, and
+/// explicit songs can't when `filtered`
+fn playable(song: &Song, filtered: bool) -> bool {
+    !(filtered && song.is_explicit()) && song.problem().is_none() && song.primary_uri().is_ok()
+}
+
+/// Where to seek the player to for a position `time` into the current track,
+/// and how long the track is. Positions in a cue album are relative to the
+/// track, and in a trimmed song to the start of the trim.
+fn seek_target(
+    player: &mut impl PlayerBackend,
+    cue_session: &Mutex<Option<CueSession>>,
+    track_duration: &Mutex<TrackDuration>,
+    epoch: u64,
+    time: TimeDelta,
+) -> (TimeDelta, Option<TimeDelta>) {
+    match &*cue_session.lock() {
+        Some(session) if session.is_active(epoch) => {
+            (session.absolute(time), Some(session.track_duration()))
+        }
+        _ => {
+            let track_duration = track_duration.lock();
+            (
+                track_duration.absolute(time),
+                track_duration.trimmed().or_else(|| player.duration()),
+            )
+        }
+    }
+}
+
+/// Whether Previous restarts the current song rather than going back one,
+/// given where the song was last reported to be
+fn prev_restarts(position: Option<TimeDelta>, threshold: TimeDelta) -> bool {
+    threshold > TimeDelta::zero() && position.is_some_and(|position| position >= threshold)
+}
+
+/// Seeks back to the start of the current song, returning it
+async fn restart(
+    player: &mut impl PlayerBackend,
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    cue_session: &Mutex<Option<CueSession>>,
+    track_duration: &Mutex<TrackDuration>,
+    epoch: u64,
+) -> Result<Song, PlayerError> {
+    let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Item(Ok(QueueItem {
+        item: QueueItemType::Single(current),
+        ..
+    })) = tx.recv().await.unwrap()
+    else {
+        return Err(PlayerError::NothingPlaying);
+    };
+
+    // A cue album's track is a song of its own
+    if let Some(res) = cue_step(player, cue_session, epoch, 0) {
+        return res;
+    }
+    let (start, _) = seek_target(
+        player,
+        cue_session,
+        track_duration,
+        epoch,
+        TimeDelta::zero(),
+    );
+    player.seek_to(start)?;
+    Ok(current.song)
+}
+
+/// Within a cue album, seeks to the start of the track `offset` away from
+/// the current one instead of loading it, returning the track's song. The
+/// player monitor picks up on the track change. Returns `None` when not
+/// playing a cue album, or when there is no such track in it.
+fn cue_step(
+    player: &mut impl PlayerBackend,
+    cue_session: &Mutex<Option<CueSession>>,
+    epoch: u64,
+    offset: isize,
+) -> Option<Result<Song, PlayerError>> {
+    let session = cue_session.lock();
+    let session = session
+        .as_ref()
+        .filter(|session| session.is_active(epoch))?;
+    let index = session.current().checked_add_signed(offset)?;
+    let start = session.start_of(index)?;
+    Some(
+        player
+            .seek_to(start)
+            .map(|_| session.song(index).unwrap().clone())
+            .map_err(PlayerError::from),
+    )
+}
+
+/// Adds songs related to the one playing to the queue, after the songs
+/// added by hand, for [`PlayerCommand::EnqueueRestOfAlbum`] and
+/// [`PlayerCommand::EnqueueMoreByArtist`]. Returns how many were added.
+async fn enqueue_related(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    command: &PlayerCommand,
+    filtered: bool,
+) -> Result<usize, PlayerError> {
+    let (queue_command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(queue_command).await.unwrap();
+    let QueueResponse::Item(Ok(QueueItem {
+        item: QueueItemType::Single(current),
+        ..
+    })) = tx.recv().await.unwrap()
+    else {
+        return Err(PlayerError::NothingPlaying);
+    };
+
+    let (queue_command, tx) = QueueCommandInput::command(QueueCommand::Get);
+    queue_mail.send(queue_command).await.unwrap();
+    let QueueResponse::GetAll(items) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    let queued: HashSet<Uuid> = items
+        .iter()
+        .filter_map(|item| match &item.item {
+            QueueItemType::Single(song) => Some(song.song.uuid),
+            _ => None,
+        })
+        .collect();
+
+    let song = &current.song;
+    let (songs, location) = match *command {
+        PlayerCommand::EnqueueRestOfAlbum => {
+            let title = song
+                .get_tag(&Tag::Album)
+                .ok_or(PlayerError::MissingTag("album".to_string()))?;
+            let key = AlbumKey {
+                title: title.clone(),
+                artist: song.get_tag(&Tag::AlbumArtist).cloned(),
+            };
+            let (lib_command, tx) =
+                LibraryCommandInput::command(LibraryCommand::AlbumTracks(key, None));
+            lib_mail.send(lib_command).await.unwrap();
+            let LibraryResponse::AlbumTracks(tracks) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            (
+                rest_of_album(tracks, song.uuid, &queued, filtered),
+                PlayerLocation::Album,
+            )
+        }
+        PlayerCommand::EnqueueMoreByArtist(count) => {
+            let artist = song
+                .get_tag(&Tag::Artist)
+                .or(song.get_tag(&Tag::AlbumArtist))
+                .ok_or(PlayerError::MissingTag("artist".to_string()))?;
+            let (lib_command, tx) =
+                LibraryCommandInput::command(LibraryCommand::ArtistSongs(artist.clone()));
+            lib_mail.send(lib_command).await.unwrap();
+            let LibraryResponse::Songs(songs) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            (
+                more_by_artist(songs, &queued, count, filtered),
+                PlayerLocation::Custom,
+            )
+        }
+        _ => unreachable!(),
+    };
+
+    let added = songs.len();
+    for song in songs {
+        let (queue_command, tx) = QueueCommandInput::command(QueueCommand::Append(
+            QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                song,
+                location,
+                transition: None,
+            })),
+            true,
+        ));
+        queue_mail.send(queue_command).await.unwrap();
+        let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        res?;
+    }
+    Ok(added)
+}
+
+/// Inserts the album's tracks after the current song as one block, leaving
+/// out banned songs
+async fn enqueue_album_next(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    key: AlbumKey,
+    filtered: bool,
+) -> Result<usize, PlayerError> {
+    let title = key.title.clone();
+    let (command, tx) = LibraryCommandInput::command(LibraryCommand::AlbumTracks(key, None));
+    lib_mail.send(command).await.unwrap();
+    let LibraryResponse::AlbumTracks(tracks) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+
+    let items: Vec<_> = tracks
+        .into_iter()
+        .filter(|song| song.banned != Some(BannedType::All) && playable(song, filtered))
+        .map(|song| {
+            QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                song,
+                location: PlayerLocation::Album,
+                transition: None,
+            }))
+        })
+        .collect();
+    if items.is_empty() {
+        return Err(PlayerError::NoSongsFound(title));
+    }
+
+    let added = items.len();
+    let (command, tx) = QueueCommandInput::command(QueueCommand::AppendBlockNext(items));
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res?;
+    Ok(added)
+}
+
+/// The tracks of an album which come after `current`, in order, leaving out
+/// songs which are already queued or banned
+fn rest_of_album(
+    tracks: Vec<Song>,
+    current: Uuid,
+    queued: &HashSet<Uuid>,
+    filtered: bool,
+) -> Vec<Song> {
+    tracks
+        .into_iter()
+        .skip_while(|song| song.uuid != current)
+        .skip(1)
+        .filter(|song| {
+            !queued.contains(&song.uuid)
+                && song.banned != Some(BannedType::All)
+                && playable(song, filtered)
+        })
+        .collect()
+}
+
+/// Picks up to `count` of an artist's songs at random, leaving out songs
+/// which are already queued or banned from shuffling
+fn more_by_artist(
+    songs: Vec<Song>,
+    queued: &HashSet<Uuid>,
+    count: usize,
+    filtered: bool,
+) -> Vec<Song> {
+    let mut songs: Vec<Song> = songs
+        .into_iter()
+        .filter(|song| {
+            !queued.contains(&song.uuid) && song.banned.is_none() && playable(song, filtered)
+        })
+        .collect();
+    songs.shuffle(&mut rand::thread_rng());
+    songs.truncate(count);
+    songs
+}
+
+/// Receives player commands, merging bursts of seeks into the latest one so
+/// dragging the seekbar doesn't leave a backlog behind. Previews are merged
+/// the same way, and dropped once a seek for the same track follows them.
+struct PlayerMailbox {
+    mail: async_channel::Receiver<PlayerCommandInput>,
+    pending: VecDeque<PlayerCommandInput>,
+}
+
+impl PlayerMailbox {
+    fn new(mail: async_channel::Receiver<PlayerCommandInput>) -> Self {
+        PlayerMailbox {
+            mail,
+            pending: VecDeque::new(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<PlayerCommandInput, async_channel::RecvError> {
+        let mut input = match self.pending.pop_front() {
+            Some(input) => input,
+            None => self.mail.recv().await?,
+        };
+
+        while let PlayerCommand::Seek { epoch, .. } | PlayerCommand::SeekPreview { epoch, .. } =
+            input.command
+        {
+            let Ok(next) = self.mail.try_recv() else {
+                break;
+            };
+            let is_preview = matches!(input.command, PlayerCommand::SeekPreview { .. });
+            match next.command {
+                // Seeks are only merged with seeks for the same track, and
+                // never across other commands. A seek is never replaced by
+                // a preview, since the preview wouldn't move the player.
+                PlayerCommand::Seek {
+                    epoch: next_epoch, ..
+                } if next_epoch == epoch => {
+                    _ = input.res_rx.send(PlayerResponse::Empty(Ok(()))).await;
+                    input = next;
+                }
+                PlayerCommand::SeekPreview {
+                    epoch: next_epoch, ..
+                } if is_preview && next_epoch == epoch => {
+                    _ = input.res_rx.send(PlayerResponse::Empty(Ok(()))).await;
+                    input = next;
+                }
+                _ => {
+                    self.pending.push_back(next);
+                    break;
+                }
+            }
+        }
+
+        Ok(input)
+    }
+}
+
+/// The number of songs queued up automatically after the current one
+const UP_NEXT_LEN: usize = 49;
+
+/// Picks the songs which could be added to the end of the up next songs
+/// when the song at `index` starts playing, in the order to try them
+fn next_up_next<T: Clone>(songs: &[T], index: usize, modes: PlaybackModes) -> Vec<T> {
+    match modes {
+        PlaybackModes {
+            shuffle: ShuffleMode::On,
+            ..
+        } => {
+            // Checking every song's file would be slow for a big library,
+            // so only a few picks are tried
+            let mut rng = rand::thread_rng();
+            (0..8)
+                .filter_map(|_| songs.choose(&mut rng).cloned())
+                .collect()
+        }
+        PlaybackModes {
+            repeat: RepeatMode::All,
+            ..
+        } if !songs.is_empty() => vec![songs[(index + UP_NEXT_LEN) % songs.len()].clone()],
+        _ => songs
+            .get(index + UP_NEXT_LEN)
+            .cloned()
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Puts the songs to play after `current` out of `songs` in order, or
+/// shuffled, wrapping around to the start when repeating
+fn up_next_songs<T: Clone>(songs: &[T], current: usize, modes: PlaybackModes) -> Vec<T> {
+    let mut up_next: Vec<T> = match modes.repeat {
+        RepeatMode::All => songs[current + 1..]
+            .iter()
+            .chain(&songs[..current])
+            .cloned()
+            .collect(),
+        _ => songs[current + 1..].to_vec(),
+    };
+    if modes.shuffle == ShuffleMode::On {
+        up_next = songs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != current)
+            .map(|(_, song)| song.clone())
+            .collect();
+        up_next.shuffle(&mut rand::thread_rng());
+    }
+    up_next
+}
+
+/// The uuids of the songs at `location`, in the order they're played
+async fn location_uuids(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    location: PlayerLocation,
+) -> Option<Vec<Uuid>> {
+    match location {
+        PlayerLocation::Library => {
+            let (command, tx) = LibraryCommandInput::command(LibraryCommand::AllUuids);
+            lib_mail.send(command).await.unwrap();
+            let LibraryResponse::AllUuids(uuids) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            Some(uuids)
+        }
+        PlayerLocation::Playlist(uuid) => {
+            let (command, tx) = LibraryCommandInput::command(LibraryCommand::PlaylistUuids(uuid));
+            lib_mail.send(command).await.unwrap();
+            let LibraryResponse::PlaylistUuids(uuids) = tx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            uuids
+        }
+        // Other sessions are queued in full when they start
+        _ => None,
+    }
+}
+
+/// Looks up the first `count` playable songs out of `uuids`, in order,
+/// leaving out explicit songs when `filtered`. They
+/// are fetched from the library a batch at a time rather than one by one,
+/// so only songs which can't be played make it take more than one trip.
+async fn fetch_playable(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    uuids: &[Uuid],
+    count: usize,
+    filtered: bool,
+) -> Vec<Song> {
+    let mut songs = Vec::new();
+    for batch in uuids.chunks(count.max(1)) {
+        if songs.len() >= count {
+            break;
+        }
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SongsBulk(batch.to_vec()));
+        lib_mail.send(command).await.unwrap();
+        let LibraryResponse::SongsBulk(batch) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        songs.extend(
+            batch
+                .into_iter()
+                .filter(|song| playable(song, filtered))
+                .map(Arc::unwrap_or_clone),
+        );
+    }
+    songs.truncate(count);
+    songs
+}
+
+/// How long the playable songs out of `uuids` take to play, looked up
+/// together in one trip
+async fn pool_duration(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    uuids: &[Uuid],
+    filtered: bool,
+) -> Duration {
+    let (command, tx) = LibraryCommandInput::command(LibraryCommand::SongsBulk(uuids.to_vec()));
+    lib_mail.send(command).await.unwrap();
+    let LibraryResponse::SongsBulk(songs) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    songs
+        .iter()
+        .filter(|song| playable(song, filtered))
+        .map(|song| song.duration)
+        .sum()
+}
+
+/// Tells the queue how long the songs out of `uuids` which weren't `queued`
+/// up take to play, for the time left in the queue
+async fn set_pool(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    uuids: &[Uuid],
+    queued: &[Song],
+    filtered: bool,
+) -> Result<(), QueueError> {
+    let queued: Duration = queued.iter().map(|song| song.duration).sum();
+    let pool = pool_duration(lib_mail, uuids, filtered)
+        .await
+        .saturating_sub(queued);
+    let (command, tx) = QueueCommandInput::command(QueueCommand::SetPool(pool));
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res
+}
+
+/// Queues up the songs out of `uuids` which weren't `queued` to be played
+/// next, in order, after the songs already coming up
+async fn set_pending(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    uuids: &[Uuid],
+    queued: &[Song],
+    filtered: bool,
+) -> Result<(), QueueError> {
+    let taken = match queued.last() {
+        Some(last) => uuids.iter().position(|uuid| *uuid == last.uuid).unwrap() + 1,
+        None => uuids.len(),
+    };
+    let uuids = uuids[taken..].to_vec();
+    let pool = pool_duration(lib_mail, &uuids, filtered).await;
+    let (command, tx) = QueueCommandInput::command(QueueCommand::SetPending {
+        uuids,
+        pool,
+        mode: QueueMode::PlayNow,
+    });
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res
+}
+
+/// The songs to queue up after `current` when it's played from `location`,
+/// and the uuids of every song after it in the order they'll play. With
+/// `shuffle_remaining` they're shuffled.
+async fn location_up_next(
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    location: PlayerLocation,
+    current: Uuid,
+    shuffle_remaining: bool,
+    filtered: bool,
+) -> (Vec<Song>, Vec<Uuid>) {
+    let Some(uuids) = location_uuids(lib_mail, location).await else {
+        return (Vec::new(), Vec::new());
+    };
+    let Some(index) = uuids.iter().position(|uuid| *uuid == current) else {
+        return (Vec::new(), Vec::new());
+    };
+    let mut rest = uuids[index + 1..].to_vec();
+    if shuffle_remaining {
+        rest.shuffle(&mut rand::thread_rng());
+    }
+    let up_next = fetch_playable(lib_mail, &rest, UP_NEXT_LEN, filtered).await;
+    (up_next, rest)
+}
+
+/// Replaces the songs queued up automatically with new ones picked for
+/// `modes` from wherever the current song is playing from. Songs added by
+/// hand stay where they are.
+async fn plan_up_next(
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    lib_mail: &async_channel::Sender<LibraryCommandInput>,
+    modes: PlaybackModes,
+    filtered: bool,
+) -> Result<(), PlayerError> {
+    let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Item(Ok(QueueItem {
+        item: QueueItemType::Single(current),
+        ..
+    })) = tx.recv().await.unwrap()
+    else {
+        // Nothing is playing, so nothing is coming up
+        return Ok(());
+    };
+
+    let Some(uuids) = location_uuids(lib_mail, current.location).await else {
+        return Ok(());
+    };
+    let Some(index) = uuids.iter().position(|uuid| *uuid == current.song.uuid) else {
+        return Ok(());
+    };
+
+    let up_next = up_next_songs(&uuids, index, modes);
+    let songs = fetch_playable(lib_mail, &up_next, UP_NEXT_LEN, filtered).await;
+    let items = songs
+        .iter()
+        .cloned()
+        .map(|song| {
+            QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                song,
+                location: current.location,
+                transition: None,
+            }))
+        })
+        .collect();
+    let (command, tx) = QueueCommandInput::command(QueueCommand::ReplaceUpNext(items));
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Empty(res) = tx.recv().await.unwrap() else {
+        unreachable!()
+    };
+    res?;
+    set_pool(queue_mail, lib_mail, &up_next, &songs, filtered)
+        .await
+        .map_err(PlayerError::from)
+}
+
+/// Loads a song into the player and starts playing it, turning any
+/// failure into the most specific [`PlayerError`]
+fn load_and_play(
+    player: &mut impl PlayerBackend,
+    track_duration: &Mutex<TrackDuration>,
+    remote_sources: &RwLock<RemoteSources>,
+    config: &RwLock<Config>,
+    song: &Song,
+) -> Result<(), PlayerError> {
+    let path = match song.primary_uri() {
+        Ok((uri @ URI::Remote(..), _)) => {
+            let sources = remote_sources.read().clone();
+            // Only a source sure the song is gone skips it, since the stream
+            // fails to load by itself when the server can't be reached
+            if sources.available(uri) == Ok(false) {
+                return Err(PlayerError::FileMissing { path: uri.path() });
+            }
+            match sources.playable(uri)? {
+                Playable::Stream(url) => {
+                    let prism_uri = Url::parse(&url)
+                        .map_err(|e| PlayerError::from_load(&uri.path(), e.to_string()))?;
+                    // Streams don't have a length to show until the decoder
+                    // finds one, if it ever does
+                    track_duration.lock().load(song.duration);
+                    player
+                        .load_new(&prism_uri)
+                        .map_err(|e| PlayerError::from_load(&uri.path(), e.to_string()))?;
+                    player.play();
+                    return Ok(());
+                }
+                Playable::Cached(path) => path,
+            }
+        }
+        Ok((uri, _)) => uri.path(),
+        Err(_) => {
+            return Err(PlayerError::FileMissing {
+                path: song
+                    .location
+                    .first()
+                    .map(|uri| uri.path())
+                    .unwrap_or_default(),
+            })
+        }
+    };
+
+    let prism_uri = prismriver::utils::path_to_uri(&path)
+        .map_err(|e| PlayerError::from_load(&path, e.to_string()))?;
+    // Until the decoder has probed the file, the duration in the library
+    // is shown instead
+    track_duration.lock().load(song.duration);
+    player
+        .load_new(&prism_uri)
+        .map_err(|e| PlayerError::from_load(&path, e.to_string()))?;
+    player.play();
+    let range = song.playback_range(&config.read().playback);
+    if let Some(start) = track_duration.lock().trim(range) {
+        if start > TimeDelta::zero() {
+            player.seek_to(start)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the current song so the player opens the current default output,
+/// then picks up where it left off
+async fn reopen_output(
+    player: &mut impl PlayerBackend,
+    track_duration: &Mutex<TrackDuration>,
+    remote_sources: &RwLock<RemoteSources>,
+    config: &RwLock<Config>,
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+    resume: bool,
+) -> Result<(), PlayerError> {
+    let position = player.position();
+
+    let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(command).await.unwrap();
+    let QueueResponse::Item(Ok(QueueItem {
+        item: QueueItemType::Single(song),
+        ..
+    })) = tx.recv().await.unwrap()
+    else {
+        // Nothing is loaded, so there is nothing to reopen
+        return Ok(());
+    };
+
+    load_and_play(player, track_duration, remote_sources, config, &song.song)?;
+    if let Some(position) = position {
+        player.seek_to(position)?;
+    }
+    if !resume {
+        player.pause();
+    }
+    Ok(())
+}
+
+/// Switches the output to `mode` for the playing song's sample rate, then
+/// reopens the output so the player's stream picks up the change. A song
+/// the device can't play exclusively carries on through the system mixer.
+#[allow(clippy::too_many_arguments)]
+async fn set_output_mode(
+    player: &mut impl PlayerBackend,
+    sink: &mut dyn OutputSink,
+    output: &Mutex<NegotiatedOutput>,
+    mode: OutputMode,
+    track_duration: &Mutex<TrackDuration>,
+    remote_sources: &RwLock<RemoteSources>,
+    config: &RwLock<Config>,
+    queue_mail: &async_channel::Sender<QueueCommandInput>,
+) -> Result<OutputSwitch, PlayerError> {
+    let (command, tx) = QueueCommandInput::command(QueueCommand::NowPlaying);
+    queue_mail.send(command).await.unwrap();
+    let rate = match tx.recv().await.unwrap() {
+        QueueResponse::Item(Ok(QueueItem {
+            item: QueueItemType::Single(song),
+            ..
+        })) => song.song.sample_rate(),
+        _ => None,
+    };
+
+    let res = switch_output(sink, mode, rate);
+    let switch = match &res {
+        Ok(switch) => switch.clone(),
+        Err(_) => switch_output(sink, OutputMode::Shared, rate)?,
+    };
+    *output.lock() = switch.output;
+
+    let playing = player.state() == PrismState::Playing;
+    reopen_output(
+        player,
+        track_duration,
+        remote_sources,
+        config,
+        queue_mail,
+        playing,
+    )
+    .await?;
+    res.map_err(PlayerError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        fs,
+        path::PathBuf,
+        sync::{atomic::AtomicU64, Arc},
+        time::{Duration, Instant},
+    };
+
+    use chrono::TimeDelta;
+    use futures::executor::block_on;
+    use kushi::{Queue, QueueItem, QueueItemType};
+    use parking_lot::{Mutex, RwLock};
+    use prismriver::{utils::Url, Error as PrismError, State as PrismState, Volume};
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::music_controller::{
+        connections::ConnectionsNotification,
+        controller::{
+            Controller, LibraryCommand, LibraryResponse, PlaybackState, PlayerCommand, PlayerError,
+            PlayerLocation, PlayerNotification, PlayerResponse, QueueCommand, QueuePosition,
+            QueueResponse, TrackChangeReason,
+        },
+        controller_handle::{LibraryCommandInput, PlayerCommandInput, QueueCommandInput},
+        controller_state::{PlaybackModes, RepeatMode, ShuffleMode},
+        listen_time::ListenTimer,
+        now_playing::NowPlayingTracker,
+        player_monitor::{SeekPosition, SEEK_SETTLE_TIME},
+        queue::QueueSong,
+        remote_source::{Playable, RemoteError, RemoteSource, RemoteSources},
+    };
+    use crate::music_storage::{
+        library::{AlbumKey, BannedType, Service, Song, Tag, URI},
+        search::QueueMode,
+    };
+
+    use super::{
+        after_skipping, change_reason, load_and_play, location_up_next, more_by_artist,
+        next_pending, play_skipping_missing, prev_restarts, queue_from_filter, queue_songs_of,
+        replace_queue_pending, rest_of_album, set_pending, up_next_songs, PlayerBackend,
+        PlayerMailbox, SongChangeNotifier, UP_NEXT_LEN,
+    };
+
+    fn seek(time: i64, epoch: u64) -> PlayerCommand {
+        PlayerCommand::Seek { time, epoch }
+    }
+
+    #[test]
+    fn seek_bursts_are_coalesced() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut mailbox = PlayerMailbox::new(rx);
+
+        let mut responses = Vec::new();
+        for command in [
+            seek(1000, 0),
+            seek(2000, 0),
+            seek(3000, 0),
+            PlayerCommand::Play,
+        ] {
+            let (input, res) = PlayerCommandInput::command(command);
+            tx.send_blocking(input).unwrap();
+            responses.push(res);
+        }
+
+        let first = block_on(mailbox.recv()).unwrap();
+        assert_eq!(first.command, seek(3000, 0));
+        for res in &responses[..2] {
+            assert_eq!(res.try_recv(), Ok(PlayerResponse::Empty(Ok(()))));
+        }
+        assert!(responses[2].try_recv().is_err());
+        assert_eq!(
+            block_on(mailbox.recv()).unwrap().command,
+            PlayerCommand::Play
+        );
+    }
+
+    fn preview(time: i64, epoch: u64) -> PlayerCommand {
+        PlayerCommand::SeekPreview { time, epoch }
+    }
+
+    #[test]
+    fn previews_end_with_one_seek() {
+        // Dragging sends a preview for every move, then a seek on release
+        let (tx, rx) = async_channel::unbounded();
+        let mut mailbox = PlayerMailbox::new(rx);
+        for command in [
+            preview(1000, 0),
+            preview(2000, 0),
+            preview(3000, 0),
+            seek(3000, 0),
+            PlayerCommand::Play,
+        ] {
+            tx.send_blocking(PlayerCommandInput::command(command).0)
+                .unwrap();
+        }
+
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, seek(3000, 0));
+        assert_eq!(
+            block_on(mailbox.recv()).unwrap().command,
+            PlayerCommand::Play
+        );
+    }
+
+    #[test]
+    fn seeks_are_not_replaced_by_previews() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut mailbox = PlayerMailbox::new(rx);
+        for command in [
+            seek(1000, 0),
+            preview(2000, 0),
+            preview(3000, 0),
+            preview(4000, 1),
+        ] {
+            tx.send_blocking(PlayerCommandInput::command(command).0)
+                .unwrap();
+        }
+
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, seek(1000, 0));
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, preview(3000, 0));
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, preview(4000, 1));
+    }
+
+    /// A stand in for the player, which keeps the seeks it's asked for
+    struct SeekingPlayer(Arc<Mutex<Vec<TimeDelta>>>);
+
+    impl PlayerBackend for SeekingPlayer {
+        fn load_new(&mut self, _: &Url) -> Result<(), PrismError> {
+            Ok(())
+        }
+
+        fn play(&mut self) {}
+
+        fn pause(&mut self) {}
+
+        fn stop(&mut self) {}
+
+        fn seek_to(&mut self, time: TimeDelta) -> Result<(), PrismError> {
+            self.0.lock().push(time);
+            Ok(())
+        }
+
+        fn position(&mut self) -> Option<TimeDelta> {
+            None
+        }
+
+        fn duration(&mut self) -> Option<TimeDelta> {
+            Some(TimeDelta::minutes(3))
+        }
+
+        fn set_volume(&mut self, _: Volume) {}
+
+        fn state(&mut self) -> PrismState {
+            PrismState::Stopped
+        }
+    }
+
+    #[test]
+    fn seek_across_track_change_is_stale() {
+        let seeks = Arc::new(Mutex::new(Vec::new()));
+        let track_epoch = Arc::new(AtomicU64::new(0));
+        let (player_mail, player_rx) = async_channel::unbounded();
+        let (queue_mail, _queue_rx) = async_channel::unbounded();
+        let (lib_mail, _) = counting_library(Vec::new());
+        let (connections, _notifications) = crossbeam_channel::unbounded();
+        let (next_song, _changes) = crossbeam_channel::unbounded();
+        let (notify_modes, _modes) = crossbeam_channel::unbounded();
+        let (notify_skipped, _skipped) = crossbeam_channel::unbounded();
+        let config = Arc::new(RwLock::new(Config::default()));
+        let song_changes = SongChangeNotifier {
+            connections,
+            next_song,
+            player_state: Arc::new(std::sync::RwLock::new(PrismState::Stopped)),
+            position: Arc::new(Mutex::new(None)),
+            track_epoch: Arc::clone(&track_epoch),
+            listen_timer: Arc::new(Mutex::new(ListenTimer::default())),
+            current: Arc::default(),
+            config: Arc::clone(&config),
+            player_mail: player_mail.clone(),
+            now_playing: NowPlayingTracker::default(),
+        };
+        let player = SeekingPlayer(Arc::clone(&seeks));
+        std::thread::spawn({
+            let track_epoch = Arc::clone(&track_epoch);
+            move || {
+                block_on(Controller::player_command_loop(
+                    player,
+                    player_rx,
+                    queue_mail,
+                    lib_mail,
+                    song_changes,
+                    Arc::default(),
+                    config,
+                    track_epoch,
+                    Arc::default(),
+                    notify_modes,
+                    notify_skipped,
+                    Arc::default(),
+                    Arc::default(),
+                    Arc::new(RwLock::new(RemoteSources::default())),
+                    Arc::default(),
+                    Arc::default(),
+                ))
+            }
+        });
+        let command = |command| {
+            let (command, tx) = PlayerCommandInput::command(command);
+            player_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+
+        assert_eq!(command(seek(1000, 0)), PlayerResponse::Empty(Ok(())));
+        // The seekbar is let go after the track changed, so the seek was
+        // meant for the old track
+        assert_eq!(command(PlayerCommand::Stop), PlayerResponse::Empty(Ok(())));
+        assert_eq!(
+            command(seek(2000, 0)),
+            PlayerResponse::Empty(Err(PlayerError::StaleCommand {
+                issued: 0,
+                current: 1
+            }))
+        );
+        assert_eq!(command(seek(3000, 1)), PlayerResponse::Empty(Ok(())));
+        assert_eq!(
+            *seeks.lock(),
+            [TimeDelta::seconds(1), TimeDelta::seconds(3)]
+        );
+    }
+
+    /// A radio station which has gone off the air for good
+    struct OffTheAir;
+
+    impl RemoteSource for OffTheAir {
+        fn metadata(&self, _: &str) -> Result<BTreeMap<Tag, String>, RemoteError> {
+            Ok(BTreeMap::new())
+        }
+
+        fn playable(&self, location: &str) -> Result<Playable, RemoteError> {
+            Ok(Playable::Stream(location.to_string()))
+        }
+
+        fn available(&self, _: &str) -> Result<bool, RemoteError> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn remote_song_off_the_air_is_missing() {
+        let song = Song {
+            location: vec![URI::Remote(
+                Service::InternetRadio,
+                "https://radio.example/stream".to_string(),
+            )],
+            ..Default::default()
+        };
+        let mut sources = RemoteSources::empty();
+        sources.register(Service::InternetRadio, Arc::new(OffTheAir));
+
+        // Missing songs are skipped rather than stopping the queue
+        let res = load_and_play(
+            &mut SeekingPlayer(Arc::default()),
+            &Mutex::default(),
+            &RwLock::new(sources),
+            &RwLock::new(Config::default()),
+            &song,
+        );
+        assert_eq!(
+            res,
+            Err(PlayerError::FileMissing {
+                path: PathBuf::from("https://radio.example/stream")
+            })
+        );
+    }
+
+    #[test]
+    fn seeks_for_different_tracks_are_kept() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut mailbox = PlayerMailbox::new(rx);
+        for command in [seek(1000, 0), seek(2000, 1)] {
+            tx.send_blocking(PlayerCommandInput::command(command).0)
+                .unwrap();
+        }
+
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, seek(1000, 0));
+        assert_eq!(block_on(mailbox.recv()).unwrap().command, seek(2000, 1));
+    }
+
+    #[test]
+    fn up_next_for_modes() {
+        let songs: Vec<Song> = (0..5)
+            .map(|_| Song {
+                location: vec![URI::Local(PathBuf::from("Cargo.toml"))],
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            })
+            .collect();
+        let uuids = |songs: Vec<Song>| songs.iter().map(|s| s.uuid).collect::<Vec<_>>();
+        let modes = |shuffle, repeat| PlaybackModes { shuffle, repeat };
+
+        let up_next = up_next_songs(&songs, 3, modes(ShuffleMode::Off, RepeatMode::Off));
+        assert_eq!(uuids(up_next), [songs[4].uuid]);
+
+        // Repeating wraps around to the start, even from the last song
+        let up_next = up_next_songs(&songs, 4, modes(ShuffleMode::Off, RepeatMode::All));
+        assert_eq!(
+            uuids(up_next),
+            [songs[0].uuid, songs[1].uuid, songs[2].uuid, songs[3].uuid]
+        );
+
+        // Shuffling picks from every song but the current one
+        let mut up_next = uuids(up_next_songs(
+            &songs,
+            2,
+            modes(ShuffleMode::On, RepeatMode::Off),
+        ));
+        up_next.sort();
+        let mut others: Vec<Uuid> = uuids(songs.clone());
+        others.remove(2);
+        others.sort();
+        assert_eq!(up_next, others);
+    }
+
+    /// A stand in for the library loop, which counts the commands it gets
+    fn counting_library(
+        songs: Vec<Song>,
+    ) -> (
+        async_channel::Sender<LibraryCommandInput>,
+        std::thread::JoinHandle<usize>,
+    ) {
+        let (lib_mail, lib_rx) = async_channel::unbounded::<LibraryCommandInput>();
+        let handle = std::thread::spawn(move || {
+            let mut trips = 0;
+            while let Ok(LibraryCommandInput { res_rx, command }) = lib_rx.recv_blocking() {
+                trips += 1;
+                let res = match command {
+                    LibraryCommand::AllUuids => {
+                        LibraryResponse::AllUuids(songs.iter().map(|song| song.uuid).collect())
+                    }
+                    // Every song is in every playlist
+                    LibraryCommand::PlaylistUuids(_) => LibraryResponse::PlaylistUuids(Some(
+                        songs.iter().map(|song| song.uuid).collect(),
+                    )),
+                    LibraryCommand::QueryUuids { .. } => LibraryResponse::QueryUuids(
+                        songs
+                            .iter()
+                            .map(|song| (song.uuid, song.duration))
+                            .collect(),
+                    ),
+                    // Every song is by every artist
+                    LibraryCommand::ArtistSongs(_) => LibraryResponse::Songs(songs.clone()),
+                    LibraryCommand::SongsBulk(uuids) => LibraryResponse::SongsBulk(
+                        uuids
+                            .iter()
+                            .filter_map(|uuid| songs.iter().find(|song| song.uuid == *uuid))
+                            .map(|song| Arc::new(song.clone()))
+                            .collect(),
+                    ),
+                    command => panic!("Unexpected {command:?}"),
+                };
+                res_rx.send_blocking(res).unwrap();
+            }
+            trips
+        });
+        (lib_mail, handle)
+    }
+
+    #[test]
+    fn up_next_looked_up_together() {
+        for count in [10, 60, 2000] {
+            let songs = playable_songs(count);
+            let current = songs[3].uuid;
+            let expected: Vec<Uuid> = songs[4..]
+                .iter()
+                .take(UP_NEXT_LEN)
+                .map(|song| song.uuid)
+                .collect();
+
+            let (lib_mail, library) = counting_library(songs);
+            let up_next = block_on(location_up_next(
+                &lib_mail,
+                PlayerLocation::Library,
+                current,
+                false,
+                false,
+            ))
+            .0;
+            drop(lib_mail);
+
+            let up_next: Vec<Uuid> = up_next.iter().map(|song| song.uuid).collect();
+            assert_eq!(up_next, expected);
+            // The uuids and then the songs, however big the library is
+            assert_eq!(library.join().unwrap(), 2, "{count} songs");
+        }
+    }
+
+    #[test]
+    fn shuffle_from_here_in_playlist() {
+        let songs = playable_songs(120);
+        let current = songs[40].uuid;
+        let in_order: Vec<Uuid> = songs[41..].iter().map(|song| song.uuid).collect();
+        let playlist = PlayerLocation::Playlist(Uuid::new_v4());
+
+        let (lib_mail, _) = counting_library(songs.clone());
+        let (up_next, rest) = block_on(location_up_next(&lib_mail, playlist, current, true, false));
+        // Only the songs after it, each once
+        assert_eq!(rest.len(), 79);
+        assert_eq!(rest.iter().collect::<HashSet<_>>().len(), 79);
+        assert!(rest.iter().all(|uuid| in_order.contains(uuid)));
+        assert_ne!(rest, in_order);
+        let up_next_uuids: Vec<Uuid> = up_next.iter().map(|song| song.uuid).collect();
+        assert_eq!(up_next_uuids, rest[..UP_NEXT_LEN]);
+
+        // The rest are queued up in the same shuffled order
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                Queue::new(false, None),
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        block_on(set_pending(&queue_mail, &lib_mail, &rest, &up_next, false)).unwrap();
+        for uuid in &rest[UP_NEXT_LEN..] {
+            let next = block_on(next_pending(&queue_mail, &lib_mail, false)).unwrap();
+            assert_eq!(next.uuid, *uuid);
+        }
+        assert!(block_on(next_pending(&queue_mail, &lib_mail, false)).is_none());
+
+        // Without shuffling they're in order
+        let (up_next, rest) =
+            block_on(location_up_next(&lib_mail, playlist, current, false, false));
+        assert_eq!(up_next[0].uuid, songs[41].uuid);
+        assert_eq!(rest, in_order);
+    }
+
+    #[test]
+    fn queue_from_filter_keeps_the_rest_pending() {
+        for mode in [QueueMode::Append, QueueMode::PlayNow] {
+            let mut songs = playable_songs(500);
+            for song in &mut songs {
+                song.duration = Duration::from_secs(60);
+            }
+            // Left out of the first songs queued up
+            songs[3].location = vec![URI::Local(PathBuf::from("missing.flac"))];
+
+            let mut queue = Queue::new(false, None);
+            queue.add_item(
+                QueueSong {
+                    song: Song::default(),
+                    location: PlayerLocation::Library,
+                    transition: None,
+                },
+                true,
+            );
+            let (queue_mail, queue_rx) = async_channel::unbounded();
+            std::thread::spawn(move || {
+                block_on(Controller::queue_loop(
+                    queue,
+                    queue_rx,
+                    Arc::new(RwLock::new(Config::default())),
+                    None,
+                    None,
+                ))
+            });
+            let queue_command = |command| {
+                let (command, tx) = QueueCommandInput::command(command);
+                queue_mail.send_blocking(command).unwrap();
+                tx.recv_blocking().unwrap()
+            };
+
+            let (lib_mail, library) = counting_library(songs.clone());
+            let (matched, play) = block_on(queue_from_filter(
+                &queue_mail,
+                &lib_mail,
+                "",
+                vec![Tag::Title],
+                vec![],
+                mode,
+                false,
+            ))
+            .unwrap();
+            assert_eq!(matched, 500);
+            assert_eq!(play.map(|song| song.uuid), {
+                (mode == QueueMode::PlayNow).then_some(songs[0].uuid)
+            });
+
+            let QueueResponse::GetAll(items) = queue_command(QueueCommand::Get) else {
+                unreachable!()
+            };
+            let queued: Vec<Uuid> = items
+                .iter()
+                .map(|item| {
+                    let QueueItemType::Single(song) = &item.item else {
+                        unreachable!()
+                    };
+                    song.song.uuid
+                })
+                .skip((mode == QueueMode::Append) as usize)
+                .collect();
+            let expected: Vec<Uuid> = songs
+                .iter()
+                .take(UP_NEXT_LEN + 1)
+                .map(|song| song.uuid)
+                .filter(|uuid| *uuid != songs[3].uuid)
+                .collect();
+            assert_eq!(queued, expected);
+
+            // The rest wait as uuids, taking up the rest of the time
+            let QueueResponse::Info(info) = queue_command(QueueCommand::Info) else {
+                unreachable!()
+            };
+            assert_eq!(
+                info.pool,
+                Duration::from_secs(60 * (500 - UP_NEXT_LEN as u64 - 1))
+            );
+            let next = block_on(next_pending(&queue_mail, &lib_mail, false)).unwrap();
+            assert_eq!(next.uuid, songs[UP_NEXT_LEN + 1].uuid);
+
+            // Starting over drops them
+            queue_command(QueueCommand::Clear);
+            assert!(block_on(next_pending(&queue_mail, &lib_mail, false)).is_none());
+            drop(lib_mail);
+            // The uuids, two batches for the first songs as one is missing,
+            // and the song taken afterwards
+            assert_eq!(library.join().unwrap(), 4);
+        }
+    }
+
+    #[test]
+    fn artist_songs_past_up_next_wait() {
+        let mut songs = playable_songs(120);
+        for song in &mut songs {
+            song.duration = Duration::from_secs(60);
+        }
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                Queue::new(false, None),
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let queue_command = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+
+        block_on(replace_queue_pending(
+            &queue_mail,
+            songs.clone(),
+            PlayerLocation::Custom,
+        ))
+        .unwrap();
+        let QueueResponse::GetAll(items) = queue_command(QueueCommand::Get) else {
+            unreachable!()
+        };
+        assert_eq!(items.len(), UP_NEXT_LEN);
+        let QueueResponse::Info(info) = queue_command(QueueCommand::Info) else {
+            unreachable!()
+        };
+        assert_eq!(
+            info.pool,
+            Duration::from_secs(60 * (120 - UP_NEXT_LEN as u64))
+        );
+
+        // Every song is played in the end
+        let mut pending = Vec::new();
+        while let QueueResponse::Pending(Some(uuid)) = queue_command(QueueCommand::TakePending) {
+            pending.push(uuid);
+        }
+        let rest: Vec<Uuid> = songs[UP_NEXT_LEN..].iter().map(|song| song.uuid).collect();
+        assert_eq!(pending, rest);
+
+        // Fewer songs are all queued
+        block_on(replace_queue_pending(
+            &queue_mail,
+            songs[..3].to_vec(),
+            PlayerLocation::Custom,
+        ))
+        .unwrap();
+        let QueueResponse::GetAll(items) = queue_command(QueueCommand::Get) else {
+            unreachable!()
+        };
+        assert_eq!(items.len(), 3);
+        assert!(matches!(
+            queue_command(QueueCommand::TakePending),
+            QueueResponse::Pending(None)
+        ));
+    }
+
+    #[test]
+    fn artist_songs_shuffled_when_shuffle_is_on() {
+        let songs = playable_songs(120);
+        let in_order: Vec<Uuid> = songs.iter().map(|song| song.uuid).collect();
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                Queue::new(false, None),
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let queue_command = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let (lib_mail, _) = counting_library(songs);
+        let config = Arc::new(RwLock::new(Config::default()));
+        let play_artist = |shuffle| {
+            let first = block_on(queue_songs_of(
+                &queue_mail,
+                &lib_mail,
+                &config,
+                LibraryCommand::ArtistSongs(String::from("Kalafina")),
+                "Kalafina",
+                shuffle,
+            ))
+            .unwrap();
+            let QueueResponse::GetAll(items) = queue_command(QueueCommand::Get) else {
+                unreachable!()
+            };
+            let mut queued: Vec<Uuid> = items
+                .iter()
+                .map(|item| {
+                    let QueueItemType::Single(song) = &item.item else {
+                        unreachable!()
+                    };
+                    song.song.uuid
+                })
+                .collect();
+            assert_eq!(queued[0], first.uuid);
+            while let QueueResponse::Pending(Some(uuid)) = queue_command(QueueCommand::TakePending)
+            {
+                queued.push(uuid);
+            }
+            queued
+        };
+
+        // Every song once, in some other order
+        let shuffled = play_artist(true);
+        assert_ne!(shuffled, in_order);
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        let mut expected = in_order.clone();
+        expected.sort();
+        assert_eq!(sorted, expected);
+
+        assert_eq!(play_artist(false), in_order);
+    }
+
+    #[test]
+    fn queue_from_filter_adds_to_pending() {
+        let mut songs = playable_songs(300);
+        for song in &mut songs {
+            song.duration = Duration::from_secs(60);
+        }
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                Queue::new(false, None),
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let queue_command = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let (lib_mail, _) = counting_library(songs.clone());
+        let filter = |mode| {
+            block_on(queue_from_filter(
+                &queue_mail,
+                &lib_mail,
+                "",
+                vec![Tag::Title],
+                vec![],
+                mode,
+                false,
+            ))
+            .unwrap()
+        };
+        let rest: Vec<Uuid> = songs[UP_NEXT_LEN..].iter().map(|song| song.uuid).collect();
+
+        // The second filter's songs wait after the first's
+        filter(QueueMode::Append);
+        filter(QueueMode::Append);
+        let QueueResponse::Info(info) = queue_command(QueueCommand::Info) else {
+            unreachable!()
+        };
+        assert_eq!(
+            info.pool,
+            Duration::from_secs(2 * 60 * (300 - UP_NEXT_LEN as u64))
+        );
+        let mut pending = Vec::new();
+        while let QueueResponse::Pending(Some(uuid)) = queue_command(QueueCommand::TakePending) {
+            pending.push(uuid);
+        }
+        assert_eq!(pending, [rest.clone(), rest.clone()].concat());
+
+        // Played next, they wait in front of the ones already waiting
+        queue_command(QueueCommand::SetPending {
+            uuids: vec![songs[0].uuid],
+            pool: Duration::from_secs(60),
+            mode: QueueMode::Append,
+        });
+        filter(QueueMode::PlayNext);
+        let QueueResponse::Pending(Some(next)) = queue_command(QueueCommand::TakePending) else {
+            unreachable!()
+        };
+        assert_eq!(next, rest[0]);
+
+        // Playing now starts over
+        filter(QueueMode::PlayNow);
+        let mut pending = Vec::new();
+        while let QueueResponse::Pending(Some(uuid)) = queue_command(QueueCommand::TakePending) {
+            pending.push(uuid);
+        }
+        assert_eq!(pending, rest);
+    }
+
+    #[test]
+    fn unplayable_up_next_looked_up_in_batches() {
+        let mut songs = playable_songs(200);
+        for song in &mut songs[10..20] {
+            song.location = vec![URI::Local(PathBuf::from("missing.flac"))];
+        }
+        let current = songs[0].uuid;
+
+        let (lib_mail, library) = counting_library(songs.clone());
+        let up_next = block_on(location_up_next(
+            &lib_mail,
+            PlayerLocation::Library,
+            current,
+            false,
+            false,
+        ))
+        .0;
+        drop(lib_mail);
+
+        assert_eq!(up_next.len(), UP_NEXT_LEN);
+        assert_eq!(up_next[9].uuid, songs[20].uuid);
+        // One more batch makes up for the missing songs
+        assert_eq!(library.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn prev_restarts_past_threshold() {
+        let now = Instant::now();
+        let threshold = TimeDelta::seconds(3);
+        let mut seek = SeekPosition::default();
+        let prev = |seek: &SeekPosition, epoch| prev_restarts(seek.last_reported(epoch), threshold);
+
+        // Well into the song Previous restarts it
+        seek.position(4, Some(TimeDelta::seconds(42)), now);
+        assert!(prev(&seek, 4));
+        // Which reports the start right away, so pressing it again goes back
+        seek.seeked(4, TimeDelta::zero(), now);
+        assert!(!prev(&seek, 4));
+        seek.position(
+            4,
+            Some(TimeDelta::milliseconds(2900)),
+            now + SEEK_SETTLE_TIME,
+        );
+        assert!(!prev(&seek, 4));
+        seek.position(4, Some(TimeDelta::seconds(3)), now + SEEK_SETTLE_TIME);
+        assert!(prev(&seek, 4));
+
+        // Nothing reported for the song yet means it only just started
+        assert!(!prev(&seek, 5));
+        seek.position(5, None, now);
+        assert!(!prev(&seek, 4));
+        assert!(!prev(&seek, 5));
+
+        // A threshold of 0 always goes back
+        seek.position(5, Some(TimeDelta::seconds(42)), now);
+        assert!(!prev_restarts(seek.last_reported(5), TimeDelta::zero()));
+    }
+
+    #[test]
+    fn filtered_up_next_leaves_out_explicit() {
+        let mut songs = playable_songs(200);
+        for song in songs.iter_mut().skip(1).step_by(2) {
+            song.set_tag(Tag::Key("ITUNESADVISORY".to_string()), "1".to_string());
+        }
+        let current = songs[0].uuid;
+
+        let up_next = |filtered| {
+            let (lib_mail, _) = counting_library(songs.clone());
+            block_on(location_up_next(
+                &lib_mail,
+                PlayerLocation::Library,
+                current,
+                false,
+                filtered,
+            ))
+            .0
+        };
+        assert!(up_next(false).iter().any(|song| song.is_explicit()));
+        let clean = up_next(true);
+        assert_eq!(clean.len(), UP_NEXT_LEN);
+        assert!(clean.iter().all(|song| !song.is_explicit()));
+        assert_eq!(clean[0].uuid, songs[2].uuid);
+
+        // Nor are they added from the rest of an album or by the same artist
+        let queued = HashSet::new();
+        let rest = rest_of_album(songs.clone(), current, &queued, true);
+        assert_eq!(rest.len(), 99);
+        assert!(rest.iter().all(|song| !song.is_explicit()));
+        let more = more_by_artist(songs.clone(), &queued, 200, true);
+        assert_eq!(more.len(), 100);
+        assert!(more.iter().all(|song| !song.is_explicit()));
+    }
+
+    #[test]
+    fn skip_songs_deleted_from_the_queue() {
+        let dir = std::env::temp_dir().join(format!("dmp-skip-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let songs: Vec<QueueSong> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(format!("{name}.flac"));
+                fs::write(&path, []).unwrap();
+                QueueSong {
+                    song: Song {
+                        location: vec![URI::Local(path)],
+                        uuid: Uuid::new_v4(),
+                        ..Default::default()
+                    },
+                    location: PlayerLocation::Library,
+                    transition: None,
+                }
+            })
+            .collect();
+
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        let (lib_mail, lib_rx) = async_channel::unbounded::<LibraryCommandInput>();
+        let (notify_skipped, skipped) = crossbeam_channel::unbounded();
+        let mut queue = Queue::new(false, None);
+        for song in &songs {
+            queue.add_item(song.clone(), true);
+        }
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let marked = std::thread::spawn(move || {
+            let mut marked = Vec::new();
+            while let Ok(LibraryCommandInput { res_rx, command }) = lib_rx.recv_blocking() {
+                let LibraryCommand::MarkMissing(uuid) = command else {
+                    unreachable!()
+                };
+                marked.push(uuid);
+                res_rx.send_blocking(LibraryResponse::Ok).unwrap();
+            }
+            marked
+        });
+
+        // The files go missing while the songs wait in the queue
+        fs::remove_file(songs[1].song.location[0].path()).unwrap();
+        fs::remove_file(songs[2].song.location[0].path()).unwrap();
+
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Next);
+        queue_mail.send_blocking(command).unwrap();
+        let QueueResponse::Item(Ok(QueueItem {
+            item: QueueItemType::Single(next),
+            ..
+        })) = tx.recv_blocking().unwrap()
+        else {
+            unreachable!()
+        };
+
+        // A stand in for the player, which fails the same way for missing files
+        let mut loaded = Vec::new();
+        let load = |song: &Song| {
+            let path = song.location[0].path();
+            match path.exists() {
+                true => {
+                    loaded.push(song.uuid);
+                    Ok(())
+                }
+                false => Err(PlayerError::FileMissing { path }),
+            }
+        };
+        let playing = block_on(play_skipping_missing(
+            load,
+            &queue_mail,
+            &lib_mail,
+            &notify_skipped,
+            next,
+        ))
+        .unwrap();
+        assert_eq!(playing.song.uuid, songs[3].song.uuid);
+        assert_eq!(loaded, [songs[3].song.uuid]);
+
+        let skipped: Vec<Uuid> = skipped.try_iter().map(|s| s.uuid).collect();
+        assert_eq!(skipped, [songs[1].song.uuid, songs[2].song.uuid]);
+        drop(lib_mail);
+        assert_eq!(marked.join().unwrap(), skipped);
+
+        // Only the song which played is left in the queue
+        let (command, tx) = QueueCommandInput::command(QueueCommand::Get);
+        queue_mail.send_blocking(command).unwrap();
+        let QueueResponse::GetAll(items) = tx.recv_blocking().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].item, QueueItemType::Single(songs[3].clone()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn playable_songs(count: usize) -> Vec<Song> {
+        (0..count)
+            .map(|_| Song {
+                location: vec![URI::Local(PathBuf::from("Cargo.toml"))],
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rest_of_album_after_current() {
+        let mut tracks = playable_songs(6);
+        tracks[4].banned = Some(BannedType::All);
+        // Only shuffling skips these
+        tracks[5].banned = Some(BannedType::Shuffle);
+        let queued = HashSet::from([tracks[1].uuid, tracks[3].uuid]);
+
+        let rest = rest_of_album(tracks.clone(), tracks[1].uuid, &queued, false);
+        let uuids: Vec<Uuid> = rest.iter().map(|song| song.uuid).collect();
+        assert_eq!(uuids, [tracks[2].uuid, tracks[5].uuid]);
+
+        // The last track, or a song which isn't on the album, adds nothing
+        assert!(rest_of_album(tracks.clone(), tracks[5].uuid, &queued, false).is_empty());
+        assert!(rest_of_album(tracks, Uuid::new_v4(), &queued, false).is_empty());
+    }
+
+    #[test]
+    fn more_by_artist_takes_what_is_left() {
+        let mut songs = playable_songs(6);
+        songs[0].banned = Some(BannedType::Shuffle);
+        let queued = HashSet::from([songs[1].uuid]);
+
+        let more = more_by_artist(songs.clone(), &queued, 3, false);
+        assert_eq!(more.len(), 3);
+        assert!(more
+            .iter()
+            .all(|song| song.uuid != songs[0].uuid && song.uuid != songs[1].uuid));
+
+        // Fewer songs than asked for are left
+        let mut more: Vec<Uuid> = more_by_artist(songs.clone(), &queued, 10, false)
+            .iter()
+            .map(|song| song.uuid)
+            .collect();
+        more.sort();
+        let mut left: Vec<Uuid> = songs[2..].iter().map(|song| song.uuid).collect();
+        left.sort();
+        assert_eq!(more, left);
+    }
+
+    #[test]
+    fn song_change_position() {
+        let song = |_| QueueSong {
+            song: Song {
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            },
+            location: PlayerLocation::Library,
+            transition: None,
+        };
+        let mut queue = Queue::new(false, None);
+        for item in (0..3).map(song) {
+            queue.add_item(item, true);
+        }
+        for item in (0..4).map(song) {
+            queue.add_item(item, false);
+        }
+        queue.next().unwrap();
+        queue.next().unwrap();
+        let playing = queue.current().unwrap().item.clone();
+        let QueueItemType::Single(playing) = playing else {
+            unreachable!()
+        };
+
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        let (lib_mail, lib_rx) = async_channel::unbounded::<LibraryCommandInput>();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        // The playing song is the 11th of 100 songs in the library
+        std::thread::spawn(move || {
+            while let Ok(LibraryCommandInput { res_rx, command }) = lib_rx.recv_blocking() {
+                let LibraryCommand::SourcePosition(_, PlayerLocation::Library) = command else {
+                    unreachable!()
+                };
+                res_rx
+                    .send_blocking(LibraryResponse::SourcePosition(Some((10, 100))))
+                    .unwrap();
+            }
+        });
+
+        let (connections, notifications) = crossbeam_channel::unbounded();
+        let (next_song, changes) = crossbeam_channel::unbounded();
+        let notifier = SongChangeNotifier {
+            connections,
+            next_song,
+            player_state: Arc::new(std::sync::RwLock::new(PrismState::Buffering(40))),
+            position: Arc::new(Mutex::new(None)),
+            track_epoch: Arc::new(AtomicU64::new(0)),
+            listen_timer: Arc::new(Mutex::new(ListenTimer::default())),
+            current: Arc::default(),
+            config: Arc::default(),
+            player_mail: async_channel::unbounded().0,
+            now_playing: NowPlayingTracker::default(),
+        };
+        let announce = |location, reason| {
+            block_on(notifier.announce(
+                &queue_mail,
+                &lib_mail,
+                playing.song.clone(),
+                location,
+                reason,
+            ));
+            let Ok(PlayerNotification::SongChange(change, PlaybackState::Playing)) =
+                changes.try_recv()
+            else {
+                panic!("Expected a playing song change")
+            };
+            change
+        };
+
+        // Two played, five queued and the 85 library songs after the four
+        // queued up automatically
+        let change = announce(PlayerLocation::Library, TrackChangeReason::Natural);
+        let position = change.position;
+        assert_eq!(
+            position,
+            QueuePosition {
+                index: 2,
+                total: 92,
+                location: PlayerLocation::Library,
+            }
+        );
+        assert_eq!(change.previous, None);
+        assert_eq!(*notifier.position.lock(), Some(position));
+        assert!(matches!(
+            notifications.try_recv(),
+            Ok(ConnectionsNotification::SongChange(change)) if change.position == position
+        ));
+
+        // Everything else is queued in full
+        let change = announce(PlayerLocation::Album, TrackChangeReason::UserJump);
+        assert_eq!((change.position.index, change.position.total), (2, 7));
+        assert_eq!(change.reason, TrackChangeReason::UserJump);
+        assert!(change.reason.by_user());
+        assert_eq!(change.previous.as_deref(), Some(&playing.song));
+
+        // Nothing played before a song after stopping
+        block_on(notifier.stopped(&lib_mail));
+        let change = announce(PlayerLocation::Album, TrackChangeReason::PlayNow);
+        assert_eq!(change.previous, None);
+    }
+
+    #[test]
+    fn change_reasons() {
+        let uuid = Uuid::new_v4();
+        let key = AlbumKey {
+            title: String::from("Fiction"),
+            artist: None,
+        };
+        let table = [
+            (PlayerCommand::TrackFinished, TrackChangeReason::Natural),
+            (
+                PlayerCommand::CueTrackChange { from: 1, to: 2 },
+                TrackChangeReason::Natural,
+            ),
+            (PlayerCommand::NextSong, TrackChangeReason::UserNext),
+            (PlayerCommand::PrevSong, TrackChangeReason::UserPrev),
+            (PlayerCommand::PlayPlayed(2), TrackChangeReason::UserJump),
+            (
+                PlayerCommand::Enqueue {
+                    index: 3,
+                    expected: None,
+                },
+                TrackChangeReason::UserJump,
+            ),
+            (
+                PlayerCommand::PlayNow {
+                    uuid,
+                    location: PlayerLocation::Library,
+                    shuffle_remaining: false,
+                },
+                TrackChangeReason::PlayNow,
+            ),
+            (
+                PlayerCommand::PlayAlbum {
+                    key: key.clone(),
+                    starting_track: None,
+                },
+                TrackChangeReason::PlayNow,
+            ),
+            (PlayerCommand::PlayCueAlbum(key), TrackChangeReason::PlayNow),
+            (
+                PlayerCommand::PlayArtist(String::from("Kalafina")),
+                TrackChangeReason::PlayNow,
+            ),
+        ];
+        for (command, reason) in table {
+            assert_eq!(change_reason(&command), reason, "{command:?}");
+        }
+        assert!(!TrackChangeReason::Natural.by_user());
+        assert!(TrackChangeReason::UserPrev.by_user());
+
+        // Songs which had to be skipped over make any change a recovery
+        let other = Uuid::new_v4();
+        for reason in [TrackChangeReason::UserNext, TrackChangeReason::Natural] {
+            assert_eq!(after_skipping(reason, uuid, uuid), reason);
+            assert_eq!(
+                after_skipping(reason, uuid, other),
+                TrackChangeReason::Recovered
+            );
+        }
+    }
+
+    #[test]
+    fn load_failure_state() {
+        let (connections, notifications) = crossbeam_channel::unbounded();
+        let (next_song, changes) = crossbeam_channel::unbounded();
+        let player_state = Arc::new(std::sync::RwLock::new(PrismState::Stopped));
+        let notifier = SongChangeNotifier {
+            connections,
+            next_song,
+            player_state: Arc::clone(&player_state),
+            position: Arc::new(Mutex::new(None)),
+            track_epoch: Arc::new(AtomicU64::new(0)),
+            listen_timer: Arc::new(Mutex::new(ListenTimer::default())),
+            current: Arc::default(),
+            config: Arc::default(),
+            player_mail: async_channel::unbounded().0,
+            now_playing: NowPlayingTracker::default(),
+        };
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from("/music/Hacking to the Gate.flac"))],
+            ..Default::default()
+        };
+        let error = PlayerError::UnsupportedFormat {
+            path: PathBuf::from("/music/Hacking to the Gate.flac"),
+            details: String::new(),
+        };
+
+        // The player is left stopped, which is what the frontend is told
+        notifier.load_failed(&song, &error);
+        let Ok(PlayerNotification::LoadFailed(failure)) = changes.try_recv() else {
+            panic!("Expected a load failure")
+        };
+        assert_eq!(failure.uuid, song.uuid);
+        assert_eq!(failure.title, "Hacking to the Gate.flac");
+        assert_eq!(failure.message, error.to_string());
+        assert_eq!(failure.state, PlaybackState::Stopped);
+        // Only the frontend hears about it, as nothing changed
+        assert!(notifications.try_recv().is_err());
+
+        // Or still playing the last song, if the player kept it going
+        *player_state.write().unwrap() = PrismState::Paused;
+        song.set_tag(Tag::Title, String::from("Hacking to the Gate"));
+        notifier.load_failed(&song, &error);
+        let Ok(PlayerNotification::LoadFailed(failure)) = changes.try_recv() else {
+            panic!("Expected a load failure")
+        };
+        assert_eq!(failure.title, "Hacking to the Gate");
+        assert_eq!(failure.state, PlaybackState::Paused);
+    }
+
+    #[test]
+    fn album_next_stays_together_when_shuffled() {
+        let song = |album: Option<&str>| {
+            let mut song = playable_songs(1).remove(0);
+            if let Some(album) = album {
+                song.set_tag(Tag::Album, album.to_string());
+            }
+            song
+        };
+        let album: Vec<Song> = (0..4).map(|_| song(Some("Fate/Zero"))).collect();
+        let album_uuids: Vec<Uuid> = album.iter().map(|song| song.uuid).collect();
+
+        let mut queue = Queue::new(false, None);
+        for (i, song) in (0..8).map(|_| song(None)).enumerate() {
+            queue.add_item(
+                QueueSong {
+                    song,
+                    location: PlayerLocation::Library,
+                    transition: None,
+                },
+                i == 0,
+            );
+        }
+        let mut config = Config::default();
+        config.playback.shuffle_keeps_manual = false;
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(config)),
+                None,
+                None,
+            ))
+        });
+        let (lib_mail, lib_rx) = async_channel::unbounded::<LibraryCommandInput>();
+        std::thread::spawn(move || {
+            while let Ok(LibraryCommandInput { res_rx, command }) = lib_rx.recv_blocking() {
+                let LibraryCommand::AlbumTracks(key, None) = command else {
+                    unreachable!()
+                };
+                assert_eq!(key.title, "Fate/Zero");
+                res_rx
+                    .send_blocking(LibraryResponse::AlbumTracks(album.clone()))
+                    .unwrap();
+            }
+        });
+
+        let queued = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let uuids = || {
+            let QueueResponse::GetAll(items) = queued(QueueCommand::Get) else {
+                unreachable!()
+            };
+            items
+                .into_iter()
+                .map(|item| match item.item {
+                    QueueItemType::Single(song) => song.song.uuid,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<Uuid>>()
+        };
+        let contiguous = |uuids: &[Uuid]| {
+            uuids
+                .windows(album_uuids.len())
+                .any(|window| window == album_uuids)
+        };
+
+        let key = AlbumKey {
+            title: String::from("Fate/Zero"),
+            artist: None,
+        };
+        let added = block_on(super::enqueue_album_next(
+            &queue_mail,
+            &lib_mail,
+            key,
+            false,
+        ));
+        assert_eq!(added, Ok(4));
+        assert_eq!(uuids()[1..5], album_uuids);
+
+        // Shuffling and picking new songs to come up, like shuffle mode
+        // does, never splits up the album
+        for _ in 0..20 {
+            queued(QueueCommand::ShuffleRemaining);
+            assert!(contiguous(&uuids()));
+        }
+        let fill = (0..6)
+            .map(|_| {
+                QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                    song: song(None),
+                    location: PlayerLocation::Library,
+                    transition: None,
+                }))
+            })
+            .collect();
+        queued(QueueCommand::ReplaceUpNext(fill));
+        let after = uuids();
+        assert!(contiguous(&after));
+        assert_eq!(after.len(), 11);
+
+        // Playing into the album keeps the rest of it next
+        while uuids()[0] != album_uuids[0] {
+            queued(QueueCommand::Next);
+        }
+        queued(QueueCommand::ShuffleRemaining);
+        assert_eq!(uuids()[..4], album_uuids);
     }
 }
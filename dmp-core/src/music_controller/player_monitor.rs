@@ -1,21 +1,241 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use chrono::TimeDelta;
 use crossbeam::atomic::AtomicCell;
 use crossbeam_channel::{Receiver, Sender};
+use kushi::QueueItemType;
+use parking_lot::Mutex;
 use prismriver::State as PrismState;
 
 use crate::{
-    music_controller::controller::{PlayerCommand, PlayerResponse},
-    music_storage::library::Song,
+    music_controller::controller::{PlayerCommand, PlayerResponse, QueueCommand, QueueResponse},
+    music_storage::gain_staging::AppliedPlayback,
 };
 
 use super::{
     connections::ConnectionsNotification,
-    controller::{Controller, PlaybackInfo},
-    controller_handle::PlayerCommandInput,
+    controller::{Controller, PlaybackInfo, PlaybackState, PlayerNotification, QueuePosition},
+    controller_handle::{PlayerCommandInput, QueueCommandInput},
+    cue_playback::CueSession,
+    listen_time::ListenTimer,
+    now_playing::NowPlayingTracker,
+    output_mode::NegotiatedOutput,
 };
 
+/// How far the decoder's duration can be from the library's before it's
+/// worth noting, since VBR files without a header are often a little off
+const DURATION_MISMATCH: TimeDelta = TimeDelta::seconds(2);
+
+/// How long positions from the player are ignored after a seek. They can
+/// still be from before it, which would make the seekbar jump back.
+pub const SEEK_SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// How long before the end of a trimmed track it's about to finish. The
+/// player only knows where the file ends, so for trimmed tracks this is
+/// worked out from the position instead.
+const TRIM_FINISH_LEAD: TimeDelta = TimeDelta::seconds(2);
+
+/// A position picked on the seekbar, which is reported instead of the
+/// player's while it's being dragged and for a moment after seeking. Also
+/// keeps the last position reported, for the player to check.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct SeekPosition {
+    /// The track epoch the position was picked in
+    epoch: u64,
+    preview: Option<TimeDelta>,
+    seeked: Option<(TimeDelta, Instant)>,
+    reported: Option<(u64, TimeDelta)>,
+}
+
+impl SeekPosition {
+    /// Shows `time` as the position while the seekbar is dragged, without
+    /// seeking
+    pub(super) fn preview(&mut self, epoch: u64, time: TimeDelta) {
+        self.epoch = epoch;
+        self.preview = Some(time);
+    }
+
+    /// Notes that the player seeked to `time` at `now`, ending the preview
+    pub(super) fn seeked(&mut self, epoch: u64, time: TimeDelta, now: Instant) {
+        self.epoch = epoch;
+        self.preview = None;
+        self.seeked = Some((time, now));
+        self.reported = Some((epoch, time));
+    }
+
+    /// The position last reported for track `epoch`, if any was
+    pub(super) fn last_reported(&self, epoch: u64) -> Option<TimeDelta> {
+        self.reported
+            .filter(|(reported, _)| *reported == epoch)
+            .map(|(_, position)| position)
+    }
+
+    /// Goes back to the player's position, like after a seek failed
+    pub(super) fn clear(&mut self) {
+        self.preview = None;
+        self.seeked = None;
+    }
+
+    /// The position to report for track `epoch`, given the one reported by
+    /// the player at `now`
+    pub(super) fn position(
+        &mut self,
+        epoch: u64,
+        reported: Option<TimeDelta>,
+        now: Instant,
+    ) -> Option<TimeDelta> {
+        let position = self.shown(epoch, reported, now);
+        self.reported = position.map(|position| (epoch, position));
+        position
+    }
+
+    fn shown(
+        &mut self,
+        epoch: u64,
+        reported: Option<TimeDelta>,
+        now: Instant,
+    ) -> Option<TimeDelta> {
+        if epoch != self.epoch {
+            return reported;
+        }
+        if let Some(preview) = self.preview {
+            return Some(preview);
+        }
+        match self.seeked {
+            Some((time, at)) if now.duration_since(at) < SEEK_SETTLE_TIME => Some(time),
+            _ => {
+                self.seeked = None;
+                reported
+            }
+        }
+    }
+}
+
+/// The duration of the loaded track. The duration stored in the library is
+/// known as soon as a track is loaded, but the decoder's is used once it has
+/// probed the file since it's more accurate.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct TrackDuration {
+    stored: Option<TimeDelta>,
+    decoded: Option<TimeDelta>,
+    trim: Option<TrackTrim>,
+}
+
+/// The part of a trimmed song's file which is played. Like a track in a cue
+/// album, positions are reported relative to its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TrackTrim {
+    start: TimeDelta,
+    end: TimeDelta,
+    /// Whether the player has reported a position inside the trim yet.
+    /// Until then positions can still be from the last track.
+    reached: bool,
+    ended: bool,
+    /// Whether it was said to be about to finish since it last played
+    /// before [`TRIM_FINISH_LEAD`]
+    finishing: bool,
+}
+
+impl TrackDuration {
+    /// Starts a new track, with the duration stored for it in the library
+    pub(super) fn load(&mut self, stored: Duration) {
+        self.stored = TimeDelta::from_std(stored)
+            .ok()
+            .filter(|stored| *stored > TimeDelta::zero());
+        self.decoded = None;
+        self.trim = None;
+    }
+
+    /// Only plays the part of the track between `start` and `end`, returning
+    /// where the player has to seek to once it's loaded
+    pub(super) fn trim(&mut self, trim: Option<(Duration, Duration)>) -> Option<TimeDelta> {
+        self.trim = trim.and_then(|(start, end)| {
+            Some(TrackTrim {
+                start: TimeDelta::from_std(start).ok()?,
+                end: TimeDelta::from_std(end).ok()?,
+                reached: false,
+                ended: false,
+                finishing: false,
+            })
+        });
+        self.trim.map(|trim| trim.start)
+    }
+
+    /// How long the track plays for, if it's trimmed
+    pub(super) fn trimmed(&self) -> Option<TimeDelta> {
+        self.trim.map(|trim| trim.end - trim.start)
+    }
+
+    /// The position in the file of `time` into the track
+    pub(super) fn absolute(&self, time: TimeDelta) -> TimeDelta {
+        match self.trim {
+            Some(trim) => (trim.start + time).min(trim.end),
+            None => time,
+        }
+    }
+
+    /// Converts a position reported by the player to one in the track, and
+    /// whether the track has just passed the end of its trim, so the next
+    /// one should be played. That's only true once for each time the trim
+    /// is played through.
+    pub(super) fn relative(&mut self, position: Option<TimeDelta>) -> (Option<TimeDelta>, bool) {
+        let (Some(trim), Some(position)) = (&mut self.trim, position) else {
+            return (position, false);
+        };
+        if position >= trim.start && position < trim.end {
+            trim.reached = true;
+            trim.ended = false;
+        }
+        let ended = trim.reached && !trim.ended && position >= trim.end;
+        if ended {
+            trim.ended = true;
+        }
+        let relative = (position - trim.start).clamp(TimeDelta::zero(), trim.end - trim.start);
+        (Some(relative), ended)
+    }
+
+    /// Whether the track is about to finish at `position`, given by the
+    /// player. Only trimmed tracks are checked, once each time they near
+    /// their end.
+    pub(super) fn about_to_finish(&mut self, position: Option<TimeDelta>) -> bool {
+        let (Some(trim), Some(position)) = (&mut self.trim, position) else {
+            return false;
+        };
+        let near = position >= trim.end - TRIM_FINISH_LEAD && position < trim.end;
+        if !near {
+            trim.finishing = false;
+            return false;
+        }
+        let finishing = trim.reached && !trim.finishing;
+        trim.finishing |= finishing;
+        finishing
+    }
+
+    /// Takes the duration reported by the decoder, which is nothing or 0
+    /// before it has probed the file, returning the best duration known
+    pub(super) fn update(&mut self, decoded: Option<TimeDelta>) -> Option<TimeDelta> {
+        if let Some(decoded) = decoded.filter(|decoded| *decoded > TimeDelta::zero()) {
+            if let (None, Some(stored)) = (self.decoded, self.stored) {
+                if (decoded - stored).abs() > DURATION_MISMATCH {
+                    println!(
+                        "Decoded duration {}ms differs from the library's {}ms",
+                        decoded.num_milliseconds(),
+                        stored.num_milliseconds()
+                    );
+                }
+            }
+            self.decoded = Some(decoded);
+        }
+        self.trimmed().or(self.decoded).or(self.stored)
+    }
+}
+
 impl Controller {
     pub(super) fn player_monitor_loop(
         playback_state: Arc<std::sync::RwLock<PrismState>>,
@@ -23,25 +243,116 @@ impl Controller {
         about_to_finish_tx: Receiver<()>,
         finished_tx: Receiver<()>,
         player_mail: async_channel::Sender<PlayerCommandInput>,
-        notify_next_song: Sender<Song>,
+        queue_mail: async_channel::Sender<QueueCommandInput>,
         notify_connections_: Sender<ConnectionsNotification>,
+        notify_state: Sender<PlayerNotification>,
         playback_info: Arc<AtomicCell<PlaybackInfo>>,
+        track_epoch: Arc<AtomicU64>,
+        track_duration: Arc<Mutex<TrackDuration>>,
+        applied: Arc<Mutex<AppliedPlayback>>,
+        queue_position: Arc<Mutex<Option<QueuePosition>>>,
+        cue_session: Arc<Mutex<Option<CueSession>>>,
+        seek_position: Arc<Mutex<SeekPosition>>,
+        output: Arc<Mutex<NegotiatedOutput>>,
+        listen_timer: Arc<Mutex<ListenTimer>>,
+        now_playing: NowPlayingTracker,
     ) -> Result<(), ()> {
         std::thread::scope(|s| {
+            let next_applied = applied.clone();
+            let finish_duration = Arc::clone(&track_duration);
+            let (trim_finishing, trim_finishing_rx) = crossbeam_channel::unbounded();
+            let timing_state = Arc::clone(&playback_state);
+            let state_now_playing = now_playing.clone();
+
             // Thread for timing and metadata
             let notify_connections = notify_connections_.clone();
+            let cue_mail = player_mail.clone();
             s.spawn({
                 move || {
                     println!("playback monitor started");
                     while true {
                         let (position, duration) = playback_time_tx.recv().unwrap();
-                        notify_connections
-                            .send(ConnectionsNotification::Playback {
-                                position: position.clone(),
-                                duration: duration.clone(),
-                            })
-                            .unwrap();
-                        playback_info.store(PlaybackInfo { position, duration });
+                        let (position, duration, trim_ended, finishing) = {
+                            let mut track_duration = track_duration.lock();
+                            let duration = track_duration.update(duration);
+                            let (relative, ended) = track_duration.relative(position);
+                            let finishing = track_duration.about_to_finish(position);
+                            (relative, duration, ended, finishing)
+                        };
+                        if finishing {
+                            _ = trim_finishing.send(());
+                        }
+
+                        // A trimmed song ends before its file does
+                        if trim_ended {
+                            let (command, tx) =
+                                PlayerCommandInput::command(PlayerCommand::TrackFinished);
+                            cue_mail.send_blocking(command).unwrap();
+                            _ = tx.recv_blocking();
+                            notify_connections
+                                .send(ConnectionsNotification::EOS)
+                                .unwrap();
+                            continue;
+                        }
+
+                        // In a cue album the player only hears about track
+                        // changes from here, as the file keeps playing
+                        let epoch = track_epoch.load(Ordering::SeqCst);
+                        let crossed = match &mut *cue_session.lock() {
+                            Some(session) if session.is_active(epoch) => {
+                                position.and_then(|position| session.update(position))
+                            }
+                            _ => None,
+                        };
+                        if let Some((from, to)) = crossed {
+                            let (command, tx) =
+                                PlayerCommandInput::command(PlayerCommand::CueTrackChange {
+                                    from,
+                                    to,
+                                });
+                            cue_mail.send_blocking(command).unwrap();
+                            _ = tx.recv_blocking();
+                        }
+                        let epoch = track_epoch.load(Ordering::SeqCst);
+                        let (position, duration) = match &*cue_session.lock() {
+                            Some(session) if session.is_active(epoch) => (
+                                position.map(|position| session.relative(position)),
+                                Some(session.track_duration()),
+                            ),
+                            _ => (position, duration),
+                        };
+                        let position =
+                            seek_position
+                                .lock()
+                                .position(epoch, position, Instant::now());
+                        let playing = matches!(*timing_state.read().unwrap(), PrismState::Playing);
+                        let listened = {
+                            let mut listen_timer = listen_timer.lock();
+                            listen_timer.position(epoch, position, playing);
+                            listen_timer.listened()
+                        };
+
+                        // Nothing is sent until the duration is known, so
+                        // listens aren't measured against a duration of 0
+                        if duration.is_some() {
+                            notify_connections
+                                .send(ConnectionsNotification::Playback { position, duration })
+                                .unwrap();
+                        }
+                        let AppliedPlayback { profile, gain } = *applied.lock();
+                        let queue_position = *queue_position.lock();
+                        let info = PlaybackInfo {
+                            position,
+                            duration,
+                            epoch,
+                            profile,
+                            gain,
+                            queue_position,
+                            output: *output.lock(),
+                            listened,
+                        };
+                        now_playing.playback(&info);
+                        playback_info.store(info);
                     }
                 }
             });
@@ -51,9 +362,51 @@ impl Controller {
                 println!("AboutToFinish monitor started");
                 futures::executor::block_on(async {
                     while true {
-                        _ = about_to_finish_tx.recv();
-                        notify_connections.send(ConnectionsNotification::AboutToFinish).unwrap();
-                        println!("About to Finish");
+                        crossbeam_channel::select! {
+                            recv(about_to_finish_tx) -> _ => {
+                                // Trimmed tracks end before their file does,
+                                // which was already noticed from the position
+                                if finish_duration.lock().trimmed().is_some() {
+                                    continue;
+                                }
+                            }
+                            recv(trim_finishing_rx) -> _ => (),
+                        }
+                        notify_connections
+                            .send(ConnectionsNotification::AboutToFinish)
+                            .unwrap();
+
+                        // The next song's own transition wins over the
+                        // profile's crossfade
+                        let mut items = Vec::new();
+                        for index in [0, 1] {
+                            let (command, tx) =
+                                QueueCommandInput::command(QueueCommand::GetIndex(index));
+                            queue_mail.send(command).await.unwrap();
+                            let QueueResponse::Item(item) = tx.recv().await.unwrap() else {
+                                unreachable!()
+                            };
+                            items.push(item.map(|item| item.item));
+                        }
+                        let next = items.pop().unwrap();
+                        let current = items.pop().unwrap();
+                        match next {
+                            Ok(QueueItemType::Single(next)) => {
+                                let settings = next_applied
+                                    .lock()
+                                    .profile
+                                    .map(|profile| profile.settings)
+                                    .unwrap_or_default();
+                                let transition = match current {
+                                    Ok(QueueItemType::Single(current)) => {
+                                        next.transition_after(&current.song, &settings)
+                                    }
+                                    _ => next.transition_into(&settings),
+                                };
+                                println!("About to Finish, {transition:?} into the next song");
+                            }
+                            _ => println!("About to Finish"),
+                        }
                     }
                 })
             });
@@ -66,21 +419,17 @@ impl Controller {
                     while true {
                         _ = finished_tx.recv();
 
-                        let (command, tx) = PlayerCommandInput::command(PlayerCommand::NextSong);
+                        // The player sends out the song change itself
+                        let (command, tx) =
+                            PlayerCommandInput::command(PlayerCommand::TrackFinished);
                         player_mail.send(command).await.unwrap();
-                        let PlayerResponse::NowPlaying(res) = tx.recv().await.unwrap() else {
+                        let PlayerResponse::NowPlaying(_) = tx.recv().await.unwrap() else {
                             unreachable!()
                         };
-                        if let Ok(song) = res {
-                            notify_next_song.send(song.clone()).unwrap();
-                            notify_connections
-                                .send(ConnectionsNotification::SongChange(song))
-                                .unwrap();
-                        }
 
                         notify_connections
-                                .send(ConnectionsNotification::EOS)
-                                .unwrap();
+                            .send(ConnectionsNotification::EOS)
+                            .unwrap();
                         println!("End of song");
                     }
                 });
@@ -89,6 +438,7 @@ impl Controller {
             let notify_connections = notify_connections_.clone();
             s.spawn(move || {
                 let mut state = PrismState::Stopped;
+                let mut shown = PlaybackState::Stopped;
                 while true {
                     let _state = playback_state.read().unwrap().to_owned();
                     if _state != state {
@@ -98,6 +448,12 @@ impl Controller {
                             .send(ConnectionsNotification::StateChange(state.clone()))
                             .unwrap();
                     }
+                    // Buffering doesn't change what the play button shows
+                    if PlaybackState::from(&state) != shown {
+                        shown = PlaybackState::from(&state);
+                        state_now_playing.state_changed(shown);
+                        _ = notify_state.send(PlayerNotification::State(shown));
+                    }
                     std::thread::sleep(Duration::from_millis(100));
                 }
             });
@@ -106,3 +462,177 @@ impl Controller {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::TimeDelta;
+
+    use super::{SeekPosition, TrackDuration, SEEK_SETTLE_TIME};
+    use crate::{
+        config::ConfigPlayback,
+        music_storage::{
+            library::{Song, URI},
+            silence::{detect_silence, tests::padded_wav},
+        },
+    };
+
+    #[test]
+    fn stored_duration_until_decoded() {
+        let mut duration = TrackDuration::default();
+        duration.load(Duration::from_secs(200));
+
+        // The decoder hasn't probed the file yet
+        assert_eq!(duration.update(None), Some(TimeDelta::seconds(200)));
+        assert_eq!(
+            duration.update(Some(TimeDelta::zero())),
+            Some(TimeDelta::seconds(200))
+        );
+
+        // Once it has, its duration is preferred even if it differs
+        assert_eq!(
+            duration.update(Some(TimeDelta::seconds(203))),
+            Some(TimeDelta::seconds(203))
+        );
+        assert_eq!(duration.update(None), Some(TimeDelta::seconds(203)));
+
+        // Loading the next track forgets the decoded duration
+        duration.load(Duration::from_secs(100));
+        assert_eq!(duration.update(None), Some(TimeDelta::seconds(100)));
+    }
+
+    #[test]
+    fn unknown_duration() {
+        // Songs without a stored duration report nothing until decoded
+        let mut duration = TrackDuration::default();
+        duration.load(Duration::ZERO);
+        assert_eq!(duration.update(Some(TimeDelta::zero())), None);
+        assert_eq!(
+            duration.update(Some(TimeDelta::seconds(5))),
+            Some(TimeDelta::seconds(5))
+        );
+    }
+
+    #[test]
+    fn trimmed_track() {
+        let secs = TimeDelta::seconds;
+        let mut duration = TrackDuration::default();
+        duration.load(Duration::from_secs(300));
+        let start = duration.trim(Some((Duration::from_secs(20), Duration::from_secs(200))));
+        assert_eq!(start, Some(secs(20)));
+        assert_eq!(duration.update(Some(secs(300))), Some(secs(180)));
+
+        // Positions from the last track, before the seek to the start, don't
+        // end this one
+        assert_eq!(duration.relative(Some(secs(250))), (Some(secs(180)), false));
+        assert_eq!(duration.relative(Some(secs(20))), (Some(secs(0)), false));
+        assert_eq!(duration.relative(Some(secs(199))), (Some(secs(179)), false));
+
+        // The next track is played as soon as the end is passed, once
+        assert_eq!(duration.relative(Some(secs(200))), (Some(secs(180)), true));
+        assert_eq!(duration.relative(Some(secs(201))), (Some(secs(180)), false));
+
+        // Seeking back plays up to the end again
+        assert_eq!(duration.absolute(secs(100)), secs(120));
+        assert_eq!(duration.absolute(secs(500)), secs(200));
+        assert_eq!(duration.relative(Some(secs(120))), (Some(secs(100)), false));
+        assert_eq!(duration.relative(Some(secs(200))), (Some(secs(180)), true));
+
+        // Without a trim the whole file plays
+        duration.load(Duration::from_secs(300));
+        assert_eq!(duration.trim(None), None);
+        assert_eq!(duration.update(None), Some(secs(300)));
+        assert_eq!(duration.absolute(secs(250)), secs(250));
+        assert_eq!(duration.relative(Some(secs(300))), (Some(secs(300)), false));
+    }
+
+    #[test]
+    fn silence_finishes_early() {
+        let millis = TimeDelta::milliseconds;
+        let path = padded_wav(3.0, 10.0, 4.0);
+        let playback = ConfigPlayback {
+            skip_silence: true,
+            ..Default::default()
+        };
+        let silence = detect_silence(&path, playback.silence_threshold_db).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let song = Song {
+            location: vec![URI::Local(path)],
+            duration: Duration::from_secs(17),
+            silence: Some(silence),
+            ..Default::default()
+        };
+
+        let mut duration = TrackDuration::default();
+        duration.load(song.duration);
+        let start = duration.trim(song.playback_range(&playback)).unwrap();
+        assert!((start - millis(3000)).abs() < millis(5), "{start}");
+        let length = duration.update(Some(TimeDelta::seconds(17))).unwrap();
+        assert!((length - millis(10_000)).abs() < millis(5), "{length}");
+
+        // It's about to finish two seconds before the sound ends, long
+        // before the file does, and only once
+        for position in [5000, 10_000, 10_900] {
+            duration.relative(Some(millis(position)));
+            assert!(!duration.about_to_finish(Some(millis(position))));
+        }
+        assert!(duration.about_to_finish(Some(millis(11_100))));
+        assert!(!duration.about_to_finish(Some(millis(11_500))));
+        assert_eq!(
+            duration.relative(Some(millis(13_100))),
+            (Some(length), true)
+        );
+
+        // Seeking back finishes again
+        assert!(!duration.about_to_finish(Some(millis(6000))));
+        assert!(duration.about_to_finish(Some(millis(11_200))));
+
+        // Whole files are left to the player
+        duration.load(song.duration);
+        duration.trim(None);
+        assert!(!duration.about_to_finish(Some(millis(16_000))));
+    }
+
+    #[test]
+    fn seek_position_reported() {
+        let now = Instant::now();
+        let player = Some(TimeDelta::seconds(10));
+        let mut seek = SeekPosition::default();
+        assert_eq!(seek.position(0, player, now), player);
+
+        // Dragging shows the thumb's position however long it takes
+        seek.preview(0, TimeDelta::seconds(60));
+        seek.preview(0, TimeDelta::seconds(90));
+        let later = now + SEEK_SETTLE_TIME * 10;
+        assert_eq!(
+            seek.position(0, player, later),
+            Some(TimeDelta::seconds(90))
+        );
+
+        // After the seek the player's old position is ignored for a moment
+        seek.seeked(0, TimeDelta::seconds(95), now);
+        assert_eq!(
+            seek.position(0, player, now + SEEK_SETTLE_TIME / 2),
+            Some(TimeDelta::seconds(95))
+        );
+        let settled = Some(TimeDelta::seconds(96));
+        assert_eq!(seek.position(0, settled, now + SEEK_SETTLE_TIME), settled);
+        assert_eq!(seek.position(0, player, now + SEEK_SETTLE_TIME / 2), player);
+    }
+
+    #[test]
+    fn seek_position_for_other_tracks() {
+        let now = Instant::now();
+        let player = Some(TimeDelta::seconds(10));
+        let mut seek = SeekPosition::default();
+
+        // A preview from the last track doesn't carry over to the next one
+        seek.preview(0, TimeDelta::seconds(60));
+        assert_eq!(seek.position(1, player, now), player);
+
+        seek.seeked(1, TimeDelta::seconds(30), now);
+        seek.clear();
+        assert_eq!(seek.position(1, player, now), player);
+    }
+}
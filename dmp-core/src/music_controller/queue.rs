@@ -1,6 +1,15 @@
-use std::vec::IntoIter;
+use std::{collections::VecDeque, time::Duration, vec::IntoIter};
 
-use crate::music_storage::library::{Album, AlbumTrack, Song};
+use kushi::{Queue, QueueItem, QueueItemType};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::music_storage::{
+    gain_staging::ResolvedProfile,
+    gapless::flows_into,
+    library::{Album, AlbumTrack, Song},
+};
 
 use super::controller::PlayerLocation;
 
@@ -8,6 +17,49 @@ use super::controller::PlayerLocation;
 pub struct QueueSong {
     pub song: Song,
     pub location: PlayerLocation,
+    /// How the song before this one goes into it, overriding the profile's
+    /// crossfade at that one boundary
+    pub transition: Option<Transition>,
+}
+
+/// How one song goes into the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Transition {
+    /// The next song starts as soon as the last one stops
+    Gapless,
+    /// The last song stops right away, like it does when skipping
+    Cut,
+    /// The songs fade into each other over `ms` milliseconds
+    Crossfade { ms: u32 },
+}
+
+impl QueueSong {
+    /// How the song before this one goes into it, using the profile's
+    /// crossfade unless the item has its own transition
+    pub fn transition_into(&self, settings: &ResolvedProfile) -> Transition {
+        match self.transition {
+            Some(transition) => transition,
+            None if settings.crossfade > 0 => Transition::Crossfade {
+                ms: settings.crossfade,
+            },
+            None => Transition::Gapless,
+        }
+    }
+
+    /// How `previous` goes into this song. Songs which run straight into
+    /// each other, like the tracks of a live album, are played gaplessly
+    /// rather than with the profile's crossfade.
+    pub fn transition_after(&self, previous: &Song, settings: &ResolvedProfile) -> Transition {
+        match self.transition_into(settings) {
+            Transition::Crossfade { .. }
+                if self.transition.is_none() && flows_into(previous, &self.song) =>
+            {
+                Transition::Gapless
+            }
+            transition => transition,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -23,3 +75,598 @@ impl IntoIterator for QueueAlbum {
         self.album.into_iter()
     }
 }
+
+/// How long an item takes to play. Albums are queued a track at a time, so
+/// only songs count.
+pub(super) fn item_duration(item: &QueueItem<QueueSong, QueueAlbum>) -> Duration {
+    match &item.item {
+        QueueItemType::Single(song) => song.song.duration,
+        _ => Duration::ZERO,
+    }
+}
+
+/// How long the queue takes to play. It's kept up to date as items are
+/// added and played, rather than adding up every song each time it's asked
+/// for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueInfo {
+    /// The items coming up after the current one
+    pub remaining: Duration,
+    /// The songs from the library or playlist being played which haven't
+    /// been queued up yet
+    pub pool: Duration,
+    /// The songs played since the queue was last cleared
+    pub elapsed: Duration,
+}
+
+impl QueueInfo {
+    /// Adds up the items in `queue`, for when the queue loop starts
+    pub fn new(queue: &Queue<QueueSong, QueueAlbum>) -> Self {
+        QueueInfo {
+            remaining: queue.items.iter().skip(1).map(item_duration).sum(),
+            ..Default::default()
+        }
+    }
+
+    /// Everything after the current item, counting the songs which haven't
+    /// been queued up yet
+    pub fn total(&self) -> Duration {
+        self.remaining + self.pool
+    }
+
+    /// Items taking `duration` were added to `queue`. The first one became
+    /// the current item if it `was_empty`, and items which weren't added
+    /// by a person were taken from the pool.
+    pub(super) fn added(
+        &mut self,
+        queue: &Queue<QueueSong, QueueAlbum>,
+        was_empty: bool,
+        duration: Duration,
+        from_pool: bool,
+    ) {
+        self.remaining += duration;
+        if was_empty {
+            self.remaining = self.remaining.saturating_sub(current_duration(queue));
+        }
+        if from_pool {
+            self.pool = self.pool.saturating_sub(duration);
+        }
+    }
+
+    /// `queue` moved on to its next item
+    pub(super) fn advanced(&mut self, queue: &Queue<QueueSong, QueueAlbum>) {
+        if let Some(played) = queue.played.last() {
+            self.elapsed += item_duration(played);
+        }
+        self.remaining = self.remaining.saturating_sub(current_duration(queue));
+    }
+
+    /// `queue` went back `steps` items, which are now at the front of it
+    pub(super) fn went_back(&mut self, queue: &Queue<QueueSong, QueueAlbum>, steps: usize) {
+        let unplayed: Duration = queue.items.iter().take(steps).map(item_duration).sum();
+        let upcoming: Duration = queue
+            .items
+            .iter()
+            .skip(1)
+            .take(steps)
+            .map(item_duration)
+            .sum();
+        self.elapsed = self.elapsed.saturating_sub(unplayed);
+        self.remaining += upcoming;
+    }
+
+    /// `item` was removed from `index` in `queue`
+    pub(super) fn removed(
+        &mut self,
+        queue: &Queue<QueueSong, QueueAlbum>,
+        index: usize,
+        item: &QueueItem<QueueSong, QueueAlbum>,
+    ) {
+        let gone = match index {
+            // The next item took the removed current one's place
+            0 => current_duration(queue),
+            _ => item_duration(item),
+        };
+        self.remaining = self.remaining.saturating_sub(gone);
+    }
+}
+
+fn current_duration(queue: &Queue<QueueSong, QueueAlbum>) -> Duration {
+    queue.items.first().map(item_duration).unwrap_or_default()
+}
+
+/// Randomly reorders everything after the current song, both what was added
+/// by hand and the songs queued up automatically, and the `pending` songs
+/// waiting to be queued up after them. With `keep_manual`, songs added by
+/// hand stay where they are.
+pub fn shuffle_remaining(
+    queue: &mut Queue<QueueSong, QueueAlbum>,
+    pending: &mut VecDeque<Uuid>,
+    keep_manual: bool,
+    rng: &mut impl Rng,
+) {
+    queue.shuffle_remaining(keep_manual, |items| items.shuffle(rng));
+    pending.make_contiguous().shuffle(rng);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+    use futures::executor::block_on;
+    use kushi::{Queue, QueueItem, QueueItemType};
+    use parking_lot::RwLock;
+    use rand::{rngs::StdRng, SeedableRng};
+    use uuid::Uuid;
+
+    use super::{item_duration, shuffle_remaining, QueueAlbum, QueueSong, Transition};
+    use crate::{
+        config::Config,
+        music_controller::{
+            controller::{
+                Controller, LibraryCommand, LibraryResponse, PlayerLocation, QueueCommand,
+                QueueResponse,
+            },
+            controller_handle::{LibraryCommandInput, QueueCommandInput},
+        },
+        music_storage::{
+            gain_staging::ResolvedProfile,
+            library::{Song, Tag},
+        },
+    };
+
+    fn queue() -> Queue<QueueSong, QueueAlbum> {
+        let mut queue = Queue::new(false, None);
+        for i in 0..20 {
+            let song = QueueSong {
+                song: Song {
+                    uuid: Uuid::new_v4(),
+                    ..Default::default()
+                },
+                location: PlayerLocation::Library,
+                transition: None,
+            };
+            // The first few are added by hand, the rest automatically
+            queue.add_item(song, i < 5);
+        }
+        queue
+    }
+
+    fn uuids(queue: &Queue<QueueSong, QueueAlbum>) -> Vec<Uuid> {
+        queue
+            .items
+            .iter()
+            .map(|item| match &item.item {
+                QueueItemType::Single(song) => song.song.uuid,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn timed(secs: u64) -> QueueItem<QueueSong, QueueAlbum> {
+        QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+            song: Song {
+                uuid: Uuid::new_v4(),
+                duration: Duration::from_secs(secs),
+                ..Default::default()
+            },
+            location: PlayerLocation::Library,
+            transition: None,
+        }))
+    }
+
+    #[test]
+    fn queue_info_follows_changes() {
+        let mut queue = Queue::new(false, None);
+        for secs in [60, 120, 180] {
+            let QueueItemType::Single(song) = timed(secs).item else {
+                unreachable!()
+            };
+            queue.add_item(song, true);
+        }
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let send = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let info = || {
+            let QueueResponse::Info(info) = send(QueueCommand::Info) else {
+                unreachable!()
+            };
+            // Adding the queue up again gives the same as keeping count
+            let QueueResponse::GetAll(items) = send(QueueCommand::Get) else {
+                unreachable!()
+            };
+            let remaining: Duration = items.iter().skip(1).map(item_duration).sum();
+            assert_eq!(info.remaining, remaining);
+            (
+                info.remaining.as_secs(),
+                info.total().as_secs(),
+                info.elapsed.as_secs(),
+            )
+        };
+
+        assert_eq!(info(), (300, 300, 0));
+        send(QueueCommand::SetPool(Duration::from_secs(600)));
+        assert_eq!(info(), (300, 900, 0));
+
+        send(QueueCommand::Next);
+        assert_eq!(info(), (180, 780, 60));
+
+        // Songs queued up automatically come out of the pool
+        send(QueueCommand::Append(timed(240), false));
+        assert_eq!(info(), (420, 780, 60));
+        send(QueueCommand::AppendNext(timed(30)));
+        assert_eq!(info(), (450, 810, 60));
+        send(QueueCommand::Remove(2));
+        assert_eq!(info(), (270, 630, 60));
+
+        send(QueueCommand::Prev);
+        assert_eq!(info(), (390, 750, 0));
+        send(QueueCommand::Next);
+        send(QueueCommand::Next);
+        assert_eq!(info(), (240, 600, 180));
+        send(QueueCommand::Back(2));
+        assert_eq!(info(), (390, 750, 0));
+
+        // Removing the current song moves on without playing it
+        send(QueueCommand::Remove(0));
+        assert_eq!(info(), (270, 630, 0));
+        send(QueueCommand::ReplaceUpNext(vec![timed(10), timed(20)]));
+        assert_eq!(info(), (60, 420, 0));
+
+        send(QueueCommand::Clear);
+        assert_eq!(info(), (0, 0, 0));
+        send(QueueCommand::Append(timed(100), true));
+        send(QueueCommand::Append(timed(50), true));
+        assert_eq!(info(), (50, 50, 0));
+    }
+
+    #[test]
+    fn prev_fills_in_pinned_history() {
+        let songs: Vec<Song> = (1..=6)
+            .map(|i| timed(i * 10))
+            .map(|item| {
+                let QueueItemType::Single(song) = item.item else {
+                    unreachable!()
+                };
+                song.song
+            })
+            .collect();
+        let mut queue = Queue::new(false, None);
+        for song in &songs {
+            queue.add_item(
+                QueueSong {
+                    song: song.clone(),
+                    location: PlayerLocation::Library,
+                    transition: None,
+                },
+                true,
+            );
+        }
+        let mut config = Config::default();
+        config.playback.played_history_limit = 1;
+        config.playback.pin_played_history = true;
+
+        // The second song was removed from the library after it played
+        let (lib_mail, lib_rx) = async_channel::unbounded::<LibraryCommandInput>();
+        let library: Vec<Song> = songs
+            .iter()
+            .filter(|song| song.uuid != songs[1].uuid)
+            .cloned()
+            .collect();
+        std::thread::spawn(move || {
+            while let Ok(LibraryCommandInput { res_rx, command }) = lib_rx.recv_blocking() {
+                let LibraryCommand::SongsBulk(uuids) = command else {
+                    unreachable!()
+                };
+                let found = uuids
+                    .iter()
+                    .filter_map(|uuid| library.iter().find(|song| song.uuid == *uuid))
+                    .map(|song| Arc::new(song.clone()))
+                    .collect();
+                res_rx
+                    .send_blocking(LibraryResponse::SongsBulk(found))
+                    .unwrap();
+            }
+        });
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(config)),
+                Some(lib_mail),
+                None,
+            ))
+        });
+        let send = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let current = |response| match response {
+            QueueResponse::Item(Ok(QueueItem {
+                item: QueueItemType::Single(song),
+                compacted: false,
+                ..
+            })) => song.song,
+            response => panic!("{response:?}"),
+        };
+
+        for _ in 0..5 {
+            send(QueueCommand::Next);
+        }
+        // Everything but the last played song is only kept as a uuid
+        let QueueResponse::Counts { played, .. } = send(QueueCommand::Counts) else {
+            unreachable!()
+        };
+        assert_eq!(played, 5);
+
+        // Going back past the last whole song fills the rest in, skipping
+        // the one which isn't in the library anymore
+        assert_eq!(current(send(QueueCommand::Prev)), songs[4]);
+        assert_eq!(current(send(QueueCommand::Prev)), songs[3]);
+        assert_eq!(current(send(QueueCommand::Back(2))), songs[0]);
+        assert!(matches!(
+            send(QueueCommand::Prev),
+            QueueResponse::Item(Err(_))
+        ));
+
+        let QueueResponse::GetAll(items) = send(QueueCommand::Get) else {
+            unreachable!()
+        };
+        let expected = [0, 2, 3, 4, 5].map(|i| songs[i].clone());
+        let items: Vec<Song> = items
+            .into_iter()
+            .map(|item| current(QueueResponse::Item(Ok(item))))
+            .collect();
+        assert_eq!(items, expected);
+    }
+
+    #[test]
+    fn stale_edits_conflict() {
+        let queue = queue();
+        let first = uuids(&queue);
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let send = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let revision = || {
+            let QueueResponse::Changes { revision, .. } = send(QueueCommand::Changes(u64::MAX))
+            else {
+                unreachable!()
+            };
+            revision
+        };
+        let upcoming = || {
+            let QueueResponse::GetAll(items) = send(QueueCommand::Get) else {
+                unreachable!()
+            };
+            items
+                .into_iter()
+                .map(|item| match item.item {
+                    QueueItemType::Single(song) => song.song.uuid,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The user means to remove the song after the current one, but the
+        // queue moves on before the click arrives
+        let seen = revision();
+        send(QueueCommand::Next);
+        let QueueResponse::Conflict(conflict) = send(QueueCommand::Remove(1).expecting(Some(seen)))
+        else {
+            panic!("a stale remove went through")
+        };
+        assert_eq!(conflict.revision, revision());
+        assert_ne!(conflict.revision, seen);
+        assert_eq!(upcoming(), first[1..]);
+        assert_eq!(conflict.items.len(), first.len() - 1);
+
+        // Against the fresh queue, the song meant is at the front
+        let QueueResponse::Item(Ok(removed)) =
+            send(QueueCommand::Remove(0).expecting(Some(conflict.revision)))
+        else {
+            panic!("an up to date remove conflicted")
+        };
+        let QueueItemType::Single(removed) = removed.item else {
+            unreachable!()
+        };
+        assert_eq!(removed.song.uuid, first[1]);
+
+        // Moves are checked the same way
+        let seen = revision();
+        send(QueueCommand::Next);
+        let stale = QueueCommand::Move { from: 1, to: 3 }.expecting(Some(seen));
+        assert!(matches!(send(stale), QueueResponse::Conflict(_)));
+        assert_eq!(upcoming()[..2], first[3..5]);
+        let fresh = QueueCommand::Move { from: 1, to: 3 }.expecting(Some(revision()));
+        assert_eq!(send(fresh), QueueResponse::Empty(Ok(())));
+        assert_eq!(upcoming()[1..4], [first[5], first[6], first[4]]);
+
+        // Reading doesn't change the revision, and commands without one
+        // aren't checked
+        let seen = revision();
+        let QueueResponse::Item(Ok(_)) = send(QueueCommand::GetIndex(1).expecting(Some(seen)))
+        else {
+            panic!("reading conflicted")
+        };
+        assert_eq!(revision(), seen);
+        send(QueueCommand::Next);
+        assert!(matches!(
+            send(QueueCommand::Remove(1).expecting(None)),
+            QueueResponse::Item(Ok(_))
+        ));
+    }
+
+    #[test]
+    fn shuffle_keeps_current_and_items() {
+        let mut queue = queue();
+        let before = uuids(&queue);
+
+        shuffle_remaining(
+            &mut queue,
+            &mut VecDeque::new(),
+            false,
+            &mut StdRng::seed_from_u64(7),
+        );
+        let after = uuids(&queue);
+        assert_eq!(after[0], before[0]);
+        assert_ne!(after, before);
+
+        let (mut before, mut after) = (before, after);
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn shuffle_keeps_manual_order() {
+        let mut queue = queue();
+        let before = uuids(&queue);
+
+        shuffle_remaining(
+            &mut queue,
+            &mut VecDeque::new(),
+            true,
+            &mut StdRng::seed_from_u64(7),
+        );
+        let after = uuids(&queue);
+        assert_eq!(after[..5], before[..5]);
+        assert_ne!(after[5..], before[5..]);
+    }
+
+    #[test]
+    fn shuffle_includes_pending() {
+        let mut queue = queue();
+        let before: VecDeque<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+        let mut pending = before.clone();
+
+        // Songs waiting to be queued are shuffled even when the ones added
+        // by hand keep their places
+        shuffle_remaining(
+            &mut queue,
+            &mut pending,
+            true,
+            &mut StdRng::seed_from_u64(7),
+        );
+        assert_ne!(pending, before);
+
+        let (mut before, mut after) = (Vec::from(before), Vec::from(pending));
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn transition_overrides_one_boundary() {
+        let mut queue = Queue::new(false, None);
+        for secs in [60, 120, 180, 240] {
+            let QueueItemType::Single(song) = timed(secs).item else {
+                unreachable!()
+            };
+            queue.add_item(song, true);
+        }
+        let (queue_mail, queue_rx) = async_channel::unbounded();
+        std::thread::spawn(move || {
+            block_on(Controller::queue_loop(
+                queue,
+                queue_rx,
+                Arc::new(RwLock::new(Config::default())),
+                None,
+                None,
+            ))
+        });
+        let send = |command| {
+            let (command, tx) = QueueCommandInput::command(command);
+            queue_mail.send_blocking(command).unwrap();
+            tx.recv_blocking().unwrap()
+        };
+        let settings = ResolvedProfile {
+            crossfade: 2000,
+            ..Default::default()
+        };
+        // How the current song goes into the next, then moves on to it
+        let boundary = || {
+            let QueueResponse::Item(Ok(next)) = send(QueueCommand::GetIndex(1)) else {
+                unreachable!()
+            };
+            let QueueItemType::Single(next) = next.item else {
+                unreachable!()
+            };
+            send(QueueCommand::Next);
+            next.transition_into(&settings)
+        };
+
+        let set = |index, transition| {
+            let QueueResponse::Empty(res) = send(QueueCommand::SetTransition { index, transition })
+            else {
+                unreachable!()
+            };
+            res
+        };
+        set(2, Some(Transition::Cut)).unwrap();
+        assert!(set(4, Some(Transition::Cut)).is_err());
+
+        let crossfade = Transition::Crossfade { ms: 2000 };
+        assert_eq!(boundary(), crossfade);
+        assert_eq!(boundary(), Transition::Cut);
+        assert_eq!(boundary(), crossfade);
+
+        // Without a crossfade songs go straight into each other
+        let next = QueueSong {
+            song: Song::default(),
+            location: PlayerLocation::Library,
+            transition: None,
+        };
+        assert_eq!(
+            next.transition_into(&ResolvedProfile::default()),
+            Transition::Gapless
+        );
+
+        // Tracks of a live album run into each other rather than fading
+        let track = |number: u16| {
+            let mut song = Song::default();
+            song.set_tag(Tag::Album, "Live".to_string());
+            song.set_tag(Tag::Track, number.to_string());
+            song.set_end_loudness(Some(-12));
+            song
+        };
+        let mut next = QueueSong {
+            song: track(2),
+            location: PlayerLocation::Album,
+            transition: None,
+        };
+        assert_eq!(
+            next.transition_after(&track(1), &settings),
+            Transition::Gapless
+        );
+        assert_eq!(next.transition_after(&track(3), &settings), crossfade);
+        // Unless the item was given a transition of its own
+        next.transition = Some(crossfade);
+        assert_eq!(next.transition_after(&track(1), &settings), crossfade);
+    }
+}
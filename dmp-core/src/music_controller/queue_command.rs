@@ -1,39 +1,115 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
 use kushi::{Queue, QueueError, QueueItemType};
+use parking_lot::RwLock;
+
+use crate::{
+    config::Config,
+    music_storage::{library::Song, search::QueueMode},
+};
 
 use super::{
-    controller::{Controller, QueueCommand, QueueResponse},
-    controller_handle::QueueCommandInput,
-    queue::{QueueAlbum, QueueSong},
+    controller::{
+        Controller, LibraryCommand, LibraryResponse, QueueCommand, QueueConflict, QueueResponse,
+    },
+    controller_handle::{LibraryCommandInput, QueueCommandInput},
+    queue::{item_duration, shuffle_remaining, QueueAlbum, QueueInfo, QueueSong},
+    queue_log::{QueueLog, QueueSnapshot},
+    queue_store::SavedQueue,
 };
 
 impl Controller {
     pub(super) async fn queue_loop(
         mut queue: Queue<QueueSong, QueueAlbum>,
         queue_mail: async_channel::Receiver<QueueCommandInput>,
+        config: Arc<RwLock<Config>>,
+        lib_mail: Option<async_channel::Sender<LibraryCommandInput>>,
+        saved: Option<crossbeam_channel::Sender<SavedQueue>>,
     ) {
+        let mut info = QueueInfo::new(&queue);
+        let mut log = QueueLog::default();
+        // Songs waiting to be queued up, kept as uuids until they're needed
+        let mut pending = VecDeque::new();
         while true {
             let QueueCommandInput { res_rx, command } = queue_mail.recv().await.unwrap();
+            let revision = log.revision();
+            // Checked here, so nothing can change the queue in between
+            let command = match command {
+                QueueCommand::Expect {
+                    revision: expected, ..
+                } if expected != revision => {
+                    let conflict = QueueConflict {
+                        revision,
+                        items: queue.items.clone(),
+                    };
+                    res_rx
+                        .send(QueueResponse::Conflict(conflict))
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                QueueCommand::Expect { command, .. } => *command,
+                command => command,
+            };
             match command {
                 QueueCommand::Append(item, by_human) => {
+                    let before = QueueSnapshot::of(&queue);
+                    let (was_empty, duration) = (queue.items.is_empty(), item_duration(&item));
                     match item.item {
                         QueueItemType::Single(song) => queue.add_item(song, by_human),
                         _ => unimplemented!(),
                     }
+                    info.added(&queue, was_empty, duration, !by_human);
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                QueueCommand::AppendNext(item) => {
+                    let before = QueueSnapshot::of(&queue);
+                    let (was_empty, duration) = (queue.items.is_empty(), item_duration(&item));
+                    match item.item {
+                        QueueItemType::Single(song) => queue.add_item_next(song),
+                        _ => unimplemented!(),
+                    }
+                    info.added(&queue, was_empty, duration, false);
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                QueueCommand::AppendBlockNext(items) => {
+                    let before = QueueSnapshot::of(&queue);
+                    let was_empty = queue.items.is_empty();
+                    let duration = items.iter().map(item_duration).sum();
+                    queue.add_block_next(items.into_iter().map(|item| item.item).collect());
+                    info.added(&queue, was_empty, duration, false);
+                    log.record(before, &queue);
                     res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
                 }
                 QueueCommand::Next => {
+                    let before = QueueSnapshot::of(&queue);
                     let next = queue
                         .next()
                         .map_or(Err(QueueError::NoNext), |s| Ok(s.clone()));
+                    info.advanced(&queue);
+                    log.record(before, &queue);
+                    trim_played(&mut queue, &config, lib_mail.is_some());
                     res_rx
                         .send(QueueResponse::Item(next.clone()))
                         .await
                         .unwrap();
                 }
                 QueueCommand::Prev => {
+                    fill_played(&mut queue, lib_mail.as_ref(), 1).await;
+                    let before = QueueSnapshot::of(&queue);
                     let prev = queue
                         .prev()
                         .map_or(Err(QueueError::EmptyPlayed), |s| Ok(s.clone()));
+                    if prev.is_ok() {
+                        info.went_back(&queue, 1);
+                    }
+                    log.record(before, &queue);
                     res_rx
                         .send(QueueResponse::Item(prev.clone()))
                         .await
@@ -59,17 +135,217 @@ impl Controller {
                         .await
                         .unwrap();
                 }
+                QueueCommand::GetPlayed(limit) => {
+                    fill_played(&mut queue, lib_mail.as_ref(), limit).await;
+                    let start = queue.played.len().saturating_sub(limit);
+                    res_rx
+                        .send(QueueResponse::GetAll(queue.played[start..].to_vec()))
+                        .await
+                        .unwrap();
+                }
+                QueueCommand::Back(steps) => {
+                    fill_played(&mut queue, lib_mail.as_ref(), steps).await;
+                    let before = QueueSnapshot::of(&queue);
+                    let item = queue.back(steps).cloned();
+                    if item.is_ok() {
+                        info.went_back(&queue, steps);
+                    }
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Item(item)).await.unwrap();
+                }
                 QueueCommand::Clear => {
+                    let before = QueueSnapshot::of(&queue);
                     queue.clear();
+                    info = QueueInfo::default();
+                    pending.clear();
+                    log.record(before, &queue);
                     res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
                 }
                 QueueCommand::Remove(index) => {
+                    let before = QueueSnapshot::of(&queue);
+                    let removed = queue.remove_item(index);
+                    if let Ok(item) = &removed {
+                        info.removed(&queue, index, item);
+                    }
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Item(removed)).await.unwrap();
+                }
+                QueueCommand::Move { from, to } => {
+                    let before = QueueSnapshot::of(&queue);
+                    let res = queue.move_item(from, to);
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(res)).await.unwrap();
+                }
+                QueueCommand::ReplaceUpNext(items) => {
+                    let before = QueueSnapshot::of(&queue);
+                    let dropped: Duration = queue
+                        .items
+                        .iter()
+                        .skip(1)
+                        .filter(|item| !item.by_human)
+                        .map(item_duration)
+                        .sum();
+                    let was_empty = queue.items.is_empty();
+                    let duration = items.iter().map(item_duration).sum();
+                    queue.replace_auto_items(items.into_iter().map(|item| item.item).collect());
+                    info.remaining = info.remaining.saturating_sub(dropped);
+                    info.added(&queue, was_empty, duration, false);
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                QueueCommand::Counts => {
+                    res_rx
+                        .send(QueueResponse::Counts {
+                            played: queue.played.len(),
+                            queued: queue.items.len(),
+                            automatic: queue
+                                .items
+                                .iter()
+                                .skip(1)
+                                .filter(|item| !item.by_human)
+                                .count(),
+                        })
+                        .await
+                        .unwrap();
+                }
+                QueueCommand::SetPool(pool) => {
+                    info.pool = pool;
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                QueueCommand::SetPending { uuids, pool, mode } => {
+                    match mode {
+                        QueueMode::PlayNow => {
+                            pending = uuids.into();
+                            info.pool = pool;
+                        }
+                        QueueMode::PlayNext => {
+                            for uuid in uuids.into_iter().rev() {
+                                pending.push_front(uuid);
+                            }
+                            info.pool += pool;
+                        }
+                        QueueMode::Append => {
+                            pending.extend(uuids);
+                            info.pool += pool;
+                        }
+                    }
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                QueueCommand::TakePending => {
                     res_rx
-                        .send(QueueResponse::Item(queue.remove_item(index)))
+                        .send(QueueResponse::Pending(pending.pop_front()))
                         .await
                         .unwrap();
                 }
+                QueueCommand::Info => {
+                    res_rx.send(QueueResponse::Info(info)).await.unwrap();
+                }
+                QueueCommand::SetTransition { index, transition } => {
+                    let before = QueueSnapshot::of(&queue);
+                    let len = queue.items.len();
+                    let res = match queue.items.get_mut(index).map(|item| &mut item.item) {
+                        Some(QueueItemType::Single(song)) => {
+                            song.transition = transition;
+                            Ok(())
+                        }
+                        _ => Err(QueueError::OutOfBounds { index, len }),
+                    };
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(res)).await.unwrap();
+                }
+                QueueCommand::Changes(since) => {
+                    res_rx
+                        .send(QueueResponse::Changes {
+                            revision: log.revision(),
+                            ops: log.since(since),
+                        })
+                        .await
+                        .unwrap();
+                }
+                QueueCommand::ShuffleRemaining => {
+                    let before = QueueSnapshot::of(&queue);
+                    let keep_manual = config.read().playback.shuffle_keeps_manual;
+                    shuffle_remaining(
+                        &mut queue,
+                        &mut pending,
+                        keep_manual,
+                        &mut rand::thread_rng(),
+                    );
+                    log.record(before, &queue);
+                    res_rx.send(QueueResponse::Empty(Ok(()))).await.unwrap();
+                }
+                // Unwrapped above, and checks aren't nested
+                QueueCommand::Expect { .. } => unreachable!(),
+            }
+
+            // Every change is handed over for saving, which is left to the
+            // saver so nothing waits on it
+            if let Some(saved) = saved.as_ref().filter(|_| log.revision() != revision) {
+                _ = saved.send(SavedQueue::of(&queue));
             }
         }
     }
 }
+
+/// Trims the played history to the configured limit. Pinned items are only
+/// compacted when they can be found in the library again.
+fn trim_played(queue: &mut Queue<QueueSong, QueueAlbum>, config: &RwLock<Config>, can_fill: bool) {
+    let playback = &config.read().playback;
+    if playback.pin_played_history && can_fill {
+        queue.check_played_pinned(
+            playback.played_history_limit,
+            playback.played_history_cap,
+            |song| {
+                song.song = Song {
+                    uuid: song.song.uuid,
+                    ..Default::default()
+                }
+            },
+        );
+    } else {
+        queue.check_played(playback.played_history_limit);
+    }
+}
+
+/// Fills in the compacted songs among the last `count` played items from
+/// the library, so they can be played or shown again. Songs which have
+/// been removed from the library since are dropped from the history, and
+/// the ones before them are filled in instead.
+async fn fill_played(
+    queue: &mut Queue<QueueSong, QueueAlbum>,
+    lib_mail: Option<&async_channel::Sender<LibraryCommandInput>>,
+    count: usize,
+) {
+    let Some(lib_mail) = lib_mail else {
+        return;
+    };
+    loop {
+        let start = queue.played.len().saturating_sub(count);
+        let uuids: Vec<_> = queue.played[start..]
+            .iter()
+            .filter(|item| item.compacted)
+            .filter_map(|item| match &item.item {
+                QueueItemType::Single(song) => Some(song.song.uuid),
+                _ => None,
+            })
+            .collect();
+        if uuids.is_empty() {
+            return;
+        }
+
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SongsBulk(uuids));
+        lib_mail.send(command).await.unwrap();
+        let LibraryResponse::SongsBulk(songs) = tx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        let songs: HashMap<_, _> = songs.into_iter().map(|song| (song.uuid, song)).collect();
+        let tail = queue.played.split_off(start);
+        queue.played.extend(tail.into_iter().filter_map(|mut item| {
+            if let (true, QueueItemType::Single(song)) = (item.compacted, &mut item.item) {
+                song.song = Song::clone(songs.get(&song.song.uuid)?);
+                item.compacted = false;
+            }
+            Some(item)
+        }));
+    }
+}
@@ -0,0 +1,437 @@
+//! A log of the changes made to the queue, so the interface can patch its
+//! copy of the queue instead of fetching all of it after every change.
+//!
+//! Each change to the queue bumps the revision and records the operations
+//! which turn the queue before it into the queue after it. Someone who has
+//! seen a revision asks for the operations since, and fetches the whole
+//! queue again if they've been forgotten.
+
+use std::collections::VecDeque;
+
+use kushi::{Queue, QueueItem, QueueItemType};
+use uuid::Uuid;
+
+use super::{
+    controller::PlayerLocation,
+    queue::{QueueAlbum, QueueSong, Transition},
+};
+
+/// The most operations remembered. Anyone further behind than this fetches
+/// the whole queue.
+pub const QUEUE_LOG_LIMIT: usize = 512;
+
+/// One change to the queue. Indices are into the items coming up, where
+/// `0` is the current item, and each operation applies to the queue as the
+/// ones before it left it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueOp {
+    Inserted {
+        index: usize,
+        item: Box<QueueItem<QueueSong, QueueAlbum>>,
+    },
+    Removed {
+        index: usize,
+    },
+    /// The item at `from` was taken out and put back in at `to`
+    Moved {
+        from: usize,
+        to: usize,
+    },
+    /// An item was changed without being moved
+    StateChanged {
+        index: usize,
+        by_human: bool,
+        transition: Option<Transition>,
+    },
+    /// The first `count` items were played, and moved to the end of the
+    /// played items
+    Played {
+        count: usize,
+    },
+    /// The last `count` played items were moved back to the front of the
+    /// queue
+    Unplayed {
+        count: usize,
+    },
+    /// Every item coming up was removed
+    Cleared,
+}
+
+/// What's compared to find what changed about an item
+#[derive(Debug, Clone, PartialEq)]
+struct ItemKey {
+    uuid: Option<Uuid>,
+    location: Option<PlayerLocation>,
+    by_human: bool,
+    transition: Option<Transition>,
+}
+
+impl ItemKey {
+    fn of(item: &QueueItem<QueueSong, QueueAlbum>) -> Self {
+        let song = match &item.item {
+            QueueItemType::Single(song) => Some(song),
+            _ => None,
+        };
+        ItemKey {
+            uuid: song.map(|song| song.song.uuid),
+            location: song.map(|song| song.location),
+            by_human: item.by_human,
+            transition: song.and_then(|song| song.transition),
+        }
+    }
+
+    /// Whether both are the same song from the same place, whether or not
+    /// it was changed
+    fn same_item(&self, other: &ItemKey) -> bool {
+        self.uuid == other.uuid && self.location == other.location
+    }
+}
+
+/// The queue before a command changes it
+pub(super) struct QueueSnapshot {
+    keys: Vec<ItemKey>,
+    played: usize,
+}
+
+impl QueueSnapshot {
+    pub(super) fn of(queue: &Queue<QueueSong, QueueAlbum>) -> Self {
+        QueueSnapshot {
+            keys: queue.items.iter().map(ItemKey::of).collect(),
+            played: queue.played.len(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueueLog {
+    revision: u64,
+    ops: VecDeque<(u64, QueueOp)>,
+    /// The newest revision whose operations were forgotten
+    forgotten: u64,
+}
+
+impl QueueLog {
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The operations made after `revision`, or `None` if some of them
+    /// were forgotten, or `revision` is from before the log was started
+    pub fn since(&self, revision: u64) -> Option<Vec<QueueOp>> {
+        if revision > self.revision || revision < self.forgotten {
+            return None;
+        }
+        Some(
+            self.ops
+                .iter()
+                .filter(|(op_revision, _)| *op_revision > revision)
+                .map(|(_, op)| op.clone())
+                .collect(),
+        )
+    }
+
+    /// Records what changed between `before` and the queue as it is now.
+    /// Played items have to be counted before the played history is
+    /// trimmed, so this is called before [`Queue::check_played`].
+    pub(super) fn record(&mut self, before: QueueSnapshot, queue: &Queue<QueueSong, QueueAlbum>) {
+        let mut ops = Vec::new();
+        let mut keys = before.keys;
+        let after: Vec<ItemKey> = queue.items.iter().map(ItemKey::of).collect();
+
+        if queue.played.len() > before.played {
+            let count = queue.played.len() - before.played;
+            keys.drain(..count.min(keys.len()));
+            ops.push(QueueOp::Played { count });
+        } else if queue.played.len() < before.played {
+            let count = (before.played - queue.played.len()).min(after.len());
+            keys.splice(0..0, after[..count].iter().cloned());
+            ops.push(QueueOp::Unplayed { count });
+        }
+
+        if after.is_empty() && !keys.is_empty() {
+            ops.push(QueueOp::Cleared);
+        } else {
+            diff(&keys, &after, &queue.items, &mut ops);
+        }
+
+        if ops.is_empty() {
+            return;
+        }
+        self.revision += 1;
+        self.ops
+            .extend(ops.into_iter().map(|op| (self.revision, op)));
+
+        // Whole revisions are forgotten at once, so none is half there, and
+        // the newest is always kept however big it is
+        while self.ops.len() > QUEUE_LOG_LIMIT {
+            let Some(&(oldest, _)) = self.ops.front() else {
+                break;
+            };
+            if oldest == self.revision {
+                break;
+            }
+            while self
+                .ops
+                .front()
+                .is_some_and(|(revision, _)| *revision == oldest)
+            {
+                self.ops.pop_front();
+            }
+            self.forgotten = oldest;
+        }
+    }
+}
+
+/// The operations which turn `before` into `after`. Only the run of items
+/// between what stayed the same at the start and end is looked at, which is
+/// all that changes for everything but shuffling, and that run is moved
+/// around if it has the same items, or replaced if it doesn't.
+fn diff(
+    before: &[ItemKey],
+    after: &[ItemKey],
+    items: &[QueueItem<QueueSong, QueueAlbum>],
+    ops: &mut Vec<QueueOp>,
+) {
+    let prefix = before
+        .iter()
+        .zip(after)
+        .take_while(|(old, new)| old.same_item(new))
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(old, new)| old.same_item(new))
+        .count();
+    let old = &before[prefix..before.len() - suffix];
+    let new = &after[prefix..after.len() - suffix];
+
+    let changed = |index: usize| QueueOp::StateChanged {
+        index,
+        by_human: after[index].by_human,
+        transition: after[index].transition,
+    };
+    ops.extend((0..prefix).filter(|&i| before[i] != after[i]).map(changed));
+
+    match moves(old, new, prefix) {
+        Some(moves) => ops.extend(moves),
+        None => {
+            ops.extend(
+                (prefix..prefix + old.len())
+                    .rev()
+                    .map(|index| QueueOp::Removed { index }),
+            );
+            ops.extend((prefix..prefix + new.len()).map(|index| QueueOp::Inserted {
+                index,
+                item: Box::new(items[index].clone()),
+            }));
+        }
+    }
+
+    ops.extend(
+        (1..=suffix)
+            .filter(|i| before[before.len() - i] != after[after.len() - i])
+            .map(|i| changed(after.len() - i)),
+    );
+}
+
+/// The moves which put `old` in the order of `new`, if they have the same
+/// items
+fn moves(old: &[ItemKey], new: &[ItemKey], offset: usize) -> Option<Vec<QueueOp>> {
+    if old.len() != new.len() || old.is_empty() {
+        return None;
+    }
+    let mut current: Vec<&ItemKey> = old.iter().collect();
+    let mut moves = Vec::new();
+    for (to, key) in new.iter().enumerate() {
+        let from = to + current[to..].iter().position(|item| item.same_item(key))?;
+        if from != to {
+            let item = current.remove(from);
+            current.insert(to, item);
+            moves.push(QueueOp::Moved {
+                from: offset + from,
+                to: offset + to,
+            });
+        }
+    }
+    Some(moves)
+}
+
+#[cfg(test)]
+mod tests {
+    use kushi::{Queue, QueueItemType};
+    use uuid::Uuid;
+
+    use super::{QueueLog, QueueOp, QueueSnapshot, QUEUE_LOG_LIMIT};
+    use crate::{
+        music_controller::{
+            controller::PlayerLocation,
+            queue::{QueueAlbum, QueueSong, Transition},
+        },
+        music_storage::library::Song,
+    };
+
+    fn song() -> QueueSong {
+        QueueSong {
+            song: Song {
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            },
+            location: PlayerLocation::Library,
+            transition: None,
+        }
+    }
+
+    /// The uuids of the items coming up, after replaying `ops` on `uuids`
+    fn replay(mut uuids: Vec<Uuid>, played: &mut Vec<Uuid>, ops: &[QueueOp]) -> Vec<Uuid> {
+        for op in ops {
+            match op {
+                QueueOp::Inserted { index, item } => {
+                    let QueueItemType::Single(song) = &item.item else {
+                        unreachable!()
+                    };
+                    uuids.insert(*index, song.song.uuid);
+                }
+                QueueOp::Removed { index } => {
+                    uuids.remove(*index);
+                }
+                QueueOp::Moved { from, to } => {
+                    let uuid = uuids.remove(*from);
+                    uuids.insert(*to, uuid);
+                }
+                QueueOp::StateChanged { .. } => (),
+                QueueOp::Played { count } => played.extend(uuids.drain(..count)),
+                QueueOp::Unplayed { count } => {
+                    let start = played.len() - count;
+                    uuids.splice(0..0, played.drain(start..));
+                }
+                QueueOp::Cleared => uuids.clear(),
+            }
+        }
+        uuids
+    }
+
+    fn uuids(queue: &Queue<QueueSong, QueueAlbum>) -> Vec<Uuid> {
+        queue
+            .items
+            .iter()
+            .map(|item| match &item.item {
+                QueueItemType::Single(song) => song.song.uuid,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    /// Changes the queue like a queue command would, returning the revision
+    fn change(
+        log: &mut QueueLog,
+        queue: &mut Queue<QueueSong, QueueAlbum>,
+        mutate: impl FnOnce(&mut Queue<QueueSong, QueueAlbum>),
+    ) -> u64 {
+        let before = QueueSnapshot::of(queue);
+        mutate(queue);
+        log.record(before, queue);
+        log.revision()
+    }
+
+    #[test]
+    fn ops_for_mutations() {
+        let mut queue: Queue<QueueSong, QueueAlbum> = Queue::new(false, None);
+        let mut log = QueueLog::default();
+
+        for _ in 0..4 {
+            change(&mut log, &mut queue, |queue| queue.add_item(song(), false));
+        }
+        let (mut seen, mut played) = (uuids(&queue), Vec::new());
+        let base = change(&mut log, &mut queue, |_| ());
+        assert_eq!(base, 4, "nothing changing isn't a revision");
+
+        let next = song();
+        let next_uuid = next.song.uuid;
+        change(&mut log, &mut queue, |queue| {
+            queue.add_item_next(next.clone())
+        });
+        let ops = log.since(base).unwrap();
+        assert!(matches!(ops[..], [QueueOp::Inserted { index: 1, .. }]));
+
+        change(&mut log, &mut queue, |queue| {
+            queue.remove_item(3).unwrap();
+        });
+        assert_eq!(
+            log.since(base + 1).unwrap(),
+            [QueueOp::Removed { index: 3 }]
+        );
+
+        change(&mut log, &mut queue, |queue| {
+            queue.next().unwrap();
+        });
+        assert_eq!(log.since(base + 2).unwrap(), [QueueOp::Played { count: 1 }]);
+        assert_eq!(uuids(&queue)[0], next_uuid);
+
+        change(&mut log, &mut queue, |queue| {
+            let QueueItemType::Single(song) = &mut queue.items[1].item else {
+                unreachable!()
+            };
+            song.transition = Some(Transition::Cut);
+        });
+        assert_eq!(
+            log.since(base + 3).unwrap(),
+            [QueueOp::StateChanged {
+                index: 1,
+                by_human: false,
+                transition: Some(Transition::Cut)
+            }]
+        );
+
+        // Shuffling only moves things around
+        change(&mut log, &mut queue, |queue| queue.items[1..].reverse());
+        let ops = log.since(base + 4).unwrap();
+        assert!(ops.iter().all(|op| matches!(op, QueueOp::Moved { .. })));
+
+        change(&mut log, &mut queue, |queue| {
+            queue.back(1).unwrap();
+        });
+        assert_eq!(
+            log.since(base + 5).unwrap(),
+            [QueueOp::Unplayed { count: 1 }]
+        );
+
+        // Everything since the base lands on the same queue
+        let ops = log.since(base).unwrap();
+        seen = replay(seen, &mut played, &ops);
+        assert_eq!(seen, uuids(&queue));
+
+        change(&mut log, &mut queue, |queue| queue.clear());
+        assert_eq!(log.since(base + 6).unwrap(), [QueueOp::Cleared]);
+        assert!(log.since(log.revision() + 1).is_none());
+    }
+
+    #[test]
+    fn old_revisions_are_forgotten() {
+        let mut queue: Queue<QueueSong, QueueAlbum> = Queue::new(false, None);
+        let mut log = QueueLog::default();
+        for _ in 0..=QUEUE_LOG_LIMIT {
+            let before = QueueSnapshot::of(&queue);
+            queue.add_item(song(), true);
+            log.record(before, &queue);
+        }
+        assert!(log.since(0).is_none());
+        let recent = log.revision() - 10;
+        assert_eq!(log.since(recent).unwrap().len(), 10);
+
+        // Replacing what's coming up is a single revision
+        let before = QueueSnapshot::of(&queue);
+        let revision = log.revision();
+        queue.items.truncate(1);
+        queue.add_item(song(), true);
+        log.record(before, &queue);
+        assert_eq!(log.revision(), revision + 1);
+        let ops = log.since(revision).unwrap();
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, QueueOp::Removed { .. }))
+                .count(),
+            QUEUE_LOG_LIMIT
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Saving the queue, so it's still there the next time the player opens.
+//!
+//! The queue loop hands a snapshot to a thread of its own after each change,
+//! so writing never holds up the queue or sees it halfway through a change.
+//! Each snapshot is written to a new file under a higher revision, keeping
+//! the one before it, so a write which was cut short leaves the last
+//! complete revision to load.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use kushi::{Queue, QueueItem, QueueItemType};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::music_storage::library::MusicLibrary;
+
+use super::{
+    controller::PlayerLocation,
+    queue::{QueueAlbum, QueueSong, Transition},
+};
+
+/// How long the queue has to stay the same before it's written. Refilling
+/// the queue clears it and adds the songs back one at a time, which is
+/// over well before this.
+pub const QUEUE_SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// How many revisions are kept on disk
+const KEPT_REVISIONS: usize = 2;
+
+/// A song in the saved queue. Only the uuid is kept, as the song is looked
+/// up in the library again when the queue is loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedItem {
+    pub uuid: Uuid,
+    pub location: PlayerLocation,
+    pub by_human: bool,
+    pub transition: Option<Transition>,
+}
+
+/// The items of the queue at one point, starting with the current one
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedQueue {
+    pub revision: u64,
+    pub items: Vec<SavedItem>,
+}
+
+impl SavedQueue {
+    /// Takes a snapshot of `queue`. Albums which haven't been split into
+    /// their tracks yet are left out.
+    pub(super) fn of(queue: &Queue<QueueSong, QueueAlbum>) -> Self {
+        let items = queue
+            .items
+            .iter()
+            .filter_map(|item| match &item.item {
+                QueueItemType::Single(song) => Some(SavedItem {
+                    uuid: song.song.uuid,
+                    location: song.location,
+                    by_human: item.by_human,
+                    transition: song.transition,
+                }),
+                _ => None,
+            })
+            .collect();
+        SavedQueue { revision: 0, items }
+    }
+
+    /// Builds the queue back up from the songs in `library`, leaving out
+    /// songs which aren't in it anymore
+    pub fn restore(&self, library: &MusicLibrary) -> Queue<QueueSong, QueueAlbum> {
+        let mut queue = Queue::new(false, None);
+        let items = self
+            .items
+            .iter()
+            .filter_map(|item| {
+                let (song, _) = library.query_uuid(&item.uuid)?;
+                let mut restored = QueueItem::from_item_type(QueueItemType::Single(QueueSong {
+                    song: song.clone(),
+                    location: item.location,
+                    transition: item.transition,
+                }));
+                restored.by_human = item.by_human;
+                Some(restored)
+            })
+            .collect();
+        queue.set_items(items);
+        queue
+    }
+}
+
+/// The revisions of the queue saved in a folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueStore {
+    dir: PathBuf,
+    /// The newest revision written
+    revision: u64,
+}
+
+impl QueueStore {
+    pub fn new(dir: PathBuf) -> Self {
+        let mut store = QueueStore { dir, revision: 0 };
+        store.revision = store.revisions().first().copied().unwrap_or(0);
+        store
+    }
+
+    fn path(&self, revision: u64) -> PathBuf {
+        self.dir.join(format!("queue.{revision}.json"))
+    }
+
+    /// The revisions in the folder, newest first
+    fn revisions(&self) -> Vec<u64> {
+        let mut revisions: Vec<u64> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let revision = name
+                    .to_str()?
+                    .strip_prefix("queue.")?
+                    .strip_suffix(".json")?;
+                revision.parse().ok()
+            })
+            .collect();
+        revisions.sort_unstable_by(|a, b| b.cmp(a));
+        revisions
+    }
+
+    /// Writes `queue` as the next revision, to a temporary file which is
+    /// then moved into place. Revisions older than the last
+    /// [`KEPT_REVISIONS`] are removed.
+    pub fn write(&mut self, queue: &mut SavedQueue) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        queue.revision = self.revision + 1;
+        let path = self.path(queue.revision);
+        let mut writer = path.clone();
+        writer.set_extension("tmp");
+        File::create(&writer)?.write_all(serde_json::to_string(queue)?.as_bytes())?;
+        fs::rename(writer, path)?;
+        self.revision = queue.revision;
+
+        for old in self.revisions().into_iter().skip(KEPT_REVISIONS) {
+            _ = fs::remove_file(self.path(old));
+        }
+        Ok(())
+    }
+
+    /// The newest revision which can be read in full
+    pub fn load(&self) -> Option<SavedQueue> {
+        self.revisions().into_iter().find_map(|revision| {
+            let queue: SavedQueue =
+                serde_json::from_str(&fs::read_to_string(self.path(revision)).ok()?).ok()?;
+            (queue.revision == revision).then_some(queue)
+        })
+    }
+}
+
+/// Writes the snapshots sent by the queue loop once they've stopped coming
+/// for [`QUEUE_SAVE_DELAY`], so only the last of a quick run of changes is
+/// written. Ends once the queue loop is gone, writing anything left.
+pub(super) fn queue_saver_loop(mut store: QueueStore, snapshots: Receiver<SavedQueue>) {
+    let mut latest: Option<SavedQueue> = None;
+    loop {
+        let received = match latest {
+            Some(_) => snapshots.recv_timeout(QUEUE_SAVE_DELAY),
+            None => snapshots.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(snapshot) => latest = Some(snapshot),
+            Err(timeout) => {
+                if let Some(mut queue) = latest.take() {
+                    if let Err(e) = store.write(&mut queue) {
+                        println!("Couldn't save the queue: {e}");
+                    }
+                }
+                if timeout == RecvTimeoutError::Disconnected {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, thread, time::Duration};
+
+    use uuid::Uuid;
+
+    use kushi::{Queue, QueueItemType};
+
+    use super::{queue_saver_loop, QueueStore, SavedItem, SavedQueue};
+    use crate::music_controller::{
+        controller::PlayerLocation,
+        queue::{QueueSong, Transition},
+    };
+    use crate::music_storage::library::{MusicLibrary, Song};
+
+    fn store_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("dmp-queue-{}", Uuid::new_v4()))
+    }
+
+    fn saved(songs: usize) -> SavedQueue {
+        SavedQueue {
+            revision: 0,
+            items: (0..songs)
+                .map(|i| SavedItem {
+                    uuid: Uuid::new_v4(),
+                    location: PlayerLocation::Library,
+                    by_human: i == 0,
+                    transition: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn restore_from_library() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let mut queue = Queue::new(false, None);
+        for (i, transition) in [None, Some(Transition::Cut), None].into_iter().enumerate() {
+            let song = Song {
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            };
+            // The last song was removed from the library since
+            if i < 2 {
+                library.library.push(song.clone());
+            }
+            let item = QueueSong {
+                song,
+                location: PlayerLocation::Album,
+                transition,
+            };
+            queue.add_item(item, i == 1);
+        }
+
+        let saved = SavedQueue::of(&queue);
+        assert_eq!(saved.items.len(), 3);
+        let restored = saved.restore(&library);
+        assert_eq!(restored.items.len(), 2);
+        for (restored, item) in restored.items.iter().zip(&queue.items) {
+            assert_eq!(restored.by_human, item.by_human);
+            assert_eq!(restored.item, item.item);
+        }
+        assert!(matches!(
+            &restored.items[1].item,
+            QueueItemType::Single(song) if song.transition == Some(Transition::Cut)
+        ));
+    }
+
+    #[test]
+    fn cut_short_write_loads_previous_revision() {
+        let dir = store_dir();
+        let mut store = QueueStore::new(dir.clone());
+        let mut revisions = Vec::new();
+        for songs in [3, 4, 5] {
+            let mut queue = saved(songs);
+            store.write(&mut queue).unwrap();
+            revisions.push(queue);
+        }
+        assert_eq!(store.load().as_ref(), revisions.last());
+        // Only the last two are kept
+        assert_eq!(store.revisions(), [3, 2]);
+
+        // The newest file is cut off partway through
+        let newest = store.path(3);
+        let written = fs::read(&newest).unwrap();
+        fs::write(&newest, &written[..written.len() / 2]).unwrap();
+        // Along with a temporary file which was never moved into place
+        fs::write(dir.join("queue.4.tmp"), "{").unwrap();
+
+        let store = QueueStore::new(dir.clone());
+        assert_eq!(store.load().as_ref(), Some(&revisions[1]));
+
+        // Writing carries on after the newest revision, broken or not
+        let mut store = store;
+        let mut queue = saved(1);
+        store.write(&mut queue).unwrap();
+        assert_eq!(queue.revision, 4);
+        assert_eq!(store.load(), Some(queue));
+
+        // Nothing saved yet
+        assert_eq!(QueueStore::new(store_dir()).load(), None);
+    }
+
+    #[test]
+    fn only_settled_queue_written() {
+        let dir = store_dir();
+        let (snapshots_tx, snapshots) = crossbeam_channel::unbounded();
+        let saver = {
+            let store = QueueStore::new(dir.clone());
+            thread::spawn(move || queue_saver_loop(store, snapshots))
+        };
+
+        // A refill clears the queue and adds the songs back
+        let full = saved(3);
+        for songs in 0..3 {
+            let mut partial = full.clone();
+            partial.items.truncate(songs);
+            snapshots_tx.send(partial).unwrap();
+        }
+        snapshots_tx.send(full.clone()).unwrap();
+        thread::sleep(super::QUEUE_SAVE_DELAY + Duration::from_millis(300));
+
+        let store = QueueStore::new(dir.clone());
+        assert_eq!(store.revisions(), [1]);
+        assert_eq!(store.load().unwrap().items, full.items);
+
+        // Whatever is waiting is written when the queue goes away
+        let last = saved(2);
+        snapshots_tx.send(last.clone()).unwrap();
+        drop(snapshots_tx);
+        saver.join().unwrap();
+        assert_eq!(store.load().unwrap().items, last.items);
+    }
+}
@@ -0,0 +1,284 @@
+//! Songs which aren't files on this computer, like internet radio stations.
+//! Each [`Service`] gets a [`RemoteSource`] which knows how to look its songs
+//! up and where the player can load them from.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::music_storage::library::{Service, Tag, URI};
+
+/// How long to wait for a remote server before giving up on it
+pub(super) const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RemoteError {
+    #[error("Nothing can play songs from {0:?} yet")]
+    NoSource(Service),
+    #[error("'{0}' isn't a remote song")]
+    NotRemote(String),
+    #[error("'{0}' isn't a URL which can be streamed")]
+    Unsupported(String),
+    #[error("Couldn't reach '{url}': {details}")]
+    Request { url: String, details: String },
+    #[error("'{url}' answered with HTTP {status}")]
+    Status { url: String, status: u16 },
+}
+
+/// Where the player should load a remote song from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Playable {
+    /// A URL the player streams from directly
+    Stream(String),
+    /// A copy of the song which was downloaded ahead of time
+    Cached(PathBuf),
+}
+
+/// Knows how to play the songs of one [`Service`]. The location passed to
+/// each method is the one stored in the song's [`URI::Remote`].
+pub trait RemoteSource: Send + Sync {
+    /// Looks up the tags of the song at `location`
+    fn metadata(&self, location: &str) -> Result<BTreeMap<Tag, String>, RemoteError>;
+
+    /// Where the player can load the song at `location` from
+    fn playable(&self, location: &str) -> Result<Playable, RemoteError>;
+
+    /// Whether the song at `location` can be played right now
+    fn available(&self, location: &str) -> Result<bool, RemoteError>;
+}
+
+/// The [`RemoteSource`] of each service, shared by the player and the
+/// controller handle so more can be registered while the player runs
+#[derive(Clone)]
+pub struct RemoteSources {
+    sources: HashMap<Service, Arc<dyn RemoteSource>>,
+}
+
+impl Default for RemoteSources {
+    /// Plain HTTP(S) audio is handled for internet radio and for remote songs
+    /// without a service
+    fn default() -> Self {
+        let mut sources = RemoteSources::empty();
+        let http: Arc<dyn RemoteSource> = Arc::new(HttpSource);
+        sources.register(Service::InternetRadio, Arc::clone(&http));
+        sources.register(Service::None, http);
+        sources
+    }
+}
+
+impl RemoteSources {
+    /// A registry which can't play anything
+    pub fn empty() -> Self {
+        RemoteSources {
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Sets what plays the songs of `service`, replacing anything before
+    pub fn register(&mut self, service: Service, source: Arc<dyn RemoteSource>) {
+        self.sources.insert(service, source);
+    }
+
+    pub fn get(&self, service: Service) -> Result<&dyn RemoteSource, RemoteError> {
+        self.sources
+            .get(&service)
+            .map(|source| source.as_ref())
+            .ok_or(RemoteError::NoSource(service))
+    }
+
+    /// Where the player can load the song at `uri` from, which has to be a
+    /// [`URI::Remote`]
+    pub fn playable(&self, uri: &URI) -> Result<Playable, RemoteError> {
+        let (service, location) = remote_parts(uri)?;
+        self.get(service)?.playable(location)
+    }
+
+    pub fn metadata(&self, uri: &URI) -> Result<BTreeMap<Tag, String>, RemoteError> {
+        let (service, location) = remote_parts(uri)?;
+        self.get(service)?.metadata(location)
+    }
+
+    pub fn available(&self, uri: &URI) -> Result<bool, RemoteError> {
+        let (service, location) = remote_parts(uri)?;
+        self.get(service)?.available(location)
+    }
+}
+
+fn remote_parts(uri: &URI) -> Result<(Service, &str), RemoteError> {
+    match uri {
+        URI::Remote(service, location) => Ok((*service, location)),
+        uri => Err(RemoteError::NotRemote(uri.to_string())),
+    }
+}
+
+/// Streams audio straight from an HTTP(S) URL, like an internet radio
+/// station. Tags come from the station's ICY headers, when it sends them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HttpSource;
+
+impl HttpSource {
+    fn check_url(location: &str) -> Result<(), RemoteError> {
+        match location.split_once("://") {
+            Some((scheme, _))
+                if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+            {
+                Ok(())
+            }
+            _ => Err(RemoteError::Unsupported(location.to_string())),
+        }
+    }
+
+    /// Starts a request for `location`, which is dropped after reading the
+    /// headers so a never ending stream isn't downloaded
+    fn request(location: &str) -> Result<attohttpc::Response, RemoteError> {
+        Self::check_url(location)?;
+        attohttpc::get(location)
+            .header("Icy-MetaData", "1")
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .map_err(|e| RemoteError::Request {
+                url: location.to_string(),
+                details: e.to_string(),
+            })
+    }
+}
+
+impl RemoteSource for HttpSource {
+    fn metadata(&self, location: &str) -> Result<BTreeMap<Tag, String>, RemoteError> {
+        let response = Self::request(location)?;
+        if !response.is_success() {
+            return Err(RemoteError::Status {
+                url: location.to_string(),
+                status: response.status().as_u16(),
+            });
+        }
+
+        let headers = response.headers();
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+
+        let mut tags = BTreeMap::new();
+        // A station is titled by its name, falling back to its address
+        tags.insert(
+            Tag::Title,
+            header("icy-name").unwrap_or_else(|| location.to_string()),
+        );
+        if let Some(genre) = header("icy-genre") {
+            tags.insert(Tag::Genre, genre);
+        }
+        if let Some(description) = header("icy-description") {
+            tags.insert(Tag::Comment, description);
+        }
+        if let Some(url) = header("icy-url") {
+            tags.insert(Tag::Key("URL".to_string()), url);
+        }
+        Ok(tags)
+    }
+
+    fn playable(&self, location: &str) -> Result<Playable, RemoteError> {
+        Self::check_url(location)?;
+        Ok(Playable::Stream(location.to_string()))
+    }
+
+    fn available(&self, location: &str) -> Result<bool, RemoteError> {
+        let response = Self::request(location)?;
+        let status = response.status().as_u16();
+        match status {
+            _ if response.is_success() => Ok(true),
+            // Only answers which mean the stream is gone count as missing,
+            // since a server error could be over in a minute
+            404 | 410 => Ok(false),
+            status => Err(RemoteError::Status {
+                url: location.to_string(),
+                status,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, thread};
+
+    use tiny_http::{Header, Response, Server};
+
+    use super::{HttpSource, Playable, RemoteError, RemoteSource, RemoteSources};
+    use crate::music_storage::library::{Service, Tag, URI};
+
+    /// Serves `test-data/cue/album.wav` as a radio station at `/stream`,
+    /// answering `requests` requests before stopping
+    fn station(requests: usize) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let audio = fs::read("test-data/cue/album.wav").unwrap();
+        thread::spawn(move || {
+            for request in server.incoming_requests().take(requests) {
+                let response = match request.url() {
+                    "/stream" => Response::from_data(audio.clone())
+                        .with_header(Header::from_bytes("Content-Type", "audio/wav").unwrap())
+                        .with_header(Header::from_bytes("icy-name", "Test FM").unwrap())
+                        .with_header(Header::from_bytes("icy-genre", "Chiptune").unwrap()),
+                    _ => Response::from_data(Vec::new()).with_status_code(404),
+                };
+                _ = request.respond(response);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn http_station() {
+        let url = station(3);
+        let stream = format!("{url}/stream");
+
+        let tags = HttpSource.metadata(&stream).unwrap();
+        assert_eq!(tags[&Tag::Title], "Test FM");
+        assert_eq!(tags[&Tag::Genre], "Chiptune");
+
+        assert_eq!(HttpSource.available(&stream), Ok(true));
+        assert_eq!(HttpSource.available(&format!("{url}/gone")), Ok(false));
+        assert_eq!(
+            HttpSource.playable(&stream),
+            Ok(Playable::Stream(stream.clone()))
+        );
+    }
+
+    #[test]
+    fn routed_by_service() {
+        let sources = RemoteSources::default();
+        let url = "https://radio.example/stream".to_string();
+
+        assert_eq!(
+            sources.playable(&URI::Remote(Service::InternetRadio, url.clone())),
+            Ok(Playable::Stream(url.clone()))
+        );
+        assert_eq!(
+            sources.playable(&URI::Remote(Service::Spotify, url.clone())),
+            Err(RemoteError::NoSource(Service::Spotify))
+        );
+        assert!(matches!(
+            sources.playable(&URI::Remote(Service::None, "ftp://radio.example".into())),
+            Err(RemoteError::Unsupported(_))
+        ));
+        assert!(matches!(
+            sources.playable(&URI::Local("song.flac".into())),
+            Err(RemoteError::NotRemote(_))
+        ));
+
+        let mut sources = RemoteSources::empty();
+        sources.register(Service::Spotify, Arc::new(HttpSource));
+        assert!(sources.get(Service::Spotify).is_ok());
+        assert!(sources.get(Service::InternetRadio).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Decides when the library is saved. Changes are gathered up and written
+//! together, and changes to play counts and other stats wait much longer
+//! than anything else, so finishing a song doesn't rewrite a big library.
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use super::{
+    controller::{LibraryCommand, LibraryResponse},
+    controller_handle::LibraryCommandInput,
+};
+
+/// The longest changes to stats wait before they're saved. They're kept in
+/// the stat journal meanwhile, so they aren't lost in a crash.
+pub const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The longest any other change waits before it's saved
+pub const STRUCTURAL_SAVE_DELAY: Duration = Duration::from_secs(10);
+
+/// How often the saver thread checks whether the library needs saving
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What kind of change was made to the library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LibraryChange {
+    /// Play counts, skips and other stats
+    Stats,
+    /// Anything else, like adding songs, editing tags or changing playlists
+    Structural,
+}
+
+/// Keeps track of unsaved changes to the library and when they're due to
+/// be saved
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SaveScheduler {
+    /// When the oldest unsaved change to stats was made
+    stats_since: Option<Instant>,
+    /// When the oldest unsaved change of any other kind was made
+    structural_since: Option<Instant>,
+    /// How many changes of other kinds were made
+    revision: u64,
+}
+
+impl SaveScheduler {
+    /// Notes a change made at `now`
+    pub fn mark(&mut self, change: LibraryChange, now: Instant) {
+        let since = match change {
+            LibraryChange::Stats => &mut self.stats_since,
+            LibraryChange::Structural => {
+                self.revision += 1;
+                &mut self.structural_since
+            }
+        };
+        since.get_or_insert(now);
+    }
+
+    /// Goes up with every change other than to stats, so what's made from
+    /// the library can tell when it's out of date
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Whether there are changes which haven't been saved yet
+    pub fn is_dirty(&self) -> bool {
+        self.stats_since.is_some() || self.structural_since.is_some()
+    }
+
+    /// When the unsaved changes are due to be saved, if there are any
+    pub fn due_at(&self) -> Option<Instant> {
+        let stats = self.stats_since.map(|since| since + STATS_SAVE_INTERVAL);
+        let structural = self
+            .structural_since
+            .map(|since| since + STRUCTURAL_SAVE_DELAY);
+        match (stats, structural) {
+            (Some(stats), Some(structural)) => Some(stats.min(structural)),
+            (due, None) | (None, due) => due,
+        }
+    }
+
+    /// Whether the library should be saved at `now`
+    pub fn save_due(&self, now: Instant) -> bool {
+        self.due_at().is_some_and(|due| now >= due)
+    }
+
+    /// Notes that the library was saved, which saves every kind of change
+    pub fn saved(&mut self) {
+        self.stats_since = None;
+        self.structural_since = None;
+    }
+}
+
+/// Asks the library to save whatever is due, for as long as the player runs
+pub(super) fn library_saver_loop(lib_mail: async_channel::Sender<LibraryCommandInput>) {
+    loop {
+        sleep(SAVE_CHECK_INTERVAL);
+        let (command, tx) = LibraryCommandInput::command(LibraryCommand::SaveIfDue);
+        if lib_mail.send_blocking(command).is_err() {
+            return;
+        }
+        let Ok(LibraryResponse::Ok) = tx.recv_blocking() else {
+            return;
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{LibraryChange, SaveScheduler, STATS_SAVE_INTERVAL, STRUCTURAL_SAVE_DELAY};
+
+    #[test]
+    fn stats_wait_for_the_interval() {
+        let start = Instant::now();
+        let mut scheduler = SaveScheduler::default();
+        assert!(!scheduler.save_due(start + STATS_SAVE_INTERVAL));
+
+        // Every song finishing only marks the library, the first one setting
+        // when it's saved
+        for minute in 0..4 {
+            let now = start + Duration::from_secs(minute * 60);
+            scheduler.mark(LibraryChange::Stats, now);
+            assert!(!scheduler.save_due(now));
+        }
+        assert!(scheduler.is_dirty());
+        assert!(!scheduler.save_due(start + STATS_SAVE_INTERVAL - Duration::from_secs(1)));
+        assert!(scheduler.save_due(start + STATS_SAVE_INTERVAL));
+
+        scheduler.saved();
+        assert!(!scheduler.is_dirty());
+        assert!(!scheduler.save_due(start + STATS_SAVE_INTERVAL * 2));
+    }
+
+    #[test]
+    fn structural_changes_save_sooner() {
+        let start = Instant::now();
+        let mut scheduler = SaveScheduler::default();
+        scheduler.mark(LibraryChange::Stats, start);
+
+        // A playlist edit a minute later brings the save forward, taking the
+        // stats along with it
+        let edit = start + Duration::from_secs(60);
+        scheduler.mark(LibraryChange::Structural, edit);
+        scheduler.mark(LibraryChange::Structural, edit + Duration::from_secs(5));
+        assert_eq!(scheduler.due_at(), Some(edit + STRUCTURAL_SAVE_DELAY));
+        assert_eq!(scheduler.revision(), 2);
+        assert!(!scheduler.save_due(edit + Duration::from_secs(9)));
+        assert!(scheduler.save_due(edit + STRUCTURAL_SAVE_DELAY));
+
+        scheduler.saved();
+        assert_eq!(scheduler.due_at(), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Keeping time for the services the playing song is shared with. Time
+//! spent paused isn't listening, so it doesn't count towards scrobbling and
+//! the start shown on Discord is moved forward by it.
+
+use prismriver::State as PrismState;
+
+/// How long ListenBrainz and Last.fm keep showing a song as playing now, in
+/// seconds. Resuming after a longer pause has to tell them again.
+pub const NOW_PLAYING_EXPIRES: i64 = 5 * 60;
+
+/// The time the playing song has been listened to, from the song and state
+/// changes the player sends, in seconds since the Unix epoch
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct ScrobbleClock {
+    playing: bool,
+    /// When the song started, moved forward by the time it was paused
+    started: Option<i64>,
+    /// When the player stopped playing, if it isn't playing
+    paused_at: Option<i64>,
+}
+
+impl ScrobbleClock {
+    /// Starts timing a song from the beginning, which also happens when the
+    /// playing song restarts
+    pub(super) fn song(&mut self, now: i64) {
+        self.started = Some(now);
+        self.paused_at = (!self.playing).then_some(now);
+    }
+
+    /// Takes in the player's new state. When it resumes playing, the time it
+    /// was paused for is returned.
+    pub(super) fn state(&mut self, state: &PrismState, now: i64) -> Option<i64> {
+        self.playing = matches!(state, PrismState::Playing);
+        match (self.playing, self.paused_at) {
+            (true, Some(paused_at)) => {
+                self.paused_at = None;
+                let paused = (now - paused_at).max(0);
+                if let Some(started) = self.started.as_mut() {
+                    *started += paused;
+                }
+                self.started.map(|_| paused)
+            }
+            (false, None) => {
+                self.paused_at = Some(now);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// How long the song has been played for, leaving out pauses
+    pub(super) fn listened(&self, now: i64) -> i64 {
+        self.started.map_or(0, |started| {
+            (self.paused_at.unwrap_or(now) - started).max(0)
+        })
+    }
+
+    /// When the song would have started if it had never been paused, which
+    /// is only known while it's playing
+    pub(super) fn started(&self) -> Option<i64> {
+        self.started.filter(|_| self.paused_at.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prismriver::State as PrismState;
+
+    use super::{ScrobbleClock, NOW_PLAYING_EXPIRES};
+
+    #[test]
+    fn paused_time_left_out() {
+        let mut clock = ScrobbleClock::default();
+        clock.state(&PrismState::Playing, 0);
+        clock.song(100);
+        assert_eq!(clock.started(), Some(100));
+        assert_eq!(clock.listened(130), 30);
+
+        // Nothing counts while paused
+        assert_eq!(clock.state(&PrismState::Paused, 130), None);
+        assert_eq!(clock.started(), None);
+        assert_eq!(clock.listened(200), 30);
+        // Buffering and pausing again don't move the pause
+        assert_eq!(clock.state(&PrismState::Buffering(0), 150), None);
+        assert_eq!(clock.state(&PrismState::Paused, 160), None);
+
+        assert_eq!(clock.state(&PrismState::Playing, 190), Some(60));
+        assert_eq!(clock.started(), Some(160));
+        assert_eq!(clock.listened(200), 40);
+        // Being told it's playing again changes nothing
+        assert_eq!(clock.state(&PrismState::Playing, 200), None);
+        assert_eq!(clock.listened(200), 40);
+
+        // A new song starts fresh
+        clock.song(300);
+        assert_eq!(clock.listened(310), 10);
+    }
+
+    #[test]
+    fn long_pauses_reported() {
+        let mut clock = ScrobbleClock::default();
+        // Songs which come in before the player says it's playing don't
+        // count until it is
+        clock.song(0);
+        assert_eq!(clock.listened(10), 0);
+        assert_eq!(clock.started(), None);
+        assert_eq!(clock.state(&PrismState::Playing, 10), Some(10));
+        assert_eq!(clock.listened(20), 10);
+
+        clock.state(&PrismState::Stopped, 20);
+        let paused = clock.state(&PrismState::Playing, 20 + NOW_PLAYING_EXPIRES + 1);
+        assert!(paused.is_some_and(|paused| paused > NOW_PLAYING_EXPIRES));
+        assert_eq!(clock.listened(20 + NOW_PLAYING_EXPIRES + 6), 15);
+    }
+
+    #[test]
+    fn nothing_playing() {
+        let mut clock = ScrobbleClock::default();
+        clock.state(&PrismState::Paused, 0);
+        assert_eq!(clock.state(&PrismState::Playing, 1000), None);
+        assert_eq!(clock.listened(2000), 0);
+        assert_eq!(clock.started(), None);
+    }
+}
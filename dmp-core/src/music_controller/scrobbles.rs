@@ -0,0 +1,394 @@
+//! The offline scrobble cache, which holds listens until they can be
+//! submitted and keeps track of the most recently submitted ones
+
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// The number of submitted scrobbles kept around for review
+const RECENT_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub song: Uuid,
+    pub artist: String,
+    pub title: String,
+    pub release: Option<String>,
+    /// Unix timestamp in seconds of when the song was listened to
+    pub listened_at: i64,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    #[serde(default)]
+    pub track_number: Option<String>,
+}
+
+impl Scrobble {
+    pub fn id(&self) -> ScrobbleId {
+        ScrobbleId {
+            song: self.song,
+            listened_at: self.listened_at,
+        }
+    }
+}
+
+/// Picks out a scrobble however the cache has changed around it, as a song
+/// can only be listened to once at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrobbleId {
+    pub song: Uuid,
+    pub listened_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScrobbleStatus {
+    Pending,
+    Submitted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScrobbleEntry {
+    pub scrobble: Scrobble,
+    pub status: ScrobbleStatus,
+}
+
+/// A correction to the metadata of a scrobble
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrobbleCorrection {
+    pub artist: String,
+    pub title: String,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ScrobbleCacheError {
+    #[error("There is no such scrobble")]
+    NotFound,
+    #[error("This scrobble has already been submitted and can't be changed")]
+    AlreadySubmitted,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScrobbleCache {
+    pending: VecDeque<Scrobble>,
+    #[serde(skip)]
+    recent: VecDeque<Scrobble>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl ScrobbleCache {
+    /// Loads the pending scrobbles from `path`, starting with an empty
+    /// cache if the file doesn't exist or can't be read
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut cache: ScrobbleCache = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|cache| serde_json::from_str(&cache).ok())
+            .unwrap_or_default();
+        cache.path = Some(path);
+        cache
+    }
+
+    /// Writes the pending scrobbles out to the file the cache was loaded from
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)?
+            .write_all(&to_string_pretty(self)?.into_bytes())?;
+        Ok(())
+    }
+
+    /// Adds a scrobble to the end of the pending queue
+    pub fn push(&mut self, scrobble: Scrobble) {
+        self.pending.push_back(scrobble);
+    }
+
+    /// Submits pending scrobbles in the order they were listened to,
+    /// stopping at the first one which fails. Returns the number submitted.
+    ///
+    /// The cache isn't locked while submitting, which can take a while
+    /// offline, so it can still be looked at and added to. Only one flush
+    /// may run at a time, as the scrobbles submitted are taken off the front
+    /// of the pending ones afterwards.
+    pub fn flush<E>(
+        cache: &RwLock<ScrobbleCache>,
+        mut submit: impl FnMut(&Scrobble) -> Result<(), E>,
+    ) -> usize {
+        // Each is read just before it's sent, so corrections and listens
+        // made in the meantime aren't missed
+        let mut submitted = Vec::new();
+        loop {
+            let next = cache.read().pending.get(submitted.len()).cloned();
+            match next {
+                Some(scrobble) if submit(&scrobble).is_ok() => submitted.push(scrobble),
+                _ => break,
+            }
+        }
+
+        let count = submitted.len();
+        let mut cache = cache.write();
+        for scrobble in submitted {
+            // Kept as it was sent, even if it was corrected in the meantime
+            cache.pending.pop_front();
+            cache.recent.push_back(scrobble);
+            if cache.recent.len() > RECENT_LIMIT {
+                cache.recent.pop_front();
+            }
+        }
+        count
+    }
+
+    /// Returns the recently submitted scrobbles followed by the pending
+    /// ones, oldest first
+    pub fn entries(&self) -> Vec<ScrobbleEntry> {
+        let entry = |status| {
+            move |scrobble: &Scrobble| ScrobbleEntry {
+                scrobble: scrobble.clone(),
+                status,
+            }
+        };
+
+        self.recent
+            .iter()
+            .map(entry(ScrobbleStatus::Submitted))
+            .chain(self.pending.iter().map(entry(ScrobbleStatus::Pending)))
+            .collect()
+    }
+
+    /// Corrects the artist and title of the scrobble `id`, which must not
+    /// have been submitted yet
+    pub fn correct(
+        &mut self,
+        id: ScrobbleId,
+        correction: ScrobbleCorrection,
+    ) -> Result<(), ScrobbleCacheError> {
+        if self.recent.iter().any(|scrobble| scrobble.id() == id) {
+            return Err(ScrobbleCacheError::AlreadySubmitted);
+        }
+
+        let scrobble = self
+            .pending
+            .iter_mut()
+            .find(|scrobble| scrobble.id() == id)
+            .ok_or(ScrobbleCacheError::NotFound)?;
+        scrobble.artist = correction.artist;
+        scrobble.title = correction.title;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::RwLock;
+    use uuid::Uuid;
+
+    use super::{
+        Scrobble, ScrobbleCache, ScrobbleCacheError, ScrobbleCorrection, ScrobbleId,
+        ScrobbleStatus, RECENT_LIMIT,
+    };
+
+    fn scrobble(title: &str, listened_at: i64) -> Scrobble {
+        Scrobble {
+            song: Uuid::new_v4(),
+            artist: String::from("Artist"),
+            title: title.to_string(),
+            release: None,
+            listened_at,
+            duration_ms: None,
+            track_number: None,
+        }
+    }
+
+    #[test]
+    fn edit_then_flush() {
+        let mut cache = ScrobbleCache::default();
+        cache.push(scrobble("One", 1));
+        cache.push(scrobble("Tow", 2));
+        cache.push(scrobble("Three", 3));
+        let ids: Vec<ScrobbleId> = cache.entries().iter().map(|e| e.scrobble.id()).collect();
+
+        cache
+            .correct(
+                ids[1],
+                ScrobbleCorrection {
+                    artist: String::from("Fixed Artist"),
+                    title: String::from("Two"),
+                },
+            )
+            .unwrap();
+
+        // The first flush fails partway through, leaving the rest pending in order
+        let cache = RwLock::new(cache);
+        let mut submitted = Vec::new();
+        let count = ScrobbleCache::flush(&cache, |s| {
+            if s.listened_at == 3 {
+                return Err(());
+            }
+            submitted.push((s.artist.clone(), s.title.clone()));
+            Ok(())
+        });
+        assert_eq!(count, 2);
+        assert_eq!(
+            submitted,
+            [
+                (String::from("Artist"), String::from("One")),
+                (String::from("Fixed Artist"), String::from("Two")),
+            ]
+        );
+
+        let entries = cache.read().entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].status, ScrobbleStatus::Pending);
+        assert_eq!(
+            cache.write().correct(
+                ids[0],
+                ScrobbleCorrection {
+                    artist: String::new(),
+                    title: String::new(),
+                }
+            ),
+            Err(ScrobbleCacheError::AlreadySubmitted)
+        );
+
+        assert_eq!(ScrobbleCache::flush(&cache, |_| Ok::<(), ()>(())), 1);
+        let other = ScrobbleId {
+            song: Uuid::new_v4(),
+            listened_at: 3,
+        };
+        assert_eq!(
+            cache.write().correct(
+                other,
+                ScrobbleCorrection {
+                    artist: String::new(),
+                    title: String::new(),
+                }
+            ),
+            Err(ScrobbleCacheError::NotFound)
+        );
+        assert!(cache
+            .read()
+            .entries()
+            .iter()
+            .all(|e| e.status == ScrobbleStatus::Submitted));
+    }
+
+    #[test]
+    fn recent_is_capped() {
+        let mut cache = ScrobbleCache::default();
+        for i in 0..RECENT_LIMIT as i64 + 10 {
+            cache.push(scrobble("Song", i));
+        }
+        let cache = RwLock::new(cache);
+        ScrobbleCache::flush(&cache, |_| Ok::<(), ()>(()));
+
+        let entries = cache.read().entries();
+        assert_eq!(entries.len(), RECENT_LIMIT);
+        assert_eq!(entries[0].scrobble.listened_at, 10);
+    }
+
+    #[test]
+    fn only_pending_is_saved() {
+        let path = std::env::temp_dir().join(format!("scrobbles_{}.json", Uuid::new_v4()));
+        let cache = RwLock::new(ScrobbleCache::load(&path));
+        cache.write().push(scrobble("Submitted", 1));
+        ScrobbleCache::flush(&cache, |_| Ok::<(), ()>(()));
+        cache.write().push(scrobble("Pending", 2));
+        cache.read().save().unwrap();
+
+        let loaded = ScrobbleCache::load(&path);
+        std::fs::remove_file(path).unwrap();
+        let entries = loaded.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].scrobble.title, "Pending");
+    }
+
+    #[test]
+    fn cache_usable_while_submitting() {
+        let mut cache = ScrobbleCache::default();
+        cache.push(scrobble("One", 1));
+        cache.push(scrobble("Tow", 2));
+        let typo = cache.entries()[1].scrobble.id();
+        let cache = RwLock::new(cache);
+
+        // Listens and corrections made while a slow submit is going on
+        // neither wait for it nor get lost
+        let count = ScrobbleCache::flush(&cache, |s| {
+            if s.listened_at == 1 {
+                let mut cache = cache.write();
+                cache.push(scrobble("Three", 3));
+                cache
+                    .correct(
+                        typo,
+                        ScrobbleCorrection {
+                            artist: String::from("Artist"),
+                            title: String::from("Two"),
+                        },
+                    )
+                    .unwrap();
+            }
+            Ok::<(), ()>(())
+        });
+        assert_eq!(count, 3);
+
+        let entries = cache.read().entries();
+        let titles: Vec<_> = entries
+            .iter()
+            .map(|e| (e.scrobble.title.as_str(), e.status))
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                ("One", ScrobbleStatus::Submitted),
+                ("Two", ScrobbleStatus::Submitted),
+                ("Three", ScrobbleStatus::Submitted),
+            ]
+        );
+    }
+
+    #[test]
+    fn correction_follows_its_scrobble() {
+        let mut cache = ScrobbleCache::default();
+        cache.push(scrobble("One", 1));
+        cache.push(scrobble("Tow", 2));
+        // Picked from the list before the first is submitted
+        let typo = cache.entries()[1].scrobble.id();
+        let cache = RwLock::new(cache);
+        ScrobbleCache::flush(&cache, |s| match s.listened_at {
+            1 => Ok(()),
+            _ => Err(()),
+        });
+        cache.write().push(scrobble("Three", 3));
+
+        cache
+            .write()
+            .correct(
+                typo,
+                ScrobbleCorrection {
+                    artist: String::from("Artist"),
+                    title: String::from("Two"),
+                },
+            )
+            .unwrap();
+        let titles: Vec<String> = cache
+            .read()
+            .entries()
+            .into_iter()
+            .map(|e| e.scrobble.title)
+            .collect();
+        assert_eq!(titles, ["One", "Two", "Three"]);
+    }
+}
@@ -0,0 +1,493 @@
+//! Running a program of the user's when the song changes or playback starts,
+//! pauses or stops, for things like status bars and home automation. Only
+//! one runs at a time and one which takes too long is killed, so a stuck
+//! program can't pile up or hold up playback.
+
+use std::{
+    io::{self, Read},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use parking_lot::Mutex;
+use prismriver::State as PrismState;
+
+use crate::config::ConfigTrackChangeHook;
+use crate::music_storage::library::Tag;
+
+use super::now_playing_file::{fill_placeholders, NowPlayingEvent};
+
+/// How long to wait for things to settle before running the program, so
+/// skipping through songs doesn't run it for each of them
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// How often a running program is checked on
+const POLL: Duration = Duration::from_millis(20);
+
+/// How long to wait for the rest of the output once the program has exited.
+/// Anything it started in the background can keep the pipes open, so what
+/// was read by then is all that's logged.
+const OUTPUT_WAIT: Duration = Duration::from_millis(100);
+
+/// What the program is told about the player
+#[derive(Debug, Clone, PartialEq)]
+struct HookValues {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    uuid: Option<String>,
+    state: &'static str,
+}
+
+impl Default for HookValues {
+    fn default() -> Self {
+        HookValues {
+            title: None,
+            artist: None,
+            album: None,
+            uuid: None,
+            state: "stopped",
+        }
+    }
+}
+
+impl HookValues {
+    fn update(&mut self, event: NowPlayingEvent) {
+        match event {
+            NowPlayingEvent::Song(change) => {
+                let song = change.shown_song();
+                let tag = |tag| song.get_tag(&tag).cloned();
+                self.title = tag(Tag::Title);
+                self.artist = tag(Tag::Artist);
+                self.album = tag(Tag::Album);
+                self.uuid = Some(song.uuid.to_string());
+            }
+            NowPlayingEvent::State(state) => {
+                self.state = match state {
+                    PrismState::Paused => "paused",
+                    PrismState::Stopped => "stopped",
+                    // Buffering only happens on the way to playing
+                    _ => "playing",
+                }
+            }
+        }
+    }
+
+    fn placeholders(&self) -> [(&'static str, Option<&str>); 5] {
+        [
+            ("{title}", self.title.as_deref()),
+            ("{artist}", self.artist.as_deref()),
+            ("{album}", self.album.as_deref()),
+            ("{uuid}", self.uuid.as_deref()),
+            ("{state}", Some(self.state)),
+        ]
+    }
+
+    fn env(&self) -> [(&'static str, &str); 5] {
+        [
+            ("DMP_TITLE", self.title.as_deref().unwrap_or_default()),
+            ("DMP_ARTIST", self.artist.as_deref().unwrap_or_default()),
+            ("DMP_ALBUM", self.album.as_deref().unwrap_or_default()),
+            ("DMP_UUID", self.uuid.as_deref().unwrap_or_default()),
+            ("DMP_STATE", self.state),
+        ]
+    }
+}
+
+/// The program to run for `values`. The arguments are given to it
+/// directly, so nothing in them is ever read by a shell unless asked for.
+fn hook_command(config: &ConfigTrackChangeHook, values: &HookValues) -> Command {
+    let mut command = match config.shell {
+        true => shell(&config.command),
+        false => {
+            let placeholders = values.placeholders();
+            let mut command = Command::new(&config.command);
+            command.args(
+                config
+                    .args
+                    .iter()
+                    .map(|arg| fill_placeholders(arg, &placeholders)),
+            );
+            command
+        }
+    };
+    command
+        .envs(values.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+#[cfg(windows)]
+fn shell(line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(line);
+    command
+}
+
+#[cfg(not(windows))]
+fn shell(line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(line);
+    command
+}
+
+/// How a run of the program ended
+#[derive(Debug)]
+enum HookOutcome {
+    Exited {
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+    },
+    /// It ran past the timeout
+    Killed,
+    Failed(io::Error),
+}
+
+/// A run of the program, with its output read as it comes so it can't fill
+/// the pipes and get stuck
+struct RunningHook {
+    child: Child,
+    started: Instant,
+    stdout: Option<PipeReader>,
+    stderr: Option<PipeReader>,
+}
+
+impl RunningHook {
+    fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command.spawn()?;
+        Ok(RunningHook {
+            stdout: child.stdout.take().map(PipeReader::new),
+            stderr: child.stderr.take().map(PipeReader::new),
+            child,
+            started: Instant::now(),
+        })
+    }
+
+    /// How it ended, once it has, killing it if it's run for longer than
+    /// `timeout`
+    fn check(&mut self, timeout: Duration) -> Option<HookOutcome> {
+        match self.child.try_wait() {
+            Ok(Some(status)) => {
+                let until = Instant::now() + OUTPUT_WAIT;
+                let output = |reader: Option<PipeReader>| {
+                    reader
+                        .map(|reader| reader.finish(until))
+                        .unwrap_or_default()
+                };
+                Some(HookOutcome::Exited {
+                    status,
+                    stdout: output(self.stdout.take()),
+                    stderr: output(self.stderr.take()),
+                })
+            }
+            // The output is left behind, since anything the program started
+            // could keep the pipes open for much longer
+            Ok(None) if self.started.elapsed() >= timeout => {
+                match self.child.kill().and_then(|_| self.child.wait()) {
+                    Ok(_) => Some(HookOutcome::Killed),
+                    Err(e) => Some(HookOutcome::Failed(e)),
+                }
+            }
+            Ok(None) => None,
+            Err(e) => Some(HookOutcome::Failed(e)),
+        }
+    }
+}
+
+/// Reads a pipe on its own thread, keeping what has been read so far
+struct PipeReader {
+    read: Arc<Mutex<Vec<u8>>>,
+    thread: JoinHandle<()>,
+}
+
+impl PipeReader {
+    fn new<R: Read + Send + 'static>(mut pipe: R) -> Self {
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let output = Arc::clone(&read);
+        let thread = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(len @ 1..) = pipe.read(&mut buf) {
+                output.lock().extend_from_slice(&buf[..len]);
+            }
+        });
+        PipeReader { read, thread }
+    }
+
+    /// What was read, once the pipe is closed or `until` has passed. A
+    /// thread still reading is left to end with the pipe.
+    fn finish(self, until: Instant) -> String {
+        while !self.thread.is_finished() && Instant::now() < until {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let output = self.read.lock();
+        String::from_utf8_lossy(&output).trim().to_string()
+    }
+}
+
+fn log_outcome(outcome: HookOutcome, timeout: Duration) {
+    match outcome {
+        HookOutcome::Exited {
+            status,
+            stdout,
+            stderr,
+        } => {
+            if !stdout.is_empty() {
+                println!("Track change hook: {stdout}");
+            }
+            if !stderr.is_empty() {
+                eprintln!("Track change hook: {stderr}");
+            }
+            if !status.success() {
+                eprintln!("The track change hook exited with {status}");
+            }
+        }
+        HookOutcome::Killed => eprintln!(
+            "The track change hook was killed after running for {}ms",
+            timeout.as_millis()
+        ),
+        HookOutcome::Failed(e) => eprintln!("Couldn't wait for the track change hook: {e}"),
+    }
+}
+
+/// Runs the configured program after each burst of events has settled and
+/// changed something. While it's running, later events wait for it to end
+/// and only the newest of them are passed on.
+pub(super) fn run_track_change_hook(
+    config: ConfigTrackChangeHook,
+    events: Receiver<NowPlayingEvent>,
+) {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut values = HookValues::default();
+    // What it was last run with, which starts out as nothing having
+    // happened yet
+    let mut ran = values.clone();
+    let mut running: Option<RunningHook> = None;
+    let mut last_event = Instant::now();
+
+    loop {
+        let event = match running.is_some() || ran != values {
+            true => events.recv_timeout(POLL),
+            false => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(event) => {
+                values.update(event);
+                last_event = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if let Some(outcome) = running.as_mut().and_then(|hook| hook.check(timeout)) {
+            log_outcome(outcome, timeout);
+            running = None;
+        }
+        if running.is_none() && ran != values && last_event.elapsed() >= DEBOUNCE {
+            ran = values.clone();
+            match RunningHook::spawn(&mut hook_command(&config, &values)) {
+                Ok(hook) => running = Some(hook),
+                Err(e) => eprintln!("Couldn't run the track change hook: {e}"),
+            }
+        }
+    }
+
+    // Whatever was still running is let finish, up to the timeout
+    if let Some(mut hook) = running {
+        loop {
+            if let Some(outcome) = hook.check(timeout) {
+                log_outcome(outcome, timeout);
+                break;
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use prismriver::State as PrismState;
+    use uuid::Uuid;
+
+    use super::{hook_command, run_track_change_hook, HookOutcome, HookValues, RunningHook};
+    use crate::config::ConfigTrackChangeHook;
+    use crate::music_controller::controller::{
+        NowPlayingChange, PlayerLocation, QueuePosition, TrackChangeReason,
+    };
+    use crate::music_controller::now_playing_file::NowPlayingEvent;
+    use crate::music_storage::library::{Song, Tag};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dmp-hook-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An executable script in `dir` which runs `body`
+    fn script(dir: &Path, body: &str) -> String {
+        let path = dir.join("hook.sh");
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn change(title: &str) -> NowPlayingEvent {
+        let mut song = Song::default();
+        song.set_tag(Tag::Title, title.to_string());
+        song.set_tag(Tag::Artist, String::from("Aimer"));
+        NowPlayingEvent::Song(Box::new(NowPlayingChange {
+            song: Arc::new(song),
+            previous: None,
+            reason: TrackChangeReason::Natural,
+            position: QueuePosition {
+                index: 0,
+                total: 1,
+                location: PlayerLocation::Library,
+            },
+            stream_title: None,
+        }))
+    }
+
+    fn wait_for(path: &Path, expected: &str) {
+        let start = Instant::now();
+        while fs::read_to_string(path).unwrap_or_default() != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Expected {expected:?}, found {:?}",
+                fs::read_to_string(path)
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn arguments_passed_as_they_are() {
+        let dir = temp_dir();
+        let out = dir.join("args.txt");
+        let config = ConfigTrackChangeHook {
+            enabled: true,
+            // Writes the arguments after the first to the file it names, one
+            // per line
+            command: script(&dir, r#"out="$1"; shift; printf '%s\n' "$@" >> "$out""#),
+            args: [
+                out.to_str().unwrap(),
+                "{title}",
+                "{artist} ({state})",
+                "{album}",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..Default::default()
+        };
+
+        let (events, rx) = crossbeam_channel::unbounded();
+        let hook = thread::spawn(move || run_track_change_hook(config, rx));
+        // Only the last of a burst is run, and quotes and `$` in tags are
+        // never read by a shell
+        events.send(change("Brave Shine")).unwrap();
+        events.send(change("Don't \"stop\" $HOME")).unwrap();
+        events
+            .send(NowPlayingEvent::State(PrismState::Playing))
+            .unwrap();
+        let first = "Don't \"stop\" $HOME\nAimer (playing)\n\n";
+        wait_for(&out, first);
+
+        events
+            .send(NowPlayingEvent::State(PrismState::Paused))
+            .unwrap();
+        wait_for(
+            &out,
+            &format!("{first}Don't \"stop\" $HOME\nAimer (paused)\n\n"),
+        );
+        drop(events);
+        hook.join().unwrap();
+    }
+
+    #[test]
+    fn shell_mode_and_timeout() {
+        let dir = temp_dir();
+        let out = dir.join("env.txt");
+        let values = HookValues {
+            title: Some(format!("$(touch {}/pwned)", dir.display())),
+            state: "paused",
+            ..Default::default()
+        };
+        let config = ConfigTrackChangeHook {
+            command: format!(
+                r#"printf '%s %s' "$DMP_TITLE" "$DMP_STATE" > '{}'"#,
+                out.display()
+            ),
+            shell: true,
+            ..Default::default()
+        };
+        let mut hook = RunningHook::spawn(&mut hook_command(&config, &values)).unwrap();
+        let start = Instant::now();
+        let outcome = loop {
+            if let Some(outcome) = hook.check(Duration::from_secs(5)) {
+                break outcome;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(matches!(outcome, HookOutcome::Exited { status, .. } if status.success()));
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            format!("{} paused", values.title.as_deref().unwrap())
+        );
+        assert!(!dir.join("pwned").exists());
+
+        // Anything still running after the timeout is killed
+        let config = ConfigTrackChangeHook {
+            command: script(&dir, "exec sleep 10"),
+            ..Default::default()
+        };
+        let mut hook = RunningHook::spawn(&mut hook_command(&config, &values)).unwrap();
+        let start = Instant::now();
+        let outcome = loop {
+            if let Some(outcome) = hook.check(Duration::from_millis(100)) {
+                break outcome;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(matches!(outcome, HookOutcome::Killed));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn background_program_keeps_pipes_open() {
+        let dir = temp_dir();
+        let config = ConfigTrackChangeHook {
+            command: script(&dir, "sleep 10 &\necho started"),
+            ..Default::default()
+        };
+        let mut hook =
+            RunningHook::spawn(&mut hook_command(&config, &HookValues::default())).unwrap();
+        let start = Instant::now();
+        let outcome = loop {
+            if let Some(outcome) = hook.check(Duration::from_secs(5)) {
+                break outcome;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // The hook ends without waiting for `sleep`, with what it printed
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            outcome,
+            HookOutcome::Exited { status, stdout, .. } if status.success() && stdout == "started"
+        ));
+    }
+}
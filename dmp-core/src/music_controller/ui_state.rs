@@ -0,0 +1,204 @@
+//! Whatever the interface wants back when the player opens again, like the
+//! last view shown and which windows were open. The backend doesn't know
+//! what any of it means, so it's kept as JSON under namespaced keys like
+//! `library.view`, and saved along with the rest of the
+//! [`ControllerState`](super::controller_state::ControllerState).
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// The most the keys and values can take up together, in bytes of JSON
+pub const MAX_UI_STATE_SIZE: usize = 64 * 1024;
+
+/// The longest a key can be
+pub const MAX_UI_KEY_LENGTH: usize = 128;
+
+/// How long a key is kept without being set, so keys the interface stopped
+/// using don't stay around forever
+pub const UI_STATE_MAX_AGE: TimeDelta = TimeDelta::days(90);
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum UiStateError {
+    #[error("'{0}' isn't a namespaced key like 'library.view'")]
+    InvalidKey(String),
+    #[error("The interface state can't be larger than {MAX_UI_STATE_SIZE} bytes")]
+    TooLarge,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct UiEntry {
+    value: Value,
+    /// When the key was last set
+    set_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UiState {
+    entries: BTreeMap<String, UiEntry>,
+}
+
+impl UiState {
+    /// Every key with its value
+    pub fn values(&self) -> BTreeMap<String, Value> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// Sets `key` to `value`, or removes it when `value` is null. Setting a
+    /// key to the value it already has still counts as using it, so it
+    /// isn't pruned. Returns whether the state needs saving, which is when
+    /// the value changed or the key wasn't set for a day.
+    pub fn set(
+        &mut self,
+        key: &str,
+        value: Value,
+        now: DateTime<Utc>,
+    ) -> Result<bool, UiStateError> {
+        if !valid_key(key) {
+            return Err(UiStateError::InvalidKey(key.to_string()));
+        }
+        if value.is_null() {
+            return Ok(self.entries.remove(key).is_some());
+        }
+
+        let old = self
+            .entries
+            .get(key)
+            .map(|entry| entry_size(key, &entry.value));
+        let size = self.size() - old.unwrap_or(0) + entry_size(key, &value);
+        if size > MAX_UI_STATE_SIZE {
+            return Err(UiStateError::TooLarge);
+        }
+
+        let changed = self
+            .entries
+            .get(key)
+            .is_none_or(|old| old.value != value || now - old.set_at > TimeDelta::days(1));
+        self.entries
+            .insert(key.to_string(), UiEntry { value, set_at: now });
+        Ok(changed)
+    }
+
+    /// Removes the keys which haven't been set for [`UI_STATE_MAX_AGE`],
+    /// returning whether any were removed
+    pub fn prune(&mut self, now: DateTime<Utc>) -> bool {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| now - entry.set_at <= UI_STATE_MAX_AGE);
+        self.entries.len() != before
+    }
+
+    fn size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(key, entry)| entry_size(key, &entry.value))
+            .sum()
+    }
+}
+
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.to_string().len()
+}
+
+/// Keys are made of at least two parts separated by dots, each of letters,
+/// digits, `_` and `-`
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_UI_KEY_LENGTH
+        && key.split('.').count() >= 2
+        && key.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+    use serde_json::json;
+
+    use super::{UiState, UiStateError, MAX_UI_STATE_SIZE, UI_STATE_MAX_AGE};
+
+    #[test]
+    fn set_and_remove() {
+        let mut state = UiState::default();
+        let now = Utc::now();
+        assert!(state
+            .set("library.view", json!({ "album": "Blue" }), now)
+            .unwrap());
+        assert!(state.set("windows.miniplayer", json!(true), now).unwrap());
+        assert!(!state.set("windows.miniplayer", json!(true), now).unwrap());
+        let tomorrow = now + TimeDelta::days(2);
+        assert!(state
+            .set("windows.miniplayer", json!(true), tomorrow)
+            .unwrap());
+        assert_eq!(state.get("library.view"), Some(&json!({ "album": "Blue" })));
+        assert_eq!(state.values().len(), 2);
+
+        assert!(state.set("windows.miniplayer", json!(null), now).unwrap());
+        assert!(!state.set("windows.miniplayer", json!(null), now).unwrap());
+        assert_eq!(state.get("windows.miniplayer"), None);
+
+        for key in [
+            "view",
+            "library.",
+            ".view",
+            "library view.x",
+            "library..view",
+        ] {
+            assert_eq!(
+                state.set(key, json!(1), now),
+                Err(UiStateError::InvalidKey(key.to_string()))
+            );
+        }
+        assert!(state
+            .set(&format!("a.{}", "b".repeat(200)), json!(1), now)
+            .is_err());
+    }
+
+    #[test]
+    fn size_cap() {
+        let mut state = UiState::default();
+        let now = Utc::now();
+        let big = "x".repeat(MAX_UI_STATE_SIZE / 2);
+        state.set("search.query", json!(big), now).unwrap();
+        assert_eq!(
+            state.set("search.other", json!(big), now),
+            Err(UiStateError::TooLarge)
+        );
+        // Replacing a value only counts the new one
+        state.set("search.query", json!(big), now).unwrap();
+        assert_eq!(state.get("search.other"), None);
+    }
+
+    #[test]
+    fn prune_old_keys() {
+        let mut state = UiState::default();
+        let start = Utc::now();
+        state.set("old.key", json!(1), start).unwrap();
+        state.set("library.view", json!("Library"), start).unwrap();
+        assert!(!state.prune(start + UI_STATE_MAX_AGE));
+
+        // Setting a key again keeps it, even without changing it
+        let later = start + TimeDelta::days(30);
+        state.set("library.view", json!("Library"), later).unwrap();
+        assert!(state.prune(start + UI_STATE_MAX_AGE + TimeDelta::days(1)));
+        assert_eq!(state.values().keys().collect::<Vec<_>>(), ["library.view"]);
+
+        // Kept as it's written to the state file
+        let read: UiState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(read, state);
+    }
+}
@@ -0,0 +1,355 @@
+//! A small HTTP server serving a remote control page, so the player can be
+//! controlled from another device on the network like a phone
+
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::{IpAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use file_format::FileFormat;
+use futures::executor::block_on;
+use kushi::QueueItemType;
+use parking_lot::{Mutex, RwLock};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use thiserror::Error;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{
+    config::ConfigWebRemote,
+    music_storage::library::{Song, SongPayload},
+};
+
+use super::controller::{ControllerHandle, PlaybackInfo, PlayerError};
+
+const PAGE: &str = include_str!("web_remote.html");
+
+/// How often connected pages are sent the playback status
+const PUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum WebRemoteError {
+    #[error("Couldn't start the web remote: {0}")]
+    Start(String),
+}
+
+/// What the player is doing, as sent to the remote page
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteStatus {
+    pub now_playing: Option<SongPayload>,
+    pub playback: PlaybackInfo,
+}
+
+struct Shared {
+    handle: ControllerHandle,
+    token: String,
+    now_playing: RwLock<Option<Song>>,
+    playback: RwLock<PlaybackInfo>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    /// Whether the remote was stopped, or the player is closing
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed) || self.handle.cancel.is_cancelled()
+    }
+
+    fn status(&self) -> RemoteStatus {
+        RemoteStatus {
+            now_playing: self.now_playing.read().as_ref().map(SongPayload::from),
+            playback: self.playback.read().clone(),
+        }
+    }
+}
+
+/// A running web remote. Clones share the same server, which runs until
+/// [`WebRemote::stop`] is called or the controller shuts down.
+#[derive(Clone)]
+pub struct WebRemote {
+    shared: Arc<Shared>,
+    server: Arc<Server>,
+    port: u16,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl WebRemote {
+    /// Starts serving the remote on every interface, with a new random token
+    /// which every request needs to include
+    pub fn start(
+        handle: ControllerHandle,
+        config: &ConfigWebRemote,
+    ) -> Result<Self, WebRemoteError> {
+        let server = Arc::new(
+            Server::http(("0.0.0.0", config.port))
+                .map_err(|e| WebRemoteError::Start(e.to_string()))?,
+        );
+        let shared = Arc::new(Shared {
+            handle,
+            token: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(TOKEN_LENGTH)
+                .map(char::from)
+                .collect(),
+            now_playing: RwLock::new(None),
+            playback: RwLock::new(PlaybackInfo::default()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let thread = thread::Builder::new()
+            .name("Web Remote".to_string())
+            .spawn({
+                let server = Arc::clone(&server);
+                let shared = Arc::clone(&shared);
+                move || {
+                    // Requests are waited for a little at a time, so the
+                    // controller shutting down is noticed
+                    while !shared.is_stopped() {
+                        let request = match server.recv_timeout(PUSH_INTERVAL) {
+                            Ok(Some(request)) => request,
+                            Ok(None) => continue,
+                            Err(_) => break,
+                        };
+                        // Event streams stay open, so every request gets a thread
+                        let shared = Arc::clone(&shared);
+                        thread::spawn(move || handle_request(&shared, request));
+                    }
+                }
+            })
+            .map_err(|e| WebRemoteError::Start(e.to_string()))?;
+
+        Ok(WebRemote {
+            shared,
+            server,
+            port: config.port,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        })
+    }
+
+    /// The address of the remote page including the token, for showing as
+    /// a link or QR code
+    pub fn url(&self) -> String {
+        let host = match local_ip() {
+            Some(IpAddr::V6(ip)) => format!("[{ip}]"),
+            Some(ip) => ip.to_string(),
+            None => String::from("localhost"),
+        };
+        format!("http://{host}:{}/?token={}", self.port, self.shared.token)
+    }
+
+    pub fn set_now_playing(&self, song: Song) {
+        *self.shared.now_playing.write() = Some(song);
+    }
+
+    pub fn set_playback(&self, info: PlaybackInfo) {
+        *self.shared.playback.write() = info;
+    }
+
+    /// Stops the server and disconnects any open pages
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.server.unblock();
+        if let Some(thread) = self.thread.lock().take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// The address of this machine on the local network, found by asking which
+/// interface would be used to reach the internet. Nothing is sent.
+fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Splits a request URL into its path and decoded query parameters
+fn parse_url(url: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let decode = |value: &str| {
+        urlencoding::decode(&value.replace('+', " "))
+            .map(|value| value.into_owned())
+            .unwrap_or_default()
+    };
+
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect();
+    (path, params)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).unwrap()
+}
+
+fn text(code: u16, body: &str) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(body).with_status_code(code)
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Cursor<Vec<u8>>> {
+    match serde_json::to_string(value) {
+        Ok(body) => {
+            Response::from_string(body).with_header(header("Content-Type", "application/json"))
+        }
+        Err(e) => text(500, &e.to_string()),
+    }
+}
+
+fn done(res: Result<impl Sized, PlayerError>) -> Response<Cursor<Vec<u8>>> {
+    match res {
+        Ok(_) => text(204, ""),
+        Err(e) => text(500, &e.to_string()),
+    }
+}
+
+/// Compares tokens without stopping at the first difference, so how long a
+/// request takes doesn't give away how much of the token it got right
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn handle_request(shared: &Shared, request: Request) {
+    let url = request.url().to_string();
+    let method = request.method().clone();
+    let (path, params) = parse_url(&url);
+    if !params
+        .get("token")
+        .is_some_and(|token| same_token(token, &shared.token))
+    {
+        _ = request.respond(text(401, "Missing or wrong token"));
+        return;
+    }
+
+    let handle = &shared.handle;
+    let response = match (method, path) {
+        (Method::Get, "/") => Response::from_string(PAGE)
+            .with_header(header("Content-Type", "text/html; charset=utf-8")),
+        (Method::Get, "/api/status") => json(&shared.status()),
+        (Method::Get, "/api/queue") => {
+            let queue: Vec<SongPayload> = block_on(handle.queue_get_all())
+                .iter()
+                .filter_map(|item| match &item.item {
+                    QueueItemType::Single(song) => Some(SongPayload::from(&song.song)),
+                    _ => None,
+                })
+                .collect();
+            json(&queue)
+        }
+        (Method::Get, "/api/art") => {
+            let art = shared
+                .now_playing
+                .read()
+                .as_ref()
+                .and_then(|song| song.front_cover().ok().flatten());
+            match art {
+                Some(art) => {
+                    let format = FileFormat::from_bytes(&art);
+                    Response::from_data(art)
+                        .with_header(header("Content-Type", format.media_type()))
+                }
+                None => text(404, "No art"),
+            }
+        }
+        (Method::Post, "/api/play") => done(block_on(handle.play())),
+        (Method::Post, "/api/pause") => done(block_on(handle.pause())),
+        (Method::Post, "/api/next") => done(block_on(handle.next())),
+        (Method::Post, "/api/prev") => done(block_on(handle.prev())),
+        (Method::Post, "/api/volume") => {
+            match params.get("volume").and_then(|v| v.parse::<f32>().ok()) {
+                Some(volume) => {
+                    block_on(handle.set_volume(volume.clamp(0.0, 1.0)));
+                    text(204, "")
+                }
+                None => text(400, "Missing volume"),
+            }
+        }
+        (Method::Get, "/api/events") => return push_events(shared, request),
+        _ => text(404, "Not found"),
+    };
+    _ = request.respond(response);
+}
+
+/// Upgrades a request to a WebSocket, then sends the playback status
+/// whenever it changes until the page goes away or the remote stops
+fn push_events(shared: &Shared, request: Request) {
+    let Some(key) = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.as_str().to_string())
+    else {
+        _ = request.respond(text(400, "Expected a WebSocket"));
+        return;
+    };
+
+    let response = Response::empty(101)
+        .with_header(header("Upgrade", "websocket"))
+        .with_header(header("Connection", "Upgrade"))
+        .with_header(header(
+            "Sec-WebSocket-Accept",
+            &derive_accept_key(key.as_bytes()),
+        ));
+    let mut socket =
+        WebSocket::from_raw_socket(request.upgrade("websocket", response), Role::Server, None);
+
+    let mut last = String::new();
+    while !shared.is_stopped() {
+        let status = serde_json::to_string(&shared.status()).unwrap_or_default();
+        if status != last {
+            if socket.send(Message::Text(status.clone())).is_err() {
+                return;
+            }
+            last = status;
+        }
+        thread::sleep(PUSH_INTERVAL);
+    }
+    _ = socket.close(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_url, same_token};
+
+    #[test]
+    fn parse_urls() {
+        let (path, params) = parse_url("/api/volume?token=abc&volume=0.5");
+        assert_eq!(path, "/api/volume");
+        assert_eq!(params["token"], "abc");
+        assert_eq!(params["volume"], "0.5");
+
+        let (path, params) = parse_url("/");
+        assert_eq!(path, "/");
+        assert!(params.is_empty());
+
+        let (_, params) = parse_url("/?token=a%20b+c&flag");
+        assert_eq!(params["token"], "a b c");
+        assert_eq!(params["flag"], "");
+    }
+
+    #[test]
+    fn tokens_compared() {
+        assert!(same_token("abc123", "abc123"));
+        assert!(!same_token("abc124", "abc123"));
+        assert!(!same_token("abc", "abc123"));
+        assert!(!same_token("", "abc123"));
+    }
+}
@@ -0,0 +1,156 @@
+//! What the album grid shows about each album, worked out once from the
+//! library rather than by the frontend fetching every song to count them.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::library::{AlbumKey, MusicLibrary, Song};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlbumSummary {
+    pub key: AlbumKey,
+    /// How long the whole album plays for
+    pub duration_ms: u64,
+    pub tracks: usize,
+    /// How many tracks are on each disc
+    pub disc_tracks: BTreeMap<u16, usize>,
+    /// The earliest year any of its songs was released
+    pub year: Option<i32>,
+    /// The song whose art is shown for the album, which the art can be
+    /// fetched with
+    pub art: Option<Uuid>,
+}
+
+/// The summaries of every album, made the first time they're needed after
+/// the library changes
+#[derive(Debug, Default)]
+pub struct AlbumIndex {
+    /// The revision of the library the index was made from
+    revision: Option<u64>,
+    albums: Arc<Vec<AlbumSummary>>,
+}
+
+impl AlbumIndex {
+    /// The albums in the library, in the same order as
+    /// [`MusicLibrary::albums`]. `revision` has to change whenever the
+    /// library does, which makes the index again.
+    pub fn albums(&mut self, library: &MusicLibrary, revision: u64) -> Arc<Vec<AlbumSummary>> {
+        if self.revision != Some(revision) {
+            self.albums = Arc::new(Self::index(library));
+            self.revision = Some(revision);
+        }
+        Arc::clone(&self.albums)
+    }
+
+    fn index(library: &MusicLibrary) -> Vec<AlbumSummary> {
+        let songs: BTreeMap<Uuid, &Song> = library.library.iter().map(|s| (s.uuid, s)).collect();
+        library
+            .albums()
+            .into_values()
+            .map(|album| {
+                let mut summary = AlbumSummary {
+                    key: album.key(),
+                    duration_ms: 0,
+                    tracks: album.len(),
+                    disc_tracks: BTreeMap::new(),
+                    year: None,
+                    art: None,
+                };
+                for (disc, tracks) in album.discs() {
+                    summary.disc_tracks.insert(*disc, tracks.len());
+                }
+
+                // Tracks are gone through in order, so the art is the first
+                // track's which has any
+                for track in album.play_order(None) {
+                    let Some(song) = songs.get(track.uuid()) else {
+                        continue;
+                    };
+                    summary.duration_ms += song.duration.as_millis() as u64;
+                    summary.year = match (summary.year, song.year()) {
+                        (Some(year), Some(song)) => Some(year.min(song)),
+                        (year, song) => year.or(song),
+                    };
+                    if summary.art.is_none() && !song.album_art.is_empty() {
+                        summary.art = Some(song.uuid);
+                    }
+                }
+                summary
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::AlbumIndex;
+    use crate::music_storage::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+
+    fn song(album: &str, disc: u16, track: u16, secs: u64, year: &str, art: bool) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(format!(
+                "/music/{album}/{disc}-{track}.flac"
+            )))],
+            duration: Duration::from_secs(secs),
+            album_art: match art {
+                true => vec![AlbumArt::Embedded(0)],
+                false => Vec::new(),
+            },
+            tags: BTreeMap::from([
+                (Tag::Album, album.to_string()),
+                (Tag::AlbumArtist, String::from("Kalafina")),
+                (Tag::Disk, disc.to_string()),
+                (Tag::Track, track.to_string()),
+                (Tag::Key(String::from("Year")), year.to_string()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn totals_from_songs() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song("Seventh Heaven", 1, 2, 241, "2009", false),
+            song("Seventh Heaven", 1, 1, 298, "2009", false),
+            song("Seventh Heaven", 2, 1, 310, "2010", true),
+            song("Red Moon", 1, 1, 200, "2010", true),
+        ];
+        let mut index = AlbumIndex::default();
+
+        let albums = index.albums(&library, 0);
+        assert_eq!(albums.len(), 2);
+        let heaven = albums
+            .iter()
+            .find(|album| album.key.title == "Seventh Heaven")
+            .unwrap();
+        let songs = &library.library[..3];
+        let total: Duration = songs.iter().map(|song| song.duration).sum();
+        assert_eq!(heaven.duration_ms, total.as_millis() as u64);
+        assert_eq!(heaven.tracks, 3);
+        assert_eq!(heaven.disc_tracks, BTreeMap::from([(1, 2), (2, 1)]));
+        assert_eq!(heaven.year, Some(2009));
+        // Only the song on the second disc has art
+        assert_eq!(heaven.art, Some(songs[2].uuid));
+
+        // Changes are only seen once the revision changes
+        library
+            .library
+            .push(song("Red Moon", 1, 2, 100, "2010", false));
+        assert_eq!(index.albums(&library, 0).len(), 2);
+        let albums = index.albums(&library, 1);
+        let moon = albums
+            .iter()
+            .find(|album| album.key.title == "Red Moon")
+            .unwrap();
+        assert_eq!((moon.tracks, moon.duration_ms), (2, 300_000));
+        assert_eq!(moon.art, Some(library.library[3].uuid));
+    }
+}
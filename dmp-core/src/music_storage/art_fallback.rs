@@ -0,0 +1,133 @@
+//! Finding art for songs which have none of their own. Rips often only have
+//! art embedded in the first track, so the art of another song on the same
+//! album is shown before the default image.
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use super::library::{AlbumKey, MusicLibrary, Song, Tag};
+
+impl Song {
+    /// The album the song is on, if it has an album tag
+    pub fn album_key(&self) -> Option<AlbumKey> {
+        Some(AlbumKey {
+            title: self.get_tag(&Tag::Album)?.clone(),
+            artist: self.get_tag(&Tag::AlbumArtist).cloned(),
+        })
+    }
+}
+
+/// Which song's art stands in for each album, made the first time it's
+/// needed after the library changes
+#[derive(Debug, Default)]
+pub struct AlbumArtIndex {
+    /// The revision of the library the index was made from
+    revision: Option<u64>,
+    sources: BTreeMap<AlbumKey, Uuid>,
+}
+
+impl AlbumArtIndex {
+    /// The song whose art is shown for `uuid`, which is the song itself if
+    /// it has any art. `revision` has to change whenever the library does,
+    /// which makes the index again.
+    pub fn art_source<'a>(
+        &mut self,
+        library: &'a MusicLibrary,
+        uuid: &Uuid,
+        revision: u64,
+    ) -> Option<&'a Song> {
+        let (song, _) = library.query_uuid(uuid)?;
+        if !song.album_art.is_empty() {
+            return Some(song);
+        }
+
+        if self.revision != Some(revision) {
+            self.sources = Self::index(library);
+            self.revision = Some(revision);
+        }
+        let source = self.sources.get(&song.album_key()?)?;
+        library.query_uuid(source).map(|(song, _)| song)
+    }
+
+    /// The first song of each album with art, by disc and track number
+    fn index(library: &MusicLibrary) -> BTreeMap<AlbumKey, Uuid> {
+        let mut firsts: BTreeMap<AlbumKey, ((u16, u16), Uuid)> = BTreeMap::new();
+        for song in library.library.iter().filter(|s| !s.album_art.is_empty()) {
+            let Some(key) = song.album_key() else {
+                continue;
+            };
+            let position = (
+                song.disc_number().unwrap_or(1),
+                song.track_number().unwrap_or_default(),
+            );
+            let first = firsts.entry(key).or_insert((position, song.uuid));
+            if position < first.0 {
+                *first = (position, song.uuid);
+            }
+        }
+        firsts
+            .into_iter()
+            .map(|(key, (_, uuid))| (key, uuid))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::AlbumArtIndex;
+    use crate::music_storage::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+
+    fn song(album: &str, track: u16, art: bool) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(format!("{album}/{track}.flac")))],
+            album_art: match art {
+                true => vec![AlbumArt::Embedded(0)],
+                false => Vec::new(),
+            },
+            tags: BTreeMap::from([
+                (Tag::Album, album.to_string()),
+                (Tag::AlbumArtist, String::from("Haru")),
+                (Tag::Track, track.to_string()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn art_from_the_same_album() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song("Seasons", 3, true),
+            song("Seasons", 2, false),
+            song("Seasons", 1, true),
+            song("Lines", 1, false),
+        ];
+        let [third, second, first, lines] = [0, 1, 2, 3].map(|i| library.library[i].uuid);
+        let mut index = AlbumArtIndex::default();
+
+        let source = |index: &mut AlbumArtIndex, library: &MusicLibrary, uuid, revision| {
+            index
+                .art_source(library, &uuid, revision)
+                .map(|song| song.uuid)
+        };
+        // Songs with art of their own show it
+        assert_eq!(source(&mut index, &library, third, 0), Some(third));
+        // The first track with art stands in for the others
+        assert_eq!(source(&mut index, &library, second, 0), Some(first));
+        assert_eq!(source(&mut index, &library, lines, 0), None);
+        assert_eq!(source(&mut index, &library, Uuid::new_v4(), 0), None);
+
+        // Changes are only seen once the revision changes
+        library.library[3].album_art.push(AlbumArt::Embedded(0));
+        library.library.push(song("Lines", 2, false));
+        let lines_two = library.library[4].uuid;
+        assert_eq!(source(&mut index, &library, lines_two, 0), None);
+        assert_eq!(source(&mut index, &library, lines_two, 1), Some(lines));
+    }
+}
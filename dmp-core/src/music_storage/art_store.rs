@@ -0,0 +1,318 @@
+//! Reading album art without going back to the files each time it's shown.
+//! External images are kept in memory, up to a size limit, and the
+//! pictures embedded in a song's file are taken out of it once and saved to
+//! a cache folder, so the whole audio file isn't parsed again.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::SystemTime,
+};
+
+use lofty::file::TaggedFileExt as _;
+use serde::Serialize;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::library::{AlbumArt, Song};
+
+/// How many bytes of images are kept in memory by default
+pub const DEFAULT_ART_CACHE_BYTES: usize = 32 * 1024 * 1024;
+
+/// How well the cache is doing, for debugging
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ArtCacheMetrics {
+    /// Images found in memory
+    pub hits: u64,
+    /// Images which had to be read from disk
+    pub misses: u64,
+    /// Images dropped to make room for others
+    pub evictions: u64,
+    /// Audio files the embedded pictures were taken out of
+    pub extractions: u64,
+    /// How many images are in memory
+    pub cached: usize,
+    /// How many bytes they take up
+    pub cached_bytes: usize,
+}
+
+/// An image read from disk, with what its file looked like then so changes
+/// to it are noticed
+#[derive(Debug)]
+struct CachedImage {
+    path: PathBuf,
+    stamp: FileStamp,
+    data: Arc<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ArtStoreInner {
+    /// The least recently used first
+    images: VecDeque<CachedImage>,
+    max_bytes: usize,
+    /// Where embedded pictures are saved
+    spill_dir: PathBuf,
+    metrics: ArtCacheMetrics,
+}
+
+/// Album art for songs, shared by everything which shows it. Cloning it
+/// gives another handle to the same cache.
+#[derive(Debug, Clone)]
+pub struct ArtStore {
+    inner: Arc<Mutex<ArtStoreInner>>,
+}
+
+impl ArtStore {
+    /// Makes a store which keeps up to `max_bytes` of images in memory and
+    /// saves embedded pictures to `spill_dir`
+    pub fn new(max_bytes: usize, spill_dir: PathBuf) -> Self {
+        ArtStore {
+            inner: Arc::new(Mutex::new(ArtStoreInner {
+                images: VecDeque::new(),
+                max_bytes,
+                spill_dir,
+                metrics: ArtCacheMetrics::default(),
+            })),
+        }
+    }
+
+    /// The store [`Song::album_art`] reads through
+    pub fn shared() -> &'static ArtStore {
+        static SHARED: OnceLock<ArtStore> = OnceLock::new();
+        SHARED.get_or_init(|| {
+            ArtStore::new(
+                DEFAULT_ART_CACHE_BYTES,
+                std::env::temp_dir().join("dango-music-player-art"),
+            )
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ArtStoreInner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Saves embedded pictures to `dir` from now on, like a temporary folder
+    /// which is removed on exit
+    pub fn spill_to(&self, dir: PathBuf) {
+        self.lock().spill_dir = dir;
+    }
+
+    pub fn metrics(&self) -> ArtCacheMetrics {
+        self.lock().metrics
+    }
+
+    /// The data of the `i`th image of the song
+    pub fn resolve(&self, song: &Song, i: usize) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let path = match song.album_art.get(i) {
+            Some(AlbumArt::External(uri)) => uri.path(),
+            Some(AlbumArt::Embedded(j)) => {
+                match self.spilled(&song.primary_uri()?.0.path(), *j)? {
+                    Some(path) => path,
+                    None => return Ok(None),
+                }
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(self.read(&path)?.to_vec()))
+    }
+
+    /// The data of the image to show for the song
+    pub fn front_cover(&self, song: &Song) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match song.front_cover_index() {
+            Some(i) => self.resolve(song, i),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the `j`th picture embedded in the file at `audio` was saved,
+    /// taking them all out of it the first time
+    fn spilled(&self, audio: &Path, j: usize) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let stamp = FileStamp::of(audio)?;
+        let mut hasher = Xxh3::new();
+        audio.hash(&mut hasher);
+        stamp.hash(&mut hasher);
+        let key = format!("{:016x}", hasher.finish());
+
+        let dir = self.lock().spill_dir.clone();
+        let path = |j: usize| dir.join(format!("{key}_{j}"));
+        // An empty marker is left for files with no pictures, so they
+        // aren't read again either
+        let done = dir.join(format!("{key}_done"));
+        if !done.exists() {
+            let file = lofty::read_from_path(audio)?;
+            let pictures = file.primary_tag().map_or(&[][..], |tag| tag.pictures());
+            fs::create_dir_all(&dir)?;
+            for (j, picture) in pictures.iter().enumerate() {
+                fs::write(path(j), picture.data())?;
+            }
+            fs::write(&done, [])?;
+            self.lock().metrics.extractions += 1;
+        }
+
+        let path = path(j);
+        Ok(path.exists().then_some(path))
+    }
+
+    /// The contents of the image at `path`, from memory if it hasn't
+    /// changed since it was read
+    fn read(&self, path: &Path) -> std::io::Result<Arc<Vec<u8>>> {
+        let stamp = FileStamp::of(path)?;
+        {
+            let mut inner = self.lock();
+            if let Some(i) = inner.images.iter().position(|image| image.path == path) {
+                let image = inner.images.remove(i).unwrap();
+                if image.stamp == stamp {
+                    let data = Arc::clone(&image.data);
+                    inner.images.push_back(image);
+                    inner.metrics.hits += 1;
+                    return Ok(data);
+                }
+                inner.metrics.cached_bytes -= image.data.len();
+                inner.metrics.cached = inner.images.len();
+            }
+        }
+
+        let data = Arc::new(fs::read(path)?);
+        let mut inner = self.lock();
+        inner.metrics.misses += 1;
+        inner.insert(CachedImage {
+            path: path.to_path_buf(),
+            stamp,
+            data: Arc::clone(&data),
+        });
+        Ok(data)
+    }
+}
+
+impl ArtStoreInner {
+    /// Keeps the image, dropping the least recently used ones until it
+    /// fits. Images bigger than the whole cache aren't kept.
+    fn insert(&mut self, image: CachedImage) {
+        let len = image.data.len();
+        if len > self.max_bytes {
+            self.metrics.cached = self.images.len();
+            return;
+        }
+        while self.metrics.cached_bytes + len > self.max_bytes {
+            let Some(evicted) = self.images.pop_front() else {
+                break;
+            };
+            self.metrics.cached_bytes -= evicted.data.len();
+            self.metrics.evictions += 1;
+        }
+        self.metrics.cached_bytes += len;
+        self.images.push_back(image);
+        self.metrics.cached = self.images.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use uuid::Uuid;
+
+    use super::{ArtCacheMetrics, ArtStore};
+    use crate::music_storage::library::{AlbumArt, Song, URI};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dmp-art-store-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A song with the images `names` of `len` bytes each next to it
+    fn song(dir: &Path, names: &[&str], len: usize) -> Song {
+        Song {
+            album_art: names
+                .iter()
+                .map(|name| {
+                    let path = dir.join(name);
+                    fs::write(&path, vec![name.as_bytes()[0]; len]).unwrap();
+                    AlbumArt::External(URI::Local(path))
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let dir = temp_dir();
+        let store = ArtStore::new(300, dir.join("spilled"));
+        let song = song(&dir, &["a.jpg", "b.jpg", "c.jpg", "d.jpg"], 100);
+
+        for i in [0, 1, 2] {
+            assert_eq!(store.resolve(&song, i).unwrap().unwrap().len(), 100);
+        }
+        // Reading the first again keeps it over the second
+        assert_eq!(store.resolve(&song, 0).unwrap(), Some(vec![b'a'; 100]));
+        assert_eq!(store.resolve(&song, 3).unwrap(), Some(vec![b'd'; 100]));
+        assert_eq!(
+            store.metrics(),
+            ArtCacheMetrics {
+                hits: 1,
+                misses: 4,
+                evictions: 1,
+                extractions: 0,
+                cached: 3,
+                cached_bytes: 300,
+            }
+        );
+
+        store.resolve(&song, 0).unwrap();
+        store.resolve(&song, 1).unwrap();
+        let metrics = store.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (2, 5));
+        assert_eq!(store.resolve(&song, 4).unwrap(), None);
+    }
+
+    #[test]
+    fn changed_and_oversized_images() {
+        let dir = temp_dir();
+        let store = ArtStore::new(150, dir.join("spilled"));
+        let mut song = song(&dir, &["small.jpg"], 100);
+        store.resolve(&song, 0).unwrap();
+
+        // Images are read again once their file changes
+        let path = dir.join("small.jpg");
+        fs::write(&path, vec![0; 120]).unwrap();
+        assert_eq!(store.resolve(&song, 0).unwrap(), Some(vec![0; 120]));
+        assert_eq!(store.metrics().cached_bytes, 120);
+
+        // One too big for the cache is read without pushing the others out
+        let big = dir.join("big.jpg");
+        fs::write(&big, vec![1; 200]).unwrap();
+        song.album_art.push(AlbumArt::External(URI::Local(big)));
+        for _ in 0..2 {
+            assert_eq!(store.resolve(&song, 1).unwrap().unwrap().len(), 200);
+        }
+        let metrics = store.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (0, 4));
+        assert_eq!((metrics.evictions, metrics.cached), (0, 1));
+    }
+}
@@ -0,0 +1,286 @@
+//! Playlists which are derived from the library contents and statistics,
+//! regenerated in place whenever the library changes
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use itertools::Itertools;
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag};
+use super::playlist::{Playlist, PlaylistFolder, PlaylistFolderItem};
+
+/// The name of the folder which holds every auto generated playlist
+pub const AUTO_PLAYLIST_FOLDER: &str = "Auto Playlists";
+
+impl MusicLibrary {
+    /// Creates or refreshes the auto generated playlists, returning the
+    /// uuid and title of each of them
+    ///
+    /// Playlists which already exist are matched by title and have their
+    /// tracks replaced, so regenerating never produces duplicates.
+    pub fn generate_auto_playlists(
+        &mut self,
+        track_limit: usize,
+        top_genres: usize,
+    ) -> Vec<(Uuid, String)> {
+        let generated = auto_playlist_contents(&self.library, track_limit, top_genres);
+
+        let folder = match self.auto_playlist_folder_mut() {
+            Some(folder) => folder,
+            None => {
+                self.playlists
+                    .items
+                    .push(PlaylistFolderItem::Folder(PlaylistFolder {
+                        name: AUTO_PLAYLIST_FOLDER.to_string(),
+                        items: Vec::new(),
+                    }));
+                self.auto_playlist_folder_mut().unwrap()
+            }
+        };
+
+        // Remove any stale lists, like a genre which fell out of the top
+        folder.items.retain(|item| match item {
+            PlaylistFolderItem::List(list) if list.auto_generated => {
+                generated.iter().any(|(title, _)| title == &list.title)
+            }
+            _ => true,
+        });
+
+        let mut lists = Vec::new();
+        for (title, tracks) in generated {
+            let existing = folder.items.iter_mut().find_map(|item| match item {
+                PlaylistFolderItem::List(list) if list.auto_generated && list.title == title => {
+                    Some(list)
+                }
+                _ => None,
+            });
+
+            match existing {
+                Some(list) => {
+                    list.set_tracks(tracks);
+                    lists.push((list.uuid, list.title.clone()));
+                }
+                None => {
+                    let mut list = Playlist {
+                        title,
+                        auto_generated: true,
+                        ..Default::default()
+                    };
+                    list.set_tracks(tracks);
+                    lists.push((list.uuid, list.title.clone()));
+                    folder.items.push(PlaylistFolderItem::List(list));
+                }
+            }
+        }
+
+        lists
+    }
+
+    /// Converts an auto generated playlist into a normal editable one,
+    /// moving it out of the auto playlist folder so refreshing leaves it alone
+    pub fn pin_auto_playlist(&mut self, uuid: &Uuid) -> Result<(), Box<dyn Error>> {
+        let folder = self
+            .auto_playlist_folder_mut()
+            .ok_or("There are no auto generated playlists")?;
+
+        let index = folder
+            .items
+            .iter()
+            .position(|item| matches!(item, PlaylistFolderItem::List(list) if &list.uuid == uuid))
+            .ok_or("Playlist is not an auto generated playlist")?;
+
+        let PlaylistFolderItem::List(mut list) = folder.items.remove(index) else {
+            unreachable!()
+        };
+        list.auto_generated = false;
+        self.playlists.items.push(PlaylistFolderItem::List(list));
+
+        Ok(())
+    }
+
+    fn auto_playlist_folder_mut(&mut self) -> Option<&mut PlaylistFolder> {
+        self.playlists.items.iter_mut().find_map(|item| match item {
+            PlaylistFolderItem::Folder(folder) if folder.name == AUTO_PLAYLIST_FOLDER => {
+                Some(folder)
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Computes the title and tracks of every auto playlist for a set of songs
+fn auto_playlist_contents(
+    songs: &[Song],
+    track_limit: usize,
+    top_genres: usize,
+) -> Vec<(String, Vec<Uuid>)> {
+    let mut lists = Vec::new();
+
+    // Genres, most common first
+    let mut genres: BTreeMap<&str, Vec<Uuid>> = BTreeMap::new();
+    for song in songs {
+        if let Some(genre) = song.get_tag(&Tag::Genre) {
+            let genre = genre.trim();
+            if !genre.is_empty() {
+                genres.entry(genre).or_default().push(song.uuid);
+            }
+        }
+    }
+    for (genre, tracks) in genres
+        .into_iter()
+        .sorted_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)))
+        .take(top_genres)
+    {
+        lists.push((genre.to_string(), limit(tracks, track_limit)));
+    }
+
+    // Decades, oldest first
+    let mut decades: BTreeMap<i32, Vec<Uuid>> = BTreeMap::new();
+    for song in songs {
+        if let Some(year) = song.year() {
+            decades
+                .entry(year - year.rem_euclid(10))
+                .or_default()
+                .push(song.uuid);
+        }
+    }
+    for (decade, tracks) in decades {
+        lists.push((format!("{decade}s"), limit(tracks, track_limit)));
+    }
+
+    let most_played = songs
+        .iter()
+        .filter(|song| song.plays > 0)
+        .sorted_by(|a, b| b.plays.cmp(&a.plays))
+        .map(|song| song.uuid)
+        .collect_vec();
+    lists.push(("Most Played".to_string(), limit(most_played, track_limit)));
+
+    let recently_added = songs
+        .iter()
+        .filter(|song| song.date_added.is_some())
+        .sorted_by(|a, b| b.date_added.cmp(&a.date_added))
+        .map(|song| song.uuid)
+        .collect_vec();
+    lists.push((
+        "Recently Added".to_string(),
+        limit(recently_added, track_limit),
+    ));
+
+    let never_played = songs
+        .iter()
+        .filter(|song| song.plays == 0)
+        .map(|song| song.uuid)
+        .collect_vec();
+    lists.push(("Never Played".to_string(), limit(never_played, track_limit)));
+
+    lists
+}
+
+fn limit(mut tracks: Vec<Uuid>, track_limit: usize) -> Vec<Uuid> {
+    tracks.truncate(track_limit);
+    tracks
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use uuid::Uuid;
+
+    use super::AUTO_PLAYLIST_FOLDER;
+    use crate::music_storage::{
+        library::{MusicLibrary, Song, Tag},
+        playlist::PlaylistFolderItem,
+    };
+
+    fn song(genre: &str, year: &str, plays: i32) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            plays,
+            date_added: Some(chrono::Utc::now()),
+            tags: BTreeMap::from([
+                (Tag::Genre, genre.to_string()),
+                (Tag::Key("Year".to_string()), year.to_string()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn library() -> MusicLibrary {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("Rock", "1994", 3),
+            song("Rock", "1999-04-01", 0),
+            song("Jazz", "1961", 12),
+            song("Pop", "2004", 0),
+        ];
+        lib
+    }
+
+    #[test]
+    fn regenerate_is_idempotent() {
+        let mut lib = library();
+        let first = lib.generate_auto_playlists(100, 2);
+        let second = lib.generate_auto_playlists(100, 2);
+        assert_eq!(first, second);
+
+        let lists = lib.playlists.lists_recursive();
+        assert_eq!(lists.len(), first.len());
+        assert!(lists.iter().all(|list| list.auto_generated));
+
+        let titles = lists
+            .iter()
+            .map(|list| list.title.as_str())
+            .collect::<Vec<_>>();
+        assert!(titles.contains(&"Rock"));
+        assert!(titles.contains(&"1990s"));
+        assert!(titles.contains(&"1960s"));
+
+        let rock = lists.iter().find(|list| list.title == "Rock").unwrap();
+        assert_eq!(rock.tracks.len(), 2);
+    }
+
+    #[test]
+    fn pinned_playlists_survive_refresh() {
+        let mut lib = library();
+        let lists = lib.generate_auto_playlists(100, 2);
+        let (uuid, title) = lists.iter().find(|(_, t)| t == "Most Played").unwrap();
+        lib.pin_auto_playlist(uuid).unwrap();
+
+        let pinned = lib.query_playlist_uuid(uuid).unwrap();
+        assert!(!pinned.auto_generated);
+        assert!(matches!(
+            lib.playlists.items.last(),
+            Some(PlaylistFolderItem::List(list)) if &list.uuid == uuid
+        ));
+
+        // Refreshing creates a fresh auto list instead of touching the pinned one
+        let refreshed = lib.generate_auto_playlists(100, 2);
+        let (new_uuid, _) = refreshed.iter().find(|(_, t)| t == title).unwrap();
+        assert_ne!(new_uuid, uuid);
+        assert!(lib.query_playlist_uuid(uuid).is_some());
+
+        let folder_count = lib
+            .playlists
+            .items
+            .iter()
+            .filter(|item| {
+                matches!(item, PlaylistFolderItem::Folder(f) if f.name == AUTO_PLAYLIST_FOLDER)
+            })
+            .count();
+        assert_eq!(folder_count, 1);
+    }
+
+    #[test]
+    fn track_limit_caps_lists() {
+        let mut lib = library();
+        lib.generate_auto_playlists(1, 5);
+        assert!(lib
+            .playlists
+            .lists_recursive()
+            .iter()
+            .all(|list| list.tracks.len() <= 1));
+    }
+}
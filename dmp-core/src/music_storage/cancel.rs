@@ -0,0 +1,107 @@
+//! Stopping long library jobs, like scans, early. Jobs check the token
+//! between files and stop at the next one once it's cancelled, leaving the
+//! library as it was after the last file so it can be saved.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+struct TokenInner {
+    cancelled: AtomicBool,
+    /// How many jobs are running
+    jobs: Mutex<usize>,
+    jobs_done: Condvar,
+}
+
+/// Shared by every long job, so they can all be stopped at once, like when
+/// the player is closing
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<TokenInner>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every job to stop at its next file. This can't be undone.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Marks a job as running until the returned guard is dropped, so
+    /// [`CancelToken::wait`] knows to wait for it
+    pub fn job(&self) -> JobGuard {
+        *self.inner.jobs.lock().unwrap() += 1;
+        JobGuard {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Waits for up to `timeout` for the running jobs to finish, returning
+    /// whether they all did
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let jobs = self.inner.jobs.lock().unwrap();
+        let (jobs, _) = self
+            .inner
+            .jobs_done
+            .wait_timeout_while(jobs, timeout, |jobs| *jobs > 0)
+            .unwrap();
+        *jobs == 0
+    }
+}
+
+/// A running job, see [`CancelToken::job`]
+#[derive(Debug)]
+pub struct JobGuard {
+    inner: Arc<TokenInner>,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        let mut jobs = self.inner.jobs.lock().unwrap();
+        *jobs -= 1;
+        if *jobs == 0 {
+            self.inner.jobs_done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::CancelToken;
+
+    #[test]
+    fn waits_for_jobs() {
+        let token = CancelToken::new();
+        assert!(token.wait(Duration::ZERO));
+
+        let job = token.job();
+        let worker = {
+            let token = token.clone();
+            thread::spawn(move || {
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                drop(job);
+            })
+        };
+        assert!(!token.wait(Duration::from_millis(50)));
+
+        token.cancel();
+        assert!(token.wait(Duration::from_secs(5)));
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+//! Telling which songs are explicit, for filtered mode to keep them from
+//! being queued up automatically or found by searching
+
+use uuid::Uuid;
+
+use super::library::{InternalTag, MusicLibrary, Song, Tag};
+
+/// Tags which mark songs as explicit. iTunes uses 1 for explicit, 2 for
+/// clean, and 4 for explicit in older files.
+const ADVISORY_KEYS: [&str; 3] = ["ParentalAdvisory", "ITUNESADVISORY", "RTNG"];
+
+impl Song {
+    /// Whether the song is explicit, going by how it was marked by hand or
+    /// else by its advisory tags
+    pub fn is_explicit(&self) -> bool {
+        if let Some(explicit) = self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::Explicit(explicit) => Some(*explicit),
+            _ => None,
+        }) {
+            return explicit;
+        }
+
+        self.tags.iter().any(|(tag, value)| {
+            let Tag::Key(key) = tag else {
+                return false;
+            };
+            let value = value.trim();
+            if ADVISORY_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                matches!(value, "1" | "4") || value.eq_ignore_ascii_case("explicit")
+            } else if key.eq_ignore_ascii_case("EXPLICIT") {
+                ["1", "true", "yes"]
+                    .iter()
+                    .any(|v| v.eq_ignore_ascii_case(value))
+            } else {
+                false
+            }
+        })
+    }
+}
+
+impl MusicLibrary {
+    /// Marks a song as explicit or not, overriding its tags, or goes back
+    /// to its tags with `None`
+    pub fn set_explicit(&mut self, uuid: &Uuid, explicit: Option<bool>) -> Result<(), String> {
+        let Some((_, i)) = self.query_uuid(uuid) else {
+            return Err("Song not found".to_string());
+        };
+        let tags = &mut self.library[i].internal_tags;
+        tags.retain(|tag| !matches!(tag, InternalTag::Explicit(_)));
+        if let Some(explicit) = explicit {
+            tags.push(InternalTag::Explicit(explicit));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::music_storage::library::{MusicLibrary, Song, Tag};
+
+    fn song(tags: &[(&str, &str)]) -> Song {
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            ..Default::default()
+        };
+        for (key, value) in tags {
+            song.set_tag(Tag::Key(key.to_string()), value.to_string());
+        }
+        song
+    }
+
+    #[test]
+    fn explicit_from_tags() {
+        assert!(song(&[("ITUNESADVISORY", "1")]).is_explicit());
+        assert!(song(&[("ParentalAdvisory", "4")]).is_explicit());
+        assert!(song(&[("EXPLICIT", "True")]).is_explicit());
+        // Clean versions are marked too
+        assert!(!song(&[("ITUNESADVISORY", "2")]).is_explicit());
+        assert!(!song(&[("ITUNESADVISORY", "0")]).is_explicit());
+        assert!(!song(&[("Comment", "1")]).is_explicit());
+        assert!(!song(&[]).is_explicit());
+    }
+
+    #[test]
+    fn marked_by_hand() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let tagged = song(&[("ITUNESADVISORY", "1")]);
+        let untagged = song(&[]);
+        let (tagged_uuid, untagged_uuid) = (tagged.uuid, untagged.uuid);
+        lib.library.extend([tagged, untagged]);
+
+        lib.set_explicit(&tagged_uuid, Some(false)).unwrap();
+        lib.set_explicit(&untagged_uuid, Some(true)).unwrap();
+        assert!(!lib.query_uuid(&tagged_uuid).unwrap().0.is_explicit());
+        assert!(lib.query_uuid(&untagged_uuid).unwrap().0.is_explicit());
+
+        // Marking again replaces the mark, and clearing it goes back to the tags
+        lib.set_explicit(&untagged_uuid, Some(false)).unwrap();
+        assert!(!lib.query_uuid(&untagged_uuid).unwrap().0.is_explicit());
+        lib.set_explicit(&tagged_uuid, None).unwrap();
+        assert!(lib.query_uuid(&tagged_uuid).unwrap().0.is_explicit());
+        assert_eq!(lib.library[0].internal_tags, []);
+
+        assert!(lib.set_explicit(&Uuid::new_v4(), Some(true)).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{types::ValueRef, Connection, OpenFlags, Row};
+use uuid::Uuid;
+
+use crate::music_storage::db_reader::extern_library::{
+    file_url_to_path, ExternalLibrary, ImportError, ImportedPlaylist,
+};
+use crate::music_storage::library::{Song, Tag, URI};
+
+/// The columns of the `songs` table which are read as tags
+const TAG_COLUMNS: [&str; 10] = [
+    "title",
+    "album",
+    "artist",
+    "albumartist",
+    "composer",
+    "genre",
+    "comment",
+    "track",
+    "disc",
+    "year",
+];
+
+/// Clementine and Strawberry store the same things under different names
+struct Columns {
+    location: &'static str,
+    library_id: &'static str,
+}
+
+impl Columns {
+    const CLEMENTINE: Self = Columns {
+        location: "filename",
+        library_id: "library_id",
+    };
+    const STRAWBERRY: Self = Columns {
+        location: "url",
+        library_id: "collection_id",
+    };
+
+    fn of(conn: &Connection) -> Self {
+        match conn.prepare("SELECT url FROM songs LIMIT 0") {
+            Ok(_) => Self::STRAWBERRY,
+            Err(_) => Self::CLEMENTINE,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ClementineLibrary {
+    tracks: Vec<ClementineSong>,
+    playlists: Vec<ImportedPlaylist>,
+}
+
+/// A row of the `songs` table. Numbers which are unknown are stored as -1.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClementineSong {
+    pub location: String,
+    pub tags: BTreeMap<Tag, String>,
+    /// In nanoseconds
+    pub length: i64,
+    pub plays: i64,
+    pub skips: i64,
+    /// Between 0 and 1
+    pub rating: f64,
+    pub last_played: i64,
+    pub date_added: i64,
+    pub date_modified: i64,
+}
+
+/// Reads a column which might be text or, in older databases, a blob
+fn text(row: &Row, column: &str) -> rusqlite::Result<Option<String>> {
+    Ok(match row.get_ref(column)? {
+        ValueRef::Text(text) | ValueRef::Blob(text) => {
+            Some(String::from_utf8_lossy(text).into_owned())
+        }
+        _ => None,
+    })
+}
+
+fn timestamp(secs: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0).filter(|_| secs > 0)
+}
+
+fn to_tag(column: &str) -> Tag {
+    match column {
+        "title" => Tag::Title,
+        "album" => Tag::Album,
+        "artist" => Tag::Artist,
+        "albumartist" => Tag::AlbumArtist,
+        "genre" => Tag::Genre,
+        "comment" => Tag::Comment,
+        "track" => Tag::Track,
+        "disc" => Tag::Disk,
+        "composer" => Tag::Key(String::from("Composer")),
+        _ => Tag::Key(String::from("Year")),
+    }
+}
+
+impl ClementineLibrary {
+    /// Reads the songs and playlists from a Clementine or Strawberry
+    /// database, without changing it
+    pub fn read(path: &Path) -> Result<Self, ImportError> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let columns = Columns::of(&conn);
+
+        let mut statement = conn.prepare(&format!(
+            "SELECT * FROM songs WHERE {} IS NOT NULL ORDER BY ROWID",
+            columns.location
+        ))?;
+        let tracks = statement
+            .query_map([], |row| {
+                let mut tags = BTreeMap::new();
+                for column in TAG_COLUMNS {
+                    let value = match row.get_ref(column)? {
+                        ValueRef::Integer(n) if n > 0 => n.to_string(),
+                        ValueRef::Text(text) if !text.is_empty() => {
+                            String::from_utf8_lossy(text).into_owned()
+                        }
+                        _ => continue,
+                    };
+                    tags.insert(to_tag(column), value);
+                }
+
+                let number = |column| row.get::<_, Option<i64>>(column).map(|n| n.unwrap_or(-1));
+                Ok(ClementineSong {
+                    location: text(row, columns.location)?.unwrap_or_default(),
+                    tags,
+                    length: number("length")?,
+                    plays: number("playcount")?,
+                    skips: number("skipcount")?,
+                    rating: row.get::<_, Option<f64>>("rating")?.unwrap_or(-1.0),
+                    last_played: number("lastplayed")?,
+                    date_added: number("ctime")?,
+                    date_modified: number("mtime")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Songs from the library are only linked to by id, other files keep
+        // their own location
+        let mut statement = conn.prepare(&format!(
+            "SELECT playlists.name AS name, COALESCE(songs.{location}, items.{location}) AS location
+            FROM playlists
+            JOIN playlist_items AS items ON items.playlist = playlists.ROWID
+            LEFT JOIN songs ON songs.ROWID = items.{library_id}
+            ORDER BY playlists.ROWID, items.ROWID",
+            location = columns.location,
+            library_id = columns.library_id,
+        ))?;
+        let mut playlists: Vec<ImportedPlaylist> = Vec::new();
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let title: String = row.get("name")?;
+            let path = text(row, "location")?.and_then(|url| file_url_to_path(&url));
+            if playlists.last().is_none_or(|list| list.title != title) {
+                playlists.push(ImportedPlaylist {
+                    title,
+                    paths: Vec::new(),
+                });
+            }
+            playlists.last_mut().unwrap().paths.extend(path);
+        }
+
+        Ok(ClementineLibrary { tracks, playlists })
+    }
+
+    pub fn tracks(self) -> Vec<ClementineSong> {
+        self.tracks
+    }
+}
+
+impl ExternalLibrary for ClementineLibrary {
+    fn from_file(file: &Path) -> Self {
+        Self::read(file).unwrap()
+    }
+
+    fn to_songs(&self) -> Vec<Song> {
+        self.tracks
+            .iter()
+            .filter_map(|track| {
+                let path: PathBuf = file_url_to_path(&track.location)?;
+                let duration = Duration::from_nanos(track.length.max(0) as u64);
+                let plays = track.plays.max(0) as i32;
+                Some(Song {
+                    location: vec![URI::Local(path)],
+                    uuid: Uuid::new_v4(),
+                    plays,
+                    skips: track.skips.max(0) as i32,
+                    // Out of 1, where the library rates out of 100
+                    rating: (track.rating > 0.0)
+                        .then(|| (track.rating.min(1.0) * 100.0).round() as u8),
+                    duration,
+                    play_time: duration * plays as u32,
+                    last_played: timestamp(track.last_played),
+                    date_added: timestamp(track.date_added),
+                    date_modified: timestamp(track.date_modified),
+                    tags: track.tags.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn to_playlists(&self) -> Vec<ImportedPlaylist> {
+        self.playlists.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::music_storage::{
+        db_reader::extern_library::{ExternalLibrary, ImportedPlaylist},
+        library::Tag,
+    };
+
+    use super::ClementineLibrary;
+
+    #[test]
+    fn read_fixture() {
+        let library = ClementineLibrary::read(Path::new("test-data/clementine.db")).unwrap();
+        let songs = library.to_songs();
+        assert_eq!(songs.len(), 3);
+
+        let song = &songs[0];
+        assert_eq!(
+            song.location[0].path(),
+            PathBuf::from("/music/Band/First Album/01 Opening.flac")
+        );
+        assert_eq!(song.get_tag(&Tag::Title).unwrap(), "Opening");
+        assert_eq!(song.get_tag(&Tag::Track).unwrap(), "1");
+        assert_eq!(song.year(), Some(2019));
+        assert_eq!(song.plays, 12);
+        assert_eq!(song.skips, 2);
+        assert_eq!(song.rating, Some(80));
+        assert_eq!(song.duration.as_secs(), 215);
+        assert_eq!(song.last_played.unwrap().timestamp(), 1650000000);
+
+        // Unknown numbers are left out rather than read as -1
+        assert_eq!(songs[1].rating, None);
+        assert_eq!(songs[1].last_played, None);
+        assert_eq!(songs[1].get_tag(&Tag::Disk), None);
+
+        assert_eq!(
+            library.to_playlists(),
+            [ImportedPlaylist {
+                title: String::from("Road Trip"),
+                paths: vec![
+                    PathBuf::from("/music/Band/First Album/02 Second Song.mp3"),
+                    PathBuf::from("/music/Other/Loose Track.ogg"),
+                    PathBuf::from("/music/Band/First Album/01 Opening.flac"),
+                ],
+            }]
+        );
+    }
+}
@@ -1,6 +1,23 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
 
-use crate::music_storage::library::Song;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use urlencoding::decode;
+
+use crate::music_storage::{
+    library::{MusicLibrary, Song, URI},
+    playlist::{Playlist, PlaylistFolderItem},
+    utils::normalize_path,
+};
+
+use super::{
+    clementine::reader::ClementineLibrary, itunes::reader::ITunesLibrary,
+    rhythmbox::reader::RhythmboxLibrary,
+};
 
 pub trait ExternalLibrary {
     fn from_file(file: &Path) -> Self;
@@ -8,4 +25,272 @@ pub trait ExternalLibrary {
         unimplemented!();
     }
     fn to_songs(&self) -> Vec<Song>;
+    fn to_playlists(&self) -> Vec<ImportedPlaylist> {
+        Vec::new()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("Couldn't read the library file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Couldn't parse the library file: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("Couldn't read the library database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Couldn't parse the iTunes library")]
+    ITunes,
+}
+
+/// The players whose libraries can be imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+pub enum ExternalSource {
+    /// An `iTunes Library.xml` export
+    ITunes,
+    /// Rhythmbox's `rhythmdb.xml`, along with the `playlists.xml` next to it
+    Rhythmbox,
+    /// The database of Clementine or its fork Strawberry
+    Clementine,
+}
+
+impl ExternalSource {
+    /// Reads the songs and playlists from another player's library
+    pub fn read(&self, path: &Path) -> Result<ExternalImport, ImportError> {
+        let (songs, playlists) = match self {
+            ExternalSource::ITunes => {
+                if !path.try_exists()? {
+                    return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+                }
+                // The iTunes reader panics on anything it doesn't expect
+                let library = catch_unwind(AssertUnwindSafe(|| ITunesLibrary::from_file(path)))
+                    .map_err(|_| ImportError::ITunes)?;
+                (library.to_songs(), library.to_playlists())
+            }
+            ExternalSource::Rhythmbox => {
+                let library = RhythmboxLibrary::read(path)?;
+                (library.to_songs(), library.to_playlists())
+            }
+            ExternalSource::Clementine => {
+                let library = ClementineLibrary::read(path)?;
+                (library.to_songs(), library.to_playlists())
+            }
+        };
+        Ok(ExternalImport { songs, playlists })
+    }
+}
+
+/// A playlist from another player, which refers to its songs by path
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportedPlaylist {
+    pub title: String,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Everything read from another player's library
+#[derive(Debug, Clone, Default)]
+pub struct ExternalImport {
+    pub songs: Vec<Song>,
+    pub playlists: Vec<ImportedPlaylist>,
+}
+
+/// What importing another player's library changed
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ImportSummary {
+    /// Songs which weren't in the library before
+    pub added: usize,
+    /// Songs already in the library which had their stats merged
+    pub merged: usize,
+    /// Songs whose files couldn't be found, which were skipped
+    pub missing: Vec<PathBuf>,
+    pub playlists: usize,
+}
+
+/// Turns a `file://` URL into a path, returning `None` for any other kind
+/// of location
+pub fn file_url_to_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?;
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    let path = decode(path).ok()?;
+
+    // Windows paths are written like `/C:/Music`
+    let path = match path.as_bytes() {
+        [b'/', _, b':', ..] if cfg!(windows) => &path[1..],
+        _ => &path,
+    };
+    Some(PathBuf::from(path))
+}
+
+/// Keeps the more complete of a song's stats in the library and in another
+/// player, so importing the same library twice changes nothing
+fn merge_stats(song: &mut Song, imported: &Song) {
+    song.plays = song.plays.max(imported.plays);
+    song.skips = song.skips.max(imported.skips);
+    song.play_time = song.play_time.max(imported.play_time);
+    song.last_played = song.last_played.max(imported.last_played);
+    song.favorited |= imported.favorited;
+    if song.rating.is_none() {
+        song.rating = imported.rating;
+    }
+    if song.date_added.is_none() {
+        song.date_added = imported.date_added;
+    }
+}
+
+impl MusicLibrary {
+    /// Adds the songs and playlists from another player. Songs are matched
+    /// to the ones already in the library by path, and have their stats
+    /// merged instead of being added again. Playlists named the same as one
+    /// in the library are left alone.
+    pub fn import_external(&mut self, import: ExternalImport) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+        let mut by_path: HashMap<PathBuf, usize> = self
+            .library
+            .iter()
+            .enumerate()
+            .flat_map(|(i, song)| {
+                song.location.iter().filter_map(move |uri| match uri {
+                    URI::Local(path) => Some((normalize_path(path), i)),
+                    _ => None,
+                })
+            })
+            .collect();
+
+        for imported in import.songs {
+            let Some(URI::Local(path)) = imported.location.first() else {
+                continue;
+            };
+            let path = normalize_path(path);
+
+            if let Some(&i) = by_path.get(&path) {
+                merge_stats(&mut self.library[i], &imported);
+                summary.merged += 1;
+                continue;
+            }
+            if !path.exists() {
+                summary.missing.push(path);
+                continue;
+            }
+
+            // The file's own tags are more trustworthy than the other player's
+            let song = match Song::from_file(&path) {
+                Ok(mut song) => {
+                    merge_stats(&mut song, &imported);
+                    song
+                }
+                Err(_) => imported,
+            };
+            by_path.insert(path, self.library.len());
+            self.library.push(song);
+            summary.added += 1;
+        }
+
+        let titles: Vec<String> = self
+            .playlists
+            .lists_recursive()
+            .into_iter()
+            .map(|list| list.title.clone())
+            .collect();
+        for imported in import.playlists {
+            if titles.contains(&imported.title) {
+                continue;
+            }
+
+            let mut playlist = Playlist::new();
+            playlist.title = imported.title;
+            playlist.set_tracks(
+                imported
+                    .paths
+                    .iter()
+                    .filter_map(|path| by_path.get(&normalize_path(path)))
+                    .map(|&i| self.library[i].uuid)
+                    .collect(),
+            );
+            self.push_playlist(PlaylistFolderItem::List(playlist));
+            summary.playlists += 1;
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{file_url_to_path, ExternalImport, ImportedPlaylist};
+    use crate::music_storage::library::{MusicLibrary, Song, URI};
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            file_url_to_path("file:///home/user/My%20Music/a.flac"),
+            Some(PathBuf::from("/home/user/My Music/a.flac"))
+        );
+        assert_eq!(
+            file_url_to_path("file://localhost/music/a.mp3"),
+            Some(PathBuf::from("/music/a.mp3"))
+        );
+        assert_eq!(file_url_to_path("http://example.com/stream"), None);
+    }
+
+    #[test]
+    fn merge_by_path() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let existing_path = dir.join("existing.flac");
+        let new_path = dir.join("new.flac");
+        fs::write(&existing_path, []).unwrap();
+        fs::write(&new_path, []).unwrap();
+
+        let song = |path: &PathBuf, plays: i32, rating: Option<u8>| Song {
+            location: vec![URI::Local(path.clone())],
+            uuid: Uuid::new_v4(),
+            plays,
+            rating,
+            ..Default::default()
+        };
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library.push(song(&existing_path, 10, Some(80)));
+        let existing = library.library[0].uuid;
+
+        let import = ExternalImport {
+            songs: vec![
+                song(&existing_path, 4, Some(20)),
+                song(&new_path, 3, Some(60)),
+                song(&dir.join("missing.flac"), 1, None),
+            ],
+            playlists: vec![ImportedPlaylist {
+                title: String::from("Imported"),
+                paths: vec![new_path.clone(), existing_path.clone()],
+            }],
+        };
+        let summary = library.import_external(import.clone());
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.merged, 1);
+        assert_eq!(summary.missing, [dir.join("missing.flac")]);
+        assert_eq!(summary.playlists, 1);
+
+        // The library's own stats win when they're more complete
+        assert_eq!(library.library.len(), 2);
+        assert_eq!(library.library[0].plays, 10);
+        assert_eq!(library.library[0].rating, Some(80));
+        assert_eq!(library.library[1].plays, 3);
+
+        let playlist = library.playlists.lists_recursive()[0].clone();
+        assert_eq!(playlist.tracks(), [library.library[1].uuid, existing]);
+
+        // Importing again doesn't add anything
+        let summary = library.import_external(import);
+        assert_eq!(
+            (summary.added, summary.merged, summary.playlists),
+            (0, 2, 0)
+        );
+        assert_eq!(library.library.len(), 2);
+        assert_eq!(library.library[1].plays, 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -190,9 +190,15 @@ impl FoobarPlaylistTrack {
             duration: self.duration,
             play_time: Duration::from_secs(0),
             last_played: None,
+            first_played: None,
             date_added: None,
             date_modified: None,
             album_art: Vec::new(),
+            art_metadata: Vec::new(),
+            preferred_art: None,
+            trim: None,
+            silence: None,
+            file_hash: None,
             tags: BTreeMap::new(),
             internal_tags,
         }
@@ -192,9 +192,15 @@ impl ExternalLibrary for ITunesLibrary {
                 duration: dur,
                 play_time: play_time_,
                 last_played: track.last_played,
+                first_played: None,
                 date_added: track.date_added,
                 date_modified: track.date_modified,
                 album_art: get_art(Path::new(&loc)).unwrap_or_default(),
+                art_metadata: Vec::new(),
+                preferred_art: None,
+                trim: None,
+                silence: None,
+                file_hash: None,
                 tags: tags_,
                 internal_tags,
             };
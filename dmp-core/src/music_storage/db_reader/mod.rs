@@ -9,5 +9,11 @@ pub mod musicbee {
 pub mod itunes {
     pub mod reader;
 }
+pub mod rhythmbox {
+    pub mod reader;
+}
+pub mod clementine {
+    pub mod reader;
+}
 pub mod common;
 pub mod extern_library;
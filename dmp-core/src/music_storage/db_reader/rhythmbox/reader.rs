@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike as _, NaiveDate, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use uuid::Uuid;
+
+use crate::music_storage::db_reader::extern_library::{
+    file_url_to_path, ExternalLibrary, ImportError, ImportedPlaylist,
+};
+use crate::music_storage::library::{Song, Tag, URI};
+
+/// Rhythmbox keeps its playlists in this file next to the database
+const PLAYLISTS_FILE: &str = "playlists.xml";
+
+#[derive(Debug, Default, Clone)]
+pub struct RhythmboxLibrary {
+    tracks: Vec<RhythmboxSong>,
+    playlists: Vec<ImportedPlaylist>,
+}
+
+/// A song entry in `rhythmdb.xml`, with each child element as a field
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RhythmboxSong {
+    pub fields: BTreeMap<String, String>,
+}
+
+impl RhythmboxSong {
+    fn field<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.fields.get(name)?.trim().parse().ok()
+    }
+
+    fn timestamp(&self, name: &str) -> Option<DateTime<Utc>> {
+        // Rhythmbox writes 0 for things which never happened
+        self.field::<i64>(name)
+            .filter(|&secs| secs > 0)
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        file_url_to_path(self.fields.get("location")?)
+    }
+}
+
+fn to_tag(name: &str) -> Option<Tag> {
+    Some(match name {
+        "title" => Tag::Title,
+        "album" => Tag::Album,
+        "artist" => Tag::Artist,
+        "album-artist" => Tag::AlbumArtist,
+        "genre" => Tag::Genre,
+        "comment" => Tag::Comment,
+        "track-number" => Tag::Track,
+        "disc-number" => Tag::Disk,
+        "composer" => Tag::Key(String::from("Composer")),
+        _ => return None,
+    })
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>, ImportError> {
+    match element.try_get_attribute(name)? {
+        Some(value) => Ok(Some(value.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Reads the children of every `entry_tag` element for which `accept`
+/// returns true, as the name and text of each child in order
+fn read_entries(
+    path: &Path,
+    entry_tag: &[u8],
+    mut accept: impl FnMut(&BytesStart) -> Result<bool, ImportError>,
+) -> Result<Vec<Vec<(String, String)>>, ImportError> {
+    let mut reader = Reader::from_file(path)?;
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut entry: Option<Vec<(String, String)>> = None;
+    let mut field: Option<String> = None;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) if e.name().as_ref() == entry_tag => {
+                entry = accept(&e)?.then(Vec::new);
+            }
+            Event::Start(e) if entry.is_some() => {
+                field = Some(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+            }
+            Event::Text(e) => {
+                if let (Some(entry), Some(field)) = (&mut entry, field.take()) {
+                    entry.push((field, e.unescape()?.into_owned()));
+                }
+            }
+            Event::End(e) if e.name().as_ref() == entry_tag => {
+                entries.extend(entry.take());
+            }
+            Event::End(_) => field = None,
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+impl RhythmboxLibrary {
+    /// Reads the songs from a `rhythmdb.xml`, and the static playlists from
+    /// the `playlists.xml` next to it if there is one
+    pub fn read(path: &Path) -> Result<Self, ImportError> {
+        let tracks = read_entries(path, b"entry", |e| {
+            Ok(attribute(e, "type")?.as_deref() == Some("song"))
+        })?
+        .into_iter()
+        .map(|fields| RhythmboxSong {
+            fields: fields.into_iter().collect(),
+        })
+        .collect();
+
+        let playlists_path = path.with_file_name(PLAYLISTS_FILE);
+        let mut playlists = Vec::new();
+        if playlists_path.exists() {
+            let mut titles = Vec::new();
+            // Automatic playlists are searches rather than lists of songs
+            let entries = read_entries(&playlists_path, b"playlist", |e| {
+                if attribute(e, "type")?.as_deref() != Some("static") {
+                    return Ok(false);
+                }
+                titles.push(attribute(e, "name")?.unwrap_or_default());
+                Ok(true)
+            })?;
+
+            playlists = titles
+                .into_iter()
+                .zip(entries)
+                .map(|(title, locations)| ImportedPlaylist {
+                    title,
+                    paths: locations
+                        .iter()
+                        .filter(|(field, _)| field == "location")
+                        .filter_map(|(_, url)| file_url_to_path(url))
+                        .collect(),
+                })
+                .collect();
+        }
+
+        Ok(RhythmboxLibrary { tracks, playlists })
+    }
+
+    pub fn tracks(self) -> Vec<RhythmboxSong> {
+        self.tracks
+    }
+}
+
+impl ExternalLibrary for RhythmboxLibrary {
+    fn from_file(file: &Path) -> Self {
+        Self::read(file).unwrap()
+    }
+
+    fn to_songs(&self) -> Vec<Song> {
+        self.tracks
+            .iter()
+            .filter_map(|track| {
+                let mut tags: BTreeMap<Tag, String> = track
+                    .fields
+                    .iter()
+                    .filter(|(_, value)| !value.is_empty())
+                    .filter_map(|(name, value)| Some((to_tag(name)?, value.clone())))
+                    .collect();
+                // Dates are stored as the number of days since the year 1
+                if let Some(date) = track
+                    .field::<i32>("date")
+                    .and_then(NaiveDate::from_num_days_from_ce_opt)
+                {
+                    tags.insert(Tag::Key(String::from("Year")), date.year().to_string());
+                }
+
+                let duration = Duration::from_secs(track.field("duration").unwrap_or(0));
+                let plays: i32 = track.field("play-count").unwrap_or(0);
+                Some(Song {
+                    location: vec![URI::Local(track.path()?)],
+                    uuid: Uuid::new_v4(),
+                    plays,
+                    // Stars out of 5, where the library rates out of 100
+                    rating: track
+                        .field::<f64>("rating")
+                        .filter(|&stars| stars > 0.0)
+                        .map(|stars| (stars.min(5.0) * 20.0).round() as u8),
+                    format: track.fields.get("media-type").cloned(),
+                    duration,
+                    play_time: duration * plays.max(0) as u32,
+                    last_played: track.timestamp("last-played"),
+                    date_added: track.timestamp("first-seen"),
+                    date_modified: track.timestamp("mtime"),
+                    tags,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn to_playlists(&self) -> Vec<ImportedPlaylist> {
+        self.playlists.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::music_storage::{
+        db_reader::extern_library::{ExternalLibrary, ImportedPlaylist},
+        library::Tag,
+    };
+
+    use super::RhythmboxLibrary;
+
+    #[test]
+    fn read_fixture() {
+        let library =
+            RhythmboxLibrary::read(Path::new("test-data/rhythmbox/rhythmdb.xml")).unwrap();
+        let songs = library.to_songs();
+        // The radio station isn't a song
+        assert_eq!(songs.len(), 2);
+
+        let song = &songs[0];
+        assert_eq!(
+            song.location[0].path(),
+            PathBuf::from("/music/Band/First Album/01 Opening.flac")
+        );
+        assert_eq!(song.get_tag(&Tag::Title).unwrap(), "Opening");
+        assert_eq!(song.get_tag(&Tag::AlbumArtist).unwrap(), "Band");
+        assert_eq!(song.get_tag(&Tag::Track).unwrap(), "1");
+        assert_eq!(song.year(), Some(2019));
+        assert_eq!(song.plays, 12);
+        assert_eq!(song.rating, Some(80));
+        assert_eq!(song.duration.as_secs(), 215);
+        assert_eq!(song.last_played.unwrap().timestamp(), 1650000000);
+        assert_eq!(song.format.as_deref(), Some("audio/x-flac"));
+
+        // Unrated and never played
+        assert_eq!(songs[1].rating, None);
+        assert_eq!(songs[1].last_played, None);
+
+        assert_eq!(
+            library.to_playlists(),
+            [ImportedPlaylist {
+                title: String::from("Road Trip"),
+                paths: vec![
+                    PathBuf::from("/music/Band/First Album/02 Second Song.mp3"),
+                    PathBuf::from("/music/Band/First Album/01 Opening.flac"),
+                ],
+            }]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Decoding audio files into interleaved samples, shared by everything which
+//! has to look at the audio itself rather than its tags
+
+use std::{fs::File, io::ErrorKind, path::Path, time::Duration};
+
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::Time,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("Decoding was cancelled")]
+    Cancelled,
+    #[error("No decodable audio track was found")]
+    NoTrack,
+    #[error("Decoding error: {0}")]
+    Decode(#[from] SymphoniaError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Decodes the default track of the file at `path`, passing each run of
+/// interleaved samples to `samples` along with the channel count and sample
+/// rate. `range` limits decoding to part of the file, which is used for songs
+/// within a CUE sheet.
+///
+/// `progress` is called with the fraction of the file decoded so far, at most
+/// once per percent, and returning `false` from it cancels decoding.
+pub(super) fn decode(
+    path: &Path,
+    range: Option<(Duration, Duration)>,
+    mut samples: impl FnMut(&[f32], usize, u32),
+    mut progress: impl FnMut(f32) -> bool,
+) -> Result<(), DecodeError> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format.default_track().ok_or(DecodeError::NoTrack)?.clone();
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let sample_rate = track.codec_params.sample_rate.unwrap_or(44_100);
+    let (start_frame, end_frame) = match range {
+        Some((start, end)) => (
+            (start.as_secs_f64() * sample_rate as f64) as u64,
+            Some((end.as_secs_f64() * sample_rate as f64) as u64),
+        ),
+        None => (0, None),
+    };
+    let total_frames = end_frame.or(track.codec_params.n_frames);
+
+    // Skip straight to the start of the range where the format allows it,
+    // rather than decoding everything before it
+    let mut frame: u64 = 0;
+    if let (Some((start, _)), Some(time_base)) = (range, track.codec_params.time_base) {
+        let seeked = format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(start.as_secs_f64()),
+                track_id: Some(track.id),
+            },
+        );
+        if let Ok(seeked) = seeked {
+            let time = time_base.calc_time(seeked.actual_ts);
+            let seconds = time.seconds as f64 + time.frac;
+            frame = ((seconds * sample_rate as f64) as u64).min(start_frame);
+            decoder.reset();
+        }
+    }
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let mut last_progress = 0.0;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track.id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet only leaves a small gap, so carry on
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+
+        // Only pass on the frames which are within the range
+        let frames = (buffer.samples().len() / channels) as u64;
+        let first = start_frame.saturating_sub(frame).min(frames);
+        let last = end_frame.map_or(frames, |end| end.saturating_sub(frame).min(frames));
+        if first < last {
+            samples(
+                &buffer.samples()[first as usize * channels..last as usize * channels],
+                channels,
+                spec.rate,
+            );
+        }
+        frame += frames;
+
+        if let Some(total) = total_frames {
+            let done = frame.saturating_sub(start_frame) as f32
+                / total.saturating_sub(start_frame).max(1) as f32;
+            // Only report every percent, so long files don't flood the receiver
+            if done - last_progress >= 0.01 {
+                last_progress = done;
+                if !progress(done.min(1.0)) {
+                    return Err(DecodeError::Cancelled);
+                }
+            }
+        }
+
+        if end_frame.is_some_and(|end| frame >= end) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes the default track of the file at `path` to check it against the
+/// checksum of its audio stored in the file, like the MD5 in a FLAC file's
+/// header. Returns `None` for files without one.
+///
+/// `keep_going` is called between packets, and returning `false` from it
+/// cancels the check.
+pub(super) fn verify_checksum(
+    path: &Path,
+    mut keep_going: impl FnMut() -> bool,
+) -> Result<Option<bool>, DecodeError> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format.default_track().ok_or(DecodeError::NoTrack)?.clone();
+    if track.codec_params.verification_check.is_none() {
+        return Ok(None);
+    }
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: true })?;
+
+    loop {
+        if !keep_going() {
+            return Err(DecodeError::Cancelled);
+        }
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track.id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(_) => (),
+            // Any damage to the audio means it can't match
+            Err(SymphoniaError::DecodeError(_)) => return Ok(Some(false)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(decoder.finalize().verify_ok)
+}
@@ -0,0 +1,180 @@
+//! Finding songs which were added to the library more than once for the same
+//! file, like from scanning a folder through two different paths before
+//! they were normalized, and merging them back into one song
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    library::{MusicLibrary, Song, URI},
+    utils::normalize_path,
+};
+
+/// Songs which all play the same file, or the same track of a CUE sheet
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DuplicateGroup {
+    pub location: URI,
+    /// The song the others are merged into, which is the one that comes
+    /// first in the library
+    pub kept: Uuid,
+    pub duplicates: Vec<Uuid>,
+}
+
+/// What makes two locations the same: the file, and the track for a CUE
+/// sheet. Remote songs are never duplicates, as the same URL can give
+/// different streams.
+fn location_key(song: &Song) -> Option<(PathBuf, Option<usize>)> {
+    match song.location.first()? {
+        URI::Local(path) => Some((normalize_path(path), None)),
+        URI::Cue {
+            location, index, ..
+        } => Some((normalize_path(location), Some(*index))),
+        URI::Remote(..) => None,
+    }
+}
+
+impl MusicLibrary {
+    /// The groups of songs which play the same file, in the order their
+    /// first song comes in the library
+    pub fn find_duplicates(&self) -> Vec<DuplicateGroup> {
+        let mut groups: BTreeMap<(PathBuf, Option<usize>), Vec<&Song>> = BTreeMap::new();
+        for song in &self.library {
+            if let Some(key) = location_key(song) {
+                groups.entry(key).or_default().push(song);
+            }
+        }
+
+        let mut groups: Vec<(usize, DuplicateGroup)> = groups
+            .into_values()
+            .filter(|songs| songs.len() > 1)
+            .map(|songs| {
+                let first = self.query_uuid(&songs[0].uuid).map_or(0, |(_, i)| i);
+                let group = DuplicateGroup {
+                    location: songs[0].location[0].clone(),
+                    kept: songs[0].uuid,
+                    duplicates: songs[1..].iter().map(|song| song.uuid).collect(),
+                };
+                (first, group)
+            })
+            .collect();
+        groups.sort_by_key(|(first, _)| *first);
+        groups.into_iter().map(|(_, group)| group).collect()
+    }
+
+    /// Merges the duplicates of each group into the song kept, adding their
+    /// plays, skips and play time to it and pointing playlists at it, then
+    /// removes them. Returns how many songs were removed.
+    pub fn merge_duplicates(&mut self, groups: &[DuplicateGroup]) -> usize {
+        let mut replaced: BTreeMap<Uuid, Uuid> = BTreeMap::new();
+        for group in groups {
+            let Some((_, kept)) = self.query_uuid(&group.kept) else {
+                continue;
+            };
+            for duplicate in &group.duplicates {
+                let Some((song, _)) = self.query_uuid(duplicate) else {
+                    continue;
+                };
+                let song = song.clone();
+                let kept = &mut self.library[kept];
+                kept.plays += song.plays;
+                kept.skips += song.skips;
+                kept.play_time += song.play_time;
+                kept.last_played = kept.last_played.max(song.last_played);
+                kept.date_added = match (kept.date_added, song.date_added) {
+                    (Some(kept), Some(song)) => Some(kept.min(song)),
+                    (kept, song) => kept.or(song),
+                };
+                kept.first_played = match (kept.first_played, song.first_played) {
+                    (Some(kept), Some(song)) => Some(kept.min(song)),
+                    (kept, song) => kept.or(song),
+                };
+                kept.favorited |= song.favorited;
+                kept.rating = kept.rating.or(song.rating);
+                replaced.insert(*duplicate, group.kept);
+            }
+        }
+
+        self.playlists.for_each_list_mut(&mut |playlist| {
+            for track in &mut playlist.tracks {
+                if let Some(kept) = replaced.get(&track.uuid) {
+                    track.uuid = *kept;
+                }
+            }
+        });
+        self.library
+            .retain(|song| !replaced.contains_key(&song.uuid));
+        replaced.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use crate::music_storage::{
+        library::{MusicLibrary, Song, URI},
+        playlist::{Playlist, PlaylistFolderItem},
+    };
+
+    fn song(location: URI, plays: i32) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![location],
+            plays,
+            play_time: Duration::from_secs(plays as u64 * 60),
+            ..Default::default()
+        }
+    }
+
+    fn cue(index: usize) -> URI {
+        URI::Cue {
+            location: PathBuf::from("/music/album.flac"),
+            index,
+            start: Duration::ZERO,
+            end: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn same_file_merged() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        // Pushed directly, as adding songs refuses files already in it
+        library.library = vec![
+            song(URI::Local(PathBuf::from("/music/a.flac")), 2),
+            song(URI::Local(PathBuf::from("/music/b.flac")), 1),
+            song(URI::Local(PathBuf::from("/music/a.flac")), 3),
+            song(cue(1), 1),
+            song(cue(2), 1),
+            song(cue(1), 4),
+            song(URI::Local(PathBuf::from("/music/a.flac")), 0),
+        ];
+        let uuids: Vec<Uuid> = library.library.iter().map(|song| song.uuid).collect();
+        let mut playlist = Playlist::new();
+        playlist.add_tracks([uuids[2], uuids[1], uuids[5]]);
+        library.push_playlist(PlaylistFolderItem::List(playlist));
+
+        let groups = library.find_duplicates();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].kept, uuids[0]);
+        assert_eq!(groups[0].duplicates, [uuids[2], uuids[6]]);
+        assert_eq!(groups[1].location, cue(1));
+        assert_eq!(groups[1].kept, uuids[3]);
+        assert_eq!(groups[1].duplicates, [uuids[5]]);
+
+        assert_eq!(library.merge_duplicates(&groups), 3);
+        assert_eq!(library.library.len(), 4);
+        let (kept, _) = library.query_uuid(&uuids[0]).unwrap();
+        assert_eq!(kept.plays, 5);
+        assert_eq!(kept.play_time, Duration::from_secs(300));
+        assert_eq!(library.query_uuid(&uuids[3]).unwrap().0.plays, 5);
+        assert_eq!(
+            library.playlists.lists_recursive()[0].tracks(),
+            [uuids[0], uuids[1], uuids[3]]
+        );
+        assert!(library.find_duplicates().is_empty());
+    }
+}
@@ -0,0 +1,503 @@
+//! Reading tags out of file names, for songs which aren't tagged. A pattern
+//! like `{artist} - {title}` says how the names are laid out, and is matched
+//! loosely, since names are rarely as tidy as their pattern.
+
+use std::path::Path;
+
+use serde::Serialize;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::{
+    library::{MusicLibrary, Song, Tag, URI},
+    tag_cleanup::TagChange,
+};
+
+/// Patterns for the most common ways of naming files
+pub const FILENAME_PRESETS: [&str; 5] = [
+    "{artist} - {title}",
+    "{track} {title}",
+    "{track} - {artist} - {title}",
+    "{artist} - {album} - {track} {title}",
+    "{disc}-{track} {title}",
+];
+
+/// Words which mark something in brackets as being about the file rather
+/// than the song, like "(Official Video)" or "(320kbps)"
+const JUNK_WORDS: [&str; 12] = [
+    "kbps", "khz", "320", "v0", "flac", "mp3", "official", "lyric", "audio", "video", "hq", "hd",
+];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum PatternError {
+    #[error("Unknown placeholder {{{0}}}")]
+    UnknownPlaceholder(String),
+    #[error("An opening brace isn't closed")]
+    Unclosed,
+    #[error("There's nothing between {{{0}}} and {{{1}}} to tell them apart")]
+    NoSeparator(String, String),
+    #[error("The pattern has no placeholders")]
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PatternField {
+    Artist,
+    Album,
+    Track,
+    Disc,
+    Title,
+}
+
+impl PatternField {
+    fn parse(name: &str) -> Result<Self, PatternError> {
+        Ok(match name {
+            "artist" => PatternField::Artist,
+            "album" => PatternField::Album,
+            "track" => PatternField::Track,
+            "disc" => PatternField::Disc,
+            "title" => PatternField::Title,
+            _ => return Err(PatternError::UnknownPlaceholder(name.to_string())),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PatternField::Artist => "artist",
+            PatternField::Album => "album",
+            PatternField::Track => "track",
+            PatternField::Disc => "disc",
+            PatternField::Title => "title",
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        match self {
+            PatternField::Artist => Tag::Artist,
+            PatternField::Album => Tag::Album,
+            PatternField::Track => Tag::Track,
+            PatternField::Disc => Tag::Disk,
+            PatternField::Title => Tag::Title,
+        }
+    }
+
+    /// Numbers are read up to the first character which isn't a digit, so
+    /// they don't need a separator after them
+    fn is_number(&self) -> bool {
+        matches!(self, PatternField::Track | PatternField::Disc)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(PatternField),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenamePattern {
+    segments: Vec<Segment>,
+}
+
+impl FilenamePattern {
+    pub fn parse(pattern: &str) -> Result<Self, PatternError> {
+        let mut segments = Vec::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or(PatternError::Unclosed)? + start;
+            let field = PatternField::parse(rest[start + 1..end].trim())?;
+            if let Some(Segment::Field(previous)) = segments.last() {
+                if !previous.is_number() {
+                    return Err(PatternError::NoSeparator(
+                        previous.name().to_string(),
+                        field.name().to_string(),
+                    ));
+                }
+            }
+            segments.push(Segment::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        if !segments.iter().any(|s| matches!(s, Segment::Field(_))) {
+            return Err(PatternError::Empty);
+        }
+        Ok(FilenamePattern { segments })
+    }
+
+    /// The tags read from the name of the file at `path`, without its
+    /// extension
+    pub fn read_path(&self, path: &Path) -> Vec<(PatternField, String)> {
+        match path.file_stem() {
+            Some(stem) => self.read(&stem.to_string_lossy()),
+            None => Vec::new(),
+        }
+    }
+
+    /// The tags read from `name`. Separators which aren't there are skipped
+    /// over, giving the rest of the name to the title, and the title takes
+    /// as much as it can when something comes after it.
+    pub fn read(&self, name: &str) -> Vec<(PatternField, String)> {
+        let name = clean_name(name);
+        let has_title =
+            |from: usize| self.segments[from..].contains(&Segment::Field(PatternField::Title));
+
+        let mut found = Vec::new();
+        let mut rest = name.as_str();
+        let mut i = 0;
+        while i < self.segments.len() && !rest.is_empty() {
+            let field = match &self.segments[i] {
+                Segment::Literal(literal) => {
+                    rest = skip_separator(rest, literal);
+                    i += 1;
+                    continue;
+                }
+                Segment::Field(field) => *field,
+            };
+            let separator = match self.segments.get(i + 1) {
+                Some(Segment::Literal(literal)) => Some(literal.as_str()),
+                _ => None,
+            };
+
+            if field.is_number() {
+                let digits = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                // Four digits or more is more likely a year than a number
+                if (1..=3).contains(&digits) {
+                    let number: u16 = rest[..digits].parse().unwrap_or_default();
+                    found.push((field, number.to_string()));
+                    rest = &rest[digits..];
+                }
+                // Whatever came between the number and what's next is
+                // skipped, whether or not it matches the pattern
+                rest = rest.trim_start_matches(|c: char| c.is_whitespace() || ".-_)".contains(c));
+                i += if separator.is_some() { 2 } else { 1 };
+                continue;
+            }
+
+            let end = match separator {
+                // A separator of spaces can't be told apart from the spaces
+                // between words, so the field takes a single word
+                Some(separator) if separator.trim().is_empty() => {
+                    rest.find(char::is_whitespace).map(|end| (end, 1))
+                }
+                // Looked for as it's written first, then without the spaces
+                // around it
+                Some(separator) => [separator, separator.trim()].iter().find_map(|separator| {
+                    let end = match field {
+                        PatternField::Title => rest.rfind(separator),
+                        _ => rest.find(separator),
+                    };
+                    end.map(|end| (end, separator.len()))
+                }),
+                None => None,
+            };
+            match end {
+                Some((end, len)) => {
+                    push_value(&mut found, field, &rest[..end]);
+                    rest = &rest[end + len..];
+                    i += 2;
+                }
+                // The separator is missing, so the rest is the title
+                None if field != PatternField::Title && has_title(i + 1) => {
+                    push_value(&mut found, PatternField::Title, rest);
+                    break;
+                }
+                None => {
+                    push_value(&mut found, field, rest);
+                    break;
+                }
+            }
+        }
+        found
+    }
+
+    /// The changes reading the song's file name makes to it. Tags it already
+    /// has are only replaced when `overwrite` is set.
+    pub fn changes(&self, song: &Song, overwrite: bool) -> Vec<TagChange> {
+        // Songs from a CUE sheet share its file, so its name says nothing
+        // about them
+        let Some(URI::Local(path)) = song.location.first() else {
+            return Vec::new();
+        };
+
+        self.read_path(path)
+            .into_iter()
+            .filter_map(|(field, new)| {
+                let tag = field.tag();
+                let old = song.get_tag(&tag).cloned().unwrap_or_default();
+                let keep = !overwrite && !old.trim().is_empty();
+                (!keep && old != new).then_some(TagChange {
+                    uuid: song.uuid,
+                    tag,
+                    old,
+                    new,
+                })
+            })
+            .collect()
+    }
+}
+
+fn push_value(found: &mut Vec<(PatternField, String)>, field: PatternField, value: &str) {
+    let value = value.trim_matches(|c: char| c.is_whitespace() || "-_.".contains(c));
+    if !value.is_empty() {
+        found.push((field, value.to_string()));
+    }
+}
+
+/// Skips `literal` at the start of `rest`, ignoring the spaces around it,
+/// and skips nothing if it isn't there
+fn skip_separator<'a>(rest: &'a str, literal: &str) -> &'a str {
+    let trimmed = rest.trim_start();
+    trimmed
+        .strip_prefix(literal.trim())
+        .map_or(rest, str::trim_start)
+}
+
+/// Takes out what's in square brackets, and anything in other brackets
+/// which is about the file rather than the song, and evens out underscores,
+/// dashes and spaces
+fn clean_name(name: &str) -> String {
+    let name = name.replace('_', " ").replace(['–', '—'], "-");
+
+    let mut cleaned = String::with_capacity(name.len());
+    let mut rest = name.as_str();
+    while let Some(start) = rest.find(['[', '(', '{']) {
+        let close = match rest.as_bytes()[start] {
+            b'[' => ']',
+            b'(' => ')',
+            _ => '}',
+        };
+        let Some(len) = rest[start..].find(close) else {
+            break;
+        };
+        let inside = &rest[start + 1..start + len];
+        let lower = inside.to_lowercase();
+        if close == ']' || JUNK_WORDS.iter().any(|word| lower.contains(word)) {
+            cleaned.push_str(&rest[..start]);
+        } else {
+            cleaned.push_str(&rest[..=start + len]);
+        }
+        rest = &rest[start + len + 1..];
+    }
+    cleaned.push_str(rest);
+
+    cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl MusicLibrary {
+    /// Fills in the songs' tags from their file names, returning what
+    /// changed. When `dry_run` is set nothing is modified, and the changes
+    /// are only a preview.
+    pub fn apply_filename_pattern(
+        &mut self,
+        uuids: &[Uuid],
+        pattern: &FilenamePattern,
+        overwrite: bool,
+        dry_run: bool,
+    ) -> Vec<TagChange> {
+        let mut changes = Vec::new();
+        for song in self.library.iter_mut().filter(|s| uuids.contains(&s.uuid)) {
+            let song_changes = pattern.changes(song, overwrite);
+            if !dry_run {
+                for change in &song_changes {
+                    song.set_tag(change.tag.clone(), change.new.clone());
+                }
+            }
+            changes.extend(song_changes);
+        }
+        changes
+    }
+}
+
+impl Song {
+    /// Fills in the tags missing from a newly imported song from its file
+    /// name, if it has no title or artist
+    pub fn fill_from_filename(&mut self, pattern: &FilenamePattern) {
+        let missing = |tag| {
+            self.get_tag(&tag)
+                .is_none_or(|value| value.trim().is_empty())
+        };
+        if !missing(Tag::Title) && !missing(Tag::Artist) {
+            return;
+        }
+        for change in pattern.changes(self, false) {
+            self.set_tag(change.tag, change.new);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{FilenamePattern, PatternError, PatternField, FILENAME_PRESETS};
+    use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+
+    use PatternField::*;
+
+    #[test]
+    fn messy_names() {
+        /// A pattern, a file name, and what should be read from it
+        type Case<'a> = (&'a str, &'a str, &'a [(PatternField, &'a str)]);
+        let table: [Case; 14] = [
+            (
+                "{artist} - {title}",
+                "Kalafina - Lacrimosa",
+                &[(Artist, "Kalafina"), (Title, "Lacrimosa")],
+            ),
+            (
+                "{artist} - {title}",
+                "Kalafina_-_Lacrimosa_[320kbps]",
+                &[(Artist, "Kalafina"), (Title, "Lacrimosa")],
+            ),
+            (
+                "{artist} - {title}",
+                "Kalafina – Lacrimosa (Official Video)",
+                &[(Artist, "Kalafina"), (Title, "Lacrimosa")],
+            ),
+            // Brackets which are part of the title are kept
+            (
+                "{artist} - {title}",
+                "ClariS - irony (TV size)",
+                &[(Artist, "ClariS"), (Title, "irony (TV size)")],
+            ),
+            // A missing separator leaves the whole name as the title
+            ("{artist} - {title}", "Lacrimosa", &[(Title, "Lacrimosa")]),
+            (
+                "{track} {title}",
+                "03. Sprinter",
+                &[(Track, "3"), (Title, "Sprinter")],
+            ),
+            (
+                "{track} {title}",
+                "03 - Sprinter [FLAC]",
+                &[(Track, "3"), (Title, "Sprinter")],
+            ),
+            // Years aren't track numbers
+            (
+                "{track} {title}",
+                "2009 Sprinter",
+                &[(Title, "2009 Sprinter")],
+            ),
+            (
+                "{track} - {artist} - {title}",
+                "07-Aimer-Brave Shine",
+                &[(Track, "7"), (Artist, "Aimer"), (Title, "Brave Shine")],
+            ),
+            // The title is greedy, so dashes in it are kept
+            (
+                "{title} - {artist}",
+                "Re - Re - ASIAN KUNG-FU GENERATION",
+                &[(Title, "Re - Re"), (Artist, "ASIAN KUNG-FU GENERATION")],
+            ),
+            (
+                "{artist} - {album} - {track} {title}",
+                "Kalafina - Seventh Heaven - 01 oblivious",
+                &[
+                    (Artist, "Kalafina"),
+                    (Album, "Seventh Heaven"),
+                    (Track, "1"),
+                    (Title, "oblivious"),
+                ],
+            ),
+            (
+                "{artist} - {album} - {track} {title}",
+                "Kalafina - oblivious",
+                &[(Artist, "Kalafina"), (Title, "oblivious")],
+            ),
+            (
+                "{disc}-{track} {title}",
+                "2-05 Magia",
+                &[(Disc, "2"), (Track, "5"), (Title, "Magia")],
+            ),
+            ("{track} {title}", "[www.site.com] 12", &[(Track, "12")]),
+        ];
+
+        for (pattern, name, expected) in table {
+            let read = FilenamePattern::parse(pattern).unwrap().read(name);
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(field, value)| (*field, value.to_string()))
+                .collect();
+            assert_eq!(read, expected, "{name:?} read with {pattern:?}");
+        }
+    }
+
+    #[test]
+    fn bad_patterns() {
+        for preset in FILENAME_PRESETS {
+            assert!(FilenamePattern::parse(preset).is_ok());
+        }
+        assert_eq!(
+            FilenamePattern::parse("{artist}{title}"),
+            Err(PatternError::NoSeparator(
+                String::from("artist"),
+                String::from("title")
+            ))
+        );
+        assert_eq!(
+            FilenamePattern::parse("{year} - {title}"),
+            Err(PatternError::UnknownPlaceholder(String::from("year")))
+        );
+        assert_eq!(
+            FilenamePattern::parse("{title"),
+            Err(PatternError::Unclosed)
+        );
+        assert_eq!(FilenamePattern::parse("song"), Err(PatternError::Empty));
+    }
+
+    #[test]
+    fn existing_tags_kept() {
+        let song = |name: &str, tags: &[(Tag, &str)]| Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(format!("/music/{name}.mp3")))],
+            tags: tags
+                .iter()
+                .map(|(tag, value)| (tag.clone(), value.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            ..Default::default()
+        };
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song("Aimer - Brave Shine", &[(Tag::Title, "Brave Shine (TV)")]),
+            song("Aimer - Ref:rain", &[(Tag::Artist, " ")]),
+        ];
+        let uuids: Vec<Uuid> = library.library.iter().map(|s| s.uuid).collect();
+        let pattern = FilenamePattern::parse("{artist} - {title}").unwrap();
+
+        // A dry run changes nothing
+        let preview = library.apply_filename_pattern(&uuids, &pattern, false, true);
+        assert_eq!(preview.len(), 3);
+        assert!(library.library[0].get_tag(&Tag::Artist).is_none());
+
+        library.apply_filename_pattern(&uuids, &pattern, false, false);
+        let tag = |library: &MusicLibrary, i: usize, tag| library.library[i].get_tag(&tag).cloned();
+        assert_eq!(tag(&library, 0, Tag::Title).unwrap(), "Brave Shine (TV)");
+        assert_eq!(tag(&library, 0, Tag::Artist).unwrap(), "Aimer");
+        // Blank tags count as missing
+        assert_eq!(tag(&library, 1, Tag::Artist).unwrap(), "Aimer");
+        assert_eq!(tag(&library, 1, Tag::Title).unwrap(), "Ref:rain");
+
+        let changes = library.apply_filename_pattern(&uuids[..1], &pattern, true, false);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].old, "Brave Shine (TV)");
+        assert_eq!(tag(&library, 0, Tag::Title).unwrap(), "Brave Shine");
+
+        // Imported songs are only filled in when they're missing a title or
+        // artist
+        let mut tagged = song("Aimer - Brave Shine", &[(Tag::Title, "Brave Shine")]);
+        tagged.set_tag(Tag::Artist, String::from("Aimer feat. someone"));
+        let before = tagged.clone();
+        tagged.fill_from_filename(&pattern);
+        assert_eq!(tagged, before);
+    }
+}
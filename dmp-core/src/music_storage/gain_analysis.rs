@@ -0,0 +1,613 @@
+//! Measuring the loudness of songs to compute ReplayGain values locally,
+//! for libraries which were never tagged with them
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use ebur128::{EbuR128, Mode};
+use lofty::tag::ItemKey;
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::config::ConfigTagWriting;
+
+use super::{
+    decode::{decode, DecodeError},
+    gapless::END_LENGTH_SECS,
+    library::{Album, AlbumKey, MusicLibrary, Song, Tag, URI},
+    silence::{SilenceBounds, SilenceMeter},
+    tag_writing::write_tag_items,
+};
+
+/// The tags gain values are stored in, named the same as the ones read from
+/// files which already have them
+pub const TRACK_GAIN: &str = "ReplayGainTrackGain";
+pub const TRACK_PEAK: &str = "ReplayGainTrackPeak";
+pub const ALBUM_GAIN: &str = "ReplayGainAlbumGain";
+pub const ALBUM_PEAK: &str = "ReplayGainAlbumPeak";
+/// The loudness measured for a song or its album in LUFS, kept only in the
+/// library, so songs can be brought to another target without measuring
+/// them again
+pub const TRACK_LOUDNESS: &str = "ReplayGainTrackLoudness";
+pub const ALBUM_LOUDNESS: &str = "ReplayGainAlbumLoudness";
+
+/// The loudness ReplayGain 2.0 brings songs to, in LUFS. Stored gains are
+/// relative to it.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum AnalyzeScope {
+    Album(AlbumKey),
+    Songs(Vec<Uuid>),
+    WholeLibrary,
+}
+
+#[derive(Error, Debug)]
+pub enum GainError {
+    #[error("Gain analysis was cancelled")]
+    Cancelled,
+    #[error("The song is silent")]
+    Silent,
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error("Loudness measurement failed: {0}")]
+    Meter(#[from] ebur128::Error),
+}
+
+/// A song to be measured
+#[derive(Debug, Clone)]
+pub struct GainTrack {
+    pub uuid: Uuid,
+    pub path: PathBuf,
+    pub range: Option<(Duration, Duration)>,
+}
+
+/// Songs which are analyzed together. When `album` is set they also share
+/// album gain values, computed from all of them at once.
+#[derive(Debug, Clone)]
+pub struct GainGroup {
+    pub tracks: Vec<GainTrack>,
+    pub album: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct GainValues {
+    /// Gain in dB
+    pub track_gain: f64,
+    /// Peak sample as a linear amplitude
+    pub track_peak: f64,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+    /// The measured loudness in LUFS
+    pub track_loudness: f64,
+    pub album_loudness: Option<f64>,
+    /// The RMS level of the end of the song in dBFS, see
+    /// [`Song::end_loudness`]
+    pub end_loudness: Option<i8>,
+    /// Where the sound starts and ends, for songs which are a whole file
+    pub silence: Option<SilenceBounds>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GainProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct GainAnalysis {
+    pub values: Vec<(Uuid, GainValues)>,
+    pub failed: Vec<(Uuid, String)>,
+    /// The number of songs which already had values, or have no local file
+    pub skipped: usize,
+    pub failed_writes: Vec<(PathBuf, String)>,
+}
+
+/// The pool analysis runs on, kept to half of the cores so playback and the
+/// rest of the app stay responsive
+fn pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get() / 2);
+        ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(|i| format!("gain-analysis-{i}"))
+            .build()
+            .unwrap()
+    })
+}
+
+/// Measures every group, calling `progress` as each song is finished.
+/// Returning `false` from `progress` cancels the analysis. The silence
+/// around songs is found along the way, with `silence_threshold_db`.
+pub fn analyze(
+    groups: &[GainGroup],
+    silence_threshold_db: f32,
+    progress: impl Fn(GainProgress) -> bool + Sync,
+) -> Result<GainAnalysis, GainError> {
+    let total = groups.iter().map(|g| g.tracks.len()).sum();
+    let done = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+    let finished = || {
+        let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+        if !progress(GainProgress { done, total }) {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+    };
+
+    let mut analysis = GainAnalysis::default();
+    for group in groups {
+        for (uuid, res) in
+            pool().install(|| group.analyze(silence_threshold_db, &cancelled, &finished))
+        {
+            match res {
+                Ok(values) => analysis.values.push((uuid, values)),
+                Err(GainError::Cancelled) => return Err(GainError::Cancelled),
+                Err(e) => analysis.failed.push((uuid, e.to_string())),
+            }
+        }
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(GainError::Cancelled);
+        }
+    }
+
+    Ok(analysis)
+}
+
+impl GainGroup {
+    fn analyze(
+        &self,
+        silence_threshold_db: f32,
+        cancelled: &AtomicBool,
+        finished: &(impl Fn() + Sync),
+    ) -> Vec<(Uuid, Result<GainValues, GainError>)> {
+        let measured: Vec<_> = self
+            .tracks
+            .par_iter()
+            .map(|track| {
+                let res = measure(&track.path, track.range, silence_threshold_db, cancelled);
+                finished();
+                (track.uuid, res)
+            })
+            .collect();
+
+        let meters: Vec<&EbuR128> = measured
+            .iter()
+            .filter_map(|(_, res)| res.as_ref().ok().map(|(meter, ..)| meter))
+            .collect();
+        let album = match self.album && !meters.is_empty() {
+            true => Some((
+                EbuR128::loudness_global_multiple(meters.iter().copied())
+                    .map_err(GainError::from)
+                    .and_then(|loudness| Ok((loudness, gain(loudness)?))),
+                meters.iter().map(|m| peak(m)).fold(0.0, f64::max),
+            )),
+            false => None,
+        };
+
+        measured
+            .into_iter()
+            .map(|(uuid, res)| {
+                let values = res.and_then(|(meter, end, silence)| {
+                    let (album_loudness, album_gain, album_peak) = match &album {
+                        Some((Ok((loudness, gain)), peak)) => {
+                            (Some(*loudness), Some(*gain), Some(*peak))
+                        }
+                        _ => (None, None, None),
+                    };
+                    let track_loudness = meter.loudness_global()?;
+                    Ok(GainValues {
+                        track_gain: gain(track_loudness)?,
+                        track_peak: peak(&meter),
+                        album_gain,
+                        album_peak,
+                        track_loudness,
+                        album_loudness,
+                        end_loudness: end.level(),
+                        silence,
+                    })
+                });
+                (uuid, values)
+            })
+            .collect()
+    }
+}
+
+/// Keeps the sum of squares of the last [`END_LENGTH_SECS`] of a song, a
+/// decoded packet at a time
+#[derive(Default)]
+struct EndMeter {
+    /// The number of samples in each packet and the sum of their squares
+    packets: VecDeque<(usize, f64)>,
+    samples: usize,
+}
+
+impl EndMeter {
+    fn add(&mut self, samples: &[f32], channels: usize, rate: u32) {
+        let sum = samples.iter().map(|s| f64::from(*s).powi(2)).sum();
+        self.packets.push_back((samples.len(), sum));
+        self.samples += samples.len();
+
+        let wanted = (rate * END_LENGTH_SECS) as usize * channels;
+        while let Some((front, _)) = self.packets.front() {
+            if self.samples - front < wanted {
+                break;
+            }
+            self.samples -= front;
+            self.packets.pop_front();
+        }
+    }
+
+    /// The RMS level in dBFS, with silence at the lowest level there is
+    fn level(&self) -> Option<i8> {
+        if self.samples == 0 {
+            return None;
+        }
+        let sum: f64 = self.packets.iter().map(|(_, sum)| sum).sum();
+        let rms = (sum / self.samples as f64).sqrt();
+        let db = 20.0 * rms.log10();
+        Some(db.round().clamp(f64::from(i8::MIN), 0.0) as i8)
+    }
+}
+
+fn measure(
+    path: &Path,
+    range: Option<(Duration, Duration)>,
+    silence_threshold_db: f32,
+    cancelled: &AtomicBool,
+) -> Result<(EbuR128, EndMeter, Option<SilenceBounds>), GainError> {
+    let mut meter = None;
+    let mut end = EndMeter::default();
+    let mut silence = SilenceMeter::new(silence_threshold_db);
+    decode(
+        path,
+        range,
+        |samples, channels, rate| {
+            let meter = meter.get_or_insert_with(|| {
+                EbuR128::new(channels as u32, rate, Mode::I | Mode::SAMPLE_PEAK)
+            });
+            if let Ok(meter) = meter {
+                _ = meter.add_frames_f32(samples);
+            }
+            end.add(samples, channels, rate);
+            silence.add(samples, channels, rate);
+        },
+        |_| !cancelled.load(Ordering::SeqCst),
+    )
+    .map_err(|e| match e {
+        DecodeError::Cancelled => GainError::Cancelled,
+        e => e.into(),
+    })?;
+
+    let meter = meter
+        .ok_or(DecodeError::NoTrack)?
+        .map_err(GainError::from)?;
+    // Silence is only skipped in whole files
+    let silence = range.is_none().then(|| silence.bounds());
+    Ok((meter, end, silence))
+}
+
+/// Turns a loudness in LUFS into the gain which brings it to the reference
+fn gain(loudness: f64) -> Result<f64, GainError> {
+    match loudness.is_finite() {
+        true => Ok(REFERENCE_LOUDNESS - loudness),
+        false => Err(GainError::Silent),
+    }
+}
+
+fn peak(meter: &EbuR128) -> f64 {
+    (0..)
+        .map_while(|channel| meter.sample_peak(channel).ok())
+        .fold(0.0, f64::max)
+}
+
+fn gain_track(song: &Song) -> Option<GainTrack> {
+    let (path, range) = match song.primary_uri().ok()?.0 {
+        URI::Local(path) => (path.clone(), None),
+        URI::Cue {
+            location,
+            start,
+            end,
+            ..
+        } => (location.clone(), Some((*start, *end))),
+        _ => return None,
+    };
+    Some(GainTrack {
+        uuid: song.uuid,
+        path,
+        range,
+    })
+}
+
+fn has_tags(song: &Song, tags: &[&str]) -> bool {
+    tags.iter()
+        .all(|tag| song.get_tag(&Tag::Key(tag.to_string())).is_some())
+}
+
+impl MusicLibrary {
+    /// Works out what has to be measured to analyze `scope`, along with the
+    /// number of songs which are skipped. Songs which already have values are
+    /// skipped unless `force` is set, while albums are measured again as a
+    /// whole if any of their songs are missing values.
+    pub fn gain_groups(&self, scope: &AnalyzeScope, force: bool) -> (Vec<GainGroup>, usize) {
+        let album_group = |uuids: Vec<Uuid>| {
+            let songs: Vec<&Song> = uuids
+                .iter()
+                .filter_map(|uuid| self.query_uuid(uuid).map(|(song, _)| song))
+                .collect();
+            let tracks: Vec<GainTrack> = songs.iter().filter_map(|s| gain_track(s)).collect();
+            let complete = songs.iter().all(|s| has_tags(s, &[TRACK_GAIN, ALBUM_GAIN]));
+
+            let skipped = songs.len() - tracks.len();
+            match (force || !complete) && !tracks.is_empty() {
+                true => (
+                    Some(GainGroup {
+                        tracks,
+                        album: true,
+                    }),
+                    skipped,
+                ),
+                false => (None, songs.len()),
+            }
+        };
+        let album_uuids =
+            |album: &Album| album.discs().values().flatten().map(|(_, u)| *u).collect();
+
+        let mut groups = Vec::new();
+        let mut skipped = 0;
+        let mut singles = Vec::new();
+        match scope {
+            AnalyzeScope::Album(key) => {
+                if let Some(album) = self.query_album(key) {
+                    let (group, s) = album_group(album_uuids(&album));
+                    groups.extend(group);
+                    skipped += s;
+                }
+            }
+            AnalyzeScope::Songs(uuids) => singles.extend(
+                uuids
+                    .iter()
+                    .filter_map(|u| self.query_uuid(u).map(|(s, _)| s)),
+            ),
+            AnalyzeScope::WholeLibrary => {
+                for album in self.albums().values() {
+                    let (group, s) = album_group(album_uuids(album));
+                    groups.extend(group);
+                    skipped += s;
+                }
+                singles.extend(
+                    self.library
+                        .iter()
+                        .filter(|s| s.get_tag(&Tag::Album).is_none()),
+                );
+            }
+        }
+
+        let tracks: Vec<GainTrack> = singles
+            .iter()
+            .filter(|song| force || !has_tags(song, &[TRACK_GAIN]))
+            .filter_map(|song| gain_track(song))
+            .collect();
+        skipped += singles.len() - tracks.len();
+        if !tracks.is_empty() {
+            groups.push(GainGroup {
+                tracks,
+                album: false,
+            });
+        }
+
+        (groups, skipped)
+    }
+
+    /// Stores measured values as tags on the songs. `write_back` also writes
+    /// them to the tags of the files themselves, in the format it gives,
+    /// which is skipped for songs from CUE sheets. Returns the files which could not be written.
+    pub fn apply_gain(
+        &mut self,
+        values: &[(Uuid, GainValues)],
+        write_back: Option<&ConfigTagWriting>,
+    ) -> Vec<(PathBuf, String)> {
+        let mut failed_writes = Vec::new();
+        for (uuid, values) in values {
+            let Some(song) = self.library.iter_mut().find(|s| s.uuid == *uuid) else {
+                continue;
+            };
+
+            let mut items = vec![
+                (ItemKey::ReplayGainTrackGain, format_gain(values.track_gain)),
+                (ItemKey::ReplayGainTrackPeak, format_peak(values.track_peak)),
+            ];
+            if let (Some(gain), Some(peak)) = (values.album_gain, values.album_peak) {
+                items.push((ItemKey::ReplayGainAlbumGain, format_gain(gain)));
+                items.push((ItemKey::ReplayGainAlbumPeak, format_peak(peak)));
+            }
+
+            for (key, value) in &items {
+                song.set_tag(Tag::Key(format!("{:?}", key)), value.clone());
+            }
+            // Files have no standard place for these, so they aren't
+            // written back
+            song.set_tag(
+                Tag::Key(TRACK_LOUDNESS.to_string()),
+                format_loudness(values.track_loudness),
+            );
+            match values
+                .album_loudness
+                .filter(|_| values.album_gain.is_some())
+            {
+                Some(loudness) => song.set_tag(
+                    Tag::Key(ALBUM_LOUDNESS.to_string()),
+                    format_loudness(loudness),
+                ),
+                None => song.remove_tag(&Tag::Key(ALBUM_LOUDNESS.to_string())),
+            }
+            if values.end_loudness.is_some() {
+                song.set_end_loudness(values.end_loudness);
+            }
+            if values.silence.is_some() {
+                song.silence = values.silence;
+            }
+            if let Some(options) = write_back {
+                if let Some(URI::Local(path)) = song.location.first() {
+                    if let Err(e) = write_tag_items(path, items, options) {
+                        failed_writes.push((path.clone(), e));
+                    }
+                }
+            }
+        }
+        failed_writes
+    }
+}
+
+fn format_gain(gain: f64) -> String {
+    format!("{gain:.2} dB")
+}
+
+fn format_peak(peak: f64) -> String {
+    format!("{peak:.6}")
+}
+
+fn format_loudness(loudness: f64) -> String {
+    format!("{loudness:.2} LUFS")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::TAU, fs, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{analyze, format_gain, GainError, GainGroup, GainTrack};
+    use crate::music_storage::silence::DEFAULT_SILENCE_THRESHOLD_DB;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Writes five seconds of a 1kHz stereo sine wave peaking at `dbfs`
+    fn sine_wav(dbfs: f32) -> PathBuf {
+        let amplitude = 10f32.powf(dbfs / 20.0);
+        let frames = SAMPLE_RATE * 5;
+        let data_len = frames * 4;
+
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(SAMPLE_RATE.to_le_bytes());
+        wav.extend((SAMPLE_RATE * 4).to_le_bytes());
+        wav.extend(4u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for i in 0..frames {
+            let sample = amplitude * (TAU * 1000.0 * i as f32 / SAMPLE_RATE as f32).sin();
+            let sample = (sample * i16::MAX as f32) as i16;
+            wav.extend(sample.to_le_bytes());
+            wav.extend(sample.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join(format!("{}_gain.wav", Uuid::new_v4()));
+        fs::write(&path, wav).unwrap();
+        path
+    }
+
+    fn group(paths: &[&PathBuf], album: bool) -> GainGroup {
+        GainGroup {
+            tracks: paths
+                .iter()
+                .map(|path| GainTrack {
+                    uuid: Uuid::new_v4(),
+                    path: path.to_path_buf(),
+                    range: None,
+                })
+                .collect(),
+            album,
+        }
+    }
+
+    #[test]
+    fn known_loudness() {
+        // A stereo 1kHz sine peaking at -23 dBFS measures -23 LUFS
+        let path = sine_wav(-23.0);
+        let analysis = analyze(
+            &[group(&[&path], false)],
+            DEFAULT_SILENCE_THRESHOLD_DB,
+            |_| true,
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (_, values) = analysis.values[0];
+        assert!((values.track_gain - 5.0).abs() < 0.1, "{values:?}");
+        assert!((values.track_peak - 0.0708).abs() < 0.001, "{values:?}");
+        assert_eq!(values.album_gain, None);
+        assert_eq!(format_gain(values.track_gain), "5.00 dB");
+        assert!((values.track_loudness + 23.0).abs() < 0.1, "{values:?}");
+        // A sine's RMS level is 3 dB under its peak
+        assert_eq!(values.end_loudness, Some(-26));
+    }
+
+    #[test]
+    fn album_gain_covers_every_track() {
+        let loud = sine_wav(-23.0);
+        let quiet = sine_wav(-33.0);
+        let analysis = analyze(
+            &[group(&[&loud, &quiet], true)],
+            DEFAULT_SILENCE_THRESHOLD_DB,
+            |_| true,
+        )
+        .unwrap();
+        fs::remove_file(&loud).unwrap();
+        fs::remove_file(&quiet).unwrap();
+
+        // Both tracks are the same length, so the album is as loud as the
+        // average of their energy
+        let expected = -18.0 - 10.0 * ((10f64.powf(-2.3) + 10f64.powf(-3.3)) / 2.0).log10();
+        let [(_, loud), (_, quiet)] = analysis.values[..] else {
+            panic!("{analysis:?}")
+        };
+        assert!((quiet.track_gain - 15.0).abs() < 0.1, "{quiet:?}");
+        assert_eq!(loud.album_gain, quiet.album_gain);
+        assert!(
+            (loud.album_gain.unwrap() - expected).abs() < 0.1,
+            "{loud:?}"
+        );
+        assert_eq!(loud.album_peak, Some(loud.track_peak));
+    }
+
+    #[test]
+    fn silence_and_cancellation() {
+        let silent = sine_wav(-200.0);
+        let analysis = analyze(
+            &[group(&[&silent], false)],
+            DEFAULT_SILENCE_THRESHOLD_DB,
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(analysis.failed.len(), 1);
+
+        let loud = sine_wav(-10.0);
+        let res = analyze(
+            &[group(&[&loud], false), group(&[&silent], false)],
+            DEFAULT_SILENCE_THRESHOLD_DB,
+            |_| false,
+        );
+        fs::remove_file(&silent).unwrap();
+        fs::remove_file(&loud).unwrap();
+        assert!(matches!(res, Err(GainError::Cancelled)));
+    }
+}
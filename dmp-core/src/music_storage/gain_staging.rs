@@ -0,0 +1,406 @@
+//! How loud songs play and how they fade into each other, combining the settings of a song, of
+//! where it's played from, and of the player as a whole.
+//!
+//! Each setting is taken from the most specific place it's set:
+//! 1. A song's own [`InternalTag::VolumeAdjustment`], which replaces the
+//!    ReplayGain and preamp it would otherwise get
+//! 2. The [`PlaybackProfile`] of the playlist it's played from, then of the
+//!    library
+//! 3. The global profile in [`ConfigPlayback`](crate::config::ConfigPlayback)
+//!
+//! Profiles only set what they need to, so a playlist can change the
+//! crossfade and keep the global ReplayGain mode.
+//!
+//! Stored ReplayGain values bring songs to [`REFERENCE_LOUDNESS`], and are
+//! moved to the configured target loudness when they're applied. Songs
+//! measured by [`gain_analysis`](super::gain_analysis) are brought to the
+//! target from their measured loudness instead.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    gain_analysis::{
+        ALBUM_GAIN, ALBUM_LOUDNESS, ALBUM_PEAK, REFERENCE_LOUDNESS, TRACK_GAIN, TRACK_LOUDNESS,
+        TRACK_PEAK,
+    },
+    library::{InternalTag, Song, Tag},
+};
+
+/// The loudness songs are brought to by default, the same as the one stored
+/// ReplayGain values are relative to
+pub const DEFAULT_TARGET_LUFS: f32 = REFERENCE_LOUDNESS as f32;
+
+/// Which ReplayGain values songs are played with
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    /// Keeps the differences in loudness between songs of an album, using
+    /// the track values for songs without album values
+    Album,
+}
+
+/// Playback settings for a playlist or library, any of which can be left to
+/// fall back on the global ones
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackProfile {
+    pub replay_gain_mode: Option<ReplayGainMode>,
+    /// Gain in dB added to the ReplayGain values
+    pub preamp: Option<f32>,
+    /// How long songs fade into each other, in milliseconds
+    pub crossfade: Option<u32>,
+}
+
+impl PlaybackProfile {
+    pub fn is_empty(&self) -> bool {
+        *self == PlaybackProfile::default()
+    }
+
+    /// Fills in the settings this profile leaves unset from `fallback`
+    pub fn or(self, fallback: PlaybackProfile) -> PlaybackProfile {
+        PlaybackProfile {
+            replay_gain_mode: self.replay_gain_mode.or(fallback.replay_gain_mode),
+            preamp: self.preamp.or(fallback.preamp),
+            crossfade: self.crossfade.or(fallback.crossfade),
+        }
+    }
+}
+
+/// The settings songs end up playing with, once every profile is applied
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ResolvedProfile {
+    pub replay_gain_mode: ReplayGainMode,
+    pub preamp: f32,
+    pub crossfade: u32,
+}
+
+impl Default for ResolvedProfile {
+    fn default() -> Self {
+        ResolvedProfile {
+            replay_gain_mode: ReplayGainMode::Off,
+            preamp: 0.0,
+            crossfade: 0,
+        }
+    }
+}
+
+impl ResolvedProfile {
+    /// Layers the profiles of where songs are played from, most specific
+    /// first, over the global profile
+    pub fn resolve(locations: &[Option<PlaybackProfile>], global: PlaybackProfile) -> Self {
+        let profile = locations
+            .iter()
+            .flatten()
+            .fold(PlaybackProfile::default(), |profile, location| {
+                profile.or(*location)
+            })
+            .or(global);
+
+        let defaults = ResolvedProfile::default();
+        ResolvedProfile {
+            replay_gain_mode: profile
+                .replay_gain_mode
+                .unwrap_or(defaults.replay_gain_mode),
+            preamp: profile
+                .preamp
+                .filter(|preamp| preamp.is_finite())
+                .unwrap_or(defaults.preamp),
+            crossfade: profile.crossfade.unwrap_or(defaults.crossfade),
+        }
+    }
+
+    /// The factor to multiply the output volume by while `song` plays,
+    /// with ReplayGain values bringing it to the default loudness
+    pub fn song_gain(&self, song: &Song) -> f32 {
+        self.gain_at(song, DEFAULT_TARGET_LUFS).factor
+    }
+
+    /// How the volume is changed while `song` plays, with ReplayGain values
+    /// bringing it to `target_lufs`
+    pub fn gain_at(&self, song: &Song, target_lufs: f32) -> SongGain {
+        // The song's own adjustment wins over anything from a profile
+        if let Some(adjustment) = song.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::VolumeAdjustment(adjustment) => Some(*adjustment),
+            _ => None,
+        }) {
+            return SongGain::of(1.0 + f32::from(adjustment.clamp(-100, 100)) / 100.0);
+        }
+
+        let track = || {
+            let gain = retarget(song, TRACK_GAIN, TRACK_LOUDNESS, target_lufs)?;
+            Some((gain, tag_value(song, TRACK_PEAK)))
+        };
+        let values = match self.replay_gain_mode {
+            ReplayGainMode::Off => return SongGain::of(1.0),
+            ReplayGainMode::Track => track(),
+            ReplayGainMode::Album => retarget(song, ALBUM_GAIN, ALBUM_LOUDNESS, target_lufs)
+                .map(|gain| (gain, tag_value(song, ALBUM_PEAK)))
+                .or_else(track),
+        };
+        let Some((gain, peak)) = values else {
+            return SongGain::of(db_to_linear(self.preamp));
+        };
+
+        let db = gain + self.preamp;
+        let factor = db_to_linear(db);
+        // Never raise a song so far that its loudest sample clips
+        match peak.filter(|peak| *peak > 0.0 && factor > 1.0 / peak) {
+            Some(peak) => SongGain {
+                db,
+                factor: 1.0 / peak,
+                peak_limited: true,
+            },
+            None => SongGain {
+                db,
+                factor,
+                peak_limited: false,
+            },
+        }
+    }
+}
+
+/// How much a song's volume is changed by while it plays
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SongGain {
+    /// The gain in dB from ReplayGain and the preamp, or from the song's
+    /// own adjustment
+    pub db: f32,
+    /// What the volume is multiplied by
+    pub factor: f32,
+    /// Whether `factor` was lowered from `db` to keep the loudest sample
+    /// from clipping
+    pub peak_limited: bool,
+}
+
+impl SongGain {
+    fn of(factor: f32) -> Self {
+        SongGain {
+            // Silence is shown as -120 dB rather than negative infinity
+            db: 20.0 * factor.max(1e-6).log10(),
+            factor,
+            peak_limited: false,
+        }
+    }
+}
+
+/// Where the profile which is being applied came from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ProfileSource {
+    Playlist(Uuid),
+    Library,
+}
+
+/// A profile overriding the global settings, shown to the user while it's
+/// in use
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ActiveProfile {
+    pub source: ProfileSource,
+    pub settings: ResolvedProfile,
+}
+
+/// What's applied to the playing song, shared with the player monitor for
+/// [`PlaybackInfo`](crate::music_controller::controller::PlaybackInfo)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AppliedPlayback {
+    pub profile: Option<ActiveProfile>,
+    pub gain: Option<SongGain>,
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// The gain in dB which brings `song` to `target_lufs`, from its measured
+/// loudness when it has one, otherwise by moving its stored gain from the
+/// reference loudness to the target
+fn retarget(song: &Song, gain_key: &str, loudness_key: &str, target_lufs: f32) -> Option<f32> {
+    match tag_value(song, loudness_key) {
+        Some(loudness) => Some(target_lufs - loudness),
+        None => Some(tag_value(song, gain_key)? + target_lufs - DEFAULT_TARGET_LUFS),
+    }
+}
+
+/// Reads a gain, peak or loudness tag, which gains and loudness are stored
+/// in with a `dB` or `LUFS` suffix
+fn tag_value(song: &Song, key: &str) -> Option<f32> {
+    let value = song.get_tag(&Tag::Key(key.to_string()))?;
+    let value = value.trim();
+    let value = value
+        .strip_suffix("LUFS")
+        .or_else(|| value.strip_suffix("dB"))
+        .unwrap_or(value)
+        .trim_end();
+    value.parse().ok().filter(|value: &f32| value.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::music_storage::{
+        gain_analysis::{ALBUM_GAIN, ALBUM_LOUDNESS, TRACK_GAIN, TRACK_LOUDNESS, TRACK_PEAK},
+        library::{InternalTag, Song, Tag},
+    };
+
+    use super::{PlaybackProfile, ReplayGainMode, ResolvedProfile};
+
+    fn song(tags: &[(&str, &str)]) -> Song {
+        Song {
+            tags: tags
+                .iter()
+                .map(|(key, value)| (Tag::Key(key.to_string()), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn profile_precedence() {
+        let global = PlaybackProfile {
+            replay_gain_mode: Some(ReplayGainMode::Track),
+            preamp: Some(-3.0),
+            crossfade: Some(2000),
+        };
+        let library = PlaybackProfile {
+            preamp: Some(2.0),
+            crossfade: Some(1000),
+            ..Default::default()
+        };
+        let playlist = PlaybackProfile {
+            replay_gain_mode: Some(ReplayGainMode::Album),
+            crossfade: Some(500),
+            ..Default::default()
+        };
+
+        // Without profiles the global settings are used as they are
+        let resolved = ResolvedProfile::resolve(&[None, None], global);
+        assert_eq!(resolved.replay_gain_mode, ReplayGainMode::Track);
+        assert_eq!(resolved.preamp, -3.0);
+        assert_eq!(resolved.crossfade, 2000);
+
+        // The playlist wins over the library, which wins over the global
+        // profile, one setting at a time
+        let resolved = ResolvedProfile::resolve(&[Some(playlist), Some(library)], global);
+        assert_eq!(
+            resolved,
+            ResolvedProfile {
+                replay_gain_mode: ReplayGainMode::Album,
+                preamp: 2.0,
+                crossfade: 500,
+            }
+        );
+
+        // Settings which make no sense fall back to the defaults
+        let broken = PlaybackProfile {
+            preamp: Some(f32::NAN),
+            ..Default::default()
+        };
+        let resolved = ResolvedProfile::resolve(&[Some(broken)], PlaybackProfile::default());
+        assert_eq!(resolved, ResolvedProfile::default());
+    }
+
+    #[test]
+    fn song_adjustment_wins() {
+        let profile = ResolvedProfile {
+            replay_gain_mode: ReplayGainMode::Track,
+            preamp: 6.0,
+            ..Default::default()
+        };
+        let mut song = song(&[(TRACK_GAIN, "-6.00 dB")]);
+        assert!(close(profile.song_gain(&song), 1.0));
+
+        song.internal_tags.push(InternalTag::VolumeAdjustment(-50));
+        assert!(close(profile.song_gain(&song), 0.5));
+    }
+
+    #[test]
+    fn replay_gain_modes() {
+        let song = song(&[
+            (TRACK_GAIN, "-6.02 dB"),
+            (TRACK_PEAK, "0.5"),
+            (ALBUM_GAIN, "-12.04 dB"),
+        ]);
+        let gain = |replay_gain_mode, preamp| {
+            ResolvedProfile {
+                replay_gain_mode,
+                preamp,
+                ..Default::default()
+            }
+            .song_gain(&song)
+        };
+
+        assert!(close(gain(ReplayGainMode::Off, 6.0), 1.0));
+        assert!(close(gain(ReplayGainMode::Track, 0.0), 0.5));
+        assert!(close(gain(ReplayGainMode::Album, 0.0), 0.25));
+        // The preamp can't push the track past its peak
+        assert!(close(gain(ReplayGainMode::Track, 20.0), 2.0));
+
+        // Album mode uses the track values when there are no album ones,
+        // and songs without any values only get the preamp
+        let untagged = self::song(&[]);
+        let track_only = self::song(&[(TRACK_GAIN, "-6.02")]);
+        let album = ResolvedProfile {
+            replay_gain_mode: ReplayGainMode::Album,
+            preamp: -6.02,
+            ..Default::default()
+        };
+        assert!(close(album.song_gain(&track_only), 0.25));
+        assert!(close(album.song_gain(&untagged), 0.5));
+    }
+
+    #[test]
+    fn target_loudness() {
+        let track = ResolvedProfile {
+            replay_gain_mode: ReplayGainMode::Track,
+            ..Default::default()
+        };
+        let album = ResolvedProfile {
+            replay_gain_mode: ReplayGainMode::Album,
+            ..track
+        };
+        let tagged = song(&[(TRACK_GAIN, "-6.00 dB"), (ALBUM_GAIN, "-4.00 dB")]);
+        let measured = song(&[
+            (TRACK_GAIN, "-6.00 dB"),
+            (TRACK_LOUDNESS, "-12.00 LUFS"),
+            (ALBUM_LOUDNESS, "-14.50 LUFS"),
+        ]);
+
+        // The profile, the song, the target loudness, and the gain applied
+        let cases = [
+            (&track, &tagged, -18.0, -6.0),
+            (&track, &tagged, -23.0, -11.0),
+            (&track, &tagged, -14.0, -2.0),
+            (&album, &tagged, -23.0, -9.0),
+            (&album, &tagged, -10.0, 4.0),
+            // Measured loudness is brought to the target directly
+            (&track, &measured, -18.0, -6.0),
+            (&track, &measured, -30.0, -18.0),
+            (&album, &measured, -23.0, -8.5),
+        ];
+        for (profile, song, target, db) in cases {
+            let gain = profile.gain_at(song, target);
+            assert!(close(gain.db, db), "{target}: {gain:?}");
+            assert!(close(gain.factor, 10f32.powf(db / 20.0)), "{gain:?}");
+            assert!(!gain.peak_limited);
+        }
+
+        // The default target is the reference, so stored gains apply as
+        // they are
+        assert!(close(
+            track.song_gain(&tagged),
+            track.gain_at(&tagged, -18.0).factor
+        ));
+
+        // A louder target can run into the peak limit
+        let peaked = song(&[(TRACK_GAIN, "0 dB"), (TRACK_PEAK, "0.5")]);
+        let gain = track.gain_at(&peaked, -10.0);
+        assert!(close(gain.db, 8.0));
+        assert!(close(gain.factor, 2.0));
+        assert!(gain.peak_limited);
+    }
+}
@@ -0,0 +1,145 @@
+//! Telling when two songs are meant to flow straight into each other, like
+//! the tracks of a live album or a DJ mix, so crossfading between them can
+//! be skipped. Crossfading there cuts into the music rather than a fade out.
+
+use super::library::{AlbumKey, InternalTag, MusicLibrary, Song, Tag};
+
+/// How much of the end of a song [`Song::end_loudness`] measures
+pub const END_LENGTH_SECS: u32 = 2;
+
+/// The quietest the end of a song can be, as an RMS level in dBFS, and
+/// still count as running straight into the next song rather than fading
+/// out or ending in silence
+pub const FULL_VOLUME_END: i8 = -40;
+
+impl Song {
+    /// The RMS level of the last [`END_LENGTH_SECS`] of the song in dBFS,
+    /// measured along with its gain
+    pub fn end_loudness(&self) -> Option<i8> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::EndLoudness(level) => Some(*level),
+            _ => None,
+        })
+    }
+
+    pub fn set_end_loudness(&mut self, level: Option<i8>) {
+        self.internal_tags
+            .retain(|tag| !matches!(tag, InternalTag::EndLoudness(_)));
+        self.internal_tags
+            .extend(level.map(InternalTag::EndLoudness));
+    }
+
+    /// Whether the song's album was marked to always play without
+    /// crossfading
+    pub fn always_gapless(&self) -> bool {
+        self.internal_tags.contains(&InternalTag::AlwaysGapless)
+    }
+
+    fn set_always_gapless(&mut self, gapless: bool) {
+        self.internal_tags
+            .retain(|tag| *tag != InternalTag::AlwaysGapless);
+        if gapless {
+            self.internal_tags.push(InternalTag::AlwaysGapless);
+        }
+    }
+}
+
+/// Whether `previous` runs straight into `next`, so they should be played
+/// gaplessly even when crossfading is on. They have to be consecutive tracks
+/// of the same album, and `previous` has to end at full volume unless the
+/// album is marked as always gapless. Songs which were never analyzed are
+/// crossfaded as usual.
+pub fn flows_into(previous: &Song, next: &Song) -> bool {
+    let album = |song: &Song| {
+        (
+            song.get_tag(&Tag::Album).cloned(),
+            song.get_tag(&Tag::AlbumArtist).cloned(),
+        )
+    };
+    if album(previous).0.is_none() || album(previous) != album(next) {
+        return false;
+    }
+
+    let consecutive = previous.disc_number().unwrap_or(1) == next.disc_number().unwrap_or(1)
+        && previous
+            .track_number()
+            .zip(next.track_number())
+            .is_some_and(|(previous, next)| previous.checked_add(1) == Some(next));
+    if !consecutive {
+        return false;
+    }
+
+    previous.always_gapless()
+        || previous
+            .end_loudness()
+            .is_some_and(|level| level >= FULL_VOLUME_END)
+}
+
+impl MusicLibrary {
+    /// Marks an album to always play without crossfading between its
+    /// tracks, or goes back to detecting it
+    pub fn set_album_gapless(&mut self, key: &AlbumKey, gapless: bool) -> Result<(), String> {
+        self.update_album(key, |song| song.set_always_gapless(gapless))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::music_storage::library::{Song, Tag};
+
+    use super::flows_into;
+
+    fn track(album: &str, disc: u16, number: u16, end_loudness: Option<i8>) -> Song {
+        let mut song = Song::default();
+        song.set_tag(Tag::Album, album.to_string());
+        song.set_tag(Tag::Disk, disc.to_string());
+        song.set_tag(Tag::Track, number.to_string());
+        song.set_end_loudness(end_loudness);
+        song
+    }
+
+    #[test]
+    fn gapless_pairs() {
+        let live = |number, end| track("Live at Budokan", 1, number, end);
+
+        // Applause running into the next song
+        assert!(flows_into(&live(3, Some(-18)), &live(4, None)));
+        assert!(flows_into(&live(3, Some(-40)), &live(4, None)));
+        // Fading out, or ending in silence
+        assert!(!flows_into(&live(3, Some(-41)), &live(4, None)));
+        assert!(!flows_into(&live(3, Some(i8::MIN)), &live(4, None)));
+        // Never analyzed
+        assert!(!flows_into(&live(3, None), &live(4, None)));
+
+        // Not next to each other
+        assert!(!flows_into(&live(3, Some(-18)), &live(5, None)));
+        assert!(!flows_into(&live(4, Some(-18)), &live(3, None)));
+        assert!(!flows_into(
+            &live(3, Some(-18)),
+            &track("Live at Budokan", 2, 4, None)
+        ));
+        assert!(!flows_into(
+            &live(3, Some(-18)),
+            &track("Studio", 1, 4, None)
+        ));
+        let mut other_artist = live(4, None);
+        other_artist.set_tag(Tag::AlbumArtist, "Someone Else".to_string());
+        assert!(!flows_into(&live(3, Some(-18)), &other_artist));
+
+        // Without an album there's nothing to say they belong together
+        let mut single = live(3, Some(-18));
+        single.remove_tag(&Tag::Album);
+        let mut next = live(4, None);
+        next.remove_tag(&Tag::Album);
+        assert!(!flows_into(&single, &next));
+
+        // Marked by hand, however it ends
+        let mut marked = live(3, Some(-60));
+        marked.set_always_gapless(true);
+        assert!(flows_into(&marked, &live(4, None)));
+        assert!(!flows_into(&marked, &live(6, None)));
+        marked.set_always_gapless(false);
+        assert!(!marked.always_gapless());
+        assert!(!flows_into(&marked, &live(4, None)));
+    }
+}
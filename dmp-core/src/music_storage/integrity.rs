@@ -0,0 +1,391 @@
+//! Finding files which were damaged on disk, like by bit rot. Each file's
+//! hash is stored the first time it's checked, and later checks compare
+//! against it. A file which changed without its modification time changing
+//! wasn't written to, so it's most likely corrupted, while one which did
+//! was probably just retagged.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+};
+
+use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{
+    decode::{verify_checksum, DecodeError},
+    gain_analysis::AnalyzeScope,
+    library::{MusicLibrary, Song, URI},
+};
+
+/// How much of a file is read at once while hashing it
+const HASH_CHUNK: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("Verifying files was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// What a file looked like when it was last checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+pub struct FileHash {
+    /// The xxh3 hash of the whole file
+    pub hash: u64,
+    pub size: u64,
+    /// When the file was last written to, to the millisecond
+    #[serde(with = "ts_milliseconds_option")]
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl FileHash {
+    /// Hashes the file at `path`, stopping early once `cancelled` is set
+    pub fn read(path: &Path, cancelled: &AtomicBool) -> Result<Self, VerifyError> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        // Only milliseconds are saved, so compare at that precision
+        let modified = metadata
+            .modified()
+            .ok()
+            .map(|time| DateTime::<Utc>::from(time).timestamp_millis())
+            .and_then(DateTime::from_timestamp_millis);
+
+        let mut hasher = Xxh3::new();
+        let mut buffer = vec![0; HASH_CHUNK];
+        loop {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(VerifyError::Cancelled);
+            }
+            match file.read(&mut buffer)? {
+                0 => break,
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+
+        Ok(FileHash {
+            hash: hasher.digest(),
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// A song's file to be checked
+#[derive(Debug, Clone)]
+pub struct VerifyTarget {
+    pub uuid: Uuid,
+    pub path: PathBuf,
+    /// The hash stored the last time it was checked
+    pub known: Option<FileHash>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct VerifyProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileProblem {
+    pub uuid: Uuid,
+    #[serde(with = "super::os_path")]
+    pub path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct VerifyReport {
+    /// The number of files which were hashed for the first time
+    pub hashed: usize,
+    pub unchanged: usize,
+    /// Files which changed along with their modification time, like when
+    /// their tags were edited
+    pub retagged: Vec<(Uuid, PathBuf)>,
+    /// Files which changed without being written to, or whose audio
+    /// doesn't match the checksum stored in them
+    pub corrupt: Vec<FileProblem>,
+    /// Files which couldn't be read
+    pub failed: Vec<FileProblem>,
+    /// The number of songs without a local file
+    pub skipped: usize,
+    /// The hashes to store for the next check
+    #[serde(skip)]
+    pub hashes: Vec<(Uuid, FileHash)>,
+}
+
+/// What checking one file found
+enum Outcome {
+    Hashed(FileHash),
+    Unchanged,
+    Retagged(FileHash),
+    Corrupt(String),
+}
+
+/// The pool files are checked on. Reading whole files is mostly waiting on
+/// the disk, so only a couple are read at once to leave it free for playback.
+fn pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("verify-files-{i}"))
+            .build()
+            .unwrap()
+    })
+}
+
+/// Checks every target, calling `progress` as each file is finished. With
+/// `deep`, files with a checksum of their audio, like FLAC files, are also
+/// decoded to check it. Returning `false` from `progress` cancels the check.
+pub fn verify(
+    targets: &[VerifyTarget],
+    deep: bool,
+    progress: impl Fn(VerifyProgress) -> bool + Sync,
+) -> Result<VerifyReport, VerifyError> {
+    let total = targets.len();
+    let done = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+
+    let checked: Vec<_> = pool().install(|| {
+        targets
+            .par_iter()
+            .map(|target| {
+                let res = check(target, deep, &cancelled);
+                let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+                if !progress(VerifyProgress { done, total }) {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                (target, res)
+            })
+            .collect()
+    });
+    if cancelled.load(Ordering::SeqCst) {
+        return Err(VerifyError::Cancelled);
+    }
+
+    let mut report = VerifyReport::default();
+    for (target, res) in checked {
+        let problem = |message: String| FileProblem {
+            uuid: target.uuid,
+            path: target.path.clone(),
+            message,
+        };
+        match res {
+            Ok(Outcome::Hashed(hash)) => {
+                report.hashed += 1;
+                report.hashes.push((target.uuid, hash));
+            }
+            Ok(Outcome::Unchanged) => report.unchanged += 1,
+            Ok(Outcome::Retagged(hash)) => {
+                report.retagged.push((target.uuid, target.path.clone()));
+                report.hashes.push((target.uuid, hash));
+            }
+            // The old hash is kept, so it's reported again until it's fixed
+            Ok(Outcome::Corrupt(message)) => report.corrupt.push(problem(message)),
+            Err(VerifyError::Cancelled) => return Err(VerifyError::Cancelled),
+            Err(e) => report.failed.push(problem(e.to_string())),
+        }
+    }
+
+    Ok(report)
+}
+
+fn check(
+    target: &VerifyTarget,
+    deep: bool,
+    cancelled: &AtomicBool,
+) -> Result<Outcome, VerifyError> {
+    let hash = FileHash::read(&target.path, cancelled)?;
+    let outcome = match target.known {
+        None => Outcome::Hashed(hash),
+        Some(known) if known.hash == hash.hash => Outcome::Unchanged,
+        Some(known) if known.modified != hash.modified => Outcome::Retagged(hash),
+        Some(_) => {
+            return Ok(Outcome::Corrupt(
+                "The file changed without being written to".to_string(),
+            ))
+        }
+    };
+
+    if deep {
+        let checked = verify_checksum(&target.path, || !cancelled.load(Ordering::SeqCst)).map_err(
+            |e| match e {
+                DecodeError::Cancelled => VerifyError::Cancelled,
+                e => e.into(),
+            },
+        )?;
+        if checked == Some(false) {
+            return Ok(Outcome::Corrupt(
+                "The audio doesn't match the checksum stored in the file".to_string(),
+            ));
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// The local file of a song, if it has one. Missing files are still
+/// checked, so they're reported rather than skipped.
+fn local_path(song: &Song) -> Option<PathBuf> {
+    song.location.iter().find_map(|uri| match uri {
+        URI::Local(path) => Some(path.clone()),
+        URI::Cue { location, .. } => Some(location.clone()),
+        URI::Remote(..) => None,
+    })
+}
+
+impl MusicLibrary {
+    /// The files of the songs in `scope` to check, and the number of songs
+    /// skipped for not having a local file
+    pub fn verify_targets(&self, scope: &AnalyzeScope) -> (Vec<VerifyTarget>, usize) {
+        let songs: Vec<&Song> = match scope {
+            AnalyzeScope::Album(key) => self
+                .query_album(key)
+                .map(|album| {
+                    album
+                        .discs()
+                        .values()
+                        .flatten()
+                        .filter_map(|(_, uuid)| self.query_uuid(uuid).map(|(song, _)| song))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            AnalyzeScope::Songs(uuids) => uuids
+                .iter()
+                .filter_map(|uuid| self.query_uuid(uuid).map(|(song, _)| song))
+                .collect(),
+            AnalyzeScope::WholeLibrary => self.library.iter().collect(),
+        };
+
+        let targets: Vec<VerifyTarget> = songs
+            .iter()
+            .filter_map(|song| {
+                Some(VerifyTarget {
+                    uuid: song.uuid,
+                    path: local_path(song)?,
+                    known: song.file_hash,
+                })
+            })
+            .collect();
+        let skipped = songs.len() - targets.len();
+        (targets, skipped)
+    }
+
+    /// Stores the hashes from a check, for the next one to compare against
+    pub fn store_hashes(&mut self, hashes: &[(Uuid, FileHash)]) {
+        for (uuid, hash) in hashes {
+            if let Some(song) = self.library.iter_mut().find(|song| song.uuid == *uuid) {
+                song.file_hash = Some(*hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        path::PathBuf,
+        sync::atomic::AtomicBool,
+        time::{Duration, SystemTime},
+    };
+
+    use uuid::Uuid;
+
+    use super::{verify, FileHash, VerifyReport};
+    use crate::music_storage::{
+        gain_analysis::AnalyzeScope,
+        library::{MusicLibrary, Service, Song, URI},
+    };
+
+    /// Checks the whole library and stores the hashes, like the controller
+    fn check(lib: &mut MusicLibrary) -> VerifyReport {
+        let (targets, _) = lib.verify_targets(&AnalyzeScope::WholeLibrary);
+        let report = verify(&targets, false, |_| true).unwrap();
+        lib.store_hashes(&report.hashes);
+        report
+    }
+
+    /// Overwrites a file, putting its modification time back if `sneaky`
+    fn overwrite(path: &PathBuf, contents: &[u8], sneaky: bool) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, contents).unwrap();
+        let modified = match sneaky {
+            true => modified,
+            false => SystemTime::now() + Duration::from_secs(60),
+        };
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn changes_behind_the_librarys_back() {
+        let dir = std::env::temp_dir().join(format!("dmp-integrity-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let mut paths = Vec::new();
+        for name in ["rotten.flac", "retagged.flac", "fine.flac"] {
+            let path = dir.join(name);
+            fs::write(&path, format!("{name} audio")).unwrap();
+            lib.library.push(Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(path.clone())],
+                ..Default::default()
+            });
+            paths.push(path);
+        }
+        lib.library.push(Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Remote(
+                Service::InternetRadio,
+                "https://example.com/stream".to_string(),
+            )],
+            ..Default::default()
+        });
+
+        let first = check(&mut lib);
+        assert_eq!(first.hashed, 3);
+        assert_eq!(lib.verify_targets(&AnalyzeScope::WholeLibrary).1, 1);
+
+        overwrite(&paths[0], b"rotten.flac audi0", true);
+        overwrite(&paths[1], b"retagged.flac audio with tags", false);
+        let second = check(&mut lib);
+        assert_eq!(second.unchanged, 1);
+        assert_eq!(second.corrupt.len(), 1);
+        assert_eq!(second.corrupt[0].path, paths[0]);
+        assert_eq!(second.retagged, [(lib.library[1].uuid, paths[1].clone())]);
+
+        // The retagged file's new hash was stored, the corrupt one's wasn't
+        let third = check(&mut lib);
+        assert_eq!(third.unchanged, 2);
+        assert_eq!(third.corrupt.len(), 1);
+
+        fs::remove_file(&paths[2]).unwrap();
+        assert_eq!(check(&mut lib).failed.len(), 1);
+
+        let hash = FileHash::read(&paths[1], &AtomicBool::new(true));
+        assert!(hash.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,27 @@
-use super::playlist::{Playlist, PlaylistFolder};
+use super::art_store::ArtStore;
+use super::cancel::CancelToken;
+use super::integrity::FileHash;
+use super::play_history::PlayHistory;
+use super::playlist::{modified_time, Playlist, PlaylistFolder};
+use super::scan_candidates::ScanCandidates;
+use super::scan_exclusions::ExclusionRules;
+use super::scan_report::{ScanError, ScanErrorKind, ScanPhase, ScanProgress, ScanReport};
+use super::silence::SilenceBounds;
+use super::song_problems::{find_problem, SongProblem};
+use super::tombstones::Tombstones;
 // Crate things
-use super::utils::{find_images, normalize, read_file, write_file};
+use super::utils::{
+    canonicalize, find_images, long_path, normalize, normalize_path, read_file, volume_root,
+    write_file,
+};
 use crate::music_storage::playlist::PlaylistFolderItem;
 
 use std::cmp::Ordering;
 // Various std things
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fmt::Display;
-use std::io::Read;
+use std::fs;
 use std::ops::ControlFlow::{Break, Continue};
 use std::vec::IntoIter;
 
@@ -16,13 +29,13 @@ use std::vec::IntoIter;
 use file_format::{FileFormat, Kind};
 
 use lofty::file::{AudioFile as _, TaggedFileExt as _};
+use lofty::picture::{Picture, PictureInformation, PictureType};
 use lofty::probe::Probe;
 use lofty::tag::{ItemKey, ItemValue, TagType};
 use rcue::parser::parse_from_file;
-use std::fs;
 use std::path::{Path, PathBuf};
+use url::Url;
 use uuid::Uuid;
-use walkdir::WalkDir;
 
 // Time
 use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
@@ -50,6 +63,82 @@ impl AlbumArt {
     }
 }
 
+fn external_art_metadata(art: &AlbumArt) -> ArtMetadata {
+    match art {
+        AlbumArt::External(uri) => ArtMetadata::from_path(&uri.path()),
+        AlbumArt::Embedded(_) => ArtMetadata::default(),
+    }
+}
+
+/// What a piece of album art shows
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ArtKind {
+    FrontCover,
+    BackCover,
+    Artist,
+    Media,
+    #[default]
+    Other,
+}
+
+impl From<PictureType> for ArtKind {
+    fn from(picture_type: PictureType) -> Self {
+        match picture_type {
+            PictureType::CoverFront => ArtKind::FrontCover,
+            PictureType::CoverBack => ArtKind::BackCover,
+            PictureType::LeadArtist | PictureType::Artist | PictureType::Band => ArtKind::Artist,
+            PictureType::Media => ArtKind::Media,
+            _ => ArtKind::Other,
+        }
+    }
+}
+
+/// Details of an entry in [`Song::album_art`], kept at the same index
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct ArtMetadata {
+    pub kind: ArtKind,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl ArtMetadata {
+    pub fn from_picture(picture: &Picture) -> Self {
+        let info = PictureInformation::from_picture(picture).ok();
+        ArtMetadata {
+            kind: picture.pic_type().into(),
+            width: info.map(|info| info.width),
+            height: info.map(|info| info.height),
+        }
+    }
+
+    /// Guesses what an image file shows from its name, like `cover.jpg`
+    pub fn from_path(path: &Path) -> Self {
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let kind = if name.contains("back") {
+            ArtKind::BackCover
+        } else if ["cover", "front", "folder"]
+            .iter()
+            .any(|front| name.contains(front))
+        {
+            ArtKind::FrontCover
+        } else {
+            ArtKind::Other
+        };
+
+        ArtMetadata {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    fn pixels(&self) -> u64 {
+        self.width.unwrap_or(0) as u64 * self.height.unwrap_or(0) as u64
+    }
+}
+
 /// A tag for a song
 #[non_exhaustive]
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -121,6 +210,27 @@ impl Display for Field {
     }
 }
 
+impl Field {
+    /// Compares two values of the same field, or returns `None` for values
+    /// of different fields
+    pub fn cmp_value(&self, other: &Field) -> Option<Ordering> {
+        Some(match (self, other) {
+            (Self::Location(a), Self::Location(b)) => a.to_string().cmp(&b.to_string()),
+            (Self::Plays(a), Self::Plays(b)) | (Self::Skips(a), Self::Skips(b)) => a.cmp(b),
+            (Self::Favorited(a), Self::Favorited(b)) => a.cmp(b),
+            (Self::Rating(a), Self::Rating(b)) => a.cmp(b),
+            (Self::Format(a), Self::Format(b)) => a.cmp(b),
+            (Self::Duration(a), Self::Duration(b)) | (Self::PlayTime(a), Self::PlayTime(b)) => {
+                a.cmp(b)
+            }
+            (Self::LastPlayed(a), Self::LastPlayed(b))
+            | (Self::DateAdded(a), Self::DateAdded(b))
+            | (Self::DateModified(a), Self::DateModified(b)) => a.cmp(b),
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 #[non_exhaustive]
 pub enum InternalTag {
@@ -129,6 +239,23 @@ pub enum InternalTag {
     SongLink(Uuid, SongType),
     // Volume Adjustment from -100% to 100%
     VolumeAdjustment(i8),
+    /// The song's rating was given to its whole album rather than to the
+    /// song itself, so rating the album again replaces it
+    AlbumRating,
+    /// The song was favorited along with its whole album
+    AlbumFavorite,
+    /// The song's file couldn't be found when it was about to play
+    Missing,
+    /// The song was marked as explicit or not by hand, which overrides what
+    /// its tags say
+    Explicit(bool),
+    /// The song was found to be silent or to have a broken length
+    Problem(SongProblem),
+    /// How loud the end of the song is in dBFS, to tell whether it runs
+    /// straight into the next one
+    EndLoudness(i8),
+    /// The song's album always plays without crossfading between tracks
+    AlwaysGapless,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -159,8 +286,44 @@ pub enum SongType {
     Custom(String),
 }
 
+/// How far apart the track numbers of vinyl sides are, so that `A1` becomes
+/// 1, `B1` becomes 101, and so on
+pub const SIDE_TRACK_OFFSET: u16 = 100;
+
+/// Parses the number from a track tag, tolerating the formats found in the
+/// wild: `3`, `03`, `3/12`, and vinyl-style sides like `A2` or `b1`
+pub fn parse_track_number(value: &str) -> Option<u16> {
+    // Only the numerator of "track/total" matters
+    let value = value.split('/').next()?.trim();
+
+    let (offset, number) = match value.chars().next()? {
+        side if side.is_ascii_alphabetic() => {
+            let side = side.to_ascii_uppercase() as u16 - b'A' as u16;
+            (side * SIDE_TRACK_OFFSET, value[1..].trim_start())
+        }
+        _ => (0, value),
+    };
+
+    // A side far enough along the alphabet can push a number past what
+    // fits, which can't be a real track
+    leading_number(number).and_then(|number| offset.checked_add(number))
+}
+
+/// Parses the number from a disc tag, which can be in the `1/2` format
+pub fn parse_disc_number(value: &str) -> Option<u16> {
+    leading_number(value.split('/').next()?.trim())
+}
+
+/// Parses the digits at the start of `value`, ignoring anything after them
+fn leading_number(value: &str) -> Option<u16> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
 /// Stores information about a single song
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
 pub struct Song {
     pub location: Vec<URI>,
     pub uuid: Uuid,
@@ -175,11 +338,33 @@ pub struct Song {
     pub play_time: Duration,
     #[serde(with = "ts_milliseconds_option")]
     pub last_played: Option<DateTime<Utc>>,
+    /// When the song's play count first went up
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub first_played: Option<DateTime<Utc>>,
     #[serde(with = "ts_milliseconds_option")]
     pub date_added: Option<DateTime<Utc>>,
     #[serde(with = "ts_milliseconds_option")]
     pub date_modified: Option<DateTime<Utc>>,
     pub album_art: Vec<AlbumArt>,
+    /// Details of each image in `album_art`, at the same index
+    #[serde(default)]
+    pub art_metadata: Vec<ArtMetadata>,
+    /// The index of the image in `album_art` to show, chosen by the user
+    #[serde(default)]
+    pub preferred_art: Option<usize>,
+    /// Where playback starts and ends in the file, set by the user to cut
+    /// off long intros or silence. `None` plays all of it.
+    #[serde(default)]
+    pub trim: Option<(Duration, Duration)>,
+    /// Where the sound in the file starts and ends, found the first time
+    /// it's needed to skip the silence around it
+    #[serde(default)]
+    pub silence: Option<SilenceBounds>,
+    /// The file's hash from the last time it was verified, see
+    /// [`integrity`](super::integrity)
+    #[serde(default)]
+    pub file_hash: Option<FileHash>,
+    #[serde(with = "super::tag_keys")]
     pub tags: BTreeMap<Tag, String>,
     pub internal_tags: Vec<InternalTag>,
 }
@@ -199,11 +384,16 @@ impl Song {
         self.tags.get(target_key)
     }
 
-    /// Gets an internal field from a song
+    /// Gets an internal field from a song. Returns `None` when the song
+    /// has no value for it, like a date it doesn't have, or when there's no
+    /// field by that name.
     pub fn get_field(&self, target_field: &str) -> Option<Field> {
         let lower_target = target_field.to_lowercase();
         match lower_target.as_str() {
-            "location" => Some(Field::Location(self.primary_uri().unwrap().0.clone())), //TODO: make this not unwrap()
+            "location" => self
+                .primary_uri()
+                .ok()
+                .map(|(uri, _)| Field::Location(uri.clone())),
             "plays" => Some(Field::Plays(self.plays)),
             "skips" => Some(Field::Skips(self.skips)),
             "favorited" => Some(Field::Favorited(self.favorited)),
@@ -211,7 +401,153 @@ impl Song {
             "duration" => Some(Field::Duration(self.duration)),
             "play_time" => Some(Field::PlayTime(self.play_time)),
             "format" => self.format.clone().map(Field::Format),
-            _ => todo!(), // Other field types are not yet supported
+            "last_played" => self.last_played.map(Field::LastPlayed),
+            "date_added" => self.date_added.map(Field::DateAdded),
+            "date_modified" => self.date_modified.map(Field::DateModified),
+            _ => None,
+        }
+    }
+
+    /// Compares two songs by the first of the given tags which both of them
+    /// have, as numbers if both values are numeric. Songs which are equal in
+    /// all of them are ordered by file name. Fields are compared by what
+    /// they hold, so dates are in order rather than by how they're written,
+    /// and songs without one go after the songs with it. Songs with the same
+    /// field, disc or track are compared by the next tag.
+    pub fn cmp_by_tags(&self, other: &Song, sort_by: &[Tag]) -> Ordering {
+        for sort_option in sort_by {
+            if let Tag::Field(field) = sort_option {
+                match (self.get_field(field), other.get_field(field)) {
+                    (Some(a), Some(b)) => match a.cmp_value(&b) {
+                        Some(Ordering::Equal) | None => continue,
+                        Some(ordering) => return ordering,
+                    },
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    (None, None) => continue,
+                }
+            }
+
+            // Songs without a disc are on the first, like in albums. Equal
+            // numbers go on to the next tag, so tracks are ordered within
+            // a disc.
+            let disc = |song: &Song| Some(song.disc_number().unwrap_or(1));
+            let numbers = match sort_option {
+                Tag::Track => Some((self.track_number(), other.track_number())),
+                Tag::Disk => Some((disc(self), disc(other))),
+                _ => None,
+            };
+            match numbers {
+                Some((Some(num_a), Some(num_b))) => match num_a.cmp(&num_b) {
+                    Ordering::Equal => continue,
+                    ordering => return ordering,
+                },
+                Some(_) => continue,
+                None => (),
+            }
+
+            let (Some(tag_a), Some(tag_b)) =
+                (self.get_tag(sort_option), other.get_tag(sort_option))
+            else {
+                continue;
+            };
+
+            if let (Ok(num_a), Ok(num_b)) = (tag_a.parse::<i32>(), tag_b.parse::<i32>()) {
+                // If parsing succeeds, compare as numbers
+                return num_a.cmp(&num_b);
+            } else {
+                // If parsing fails, compare as strings
+                return tag_a.cmp(tag_b);
+            }
+        }
+
+        // If all tags are equal, sort by file name
+        let file_name = |song: &Song| song.location.first().map(|uri| uri.path());
+        let (path_a, path_b) = (file_name(self), file_name(other));
+        path_a
+            .as_deref()
+            .and_then(Path::file_name)
+            .cmp(&path_b.as_deref().and_then(Path::file_name))
+    }
+
+    /// Returns the release year of the song, taken from the first
+    /// date-like tag which starts with a four digit year
+    pub fn year(&self) -> Option<i32> {
+        ["Year", "RecordingDate", "OriginalReleaseDate"]
+            .iter()
+            .find_map(|key| {
+                let value = self.get_tag(&Tag::Key(key.to_string()))?;
+                value.trim().get(0..4)?.parse::<i32>().ok()
+            })
+    }
+
+    /// Returns the track number of the song, taken from the digits at the
+    /// start of its file name if it isn't tagged with one
+    pub fn track_number(&self) -> Option<u16> {
+        if let Some(track) = self.get_tag(&Tag::Track) {
+            return parse_track_number(track);
+        }
+
+        let path = self.location.first()?.path();
+        let name = path.file_name()?.to_string_lossy();
+        // Four digits or more is more likely a year than a track
+        match name.find(|c: char| !c.is_ascii_digit()) {
+            Some(1..=3) => leading_number(&name),
+            _ => None,
+        }
+    }
+
+    /// Returns the disc number of the song
+    pub fn disc_number(&self) -> Option<u16> {
+        parse_disc_number(self.get_tag(&Tag::Disk)?)
+    }
+
+    /// Rates the song itself, so rating its album no longer changes it
+    pub fn set_rating(&mut self, rating: Option<u8>) {
+        self.rating = rating;
+        self.internal_tags
+            .retain(|tag| *tag != InternalTag::AlbumRating);
+    }
+
+    /// Favorites the song itself, so favoriting its album no longer changes it
+    pub fn set_favorited(&mut self, favorited: bool) {
+        self.favorited = favorited;
+        self.internal_tags
+            .retain(|tag| *tag != InternalTag::AlbumFavorite);
+    }
+
+    /// Gives the song its album's rating, unless it was rated on its own
+    fn set_album_rating(&mut self, rating: Option<u8>) {
+        let from_album = self.internal_tags.contains(&InternalTag::AlbumRating);
+        if self.rating.is_some() && !from_album {
+            return;
+        }
+
+        self.rating = rating;
+        match (rating.is_some(), from_album) {
+            (true, false) => self.internal_tags.push(InternalTag::AlbumRating),
+            (false, true) => self
+                .internal_tags
+                .retain(|tag| *tag != InternalTag::AlbumRating),
+            _ => (),
+        }
+    }
+
+    /// Favorites the song along with its album, unless it was favorited on
+    /// its own
+    fn set_album_favorited(&mut self, favorited: bool) {
+        let from_album = self.internal_tags.contains(&InternalTag::AlbumFavorite);
+        if self.favorited && !from_album {
+            return;
+        }
+
+        self.favorited = favorited;
+        match (favorited, from_album) {
+            (true, false) => self.internal_tags.push(InternalTag::AlbumFavorite),
+            (false, true) => self
+                .internal_tags
+                .retain(|tag| *tag != InternalTag::AlbumFavorite),
+            _ => (),
         }
     }
 
@@ -227,6 +563,10 @@ impl Song {
 
     /// Creates a [`Song`] from a music file
     pub fn from_file<P: ?Sized + AsRef<Path>>(target_file: &P) -> Result<Self, Box<dyn Error>> {
+        // A dangling symlink, or a file removed since it was found, fails
+        // here before anything tries to read it
+        let canonical = canonicalize(target_file.as_ref())?;
+
         let normal_options =
             lofty::config::ParseOptions::new().parsing_mode(lofty::config::ParsingMode::Relaxed);
 
@@ -284,14 +624,17 @@ impl Song {
 
         // Get all the album artwork information from the file
         let mut album_art: Vec<AlbumArt> = Vec::new();
-        for (i, _art) in tag.pictures().iter().enumerate() {
+        let mut art_metadata: Vec<ArtMetadata> = Vec::new();
+        for (i, art) in tag.pictures().iter().enumerate() {
             let new_art = AlbumArt::Embedded(i);
 
-            album_art.push(new_art)
+            album_art.push(new_art);
+            art_metadata.push(ArtMetadata::from_picture(art));
         }
 
         // Find images around the music file that can be used
-        let found_images = find_images(target_file.as_ref()).unwrap();
+        let found_images = find_images(target_file.as_ref())?;
+        art_metadata.extend(found_images.iter().map(external_art_metadata));
         album_art.extend_from_slice(&found_images);
 
         // Get the format as a string
@@ -300,13 +643,21 @@ impl Song {
             Err(_) => None,
         };
 
-        // TODO: Fix error handling
-        let binding = fs::canonicalize(target_file).unwrap();
+        // A symlink's own path is kept after the file it points to, so the
+        // song is found by either
+        let mut location = vec![URI::Local(canonical.clone())];
+        let is_link = fs::symlink_metadata(target_file).is_ok_and(|m| m.file_type().is_symlink());
+        if is_link {
+            let link = normalize_path(&std::path::absolute(target_file)?);
+            if link != canonical {
+                location.push(URI::Local(link));
+            }
+        }
 
         // TODO: Handle creation of internal tag: Song Type and Song Links
         let internal_tags = { Vec::new() };
         let new_song = Song {
-            location: vec![URI::Local(binding)],
+            location,
             uuid: Uuid::new_v4(),
             plays: 0,
             skips: 0,
@@ -317,10 +668,16 @@ impl Song {
             duration,
             play_time: Duration::from_secs(0),
             last_played: None,
+            first_played: None,
             date_added: Some(chrono::offset::Utc::now()),
             date_modified: Some(chrono::offset::Utc::now()),
             tags,
             album_art,
+            art_metadata,
+            preferred_art: None,
+            trim: None,
+            silence: None,
+            file_hash: None,
             internal_tags,
         };
         Ok(new_song)
@@ -436,10 +793,16 @@ impl Song {
                     duration,
                     play_time: Duration::from_secs(0),
                     last_played: None,
+                    first_played: None,
                     date_added: Some(chrono::offset::Utc::now()),
                     date_modified: Some(chrono::offset::Utc::now()),
                     tags,
+                    art_metadata: album_art.iter().map(external_art_metadata).collect(),
                     album_art,
+                    preferred_art: None,
+                    trim: None,
+                    silence: None,
+                    file_hash: None,
                     internal_tags: Vec::new(),
                 };
                 tracks.push((new_song, audio_location.clone()));
@@ -475,33 +838,109 @@ impl Song {
         }
     }
 
+    /// The index in `album_art` of the image to show for this song. This is
+    /// the one chosen by the user, otherwise the front cover, otherwise the
+    /// largest embedded image.
+    pub fn front_cover_index(&self) -> Option<usize> {
+        if let Some(i) = self.preferred_art.filter(|&i| i < self.album_art.len()) {
+            return Some(i);
+        }
+
+        let metadata = |i: usize| self.art_metadata.get(i).cloned().unwrap_or_default();
+        (0..self.album_art.len())
+            .find(|&i| metadata(i).kind == ArtKind::FrontCover)
+            .or_else(|| {
+                // `max_by_key` takes the last of equal elements, so reverse to
+                // prefer the first
+                (0..self.album_art.len())
+                    .rev()
+                    .max_by_key(|&i| metadata(i).pixels())
+            })
+    }
+
+    /// Returns the data of the image to show for this song
+    pub fn front_cover(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self.front_cover_index() {
+            Some(i) => self.album_art(i),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the data of the `i`th image, read through the shared
+    /// [`ArtStore`]
     pub fn album_art(&self, i: usize) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        if let Some(art) = self.album_art.get(i) {
-            match art {
-                AlbumArt::Embedded(j) => {
-                    let file = lofty::read_from_path(self.primary_uri()?.0.path())?;
-                    Ok(Some(
-                        file.tag(file.primary_tag_type()).unwrap().pictures()[*j]
-                            .data()
-                            .to_vec(),
-                    ))
-                }
-                AlbumArt::External(ref path) => {
-                    let mut buf = vec![];
-                    std::fs::File::open(path.path())?.read_to_end(&mut buf)?;
-                    Ok(Some(buf))
-                }
-            }
-        } else {
-            Ok(None)
+        ArtStore::shared().resolve(self, i)
+    }
+}
+
+/// A [`Song`] as sent to frontends, with tags keyed by their names so it can
+/// be represented in JSON
+#[derive(Serialize, Debug, Clone)]
+pub struct SongPayload {
+    pub location: Vec<URI>,
+    pub uuid: Uuid,
+    pub plays: i32,
+    pub format: Option<String>,
+    /// In seconds
+    pub duration: String,
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_played: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option")]
+    pub date_added: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option")]
+    pub date_modified: Option<DateTime<Utc>>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl From<&Song> for SongPayload {
+    fn from(value: &Song) -> Self {
+        SongPayload {
+            location: value.location.clone(),
+            uuid: value.uuid,
+            plays: value.plays,
+            duration: value.duration.as_secs().to_string(),
+            format: value.format.clone(),
+            last_played: value.last_played,
+            date_added: value.date_added,
+            date_modified: value.date_modified,
+            tags: value
+                .tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Only what a row of the song list shows about a [`Song`], which is much
+/// smaller to send than a [`SongPayload`] when there are many
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SongRef {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// In seconds
+    pub duration: u64,
+}
+
+impl From<&Song> for SongRef {
+    fn from(value: &Song) -> Self {
+        SongRef {
+            uuid: value.uuid,
+            title: value.get_tag(&Tag::Title).cloned(),
+            artist: value.get_tag(&Tag::Artist).cloned(),
+            album: value.get_tag(&Tag::Album).cloned(),
+            duration: value.duration.as_secs(),
         }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum URI {
-    Local(PathBuf),
+    Local(#[serde(with = "super::os_path")] PathBuf),
     Cue {
+        #[serde(with = "super::os_path")]
         location: PathBuf,
         index: usize,
         start: Duration,
@@ -550,12 +989,11 @@ impl URI {
 
     pub fn as_uri(&self) -> String {
         let path_str = match self {
-            URI::Local(location) => prismriver::utils::path_to_uri(location)
-                .expect("couldn't convert path to URI")
-                .to_string(),
-            URI::Cue { location, .. } => prismriver::utils::path_to_uri(location)
-                .expect("couldn't convert path to URI")
-                .to_string(),
+            URI::Local(location) | URI::Cue { location, .. } => {
+                Url::from_file_path(normalize_path(location))
+                    .expect("couldn't convert path to URI")
+                    .to_string()
+            }
             URI::Remote(_, location) => location.clone(),
         };
         path_str.to_string()
@@ -571,9 +1009,12 @@ impl URI {
 
     pub fn exists(&self) -> Result<bool, std::io::Error> {
         match self {
-            URI::Local(loc) => loc.try_exists(),
-            URI::Cue { location, .. } => location.try_exists(),
-            URI::Remote(_, _loc) => Ok(true), // TODO: Investigate a way to do this?
+            URI::Local(loc) => long_path(loc).try_exists(),
+            URI::Cue { location, .. } => long_path(location).try_exists(),
+            // Checking means asking a server, which is left to the service's
+            // `RemoteSource` when the song is played rather than done for
+            // every song
+            URI::Remote(_, _loc) => Ok(true),
         }
     }
 }
@@ -590,7 +1031,7 @@ impl Display for URI {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Hash)]
 pub enum Service {
     InternetRadio,
     Spotify,
@@ -598,12 +1039,21 @@ pub enum Service {
     None,
 }
 
+/// Identifies an album by its title and album artist
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AlbumKey {
+    pub title: String,
+    pub artist: Option<String>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Album {
     title: String,
     artist: Option<String>,
     cover: Option<AlbumArt>,
     discs: BTreeMap<u16, Vec<(u16, Uuid)>>,
+    rating: Option<f32>,
+    favorited: bool,
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -626,6 +1076,36 @@ impl Album {
     pub fn discs(&self) -> &BTreeMap<u16, Vec<(u16, Uuid)>> {
         &self.discs
     }
+
+    /// Returns the average rating of the album's rated songs
+    pub fn rating(&self) -> Option<f32> {
+        self.rating
+    }
+
+    /// Returns whether every song in the album is favorited
+    pub fn favorited(&self) -> bool {
+        self.favorited
+    }
+
+    /// Returns the key which identifies this album
+    pub fn key(&self) -> AlbumKey {
+        AlbumKey {
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+        }
+    }
+
+    /// Returns the tracks in playback order, disc by disc, skipping
+    /// everything before the `(disc, track)` given in `start`
+    pub fn play_order(self, start: Option<(u16, u16)>) -> Vec<AlbumTrack> {
+        let tracks = self.into_iter();
+        match start {
+            Some(start) => tracks
+                .skip_while(|track| (track.disc, track.track) < start)
+                .collect(),
+            None => tracks.collect(),
+        }
+    }
     /// Returns the specified track at `index` from the album, returning
     /// an error if the track index is out of range
     pub fn track(&self, disc: u16, index: usize) -> Option<&(u16, Uuid)> {
@@ -671,6 +1151,7 @@ impl IntoIterator for Album {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct AlbumTrack {
     disc: u16,
     track: u16,
@@ -691,26 +1172,85 @@ impl AlbumTrack {
     }
 }
 
+/// How [`MusicLibrary::remove_missing`] decides what to remove
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoveMissingOptions {
+    /// How long to wait before checking missing files again. Only files
+    /// missing both times are removed.
+    pub recheck_delay: Duration,
+    /// The largest share of the library, in percent, which can be removed
+    /// at once
+    pub max_percent: f32,
+    /// Only find the songs which would be removed
+    pub dry_run: bool,
+    /// The library's scan folders, each of which counts as a volume of its
+    /// own, so one on a drive which isn't plugged in is left alone
+    pub scan_folders: Vec<PathBuf>,
+}
+
+/// Finds which of `locations` no longer exist, checking them again after
+/// `recheck_delay`. Files which came back, or which could not be checked at
+/// all, aren't returned.
+pub fn find_missing(locations: Vec<URI>, recheck_delay: Duration) -> Vec<URI> {
+    let mut missing: Vec<URI> = locations
+        .into_par_iter()
+        .filter(|location| matches!(location.exists(), Ok(false)))
+        .collect();
+    if missing.is_empty() {
+        return missing;
+    }
+
+    // A file on a flaky drive or share can fail to be found once, which
+    // for a cue sheet would take a whole album with it
+    std::thread::sleep(recheck_delay);
+    missing.retain(|location| matches!(location.exists(), Ok(false)));
+    missing
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum RemoveMissingError {
+    #[error(
+        "{missing} of {total} songs are missing, more than the {max_percent}% which can be \
+         removed at once. Nothing was removed."
+    )]
+    TooMany {
+        missing: usize,
+        total: usize,
+        max_percent: f32,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MusicLibrary {
     pub name: String,
     pub uuid: Uuid,
     pub library: Vec<Song>,
     pub playlists: PlaylistFolder,
-    pub backup_songs: Vec<Song>, // maybe move this to the config instead?
+    /// The songs taken out by the last [`MusicLibrary::remove_missing`], so
+    /// it can be undone. They're only kept until the player closes.
+    #[serde(skip)]
+    pub backup_songs: Vec<Song>,
+    /// Songs which were taken out, so they get the same Uuid if they're
+    /// added again
+    #[serde(default)]
+    pub tombstones: Tombstones,
+    #[serde(default)]
+    pub play_history: PlayHistory,
 }
 
 impl MusicLibrary {
     const BLOCKED_EXTENSIONS: &'static [&'static str] = &["vob", "log", "txt", "sf2"];
 
     /// Create a new library from a name and [Uuid]
-    fn new(name: String, uuid: Uuid) -> Self {
+    pub(crate) fn new(name: String, uuid: Uuid) -> Self {
         MusicLibrary {
             name,
             uuid,
             library: Vec::new(),
             playlists: PlaylistFolder::default(),
             backup_songs: Vec::new(),
+            tombstones: Tombstones::default(),
+            play_history: PlayHistory::default(),
         }
     }
 
@@ -721,7 +1261,12 @@ impl MusicLibrary {
     /// the [MusicLibrary] Vec
     pub fn init(path: PathBuf, uuid: Uuid) -> Result<Self, Box<dyn Error>> {
         let library: MusicLibrary = match path.exists() {
-            true => read_file(path)?,
+            true => {
+                let modified = modified_time(&path)?;
+                let mut library: MusicLibrary = read_file(path)?;
+                library.playlists.date_legacy_tracks(modified);
+                library
+            }
             false => {
                 // If the library does not exist, re-create it
                 let lib = MusicLibrary::new(String::new(), uuid);
@@ -736,7 +1281,12 @@ impl MusicLibrary {
     pub fn from_path<P: ?Sized + AsRef<Path>>(path: &P) -> Result<Self, Box<dyn Error>> {
         let path: PathBuf = path.as_ref().to_path_buf();
         let library: MusicLibrary = match path.exists() {
-            true => read_file(path)?,
+            true => {
+                let modified = modified_time(&path)?;
+                let mut library: MusicLibrary = read_file(path)?;
+                library.playlists.date_legacy_tracks(modified);
+                library
+            }
             false => {
                 let lib = MusicLibrary::new(String::new(), Uuid::new_v4());
                 write_file(&lib, path)?;
@@ -822,13 +1372,16 @@ impl MusicLibrary {
     }
 
     /// Queries for a [Song] by its [PathBuf], returning a `Vec<&Song>`
-    /// with matching `PathBuf`s
+    /// with any local location matching the `PathBuf`
     fn query_path(&self, path: PathBuf) -> Option<Vec<&Song>> {
+        let path = normalize_path(&path);
         let result: Arc<Mutex<Vec<&Song>>> = Arc::new(Mutex::new(Vec::new()));
         self.library.par_iter().for_each(|track| {
-            // dbg!(&track);
-            if path == track.primary_uri().unwrap().0.path() {
-                //TODO: make this also not unwrap
+            let matches = track.location.iter().any(|location| match location {
+                URI::Local(local) => path == normalize_path(local),
+                _ => false,
+            });
+            if matches {
                 Arc::clone(&result).lock().unwrap().push(track);
             }
         });
@@ -840,86 +1393,300 @@ impl MusicLibrary {
         }
     }
 
-    /// Finds all the audio files within a specified folder
+    /// Finds all the audio files within a specified folder, returning how
+    /// many were added along with the files which could not be. Files which
+    /// `exclusions` rule out are left out.
+    ///
+    /// Once `cancel` is cancelled the scan stops before the next file,
+    /// keeping the songs it has added.
     pub fn scan_folder<P: ?Sized + AsRef<Path>>(
         &mut self,
         target_path: &P,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let mut total = 0;
-        let mut errors = 0;
-        for target_file in WalkDir::new(target_path)
-            .follow_links(true)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let path = target_file.path();
+        exclusions: &ExclusionRules,
+        cancel: &CancelToken,
+    ) -> Result<ScanReport, Box<dyn std::error::Error>> {
+        self.scan_folder_with_progress(target_path, exclusions, cancel, |_| ())
+    }
 
-            // Ensure the target is a file and not a directory,
-            // if it isn't a file, skip this loop
-            if !path.is_file() {
-                continue;
+    /// [`MusicLibrary::scan_folder`], calling `progress` while it counts
+    /// the files and after each one it imports
+    pub fn scan_folder_with_progress<P: ?Sized + AsRef<Path>>(
+        &mut self,
+        target_path: &P,
+        exclusions: &ExclusionRules,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(ScanProgress),
+    ) -> Result<ScanReport, Box<dyn std::error::Error>> {
+        let mut report = ScanReport::default();
+        let mut candidates = ScanCandidates::new();
+        candidates.find(target_path, exclusions, cancel, &mut report, |found| {
+            progress(ScanProgress {
+                total_candidates: found,
+                ..Default::default()
+            })
+        });
+        if !report.cancelled {
+            report.extend(self.import_candidates(candidates, exclusions, cancel, progress));
+        }
+
+        log::info!("Total scanning errors: {}", report.error_count());
+
+        Ok(report)
+    }
+
+    /// Adds the files found by [`ScanCandidates::find`] which aren't in the
+    /// library yet, calling `progress` after each one
+    pub fn import_candidates(
+        &mut self,
+        candidates: ScanCandidates,
+        exclusions: &ExclusionRules,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(ScanProgress),
+    ) -> ScanReport {
+        let mut report = ScanReport::default();
+        let total_candidates = candidates.len();
+        for (i, path) in candidates.into_chunks().flatten().enumerate() {
+            if cancel.is_cancelled() {
+                report.cancelled = true;
+                break;
             }
 
             // Check if the file path is already in the db
-            if self.query_uri(&URI::Local(path.to_path_buf())).is_some() {
-                continue;
+            if self.query_uri(&URI::Local(normalize_path(&path))).is_none() {
+                match self.scan_file(&path, exclusions) {
+                    Ok((added, errors)) => {
+                        report.added += added;
+                        errors.into_iter().for_each(|error| report.push(error));
+                    }
+                    Err(error) => {
+                        log::warn!("{:?}: {}", path.file_name(), error.message);
+                        report.push(error);
+                    }
+                }
             }
+            progress(ScanProgress {
+                phase: ScanPhase::Importing,
+                total_candidates,
+                processed: i + 1,
+                added: report.added,
+            });
+        }
+        report
+    }
 
-            let format = FileFormat::from_file(path)?;
-            let extension = match path.extension() {
-                Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
-                None => String::new(),
-            };
+    /// A copy of the library with only the songs' locations, which is all a
+    /// scan looks at, so folders can be scanned into it while the library
+    /// itself is in use. The songs it finds go back in with
+    /// [`MusicLibrary::add_scanned`].
+    pub fn scan_copy(&self) -> MusicLibrary {
+        MusicLibrary {
+            library: self
+                .library
+                .iter()
+                .map(|song| Song {
+                    uuid: song.uuid,
+                    location: song.location.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..MusicLibrary::new(self.name.clone(), self.uuid)
+        }
+    }
 
-            // If it's a normal file, add it to the database
-            // if it's a cuesheet, do a bunch of fancy stuff
-            if (format.kind() == Kind::Audio || format.kind() == Kind::Video)
-                && !Self::BLOCKED_EXTENSIONS.contains(&extension.as_str())
-            {
-                match self.add_file(target_file.path()) {
-                    Ok(_) => total += 1,
-                    Err(_error) => {
-                        errors += 1;
-                        println!("{:?}: {}", target_file.file_name(), _error)
-                    } // TODO: Handle more of these errors
-                };
-            } else if extension == "cue" {
-                total += match self.add_cuesheet(target_file.path()) {
-                    Ok(added) => added,
-                    Err(_error) => {
-                        errors += 1;
-                        println!("{:?}: {}", target_file.file_name(), _error);
-                        0
-                    }
-                }
+    /// Adds songs found by scanning a [`MusicLibrary::scan_copy`], returning
+    /// how many were added. Ones which were added some other way since are
+    /// left out.
+    pub fn add_scanned(&mut self, songs: Vec<Song>) -> usize {
+        let mut added = 0;
+        for song in songs {
+            // The tracks of a CUE sheet take the place of its whole file
+            if let Some(URI::Cue { location, .. }) = song.location.first() {
+                _ = self.remove_uri(&URI::Local(location.clone()));
+            }
+            if self.add_song(song).is_ok() {
+                added += 1;
             }
         }
+        added
+    }
 
-        println!("Total scanning errors: {}", errors);
+    /// Adds a single file to the library, which may be a CUE sheet, returning
+    /// the number of songs added and any errors with individual CUE tracks.
+    /// Songs `exclusions` rule out by their tags aren't added.
+    pub fn scan_file(
+        &mut self,
+        path: &Path,
+        exclusions: &ExclusionRules,
+    ) -> Result<(i32, Vec<ScanError>), ScanError> {
+        // macOS leaves these next to files copied from it, and they only
+        // look like audio by their extension
+        let is_resource_fork = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("._"));
+        if is_resource_fork {
+            return Err(ScanError::new(
+                path,
+                ScanErrorKind::ResourceFork,
+                "macOS resource fork, not a song",
+            ));
+        }
+        let metadata = fs::metadata(path).map_err(|e| ScanError::from_error(path, &e))?;
+        if metadata.len() == 0 {
+            return Err(ScanError::new(
+                path,
+                ScanErrorKind::EmptyFile,
+                "The file is empty",
+            ));
+        }
+
+        let format = FileFormat::from_file(path).map_err(|e| ScanError::from_error(path, &e))?;
+        let extension = match path.extension() {
+            Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+            None => String::new(),
+        };
 
-        Ok(total)
+        // If it's a normal file, add it to the database
+        // if it's a cuesheet, do a bunch of fancy stuff
+        if (format.kind() == Kind::Audio || format.kind() == Kind::Video)
+            && !Self::BLOCKED_EXTENSIONS.contains(&extension.as_str())
+        {
+            let mut song =
+                Song::from_file(path).map_err(|e| ScanError::from_error(path, e.as_ref()))?;
+            if exclusions.excludes_song(&song) {
+                return Ok((0, Vec::new()));
+            }
+            // A file which can't be decoded is still added, as it may play
+            // with another backend
+            if let Ok(problem) = find_problem(&song) {
+                song.set_problem(problem);
+            }
+            _ = self.add_song(song);
+            Ok((1, Vec::new()))
+        } else if extension == "cue" {
+            self.add_cuesheet(path, exclusions)
+                .map_err(|e| ScanError::from_error(path, e.as_ref()))
+        } else {
+            Ok((0, Vec::new()))
+        }
     }
 
-    pub fn remove_missing(&mut self) {
-        let target_removals = Arc::new(Mutex::new(Vec::new()));
-        self.library.par_iter().for_each(|t| {
-            for location in &t.location {
-                if !location.exists().unwrap() {
-                    Arc::clone(&target_removals)
-                        .lock()
-                        .unwrap()
-                        .push(location.clone());
-                }
+    /// Removes songs whose files no longer exist, returning the removed
+    /// songs, which are kept in `backup_songs` until
+    /// [`MusicLibrary::undo_remove_missing`] or the next removal
+    ///
+    /// This waits for the recheck, so the library loop finds the missing
+    /// files with [`find_missing`] off the loop and removes them with
+    /// [`MusicLibrary::remove_found_missing`] instead.
+    pub fn remove_missing(
+        &mut self,
+        options: RemoveMissingOptions,
+    ) -> Result<Vec<Song>, RemoveMissingError> {
+        let missing = find_missing(self.locations(), options.recheck_delay);
+        self.remove_found_missing(missing, &options)
+    }
+
+    /// Every location of every song, except remote ones
+    pub fn locations(&self) -> Vec<URI> {
+        self.library
+            .iter()
+            .flat_map(|song| &song.location)
+            .filter(|location| !matches!(location, URI::Remote(..)))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the songs at the locations [`find_missing`] found, returning
+    /// the removed songs, which are kept in `backup_songs` until
+    /// [`MusicLibrary::undo_remove_missing`] or the next removal
+    ///
+    /// Songs on a volume which is entirely unavailable, like an unmounted
+    /// drive or a disconnected network share, are kept. If more of the
+    /// library is missing than `max_percent`, nothing is removed.
+    pub fn remove_found_missing(
+        &mut self,
+        mut target_removals: Vec<URI>,
+        options: &RemoveMissingOptions,
+    ) -> Result<Vec<Song>, RemoveMissingError> {
+        if target_removals.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Count how many locations live on each volume, and how many of those are missing
+        let root_of = |location: &URI| {
+            let path = location.path();
+            options
+                .scan_folders
+                .iter()
+                .filter(|folder| path.starts_with(folder))
+                .max_by_key(|folder| folder.components().count())
+                .cloned()
+                .unwrap_or_else(|| volume_root(&path))
+        };
+        let mut volumes: BTreeMap<PathBuf, (usize, usize)> = BTreeMap::new();
+        for location in self.library.iter().flat_map(|song| &song.location) {
+            if let URI::Remote(..) = location {
+                continue;
             }
+            volumes.entry(root_of(location)).or_default().0 += 1;
+        }
+        for location in &target_removals {
+            volumes.entry(root_of(location)).or_default().1 += 1;
+        }
+
+        target_removals.retain(|location| {
+            let root = root_of(location);
+            let root_missing = !long_path(&root).try_exists().unwrap_or(false);
+            let (total, missing) = volumes[&root];
+            !root_missing && total != missing
         });
 
-        let target_removals = Arc::try_unwrap(target_removals)
-            .unwrap()
-            .into_inner()
-            .unwrap();
-        for location in target_removals {
-            self.remove_uri(&location).unwrap();
+        let removals = self
+            .library
+            .iter()
+            .enumerate()
+            .filter(|(_, song)| {
+                song.location
+                    .iter()
+                    .any(|location| target_removals.contains(location))
+            })
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        let total = self.library.len();
+        if removals.len() as f32 > total as f32 * options.max_percent / 100.0 {
+            return Err(RemoveMissingError::TooMany {
+                missing: removals.len(),
+                total,
+                max_percent: options.max_percent,
+            });
+        }
+
+        if options.dry_run {
+            return Ok(removals.iter().map(|&i| self.library[i].clone()).collect());
         }
+        let mut removed = removals
+            .into_iter()
+            .rev()
+            .map(|i| self.library.remove(i))
+            .collect::<Vec<_>>();
+        removed.reverse();
+        self.bury(&removed);
+        self.backup_songs = removed.clone();
+        Ok(removed)
+    }
+
+    /// Puts back the songs taken out by the last
+    /// [`MusicLibrary::remove_missing`], returning how many were restored
+    pub fn undo_remove_missing(&mut self) -> usize {
+        let mut restored = 0;
+        for song in std::mem::take(&mut self.backup_songs) {
+            if self.query_uuid(&song.uuid).is_none() {
+                self.unbury(&song.uuid);
+                self.library.push(song);
+                restored += 1;
+            }
+        }
+        restored
     }
 
     pub fn add_file(&mut self, target_file: &Path) -> Result<(), Box<dyn Error>> {
@@ -934,11 +1701,33 @@ impl MusicLibrary {
         Ok(())
     }
 
-    pub fn add_cuesheet(&mut self, cuesheet: &Path) -> Result<i32, Box<dyn Error>> {
+    /// Adds every track of a CUE sheet which `exclusions` don't rule out,
+    /// returning the number of tracks added and errors for the tracks which
+    /// could not be
+    pub fn add_cuesheet(
+        &mut self,
+        cuesheet: &Path,
+        exclusions: &ExclusionRules,
+    ) -> Result<(i32, Vec<ScanError>), Box<dyn Error>> {
         let tracks = Song::from_cue(cuesheet)?;
         let mut tracks_added = tracks.len() as i32;
+        let mut errors = Vec::new();
 
         for (new_song, location) in tracks {
+            if let Err(error) = new_song.primary_uri() {
+                tracks_added -= 1;
+                errors.push(ScanError::new(
+                    cuesheet,
+                    ScanErrorKind::Io,
+                    format!("{}: {error}", location.display()),
+                ));
+                continue;
+            }
+            if exclusions.excludes_song(&new_song) {
+                tracks_added -= 1;
+                continue;
+            }
+
             // Try to remove the original audio file from the db if it exists
             if self.remove_uri(&URI::Local(location.clone())).is_ok() {
                 tracks_added -= 1
@@ -951,10 +1740,12 @@ impl MusicLibrary {
                 }
             };
         }
-        Ok(tracks_added)
+        Ok((tracks_added, errors))
     }
 
-    pub fn add_song(&mut self, new_song: Song) -> Result<(), Box<dyn Error>> {
+    /// Adds a song, which gets back its old Uuid and stats if it was in the
+    /// library before
+    pub fn add_song(&mut self, mut new_song: Song) -> Result<(), Box<dyn Error>> {
         let location = new_song.primary_uri()?.0;
         if self.query_uri(location).is_some() {
             return Err(format!("URI already in database: {:?}", location).into());
@@ -967,6 +1758,7 @@ impl MusicLibrary {
             _ => (),
         }
 
+        self.restore_identity(&mut new_song);
         self.library.push(new_song);
 
         Ok(())
@@ -979,7 +1771,8 @@ impl MusicLibrary {
             None => return Err("URI not in database".into()),
         };
 
-        self.library.remove(location);
+        let removed = self.library.remove(location);
+        self.bury(&[removed]);
 
         Ok(location)
     }
@@ -1033,7 +1826,7 @@ impl MusicLibrary {
         &self,
         query_string: &String,  // The query itself
         target_tags: &Vec<Tag>, // The tags to search
-        sort_by: &Vec<Tag>,     // Tags to sort the resulting data by
+        sort_by: &[Tag],        // Tags to sort the resulting data by
     ) -> Option<Vec<&Song>> {
         let songs = Arc::new(Mutex::new(Vec::new()));
 
@@ -1063,45 +1856,7 @@ impl MusicLibrary {
         let mut new_songs = lock.into_inner().expect("Mutex cannot be locked!");
 
         // Sort the returned list of songs
-        new_songs.par_sort_by(|a, b| {
-            for sort_option in sort_by {
-                let tag_a = match sort_option {
-                    Tag::Field(field_selection) => match a.get_field(field_selection) {
-                        Some(field_value) => field_value.to_string(),
-                        None => continue,
-                    },
-                    _ => match a.get_tag(sort_option) {
-                        Some(tag_value) => tag_value.to_owned(),
-                        None => continue,
-                    },
-                };
-
-                let tag_b = match sort_option {
-                    Tag::Field(field_selection) => match b.get_field(field_selection) {
-                        Some(field_value) => field_value.to_string(),
-                        None => continue,
-                    },
-                    _ => match b.get_tag(sort_option) {
-                        Some(tag_value) => tag_value.to_owned(),
-                        None => continue,
-                    },
-                };
-
-                if let (Ok(num_a), Ok(num_b)) = (tag_a.parse::<i32>(), tag_b.parse::<i32>()) {
-                    // If parsing succeeds, compare as numbers
-                    return num_a.cmp(&num_b);
-                } else {
-                    // If parsing fails, compare as strings
-                    return tag_a.cmp(&tag_b);
-                }
-            }
-
-            // If all tags are equal, sort by Track number
-            let path_a = PathBuf::from(a.get_field("location").unwrap().to_string());
-            let path_b = PathBuf::from(b.get_field("location").unwrap().to_string());
-
-            path_a.file_name().cmp(&path_b.file_name())
-        });
+        new_songs.par_sort_by(|a, b| a.cmp_by_tags(b, sort_by));
 
         if !new_songs.is_empty() {
             Some(new_songs)
@@ -1113,6 +1868,8 @@ impl MusicLibrary {
     /// Generates all albums from the track list
     pub fn albums(&self) -> BTreeMap<String, Album> {
         let mut paths = BTreeMap::new();
+        // The total and count of ratings, and whether every song is favorited
+        let mut ratings: BTreeMap<String, (u32, u32, bool)> = BTreeMap::new();
 
         let mut albums: BTreeMap<String, Album> = BTreeMap::new();
         for song in &self.library {
@@ -1122,33 +1879,23 @@ impl MusicLibrary {
             };
             //let norm_title = normalize(&album_title);
 
-            let disc_num = song
-                .get_tag(&Tag::Disk)
-                .unwrap_or(&"".to_string())
-                .parse::<u16>()
-                .unwrap_or(1);
+            let disc_num = song.disc_number().unwrap_or(1);
+            let track_num = song.track_number().unwrap_or_default();
+
+            let (total, count, favorited) =
+                ratings.entry(album_title.clone()).or_insert((0, 0, true));
+            if let Some(rating) = song.rating {
+                *total += rating as u32;
+                *count += 1;
+            }
+            *favorited &= song.favorited;
 
             match albums.get_mut(&album_title) {
                 // If the album is in the list, add the track to the appropriate disc within the album
                 Some(album) => match album.discs.get_mut(&disc_num) {
-                    Some(disc) => disc.push((
-                        song.get_tag(&Tag::Track)
-                            .unwrap_or(&String::new())
-                            .parse::<u16>()
-                            .unwrap_or_default(),
-                        song.uuid,
-                    )),
+                    Some(disc) => disc.push((track_num, song.uuid)),
                     None => {
-                        album.discs.insert(
-                            disc_num,
-                            vec![(
-                                song.get_tag(&Tag::Track)
-                                    .unwrap_or(&String::new())
-                                    .parse::<u16>()
-                                    .unwrap_or_default(),
-                                song.uuid,
-                            )],
-                        );
+                        album.discs.insert(disc_num, vec![(track_num, song.uuid)]);
                     }
                 },
                 // If the album is not in the list, make it new one and add it
@@ -1157,22 +1904,26 @@ impl MusicLibrary {
                     let new_album = Album {
                         title: album_title.clone(),
                         artist: song.get_tag(&Tag::AlbumArtist).cloned(),
-                        discs: BTreeMap::from([(
-                            disc_num,
-                            vec![(
-                                song.get_tag(&Tag::Track)
-                                    .unwrap_or(&String::new())
-                                    .parse::<u16>()
-                                    .unwrap_or_default(),
-                                song.uuid,
-                            )],
-                        )]),
+                        discs: BTreeMap::from([(disc_num, vec![(track_num, song.uuid)])]),
                         cover: album_art.cloned(),
+                        rating: None,
+                        favorited: false,
                     };
                     albums.insert(album_title, new_album);
                 }
             }
-            paths.insert(song.uuid, song.primary_uri().unwrap());
+            // Only used to order untracked songs, so the files aren't
+            // checked, and songs whose files are missing still count
+            if let Some(uri) = song.location.first() {
+                paths.insert(song.uuid, uri);
+            }
+        }
+
+        for (title, (total, count, favorited)) in ratings {
+            if let Some(album) = albums.get_mut(&title) {
+                album.rating = (count > 0).then(|| total as f32 / count as f32);
+                album.favorited = favorited;
+            }
         }
 
         // Sort the tracks in each disk in each album
@@ -1187,12 +1938,12 @@ impl MusicLibrary {
                         num_a.cmp(&num_b)
                     } else {
                         // If parsing doesn't succeed, compare the locations
-                        let a = match paths.get_key_value(&a.1) {
-                            Some((_, (uri, _))) => uri,
+                        let a = match paths.get(&a.1) {
+                            Some(uri) => uri,
                             None => return Ordering::Equal,
                         };
-                        let b = match paths.get_key_value(&b.1) {
-                            Some((_, (uri, _))) => uri,
+                        let b = match paths.get(&b.1) {
+                            Some(uri) => uri,
                             None => return Ordering::Equal,
                         };
 
@@ -1228,10 +1979,81 @@ impl MusicLibrary {
         Ok(albums)
     }
 
+    /// Returns every song by an artist in album order, matching either
+    /// the track artist or the album artist
+    pub fn artist_songs(&self, artist: &str) -> Vec<&Song> {
+        let artist = normalize(artist);
+        self.songs_in_album_order(|song| {
+            [Tag::Artist, Tag::AlbumArtist].iter().any(|tag| {
+                song.get_tag(tag)
+                    .is_some_and(|value| normalize(value) == artist)
+            })
+        })
+    }
+
+    /// Returns every song in a genre in album order
+    pub fn genre_songs(&self, genre: &str) -> Vec<&Song> {
+        let genre = normalize(genre);
+        self.songs_in_album_order(|song| {
+            song.get_tag(&Tag::Genre)
+                .is_some_and(|value| normalize(value) == genre)
+        })
+    }
+
+    fn songs_in_album_order<F: Fn(&Song) -> bool>(&self, filter: F) -> Vec<&Song> {
+        let mut songs: Vec<&Song> = self.library.iter().filter(|song| filter(song)).collect();
+        songs.sort_by(|a, b| {
+            a.get_tag(&Tag::Album)
+                .cmp(&b.get_tag(&Tag::Album))
+                .then(a.disc_number().cmp(&b.disc_number()))
+                .then(a.track_number().cmp(&b.track_number()))
+        });
+        songs
+    }
+
+    /// Finds a single album by its key
+    pub fn query_album(&self, key: &AlbumKey) -> Option<Album> {
+        self.albums()
+            .remove(&key.title)
+            .filter(|album| key.artist.is_none() || album.artist == key.artist)
+    }
+
+    /// Applies `change` to every song in an album, returning an error if
+    /// there is no such album
+    pub(super) fn update_album(
+        &mut self,
+        key: &AlbumKey,
+        change: impl Fn(&mut Song),
+    ) -> Result<(), String> {
+        let album = self.query_album(key).ok_or("Album not found")?;
+        let uuids: HashSet<Uuid> = album.tracks().into_iter().map(|(_, uuid)| uuid).collect();
+        self.library
+            .iter_mut()
+            .filter(|song| uuids.contains(&song.uuid))
+            .for_each(change);
+        Ok(())
+    }
+
+    /// Rates every song in an album which wasn't rated on its own. Songs
+    /// rated along with the album before are rated again.
+    pub fn set_album_rating(&mut self, key: &AlbumKey, rating: Option<u8>) -> Result<(), String> {
+        self.update_album(key, |song| song.set_album_rating(rating))
+    }
+
+    /// Favorites or unfavorites every song in an album, leaving the songs
+    /// which were favorited on their own alone
+    pub fn set_album_favorited(&mut self, key: &AlbumKey, favorited: bool) -> Result<(), String> {
+        self.update_album(key, |song| song.set_album_favorited(favorited))
+    }
+
     pub fn query_playlist_uuid(&self, uuid: &Uuid) -> Option<&Playlist> {
         self.playlists.query_uuid(uuid)
     }
 
+    pub fn query_playlist_uuid_mut(&mut self, uuid: &Uuid) -> Option<&mut Playlist> {
+        self.playlists.query_uuid_mut(uuid)
+    }
+
     pub fn push_playlist(&mut self, playlist: PlaylistFolderItem) {
         self.playlists.items.push(playlist);
     }
@@ -1239,8 +2061,18 @@ impl MusicLibrary {
 
 #[cfg(test)]
 mod test {
-    use crate::music_storage::library::Tag;
-    use std::{path::PathBuf, time::Instant};
+    use crate::music_storage::cancel::CancelToken;
+    use crate::music_storage::library::{
+        parse_disc_number, parse_track_number, Album, AlbumArt, ArtKind, ArtMetadata,
+        RemoveMissingError, RemoveMissingOptions, Song, Tag, URI,
+    };
+    use crate::music_storage::scan_exclusions::ExclusionRules;
+    use lofty::picture::{MimeType, Picture, PictureType};
+    use std::{
+        collections::BTreeMap,
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
 
     use uuid::Uuid;
 
@@ -1271,7 +2103,7 @@ mod test {
             .query_tracks(
                 &String::from(""),
                 &vec![],
-                &vec![
+                &[
                     Tag::Field("location".to_string()),
                     Tag::Album,
                     Tag::Disk,
@@ -1291,4 +2123,751 @@ mod test {
         }
         */
     }
+
+    fn fixture_library() -> MusicLibrary {
+        let song = |artist: &str, album: &str, track: &str, genre: &str| Song {
+            uuid: Uuid::new_v4(),
+            tags: BTreeMap::from([
+                (Tag::Artist, artist.to_string()),
+                (Tag::Album, album.to_string()),
+                (Tag::Track, track.to_string()),
+                (Tag::Genre, genre.to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("Band", "Second", "1", "Rock"),
+            song("Other", "Elsewhere", "1", "Jazz"),
+            song("Band", "First", "2", "Rock"),
+            song("band", "First", "1", "Rock"),
+            song("Other", "Elsewhere", "2", "rock"),
+        ];
+        lib
+    }
+
+    #[test]
+    fn artist_songs() {
+        let lib = fixture_library();
+        let tracks = |songs: Vec<&Song>| {
+            songs
+                .iter()
+                .map(|song| {
+                    (
+                        song.get_tag(&Tag::Album).unwrap().clone(),
+                        song.get_tag(&Tag::Track).unwrap().clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tracks(lib.artist_songs("BAND")),
+            [("First", "1"), ("First", "2"), ("Second", "1")].map(|(a, t)| (a.into(), t.into()))
+        );
+        assert!(lib.artist_songs("Nobody").is_empty());
+    }
+
+    #[test]
+    fn genre_songs() {
+        let lib = fixture_library();
+        assert_eq!(lib.genre_songs("Rock").len(), 4);
+        assert_eq!(lib.genre_songs("jazz").len(), 1);
+        assert!(lib.genre_songs("Polka").is_empty());
+    }
+
+    #[test]
+    fn sort_by_date_added() {
+        let song = |title: &str, added: Option<&str>| Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(format!("/music/{title}.flac")))],
+            date_added: added.map(|date| date.parse().unwrap()),
+            tags: BTreeMap::from([(Tag::Title, title.to_string())]),
+            ..Default::default()
+        };
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        // Written out, "Sat, 1 Jun" comes after "Mon, 5 Feb"
+        lib.library = vec![
+            song("b", None),
+            song("june", Some("2024-06-01T12:00:00Z")),
+            song("a", None),
+            song("february", Some("2024-02-05T12:00:00Z")),
+            song("last year", Some("2023-12-31T23:59:59Z")),
+            song("spring", Some("2024-03-01T12:00:00Z")),
+            song("march", Some("2024-03-01T12:00:00Z")),
+        ];
+
+        let sorted = lib
+            .query_tracks(
+                &String::new(),
+                &vec![Tag::Title],
+                &[Tag::Field(String::from("date_added")), Tag::Title],
+            )
+            .unwrap();
+        let titles: Vec<_> = sorted
+            .iter()
+            .map(|song| song.get_tag(&Tag::Title).unwrap().as_str())
+            .collect();
+        // Songs added at the same time, and songs without a date, which go
+        // last, are ordered by title
+        assert_eq!(
+            titles,
+            ["last year", "february", "march", "spring", "june", "a", "b"]
+        );
+
+        // Fields which don't exist don't match anything
+        assert!(lib.library[0].get_field("date_played").is_none());
+        assert!(lib
+            .query_tracks(
+                &String::new(),
+                &vec![Tag::Field(String::from("date_played"))],
+                &[]
+            )
+            .is_none());
+    }
+
+    fn path_song(path: PathBuf) -> Song {
+        Song {
+            location: vec![URI::Local(path)],
+            uuid: Uuid::new_v4(),
+            plays: 3,
+            ..Default::default()
+        }
+    }
+
+    fn remove_options(max_percent: f32, dry_run: bool) -> RemoveMissingOptions {
+        RemoveMissingOptions {
+            recheck_delay: Duration::ZERO,
+            max_percent,
+            dry_run,
+            scan_folders: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn scan_phases_agree() {
+        use crate::music_storage::scan_report::{ScanPhase, ScanProgress};
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(dir.join("extras")).unwrap();
+        for name in ["a.wav", "b.wav", "notes.txt", "extras/c.wav"] {
+            std::fs::write(dir.join(name), b"not really a song").unwrap();
+        }
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let mut events = Vec::new();
+        lib.scan_folder_with_progress(
+            &dir,
+            &ExclusionRules::default(),
+            &CancelToken::new(),
+            |progress| events.push(progress),
+        )
+        .unwrap();
+
+        let (counting, importing): (Vec<&ScanProgress>, Vec<_>) = events
+            .iter()
+            .partition(|progress| progress.phase == ScanPhase::Counting);
+        assert_eq!(counting.last().unwrap().total_candidates, 4);
+        // Every file counted is imported, whether or not it's a song
+        assert_eq!(importing.len(), 4);
+        assert!(importing
+            .iter()
+            .all(|progress| progress.total_candidates == 4));
+        assert_eq!(importing.last().unwrap().processed, 4);
+        // Counting comes first
+        assert!(events.is_sorted_by_key(|progress| progress.phase == ScanPhase::Importing));
+    }
+
+    #[test]
+    fn cancelled_scan_keeps_added_songs() {
+        const SONGS: usize = 300;
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let music = dir.join("music");
+        std::fs::create_dir_all(&music).unwrap();
+        for i in 0..SONGS {
+            std::fs::copy("test-data/cue/album.wav", music.join(format!("{i:03}.wav"))).unwrap();
+        }
+
+        // Nothing is added once the scan has already been stopped
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let report = lib
+            .scan_folder(&music, &ExclusionRules::default(), &cancel)
+            .unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.added, 0);
+        assert!(lib.library.is_empty());
+
+        // Stopped partway through, like when the player is closed
+        let cancel = CancelToken::new();
+        let scan = std::thread::spawn({
+            let (music, cancel) = (music.clone(), cancel.clone());
+            move || {
+                let report = lib
+                    .scan_folder(&music, &ExclusionRules::default(), &cancel)
+                    .unwrap();
+                (lib, report)
+            }
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        cancel.cancel();
+        let (lib, report) = scan.join().unwrap();
+        assert_eq!(report.added as usize, lib.library.len());
+        if report.cancelled {
+            assert!(lib.library.len() < SONGS);
+        }
+
+        // What was added is saved and loads cleanly
+        let path = dir.join("library.dlib");
+        lib.save(path.clone()).unwrap();
+        let mut loaded = MusicLibrary::init(path, lib.uuid).unwrap();
+        assert_eq!(loaded.library, lib.library);
+
+        // And scanning again picks up where it stopped, without duplicates
+        let report = loaded
+            .scan_folder(&music, &ExclusionRules::default(), &CancelToken::new())
+            .unwrap();
+        assert!(!report.cancelled);
+        assert_eq!(report.added as usize, SONGS - lib.library.len());
+        assert_eq!(loaded.library.len(), SONGS);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_with_symlinks() {
+        use crate::music_storage::scan_report::ScanErrorKind;
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let music = dir.join("music");
+        std::fs::create_dir_all(&music).unwrap();
+        let song = dir.join("song.wav");
+        std::fs::copy("test-data/cue/album.wav", &song).unwrap();
+        symlink(&song, music.join("linked.wav")).unwrap();
+        symlink(dir.join("nowhere.flac"), music.join("dangling.flac")).unwrap();
+        std::fs::write(music.join("._linked.wav"), b"fork").unwrap();
+        std::fs::write(music.join("empty.mp3"), b"").unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let report = lib
+            .scan_folder(&music, &ExclusionRules::default(), &CancelToken::new())
+            .unwrap();
+
+        assert_eq!(report.added, 1);
+        let kinds = |kind| report.errors.iter().filter(|e| e.kind == kind).count();
+        assert_eq!(report.error_count(), 3);
+        assert_eq!(kinds(ScanErrorKind::Io), 1);
+        assert_eq!(kinds(ScanErrorKind::ResourceFork), 1);
+        assert_eq!(kinds(ScanErrorKind::EmptyFile), 1);
+
+        // The song is stored where the link points, and found by either path
+        let canonical = std::fs::canonicalize(&song).unwrap();
+        assert_eq!(lib.library[0].location[0], URI::Local(canonical.clone()));
+        assert!(lib.query_path(canonical).is_some());
+        assert!(lib.query_path(music.join("linked.wav")).is_some());
+
+        // Rescanning doesn't add the link again
+        assert_eq!(
+            lib.scan_folder(&music, &ExclusionRules::default(), &CancelToken::new())
+                .unwrap()
+                .added,
+            0
+        );
+        assert_eq!(lib.library.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        // "Café.wav" in Latin-1, which isn't valid UTF-8
+        let song = dir.join(OsStr::from_bytes(b"Caf\xe9.wav"));
+        std::fs::copy("test-data/cue/album.wav", &song).unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let report = lib
+            .scan_folder(&dir, &ExclusionRules::default(), &CancelToken::new())
+            .unwrap();
+        assert_eq!(report.added, 1);
+        let canonical = std::fs::canonicalize(&song).unwrap();
+        assert_eq!(lib.library[0].location[0], URI::Local(canonical.clone()));
+
+        // The path comes back the same after saving, so it can still play
+        let saved = dir.join("library.dlib");
+        lib.save_path(&saved).unwrap();
+        let read = MusicLibrary::from_path(&saved).unwrap();
+        assert_eq!(read.library[0].primary_uri().unwrap().0.path(), canonical);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_missing_keeps_unavailable_volumes() {
+        let here = std::env::current_dir().unwrap();
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            path_song(here.join("Cargo.toml")),
+            path_song(here.join("missing.flac")),
+            path_song(PathBuf::from("/dmp_unmounted_volume/music/a.flac")),
+            path_song(PathBuf::from("/dmp_unmounted_volume/music/b.flac")),
+        ];
+        lib.remove_missing(remove_options(100.0, false)).unwrap();
+
+        let remaining: Vec<_> = lib
+            .library
+            .iter()
+            .map(|song| song.location[0].path())
+            .collect();
+        assert_eq!(remaining.len(), 3);
+        assert!(!remaining.contains(&here.join("missing.flac")));
+    }
+
+    #[test]
+    fn remove_missing_keeps_unmounted_media() {
+        use crate::music_storage::utils::volume_root;
+
+        // Drives are mounted at /media/<user>/<label>, and the label's
+        // folder is removed when the drive is unmounted, leaving the user's
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let user = root.join("media").join("user");
+        std::fs::create_dir_all(&user).unwrap();
+        let music = user.join("label").join("Music");
+        let album = |name: &str| music.join("Album").join(name);
+        assert_eq!(volume_root(&album("a.flac")), user.join("label"));
+        assert_eq!(volume_root(&user.join("a.flac")), volume_root(&root));
+
+        let here = std::env::current_dir().unwrap();
+        for scan_folders in [Vec::new(), vec![music.clone()]] {
+            let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+            lib.library = vec![
+                path_song(here.join("Cargo.toml")),
+                path_song(album("a.flac")),
+                path_song(album("b.flac")),
+            ];
+            let options = RemoveMissingOptions {
+                scan_folders,
+                ..remove_options(100.0, false)
+            };
+            assert!(lib.remove_missing(options).unwrap().is_empty());
+        }
+
+        // Once it's mounted again, songs deleted from it are removed, and
+        // not the ones still there
+        std::fs::create_dir_all(music.join("Album")).unwrap();
+        std::fs::write(album("a.flac"), []).unwrap();
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![path_song(album("a.flac")), path_song(album("b.flac"))];
+        let options = RemoveMissingOptions {
+            scan_folders: vec![music.clone()],
+            ..remove_options(100.0, false)
+        };
+        let removed = lib.remove_missing(options).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].location[0].path(), album("b.flac"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn remove_missing_aborts_over_threshold() {
+        let here = std::env::current_dir().unwrap();
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            path_song(here.join("Cargo.toml")),
+            path_song(here.join("src/lib.rs")),
+            path_song(here.join("missing.flac")),
+            path_song(here.join("also_missing.flac")),
+        ];
+
+        assert_eq!(
+            lib.remove_missing(remove_options(10.0, false)),
+            Err(RemoveMissingError::TooMany {
+                missing: 2,
+                total: 4,
+                max_percent: 10.0
+            })
+        );
+        assert_eq!(lib.library.len(), 4);
+
+        // A dry run finds the same songs without removing them
+        let found = lib.remove_missing(remove_options(50.0, true)).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(lib.library.len(), 4);
+        assert!(lib.backup_songs.is_empty());
+    }
+
+    #[test]
+    fn undo_remove_missing() {
+        let here = std::env::current_dir().unwrap();
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            path_song(here.join("Cargo.toml")),
+            path_song(here.join("missing.flac")),
+        ];
+        let missing = lib.library[1].uuid;
+
+        let removed = lib.remove_missing(remove_options(50.0, false)).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].uuid, missing);
+        assert_eq!(lib.library.len(), 1);
+
+        assert_eq!(lib.undo_remove_missing(), 1);
+        let (restored, _) = lib.query_uuid(&missing).unwrap();
+        assert_eq!(restored.plays, 3);
+        assert_eq!(lib.undo_remove_missing(), 0);
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn windows_paths() {
+        use crate::music_storage::utils::{long_path, normalize_path, volume_root};
+
+        assert_eq!(
+            normalize_path(&PathBuf::from(r"\\?\C:\Music\song.flac")),
+            PathBuf::from(r"C:\Music\song.flac")
+        );
+        assert_eq!(
+            normalize_path(&PathBuf::from(r"\\?\UNC\server\share\song.flac")),
+            PathBuf::from(r"\\server\share\song.flac")
+        );
+        assert_eq!(
+            normalize_path(&PathBuf::from(r"D:\song.flac")),
+            PathBuf::from(r"D:\song.flac")
+        );
+
+        assert_eq!(
+            volume_root(&PathBuf::from(r"\\server\share\Music\song.flac")),
+            PathBuf::from(r"\\server\share\")
+        );
+        assert_eq!(
+            volume_root(&PathBuf::from(r"\\?\E:\Music\song.flac")),
+            PathBuf::from(r"E:\")
+        );
+
+        let long = PathBuf::from(format!(r"C:\{}\song.flac", "a".repeat(300)));
+        assert!(long_path(&long).to_string_lossy().starts_with(r"\\?\C:\"));
+        assert_eq!(normalize_path(&long_path(&long)), long);
+    }
+
+    #[test]
+    fn album_play_order() {
+        let uuids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let album = Album {
+            title: String::from("Album"),
+            artist: None,
+            cover: None,
+            discs: BTreeMap::from([
+                (2, vec![(2, uuids[4]), (1, uuids[3])]),
+                (1, vec![(1, uuids[0]), (2, uuids[1]), (3, uuids[2])]),
+            ]),
+            rating: None,
+            favorited: false,
+        };
+
+        let order = |start| {
+            album
+                .clone()
+                .play_order(start)
+                .iter()
+                .map(|track| *track.uuid())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(None), uuids);
+        assert_eq!(order(Some((1, 3))), uuids[2..]);
+        assert_eq!(order(Some((2, 1))), uuids[3..]);
+    }
+
+    /// A PNG header, which is enough to read the size of the image from
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend(width.to_be_bytes());
+        data.extend(height.to_be_bytes());
+        data.extend([8, 6, 0, 0, 0, 0, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn front_cover_from_pictures() {
+        let pictures = [
+            Picture::new_unchecked(
+                PictureType::CoverBack,
+                Some(MimeType::Png),
+                None,
+                png(1200, 1200),
+            ),
+            Picture::new_unchecked(
+                PictureType::CoverFront,
+                Some(MimeType::Png),
+                None,
+                png(500, 400),
+            ),
+        ];
+        let metadata: Vec<ArtMetadata> = pictures.iter().map(ArtMetadata::from_picture).collect();
+        assert_eq!(
+            metadata[0],
+            ArtMetadata {
+                kind: ArtKind::BackCover,
+                width: Some(1200),
+                height: Some(1200),
+            }
+        );
+        assert_eq!(metadata[1].kind, ArtKind::FrontCover);
+        assert_eq!(
+            (metadata[1].width, metadata[1].height),
+            (Some(500), Some(400))
+        );
+
+        // The front cover wins over the larger back cover scan
+        let mut song = Song {
+            album_art: vec![AlbumArt::Embedded(0), AlbumArt::Embedded(1)],
+            art_metadata: metadata,
+            ..Default::default()
+        };
+        assert_eq!(song.front_cover_index(), Some(1));
+
+        song.preferred_art = Some(0);
+        assert_eq!(song.front_cover_index(), Some(0));
+        song.preferred_art = Some(2);
+        assert_eq!(song.front_cover_index(), Some(1));
+    }
+
+    #[test]
+    fn front_cover_fallbacks() {
+        let sized = |kind, width| ArtMetadata {
+            kind,
+            width: Some(width),
+            height: Some(width),
+        };
+        let mut song = Song {
+            album_art: vec![
+                AlbumArt::Embedded(0),
+                AlbumArt::Embedded(1),
+                AlbumArt::External(URI::Local(PathBuf::from("/music/scan.jpg"))),
+            ],
+            art_metadata: vec![
+                sized(ArtKind::Other, 100),
+                sized(ArtKind::Artist, 500),
+                ArtMetadata::from_path(Path::new("/music/scan.jpg")),
+            ],
+            ..Default::default()
+        };
+        // Without a front cover, the largest image is used
+        assert_eq!(song.front_cover_index(), Some(1));
+
+        // Songs from before the metadata was recorded use the first image
+        song.art_metadata.clear();
+        assert_eq!(song.front_cover_index(), Some(0));
+
+        song.album_art.clear();
+        assert_eq!(song.front_cover_index(), None);
+
+        assert_eq!(
+            ArtMetadata::from_path(Path::new("/music/Cover.jpg")).kind,
+            ArtKind::FrontCover
+        );
+        assert_eq!(
+            ArtMetadata::from_path(Path::new("/music/folder.png")).kind,
+            ArtKind::FrontCover
+        );
+        assert_eq!(
+            ArtMetadata::from_path(Path::new("/music/back.png")).kind,
+            ArtKind::BackCover
+        );
+    }
+
+    #[test]
+    fn track_number_formats() {
+        for (value, expected) in [
+            ("3", Some(3)),
+            ("03", Some(3)),
+            (" 7 ", Some(7)),
+            ("3/12", Some(3)),
+            ("03 / 12", Some(3)),
+            ("12.", Some(12)),
+            ("A1", Some(1)),
+            ("a2", Some(2)),
+            ("B1", Some(101)),
+            ("B 03", Some(103)),
+            ("D4/8", Some(304)),
+            ("Z100", Some(2600)),
+            ("Z65000", None),
+            ("/12", None),
+            ("A", None),
+            ("", None),
+            ("Unknown", None),
+        ] {
+            assert_eq!(parse_track_number(value), expected, "{value:?}");
+        }
+
+        assert_eq!(parse_disc_number("2/2"), Some(2));
+        assert_eq!(parse_disc_number("1"), Some(1));
+        assert_eq!(parse_disc_number(""), None);
+    }
+
+    #[test]
+    fn track_number_from_file_name() {
+        let song = |name: &str| Song {
+            location: vec![URI::Local(PathBuf::from("/music").join(name))],
+            ..Default::default()
+        };
+
+        assert_eq!(song("04 - Title.flac").track_number(), Some(4));
+        assert_eq!(song("12.Title.mp3").track_number(), Some(12));
+        assert_eq!(song("Title.flac").track_number(), None);
+        assert_eq!(song("1999 - Title.flac").track_number(), None);
+
+        // The tag wins over the file name
+        let mut tagged = song("04 - Title.flac");
+        tagged.set_tag(Tag::Track, "2/10".to_string());
+        assert_eq!(tagged.track_number(), Some(2));
+    }
+
+    #[test]
+    fn album_track_order() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for (file, track, disc) in [
+            ("x.flac", Some("3/12"), None),
+            ("y.flac", Some("10/12"), None),
+            ("z.flac", Some("1/12"), None),
+            ("02 Untagged.flac", None, None),
+            ("b.flac", Some("B1"), Some("2/2")),
+            ("a.flac", Some("A2"), Some("2/2")),
+            ("c.flac", Some("A1"), Some("2/2")),
+        ] {
+            let path = dir.join(file);
+            std::fs::File::create(&path).unwrap();
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(path)],
+                ..Default::default()
+            };
+            song.set_tag(Tag::Album, "Album".to_string());
+            song.set_tag(Tag::Title, file.to_string());
+            if let Some(track) = track {
+                song.set_tag(Tag::Track, track.to_string());
+            }
+            if let Some(disc) = disc {
+                song.set_tag(Tag::Disk, disc.to_string());
+            }
+            lib.library.push(song);
+        }
+
+        let album = lib.albums().remove("Album").unwrap();
+        let titles: Vec<_> = album
+            .play_order(None)
+            .iter()
+            .map(|track| {
+                let (song, _) = lib.query_uuid(track.uuid()).unwrap();
+                song.get_tag(&Tag::Title).unwrap().as_str()
+            })
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                "z.flac",
+                "02 Untagged.flac",
+                "x.flac",
+                "y.flac",
+                "c.flac",
+                "a.flac",
+                "b.flac"
+            ]
+        );
+
+        // Sorting by tags uses the same numbers
+        let mut songs = lib.library.clone();
+        songs.sort_by(|a, b| a.cmp_by_tags(b, &[Tag::Disk, Tag::Track]));
+        let sorted: Vec<_> = songs
+            .iter()
+            .map(|song| song.get_tag(&Tag::Title).unwrap().as_str())
+            .collect();
+        assert_eq!(sorted, titles);
+    }
+
+    #[test]
+    fn album_rating_precedence() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for (file, rating) in [("1.flac", None), ("2.flac", Some(20)), ("3.flac", None)] {
+            let path = dir.join(file);
+            std::fs::File::create(&path).unwrap();
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(path)],
+                rating,
+                ..Default::default()
+            };
+            song.set_tag(Tag::Album, "Album".to_string());
+            lib.library.push(song);
+        }
+        let key = lib.albums()["Album"].key();
+        let ratings = |lib: &MusicLibrary| lib.library.iter().map(|s| s.rating).collect::<Vec<_>>();
+        assert_eq!(lib.albums()["Album"].rating(), Some(20.0));
+
+        // Songs rated on their own keep their rating
+        lib.set_album_rating(&key, Some(80)).unwrap();
+        assert_eq!(ratings(&lib), [Some(80), Some(20), Some(80)]);
+        assert_eq!(lib.albums()["Album"].rating(), Some(60.0));
+
+        // Re-rating the album replaces the ratings it gave
+        lib.library[2].set_rating(Some(100));
+        lib.set_album_rating(&key, Some(40)).unwrap();
+        assert_eq!(ratings(&lib), [Some(40), Some(20), Some(100)]);
+
+        lib.set_album_rating(&key, None).unwrap();
+        assert_eq!(ratings(&lib), [None, Some(20), Some(100)]);
+        assert!(lib.library[0].internal_tags.is_empty());
+
+        let missing = super::AlbumKey {
+            title: "Missing".to_string(),
+            artist: None,
+        };
+        assert!(lib.set_album_rating(&missing, Some(10)).is_err());
+    }
+
+    #[test]
+    fn album_favorite_precedence() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for (file, favorited) in [("1.flac", false), ("2.flac", true)] {
+            let path = dir.join(file);
+            std::fs::File::create(&path).unwrap();
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(path)],
+                favorited,
+                ..Default::default()
+            };
+            song.set_tag(Tag::Album, "Album".to_string());
+            lib.library.push(song);
+        }
+        let key = lib.albums()["Album"].key();
+        assert!(!lib.albums()["Album"].favorited());
+
+        lib.set_album_favorited(&key, true).unwrap();
+        assert!(lib.albums()["Album"].favorited());
+
+        // Unfavoriting the album keeps the song favorited on its own
+        lib.set_album_favorited(&key, false).unwrap();
+        assert!(!lib.library[0].favorited);
+        assert!(lib.library[1].favorited);
+    }
 }
@@ -0,0 +1,552 @@
+//! Keeps the library from overwriting changes made to its file by other
+//! programs, like a sync tool bringing in the copy from another machine
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{
+    library::MusicLibrary,
+    utils::{read_file, write_file},
+};
+
+/// What the library file looked like when it was last loaded or saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LibraryStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl LibraryStamp {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(LibraryStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: xxh3_64(&fs::read(path)?),
+        })
+    }
+
+    /// Whether the file at `path` is different from when this was taken. The
+    /// contents are compared as well, since syncing can touch the modified
+    /// time without changing anything.
+    pub fn changed(&self, path: &Path) -> io::Result<bool> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // If the file was deleted there is nothing to clobber
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if metadata.modified().ok() == self.modified && metadata.len() == self.len {
+            return Ok(false);
+        }
+
+        Ok(xxh3_64(&fs::read(path)?) != self.hash)
+    }
+}
+
+/// The changes to a song's stats since the library was loaded
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct StatDelta {
+    pub plays: i32,
+    pub skips: i32,
+    pub play_time: Duration,
+    pub last_played: Option<DateTime<Utc>>,
+}
+
+impl StatDelta {
+    fn merge(&mut self, other: &StatDelta) {
+        self.plays += other.plays;
+        self.skips += other.skips;
+        self.play_time += other.play_time;
+        self.last_played = self.last_played.max(other.last_played);
+    }
+}
+
+/// One line of the stat journal file
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    uuid: Uuid,
+    delta: StatDelta,
+}
+
+/// Stat changes made since the last save, so they can be applied on top of a
+/// library file which was changed elsewhere
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StatJournal {
+    deltas: BTreeMap<Uuid, StatDelta>,
+}
+
+impl StatJournal {
+    pub fn record(&mut self, uuid: Uuid, delta: &StatDelta) {
+        self.deltas.entry(uuid).or_default().merge(delta);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+
+    /// Adds the recorded changes to the songs in `library`, skipping songs
+    /// which aren't in it
+    pub fn apply(&self, library: &mut MusicLibrary) {
+        for (uuid, delta) in &self.deltas {
+            let Some((_, i)) = library.query_uuid(uuid) else {
+                continue;
+            };
+            let song = &mut library.library[i];
+            song.plays += delta.plays;
+            song.skips += delta.skips;
+            song.play_time += delta.play_time;
+            song.last_played = song.last_played.max(delta.last_played);
+            if delta.plays > 0 {
+                song.first_played = song.first_played.or(delta.last_played);
+            }
+        }
+    }
+}
+
+/// Sent when saving was stopped because the library file was changed by
+/// another program
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LibraryConflict {
+    #[serde(with = "super::os_path")]
+    pub path: PathBuf,
+}
+
+/// How to settle a library file which was changed by another program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+pub enum ConflictResolution {
+    /// Load the file, discarding unsaved changes
+    Reload,
+    /// Save over the file anyway
+    Overwrite,
+    /// Load the file, then apply the stat changes made since the last save
+    MergeStats,
+}
+
+#[derive(Error, Debug)]
+pub enum LibrarySaveError {
+    #[error("The library file was changed by another program")]
+    Conflict,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<Box<dyn Error>> for LibrarySaveError {
+    fn from(error: Box<dyn Error>) -> Self {
+        LibrarySaveError::Other(error.to_string())
+    }
+}
+
+/// Watches the file a library is saved to, refusing to save over it once it
+/// was changed externally until the conflict is resolved
+///
+/// Stat changes are also appended to a journal file next to the library
+/// until it's saved, so they can be replayed if the player crashes first.
+#[derive(Debug)]
+pub struct LibraryGuard {
+    path: PathBuf,
+    stamp: Option<LibraryStamp>,
+    journal: StatJournal,
+}
+
+impl LibraryGuard {
+    /// Starts watching `path`, which the library should just have been
+    /// loaded from or saved to
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        LibraryGuard {
+            stamp: LibraryStamp::read(&path).ok(),
+            path,
+            journal: StatJournal::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where stat changes are kept until the library is saved
+    pub fn journal_path(&self) -> PathBuf {
+        self.path.with_extension("journal")
+    }
+
+    /// Records a change to a song's stats, which should already have been
+    /// applied to the library
+    pub fn record(&mut self, uuid: Uuid, delta: &StatDelta) {
+        self.journal.record(uuid, delta);
+        if let Err(e) = self.append_journal(uuid, delta) {
+            println!("Could not write to the stat journal: {e}");
+        }
+    }
+
+    /// Applies the stat changes left in the journal file by a session which
+    /// ended without saving, returning whether there were any
+    pub fn replay(&mut self, library: &mut MusicLibrary) -> io::Result<bool> {
+        let file = match fs::File::open(self.journal_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let mut replayed = StatJournal::default();
+        for line in BufReader::new(file).lines() {
+            // A crash can leave the last line half written
+            let Ok(entry) = serde_json::from_str::<JournalEntry>(&line?) else {
+                continue;
+            };
+            replayed.record(entry.uuid, &entry.delta);
+        }
+        replayed.apply(library);
+        for (uuid, delta) in &replayed.deltas {
+            self.journal.record(*uuid, delta);
+        }
+        Ok(!replayed.is_empty())
+    }
+
+    pub fn changed_externally(&self) -> io::Result<bool> {
+        match &self.stamp {
+            Some(stamp) => stamp.changed(&self.path),
+            None => Ok(false),
+        }
+    }
+
+    /// Saves the library, unless the file was changed since it was last
+    /// loaded or saved
+    pub fn save(&mut self, library: &MusicLibrary) -> Result<(), LibrarySaveError> {
+        if self.changed_externally()? {
+            return Err(LibrarySaveError::Conflict);
+        }
+        self.write(library)
+    }
+
+    /// Settles a conflict, leaving `library` matching what is saved
+    pub fn resolve(
+        &mut self,
+        library: &mut MusicLibrary,
+        resolution: ConflictResolution,
+    ) -> Result<(), LibrarySaveError> {
+        match resolution {
+            ConflictResolution::Reload => {
+                *library = read_file(self.path.clone())?;
+                self.clear_journal();
+                self.stamp = Some(LibraryStamp::read(&self.path)?);
+                Ok(())
+            }
+            ConflictResolution::Overwrite => self.write(library),
+            ConflictResolution::MergeStats => {
+                let mut external: MusicLibrary = read_file(self.path.clone())?;
+                self.journal.apply(&mut external);
+                *library = external;
+                self.write(library)
+            }
+        }
+    }
+
+    fn write(&mut self, library: &MusicLibrary) -> Result<(), LibrarySaveError> {
+        write_file(library, &self.path)?;
+        self.clear_journal();
+        self.stamp = Some(LibraryStamp::read(&self.path)?);
+        Ok(())
+    }
+
+    fn append_journal(&self, uuid: Uuid, delta: &StatDelta) -> io::Result<()> {
+        let mut line = serde_json::to_string(&JournalEntry {
+            uuid,
+            delta: delta.clone(),
+        })?;
+        line.push('\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.journal_path())?
+            .write_all(line.as_bytes())
+    }
+
+    fn clear_journal(&mut self) {
+        self.journal.clear();
+        _ = fs::remove_file(self.journal_path());
+    }
+}
+
+/// The library is open in another process, which has it locked
+#[derive(Error, Debug)]
+pub enum LibraryLockError {
+    #[error(
+        "The library is open in another process ({pid}). Close it first, or remove {} if that \
+         process isn't DMP.",
+        .path.display()
+    )]
+    Held { pid: u32, path: PathBuf },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Marks a library file as open for writing by this process until it's
+/// dropped, so other processes like the `dmp` command line tool don't save
+/// over it. The lock is a file next to the library holding the process ID,
+/// and a lock left behind by a process which isn't running anymore is taken
+/// over.
+#[derive(Debug)]
+pub struct LibraryLock {
+    path: PathBuf,
+}
+
+impl LibraryLock {
+    /// Where the lock for the library at `library` is kept
+    pub fn lock_path(library: &Path) -> PathBuf {
+        library.with_extension("lock")
+    }
+
+    /// Locks the library at `library`, unless another running process has
+    /// it locked
+    pub fn acquire(library: &Path) -> Result<Self, LibraryLockError> {
+        let path = Self::lock_path(library);
+        let pid = std::process::id();
+        // The ID is written before the lock is put in place, so nothing
+        // ever sees a lock without one
+        let written = path.with_extension(format!("lock.{pid}"));
+        fs::write(&written, pid.to_string())?;
+
+        let locked = Self::place(&written, &path, library);
+        _ = fs::remove_file(&written);
+        locked.map(|()| LibraryLock { path })
+    }
+
+    /// Links the lock file `written` into place at `path`
+    fn place(written: &Path, path: &Path, library: &Path) -> Result<(), LibraryLockError> {
+        match fs::hard_link(written, path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if let Some(pid) = Self::holder(library) {
+                    return Err(LibraryLockError::Held {
+                        pid,
+                        path: path.to_path_buf(),
+                    });
+                }
+                // Left behind by a process which isn't running anymore
+                _ = fs::remove_file(path);
+                Ok(fs::hard_link(written, path)?)
+            }
+            locked => Ok(locked?),
+        }
+    }
+
+    /// The running process which has the library at `library` locked, if
+    /// there is one
+    pub fn holder(library: &Path) -> Option<u32> {
+        let pid = fs::read_to_string(Self::lock_path(library)).ok()?;
+        let pid = pid.trim().parse().ok()?;
+        process_running(pid).then_some(pid)
+    }
+}
+
+impl Drop for LibraryLock {
+    fn drop(&mut self) {
+        // Only remove the lock if it wasn't taken over in the meantime
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+        if ours {
+            _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(windows)]
+fn process_running(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::{
+        ConflictResolution, LibraryGuard, LibraryLock, LibraryLockError, LibrarySaveError,
+        StatDelta,
+    };
+    use crate::music_storage::library::{MusicLibrary, Song, URI};
+
+    /// A saved library with one song, along with a guard watching it
+    fn saved_library() -> (MusicLibrary, LibraryGuard, Uuid) {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("library.dlib");
+        let song_path = dir.join("song.flac");
+        std::fs::File::create(&song_path).unwrap();
+
+        let mut library = MusicLibrary::init(path.clone(), Uuid::new_v4()).unwrap();
+        let song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(song_path)],
+            plays: 5,
+            ..Default::default()
+        };
+        let uuid = song.uuid;
+        library.add_song(song).unwrap();
+        library.save(path.clone()).unwrap();
+
+        (library, LibraryGuard::new(path), uuid)
+    }
+
+    /// Saves a copy of `library` from "another machine", with a different
+    /// play count and name
+    fn modify_externally(guard: &LibraryGuard, library: &MusicLibrary, plays: i32) {
+        let mut external = library.clone();
+        external.name = "Synced".to_string();
+        external.library[0].plays = plays;
+        external.library[0].play_time = Duration::ZERO;
+        external.save(guard.path().to_path_buf()).unwrap();
+    }
+
+    fn play(library: &mut MusicLibrary, guard: &mut LibraryGuard, uuid: Uuid) {
+        let delta = StatDelta {
+            plays: 1,
+            play_time: Duration::from_secs(60),
+            ..Default::default()
+        };
+        library.library[0].plays += delta.plays;
+        library.library[0].play_time += delta.play_time;
+        guard.record(uuid, &delta);
+    }
+
+    #[test]
+    fn save_without_changes() {
+        let (mut library, mut guard, uuid) = saved_library();
+        play(&mut library, &mut guard, uuid);
+        guard.save(&library).unwrap();
+        guard.save(&library).unwrap();
+
+        let saved = MusicLibrary::from_path(guard.path()).unwrap();
+        assert_eq!(saved.library[0].plays, 6);
+    }
+
+    #[test]
+    fn replay_unsaved_stats() {
+        let (mut library, mut guard, uuid) = saved_library();
+        play(&mut library, &mut guard, uuid);
+        play(&mut library, &mut guard, uuid);
+
+        // The player crashed, so the next session starts from the saved file
+        let mut library = MusicLibrary::from_path(guard.path()).unwrap();
+        let mut guard = LibraryGuard::new(guard.path());
+        assert!(guard.replay(&mut library).unwrap());
+        assert_eq!(library.library[0].plays, 7);
+        assert_eq!(library.library[0].play_time, Duration::from_secs(120));
+
+        guard.save(&library).unwrap();
+        assert!(!guard.journal_path().exists());
+        assert!(!guard.replay(&mut library).unwrap());
+        assert_eq!(library.library[0].plays, 7);
+    }
+
+    #[test]
+    fn external_change_is_not_clobbered() {
+        let (mut library, mut guard, uuid) = saved_library();
+        play(&mut library, &mut guard, uuid);
+        modify_externally(&guard, &library, 20);
+
+        assert!(matches!(
+            guard.save(&library),
+            Err(LibrarySaveError::Conflict)
+        ));
+        let saved = MusicLibrary::from_path(guard.path()).unwrap();
+        assert_eq!(saved.name, "Synced");
+        assert_eq!(saved.library[0].plays, 20);
+    }
+
+    #[test]
+    fn resolve_conflicts() {
+        let (mut library, mut guard, uuid) = saved_library();
+        play(&mut library, &mut guard, uuid);
+        modify_externally(&guard, &library, 20);
+        guard
+            .resolve(&mut library, ConflictResolution::Reload)
+            .unwrap();
+        assert_eq!(library.name, "Synced");
+        assert_eq!(library.library[0].plays, 20);
+        // Reloading drops the local plays, so nothing is merged later
+        guard.save(&library).unwrap();
+
+        play(&mut library, &mut guard, uuid);
+        modify_externally(&guard, &library, 30);
+        guard
+            .resolve(&mut library, ConflictResolution::MergeStats)
+            .unwrap();
+        assert_eq!(library.name, "Synced");
+        assert_eq!(library.library[0].plays, 31);
+        assert_eq!(library.library[0].play_time, Duration::from_secs(60));
+        let saved = MusicLibrary::from_path(guard.path()).unwrap();
+        assert_eq!(saved.library[0].plays, 31);
+
+        library.name = "Local".to_string();
+        modify_externally(&guard, &library, 40);
+        guard
+            .resolve(&mut library, ConflictResolution::Overwrite)
+            .unwrap();
+        let saved = MusicLibrary::from_path(guard.path()).unwrap();
+        assert_eq!(saved.name, "Local");
+        assert_eq!(saved.library[0].plays, 31);
+    }
+
+    #[test]
+    fn lock_held_until_dropped() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        let library = dir.join("library.dlib");
+        let pid = std::process::id();
+
+        let lock = LibraryLock::acquire(&library).unwrap();
+        assert_eq!(LibraryLock::holder(&library), Some(pid));
+        assert!(matches!(
+            LibraryLock::acquire(&library),
+            Err(LibraryLockError::Held { pid: holder, .. }) if holder == pid
+        ));
+        drop(lock);
+        assert!(!LibraryLock::lock_path(&library).exists());
+        assert_eq!(LibraryLock::holder(&library), None);
+
+        // A process which crashed left its lock behind
+        std::fs::write(LibraryLock::lock_path(&library), "999999999").unwrap();
+        assert_eq!(LibraryLock::holder(&library), None);
+        let _lock = LibraryLock::acquire(&library).unwrap();
+        assert_eq!(LibraryLock::holder(&library), Some(pid));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
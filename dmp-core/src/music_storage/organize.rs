@@ -0,0 +1,862 @@
+//! Moves song files into folders named after their tags, like
+//! `{album_artist}/{album}/{disc}-{track} {title}`, keeping the library
+//! pointed at them. Moves are journaled, so an interrupted run is finished
+//! the next time the library is loaded.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{
+    cancel::CancelToken,
+    gain_analysis::AnalyzeScope,
+    library::{MusicLibrary, Song, Tag, URI},
+    utils::normalize_path,
+};
+
+/// The name of the file moves are journaled in while they're being applied,
+/// next to the config
+pub const ORGANIZE_JOURNAL_FILE: &str = "organize_journal.json";
+
+/// Characters which can't be used in file names on Windows
+const INVALID_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, whatever their extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Leaves room for an extension within the usual 255 byte limit
+const MAX_COMPONENT_LEN: usize = 200;
+
+#[derive(Error, Debug)]
+pub enum OrganizeError {
+    #[error("The pattern is empty")]
+    EmptyPattern,
+    #[error("Unknown field in the pattern: {{{0}}}")]
+    UnknownField(String),
+    #[error("A brace in the pattern isn't closed")]
+    UnclosedBrace,
+    #[error("Couldn't use the journal: {0}")]
+    Io(#[from] io::Error),
+    #[error("Couldn't read the journal: {0}")]
+    Journal(#[from] serde_json::Error),
+}
+
+/// A value from a song which can be used in a pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateField {
+    /// The album artist, or the artist if there isn't one
+    AlbumArtist,
+    Artist,
+    Album,
+    Title,
+    /// Padded to two digits
+    Track,
+    Disc,
+    Year,
+    Genre,
+}
+
+impl TemplateField {
+    fn parse(name: &str) -> Result<Self, OrganizeError> {
+        Ok(match name.trim() {
+            "album_artist" => TemplateField::AlbumArtist,
+            "artist" => TemplateField::Artist,
+            "album" => TemplateField::Album,
+            "title" => TemplateField::Title,
+            "track" => TemplateField::Track,
+            "disc" => TemplateField::Disc,
+            "year" => TemplateField::Year,
+            "genre" => TemplateField::Genre,
+            _ => return Err(OrganizeError::UnknownField(name.to_string())),
+        })
+    }
+
+    /// The value of the field for a song, with a placeholder for anything
+    /// the song isn't tagged with
+    fn value(&self, song: &Song, path: &Path) -> String {
+        let tag = |tag: Tag, default: &str| {
+            song.get_tag(&tag)
+                .filter(|value| !value.trim().is_empty())
+                .cloned()
+                .unwrap_or_else(|| default.to_string())
+        };
+        match self {
+            TemplateField::AlbumArtist => match song.get_tag(&Tag::AlbumArtist) {
+                Some(artist) if !artist.trim().is_empty() => artist.clone(),
+                _ => tag(Tag::Artist, "Unknown Artist"),
+            },
+            TemplateField::Artist => tag(Tag::Artist, "Unknown Artist"),
+            TemplateField::Album => tag(Tag::Album, "Unknown Album"),
+            TemplateField::Title => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                tag(Tag::Title, &stem)
+            }
+            TemplateField::Track => format!("{:02}", song.track_number().unwrap_or(0)),
+            TemplateField::Disc => song.disc_number().unwrap_or(1).to_string(),
+            TemplateField::Year => song
+                .year()
+                .map_or_else(|| String::from("0000"), |year| year.to_string()),
+            TemplateField::Genre => tag(Tag::Genre, "Unknown Genre"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(TemplateField),
+}
+
+/// A parsed pattern, where each `/` separated part is a folder except the
+/// last, which is the file name without its extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    components: Vec<Vec<Piece>>,
+}
+
+impl PathTemplate {
+    pub fn parse(pattern: &str) -> Result<Self, OrganizeError> {
+        let mut components = Vec::new();
+        for component in pattern.split(['/', '\\']) {
+            let mut pieces = Vec::new();
+            let mut rest = component;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    pieces.push(Piece::Text(rest[..start].to_string()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or(OrganizeError::UnclosedBrace)?;
+                pieces.push(Piece::Field(TemplateField::parse(
+                    &rest[start + 1..start + end],
+                )?));
+                rest = &rest[start + end + 1..];
+            }
+            if !rest.is_empty() {
+                pieces.push(Piece::Text(rest.to_string()));
+            }
+            // Doubled and trailing slashes don't make empty folders
+            if !pieces.is_empty() {
+                components.push(pieces);
+            }
+        }
+
+        match components.is_empty() {
+            true => Err(OrganizeError::EmptyPattern),
+            false => Ok(PathTemplate { components }),
+        }
+    }
+
+    /// Where a song at `path` belongs relative to the library folder,
+    /// keeping its extension
+    pub fn render(&self, song: &Song, path: &Path) -> PathBuf {
+        let mut target: PathBuf = self
+            .components
+            .iter()
+            .map(|pieces| {
+                let name: String = pieces
+                    .iter()
+                    .map(|piece| match piece {
+                        Piece::Text(text) => text.clone(),
+                        Piece::Field(field) => field.value(song, path),
+                    })
+                    .collect();
+                sanitize_component(&name)
+            })
+            .collect();
+
+        if let Some(extension) = path.extension() {
+            let mut name = target.file_name().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(extension);
+            target.set_file_name(name);
+        }
+        target
+    }
+}
+
+/// Makes a folder or file name which is valid on every platform
+pub fn sanitize_component(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            c if INVALID_CHARS.contains(&c) || c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    if name.len() > MAX_COMPONENT_LEN {
+        let mut end = MAX_COMPONENT_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+
+    // Windows drops trailing dots and spaces, which also takes care of `..`
+    let mut name = name
+        .trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+    if name.is_empty() {
+        name = String::from("_");
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        name.insert(stem.len(), '_');
+    }
+    name
+}
+
+/// A file to be moved, along with the songs which are stored in it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileMove {
+    #[serde(with = "super::os_path")]
+    pub from: PathBuf,
+    #[serde(with = "super::os_path")]
+    pub to: PathBuf,
+    pub songs: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictKind {
+    /// Another file is already where the song belongs
+    TargetExists,
+    /// An earlier song in the same run belongs at the same place
+    DuplicateTarget,
+    /// The song's own file couldn't be found
+    Missing,
+}
+
+/// A song which was left where it is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrganizeConflict {
+    pub uuid: Uuid,
+    #[serde(with = "super::os_path")]
+    pub from: PathBuf,
+    #[serde(with = "super::os_path")]
+    pub to: PathBuf,
+    pub kind: ConflictKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MoveFailure {
+    #[serde(with = "super::os_path")]
+    pub from: PathBuf,
+    #[serde(with = "super::os_path")]
+    pub to: PathBuf,
+    pub error: String,
+}
+
+/// The moves organizing would make, or made once applied
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrganizeReport {
+    pub moves: Vec<FileMove>,
+    pub conflicts: Vec<OrganizeConflict>,
+    /// Moves which went wrong when applying, which are left out of `moves`
+    pub failed: Vec<MoveFailure>,
+    /// How many songs were already in the right place
+    pub unchanged: usize,
+}
+
+/// Moves a file, copying it when it's on another drive. Copies are made
+/// next to the target and checked before the original is removed, so an
+/// interrupted copy never leaves a partial file at the target.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_verify_delete(from, to)
+}
+
+fn copy_verify_delete(from: &Path, to: &Path) -> io::Result<()> {
+    let mut partial = to.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    fs::copy(from, &partial)?;
+    if !same_contents(from, &partial)? {
+        _ = fs::remove_file(&partial);
+        return Err(io::Error::other("The copy doesn't match the original"));
+    }
+    fs::rename(&partial, to)?;
+    fs::remove_file(from)
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(xxh3_64(&fs::read(a)?) == xxh3_64(&fs::read(b)?))
+}
+
+/// Paths which only differ by case can be the same file, so targets are
+/// compared this way
+fn fold_case(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_lowercase())
+}
+
+/// Removes the folders a file was moved out of if that left them empty,
+/// without going above `root`
+fn remove_empty_dirs(from: &Path, root: &Path) {
+    let mut dir = from.parent();
+    while let Some(current) = dir.filter(|dir| dir.starts_with(root) && *dir != root) {
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum MoveResult {
+    Moved,
+    /// Neither the file nor its copy could be found
+    Gone,
+    /// A different file is already at the target, so the file was left
+    TargetTaken,
+}
+
+/// Finishes a move which might have been interrupted
+fn finish_move(file_move: &FileMove) -> io::Result<MoveResult> {
+    match (file_move.from.exists(), file_move.to.exists()) {
+        (true, false) => move_file(&file_move.from, &file_move.to).map(|_| MoveResult::Moved),
+        // A verified copy was put in place but the original wasn't removed,
+        // unless the target is some other file
+        (true, true) => match same_contents(&file_move.from, &file_move.to)? {
+            true => fs::remove_file(&file_move.from).map(|_| MoveResult::Moved),
+            false => Ok(MoveResult::TargetTaken),
+        },
+        (false, true) => Ok(MoveResult::Moved),
+        (false, false) => Ok(MoveResult::Gone),
+    }
+}
+
+impl MusicLibrary {
+    /// Works out where each song in `scope` belongs under `root`, without
+    /// moving anything
+    pub fn plan_organize(
+        &self,
+        template: &PathTemplate,
+        root: &Path,
+        scope: &AnalyzeScope,
+    ) -> OrganizeReport {
+        let songs: Vec<&Song> = match scope {
+            AnalyzeScope::Album(key) => self
+                .query_album(key)
+                .map(|album| {
+                    album
+                        .discs()
+                        .values()
+                        .flatten()
+                        .filter_map(|(_, uuid)| self.query_uuid(uuid).map(|(song, _)| song))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            AnalyzeScope::Songs(uuids) => uuids
+                .iter()
+                .filter_map(|uuid| self.query_uuid(uuid).map(|(song, _)| song))
+                .collect(),
+            AnalyzeScope::WholeLibrary => self.library.iter().collect(),
+        };
+
+        let mut report = OrganizeReport::default();
+        let mut targets: HashMap<PathBuf, usize> = HashMap::new();
+        for song in songs {
+            // Files split by cue sheets are referred to by the sheet, so
+            // they stay where they are
+            let Some(URI::Local(from)) = song.location.first() else {
+                continue;
+            };
+            let from = normalize_path(from);
+            let to = root.join(template.render(song, &from));
+
+            let conflict = |kind| OrganizeConflict {
+                uuid: song.uuid,
+                from: from.clone(),
+                to: to.clone(),
+                kind,
+            };
+            if from == to {
+                report.unchanged += 1;
+            } else if !from.exists() {
+                report.conflicts.push(conflict(ConflictKind::Missing));
+            } else if to.exists() {
+                report.conflicts.push(conflict(ConflictKind::TargetExists));
+            } else if let Some(&i) = targets.get(&fold_case(&to)) {
+                // The same file can be in the library more than once
+                match report.moves[i].from == from {
+                    true => report.moves[i].songs.push(song.uuid),
+                    false => report
+                        .conflicts
+                        .push(conflict(ConflictKind::DuplicateTarget)),
+                }
+            } else {
+                targets.insert(fold_case(&to), report.moves.len());
+                report.moves.push(FileMove {
+                    from,
+                    to,
+                    songs: vec![song.uuid],
+                });
+            }
+        }
+        report
+    }
+
+    /// Makes the moves in a plan, first writing them to `journal` so they
+    /// can be finished by [`MusicLibrary::resume_organize`] if this is
+    /// interrupted. The journal should be removed once the library is saved.
+    ///
+    /// Once `cancel` is cancelled the rest of the files are left where they
+    /// are, and reported as failed.
+    pub fn organize_files(
+        &mut self,
+        plan: OrganizeReport,
+        root: &Path,
+        journal: &Path,
+        cancel: &CancelToken,
+    ) -> Result<OrganizeReport, OrganizeError> {
+        fs::write(journal, serde_json::to_vec(&(root, &plan.moves))?)?;
+
+        let mut report = OrganizeReport {
+            moves: Vec::new(),
+            ..plan
+        };
+        for file_move in plan.moves {
+            if cancel.is_cancelled() {
+                report.failed.push(MoveFailure {
+                    from: file_move.from,
+                    to: file_move.to,
+                    error: String::from("Cancelled before the file was moved"),
+                });
+                continue;
+            }
+            match finish_move(&file_move) {
+                Ok(MoveResult::Moved) => {
+                    remove_empty_dirs(&file_move.from, root);
+                    report.moves.push(file_move);
+                }
+                Ok(MoveResult::TargetTaken) => {
+                    report
+                        .conflicts
+                        .extend(file_move.songs.iter().map(|&uuid| OrganizeConflict {
+                            uuid,
+                            from: file_move.from.clone(),
+                            to: file_move.to.clone(),
+                            kind: ConflictKind::TargetExists,
+                        }))
+                }
+                Ok(MoveResult::Gone) => report.failed.push(MoveFailure {
+                    from: file_move.from,
+                    to: file_move.to,
+                    error: String::from("The file has disappeared"),
+                }),
+                Err(e) => report.failed.push(MoveFailure {
+                    from: file_move.from,
+                    to: file_move.to,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        self.rewrite_uris(&report.moves);
+        Ok(report)
+    }
+
+    /// Finishes the moves in a journal left by an interrupted
+    /// [`MusicLibrary::organize_files`], returning `None` if there wasn't one
+    pub fn resume_organize(
+        &mut self,
+        journal: &Path,
+    ) -> Result<Option<OrganizeReport>, OrganizeError> {
+        if !journal.exists() {
+            return Ok(None);
+        }
+        let (root, moves): (PathBuf, Vec<FileMove>) = serde_json::from_slice(&fs::read(journal)?)?;
+        let plan = OrganizeReport {
+            moves,
+            ..Default::default()
+        };
+        self.organize_files(plan, &root, journal, &CancelToken::new())
+            .map(Some)
+    }
+
+    /// Points the songs in each finished move at their new file. Every
+    /// location is changed before the library is next saved, so it never
+    /// refers to a mix of old and new paths.
+    fn rewrite_uris(&mut self, moves: &[FileMove]) {
+        for file_move in moves {
+            for uuid in &file_move.songs {
+                let Some((_, i)) = self.query_uuid(uuid) else {
+                    continue;
+                };
+                for location in &mut self.library[i].location {
+                    if let URI::Local(path) = location {
+                        if normalize_path(path) == file_move.from {
+                            *path = file_move.to.clone();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use uuid::Uuid;
+
+    use super::{
+        copy_verify_delete, sanitize_component, ConflictKind, FileMove, OrganizeError,
+        OrganizeReport, PathTemplate,
+    };
+    use crate::music_storage::{
+        cancel::CancelToken,
+        gain_analysis::AnalyzeScope,
+        library::{MusicLibrary, Song, Tag, URI},
+    };
+
+    fn song(path: &Path, tags: &[(Tag, &str)]) -> Song {
+        Song {
+            location: vec![URI::Local(path.to_path_buf())],
+            uuid: Uuid::new_v4(),
+            tags: tags
+                .iter()
+                .map(|(tag, value)| (tag.clone(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn render_templates() {
+        let template =
+            PathTemplate::parse("{album_artist}/{album}/{disc}-{track} {title}").unwrap();
+        let tagged = song(
+            Path::new("/in/a.flac"),
+            &[
+                (Tag::Artist, "Singer"),
+                (Tag::AlbumArtist, "Band"),
+                (Tag::Album, "First"),
+                (Tag::Track, "3/12"),
+                (Tag::Disk, "2"),
+                (Tag::Title, "Song"),
+            ],
+        );
+        assert_eq!(
+            template.render(&tagged, Path::new("/in/a.flac")),
+            PathBuf::from("Band/First/2-03 Song.flac")
+        );
+
+        // Untagged songs fall back to placeholders and their file name
+        let untagged = song(Path::new("/in/b.mp3"), &[(Tag::Artist, "Singer")]);
+        assert_eq!(
+            template.render(&untagged, Path::new("/in/b.mp3")),
+            PathBuf::from("Singer/Unknown Album/1-00 b.mp3")
+        );
+
+        // Empty parts are skipped, and tags can't add folders
+        let template = PathTemplate::parse("//{artist}//{year} {title}/").unwrap();
+        let slashed = song(
+            Path::new("/in/c.ogg"),
+            &[(Tag::Artist, "AC/DC"), (Tag::Title, "Song")],
+        );
+        assert_eq!(
+            template.render(&slashed, Path::new("/in/c.ogg")),
+            PathBuf::from("AC_DC/0000 Song.ogg")
+        );
+
+        assert!(matches!(
+            PathTemplate::parse("{album}/{nope}"),
+            Err(OrganizeError::UnknownField(field)) if field == "nope"
+        ));
+        assert!(matches!(
+            PathTemplate::parse("{album"),
+            Err(OrganizeError::UnclosedBrace)
+        ));
+        assert!(matches!(
+            PathTemplate::parse("//"),
+            Err(OrganizeError::EmptyPattern)
+        ));
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize_component("What? Now: \"Yes\""), "What_ Now_ _Yes_");
+        assert_eq!(sanitize_component("a<b>c|d*e\\f\tg"), "a_b_c_d_e_f_g");
+        assert_eq!(sanitize_component("Trailing... "), "Trailing");
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component("   "), "_");
+        assert_eq!(sanitize_component("con"), "con_");
+        assert_eq!(sanitize_component("Aux.flac"), "Aux_.flac");
+        assert_eq!(sanitize_component("Console"), "Console");
+
+        let long = "é".repeat(150);
+        let sanitized = sanitize_component(&long);
+        assert!(sanitized.len() <= 200);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn conflict_rules() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let template = PathTemplate::parse("{artist}/{title}").unwrap();
+
+        let paths: Vec<PathBuf> = ["a.flac", "b.flac", "c.flac", "d.flac"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        for path in &paths[..3] {
+            fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+        fs::create_dir_all(root.join("Band")).unwrap();
+        fs::write(root.join("Band/Taken.flac"), []).unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song(&paths[0], &[(Tag::Artist, "Band"), (Tag::Title, "Song")]),
+            song(&paths[1], &[(Tag::Artist, "Band"), (Tag::Title, "Song")]),
+            song(&paths[2], &[(Tag::Artist, "Band"), (Tag::Title, "Taken")]),
+            song(&paths[3], &[(Tag::Artist, "Band"), (Tag::Title, "Gone")]),
+            song(
+                &root.join("Band/Taken.flac"),
+                &[(Tag::Artist, "Band"), (Tag::Title, "Taken")],
+            ),
+        ];
+
+        let plan = library.plan_organize(&template, &root, &AnalyzeScope::WholeLibrary);
+        assert_eq!(
+            plan.moves,
+            [FileMove {
+                from: paths[0].clone(),
+                to: root.join("Band/Song.flac"),
+                songs: vec![library.library[0].uuid],
+            }]
+        );
+        let kinds: Vec<(Uuid, ConflictKind)> =
+            plan.conflicts.iter().map(|c| (c.uuid, c.kind)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (library.library[1].uuid, ConflictKind::DuplicateTarget),
+                (library.library[2].uuid, ConflictKind::TargetExists),
+                (library.library[3].uuid, ConflictKind::Missing),
+            ]
+        );
+        assert_eq!(plan.unchanged, 1);
+
+        // Planning is only a preview
+        assert!(paths[0].exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn apply_and_rewrite_uris() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let journal = dir.join("journal.json");
+        let from = root.join("Unsorted/track.flac");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"audio").unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![song(&from, &[(Tag::Artist, "Band"), (Tag::Title, "Song")])];
+        let uuid = library.library[0].uuid;
+
+        let template = PathTemplate::parse("{artist}/{title}").unwrap();
+        let plan = library.plan_organize(&template, &root, &AnalyzeScope::Songs(vec![uuid]));
+        let report = library
+            .organize_files(plan, &root, &journal, &CancelToken::new())
+            .unwrap();
+
+        let to = root.join("Band/Song.flac");
+        assert_eq!(report.moves.len(), 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read(&to).unwrap(), b"audio");
+        assert!(!from.exists());
+        // The folder left empty is cleaned up
+        assert!(!root.join("Unsorted").exists());
+        assert_eq!(library.library[0].location, [URI::Local(to)]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cancelled_moves_are_left() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let journal = dir.join("journal.json");
+        let from = root.join("Unsorted/track.flac");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"audio").unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![song(&from, &[(Tag::Artist, "Band"), (Tag::Title, "Song")])];
+
+        let template = PathTemplate::parse("{artist}/{title}").unwrap();
+        let plan = library.plan_organize(&template, &root, &AnalyzeScope::WholeLibrary);
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let report = library
+            .organize_files(plan, &root, &journal, &cancel)
+            .unwrap();
+
+        assert!(report.moves.is_empty());
+        assert_eq!(report.failed.len(), 1);
+        assert!(from.exists());
+        assert_eq!(library.library[0].location, [URI::Local(from)]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resume_interrupted() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let journal = dir.join("journal.json");
+        let done_from = dir.join("done.flac");
+        let done_to = root.join("Done.flac");
+        let copied_from = dir.join("copied.flac");
+        let copied_to = root.join("Copied.flac");
+        let pending_from = dir.join("pending.flac");
+        let pending_to = root.join("Pending.flac");
+
+        // One move finished, one copied but not cleaned up, one not started
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&done_to, b"done").unwrap();
+        fs::write(&copied_from, b"copied").unwrap();
+        fs::write(&copied_to, b"copied").unwrap();
+        fs::write(&pending_from, b"pending").unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song(&done_from, &[]),
+            song(&copied_from, &[]),
+            song(&pending_from, &[]),
+        ];
+        let moves: Vec<FileMove> = [
+            (&done_from, &done_to),
+            (&copied_from, &copied_to),
+            (&pending_from, &pending_to),
+        ]
+        .into_iter()
+        .zip(&library.library)
+        .map(|((from, to), song)| FileMove {
+            from: from.clone(),
+            to: to.clone(),
+            songs: vec![song.uuid],
+        })
+        .collect();
+        fs::write(&journal, serde_json::to_vec(&(&root, &moves)).unwrap()).unwrap();
+
+        let report = library.resume_organize(&journal).unwrap().unwrap();
+        assert_eq!(report.moves, moves);
+        assert!(!copied_from.exists() && !pending_from.exists());
+        assert_eq!(fs::read(&pending_to).unwrap(), b"pending");
+        for (song, file_move) in library.library.iter().zip(&moves) {
+            assert_eq!(song.location, [URI::Local(file_move.to.clone())]);
+        }
+
+        fs::remove_file(&journal).unwrap();
+        assert!(library.resume_organize(&journal).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn targets_differing_by_case_conflict() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let template = PathTemplate::parse("{artist}/{title}").unwrap();
+        let (a, b) = (dir.join("a.flac"), dir.join("b.flac"));
+        fs::write(&a, b"a").unwrap();
+        fs::write(&b, b"b").unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![
+            song(&a, &[(Tag::Artist, "Band"), (Tag::Title, "Song")]),
+            song(&b, &[(Tag::Artist, "band"), (Tag::Title, "SONG")]),
+        ];
+
+        // Both would be the same file on a case-insensitive drive
+        let plan = library.plan_organize(&template, &root, &AnalyzeScope::WholeLibrary);
+        assert_eq!(plan.moves.len(), 1);
+        assert_eq!(plan.conflicts[0].uuid, library.library[1].uuid);
+        assert_eq!(plan.conflicts[0].kind, ConflictKind::DuplicateTarget);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn different_target_is_not_replaced() {
+        let dir = temp_dir();
+        let root = dir.join("Music");
+        let journal = dir.join("journal.json");
+        let from = dir.join("song.flac");
+        let to = root.join("Song.flac");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&from, b"ours").unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![song(&from, &[])];
+        let uuid = library.library[0].uuid;
+        let plan = OrganizeReport {
+            moves: vec![FileMove {
+                from: from.clone(),
+                to: to.clone(),
+                songs: vec![uuid],
+            }],
+            ..Default::default()
+        };
+        // Another file ended up at the target after planning
+        fs::write(&to, b"theirs").unwrap();
+
+        let report = library
+            .organize_files(plan, &root, &journal, &CancelToken::new())
+            .unwrap();
+        assert!(report.moves.is_empty());
+        assert_eq!(report.conflicts[0].uuid, uuid);
+        assert_eq!(report.conflicts[0].kind, ConflictKind::TargetExists);
+        assert_eq!(fs::read(&from).unwrap(), b"ours");
+        assert_eq!(fs::read(&to).unwrap(), b"theirs");
+        assert_eq!(library.library[0].location, [URI::Local(from)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copy_when_renaming_fails() {
+        let dir = temp_dir();
+        let from = dir.join("from.flac");
+        let to = dir.join("to.flac");
+        fs::write(&from, b"audio").unwrap();
+
+        copy_verify_delete(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert!(!dir.join("to.flac.part").exists());
+        assert_eq!(fs::read(&to).unwrap(), b"audio");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
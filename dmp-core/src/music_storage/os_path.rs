@@ -0,0 +1,148 @@
+//! Keeping paths exactly as the filesystem has them. Old files can have
+//! names in legacy encodings which aren't valid UTF-8, and serde won't save
+//! those paths at all, so they're saved as their raw bytes instead. Paths
+//! which are valid UTF-8 are still saved as strings, so libraries saved
+//! before read the same.
+//!
+//! Used with `#[serde(with = "os_path")]` on `PathBuf` fields.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserializer, Serialize, Serializer,
+};
+
+/// A path to show, which has had parts replaced if it isn't valid UTF-8
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DisplayPath {
+    pub text: String,
+    /// Whether `text` isn't exactly the path, so it can't be used to find
+    /// the file
+    pub lossy: bool,
+}
+
+impl DisplayPath {
+    pub fn new(path: &Path) -> Self {
+        DisplayPath {
+            text: path.to_string_lossy().into_owned(),
+            lossy: path.to_str().is_none(),
+        }
+    }
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    match path.to_str() {
+        Some(path) => serializer.serialize_str(path),
+        None => serialize_raw(path, serializer),
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    deserializer.deserialize_any(PathVisitor)
+}
+
+#[cfg(not(windows))]
+type Unit = u8;
+/// Windows paths are UTF-16, which can have unpaired surrogates
+#[cfg(windows)]
+type Unit = u16;
+
+#[cfg(not(windows))]
+fn serialize_raw<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    serializer.serialize_bytes(path.as_os_str().as_bytes())
+}
+
+#[cfg(windows)]
+fn serialize_raw<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    use std::os::windows::ffi::OsStrExt;
+    serializer.collect_seq(path.as_os_str().encode_wide())
+}
+
+#[cfg(not(windows))]
+fn from_units(units: Vec<Unit>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(units))
+}
+
+#[cfg(windows)]
+fn from_units(units: Vec<Unit>) -> PathBuf {
+    use std::os::windows::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_wide(&units))
+}
+
+struct PathVisitor;
+
+impl<'de> Visitor<'de> for PathVisitor {
+    type Value = PathBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a path, as a string or its raw bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(v))
+    }
+
+    #[cfg(not(windows))]
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<PathBuf, E> {
+        Ok(from_units(v.to_vec()))
+    }
+
+    /// Formats like JSON have no bytes, so they're written as a list of
+    /// numbers
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+        let mut units = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(unit) = seq.next_element::<Unit>()? {
+            units.push(unit);
+        }
+        Ok(from_units(units))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::DisplayPath;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Saved {
+        #[serde(with = "super")]
+        path: PathBuf,
+    }
+
+    #[test]
+    fn raw_paths_kept() {
+        // "Café" in Latin-1
+        let raw = Saved {
+            path: PathBuf::from(OsStr::from_bytes(b"/music/Caf\xe9.mp3")),
+        };
+        let json = serde_json::to_string(&raw).unwrap();
+        assert_eq!(serde_json::from_str::<Saved>(&json).unwrap(), raw);
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&raw, &mut cbor).unwrap();
+        assert_eq!(ciborium::from_reader::<Saved, _>(&cbor[..]).unwrap(), raw);
+
+        // Paths which are valid UTF-8 are saved as they always were
+        let utf8 = Saved {
+            path: PathBuf::from("/music/Café.mp3"),
+        };
+        let json = serde_json::to_string(&utf8).unwrap();
+        assert_eq!(json, r#"{"path":"/music/Café.mp3"}"#);
+        assert_eq!(serde_json::from_str::<Saved>(&json).unwrap(), utf8);
+
+        let shown = DisplayPath::new(&raw.path);
+        assert_eq!(
+            (shown.text.as_str(), shown.lossy),
+            ("/music/Caf\u{FFFD}.mp3", true)
+        );
+        assert!(!DisplayPath::new(&utf8.path).lossy);
+    }
+}
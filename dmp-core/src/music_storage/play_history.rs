@@ -0,0 +1,208 @@
+//! When each song was played, kept so plays can be looked back on by date,
+//! like the songs first played or played over and over on this day in years
+//! before.
+
+use std::collections::BTreeMap;
+
+use chrono::{serde::ts_milliseconds, DateTime, Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, SongRef};
+
+/// How many times a song has to be played on one day to count as played
+/// heavily on it
+pub const HEAVY_ROTATION_PLAYS: usize = 3;
+
+/// One play of a song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayRecord {
+    pub uuid: Uuid,
+    #[serde(with = "ts_milliseconds")]
+    pub played_at: DateTime<Utc>,
+}
+
+/// Every play counted in the library, oldest first. It's kept for as long
+/// as the library is, as plays are looked back on years later.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayHistory {
+    plays: Vec<PlayRecord>,
+}
+
+impl PlayHistory {
+    pub fn push(&mut self, uuid: Uuid, played_at: DateTime<Utc>) {
+        self.plays.push(PlayRecord { uuid, played_at });
+    }
+
+    pub fn plays(&self) -> &[PlayRecord] {
+        &self.plays
+    }
+}
+
+/// A song played on the day asked for, in one year
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OnThisDaySong {
+    pub song: SongRef,
+    /// How many times it was played that day
+    pub plays: usize,
+    /// Whether it was played for the first time that day
+    pub first_played: bool,
+}
+
+/// The songs first or heavily played on the day asked for in one year
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OnThisDayYear {
+    pub year: i32,
+    /// First played songs first, then the most played
+    pub songs: Vec<OnThisDaySong>,
+}
+
+impl MusicLibrary {
+    /// Counts a play of a song in its history, which is its first play if it
+    /// has none before
+    pub fn record_play(&mut self, uuid: Uuid, played_at: DateTime<Utc>) {
+        let Some((_, i)) = self.query_uuid(&uuid) else {
+            return;
+        };
+        let song = &mut self.library[i];
+        song.first_played = song.first_played.or(Some(played_at));
+        self.play_history.push(uuid, played_at);
+    }
+
+    /// The songs first played, or played at least [`HEAVY_ROTATION_PLAYS`]
+    /// times, on `month`/`day` of the years before `this_year`, newest year
+    /// first. Days are the calendar days of `tz`.
+    pub fn on_this_day<Tz: TimeZone>(
+        &self,
+        month: u32,
+        day: u32,
+        this_year: i32,
+        tz: &Tz,
+    ) -> Vec<OnThisDayYear> {
+        let on_day = |time: &DateTime<Utc>| {
+            let date = time.with_timezone(tz).date_naive();
+            (date.month() == month && date.day() == day && date.year() < this_year)
+                .then_some(date.year())
+        };
+
+        // Plays by year and song, along with whether it was played first
+        let mut years: BTreeMap<i32, BTreeMap<Uuid, (usize, bool)>> = BTreeMap::new();
+        for play in &self.play_history.plays {
+            if let Some(year) = on_day(&play.played_at) {
+                years
+                    .entry(year)
+                    .or_default()
+                    .entry(play.uuid)
+                    .or_default()
+                    .0 += 1;
+            }
+        }
+        for song in &self.library {
+            if let Some(year) = song.first_played.as_ref().and_then(on_day) {
+                years
+                    .entry(year)
+                    .or_default()
+                    .entry(song.uuid)
+                    .or_default()
+                    .1 = true;
+            }
+        }
+
+        years
+            .into_iter()
+            .rev()
+            .filter_map(|(year, played)| {
+                let mut songs: Vec<OnThisDaySong> = played
+                    .into_iter()
+                    .filter(|(_, (plays, first))| *first || *plays >= HEAVY_ROTATION_PLAYS)
+                    .filter_map(|(uuid, (plays, first_played))| {
+                        let (song, _) = self.query_uuid(&uuid)?;
+                        Some(OnThisDaySong {
+                            song: SongRef::from(song),
+                            plays,
+                            first_played,
+                        })
+                    })
+                    .collect();
+                songs.sort_by_key(|song| (!song.first_played, std::cmp::Reverse(song.plays)));
+                (!songs.is_empty()).then_some(OnThisDayYear { year, songs })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use uuid::Uuid;
+
+    use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+
+    fn song(title: &str) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(format!("/music/{title}.flac")))],
+            tags: BTreeMap::from([(Tag::Title, title.to_string())]),
+            ..Default::default()
+        }
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn grouped_by_year() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library = vec![song("Oblivious"), song("Sprinkler"), song("Magia")];
+        let [oblivious, sprinkler, magia] = [0, 1, 2].map(|i| library.library[i].uuid);
+
+        // Oblivious is first played on the 16th of October 2023, and played
+        // over and over on the same day a year later
+        library.record_play(oblivious, at(2023, 10, 16, 20));
+        for hour in [9, 12, 18] {
+            library.record_play(oblivious, at(2024, 10, 16, hour));
+        }
+        // Sprinkler is played only twice that day, after its first play
+        // the day before
+        library.record_play(sprinkler, at(2024, 10, 15, 23));
+        library.record_play(sprinkler, at(2024, 10, 16, 1));
+        library.record_play(sprinkler, at(2024, 10, 16, 2));
+        // Magia is first played on the day in 2022, and on the day this
+        // year, which doesn't count yet
+        library.record_play(magia, at(2022, 10, 16, 8));
+        library.record_play(magia, at(2026, 10, 16, 8));
+        // Songs no longer in the library are left out
+        library
+            .play_history
+            .push(Uuid::new_v4(), at(2021, 10, 16, 8));
+
+        assert_eq!(library.library[0].first_played, Some(at(2023, 10, 16, 20)));
+        assert_eq!(library.play_history.plays().len(), 10);
+
+        let days = library.on_this_day(10, 16, 2026, &Utc);
+        let years: Vec<i32> = days.iter().map(|year| year.year).collect();
+        assert_eq!(years, [2024, 2023, 2022]);
+
+        let songs = |i: usize| -> Vec<(Uuid, usize, bool)> {
+            days[i]
+                .songs
+                .iter()
+                .map(|song| (song.song.uuid, song.plays, song.first_played))
+                .collect()
+        };
+        assert_eq!(songs(0), [(oblivious, 3, false)]);
+        assert_eq!(songs(1), [(oblivious, 1, true)]);
+        assert_eq!(songs(2), [(magia, 1, true)]);
+        assert_eq!(days[1].songs[0].song.title.as_deref(), Some("Oblivious"));
+
+        // The day before has Sprinkler's first play
+        let days = library.on_this_day(10, 15, 2026, &Utc);
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].year, 2024);
+        assert_eq!(days[0].songs[0].song.uuid, sprinkler);
+        assert!(library.on_this_day(1, 1, 2026, &Utc).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Borrow;
 use std::error::Error;
 use std::path::Path;
 use std::{
@@ -7,12 +8,16 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 // use chrono::Duration;
+use super::gain_staging::PlaybackProfile;
 use super::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+use super::search::SearchCandidate;
+use super::utils::canonicalize;
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
 use m3u8_rs::{MediaPlaylist, MediaPlaylistType, MediaSegment, Playlist as List2};
@@ -20,10 +25,99 @@ use nestify::nest;
 
 use rayon::prelude::*;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum SortOrder {
     Manual,
     Tag(Vec<Tag>),
+    /// By when each song was added to the playlist
+    DateAdded {
+        newest_first: bool,
+    },
+}
+
+impl SortOrder {
+    /// Puts a playlist's songs in this order, along with when each one was
+    /// added, leaving them as they are for `Manual`
+    pub fn sort<S: Borrow<Song> + Send>(&self, tracks: &mut [(S, DateTime<Utc>)]) {
+        match self {
+            SortOrder::Manual => (),
+            SortOrder::Tag(sort_by) => {
+                tracks.par_sort_by(|(a, _), (b, _)| a.borrow().cmp_by_tags(b.borrow(), sort_by));
+            }
+            SortOrder::DateAdded {
+                newest_first: false,
+            } => {
+                tracks.par_sort_by_key(|(_, added)| *added);
+            }
+            SortOrder::DateAdded { newest_first: true } => {
+                tracks.par_sort_by_key(|(_, added)| std::cmp::Reverse(*added));
+            }
+        }
+    }
+}
+
+/// Tracks of playlists saved before the date was kept are read with this,
+/// until they're given the time of the file they were read from
+const UNDATED: DateTime<Utc> = DateTime::UNIX_EPOCH;
+
+/// A song in a playlist, and when it was added to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PlaylistTrack {
+    pub uuid: Uuid,
+    #[serde(with = "ts_milliseconds")]
+    pub added: DateTime<Utc>,
+}
+
+impl PlaylistTrack {
+    pub fn new(uuid: Uuid) -> Self {
+        PlaylistTrack {
+            uuid,
+            added: Utc::now(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PlaylistTrack {
+    /// Reads a track, which older versions stored as only its [`Uuid`]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Track {
+                uuid: Uuid,
+                #[serde(with = "ts_milliseconds")]
+                added: DateTime<Utc>,
+            },
+            Legacy(Uuid),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Track { uuid, added } => PlaylistTrack { uuid, added },
+            Repr::Legacy(uuid) => PlaylistTrack {
+                uuid,
+                added: UNDATED,
+            },
+        })
+    }
+}
+
+/// A track of an imported playlist whose file couldn't be found, kept so it
+/// can be pointed at the right song later rather than vanishing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MissingTrack {
+    /// How many of the playlist's tracks come before it
+    pub position: usize,
+    pub original_path: PathBuf,
+    /// The title the playlist file gave it, or its file name without one
+    pub display: String,
+}
+
+/// What to replace a missing track with
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum MissingResolution {
+    Song(Uuid),
+    /// A file, which is added to the library if it isn't in it yet
+    Path(#[serde(with = "super::os_path")] PathBuf),
 }
 
 nest! {
@@ -44,7 +138,11 @@ impl PlaylistFolder {
     pub fn query_uuid(&self, uuid: &Uuid) -> Option<&Playlist> {
         for item in &self.items {
             match item {
-                PlaylistFolderItem::Folder(folder) => return folder.query_uuid(uuid),
+                PlaylistFolderItem::Folder(folder) => {
+                    if let Some(playlist) = folder.query_uuid(uuid) {
+                        return Some(playlist);
+                    }
+                }
                 PlaylistFolderItem::List(ref playlist) => {
                     if &playlist.uuid == uuid {
                         return Some(playlist);
@@ -55,6 +153,24 @@ impl PlaylistFolder {
         None
     }
 
+    pub fn query_uuid_mut(&mut self, uuid: &Uuid) -> Option<&mut Playlist> {
+        for item in &mut self.items {
+            match item {
+                PlaylistFolderItem::Folder(folder) => {
+                    if let Some(playlist) = folder.query_uuid_mut(uuid) {
+                        return Some(playlist);
+                    }
+                }
+                PlaylistFolderItem::List(ref mut playlist) => {
+                    if &playlist.uuid == uuid {
+                        return Some(playlist);
+                    }
+                }
+            }
+        }
+        None
+    }
+
     pub fn lists_recursive(&self) -> Vec<&Playlist> {
         let mut vec = vec![];
         for item in &self.items {
@@ -65,6 +181,27 @@ impl PlaylistFolder {
         }
         vec
     }
+
+    /// Calls `f` on every playlist, including the ones in folders
+    pub(crate) fn for_each_list_mut(&mut self, f: &mut impl FnMut(&mut Playlist)) {
+        for item in &mut self.items {
+            match item {
+                PlaylistFolderItem::Folder(folder) => folder.for_each_list_mut(f),
+                PlaylistFolderItem::List(playlist) => f(playlist),
+            }
+        }
+    }
+
+    /// Dates the tracks of every playlist saved before the date they were
+    /// added was kept, with the time their file was last modified
+    pub(crate) fn date_legacy_tracks(&mut self, modified: DateTime<Utc>) {
+        for item in &mut self.items {
+            match item {
+                PlaylistFolderItem::Folder(folder) => folder.date_legacy_tracks(modified),
+                PlaylistFolderItem::List(playlist) => playlist.date_legacy_tracks(modified),
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -72,10 +209,20 @@ pub struct Playlist {
     pub(crate) uuid: Uuid,
     pub(crate) title: String,
     pub(crate) cover: Option<AlbumArt>,
-    pub(crate) tracks: Vec<Uuid>,
+    pub(crate) tracks: Vec<PlaylistTrack>,
     pub(crate) sort_order: SortOrder,
     pub(crate) play_count: i32,
     pub(crate) play_time: Duration,
+    /// Whether this playlist is managed by the auto playlist generator
+    #[serde(default)]
+    pub(crate) auto_generated: bool,
+    /// Playback settings used while playing from this playlist
+    #[serde(default)]
+    pub(crate) profile: Option<PlaybackProfile>,
+    /// Tracks which couldn't be found when the playlist was imported, in
+    /// order. They're shown in the playlist but never played.
+    #[serde(default)]
+    pub(crate) missing: Vec<MissingTrack>,
 }
 
 impl Playlist {
@@ -93,6 +240,10 @@ impl Playlist {
         &self.title
     }
 
+    pub fn auto_generated(&self) -> bool {
+        self.auto_generated
+    }
+
     pub fn cover(&self) -> Option<&AlbumArt> {
         match &self.cover {
             Some(e) => Some(e),
@@ -100,29 +251,118 @@ impl Playlist {
         }
     }
 
+    pub fn sort_order(&self) -> &SortOrder {
+        &self.sort_order
+    }
+
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    pub fn profile(&self) -> Option<PlaybackProfile> {
+        self.profile
+    }
+
+    /// Sets the playback settings for this playlist, where an empty profile
+    /// is the same as none
+    pub fn set_profile(&mut self, profile: Option<PlaybackProfile>) {
+        self.profile = profile.filter(|profile| !profile.is_empty());
+    }
+
     pub fn tracks(&self) -> Vec<Uuid> {
-        self.tracks.to_owned()
+        self.tracks.iter().map(|track| track.uuid).collect()
+    }
+
+    /// The tracks along with when each one was added
+    pub fn dated_tracks(&self) -> &[PlaylistTrack] {
+        &self.tracks
     }
 
+    /// Replaces the tracks of the playlist. Songs which were already in it
+    /// keep the date they were added, and the rest are added now.
     pub fn set_tracks(&mut self, tracks: Vec<Uuid>) {
-        self.tracks = tracks;
+        let now = Utc::now();
+        self.tracks = tracks
+            .into_iter()
+            .map(|uuid| {
+                let added = self
+                    .tracks
+                    .iter()
+                    .find(|track| track.uuid == uuid)
+                    .map_or(now, |track| track.added);
+                PlaylistTrack { uuid, added }
+            })
+            .collect();
     }
 
     pub fn add_track(&mut self, track: Uuid) {
-        self.tracks.push(track);
+        self.tracks.push(PlaylistTrack::new(track));
+    }
+
+    /// Adds songs to the end of the playlist, all at the same time
+    pub fn add_tracks(&mut self, tracks: impl IntoIterator<Item = Uuid>) {
+        let added = Utc::now();
+        self.tracks
+            .extend(tracks.into_iter().map(|uuid| PlaylistTrack { uuid, added }));
+    }
+
+    /// Keeps only the tracks whose Uuid matches `keep`
+    pub fn retain_tracks(&mut self, mut keep: impl FnMut(&Uuid) -> bool) {
+        let kept: Vec<bool> = self.tracks.iter().map(|track| keep(&track.uuid)).collect();
+        for missing in &mut self.missing {
+            missing.position = kept[..missing.position.min(kept.len())]
+                .iter()
+                .filter(|kept| **kept)
+                .count();
+        }
+        let mut kept = kept.into_iter();
+        self.tracks.retain(|_| kept.next().unwrap_or(true));
+    }
+
+    pub(crate) fn date_legacy_tracks(&mut self, modified: DateTime<Utc>) {
+        for track in &mut self.tracks {
+            if track.added == UNDATED {
+                track.added = modified;
+            }
+        }
     }
 
     pub fn remove_track(&mut self, index: i32) {
         let index = index as usize;
         if (self.tracks.len() - 1) >= index {
             self.tracks.remove(index);
+            for missing in &mut self.missing {
+                if missing.position > index {
+                    missing.position -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn missing_tracks(&self) -> &[MissingTrack] {
+        &self.missing
+    }
+
+    /// Puts `uuid` where the missing track at `index` was. Returns `false`
+    /// if there's no missing track at `index`.
+    pub fn resolve_missing(&mut self, index: usize, uuid: Uuid) -> bool {
+        if index >= self.missing.len() {
+            return false;
+        }
+        let missing = self.missing.remove(index);
+        let position = missing.position.min(self.tracks.len());
+        self.tracks.insert(position, PlaylistTrack::new(uuid));
+        // Everything after it now comes after one more track
+        for later in &mut self.missing[index..] {
+            later.position += 1;
         }
+        true
     }
     pub fn get_index(&self, uuid: Uuid) -> Option<usize> {
         let mut i = 0;
-        if self.tracks.contains(&uuid) {
+        if self.tracks.iter().any(|track| track.uuid == uuid) {
             for track in &self.tracks {
-                if &uuid == track {
+                if uuid == track.uuid {
                     dbg!("Index gotted! ", i);
                     return Some(i);
                 }
@@ -141,36 +381,43 @@ impl Playlist {
     }
 
     pub fn from_file(path: &str) -> Result<Playlist, Box<dyn Error>> {
-        super::utils::read_file(PathBuf::from(path))
+        let mut playlist: Playlist = super::utils::read_file(PathBuf::from(path))?;
+        playlist.date_legacy_tracks(modified_time(Path::new(path))?);
+        Ok(playlist)
     }
 
+    /// Writes the playlist out as an m3u file. Songs whose file is missing
+    /// are left out, as are ones whose path isn't valid UTF-8, which m3u
+    /// files can't hold.
     pub fn to_m3u(
         &mut self,
         lib: Arc<RwLock<MusicLibrary>>,
-        location: &str,
+        location: impl AsRef<Path>,
     ) -> Result<(), Box<dyn Error>> {
         let lib = lib.read().unwrap();
         let seg = self
             .tracks
             .iter()
-            .filter_map(|uuid| {
-                // TODO: The Unwraps need to be handled here
-                if let Some((track, _)) = lib.query_uuid(uuid) {
-                    if let URI::Local(_) = track.primary_uri().unwrap().0 {
-                        Some(MediaSegment {
-                            uri: track.primary_uri().unwrap().0.to_string(),
-                            duration: track.duration.as_millis() as f32,
-                            title: track
-                                .tags
-                                .get_key_value(&Tag::Title)
-                                .map(|tag| tag.1.into()),
-                            ..Default::default()
-                        })
-                    } else {
+            .filter_map(|PlaylistTrack { uuid, .. }| {
+                let (track, _) = lib.query_uuid(uuid)?;
+                match track.primary_uri() {
+                    Ok((URI::Local(path), _)) if path.to_str().is_none() => {
+                        eprintln!(
+                            "Left {} out of the playlist, since its path isn't valid UTF-8",
+                            path.display()
+                        );
                         None
                     }
-                } else {
-                    None
+                    Ok((uri @ URI::Local(_), _)) => Some(MediaSegment {
+                        uri: uri.to_string(),
+                        duration: track.duration.as_millis() as f32,
+                        title: track
+                            .tags
+                            .get_key_value(&Tag::Title)
+                            .map(|tag| tag.1.into()),
+                        ..Default::default()
+                    }),
+                    _ => None,
                 }
             })
             .collect::<Vec<MediaSegment>>();
@@ -191,7 +438,7 @@ impl Playlist {
             .create(true)
             .truncate(true)
             .write(true)
-            .open(location)?;
+            .open(location.as_ref())?;
         m3u.write_to(&mut file)?;
         Ok(())
     }
@@ -216,124 +463,99 @@ impl Playlist {
                 Err("This is a Master Playlist!\nPlase input a Media Playlist".into())
             }
             List2::MediaPlaylist(playlist_) => {
-                let mut uuids = Vec::new();
-                for seg in playlist_.segments {
-                    let seg_path = seg.uri.to_owned();
-
-                    let song_path = if let Ok(path) = PathBuf::from(&seg_path).canonicalize() {
-                        path
-                    } else {
-                        println!("{seg_path}");
-                        continue;
-                    };
-
-                    let uuid =
-                        if let Some((song, _)) = lib.query_uri(&URI::Local(song_path.clone())) {
-                            song.uuid
-                        } else {
-                            let song_: Song = match Song::from_file(&song_path) {
-                                Ok(s) => s,
-                                Err(e) => panic!("{e}\npath: {}", song_path.display()),
-                            };
-                            let uuid = song_.uuid.to_owned();
-                            _ = lib.add_song(song_); // TODO: Add proper error handling with Library
-                            uuid
-                        };
-                    uuids.push(uuid);
-                }
-                let mut playlist = Playlist::new();
-
-                let path: &str = m3u_path.as_ref().to_str().unwrap();
-
-                #[cfg(target_family = "windows")]
-                {
-                    playlist.title = path
-                        .split('\\')
-                        .last()
-                        .unwrap_or_default()
-                        .strip_suffix(".m3u")
-                        .unwrap_or_default()
-                        .to_string();
-                }
-                #[cfg(target_family = "unix")]
-                {
-                    playlist.title = path
-                        .split("/")
-                        .last()
-                        .unwrap_or_default()
-                        .strip_suffix(".m3u")
-                        .unwrap_or_default()
-                        .to_string();
-                }
+                let mut playlist = Playlist::from_segments(playlist_.segments, lib);
+
+                let name = m3u_path
+                    .as_ref()
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                playlist.title = name.strip_suffix(".m3u").unwrap_or_default().to_string();
 
-                playlist.set_tracks(uuids);
                 Ok(playlist)
             }
         }
     }
 
+    /// A playlist of the songs in an m3u file, adding the ones which aren't
+    /// in the library yet. Files which can't be found are kept as missing
+    /// tracks, named by their `#EXTINF` title.
+    fn from_segments(segments: Vec<MediaSegment>, lib: &mut MusicLibrary) -> Playlist {
+        let mut uuids = Vec::new();
+        let mut missing = Vec::new();
+        for seg in segments {
+            let song_path = match canonicalize(Path::new(&seg.uri)) {
+                Ok(path) => path,
+                Err(_) => {
+                    let original_path = PathBuf::from(&seg.uri);
+                    let display = seg
+                        .title
+                        .filter(|title| !title.trim().is_empty())
+                        .or_else(|| {
+                            original_path
+                                .file_stem()
+                                .map(|stem| stem.to_string_lossy().into_owned())
+                        })
+                        .unwrap_or_else(|| seg.uri.clone());
+                    missing.push(MissingTrack {
+                        position: uuids.len(),
+                        original_path,
+                        display,
+                    });
+                    continue;
+                }
+            };
+
+            let uuid = if let Some((song, _)) = lib.query_uri(&URI::Local(song_path.clone())) {
+                song.uuid
+            } else {
+                let song_: Song = match Song::from_file(&song_path) {
+                    Ok(s) => s,
+                    Err(e) => panic!("{e}\npath: {}", song_path.display()),
+                };
+                let uuid = song_.uuid.to_owned();
+                _ = lib.add_song(song_); // TODO: Add proper error handling with Library
+                uuid
+            };
+            uuids.push(uuid);
+        }
+
+        let mut playlist = Playlist::new();
+        playlist.set_tracks(uuids);
+        playlist.missing = missing;
+        playlist
+    }
+
     pub fn out_tracks(&self, lib: Arc<RwLock<MusicLibrary>>) -> (Vec<Song>, Vec<&Uuid>) {
         let lib = lib.read().unwrap();
         let mut songs = vec![];
         let mut invalid_uuids = vec![];
 
-        for uuid in &self.tracks {
+        for PlaylistTrack { uuid, added } in &self.tracks {
             if let Some((track, _)) = lib.query_uuid(uuid) {
-                songs.push(track.to_owned());
+                songs.push((track.to_owned(), *added));
             } else {
                 invalid_uuids.push(uuid);
             }
         }
 
-        if let SortOrder::Tag(sort_by) = &self.sort_order {
-            println!("sorting by: {:?}", sort_by);
-
-            songs.par_sort_by(|a, b| {
-                for (i, sort_option) in sort_by.iter().enumerate() {
-                    dbg!(&i);
-                    let tag_a = match sort_option {
-                        Tag::Field(field_selection) => {
-                            match a.get_field(field_selection.as_str()) {
-                                Some(field_value) => field_value.to_string(),
-                                None => continue,
-                            }
-                        }
-                        _ => match a.get_tag(sort_option) {
-                            Some(tag_value) => tag_value.to_owned(),
-                            None => continue,
-                        },
-                    };
-
-                    let tag_b = match sort_option {
-                        Tag::Field(field_selection) => match b.get_field(field_selection) {
-                            Some(field_value) => field_value.to_string(),
-                            None => continue,
-                        },
-                        _ => match b.get_tag(sort_option) {
-                            Some(tag_value) => tag_value.to_owned(),
-                            None => continue,
-                        },
-                    };
-                    dbg!(&i);
-
-                    if let (Ok(num_a), Ok(num_b)) = (tag_a.parse::<i32>(), tag_b.parse::<i32>()) {
-                        // If parsing succeeds, compare as numbers
-                        return dbg!(num_a.cmp(&num_b));
-                    } else {
-                        // If parsing fails, compare as strings
-                        return dbg!(tag_a.cmp(&tag_b));
-                    }
-                }
-
-                // If all tags are equal, sort by Track number
-                let path_a = PathBuf::from(a.get_field("location").unwrap().to_string());
-                let path_b = PathBuf::from(b.get_field("location").unwrap().to_string());
+        self.sort_order.sort(&mut songs);
 
-                path_a.file_name().cmp(&path_b.file_name())
-            })
-        }
+        (
+            songs.into_iter().map(|(song, _)| song).collect(),
+            invalid_uuids,
+        )
+    }
 
-        (songs, invalid_uuids)
+    /// The uuids of the songs in the library, in the playlist's sort order
+    pub fn sorted_uuids(&self, library: &MusicLibrary) -> Vec<Uuid> {
+        let mut tracks = self
+            .tracks
+            .iter()
+            .filter_map(|track| Some((library.query_uuid(&track.uuid)?.0, track.added)))
+            .collect_vec();
+        self.sort_order.sort(&mut tracks);
+        tracks.into_iter().map(|(song, _)| song.uuid).collect()
     }
 }
 
@@ -347,6 +569,9 @@ impl Default for Playlist {
             sort_order: SortOrder::Manual,
             play_count: 0,
             play_time: Duration::from_secs(0),
+            auto_generated: false,
+            profile: None,
+            missing: Vec::new(),
         }
     }
 }
@@ -356,26 +581,41 @@ pub struct ExternalPlaylist {
     pub uuid: Uuid,
     pub title: String,
     pub tracks: Vec<Song>,
+    /// When each of `tracks` was added, at the same index
+    pub added: Vec<DateTime<Utc>>,
     pub sort_order: SortOrder,
     pub play_count: i32,
     pub play_time: Duration,
+    pub auto_generated: bool,
+    pub profile: Option<PlaybackProfile>,
+    #[serde(default)]
+    pub missing: Vec<MissingTrack>,
 }
 
 impl ExternalPlaylist {
-    pub(crate) fn from_playlist(playlist: &Playlist, library: &MusicLibrary) -> Self {
-        let tracks: Vec<Song> = playlist
+    pub fn from_playlist(playlist: &Playlist, library: &MusicLibrary) -> Self {
+        let mut tracks = playlist
             .tracks
             .iter()
-            .filter_map(|uuid| library.query_uuid(uuid).map(|res| res.0.clone()))
+            .filter_map(|track| {
+                let (song, _) = library.query_uuid(&track.uuid)?;
+                Some((song.clone(), track.added))
+            })
             .collect_vec();
+        playlist.sort_order.sort(&mut tracks);
+        let (tracks, added) = tracks.into_iter().unzip();
 
         Self {
             uuid: playlist.uuid,
             title: playlist.title.clone(),
             tracks,
+            added,
             sort_order: playlist.sort_order.clone(),
             play_count: playlist.play_count,
             play_time: playlist.play_time,
+            auto_generated: playlist.auto_generated,
+            profile: playlist.profile,
+            missing: playlist.missing.clone(),
         }
     }
 
@@ -401,6 +641,65 @@ impl ExternalPlaylist {
     }
 }
 
+impl MusicLibrary {
+    /// Replaces the missing track at `index` of a playlist with a song,
+    /// returning the song's Uuid
+    pub fn resolve_missing_track(
+        &mut self,
+        playlist: &Uuid,
+        index: usize,
+        to: MissingResolution,
+    ) -> Result<Uuid, Box<dyn Error>> {
+        let uuid = match to {
+            MissingResolution::Song(uuid) => {
+                self.query_uuid(&uuid)
+                    .ok_or("The song isn't in the library")?;
+                uuid
+            }
+            MissingResolution::Path(path) => {
+                let uri = URI::Local(canonicalize(&path)?);
+                if self.query_uri(&uri).is_none() {
+                    self.add_song(Song::from_file(&path)?)?;
+                }
+                // Adding it may have given it back a Uuid it had before
+                self.query_uri(&uri)
+                    .ok_or("The song couldn't be added")?
+                    .0
+                    .uuid
+            }
+        };
+
+        let playlist = self
+            .playlists
+            .query_uuid_mut(playlist)
+            .ok_or("The playlist doesn't exist")?;
+        if !playlist.resolve_missing(index, uuid) {
+            return Err(format!("There's no missing track at {index}").into());
+        }
+        Ok(uuid)
+    }
+
+    /// Songs in the library which could be the missing track at `index` of
+    /// a playlist, best first
+    pub fn missing_track_matches(&self, playlist: &Uuid, index: usize) -> Vec<SearchCandidate> {
+        let Some(missing) = self
+            .playlists
+            .query_uuid(playlist)
+            .and_then(|playlist| playlist.missing.get(index))
+        else {
+            return Vec::new();
+        };
+        self.search_candidates(&missing.display)
+    }
+}
+
+/// When a file was last modified, which is as close as playlists saved
+/// before dates were kept can get to when their tracks were added
+pub(crate) fn modified_time(path: &Path) -> std::io::Result<DateTime<Utc>> {
+    let modified: SystemTime = std::fs::metadata(path)?.modified()?;
+    Ok(modified.into())
+}
+
 #[cfg(test)]
 mod test_super {
     use super::*;
@@ -431,6 +730,269 @@ mod test_super {
         dbg!(&playlist, playlist.tracks.len());
     }
 
+    fn sorted_library() -> (MusicLibrary, Playlist) {
+        let mut lib = MusicLibrary::init(
+            std::env::temp_dir().join(format!("{}.dlib", Uuid::new_v4())),
+            Uuid::new_v4(),
+        )
+        .unwrap();
+        // Songs can only be added if their files exist
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+
+        let mut playlist = Playlist::new();
+        for (track, title) in [("3", "C"), ("1", "A"), ("10", "D"), ("2", "B")] {
+            let path = dir.join(format!("{title}.flac"));
+            File::create(&path).unwrap();
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                location: vec![URI::Local(path)],
+                ..Default::default()
+            };
+            song.set_tag(Tag::Track, track.to_string());
+            song.set_tag(Tag::Title, title.to_string());
+            playlist.add_track(song.uuid);
+            lib.add_song(song).unwrap();
+        }
+        (lib, playlist)
+    }
+
+    fn titles(songs: &[Song]) -> Vec<&str> {
+        songs
+            .iter()
+            .map(|song| song.get_tag(&Tag::Title).unwrap().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn external_playlist_sort() {
+        let (lib, mut playlist) = sorted_library();
+
+        let list = ExternalPlaylist::from_playlist(&playlist, &lib);
+        assert_eq!(titles(&list.tracks), ["C", "A", "D", "B"]);
+
+        // Track numbers are compared as numbers, not strings
+        playlist.set_sort_order(SortOrder::Tag(vec![Tag::Track]));
+        let list = ExternalPlaylist::from_playlist(&playlist, &lib);
+        assert_eq!(titles(&list.tracks), ["A", "B", "C", "D"]);
+
+        let (songs, invalid) = playlist.out_tracks(Arc::new(RwLock::new(lib)));
+        assert_eq!(titles(&songs), ["A", "B", "C", "D"]);
+        assert!(invalid.is_empty());
+    }
+
+    #[test]
+    fn up_next_follows_sort() {
+        let (lib, mut playlist) = sorted_library();
+        playlist.set_sort_order(SortOrder::Tag(vec![Tag::Track]));
+
+        // Playing a song from the playlist queues the ones after it
+        let list = ExternalPlaylist::from_playlist(&playlist, &lib);
+        let playing = lib
+            .library
+            .iter()
+            .find(|song| song.get_tag(&Tag::Title).unwrap() == "B");
+        let index = list.get_index(playing.unwrap().uuid).unwrap();
+        assert_eq!(titles(&list.tracks[index + 1..]), ["C", "D"]);
+    }
+
+    #[test]
+    fn sort_by_date_added() {
+        let (lib, mut playlist) = sorted_library();
+        let day = chrono::TimeDelta::days(1);
+        let start = Utc::now() - day * 10;
+        // Added in the order D, B, C, A
+        for (track, days) in playlist.tracks.iter_mut().zip([2, 3, 0, 1]) {
+            track.added = start + day * days;
+        }
+
+        playlist.set_sort_order(SortOrder::DateAdded {
+            newest_first: false,
+        });
+        let list = ExternalPlaylist::from_playlist(&playlist, &lib);
+        assert_eq!(titles(&list.tracks), ["D", "B", "C", "A"]);
+        assert!(list.added.is_sorted());
+
+        playlist.set_sort_order(SortOrder::DateAdded { newest_first: true });
+        let list = ExternalPlaylist::from_playlist(&playlist, &lib);
+        assert_eq!(titles(&list.tracks), ["A", "C", "B", "D"]);
+        assert_eq!(list.added[0], start + day * 3);
+    }
+
+    #[test]
+    fn set_tracks_keeps_dates() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut playlist = Playlist::new();
+        playlist.add_tracks([a]);
+        let added = Utc::now() - chrono::TimeDelta::days(30);
+        playlist.tracks[0].added = added;
+
+        playlist.set_tracks(vec![b, a]);
+        assert_eq!(playlist.tracks(), [b, a]);
+        assert_eq!(playlist.dated_tracks()[1].added, added);
+        assert!(playlist.dated_tracks()[0].added > added);
+    }
+
+    #[test]
+    fn dated_tracks_round_trip() {
+        let mut playlist = Playlist::new();
+        playlist.add_tracks([Uuid::new_v4(), Uuid::new_v4()]);
+        let path = std::env::temp_dir().join(format!("{}.dlist", Uuid::new_v4()));
+        playlist.to_file(path.to_str().unwrap()).unwrap();
+
+        let read = Playlist::from_file(path.to_str().unwrap()).unwrap();
+        // Dates are kept to the millisecond
+        let millis = |list: &Playlist| {
+            list.dated_tracks()
+                .iter()
+                .map(|track| (track.uuid, track.added.timestamp_millis()))
+                .collect_vec()
+        };
+        assert_eq!(millis(&read), millis(&playlist));
+    }
+
+    #[test]
+    fn legacy_tracks_dated_by_file() {
+        // How playlists were saved when only the uuids of tracks were kept
+        #[derive(Serialize)]
+        struct LegacyPlaylist {
+            uuid: Uuid,
+            title: String,
+            cover: Option<AlbumArt>,
+            tracks: Vec<Uuid>,
+            sort_order: SortOrder,
+            play_count: i32,
+            play_time: Duration,
+        }
+
+        let tracks = vec![Uuid::new_v4(), Uuid::new_v4()];
+        let legacy = LegacyPlaylist {
+            uuid: Uuid::new_v4(),
+            title: "Old".to_string(),
+            cover: None,
+            tracks: tracks.clone(),
+            sort_order: SortOrder::Manual,
+            play_count: 2,
+            play_time: Duration::from_secs(60),
+        };
+        let path = std::env::temp_dir().join(format!("{}.dlist", Uuid::new_v4()));
+        ciborium::into_writer(&legacy, File::create(&path).unwrap()).unwrap();
+        let modified = modified_time(&path).unwrap();
+
+        let playlist = Playlist::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(playlist.tracks(), tracks);
+        assert!(playlist
+            .dated_tracks()
+            .iter()
+            .all(|track| track.added == modified));
+        assert_eq!(playlist.play_count(), 2);
+    }
+
+    #[test]
+    fn m3u_leaves_out_dates() {
+        let (mut lib, playlist) = sorted_library();
+        let path = std::env::temp_dir().join(format!("{}.m3u", Uuid::new_v4()));
+        let shared = Arc::new(RwLock::new(lib.clone()));
+        playlist
+            .clone()
+            .to_m3u(shared, path.to_str().unwrap())
+            .unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("added"));
+
+        // Imported tracks count as added when they're imported
+        let before = Utc::now();
+        let imported = Playlist::from_m3u(&path, &mut lib).unwrap();
+        assert_eq!(imported.tracks(), playlist.tracks());
+        assert!(imported
+            .dated_tracks()
+            .iter()
+            .all(|track| track.added >= before));
+    }
+
+    #[test]
+    fn missing_tracks_kept_and_resolved() {
+        let (mut lib, _) = sorted_library();
+        let path = |lib: &MusicLibrary, title: &str| {
+            let song = lib
+                .library
+                .iter()
+                .find(|song| song.get_tag(&Tag::Title).unwrap() == title)
+                .unwrap();
+            (song.uuid, song.location[0].path())
+        };
+        let (a, c) = (path(&lib, "A"), path(&lib, "C"));
+        let segment = |uri: &Path, title: Option<&str>| MediaSegment {
+            uri: uri.to_string_lossy().into_owned(),
+            title: title.map(str::to_string),
+            ..Default::default()
+        };
+
+        let gone = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let segments = vec![
+            segment(&a.1, Some("A")),
+            segment(&gone.join("01.mp3"), Some("Kalafina - Lacrimosa")),
+            segment(&c.1, None),
+            segment(&gone.join("Hikaru.flac"), None),
+        ];
+        let playlist = Playlist::from_segments(segments, &mut lib);
+        assert_eq!(playlist.tracks(), [a.0, c.0]);
+        let displays = |playlist: &Playlist| {
+            playlist
+                .missing_tracks()
+                .iter()
+                .map(|missing| (missing.position, missing.display.clone()))
+                .collect_vec()
+        };
+        assert_eq!(
+            displays(&playlist),
+            [
+                (1, "Kalafina - Lacrimosa".to_string()),
+                (2, "Hikaru".to_string())
+            ]
+        );
+        assert_eq!(
+            playlist.missing_tracks()[0].original_path,
+            gone.join("01.mp3")
+        );
+        let uuid = playlist.uuid;
+        lib.playlists.items.push(PlaylistFolderItem::List(playlist));
+
+        // The same song, found elsewhere
+        let mut lacrimosa = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from("/music/Lacrimosa.flac"))],
+            ..Default::default()
+        };
+        lacrimosa.set_tag(Tag::Title, "Lacrimosa".to_string());
+        lacrimosa.set_tag(Tag::Artist, "Kalafina".to_string());
+        let lacrimosa = {
+            let uuid = lacrimosa.uuid;
+            lib.library.push(lacrimosa);
+            uuid
+        };
+
+        let matches = lib.missing_track_matches(&uuid, 0);
+        assert_eq!(matches[0].uuid, lacrimosa);
+        assert!(lib.missing_track_matches(&uuid, 5).is_empty());
+
+        let resolved =
+            lib.resolve_missing_track(&uuid, 0, MissingResolution::Song(matches[0].uuid));
+        assert_eq!(resolved.unwrap(), lacrimosa);
+        let playlist = lib.playlists.query_uuid_mut(&uuid).unwrap();
+        assert_eq!(playlist.tracks(), [a.0, lacrimosa, c.0]);
+        assert_eq!(displays(playlist), [(3, "Hikaru".to_string())]);
+
+        playlist.remove_track(0);
+        assert_eq!(displays(playlist), [(2, "Hikaru".to_string())]);
+        playlist.retain_tracks(|track| *track != c.0);
+        assert_eq!(displays(playlist), [(1, "Hikaru".to_string())]);
+
+        let unknown = MissingResolution::Song(Uuid::new_v4());
+        assert!(lib.resolve_missing_track(&uuid, 0, unknown).is_err());
+        let out_of_range = MissingResolution::Song(lacrimosa);
+        assert!(lib.resolve_missing_track(&uuid, 1, out_of_range).is_err());
+    }
+
     // #[test]
     // fn out_queue_sort() {
     //     let (_, lib) = read_config_lib();
@@ -0,0 +1,115 @@
+//! Internet radio stations kept in the library. A station is a song whose
+//! location is the stream's URL, tagged as a [`RADIO`] song so it can be
+//! told apart from other remote songs.
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use super::library::{InternalTag, MusicLibrary, Service, Song, SongType, Tag, URI};
+
+/// The custom [`SongType`] of internet radio stations
+pub const RADIO: &str = "radio";
+
+impl Song {
+    /// Whether this song is an internet radio station
+    pub fn is_radio_station(&self) -> bool {
+        self.internal_tags
+            .contains(&InternalTag::SongType(SongType::Custom(RADIO.to_string())))
+    }
+}
+
+impl MusicLibrary {
+    /// Adds an internet radio station streaming from `url`, returning the
+    /// uuid of its song
+    pub fn add_radio_station(
+        &mut self,
+        name: &str,
+        url: &str,
+        genre: Option<&str>,
+    ) -> Result<Uuid, String> {
+        let name = name.trim();
+        let url = url.trim();
+        if name.is_empty() {
+            return Err("A station needs a name".to_string());
+        }
+        match url.split_once("://") {
+            Some((scheme, _))
+                if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") => {}
+            _ => return Err(format!("'{url}' isn't an HTTP stream")),
+        }
+
+        let mut song = Song {
+            location: vec![URI::Remote(Service::InternetRadio, url.to_string())],
+            uuid: Uuid::new_v4(),
+            date_added: Some(Utc::now()),
+            internal_tags: vec![InternalTag::SongType(SongType::Custom(RADIO.to_string()))],
+            ..Default::default()
+        };
+        song.set_tag(Tag::Title, name.to_string());
+        if let Some(genre) = genre.map(str::trim).filter(|genre| !genre.is_empty()) {
+            song.set_tag(Tag::Genre, genre.to_string());
+        }
+
+        let uuid = song.uuid;
+        self.add_song(song).map_err(|e| e.to_string())?;
+        Ok(uuid)
+    }
+
+    /// Every radio station in the library, by name
+    pub fn radio_stations(&self) -> Vec<&Song> {
+        let mut stations: Vec<&Song> = self
+            .library
+            .iter()
+            .filter(|song| song.is_radio_station())
+            .collect();
+        stations
+            .sort_by_cached_key(|song| song.get_tag(&Tag::Title).map(|title| title.to_lowercase()));
+        stations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::music_storage::library::{MusicLibrary, Service, Tag, URI};
+
+    #[test]
+    fn radio_stations() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let jazz = lib
+            .add_radio_station("Jazz FM", "https://jazz.example/live", Some("Jazz"))
+            .unwrap();
+        let anime = lib
+            .add_radio_station(" anime radio ", "http://anime.example:8000/stream", None)
+            .unwrap();
+
+        let stations: Vec<Uuid> = lib.radio_stations().iter().map(|s| s.uuid).collect();
+        assert_eq!(stations, [anime, jazz]);
+
+        let (station, _) = lib.query_uuid(&jazz).unwrap();
+        assert_eq!(
+            station.location,
+            [URI::Remote(
+                Service::InternetRadio,
+                "https://jazz.example/live".to_string()
+            )]
+        );
+        assert_eq!(station.get_tag(&Tag::Genre).unwrap(), "Jazz");
+        let (station, _) = lib.query_uuid(&anime).unwrap();
+        assert_eq!(station.get_tag(&Tag::Title).unwrap(), "anime radio");
+        assert_eq!(station.get_tag(&Tag::Genre), None);
+
+        // The same stream can't be added twice, and only streams are stations
+        assert!(lib
+            .add_radio_station("Jazz Again", "https://jazz.example/live", None)
+            .is_err());
+        assert!(lib
+            .add_radio_station("Files", "file:///music/song.flac", None)
+            .is_err());
+        assert!(lib
+            .add_radio_station(" ", "https://a.example", None)
+            .is_err());
+        assert_eq!(lib.radio_stations().len(), 2);
+    }
+}
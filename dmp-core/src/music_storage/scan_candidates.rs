@@ -0,0 +1,157 @@
+//! The first phase of a scan, which only finds the files to look at. It
+//! takes seconds even for huge folders, so the import after it knows how
+//! many files there are and can show how far along it is.
+
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use super::{
+    cancel::CancelToken,
+    scan_exclusions::ExclusionRules,
+    scan_report::{ScanError, ScanReport},
+};
+
+/// How many paths are kept together
+pub const CANDIDATE_CHUNK_LEN: usize = 4096;
+
+/// How often counting reports its progress, in files
+const COUNT_PROGRESS_EVERY: usize = 256;
+
+/// The files a scan will look at, in the order they were found. They're kept
+/// in chunks, so a million files don't need one huge allocation, and each
+/// chunk is freed once it's been imported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanCandidates {
+    chunks: Vec<Vec<PathBuf>>,
+    chunk_len: usize,
+    len: usize,
+}
+
+impl Default for ScanCandidates {
+    fn default() -> Self {
+        Self::with_chunk_len(CANDIDATE_CHUNK_LEN)
+    }
+}
+
+impl ScanCandidates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(super) fn with_chunk_len(chunk_len: usize) -> Self {
+        ScanCandidates {
+            chunks: Vec::new(),
+            chunk_len: chunk_len.max(1),
+            len: 0,
+        }
+    }
+
+    /// Adds every file in `target` which `exclusions` doesn't rule out,
+    /// recording the folders which can't be read in `report`. `progress` is
+    /// called with the number found so far every so often, and once more at
+    /// the end.
+    pub fn find<P: ?Sized + AsRef<Path>>(
+        &mut self,
+        target: &P,
+        exclusions: &ExclusionRules,
+        cancel: &CancelToken,
+        report: &mut ScanReport,
+        mut progress: impl FnMut(usize),
+    ) {
+        for entry in WalkDir::new(target).follow_links(true) {
+            if cancel.is_cancelled() {
+                report.cancelled = true;
+                break;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    report.push(ScanError::from_walkdir(&error));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if !path.is_file() || exclusions.excludes_path(path) {
+                continue;
+            }
+
+            self.push(entry.into_path());
+            if self.len.is_multiple_of(COUNT_PROGRESS_EVERY) {
+                progress(self.len);
+            }
+        }
+        progress(self.len);
+    }
+
+    fn push(&mut self, path: PathBuf) {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() < self.chunk_len => chunk.push(path),
+            _ => {
+                let mut chunk = Vec::with_capacity(self.chunk_len);
+                chunk.push(path);
+                self.chunks.push(chunk);
+            }
+        }
+        self.len += 1;
+    }
+
+    /// How many files were found
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The files, a chunk at a time
+    pub fn into_chunks(self) -> impl Iterator<Item = Vec<PathBuf>> {
+        self.chunks.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::ScanCandidates;
+    use crate::music_storage::{
+        cancel::CancelToken, scan_exclusions::ExclusionRules, scan_report::ScanReport,
+    };
+
+    #[test]
+    fn found_in_chunks() {
+        let dir = std::env::temp_dir().join(format!("dmp-candidates-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("disc 2")).unwrap();
+        for name in [
+            "1.flac",
+            "2.flac",
+            "cover.jpg",
+            "disc 2/1.flac",
+            "disc 2/2.flac",
+        ] {
+            fs::write(dir.join(name), b"fLaC").unwrap();
+        }
+
+        let mut candidates = ScanCandidates::with_chunk_len(2);
+        let mut report = ScanReport::default();
+        let mut counted = Vec::new();
+        candidates.find(
+            &dir,
+            &ExclusionRules::default(),
+            &CancelToken::new(),
+            &mut report,
+            |found| counted.push(found),
+        );
+        assert_eq!(candidates.len(), 5);
+        assert_eq!(counted.last(), Some(&5));
+        assert_eq!(report, ScanReport::default());
+
+        let chunks: Vec<Vec<_>> = candidates.into_chunks().collect();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+        assert!(chunks.concat().iter().all(|path| path.is_file()));
+    }
+}
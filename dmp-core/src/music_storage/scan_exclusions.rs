@@ -0,0 +1,223 @@
+//! Rules for leaving files out of the library when scanning, like
+//! audiobooks or karaoke tracks kept in the music folder
+
+use std::{path::Path, time::Duration};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// What to leave out when scanning a library's folders
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanExclusions {
+    /// Glob patterns of paths to skip, like `**/Audiobooks/**`. They're
+    /// matched against the whole path ignoring case, with either kind of
+    /// slash.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Songs shorter than this are skipped. Songs whose length couldn't be
+    /// read are kept.
+    #[serde(default)]
+    pub min_duration_secs: Option<u64>,
+    /// Songs with any of these genres are skipped, ignoring case
+    #[serde(default)]
+    pub genres: Vec<String>,
+}
+
+impl ScanExclusions {
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.min_duration_secs.is_none() && self.genres.is_empty()
+    }
+}
+
+/// [`ScanExclusions`] ready to check files against
+#[derive(Debug, Clone, Default)]
+pub struct ExclusionRules {
+    paths: GlobSet,
+    min_duration: Option<Duration>,
+    genres: Vec<String>,
+}
+
+impl ExclusionRules {
+    pub fn new(exclusions: &ScanExclusions) -> Result<Self, globset::Error> {
+        let mut paths = GlobSetBuilder::new();
+        for pattern in &exclusions.patterns {
+            let glob = GlobBuilder::new(&pattern.replace('\\', "/"))
+                .literal_separator(true)
+                .backslash_escape(false)
+                .case_insensitive(true)
+                .build()?;
+            paths.add(glob);
+        }
+        Ok(ExclusionRules {
+            paths: paths.build()?,
+            min_duration: exclusions.min_duration_secs.map(Duration::from_secs),
+            genres: exclusions
+                .genres
+                .iter()
+                .map(|genre| genre.trim().to_lowercase())
+                .collect(),
+        })
+    }
+
+    /// Whether the file at `path` is skipped before it's read
+    pub fn excludes_path(&self, path: &Path) -> bool {
+        !self.paths.is_empty()
+            && self
+                .paths
+                .is_match(path.to_string_lossy().replace('\\', "/"))
+    }
+
+    /// Whether `song` is skipped once its tags are read, for its length or
+    /// genre. Genre tags holding a few genres split by `;` are checked one
+    /// by one.
+    pub fn excludes_song(&self, song: &Song) -> bool {
+        let too_short = self
+            .min_duration
+            .is_some_and(|min| !song.duration.is_zero() && song.duration < min);
+        let genre = song.get_tag(&Tag::Genre).is_some_and(|genre| {
+            genre
+                .split(';')
+                .any(|genre| self.genres.contains(&genre.trim().to_lowercase()))
+        });
+        too_short || genre
+    }
+
+    /// Whether the song would have been left out of a scan, by any of its
+    /// files or by its tags
+    fn excludes(&self, song: &Song) -> bool {
+        let path_excluded = song
+            .location
+            .iter()
+            .any(|uri| !matches!(uri, URI::Remote(..)) && self.excludes_path(&uri.path()));
+        path_excluded || self.excludes_song(song)
+    }
+}
+
+impl MusicLibrary {
+    /// Removes the songs which `rules` would have kept out of the library,
+    /// or only finds them if `dry_run`. Removed songs can be put back with
+    /// [`MusicLibrary::undo_remove_missing`], like missing ones.
+    pub fn remove_excluded(&mut self, rules: &ExclusionRules, dry_run: bool) -> Vec<Song> {
+        if dry_run {
+            return self
+                .library
+                .iter()
+                .filter(|song| rules.excludes(song))
+                .cloned()
+                .collect();
+        }
+
+        let (removed, kept) = std::mem::take(&mut self.library)
+            .into_iter()
+            .partition(|song| rules.excludes(song));
+        self.library = kept;
+        self.bury(&removed);
+        self.backup_songs.clone_from(&removed);
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{ExclusionRules, ScanExclusions};
+    use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+
+    fn rules(patterns: &[&str]) -> ExclusionRules {
+        ExclusionRules::new(&ScanExclusions {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn nested_patterns() {
+        let rules = rules(&["**/Audiobooks/**", "**/karaoke/*.mp3"]);
+        let excluded = |path: &str| rules.excludes_path(Path::new(path));
+
+        assert!(excluded("/home/user/Music/Audiobooks/Dune/01.m4b"));
+        assert!(excluded("/home/user/Music/Audiobooks/chapter.mp3"));
+        assert!(excluded("/home/user/Music/Karaoke/song.mp3"));
+        // One level only, as `*` doesn't cross folders
+        assert!(!excluded("/home/user/Music/Karaoke/old/song.mp3"));
+        assert!(!excluded("/home/user/Music/Karaoke/song.flac"));
+        assert!(!excluded("/home/user/Music/Audiobooks.mp3"));
+        assert!(!excluded("/home/user/Music/ClariS/irony.flac"));
+
+        // Windows paths, and patterns written with backslashes
+        assert!(excluded(r"C:\Users\user\Music\Audiobooks\Dune\01.m4b"));
+        assert!(excluded(r"D:\karaoke\song.mp3"));
+        assert!(!excluded(r"C:\Users\user\Music\ClariS\irony.flac"));
+        let backslashes = self::rules(&[r"**\Audiobooks\**"]);
+        assert!(backslashes.excludes_path(Path::new(r"C:\Music\Audiobooks\01.m4b")));
+        assert!(backslashes.excludes_path(Path::new("/music/Audiobooks/01.m4b")));
+
+        assert!(!self::rules(&[]).excludes_path(Path::new("/music/Audiobooks/01.m4b")));
+        assert!(ExclusionRules::new(&ScanExclusions {
+            patterns: vec![String::from("[unclosed")],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    fn song(path: &str, secs: u64, genre: Option<&str>) -> Song {
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(path.into())],
+            duration: Duration::from_secs(secs),
+            ..Default::default()
+        };
+        if let Some(genre) = genre {
+            song.set_tag(Tag::Genre, genre.to_string());
+        }
+        song
+    }
+
+    #[test]
+    fn tags_and_length() {
+        let rules = ExclusionRules::new(&ScanExclusions {
+            min_duration_secs: Some(30),
+            genres: vec![String::from("Audiobook"), String::from(" Karaoke ")],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(rules.excludes_song(&song("/a.mp3", 10, None)));
+        assert!(!rules.excludes_song(&song("/a.mp3", 0, None)));
+        assert!(!rules.excludes_song(&song("/a.mp3", 200, Some("J-Pop"))));
+        assert!(rules.excludes_song(&song("/a.mp3", 200, Some("audiobook"))));
+        assert!(rules.excludes_song(&song("/a.mp3", 200, Some("J-Pop; Karaoke"))));
+    }
+
+    #[test]
+    fn preview_and_remove() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("/music/ClariS/irony.flac", 200, Some("J-Pop")),
+            song("/music/Audiobooks/Dune/01.m4b", 3600, None),
+            song("/music/Kalafina/Lacrimosa.flac", 250, Some("Karaoke")),
+        ];
+        let rules = ExclusionRules::new(&ScanExclusions {
+            patterns: vec![String::from("**/Audiobooks/**")],
+            genres: vec![String::from("Karaoke")],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let preview = lib.remove_excluded(&rules, true);
+        assert_eq!(preview.len(), 2);
+        assert_eq!(lib.library.len(), 3);
+
+        let removed = lib.remove_excluded(&rules, false);
+        assert_eq!(removed, preview);
+        assert_eq!(lib.library.len(), 1);
+        assert_eq!(lib.undo_remove_missing(), 2);
+        assert_eq!(lib.library.len(), 3);
+    }
+}
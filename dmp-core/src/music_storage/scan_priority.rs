@@ -0,0 +1,326 @@
+//! Keeping one song for the same track found under more than one scan
+//! folder, like an album kept both as lossless files and as mp3s for a
+//! portable player. Each folder can be given a priority, and a scanned file
+//! which matches a song from a folder of another priority is added to that
+//! song as another location instead of as a song of its own.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// How far apart the durations of two files can be for them to still be the
+/// same track, as different encodings of it are rarely exactly as long
+pub const DURATION_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// What a scan does with a file which looks like a song already in the
+/// library
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScanConflicts {
+    /// Adds it as a song of its own
+    #[default]
+    KeepBoth,
+    /// Adds it to the song as another location when it's from a folder of
+    /// another priority, with the location from the higher priority folder
+    /// played first
+    AddLocation,
+}
+
+/// The priority of every scan folder of a library
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FolderPriorities {
+    /// Deepest folders first, so a folder inside another one wins
+    folders: Vec<(PathBuf, i32)>,
+}
+
+impl FolderPriorities {
+    /// Folders with no priority set have a priority of 0
+    pub fn new(folders: &[PathBuf], priorities: &BTreeMap<PathBuf, i32>) -> Self {
+        let mut folders: Vec<(PathBuf, i32)> = folders
+            .iter()
+            .map(|folder| {
+                let priority = priorities.get(folder).copied().unwrap_or_default();
+                (folder.clone(), priority)
+            })
+            .collect();
+        folders.sort_by_key(|(folder, _)| std::cmp::Reverse(folder.components().count()));
+        FolderPriorities { folders }
+    }
+
+    /// The priority of the folder `path` is in, or 0 if it isn't in any
+    pub fn priority(&self, path: &Path) -> i32 {
+        self.folders
+            .iter()
+            .find(|(folder, _)| path.starts_with(folder))
+            .map(|(_, priority)| *priority)
+            .unwrap_or_default()
+    }
+
+    fn uri_priority(&self, uri: &URI) -> i32 {
+        self.priority(&uri.path())
+    }
+}
+
+/// A song with more than one location
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MultiLocationSong {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    /// In the order they're tried when playing
+    pub locations: Vec<URI>,
+}
+
+impl Song {
+    /// Whether `other` looks like the same track, by its tags and length.
+    /// Songs with no title are never the same, as there's too little to go
+    /// on.
+    pub fn same_track(&self, other: &Song) -> bool {
+        let tag = |song: &Song, tag: Tag| {
+            song.get_tag(&tag)
+                .map(|value| value.trim().to_lowercase())
+                .unwrap_or_default()
+        };
+        if tag(self, Tag::Title).is_empty() {
+            return false;
+        }
+        let tracks_match = match (self.track_number(), other.track_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        [Tag::Title, Tag::Artist, Tag::Album]
+            .into_iter()
+            .all(|t| tag(self, t.clone()) == tag(other, t))
+            && tracks_match
+            && self.duration.abs_diff(other.duration) <= DURATION_TOLERANCE
+    }
+}
+
+impl MusicLibrary {
+    /// Adds the songs a scan found like [`MusicLibrary::add_scanned`], except
+    /// a song matching one already in the library which has no location from
+    /// a folder of the same priority becomes another location of it.
+    /// Returns the number of songs added and the number added as locations.
+    pub fn add_scanned_by_priority(
+        &mut self,
+        songs: Vec<Song>,
+        priorities: &FolderPriorities,
+    ) -> (usize, usize) {
+        let (mut added, mut merged) = (0, 0);
+        // One at a time, so copies found in the same scan are kept together
+        for song in songs {
+            if self.add_location(&song, priorities) {
+                merged += 1;
+            } else {
+                added += self.add_scanned(vec![song]);
+            }
+        }
+        (added, merged)
+    }
+
+    /// Adds the location of `new` to a song it's the same track as, if
+    /// there is one to add it to
+    fn add_location(&mut self, new: &Song, priorities: &FolderPriorities) -> bool {
+        let Some(uri) = new.location.first() else {
+            return false;
+        };
+        if self.query_uri(uri).is_some() {
+            return false;
+        }
+        let priority = priorities.uri_priority(uri);
+        let Some(song) = self.library.iter_mut().find(|song| {
+            song.same_track(new)
+                && song
+                    .location
+                    .iter()
+                    .all(|location| priorities.uri_priority(location) != priority)
+        }) else {
+            return false;
+        };
+
+        // Locations from higher priority folders come first, which is the
+        // order they're tried when playing
+        let at = song
+            .location
+            .iter()
+            .position(|location| priorities.uri_priority(location) < priority)
+            .unwrap_or(song.location.len());
+        song.location.insert(at, uri.clone());
+        true
+    }
+
+    /// The songs with more than one location
+    pub fn multi_location_songs(&self) -> Vec<MultiLocationSong> {
+        self.library
+            .iter()
+            .filter(|song| song.location.len() > 1)
+            .map(|song| MultiLocationSong {
+                uuid: song.uuid,
+                title: song.get_tag(&Tag::Title).cloned(),
+                locations: song.location.clone(),
+            })
+            .collect()
+    }
+
+    /// Removes one location of a song, which has to have another
+    pub fn drop_location(&mut self, uuid: &Uuid, uri: &URI) -> Result<(), String> {
+        let song = self
+            .library
+            .iter_mut()
+            .find(|song| song.uuid == *uuid)
+            .ok_or_else(|| format!("No song with the uuid {uuid}"))?;
+        let at = song
+            .location
+            .iter()
+            .position(|location| location == uri)
+            .ok_or_else(|| format!("The song has no location {uri:?}"))?;
+        if song.location.len() == 1 {
+            return Err("A song's only location can't be dropped".to_string());
+        }
+        song.location.remove(at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::FolderPriorities;
+    use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+
+    /// Makes the folders under a temporary root, with an empty file for
+    /// each song
+    fn roots() -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("{}_priority", Uuid::new_v4()));
+        let (lossless, portable) = (root.join("lossless"), root.join("portable"));
+        fs::create_dir_all(&lossless).unwrap();
+        fs::create_dir_all(&portable).unwrap();
+        (root, lossless, portable)
+    }
+
+    fn song(path: PathBuf, secs: f32) -> Song {
+        fs::write(&path, []).unwrap();
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(path)],
+            duration: Duration::from_secs_f32(secs),
+            tags: BTreeMap::from([
+                (Tag::Title, String::from("Oblivious")),
+                (Tag::Artist, String::from("Kalafina")),
+                (Tag::Album, String::from("Seventh Heaven")),
+                (Tag::Track, String::from("1")),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lower_priority_copy_added_as_location() {
+        let (root, lossless, portable) = roots();
+        let priorities = FolderPriorities::new(
+            &[lossless.clone(), portable.clone()],
+            &BTreeMap::from([(lossless.clone(), 10)]),
+        );
+        let flac = song(lossless.join("01 Oblivious.flac"), 263.4);
+        let mp3 = song(portable.join("01 Oblivious.mp3"), 264.1);
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+
+        // The portable copy is found first, but the lossless one is played
+        assert_eq!(
+            library.add_scanned_by_priority(vec![mp3.clone(), flac.clone()], &priorities),
+            (1, 1)
+        );
+        assert_eq!(library.library.len(), 1);
+        let uuid = library.library[0].uuid;
+        assert_eq!(uuid, mp3.uuid);
+        assert_eq!(
+            library.library[0].location,
+            [flac.location[0].clone(), mp3.location[0].clone()]
+        );
+        assert_eq!(
+            library.library[0].primary_uri().unwrap().0,
+            &flac.location[0]
+        );
+
+        // Scanning again adds nothing
+        assert_eq!(
+            library.add_scanned_by_priority(vec![mp3.clone(), flac.clone()], &priorities),
+            (0, 0)
+        );
+
+        // Another track of the same album is a song of its own
+        let mut other = song(portable.join("02 Sprinkler.mp3"), 250.0);
+        other.tags.insert(Tag::Title, String::from("Sprinkler"));
+        other.tags.insert(Tag::Track, String::from("2"));
+        assert_eq!(
+            library.add_scanned_by_priority(vec![other], &priorities),
+            (1, 0)
+        );
+
+        let multi = library.multi_location_songs();
+        assert_eq!(multi.len(), 1);
+        assert_eq!(multi[0].uuid, uuid);
+
+        library.drop_location(&uuid, &flac.location[0]).unwrap();
+        assert_eq!(library.library[0].location, mp3.location);
+        assert!(library.drop_location(&uuid, &mp3.location[0]).is_err());
+        assert!(library.multi_location_songs().is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn same_priority_copies_kept_apart() {
+        let (root, lossless, portable) = roots();
+        let priorities =
+            FolderPriorities::new(&[lossless.clone(), portable.clone()], &BTreeMap::new());
+        let flac = song(lossless.join("01 Oblivious.flac"), 263.4);
+        let mp3 = song(portable.join("01 Oblivious.mp3"), 264.1);
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+
+        assert_eq!(
+            library.add_scanned_by_priority(vec![flac, mp3], &priorities),
+            (2, 0)
+        );
+
+        // Nor are songs of different lengths the same
+        let priorities = FolderPriorities::new(
+            &[lossless.clone(), portable.clone()],
+            &BTreeMap::from([(portable.clone(), -1)]),
+        );
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let flac = song(lossless.join("01 Oblivious.flac"), 263.4);
+        let edit = song(portable.join("01 Oblivious (Edit).mp3"), 200.0);
+        assert_eq!(
+            library.add_scanned_by_priority(vec![flac, edit], &priorities),
+            (2, 0)
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn deepest_folder_wins() {
+        let priorities = FolderPriorities::new(
+            &[PathBuf::from("/music"), PathBuf::from("/music/lossless")],
+            &BTreeMap::from([
+                (PathBuf::from("/music"), 1),
+                (PathBuf::from("/music/lossless"), 5),
+            ]),
+        );
+        assert_eq!(
+            priorities.priority(&PathBuf::from("/music/lossless/a.flac")),
+            5
+        );
+        assert_eq!(priorities.priority(&PathBuf::from("/music/mp3/a.mp3")), 1);
+        assert_eq!(priorities.priority(&PathBuf::from("/other/a.mp3")), 0);
+    }
+}
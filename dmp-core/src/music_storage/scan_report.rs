@@ -0,0 +1,241 @@
+//! A record of the files which could not be added during a library scan,
+//! so they can be shown to the user instead of only being counted
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use lofty::error::{ErrorKind as LoftyErrorKind, LoftyError};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+
+/// The name of the file the most recent report is kept in, next to the config
+pub const SCAN_REPORT_FILE: &str = "scan_report.json";
+
+/// The most errors a report keeps individually, the rest are only counted
+pub const SCAN_REPORT_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ScanErrorKind {
+    PermissionDenied,
+    UnsupportedFormat,
+    TagParse,
+    Io,
+    /// A `._` file left by macOS, which was skipped
+    ResourceFork,
+    /// A file with nothing in it, which was skipped
+    EmptyFile,
+}
+
+impl ScanErrorKind {
+    fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => ScanErrorKind::PermissionDenied,
+            _ => ScanErrorKind::Io,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanError {
+    #[serde(with = "super::os_path")]
+    pub path: PathBuf,
+    pub kind: ScanErrorKind,
+    pub message: String,
+}
+
+impl ScanError {
+    pub fn new(path: impl Into<PathBuf>, kind: ScanErrorKind, message: impl Into<String>) -> Self {
+        ScanError {
+            path: path.into(),
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Works out what kind of failure an error from adding a file was
+    pub fn from_error(path: impl Into<PathBuf>, error: &(dyn Error + 'static)) -> Self {
+        let kind = if let Some(e) = error.downcast_ref::<io::Error>() {
+            ScanErrorKind::from_io(e)
+        } else if let Some(e) = error.downcast_ref::<LoftyError>() {
+            match e.kind() {
+                LoftyErrorKind::Io(e) => ScanErrorKind::from_io(e),
+                LoftyErrorKind::UnknownFormat => ScanErrorKind::UnsupportedFormat,
+                _ => ScanErrorKind::TagParse,
+            }
+        } else {
+            ScanErrorKind::TagParse
+        };
+
+        ScanError::new(path, kind, error.to_string())
+    }
+
+    pub fn from_walkdir(error: &walkdir::Error) -> Self {
+        let kind = error
+            .io_error()
+            .map(ScanErrorKind::from_io)
+            .unwrap_or(ScanErrorKind::Io);
+        ScanError::new(
+            error.path().unwrap_or(Path::new("")),
+            kind,
+            error.to_string(),
+        )
+    }
+}
+
+/// What a scan is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ScanPhase {
+    /// Finding the files to look at, see
+    /// [`ScanCandidates`](super::scan_candidates::ScanCandidates)
+    #[default]
+    Counting,
+    /// Adding the files found
+    Importing,
+}
+
+/// How far a scan is, sent every so often while it counts files and after
+/// each file it imports
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct ScanProgress {
+    pub phase: ScanPhase,
+    /// The files found so far while counting, and all of them after
+    pub total_candidates: usize,
+    /// The files looked at, including ones which were already in the
+    /// library
+    pub processed: usize,
+    pub added: i32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanReport {
+    /// The number of songs which were added
+    pub added: i32,
+    pub errors: Vec<ScanError>,
+    /// The number of errors past [`SCAN_REPORT_LIMIT`], by kind
+    pub omitted: BTreeMap<ScanErrorKind, usize>,
+    /// Whether the scan was stopped before it finished. The songs found
+    /// until then were still added.
+    #[serde(default)]
+    pub cancelled: bool,
+    /// The number of files added to songs already in the library as another
+    /// location, see [`ScanConflicts`](super::scan_priority::ScanConflicts)
+    #[serde(default)]
+    pub merged: usize,
+}
+
+impl ScanReport {
+    /// Records an error, only counting it once the report is full
+    pub fn push(&mut self, error: ScanError) {
+        if self.errors.len() < SCAN_REPORT_LIMIT {
+            self.errors.push(error);
+        } else {
+            *self.omitted.entry(error.kind).or_default() += 1;
+        }
+    }
+
+    /// Adds the report of another folder scanned at the same time
+    pub fn extend(&mut self, other: ScanReport) {
+        self.added += other.added;
+        other.errors.into_iter().for_each(|error| self.push(error));
+        for (kind, count) in other.omitted {
+            *self.omitted.entry(kind).or_default() += count;
+        }
+        self.cancelled |= other.cancelled;
+        self.merged += other.merged;
+    }
+
+    /// The total number of errors, including omitted ones
+    pub fn error_count(&self) -> usize {
+        self.errors.len() + self.omitted.values().sum::<usize>()
+    }
+
+    /// Removes the errors for a path, like after it was retried successfully
+    pub fn remove(&mut self, path: &Path) {
+        self.errors.retain(|error| error.path != path);
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let report = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(report)
+    }
+
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(path)?
+            .write_all(&to_string_pretty(self)?.into_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use uuid::Uuid;
+
+    use super::{ScanError, ScanErrorKind, ScanReport, SCAN_REPORT_LIMIT};
+
+    #[test]
+    fn report_is_capped() {
+        let mut report = ScanReport::default();
+        for i in 0..SCAN_REPORT_LIMIT + 5 {
+            let kind = match i % 2 {
+                0 => ScanErrorKind::Io,
+                _ => ScanErrorKind::TagParse,
+            };
+            report.push(ScanError::new(format!("{i}.mp3"), kind, ""));
+        }
+
+        assert_eq!(report.errors.len(), SCAN_REPORT_LIMIT);
+        assert_eq!(report.error_count(), SCAN_REPORT_LIMIT + 5);
+        assert_eq!(report.omitted.get(&ScanErrorKind::Io), Some(&3));
+        assert_eq!(report.omitted.get(&ScanErrorKind::TagParse), Some(&2));
+    }
+
+    #[test]
+    fn error_kinds() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            ScanError::from_error("a.flac", &denied).kind,
+            ScanErrorKind::PermissionDenied
+        );
+
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(
+            ScanError::from_error("a.flac", &missing).kind,
+            ScanErrorKind::Io
+        );
+
+        let walk = walkdir::WalkDir::new(format!("/{}", Uuid::new_v4()))
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ScanError::from_walkdir(&walk).kind, ScanErrorKind::Io);
+    }
+
+    #[test]
+    fn save_and_retry() {
+        let path = std::env::temp_dir().join(format!("scan_report_{}.json", Uuid::new_v4()));
+        let mut report = ScanReport::default();
+        report.push(ScanError::new("a.mp3", ScanErrorKind::Io, "broken"));
+        report.push(ScanError::new("b.mp3", ScanErrorKind::TagParse, "bad tags"));
+        report.write_file(&path).unwrap();
+
+        let mut loaded = ScanReport::read_file(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded, report);
+
+        loaded.remove(Path::new("a.mp3"));
+        assert_eq!(loaded.errors.len(), 1);
+        assert_eq!(loaded.errors[0].path, Path::new("b.mp3"));
+    }
+}
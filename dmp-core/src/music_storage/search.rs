@@ -0,0 +1,334 @@
+//! Picking a single song from a typed search, for queueing it straight away
+
+use std::{cmp::Ordering, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    library::{MusicLibrary, Song, Tag},
+    utils::normalize,
+};
+
+/// How sure a search has to be of its top result to use it without asking
+pub const SEARCH_CONFIDENCE: f32 = 0.75;
+
+/// The most candidates returned when a search is ambiguous
+pub const SEARCH_CANDIDATES: usize = 10;
+
+/// What to do with the song a search found
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum QueueMode {
+    /// Replace the queue and play the song
+    PlayNow,
+    /// Play the song after the current one
+    PlayNext,
+    /// Add the song to the end of what was added to the queue
+    Append,
+}
+
+/// A possible result of a search, with enough to show it in a list
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchCandidate {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// From 0 to 1, how well the song matched
+    pub score: f32,
+}
+
+impl SearchCandidate {
+    fn new(song: &Song, score: f32) -> Self {
+        SearchCandidate {
+            uuid: song.uuid,
+            title: song.get_tag(&Tag::Title).cloned(),
+            artist: song.get_tag(&Tag::Artist).cloned(),
+            album: song.get_tag(&Tag::Album).cloned(),
+            score,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SearchMatch {
+    /// A single song matched well enough
+    Found(Box<Song>),
+    /// Several songs matched about as well, or none matched well enough,
+    /// best first
+    Ambiguous(Vec<SearchCandidate>),
+    NotFound,
+}
+
+/// Scores how well a normalized query matches a value, with an exact match
+/// being 1 and partial matches scoring by how much of the value they cover
+fn score(query: &str, value: &str) -> f32 {
+    let value = normalize(value);
+    if value == query {
+        1.0
+    } else if value.contains(query) {
+        // Never let a partial match look as good as an exact one
+        0.9 * query.len() as f32 / value.len() as f32
+    } else {
+        0.0
+    }
+}
+
+fn score_song(query: &str, song: &Song) -> f32 {
+    let tag = |tag: &Tag| song.get_tag(tag).map(String::as_str).unwrap_or_default();
+    let title = score(query, tag(&Tag::Title));
+    // Typing the title along with the artist is as exact as the title alone
+    let title_artist = score(
+        query,
+        &format!("{} {}", tag(&Tag::Title), tag(&Tag::Artist)),
+    )
+    .max(score(
+        query,
+        &format!("{} {}", tag(&Tag::Artist), tag(&Tag::Title)),
+    ));
+    // A whole artist or album matching is rarely a single song
+    let other = score(query, tag(&Tag::Artist)).max(score(query, tag(&Tag::Album))) * 0.8;
+
+    title.max(title_artist).max(other)
+}
+
+impl MusicLibrary {
+    /// Searches the titles, artists, and albums of songs for `query`,
+    /// returning the best match if it's clearly better than the rest
+    pub fn search_best(&self, query: &str) -> SearchMatch {
+        self.search_best_where(query, |_| true)
+    }
+
+    /// Like [`search_best`](Self::search_best), leaving out the songs which
+    /// `keep` returns false for
+    pub fn search_best_where(&self, query: &str, keep: impl Fn(&Song) -> bool) -> SearchMatch {
+        let matches = self.search_scored(query, keep);
+        match matches.as_slice() {
+            [] => SearchMatch::NotFound,
+            [(song, best), rest @ ..]
+                if *best >= SEARCH_CONFIDENCE && rest.first().is_none_or(|(_, s)| s < best) =>
+            {
+                SearchMatch::Found(Box::new((*song).clone()))
+            }
+            _ => SearchMatch::Ambiguous(
+                matches
+                    .iter()
+                    .take(SEARCH_CANDIDATES)
+                    .map(|(song, score)| SearchCandidate::new(song, *score))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The songs which match `query` at all, best first, however sure the
+    /// best one is
+    pub fn search_candidates(&self, query: &str) -> Vec<SearchCandidate> {
+        self.search_scored(query, |_| true)
+            .iter()
+            .take(SEARCH_CANDIDATES)
+            .map(|(song, score)| SearchCandidate::new(song, *score))
+            .collect()
+    }
+
+    /// The songs [`query_tracks`](Self::query_tracks) finds, as their uuids
+    /// and durations, leaving out songs marked with a problem and explicit
+    /// songs when `filtered`. Nothing is cloned, so this stays quick for a
+    /// filter which matches most of a big library.
+    pub fn query_uuids(
+        &self,
+        query: &str,
+        target_tags: &[Tag],
+        sort_by: &[Tag],
+        filtered: bool,
+    ) -> Vec<(Uuid, Duration)> {
+        self.query_tracks(&query.to_string(), &target_tags.to_vec(), sort_by)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|song| !(filtered && song.is_explicit()) && song.problem().is_none())
+            .map(|song| (song.uuid, song.duration))
+            .collect()
+    }
+
+    fn search_scored(&self, query: &str, keep: impl Fn(&Song) -> bool) -> Vec<(&Song, f32)> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches: Vec<(&Song, f32)> = self
+            .library
+            .iter()
+            .filter(|song| keep(song))
+            .map(|song| (song, score_song(&query, song)))
+            .filter(|(_, score)| *score > 0.0)
+            .collect();
+        matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use super::SearchMatch;
+    use crate::music_storage::{
+        library::{MusicLibrary, Song, Tag},
+        song_problems::SongProblem,
+    };
+
+    fn library() -> MusicLibrary {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for (title, artist, album) in [
+            ("Airborne", "Kaede", "Skies"),
+            ("Airborne Again", "Kaede", "Skies"),
+            ("Blue Hour", "Kaede", "Skies"),
+            ("Blue Hour", "Nagi", "Night"),
+            ("Hanabi", "Nagi", "Night"),
+        ] {
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            };
+            song.set_tag(Tag::Title, title.to_string());
+            song.set_tag(Tag::Artist, artist.to_string());
+            song.set_tag(Tag::Album, album.to_string());
+            library.library.push(song);
+        }
+        library
+    }
+
+    fn title(song: &Song) -> &str {
+        song.get_tag(&Tag::Title).unwrap()
+    }
+
+    #[test]
+    fn exact_match() {
+        let library = library();
+
+        // Beats "Airborne Again", which only contains the query
+        let SearchMatch::Found(song) = library.search_best("airborne") else {
+            panic!("Expected a single match");
+        };
+        assert_eq!(title(&song), "Airborne");
+
+        let SearchMatch::Found(song) = library.search_best("Blue Hour - Nagi") else {
+            panic!("Expected a single match");
+        };
+        assert_eq!(song.get_tag(&Tag::Artist).unwrap(), "Nagi");
+
+        // Most of a title is close enough
+        let SearchMatch::Found(song) = library.search_best("airborne agai") else {
+            panic!("Expected a single match");
+        };
+        assert_eq!(title(&song), "Airborne Again");
+    }
+
+    #[test]
+    fn ambiguous_match() {
+        let library = library();
+
+        // Two songs have the same title
+        let SearchMatch::Ambiguous(candidates) = library.search_best("blue hour") else {
+            panic!("Expected an ambiguous match");
+        };
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates
+            .iter()
+            .all(|c| c.title.as_deref() == Some("Blue Hour")));
+
+        // An artist matches all of their songs equally
+        let SearchMatch::Ambiguous(candidates) = library.search_best("Kaede") else {
+            panic!("Expected an ambiguous match");
+        };
+        assert_eq!(candidates.len(), 3);
+
+        // Only a small part of the title matching isn't confident enough
+        let SearchMatch::Ambiguous(candidates) = library.search_best("air") else {
+            panic!("Expected an ambiguous match");
+        };
+        assert_eq!(candidates[0].title.as_deref(), Some("Airborne"));
+        assert!(candidates[0].score > candidates[1].score);
+    }
+
+    #[test]
+    fn filtered_match() {
+        let mut library = library();
+        // "Blue Hour" by Kaede is explicit, leaving Nagi's as the only match
+        library.library[2].set_tag(Tag::Key("ITUNESADVISORY".to_string()), "1".to_string());
+        let clean = |song: &Song| !song.is_explicit();
+
+        let SearchMatch::Found(song) = library.search_best_where("blue hour", clean) else {
+            panic!("Expected a single match");
+        };
+        assert_eq!(song.get_tag(&Tag::Artist).unwrap(), "Nagi");
+        let SearchMatch::Ambiguous(candidates) = library.search_best_where("kaede", clean) else {
+            panic!("Expected an ambiguous match");
+        };
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates
+            .iter()
+            .all(|c| c.title.as_deref() != Some("Blue Hour")));
+    }
+
+    #[test]
+    fn no_match() {
+        let library = library();
+        assert_eq!(library.search_best("nothing"), SearchMatch::NotFound);
+        assert_eq!(library.search_best("  !? "), SearchMatch::NotFound);
+    }
+
+    #[test]
+    fn query_uuids_in_order() {
+        let mut library = library();
+        library.library[2].set_tag(Tag::Key("ITUNESADVISORY".to_string()), "1".to_string());
+        library.library[3].set_problem(Some(SongProblem::Silent));
+
+        let titles = |filtered| {
+            library
+                .query_uuids("kaede", &[Tag::Artist], &[Tag::Title], filtered)
+                .iter()
+                .map(|(uuid, _)| title(library.query_uuid(uuid).unwrap().0).to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles(false), ["Airborne", "Airborne Again", "Blue Hour"]);
+        assert_eq!(titles(true), ["Airborne", "Airborne Again"]);
+        // Nagi's "Blue Hour" has a problem
+        assert_eq!(
+            library
+                .query_uuids("blue", &[Tag::Title], &[Tag::Artist], false)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn query_uuids_for_big_library() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for i in 0..15_000 {
+            let mut song = Song {
+                uuid: Uuid::new_v4(),
+                duration: Duration::from_secs(180),
+                ..Default::default()
+            };
+            song.set_tag(Tag::Title, format!("Track {i}"));
+            song.set_tag(Tag::Artist, format!("Artist {}", i / 100));
+            song.set_tag(Tag::Album, format!("Album {}", i / 10));
+            song.set_tag(Tag::Track, (i % 10 + 1).to_string());
+            library.library.push(song);
+        }
+
+        let start = Instant::now();
+        let found = library.query_uuids(
+            "artist",
+            &[Tag::Title, Tag::Artist],
+            &[Tag::Artist, Tag::Album, Tag::Track],
+            false,
+        );
+        let elapsed = start.elapsed();
+        assert_eq!(found.len(), 15_000);
+        assert!(elapsed < Duration::from_secs(1), "took {elapsed:?}");
+    }
+}
@@ -0,0 +1,289 @@
+//! Finding the silence at the start and end of songs, so it can be skipped
+//! when they're played. Where the sound starts and ends is found once, the
+//! first time the song plays or when its gain is analyzed, and kept on the
+//! song.
+
+use std::{path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::ConfigPlayback;
+
+use super::{
+    decode::{decode, DecodeError},
+    library::{MusicLibrary, Song, URI},
+};
+
+/// How long silence has to go on for to be skipped
+pub const MIN_SILENCE: Duration = Duration::from_secs(1);
+
+/// How quiet a sample has to be to count as silence, in dBFS
+pub const DEFAULT_SILENCE_THRESHOLD_DB: f32 = -60.0;
+
+/// The most silence skipped at either end of a song, in seconds
+pub const DEFAULT_MAX_SILENCE_SKIP_SECS: u32 = 10;
+
+/// Where the sound in a song's file starts and ends
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct SilenceBounds {
+    /// The first sample louder than the threshold
+    pub start: Duration,
+    /// Just after the last sample louder than the threshold
+    pub end: Duration,
+    /// The threshold in dBFS the bounds were found with, so they're found
+    /// again when it changes
+    pub threshold_db: f32,
+}
+
+/// Keeps where the first and last samples louder than a threshold are, a
+/// decoded packet at a time
+pub(super) struct SilenceMeter {
+    threshold_db: f32,
+    /// As a linear amplitude
+    threshold: f32,
+    frames: u64,
+    rate: u32,
+    first: Option<u64>,
+    /// The frame after the last loud one
+    last: u64,
+}
+
+impl SilenceMeter {
+    pub(super) fn new(threshold_db: f32) -> Self {
+        SilenceMeter {
+            threshold_db,
+            threshold: 10f32.powf(threshold_db / 20.0),
+            frames: 0,
+            rate: 0,
+            first: None,
+            last: 0,
+        }
+    }
+
+    pub(super) fn add(&mut self, samples: &[f32], channels: usize, rate: u32) {
+        for (i, frame) in samples.chunks_exact(channels.max(1)).enumerate() {
+            if frame.iter().any(|sample| sample.abs() > self.threshold) {
+                let frame = self.frames + i as u64;
+                self.first.get_or_insert(frame);
+                self.last = frame + 1;
+            }
+        }
+        self.frames += (samples.len() / channels.max(1)) as u64;
+        self.rate = rate;
+    }
+
+    /// The bounds of the sound, both at the start of the song if it's
+    /// silent all the way through
+    pub(super) fn bounds(&self) -> SilenceBounds {
+        let time = |frame: u64| match self.rate {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs_f64(frame as f64 / rate as f64),
+        };
+        SilenceBounds {
+            start: time(self.first.unwrap_or_default()),
+            end: time(self.last),
+            threshold_db: self.threshold_db,
+        }
+    }
+}
+
+/// Finds where the sound starts and ends in the file at `path`
+pub fn detect_silence(path: &Path, threshold_db: f32) -> Result<SilenceBounds, DecodeError> {
+    let mut meter = SilenceMeter::new(threshold_db);
+    decode(
+        path,
+        None,
+        |samples, channels, rate| meter.add(samples, channels, rate),
+        |_| true,
+    )?;
+    Ok(meter.bounds())
+}
+
+impl Song {
+    /// The part of the song's file to play. A trim set by the user always
+    /// wins, and otherwise silence longer than [`MIN_SILENCE`] at either end
+    /// is left out when `playback` skips silence, up to its limit.
+    pub fn playback_range(&self, playback: &ConfigPlayback) -> Option<(Duration, Duration)> {
+        if self.trim.is_some() {
+            return self.trim;
+        }
+        let silence = self
+            .silence
+            .filter(|_| playback.skip_silence && self.whole_file())
+            .filter(|silence| silence.threshold_db == playback.silence_threshold_db)?;
+        // Silent songs are played as they are
+        if silence.end <= silence.start || self.duration.is_zero() {
+            return None;
+        }
+
+        let max = Duration::from_secs(playback.max_silence_skip_secs.into());
+        let start = match silence.start {
+            start if start > MIN_SILENCE => start.min(max),
+            _ => Duration::ZERO,
+        };
+        let trailing = self.duration.saturating_sub(silence.end);
+        let end = match trailing {
+            trailing if trailing > MIN_SILENCE => self.duration - trailing.min(max),
+            _ => self.duration,
+        };
+        (start > Duration::ZERO || end < self.duration).then_some((start, end))
+    }
+
+    /// Whether the silence in the song still has to be found before it can
+    /// be skipped
+    pub fn needs_silence_detection(&self, playback: &ConfigPlayback) -> bool {
+        playback.skip_silence
+            && self.trim.is_none()
+            && self.whole_file()
+            && self
+                .silence
+                .is_none_or(|silence| silence.threshold_db != playback.silence_threshold_db)
+    }
+
+    /// Only the silence of whole local files is skipped, as a song from a
+    /// CUE sheet shares its file with the others
+    fn whole_file(&self) -> bool {
+        matches!(self.location.first(), Some(URI::Local(_)))
+    }
+}
+
+impl MusicLibrary {
+    /// Keeps where the sound in a song starts and ends
+    pub fn set_silence(&mut self, uuid: &Uuid, silence: SilenceBounds) {
+        if let Some(song) = self.library.iter_mut().find(|song| song.uuid == *uuid) {
+            song.silence = Some(silence);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{f32::consts::TAU, fs, path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{detect_silence, SilenceBounds, DEFAULT_SILENCE_THRESHOLD_DB};
+    use crate::{
+        config::ConfigPlayback,
+        music_storage::library::{Song, URI},
+    };
+
+    const SAMPLE_RATE: u32 = 8_000;
+
+    /// Writes a mono WAV of `lead` seconds of silence, `sound` seconds of a
+    /// sine wave and `tail` seconds of silence
+    pub(crate) fn padded_wav(lead: f32, sound: f32, tail: f32) -> PathBuf {
+        let frames = |secs: f32| (secs * SAMPLE_RATE as f32) as u32;
+        let (lead, sound, tail) = (frames(lead), frames(sound), frames(tail));
+        let data_len = (lead + sound + tail) * 2;
+
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(SAMPLE_RATE.to_le_bytes());
+        wav.extend((SAMPLE_RATE * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for i in 0..lead + sound + tail {
+            let sample = match i >= lead && i < lead + sound {
+                true => 0.5 * (TAU * 440.0 * i as f32 / SAMPLE_RATE as f32).sin(),
+                false => 0.0,
+            };
+            wav.extend(((sample * i16::MAX as f32) as i16).to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join(format!("{}_silence.wav", Uuid::new_v4()));
+        fs::write(&path, wav).unwrap();
+        path
+    }
+
+    fn close(a: Duration, b: Duration) -> bool {
+        a.abs_diff(b) < Duration::from_millis(5)
+    }
+
+    #[test]
+    fn padded_bounds() {
+        let path = padded_wav(2.5, 3.0, 1.5);
+        let silence = detect_silence(&path, DEFAULT_SILENCE_THRESHOLD_DB).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            close(silence.start, Duration::from_millis(2500)),
+            "{silence:?}"
+        );
+        assert!(
+            close(silence.end, Duration::from_millis(5500)),
+            "{silence:?}"
+        );
+
+        let path = padded_wav(0.0, 1.0, 0.0);
+        let silence = detect_silence(&path, DEFAULT_SILENCE_THRESHOLD_DB).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(close(silence.start, Duration::ZERO), "{silence:?}");
+        assert!(close(silence.end, Duration::from_secs(1)), "{silence:?}");
+    }
+
+    #[test]
+    fn skipped_range() {
+        let mut playback = ConfigPlayback {
+            skip_silence: true,
+            max_silence_skip_secs: 10,
+            ..Default::default()
+        };
+        let secs = Duration::from_secs;
+        let song = |start, end| Song {
+            location: vec![URI::Local(PathBuf::from("/music/padded.flac"))],
+            duration: secs(200),
+            silence: Some(SilenceBounds {
+                start,
+                end,
+                threshold_db: DEFAULT_SILENCE_THRESHOLD_DB,
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            song(secs(3), secs(195)).playback_range(&playback),
+            Some((secs(3), secs(195)))
+        );
+        // A second or less isn't skipped
+        assert_eq!(
+            song(secs(1), secs(196)).playback_range(&playback),
+            Some((Duration::ZERO, secs(196)))
+        );
+        assert_eq!(song(secs(1), secs(199)).playback_range(&playback), None);
+        // Only up to the limit is skipped
+        assert_eq!(
+            song(secs(30), secs(150)).playback_range(&playback),
+            Some((secs(10), secs(190)))
+        );
+        // Silent songs play as they are
+        assert_eq!(song(secs(0), secs(0)).playback_range(&playback), None);
+
+        // The user's trim wins
+        let mut trimmed = song(secs(3), secs(195));
+        trimmed.trim = Some((secs(20), secs(100)));
+        assert_eq!(
+            trimmed.playback_range(&playback),
+            Some((secs(20), secs(100)))
+        );
+        assert!(!trimmed.needs_silence_detection(&playback));
+
+        // Bounds found with another threshold are found again
+        let song = song(secs(3), secs(195));
+        assert!(!song.needs_silence_detection(&playback));
+        playback.silence_threshold_db = -50.0;
+        assert_eq!(song.playback_range(&playback), None);
+        assert!(song.needs_silence_detection(&playback));
+
+        playback.skip_silence = false;
+        assert!(!song.needs_silence_detection(&playback));
+        assert!(!Song::default().needs_silence_detection(&playback));
+    }
+}
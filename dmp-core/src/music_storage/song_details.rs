@@ -0,0 +1,436 @@
+//! Everything known about a single song, as shown in its properties
+
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+use lofty::{
+    file::{AudioFile as _, TaggedFileExt as _},
+    picture::{Picture, PictureInformation},
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    library::{AlbumArt, AlbumKey, BannedType, InternalTag, MusicLibrary, Song, Tag, URI},
+    os_path::DisplayPath,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocationDetails {
+    pub uri: URI,
+    /// The path to show, which is marked when it had to be changed because
+    /// it isn't valid UTF-8
+    pub display: DisplayPath,
+    pub exists: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArtDetails {
+    pub art: AlbumArt,
+    /// The size of the image in bytes, `None` if it couldn't be read
+    pub bytes: Option<usize>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Technical details of the audio file
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct AudioDetails {
+    pub file_type: Option<String>,
+    pub file_size: Option<u64>,
+    /// Bitrates in kbps
+    pub overall_bitrate: Option<u32>,
+    pub audio_bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SongDetails {
+    pub uuid: Uuid,
+    /// Every tag, including custom ones, keyed by its name
+    pub tags: BTreeMap<String, String>,
+    pub plays: i32,
+    pub skips: i32,
+    pub favorited: bool,
+    pub banned: Option<BannedType>,
+    pub rating: Option<u8>,
+    pub format: Option<String>,
+    pub duration: Duration,
+    /// Where playback starts and ends, if the song is trimmed
+    pub trim: Option<(Duration, Duration)>,
+    pub play_time: Duration,
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_played: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option")]
+    pub date_added: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option")]
+    pub date_modified: Option<DateTime<Utc>>,
+    pub internal_tags: Vec<InternalTag>,
+    pub locations: Vec<LocationDetails>,
+    pub art: Vec<ArtDetails>,
+    pub audio: AudioDetails,
+}
+
+impl SongDetails {
+    /// Gathers the details of a song. This reads its files, so it shouldn't
+    /// be done on the library loop.
+    pub fn read(song: &Song) -> Self {
+        let locations = song
+            .location
+            .iter()
+            .map(|uri| LocationDetails {
+                uri: uri.clone(),
+                display: DisplayPath::new(&uri.path()),
+                exists: uri.exists().unwrap_or(false),
+            })
+            .collect();
+
+        let art = song
+            .album_art
+            .iter()
+            .enumerate()
+            .map(|(i, art)| {
+                let data = song.album_art(i).ok().flatten();
+                let info = data.as_ref().and_then(|data| {
+                    let picture = Picture::from_reader(&mut data.as_slice()).ok()?;
+                    PictureInformation::from_picture(&picture).ok()
+                });
+                ArtDetails {
+                    art: art.clone(),
+                    bytes: data.map(|data| data.len()),
+                    width: info.map(|info| info.width),
+                    height: info.map(|info| info.height),
+                }
+            })
+            .collect();
+
+        let audio = match song.primary_uri() {
+            Ok((URI::Remote(..), _)) | Err(_) => AudioDetails::default(),
+            Ok((uri, _)) => AudioDetails::read(&uri.path()),
+        };
+
+        SongDetails {
+            uuid: song.uuid,
+            tags: song
+                .tags
+                .iter()
+                .map(|(tag, value)| (tag.to_string(), value.clone()))
+                .collect(),
+            plays: song.plays,
+            skips: song.skips,
+            favorited: song.favorited,
+            banned: song.banned.clone(),
+            rating: song.rating,
+            format: song.format.clone(),
+            duration: song.duration,
+            trim: song.trim,
+            play_time: song.play_time,
+            last_played: song.last_played,
+            date_added: song.date_added,
+            date_modified: song.date_modified,
+            internal_tags: song.internal_tags.clone(),
+            locations,
+            art,
+            audio,
+        }
+    }
+}
+
+impl AudioDetails {
+    fn read(path: &Path) -> Self {
+        let file_size = std::fs::metadata(path).ok().map(|m| m.len());
+        let Ok(file) = lofty::read_from_path(path) else {
+            return AudioDetails {
+                file_size,
+                ..Default::default()
+            };
+        };
+
+        let properties = file.properties();
+        AudioDetails {
+            file_type: Some(format!("{:?}", file.file_type())),
+            file_size,
+            overall_bitrate: properties.overall_bitrate(),
+            audio_bitrate: properties.audio_bitrate(),
+            sample_rate: properties.sample_rate(),
+            bit_depth: properties.bit_depth(),
+            channels: properties.channels(),
+        }
+    }
+}
+
+/// What reading songs from their files again did
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RefreshReport {
+    /// The songs as they are now
+    pub refreshed: Vec<Song>,
+    pub failed: Vec<(Uuid, String)>,
+}
+
+impl Song {
+    /// Takes what was read from the song's file in `fresh`, keeping what
+    /// only the library knows: the uuid, stats and internal tags, the
+    /// choices made by the user, and ReplayGain values which were never
+    /// written to the file
+    fn refresh_from(&mut self, fresh: Song) {
+        let kept: Vec<(Tag, String)> = std::mem::replace(&mut self.tags, fresh.tags)
+            .into_iter()
+            .filter(|(tag, _)| matches!(tag, Tag::Key(key) if key.starts_with("ReplayGain")))
+            .collect();
+        for (tag, value) in kept {
+            self.tags.entry(tag).or_insert(value);
+        }
+
+        self.album_art = fresh.album_art;
+        self.art_metadata = fresh.art_metadata;
+        self.format = fresh.format;
+        // The audio itself changed, so its silence is found again
+        if self.duration != fresh.duration {
+            self.silence = None;
+        }
+        self.duration = fresh.duration;
+        // Only dropped once they no longer fit the file
+        self.preferred_art = self.preferred_art.filter(|&i| i < self.album_art.len());
+        self.trim = self
+            .trim
+            .filter(|&(_, end)| self.duration.is_zero() || end <= self.duration);
+        self.date_modified = Some(Utc::now());
+    }
+
+    /// The sample rate of the song's file, read from its header. Songs
+    /// without a local file don't have one.
+    pub fn sample_rate(&self) -> Option<u32> {
+        match self.location.first()? {
+            URI::Local(path) | URI::Cue { location: path, .. } => {
+                AudioDetails::read(path).sample_rate
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MusicLibrary {
+    /// Reads the tags of a song from its file again, replacing the ones in
+    /// the library. Play counts and other fields are kept.
+    pub fn reread_song(&mut self, uuid: &Uuid) -> Result<Song, String> {
+        let Some((_, i)) = self.query_uuid(uuid) else {
+            return Err("Song not found".to_string());
+        };
+        let song = &mut self.library[i];
+        let path = match song.primary_uri() {
+            Ok((URI::Local(path), _)) => path.clone(),
+            Ok(_) => return Err("Only songs from local files can be re-read".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let fresh = Song::from_file(&path).map_err(|e| e.to_string())?;
+        song.refresh_from(fresh);
+        Ok(song.clone())
+    }
+
+    /// Reads each of the songs from its file again, like
+    /// [`MusicLibrary::reread_song`], carrying on past the ones which fail
+    pub fn refresh_songs(&mut self, uuids: &[Uuid]) -> RefreshReport {
+        let mut report = RefreshReport::default();
+        for uuid in uuids {
+            match self.reread_song(uuid) {
+                Ok(song) => report.refreshed.push(song),
+                Err(e) => report.failed.push((*uuid, e)),
+            }
+        }
+        report
+    }
+
+    /// Reads every song in an album from its file again
+    pub fn refresh_album(&mut self, key: &AlbumKey) -> Result<RefreshReport, String> {
+        let album = self.query_album(key).ok_or("Album not found")?;
+        let uuids: Vec<Uuid> = album
+            .play_order(None)
+            .iter()
+            .map(|track| *track.uuid())
+            .collect();
+        Ok(self.refresh_songs(&uuids))
+    }
+
+    /// Sets where playback of a song starts and ends, `None` going back to
+    /// playing the whole file. The start has to be before the end, and the
+    /// end can't be past the song's duration when it's known.
+    pub fn set_trim(
+        &mut self,
+        uuid: &Uuid,
+        trim: Option<(Duration, Duration)>,
+    ) -> Result<(), String> {
+        let Some((song, i)) = self.query_uuid(uuid) else {
+            return Err("Song not found".to_string());
+        };
+        if let Some((start, end)) = trim {
+            if start >= end {
+                return Err("The start of a trim has to be before its end".to_string());
+            }
+            if !song.duration.is_zero() && end > song.duration {
+                return Err("The end of a trim can't be past the end of the song".to_string());
+            }
+        }
+        self.library[i].trim = trim;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{AudioDetails, SongDetails};
+    use crate::music_storage::library::{
+        AlbumArt, AlbumKey, InternalTag, MusicLibrary, Song, Tag, URI,
+    };
+
+    #[test]
+    fn details_of_missing_file() {
+        let missing = std::env::temp_dir().join(format!("{}.flac", Uuid::new_v4()));
+        let mut song = Song {
+            location: vec![URI::Local(missing.clone())],
+            album_art: vec![AlbumArt::External(URI::Local(
+                missing.with_extension("jpg"),
+            ))],
+            plays: 3,
+            ..Default::default()
+        };
+        song.tags.insert(Tag::Title, "Title".to_string());
+        song.tags.insert(
+            Tag::Key("ReplayGainTrackGain".to_string()),
+            "1.00 dB".to_string(),
+        );
+
+        let details = SongDetails::read(&song);
+        assert_eq!(details.plays, 3);
+        assert_eq!(details.tags["TrackTitle"], "Title");
+        assert_eq!(details.tags["ReplayGainTrackGain"], "1.00 dB");
+        assert!(!details.locations[0].exists);
+        assert_eq!(details.art[0].bytes, None);
+        assert_eq!(details.audio, AudioDetails::default());
+    }
+
+    #[test]
+    fn reread_needs_local_file() {
+        let mut library = MusicLibrary::init(
+            std::env::temp_dir().join(format!("{}.dlib", Uuid::new_v4())),
+            Uuid::new_v4(),
+        )
+        .unwrap();
+        let song = Song {
+            location: vec![URI::Remote(
+                crate::music_storage::library::Service::InternetRadio,
+                "https://example.com/stream".to_string(),
+            )],
+            ..Default::default()
+        };
+        let uuid = song.uuid;
+        library.add_song(song).unwrap();
+
+        assert!(library.reread_song(&uuid).is_err());
+        assert_eq!(
+            library.reread_song(&Uuid::new_v4()),
+            Err("Song not found".to_string())
+        );
+
+        // A path which isn't there is reported rather than replacing the tags
+        library.library[0].location = vec![URI::Local(PathBuf::from("/missing.flac"))];
+        assert!(library.reread_song(&uuid).is_err());
+    }
+
+    #[test]
+    fn refresh_keeps_library_fields() {
+        use crate::music_storage::tag_writing::tests::{flac, temp_file};
+
+        let path = temp_file("flac", flac(&["TITLE=Draft", "ALBUM=Demos"]));
+        let mut song = Song::from_file(&path).unwrap();
+        song.plays = 7;
+        song.rating = Some(4);
+        song.trim = Some((Duration::from_secs(1), Duration::from_secs(2)));
+        song.internal_tags.push(InternalTag::AlbumFavorite);
+        song.tags.insert(
+            Tag::Key("ReplayGainTrackGain".to_string()),
+            "-3.00 dB".to_string(),
+        );
+        let uuid = song.uuid;
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        library.library.push(song);
+
+        // The tags are fixed up by another program
+        std::fs::write(
+            &path,
+            flac(&["TITLE=Final", "ALBUM=Demos", "ARTIST=Kalafina"]),
+        )
+        .unwrap();
+        let missing = Uuid::new_v4();
+        let report = library.refresh_songs(&[uuid, missing]);
+        assert_eq!(report.failed, [(missing, "Song not found".to_string())]);
+        let song = &report.refreshed[0];
+        assert_eq!(song.uuid, uuid);
+        assert_eq!(song.get_tag(&Tag::Title).unwrap(), "Final");
+        assert_eq!(song.get_tag(&Tag::Artist).unwrap(), "Kalafina");
+        assert_eq!(
+            song.get_tag(&Tag::Key("ReplayGainTrackGain".to_string()))
+                .unwrap(),
+            "-3.00 dB"
+        );
+        assert_eq!((song.plays, song.rating), (7, Some(4)));
+        assert_eq!(song.internal_tags, [InternalTag::AlbumFavorite]);
+        assert!(song.trim.is_some());
+        assert_eq!(library.library[0], *song);
+
+        let album = AlbumKey {
+            title: "Demos".to_string(),
+            artist: None,
+        };
+        assert_eq!(library.refresh_album(&album).unwrap().refreshed.len(), 1);
+        let unknown = AlbumKey {
+            title: "Singles".to_string(),
+            artist: None,
+        };
+        assert!(library.refresh_album(&unknown).is_err());
+    }
+
+    #[test]
+    fn trim_points() {
+        let mut library = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let song = Song {
+            uuid: Uuid::new_v4(),
+            duration: Duration::from_secs(240),
+            ..Default::default()
+        };
+        let uuid = song.uuid;
+        library.library.push(song);
+
+        let secs = Duration::from_secs;
+        assert!(library.set_trim(&uuid, Some((secs(30), secs(30)))).is_err());
+        assert!(library.set_trim(&uuid, Some((secs(60), secs(30)))).is_err());
+        assert!(library
+            .set_trim(&uuid, Some((secs(30), secs(241))))
+            .is_err());
+        assert_eq!(library.library[0].trim, None);
+
+        library
+            .set_trim(&uuid, Some((secs(12), secs(240))))
+            .unwrap();
+        assert_eq!(library.library[0].trim, Some((secs(12), secs(240))));
+        assert_eq!(
+            SongDetails::read(&library.library[0]).trim,
+            Some((secs(12), secs(240)))
+        );
+
+        // Clearing goes back to the whole file
+        library.set_trim(&uuid, None).unwrap();
+        assert_eq!(library.library[0].trim, None);
+
+        assert_eq!(
+            library.set_trim(&Uuid::new_v4(), None),
+            Err("Song not found".to_string())
+        );
+    }
+}
@@ -0,0 +1,368 @@
+//! Finding songs which are other versions of a song in the library, like
+//! the instrumental or TV size cut of an anime opening, so they can be
+//! linked together once the user confirms them
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    library::{InternalTag, MusicLibrary, Song, SongType, Tag},
+    utils::normalize,
+};
+
+/// The custom [`SongType`] of shortened versions made for TV
+pub const TV_SIZE: &str = "TV Size";
+
+/// Brackets a version marker can be wrapped in at the end of a title, as
+/// `(closing, opening)`. Dashes and tildes wrap markers like `-inst-`.
+const MARKER_BRACKETS: [(char, char); 8] = [
+    (')', '('),
+    (']', '['),
+    ('）', '（'),
+    ('】', '【'),
+    ('」', '「'),
+    ('-', '-'),
+    ('~', '~'),
+    ('～', '～'),
+];
+
+/// Songs proposed to be linked as other versions of `main`
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct LinkGroup {
+    pub main: Uuid,
+    /// The other versions, and what kind of version each one is
+    pub versions: Vec<(Uuid, SongType)>,
+}
+
+/// What kind of version a marker like "Off Vocal Ver." means, if it's one
+/// which is known
+fn marker_type(marker: &str) -> Option<SongType> {
+    let marker = marker
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    let marker = marker
+        .strip_suffix("version")
+        .or_else(|| marker.strip_suffix("ver"))
+        .unwrap_or(&marker);
+
+    match marker {
+        "instrumental"
+        | "inst"
+        | "offvocal"
+        | "offvo"
+        | "karaoke"
+        | "originalkaraoke"
+        | "backingtrack"
+        | "インスト"
+        | "オフボーカル"
+        | "オフヴォーカル"
+        | "カラオケ"
+        | "オリジナルカラオケ" => Some(SongType::Instrumental),
+        "tvsize" | "tv" | "tvedit" | "tvサイズ" => Some(SongType::Custom(TV_SIZE.to_string())),
+        _ => None,
+    }
+}
+
+/// Splits a version marker off the end of a title, so `"Song (off vocal)"`
+/// becomes `"Song"` and [`SongType::Instrumental`]. Markers have to be set
+/// apart by brackets, dashes, or a `" - "`, so titles which only happen to
+/// end in a word like "Instrumental" are left alone.
+pub fn split_version(title: &str) -> Option<(&str, SongType)> {
+    let title = title.trim_end();
+    let last = title.chars().last()?;
+
+    let (base, marker) = match MARKER_BRACKETS.iter().find(|(close, _)| *close == last) {
+        Some(&(_, open)) => {
+            let inner = &title[..title.len() - last.len_utf8()];
+            let start = inner.rfind(open)?;
+            (&title[..start], &inner[start + open.len_utf8()..])
+        }
+        None => title.rsplit_once(" - ")?,
+    };
+
+    let base = base.trim_end();
+    if base.is_empty() {
+        return None;
+    }
+    Some((base, marker_type(marker)?))
+}
+
+/// Songs are only matched up within the same artist, by their title
+fn version_key(song: &Song, title: &str) -> Option<(String, String)> {
+    let artist = normalize(song.get_tag(&Tag::Artist)?);
+    let title = normalize(title);
+    if artist.is_empty() || title.is_empty() {
+        return None;
+    }
+    Some((artist, title))
+}
+
+fn album_of(song: &Song) -> Option<String> {
+    song.get_tag(&Tag::Album).map(|album| normalize(album))
+}
+
+fn is_linked(song: &Song, other: Uuid) -> bool {
+    song.internal_tags
+        .iter()
+        .any(|tag| matches!(tag, InternalTag::SongLink(uuid, _) if *uuid == other))
+}
+
+impl MusicLibrary {
+    /// Finds songs whose titles only differ from another song by the same
+    /// artist by a version marker, like "(Instrumental)" or "(TV Size)".
+    ///
+    /// Nothing is changed, the groups are only proposals to be confirmed
+    /// and then passed to [`MusicLibrary::link_versions`]. Versions which
+    /// could belong to more than one song are left out, unless only one of
+    /// them is on the same album.
+    pub fn detect_linked_versions(&self) -> Vec<LinkGroup> {
+        let mut mains: HashMap<(String, String), Vec<usize>> = HashMap::new();
+        let mut versions = Vec::new();
+        for (i, song) in self.library.iter().enumerate() {
+            let Some(title) = song.get_tag(&Tag::Title) else {
+                continue;
+            };
+            match split_version(title) {
+                Some((base, song_type)) => {
+                    if let Some(key) = version_key(song, base) {
+                        versions.push((i, key, song_type));
+                    }
+                }
+                None => {
+                    if let Some(key) = version_key(song, title) {
+                        mains.entry(key).or_default().push(i);
+                    }
+                }
+            }
+        }
+
+        // Keyed by index, so groups come out in library order
+        let mut groups: BTreeMap<usize, Vec<(Uuid, SongType)>> = BTreeMap::new();
+        for (i, key, song_type) in versions {
+            let Some(candidates) = mains.get(&key) else {
+                continue;
+            };
+            let version = &self.library[i];
+            let main = match candidates.as_slice() {
+                [main] => *main,
+                candidates => {
+                    let album = album_of(version);
+                    let same_album: Vec<_> = candidates
+                        .iter()
+                        .filter(|&&main| album.is_some() && album_of(&self.library[main]) == album)
+                        .collect();
+                    match same_album.as_slice() {
+                        [main] => **main,
+                        _ => continue,
+                    }
+                }
+            };
+
+            if is_linked(version, self.library[main].uuid) {
+                continue;
+            }
+            groups
+                .entry(main)
+                .or_default()
+                .push((version.uuid, song_type));
+        }
+
+        groups
+            .into_iter()
+            .map(|(main, versions)| LinkGroup {
+                main: self.library[main].uuid,
+                versions,
+            })
+            .collect()
+    }
+
+    /// Links the songs of each group to its main song, both ways, and tags
+    /// each version with its [`SongType`]. Returns how many versions were
+    /// linked, leaving out songs which aren't in the library.
+    pub fn link_versions(&mut self, groups: &[LinkGroup]) -> usize {
+        let mut linked = 0;
+        for group in groups {
+            if self.query_uuid(&group.main).is_none() {
+                continue;
+            }
+
+            for (uuid, song_type) in &group.versions {
+                if *uuid == group.main {
+                    continue;
+                }
+                let Some(version) = self.library.iter_mut().find(|song| song.uuid == *uuid) else {
+                    continue;
+                };
+                version
+                    .internal_tags
+                    .retain(|tag| !matches!(tag, InternalTag::SongType(_)));
+                version
+                    .internal_tags
+                    .push(InternalTag::SongType(song_type.clone()));
+                if !is_linked(version, group.main) {
+                    version
+                        .internal_tags
+                        .push(InternalTag::SongLink(group.main, SongType::Main));
+                }
+
+                let main = self
+                    .library
+                    .iter_mut()
+                    .find(|song| song.uuid == group.main)
+                    .unwrap();
+                if !is_linked(main, *uuid) {
+                    main.internal_tags
+                        .push(InternalTag::SongLink(*uuid, song_type.clone()));
+                }
+                linked += 1;
+            }
+        }
+        linked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{split_version, LinkGroup, TV_SIZE};
+    use crate::music_storage::library::{InternalTag, MusicLibrary, Song, SongType, Tag};
+
+    fn song(title: &str, artist: &str, album: &str) -> Song {
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            ..Default::default()
+        };
+        song.set_tag(Tag::Title, title.to_string());
+        song.set_tag(Tag::Artist, artist.to_string());
+        song.set_tag(Tag::Album, album.to_string());
+        song
+    }
+
+    fn tv_size() -> SongType {
+        SongType::Custom(TV_SIZE.to_string())
+    }
+
+    #[test]
+    fn version_markers() {
+        for (title, base, song_type) in [
+            (
+                "君の知らない物語 (Instrumental)",
+                "君の知らない物語",
+                SongType::Instrumental,
+            ),
+            (
+                "コネクト（オリジナル・カラオケ）",
+                "コネクト",
+                SongType::Instrumental,
+            ),
+            (
+                "only my railgun -instrumental-",
+                "only my railgun",
+                SongType::Instrumental,
+            ),
+            (
+                "ストリーミングハート [Off Vocal Ver.]",
+                "ストリーミングハート",
+                SongType::Instrumental,
+            ),
+            (
+                "Hacking to the Gate -inst-",
+                "Hacking to the Gate",
+                SongType::Instrumental,
+            ),
+            ("紅蓮華 (TV size)", "紅蓮華", tv_size()),
+            ("God knows... ～TV Ver.～", "God knows...", tv_size()),
+            (
+                "secret base - Karaoke",
+                "secret base",
+                SongType::Instrumental,
+            ),
+        ] {
+            assert_eq!(split_version(title), Some((base, song_type)), "{title}");
+        }
+
+        for title in [
+            "Instrumental",
+            "(Instrumental)",
+            "Dreamer (Remix)",
+            "Re-Re-",
+            "Song for an Instrumental",
+        ] {
+            assert_eq!(split_version(title), None, "{title}");
+        }
+    }
+
+    #[test]
+    fn detect_and_link() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("コネクト", "ClariS", "コネクト"),
+            song("ルミナス", "ClariS", "コネクト"),
+            song("コネクト (TV size)", "ClariS", "コネクト"),
+            song("コネクト（オリジナル・カラオケ）", "ClariS", "コネクト"),
+            song("ルミナス -off vocal-", "ClariS", "コネクト"),
+            // Another artist's cover isn't a version of the original
+            song("コネクト (Instrumental)", "Someone Else", "Covers"),
+            // Without the song itself there's nothing to link to
+            song("irony (Karaoke)", "ClariS", "irony"),
+            // The same song on two albums, only one of which has a version
+            song("secret base", "ZONE", "secret base"),
+            song("secret base", "ZONE", "ZONE BEST"),
+            song("secret base (Instrumental)", "ZONE", "secret base"),
+        ];
+        let uuids: Vec<Uuid> = lib.library.iter().map(|song| song.uuid).collect();
+        let uuid = |i: usize| uuids[i];
+
+        let groups = lib.detect_linked_versions();
+        assert_eq!(
+            groups,
+            vec![
+                LinkGroup {
+                    main: uuid(0),
+                    versions: vec![(uuid(2), tv_size()), (uuid(3), SongType::Instrumental)],
+                },
+                LinkGroup {
+                    main: uuid(1),
+                    versions: vec![(uuid(4), SongType::Instrumental)],
+                },
+                LinkGroup {
+                    main: uuid(7),
+                    versions: vec![(uuid(9), SongType::Instrumental)],
+                },
+            ]
+        );
+
+        // Only the confirmed groups are applied
+        assert_eq!(lib.link_versions(&groups[..1]), 2);
+        assert!(lib.library[0]
+            .internal_tags
+            .contains(&InternalTag::SongLink(uuid(3), SongType::Instrumental)));
+        assert!(lib.library[2]
+            .internal_tags
+            .contains(&InternalTag::SongLink(uuid(0), SongType::Main)));
+        assert!(lib.library[2]
+            .internal_tags
+            .contains(&InternalTag::SongType(tv_size())));
+
+        // Linked versions aren't proposed again
+        let groups = lib.detect_linked_versions();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].main, uuid(1));
+    }
+
+    #[test]
+    fn ambiguous_versions_left_out() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("secret base", "ZONE", "secret base"),
+            song("secret base", "ZONE", "ZONE BEST"),
+            song("secret base (Instrumental)", "ZONE", "Karaoke Collection"),
+        ];
+        assert!(lib.detect_linked_versions().is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+//! Finding songs which can't really be played, like failed rips which are
+//! silent or claim to have no length. They're marked with
+//! [`InternalTag::Problem`] and kept out of the songs queued up
+//! automatically, until they're checked again and found to be fine.
+
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    decode::{decode, DecodeError},
+    gain_analysis::AnalyzeScope,
+    gain_staging::{ReplayGainMode, ResolvedProfile},
+    integrity::VerifyProgress,
+    library::{InternalTag, MusicLibrary, Song, Tag, URI},
+};
+
+/// The loudest a song can be after its ReplayGain adjustment and still
+/// count as silent, about -60 dBFS
+pub const SILENCE_THRESHOLD: f32 = 0.001;
+
+/// How much of the start and the end of a song is decoded to check it
+const SAMPLE_LENGTH: Duration = Duration::from_secs(10);
+
+/// Nothing in a music library is this long, so a song which says it is has
+/// a broken header
+const MAX_PLAUSIBLE_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SongProblem {
+    /// The file says the song has no length
+    ZeroDuration,
+    /// The song is far too long, or its end can't be decoded, so the file
+    /// is truncated or its header is wrong
+    ImplausibleDuration,
+    /// Both the start and the end of the song are silent
+    Silent,
+}
+
+/// A song marked with a problem, for listing them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlaggedSong {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    #[serde(with = "super::os_path")]
+    pub path: PathBuf,
+    pub problem: SongProblem,
+}
+
+impl Song {
+    /// The problem the song was last found to have
+    pub fn problem(&self) -> Option<SongProblem> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::Problem(problem) => Some(*problem),
+            _ => None,
+        })
+    }
+
+    /// Marks the song with `problem`, or clears the mark with `None`
+    pub fn set_problem(&mut self, problem: Option<SongProblem>) {
+        self.internal_tags
+            .retain(|tag| !matches!(tag, InternalTag::Problem(_)));
+        self.internal_tags.extend(problem.map(InternalTag::Problem));
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ProblemReport {
+    pub checked: usize,
+    /// The songs found to have a problem
+    pub flagged: Vec<FlaggedSong>,
+    /// The number of songs which were marked before and are fine now
+    pub cleared: usize,
+    /// Songs whose files couldn't be decoded, which are left as they were
+    pub failed: Vec<(Uuid, String)>,
+    /// The marks to store on the songs
+    #[serde(skip)]
+    pub problems: Vec<(Uuid, Option<SongProblem>)>,
+}
+
+/// Checks each song with [`find_problem`], calling `progress` after each
+/// one. Returns `None` if `progress` returned `false` to cancel the check.
+pub fn check_songs(
+    songs: &[Song],
+    mut progress: impl FnMut(VerifyProgress) -> bool,
+) -> Option<ProblemReport> {
+    let mut report = ProblemReport::default();
+    for (done, song) in songs.iter().enumerate() {
+        match find_problem(song) {
+            Ok(problem) => {
+                report.checked += 1;
+                if let (Some(problem), Some(uri)) = (problem, song.location.first()) {
+                    report.flagged.push(FlaggedSong {
+                        uuid: song.uuid,
+                        title: song.get_tag(&Tag::Title).cloned(),
+                        path: uri.path(),
+                        problem,
+                    });
+                } else if problem.is_none() && song.problem().is_some() {
+                    report.cleared += 1;
+                }
+                report.problems.push((song.uuid, problem));
+            }
+            Err(e) => report.failed.push((song.uuid, e.to_string())),
+        }
+
+        let total = songs.len();
+        if !progress(VerifyProgress {
+            done: done + 1,
+            total,
+        }) {
+            return None;
+        }
+    }
+    Some(report)
+}
+
+/// Checks a song's length, then decodes the first and last
+/// [`SAMPLE_LENGTH`] of it to see whether it's silent. Songs without a
+/// local file aren't checked.
+pub fn find_problem(song: &Song) -> Result<Option<SongProblem>, DecodeError> {
+    if song.duration.is_zero() {
+        return Ok(Some(SongProblem::ZeroDuration));
+    }
+    if song.duration > MAX_PLAUSIBLE_DURATION {
+        return Ok(Some(SongProblem::ImplausibleDuration));
+    }
+
+    let (path, start) = match song.location.first() {
+        Some(URI::Local(path)) => (path, Duration::ZERO),
+        Some(URI::Cue {
+            location, start, ..
+        }) => (location, *start),
+        _ => return Ok(None),
+    };
+    let end = start + song.duration;
+    let ranges = match song.duration > SAMPLE_LENGTH * 2 {
+        true => vec![(start, start + SAMPLE_LENGTH), (end - SAMPLE_LENGTH, end)],
+        false => vec![(start, end)],
+    };
+
+    let mut peak: f32 = 0.0;
+    for range in ranges {
+        let mut decoded = false;
+        decode(
+            path,
+            Some(range),
+            |samples, _, _| {
+                decoded |= !samples.is_empty();
+                peak = samples.iter().fold(peak, |peak, s| peak.max(s.abs()));
+            },
+            |_| true,
+        )?;
+        // A file which ends before its header says it does
+        if !decoded {
+            return Ok(Some(SongProblem::ImplausibleDuration));
+        }
+    }
+
+    // A quiet song which ReplayGain raises a lot can still be heard
+    let gain = ResolvedProfile {
+        replay_gain_mode: ReplayGainMode::Track,
+        ..Default::default()
+    }
+    .song_gain(song);
+    Ok((peak * gain < SILENCE_THRESHOLD).then_some(SongProblem::Silent))
+}
+
+impl MusicLibrary {
+    /// The songs in `scope`, cloned so they can be checked off the library
+    pub fn songs_in_scope(&self, scope: &AnalyzeScope) -> Vec<Song> {
+        match scope {
+            AnalyzeScope::Album(key) => self
+                .query_album(key)
+                .map(|album| {
+                    album
+                        .discs()
+                        .values()
+                        .flatten()
+                        .filter_map(|(_, uuid)| self.query_uuid(uuid))
+                        .map(|(song, _)| song.clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            AnalyzeScope::Songs(uuids) => uuids
+                .iter()
+                .filter_map(|uuid| self.query_uuid(uuid))
+                .map(|(song, _)| song.clone())
+                .collect(),
+            AnalyzeScope::WholeLibrary => self.library.clone(),
+        }
+    }
+
+    /// Marks songs with the problems they were found to have, clearing the
+    /// mark from the ones found to be fine
+    pub fn set_problems(&mut self, problems: &[(Uuid, Option<SongProblem>)]) {
+        for (uuid, problem) in problems {
+            if let Some(song) = self.library.iter_mut().find(|song| song.uuid == *uuid) {
+                song.set_problem(*problem);
+            }
+        }
+    }
+
+    /// Every song marked with a problem
+    pub fn flagged_songs(&self) -> Vec<FlaggedSong> {
+        self.library
+            .iter()
+            .filter_map(|song| {
+                Some(FlaggedSong {
+                    uuid: song.uuid,
+                    title: song.get_tag(&Tag::Title).cloned(),
+                    path: song.location.first()?.path(),
+                    problem: song.problem()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{check_songs, find_problem, SongProblem};
+    use crate::music_storage::{
+        gain_analysis::TRACK_GAIN,
+        library::{Song, Tag, URI},
+    };
+
+    const RATE: u32 = 8000;
+
+    /// Writes a mono 16 bit WAV file with `samples`, whose header claims it
+    /// has `claimed` samples
+    fn write_wav(path: &Path, samples: &[i16], claimed: usize) {
+        let data_len = (claimed * 2) as u32;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(RATE.to_le_bytes());
+        wav.extend((RATE * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        wav.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+        fs::write(path, wav).unwrap();
+    }
+
+    fn song(path: &Path, seconds: u64) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(path.to_path_buf())],
+            duration: Duration::from_secs(seconds),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn silent_and_truncated_files() {
+        let dir = std::env::temp_dir().join(format!("dmp-problems-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let len = RATE as usize * 30;
+
+        let silent = dir.join("silent.wav");
+        write_wav(&silent, &vec![0; len], len);
+        assert_eq!(
+            find_problem(&song(&silent, 30)).unwrap(),
+            Some(SongProblem::Silent)
+        );
+
+        // A tone which is only there in the middle is still missed, as only
+        // the ends are checked, but one at the end isn't
+        let tone: Vec<i16> = (0..len)
+            .map(|i| match i > len - RATE as usize {
+                true => ((i as f32 / 10.0).sin() * 8000.0) as i16,
+                false => 0,
+            })
+            .collect();
+        let ending = dir.join("ending.wav");
+        write_wav(&ending, &tone, len);
+        assert_eq!(find_problem(&song(&ending, 30)).unwrap(), None);
+
+        // Quiet, but ReplayGain brings it up to be heard
+        let quiet: Vec<i16> = (0..len).map(|i| (i % 3) as i16 * 10).collect();
+        let quiet_path = dir.join("quiet.wav");
+        write_wav(&quiet_path, &quiet, len);
+        let mut quiet = song(&quiet_path, 30);
+        assert_eq!(find_problem(&quiet).unwrap(), Some(SongProblem::Silent));
+        quiet.set_tag(Tag::Key(TRACK_GAIN.to_string()), "+40.0 dB".to_string());
+        assert_eq!(find_problem(&quiet).unwrap(), None);
+
+        // Cut off a third of the way through
+        let truncated = dir.join("truncated.wav");
+        write_wav(&truncated, &tone[..len / 3], len);
+        assert_eq!(
+            find_problem(&song(&truncated, 30)).unwrap(),
+            Some(SongProblem::ImplausibleDuration)
+        );
+
+        assert_eq!(
+            find_problem(&song(&silent, 0)).unwrap(),
+            Some(SongProblem::ZeroDuration)
+        );
+
+        // Re-checking a song which was fixed clears its mark
+        let mut fixed = song(&ending, 30);
+        fixed.set_problem(Some(SongProblem::Silent));
+        let songs = [fixed, song(&silent, 30), song(&dir.join("gone.wav"), 30)];
+        let report = check_songs(&songs, |_| true).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.cleared, 1);
+        assert_eq!(report.flagged.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert!(check_songs(&songs, |_| false).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn problem_marks() {
+        let mut song = Song::default();
+        assert_eq!(song.problem(), None);
+        song.set_problem(Some(SongProblem::Silent));
+        song.set_problem(Some(SongProblem::ZeroDuration));
+        assert_eq!(song.problem(), Some(SongProblem::ZeroDuration));
+        assert_eq!(song.internal_tags.len(), 1);
+        song.set_problem(None);
+        assert!(song.internal_tags.is_empty());
+    }
+}
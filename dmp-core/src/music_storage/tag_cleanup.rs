@@ -0,0 +1,463 @@
+//! Rules for tidying up messy tags, like stray whitespace and inconsistent
+//! featuring notation, which can be previewed before being applied
+
+use std::path::PathBuf;
+
+use lofty::tag::ItemKey;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::ConfigTagWriting;
+
+use super::{
+    library::{MusicLibrary, Song, Tag, URI},
+    tag_writing::write_tag_items,
+};
+
+/// The tags cleanup rules are applied to
+const CLEANED_TAGS: [Tag; 5] = [
+    Tag::Title,
+    Tag::Album,
+    Tag::Artist,
+    Tag::AlbumArtist,
+    Tag::Genre,
+];
+
+/// Spellings of "featuring" which are normalized to `feat.`
+const FEATURING: [&str; 6] = ["feat.", "feat", "ft.", "ft", "featuring", "feat:"];
+
+/// Words which stay lowercase in title case, unless they start or end it
+const SMALL_WORDS: [&str; 17] = [
+    "a", "an", "and", "as", "at", "but", "by", "feat.", "for", "in", "nor", "of", "on", "or",
+    "the", "to", "vs.",
+];
+
+/// A rule for cleaning up tags. Rules are always applied in the order they
+/// are declared in, so [`CleanRule::SentenceCase`] wins over
+/// [`CleanRule::TitleCase`] if both are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum CleanRule {
+    /// Removes leading and trailing whitespace
+    TrimWhitespace,
+    /// Turns runs of whitespace into a single space
+    CollapseSpaces,
+    /// Writes every spelling of "featuring" as `feat.`
+    NormalizeFeaturing,
+    /// Removes suffixes like "(Disc 1)" from album names
+    StripDiscSuffix,
+    /// Capitalizes titles and album names like "The Sound of Silence"
+    TitleCase,
+    /// Capitalizes titles and album names like "The sound of silence"
+    SentenceCase,
+}
+
+impl CleanRule {
+    /// Applies the rule to the value of a tag
+    pub fn apply(&self, tag: &Tag, value: &str) -> String {
+        match (self, tag) {
+            (CleanRule::TrimWhitespace, _) => value.trim().to_string(),
+            (CleanRule::CollapseSpaces, _) => collapse_spaces(value),
+            (CleanRule::NormalizeFeaturing, Tag::Title | Tag::Artist | Tag::AlbumArtist) => {
+                normalize_featuring(value)
+            }
+            (CleanRule::StripDiscSuffix, Tag::Album) => strip_disc_suffix(value),
+            (CleanRule::TitleCase, Tag::Title | Tag::Album) => title_case(value),
+            (CleanRule::SentenceCase, Tag::Title | Tag::Album) => sentence_case(value),
+            _ => value.to_string(),
+        }
+    }
+}
+
+/// A change to a single tag of a song
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagChange {
+    pub uuid: Uuid,
+    pub tag: Tag,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TagCleanup {
+    pub changes: Vec<TagChange>,
+    /// Files which the changes could not be written back to, and why
+    pub failed_writes: Vec<(PathBuf, String)>,
+}
+
+impl MusicLibrary {
+    /// Cleans up the tags of every song with the given rules, returning
+    /// what changed. When `dry_run` is set nothing is modified, and the
+    /// changes are only a preview of what applying the rules would do.
+    ///
+    /// `write_back` also writes the changes to the tags of the files
+    /// themselves, in the format it gives, which is skipped for songs from
+    /// CUE sheets.
+    pub fn clean_tags(
+        &mut self,
+        rules: &[CleanRule],
+        dry_run: bool,
+        write_back: Option<&ConfigTagWriting>,
+    ) -> TagCleanup {
+        let mut rules = rules.to_vec();
+        rules.sort();
+        rules.dedup();
+
+        let mut cleanup = TagCleanup::default();
+        for song in &mut self.library {
+            let changes = song_changes(song, &rules);
+            if dry_run || changes.is_empty() {
+                cleanup.changes.extend(changes);
+                continue;
+            }
+
+            for change in &changes {
+                song.set_tag(change.tag.clone(), change.new.clone());
+            }
+            if let Some(options) = write_back {
+                if let Err(e) = write_tags(song, &changes, options) {
+                    let path = song.location.first().map(|l| l.path()).unwrap_or_default();
+                    cleanup.failed_writes.push((path, e));
+                }
+            }
+            cleanup.changes.extend(changes);
+        }
+
+        cleanup
+    }
+}
+
+fn song_changes(song: &Song, rules: &[CleanRule]) -> Vec<TagChange> {
+    CLEANED_TAGS
+        .iter()
+        .filter_map(|tag| {
+            let old = song.get_tag(tag)?;
+            let new = rules
+                .iter()
+                .fold(old.clone(), |value, rule| rule.apply(tag, &value));
+
+            (&new != old).then(|| TagChange {
+                uuid: song.uuid,
+                tag: tag.clone(),
+                old: old.clone(),
+                new,
+            })
+        })
+        .collect()
+}
+
+fn write_tags(
+    song: &Song,
+    changes: &[TagChange],
+    options: &ConfigTagWriting,
+) -> Result<(), String> {
+    let Some(URI::Local(path)) = song.location.first() else {
+        return Ok(());
+    };
+
+    let items = changes.iter().filter_map(|change| {
+        let key = match change.tag {
+            Tag::Title => ItemKey::TrackTitle,
+            Tag::Album => ItemKey::AlbumTitle,
+            Tag::Artist => ItemKey::TrackArtist,
+            Tag::AlbumArtist => ItemKey::AlbumArtist,
+            Tag::Genre => ItemKey::Genre,
+            _ => return None,
+        };
+        Some((key, change.new.clone()))
+    });
+    write_tag_items(path, items, options)
+}
+
+fn collapse_spaces(value: &str) -> String {
+    let mut collapsed = String::with_capacity(value.len());
+    let mut last_was_space = false;
+    for c in value.chars() {
+        if c.is_whitespace() {
+            if !last_was_space {
+                collapsed.push(' ');
+            }
+            last_was_space = true;
+        } else {
+            collapsed.push(c);
+            last_was_space = false;
+        }
+    }
+    collapsed
+}
+
+fn normalize_featuring(value: &str) -> String {
+    value
+        .split(' ')
+        .map(|word| {
+            let inner = word.trim_start_matches(['(', '[']);
+            let prefix = &word[..word.len() - inner.len()];
+            if FEATURING.contains(&inner.to_lowercase().as_str()) {
+                format!("{prefix}feat.")
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether some text is a disc label like "Disc 2" or "CD1"
+fn is_disc_label(text: &str) -> bool {
+    let text = text.trim().to_ascii_lowercase();
+    let number = ["disc", "disk", "cd"]
+        .iter()
+        .find_map(|label| text.strip_prefix(label));
+    matches!(number, Some(n) if !n.trim().is_empty() && n.trim().chars().all(|c| c.is_ascii_digit()))
+}
+
+fn strip_disc_suffix(value: &str) -> String {
+    let trimmed = value.trim_end();
+    let tidy = |base: &str| {
+        base.trim_end()
+            .trim_end_matches(['-', ':', ','])
+            .trim_end()
+            .to_string()
+    };
+
+    // Bracketed labels, like "Album (Disc 1)" or "Album [CD 2]"
+    for (open, close) in [('(', ')'), ('[', ']')] {
+        if let Some(inner) = trimmed.strip_suffix(close) {
+            if let Some(start) = inner.rfind(open) {
+                if is_disc_label(&inner[start + 1..]) && start > 0 {
+                    return tidy(&inner[..start]);
+                }
+            }
+        }
+    }
+
+    // Bare labels, like "Album - Disc 1" or "Album CD2"
+    let lowercase = trimmed.to_ascii_lowercase();
+    for label in ["disc", "disk", "cd"] {
+        if let Some(start) = lowercase.rfind(label) {
+            let separated = lowercase[..start].ends_with([' ', '-']);
+            if start > 0 && separated && is_disc_label(&trimmed[start..]) {
+                let base = tidy(&trimmed[..start]);
+                if !base.is_empty() {
+                    return base;
+                }
+            }
+        }
+    }
+
+    value.to_string()
+}
+
+/// Uppercases the first letter of a word, skipping leading punctuation
+fn capitalize(word: &str) -> String {
+    match word.char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((i, c)) => {
+            let rest = &word[i + c.len_utf8()..];
+            format!("{}{}{rest}", &word[..i], c.to_uppercase())
+        }
+        None => word.to_string(),
+    }
+}
+
+/// Words like "AC/DC" or "USA" which should keep their casing
+fn is_acronym(word: &str) -> bool {
+    word.chars().filter(|c| c.is_alphabetic()).count() > 1
+        && !word.chars().any(|c| c.is_lowercase())
+}
+
+fn title_case(value: &str) -> String {
+    let words = value.split(' ').collect::<Vec<_>>();
+    let last = words.len().saturating_sub(1);
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let lowercase = word.to_lowercase();
+            let small = SMALL_WORDS.contains(&lowercase.trim_start_matches(['(', '[']));
+            if is_acronym(word) {
+                word.to_string()
+            } else if i != 0 && i != last && small {
+                lowercase
+            } else {
+                capitalize(word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn sentence_case(value: &str) -> String {
+    value
+        .split(' ')
+        .enumerate()
+        .map(|(i, word)| {
+            if is_acronym(word) || word == "I" || word.starts_with("I'") {
+                word.to_string()
+            } else if i == 0 {
+                capitalize(&word.to_lowercase())
+            } else {
+                word.to_lowercase()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use uuid::Uuid;
+
+    use super::CleanRule;
+    use crate::music_storage::library::{MusicLibrary, Song, Tag};
+
+    fn apply(rule: CleanRule, tag: Tag, value: &str) -> String {
+        rule.apply(&tag, value)
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(
+            apply(CleanRule::TrimWhitespace, Tag::Title, "  Song \t"),
+            "Song"
+        );
+        assert_eq!(
+            apply(CleanRule::CollapseSpaces, Tag::Album, "An  Album \t Name"),
+            "An Album Name"
+        );
+    }
+
+    #[test]
+    fn featuring() {
+        for value in [
+            "Song (Feat. Someone)",
+            "Song (ft. Someone)",
+            "Song (featuring Someone)",
+            "Song (FT Someone)",
+        ] {
+            assert_eq!(
+                apply(CleanRule::NormalizeFeaturing, Tag::Title, value),
+                "Song (feat. Someone)"
+            );
+        }
+        assert_eq!(
+            apply(
+                CleanRule::NormalizeFeaturing,
+                Tag::Artist,
+                "Artist Ft. Other"
+            ),
+            "Artist feat. Other"
+        );
+        // Only for tags where featuring shows up
+        assert_eq!(
+            apply(CleanRule::NormalizeFeaturing, Tag::Album, "Ft. Album"),
+            "Ft. Album"
+        );
+    }
+
+    #[test]
+    fn disc_suffixes() {
+        for value in [
+            "Album (Disc 1)",
+            "Album [CD 2]",
+            "Album - Disc 3",
+            "Album CD1",
+            "Album (disk 10)",
+        ] {
+            assert_eq!(
+                apply(CleanRule::StripDiscSuffix, Tag::Album, value),
+                "Album"
+            );
+        }
+        for value in ["Disc 1", "The Discovery", "Album (Live)", "Abacd 2"] {
+            assert_eq!(apply(CleanRule::StripDiscSuffix, Tag::Album, value), value);
+        }
+        assert_eq!(
+            apply(CleanRule::StripDiscSuffix, Tag::Title, "Song (Disc 1)"),
+            "Song (Disc 1)"
+        );
+    }
+
+    #[test]
+    fn casing() {
+        assert_eq!(
+            apply(CleanRule::TitleCase, Tag::Title, "the sound of silence"),
+            "The Sound of Silence"
+        );
+        assert_eq!(
+            apply(
+                CleanRule::TitleCase,
+                Tag::Title,
+                "song (feat. someone) by AC/DC"
+            ),
+            "Song (feat. Someone) by AC/DC"
+        );
+        assert_eq!(
+            apply(CleanRule::SentenceCase, Tag::Album, "The Sound Of Silence"),
+            "The sound of silence"
+        );
+        assert_eq!(
+            apply(CleanRule::SentenceCase, Tag::Title, "I Like The USA"),
+            "I like the USA"
+        );
+        assert_eq!(
+            apply(CleanRule::TitleCase, Tag::Artist, "the artist"),
+            "the artist"
+        );
+    }
+
+    fn library() -> MusicLibrary {
+        let song = |title: &str, album: &str| Song {
+            uuid: Uuid::new_v4(),
+            tags: BTreeMap::from([
+                (Tag::Title, title.to_string()),
+                (Tag::Album, album.to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library = vec![
+            song("  first song  (Ft. Someone)", "Album  (Disc 1)"),
+            song("Second Song", "Album (Disc 2)"),
+            song("Already Clean", "Album"),
+        ];
+        lib
+    }
+
+    #[test]
+    fn dry_run_matches_apply() {
+        let rules = [
+            CleanRule::TitleCase,
+            CleanRule::TrimWhitespace,
+            CleanRule::CollapseSpaces,
+            CleanRule::NormalizeFeaturing,
+            CleanRule::StripDiscSuffix,
+        ];
+
+        let mut lib = library();
+        let before = lib.library.clone();
+        let preview = lib.clean_tags(&rules, true, None);
+        assert_eq!(lib.library, before);
+
+        let applied = lib.clean_tags(&rules, false, None);
+        assert_eq!(preview, applied);
+        assert_eq!(applied.changes.len(), 3);
+
+        for change in &applied.changes {
+            let (song, _) = lib.query_uuid(&change.uuid).unwrap();
+            assert_eq!(song.get_tag(&change.tag), Some(&change.new));
+        }
+        assert_eq!(
+            lib.library[0].get_tag(&Tag::Title).unwrap(),
+            "First Song (feat. Someone)"
+        );
+        assert!(lib
+            .library
+            .iter()
+            .all(|song| song.get_tag(&Tag::Album).unwrap() == "Album"));
+
+        // Everything is clean now, so there is nothing left to do
+        assert!(lib.clean_tags(&rules, true, None).changes.is_empty());
+    }
+}
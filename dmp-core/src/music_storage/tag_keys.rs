@@ -0,0 +1,173 @@
+//! How the tags of a song are stored in the library file. The tags map is
+//! written with string keys rather than the [`Tag`] enum itself, so adding
+//! or reordering variants can't change what an existing library means.
+
+use std::{collections::BTreeMap, convert::Infallible, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+use super::library::Tag;
+
+/// Marks a [`Tag::Key`] whose name would otherwise be read as another tag
+const KEY_PREFIX: &str = "key:";
+/// Marks a [`Tag::Field`], so it isn't confused with a key of the same name
+const FIELD_PREFIX: &str = "field:";
+
+impl Tag {
+    /// The name the tag is stored under, which [`Tag::from_str`] turns back
+    /// into the same tag. This is the same as the displayed name except for
+    /// fields, and keys which would clash with another tag.
+    pub fn to_key(&self) -> String {
+        match self {
+            Tag::Key(key) if Tag::from_str(key) != Ok(self.clone()) => {
+                format!("{KEY_PREFIX}{key}")
+            }
+            Tag::Field(field) => format!("{FIELD_PREFIX}{field}"),
+            tag => tag.to_string(),
+        }
+    }
+}
+
+impl FromStr for Tag {
+    type Err = Infallible;
+
+    /// Reads a stored tag name, accepting the displayed names of the
+    /// standard tags as well as their variant names. Anything else is a key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix(KEY_PREFIX) {
+            return Ok(Tag::Key(key.to_string()));
+        }
+        if let Some(field) = s.strip_prefix(FIELD_PREFIX) {
+            return Ok(Tag::Field(field.to_string()));
+        }
+
+        Ok(match s {
+            "TrackTitle" | "Title" => Tag::Title,
+            "AlbumTitle" | "Album" => Tag::Album,
+            "TrackArtist" | "Artist" => Tag::Artist,
+            "AlbumArtist" => Tag::AlbumArtist,
+            "Genre" => Tag::Genre,
+            "Comment" => Tag::Comment,
+            "TrackNumber" | "Track" => Tag::Track,
+            "DiscNumber" | "Disk" => Tag::Disk,
+            key => Tag::Key(key.to_string()),
+        })
+    }
+}
+
+/// A tag read from a library file, which is a stored name in current
+/// libraries or the serialized enum in ones saved by older versions
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct StoredTag(Tag);
+
+impl<'de> Deserialize<'de> for StoredTag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Name(String),
+            Legacy(Tag),
+        }
+
+        Ok(StoredTag(match Repr::deserialize(deserializer)? {
+            Repr::Name(name) => Tag::from_str(&name).unwrap(),
+            Repr::Legacy(tag) => tag,
+        }))
+    }
+}
+
+/// Used with `#[serde(with = "...")]` on a map of tags
+pub fn serialize<S: Serializer>(
+    tags: &BTreeMap<Tag, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(tags.iter().map(|(tag, value)| (tag.to_key(), value)))
+}
+
+/// Used with `#[serde(with = "...")]` on a map of tags
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<Tag, String>, D::Error> {
+    let stored = BTreeMap::<StoredTag, String>::deserialize(deserializer)?;
+    Ok(stored
+        .into_iter()
+        .map(|(StoredTag(tag), value)| (tag, value))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::music_storage::library::Tag;
+
+    /// How the tags of a song were serialized before they had stable names
+    #[derive(Serialize)]
+    struct LegacySong {
+        tags: BTreeMap<Tag, String>,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct StoredSong {
+        #[serde(with = "super")]
+        tags: BTreeMap<Tag, String>,
+    }
+
+    fn tags() -> BTreeMap<Tag, String> {
+        BTreeMap::from([
+            (Tag::Title, "Title".to_string()),
+            (Tag::Track, "3".to_string()),
+            (Tag::Key("Year".to_string()), "1999".to_string()),
+            (Tag::Key("TrackTitle".to_string()), "Clash".to_string()),
+            (Tag::Key("key:Prefixed".to_string()), "Escaped".to_string()),
+            (Tag::Field("location".to_string()), "Field".to_string()),
+            (Tag::Key("location".to_string()), "Key".to_string()),
+        ])
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for tag in tags().into_keys() {
+            assert_eq!(Tag::from_str(&tag.to_key()), Ok(tag));
+        }
+        assert_eq!(Tag::Title.to_key(), "TrackTitle");
+        assert_eq!(Tag::Key("Year".to_string()).to_key(), "Year");
+        assert_eq!(Tag::from_str("Disk"), Ok(Tag::Disk));
+    }
+
+    #[test]
+    fn stored_with_names() {
+        let song = StoredSong { tags: tags() };
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&song, &mut bytes).unwrap();
+        let read: StoredSong = ciborium::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(read, song);
+
+        // Names are plain strings, so the map can be written as JSON as well
+        let json = serde_json::to_value(&song).unwrap();
+        assert_eq!(json["tags"]["TrackTitle"], "Title");
+        assert_eq!(json["tags"]["key:TrackTitle"], "Clash");
+        assert_eq!(json["tags"]["field:location"], "Field");
+        let read: StoredSong = serde_json::from_value(json).unwrap();
+        assert_eq!(read, song);
+    }
+
+    #[test]
+    fn legacy_libraries_load() {
+        let legacy = LegacySong { tags: tags() };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&legacy, &mut bytes).unwrap();
+
+        let read: StoredSong = ciborium::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(read.tags, tags());
+
+        // Unit variants were already written as their names
+        let read: StoredSong =
+            serde_json::from_str(r#"{"tags": {"Title": "Title", "Year": "1999"}}"#).unwrap();
+        assert_eq!(read.tags[&Tag::Title], "Title");
+        assert_eq!(read.tags[&Tag::Key("Year".to_string())], "1999");
+    }
+}
@@ -0,0 +1,229 @@
+//! Writing tags back to the files, in the format set in the config. ID3v2
+//! tags are saved as the configured version, ID3v1 tags can be kept in step
+//! with the main tag, and Vorbis comments get the uppercase field names
+//! their specification spells them with.
+
+use std::path::Path;
+
+use lofty::{
+    config::WriteOptions,
+    file::{AudioFile as _, TaggedFileExt as _},
+    probe::Probe,
+    tag::{ItemKey, Tag, TagItem, TagType},
+};
+use uuid::Uuid;
+
+use crate::config::{ConfigTagWriting, Id3Version};
+
+use super::library::{MusicLibrary, URI};
+
+/// The room left after a tag when padding is on, which is lofty's default
+const TAG_PADDING: u32 = 1024;
+
+fn write_options(options: &ConfigTagWriting) -> WriteOptions {
+    WriteOptions::new()
+        .use_id3v23(options.id3_version == Id3Version::V23)
+        .preferred_padding(if options.pad_tags { TAG_PADDING } else { 0 })
+}
+
+/// Vorbis comment field names are read without regard to case, but lofty
+/// keeps the ones it has no key for as they were found, like `mood`
+fn uppercase_vorbis_keys(tag: &mut Tag) {
+    let lowercase: Vec<String> = tag
+        .items()
+        .filter_map(|item| match item.key() {
+            ItemKey::Unknown(key) if *key != key.to_ascii_uppercase() => Some(key.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for key in lowercase {
+        let items: Vec<TagItem> = tag.take(&ItemKey::Unknown(key.clone())).collect();
+        for item in items {
+            let key = ItemKey::Unknown(key.to_ascii_uppercase());
+            tag.push(TagItem::new(key, item.into_value()));
+        }
+    }
+}
+
+/// Writes text items into the main tag of the file at `path`, which is
+/// made if the file has none, and saves its tags the way `options` says
+pub(super) fn write_tag_items(
+    path: &Path,
+    items: impl IntoIterator<Item = (ItemKey, String)>,
+    options: &ConfigTagWriting,
+) -> Result<(), String> {
+    let items: Vec<(ItemKey, String)> = items.into_iter().collect();
+    let mut file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+
+    let tag_type = file.primary_tag_type();
+    if file.primary_tag().is_none() && !items.is_empty() {
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let Some(tag) = file.primary_tag_mut() else {
+        return Err("The file has no tags to update".to_string());
+    };
+
+    for (key, value) in items {
+        tag.insert_text(key, value);
+    }
+    if tag_type == TagType::VorbisComments {
+        uppercase_vorbis_keys(tag);
+    }
+
+    if options.update_id3v1 && tag_type != TagType::Id3v1 {
+        // Only the fields ID3v1 has are kept, and files which can't have
+        // an ID3v1 tag leave it out
+        let mut id3v1 = tag.clone();
+        id3v1.re_map(TagType::Id3v1);
+        file.insert_tag(id3v1);
+    }
+
+    file.save_to_path(path, write_options(options))
+        .map_err(|e| e.to_string())
+}
+
+impl MusicLibrary {
+    /// Saves the tags of a song's file again in the format of `options`,
+    /// like after the ID3 version was changed, without changing any values
+    pub fn retag_file_format(&self, uuid: &Uuid, options: &ConfigTagWriting) -> Result<(), String> {
+        let Some((song, _)) = self.query_uuid(uuid) else {
+            return Err("Song not found".to_string());
+        };
+        match song.location.first() {
+            // The whole file is saved again, so this is fine for CUE sheets
+            Some(URI::Local(path) | URI::Cue { location: path, .. }) => {
+                write_tag_items(path, [], options)
+            }
+            _ => Err("The song has no local file".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{fs, path::PathBuf};
+
+    use lofty::{
+        config::{ParseOptions, ParsingMode},
+        file::{TaggedFile, TaggedFileExt as _},
+        probe::Probe,
+        tag::{ItemKey, TagType},
+    };
+    use uuid::Uuid;
+
+    use super::write_tag_items;
+    use crate::config::{ConfigTagWriting, Id3Version};
+
+    pub(in crate::music_storage) fn temp_file(extension: &str, contents: Vec<u8>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_tags.{extension}", Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// A FLAC file with no audio, and a Vorbis comment block of `comments`
+    pub(in crate::music_storage) fn flac(comments: &[&str]) -> Vec<u8> {
+        let mut flac = b"fLaC".to_vec();
+        // STREAMINFO, for 44.1kHz 16 bit mono
+        flac.extend([0, 0, 0, 34]);
+        flac.extend(4096u16.to_be_bytes());
+        flac.extend(4096u16.to_be_bytes());
+        flac.extend([0; 6]);
+        flac.extend(((44_100u64 << 44) | (15 << 36)).to_be_bytes());
+        flac.extend([0; 16]);
+
+        let mut block = Vec::new();
+        block.extend(4u32.to_le_bytes());
+        block.extend(b"test");
+        block.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        // The last block, of type VORBIS_COMMENT
+        flac.push(0x80 | 4);
+        flac.extend(&(block.len() as u32).to_be_bytes()[1..]);
+        flac.extend(block);
+        flac
+    }
+
+    /// Silent MPEG-1 layer III frames at 128kbps, with no tags
+    fn mp3() -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 0);
+        frame.repeat(20)
+    }
+
+    fn read(path: &PathBuf, mode: ParsingMode) -> TaggedFile {
+        Probe::open(path)
+            .unwrap()
+            .options(ParseOptions::new().parsing_mode(mode))
+            .read()
+            .unwrap()
+    }
+
+    fn contains(bytes: &[u8], part: &[u8]) -> bool {
+        bytes.windows(part.len()).any(|window| window == part)
+    }
+
+    #[test]
+    fn vorbis_keys_uppercased() {
+        let path = temp_file("flac", flac(&["TITLE=Old", "mood=calm"]));
+        let items = [(ItemKey::TrackTitle, String::from("New"))];
+        write_tag_items(&path, items, &ConfigTagWriting::default()).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(contains(&bytes, b"MOOD=calm"));
+        assert!(!contains(&bytes, b"mood="));
+        for mode in [ParsingMode::Strict, ParsingMode::Relaxed] {
+            let file = read(&path, mode);
+            let tag = file.primary_tag().unwrap();
+            assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("New"));
+            assert_eq!(
+                tag.get_string(&ItemKey::Unknown(String::from("MOOD"))),
+                Some("calm")
+            );
+        }
+    }
+
+    #[test]
+    fn id3_written_as_configured() {
+        let path = temp_file("mp3", mp3());
+        let options = ConfigTagWriting {
+            id3_version: Id3Version::V23,
+            update_id3v1: true,
+            pad_tags: false,
+        };
+        let items = [(ItemKey::TrackTitle, String::from("Blue Sky"))];
+        write_tag_items(&path, items, &options).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"ID3\x03"));
+        assert!(bytes[bytes.len() - 128..].starts_with(b"TAG"));
+        for mode in [ParsingMode::Strict, ParsingMode::Relaxed] {
+            let file = read(&path, mode);
+            for tag_type in [TagType::Id3v2, TagType::Id3v1] {
+                let tag = file.tag(tag_type).unwrap();
+                assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("Blue Sky"));
+            }
+        }
+
+        // Saved again as 2.4, with nothing else changed
+        write_tag_items(&path, [], &ConfigTagWriting::default()).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(b"ID3\x04"));
+        for mode in [ParsingMode::Strict, ParsingMode::Relaxed] {
+            let file = read(&path, mode);
+            let tag = file.primary_tag().unwrap();
+            assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("Blue Sky"));
+        }
+    }
+
+    #[test]
+    fn untagged_file_not_retagged() {
+        let path = temp_file("mp3", mp3());
+        assert!(write_tag_items(&path, [], &ConfigTagWriting::default()).is_err());
+        assert_eq!(fs::read(&path).unwrap(), mp3());
+    }
+}
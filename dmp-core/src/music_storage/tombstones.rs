@@ -0,0 +1,340 @@
+//! Remembering songs taken out of the library, so when one comes back, like
+//! after its folder is rescanned, it gets its old [`Uuid`] and stats again
+//! and the playlists and history pointing at it keep working
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::library::{MusicLibrary, Song, Tag};
+
+/// The most removed songs remembered, the oldest are forgotten first
+pub const TOMBSTONE_LIMIT: usize = 10_000;
+
+/// What a song had built up while it was in the library
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SongStats {
+    pub plays: i32,
+    pub skips: i32,
+    pub favorited: bool,
+    pub rating: Option<u8>,
+    pub play_time: Duration,
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_played: Option<DateTime<Utc>>,
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub first_played: Option<DateTime<Utc>>,
+    #[serde(with = "ts_milliseconds_option")]
+    pub date_added: Option<DateTime<Utc>>,
+}
+
+impl SongStats {
+    fn of(song: &Song) -> Self {
+        SongStats {
+            plays: song.plays,
+            skips: song.skips,
+            favorited: song.favorited,
+            rating: song.rating,
+            play_time: song.play_time,
+            last_played: song.last_played,
+            first_played: song.first_played,
+            date_added: song.date_added,
+        }
+    }
+
+    fn restore(self, song: &mut Song) {
+        song.plays = self.plays;
+        song.skips = self.skips;
+        song.favorited = self.favorited;
+        song.rating = self.rating;
+        song.play_time = self.play_time;
+        song.last_played = self.last_played;
+        song.first_played = self.first_played;
+        song.date_added = self.date_added.or(song.date_added);
+    }
+}
+
+/// A song which was removed from the library
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub fingerprint: u64,
+    pub uuid: Uuid,
+    pub stats: SongStats,
+}
+
+/// The songs removed from a library, saved with it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tombstones {
+    entries: VecDeque<Tombstone>,
+}
+
+impl Tombstones {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remembers `song`, replacing anything kept for the same file
+    fn bury(&mut self, song: &Song) {
+        let Some(fingerprint) = fingerprint(song) else {
+            return;
+        };
+        self.entries
+            .retain(|tomb| tomb.fingerprint != fingerprint && tomb.uuid != song.uuid);
+        if self.entries.len() >= TOMBSTONE_LIMIT {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Tombstone {
+            fingerprint,
+            uuid: song.uuid,
+            stats: SongStats::of(song),
+        });
+    }
+
+    /// Takes out what was kept for the song with `fingerprint`
+    fn take(&mut self, fingerprint: u64) -> Option<Tombstone> {
+        let index = self
+            .entries
+            .iter()
+            .rposition(|tomb| tomb.fingerprint == fingerprint)?;
+        self.entries.remove(index)
+    }
+
+    fn forget(&mut self, uuid: &Uuid) {
+        self.entries.retain(|tomb| tomb.uuid != *uuid);
+    }
+}
+
+/// Identifies a song by its file, length and title, which stay the same
+/// when it's removed and scanned again. Songs without a location have none.
+fn fingerprint(song: &Song) -> Option<u64> {
+    let location = song.location.first()?.as_uri();
+    let title = song.get_tag(&Tag::Title).map_or("", String::as_str);
+    let key = format!("{location}\0{}\0{title}", song.duration.as_millis());
+    Some(xxh3_64(key.as_bytes()))
+}
+
+/// A playlist's songs which aren't in the library
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DanglingTracks {
+    pub playlist: Uuid,
+    pub title: String,
+    pub uuids: Vec<Uuid>,
+}
+
+impl MusicLibrary {
+    /// Remembers songs which were taken out, so they're recognised if they
+    /// come back
+    pub(crate) fn bury(&mut self, songs: &[Song]) {
+        for song in songs {
+            self.tombstones.bury(song);
+        }
+    }
+
+    /// Forgets that a song was taken out, like when it was put back
+    pub(crate) fn unbury(&mut self, uuid: &Uuid) {
+        self.tombstones.forget(uuid);
+    }
+
+    /// Gives a song which is being added the [`Uuid`] and stats it had if it
+    /// was in the library before, either removed this session or
+    /// remembered in the tombstones. Returns whether it was recognised.
+    pub(crate) fn restore_identity(&mut self, song: &mut Song) -> bool {
+        let Some(key) = fingerprint(song) else {
+            return false;
+        };
+
+        let backup = self
+            .backup_songs
+            .iter()
+            .position(|old| fingerprint(old) == Some(key));
+        let (uuid, stats) = match backup {
+            Some(index) => {
+                let old = self.backup_songs.remove(index);
+                self.tombstones.forget(&old.uuid);
+                (old.uuid, SongStats::of(&old))
+            }
+            None => match self.tombstones.take(key) {
+                Some(tomb) => (tomb.uuid, tomb.stats),
+                None => return false,
+            },
+        };
+
+        // Something else may have been given the Uuid since
+        if self.query_uuid(&uuid).is_some() {
+            return false;
+        }
+        song.uuid = uuid;
+        stats.restore(song);
+        true
+    }
+
+    /// Finds the songs in playlists which aren't in the library, taking
+    /// them out of the playlists if `prune`
+    pub fn repair_playlists(&mut self, prune: bool) -> Vec<DanglingTracks> {
+        let known: HashSet<Uuid> = self.library.iter().map(|song| song.uuid).collect();
+        let mut dangling = Vec::new();
+        self.playlists.for_each_list_mut(&mut |playlist| {
+            let uuids: Vec<Uuid> = playlist
+                .tracks()
+                .into_iter()
+                .filter(|uuid| !known.contains(uuid))
+                .collect();
+            if uuids.is_empty() {
+                return;
+            }
+            if prune {
+                playlist.retain_tracks(|uuid| known.contains(uuid));
+            }
+            dangling.push(DanglingTracks {
+                playlist: playlist.uuid,
+                title: playlist.title.clone(),
+                uuids,
+            });
+        });
+        dangling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{Tombstones, TOMBSTONE_LIMIT};
+    use crate::music_storage::{
+        library::{MusicLibrary, Song, Tag, URI},
+        playlist::{Playlist, PlaylistFolderItem},
+    };
+
+    fn song(path: &str, title: &str) -> Song {
+        let mut song = Song {
+            uuid: Uuid::new_v4(),
+            location: vec![URI::Local(PathBuf::from(path))],
+            duration: Duration::from_secs(200),
+            ..Default::default()
+        };
+        song.set_tag(Tag::Title, title.to_string());
+        song
+    }
+
+    /// The song as it's read from its file again, with a new Uuid
+    fn rescanned(song: &Song) -> Song {
+        Song {
+            uuid: Uuid::new_v4(),
+            location: song.location.clone(),
+            duration: song.duration,
+            tags: song.tags.clone(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn uuid_restored_after_rescan() {
+        let dir = std::env::temp_dir().join(format!("dmp-tombstones-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| {
+            let path = dir.join(name);
+            fs::write(&path, b"audio").unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let mut irony = song(&path("irony.flac"), "irony");
+        irony.plays = 12;
+        irony.favorited = true;
+        let connect = song(&path("CONNECT.flac"), "CONNECT");
+        lib.add_song(irony.clone()).unwrap();
+        lib.add_song(connect.clone()).unwrap();
+
+        // Removed, then the player is restarted
+        lib.remove_uri(&irony.location[0]).unwrap();
+        let library_path = dir.join("library.dlib");
+        lib.save(library_path.clone()).unwrap();
+        let mut lib = MusicLibrary::init(library_path, lib.uuid).unwrap();
+        assert_eq!(lib.tombstones.len(), 1);
+
+        lib.add_song(rescanned(&irony)).unwrap();
+        let (back, _) = lib.query_uri(&irony.location[0]).unwrap();
+        assert_eq!(back.uuid, irony.uuid);
+        assert_eq!(back.plays, 12);
+        assert!(back.favorited);
+        assert!(lib.tombstones.is_empty());
+
+        // A different song at the same path isn't mistaken for it
+        lib.remove_uri(&connect.location[0]).unwrap();
+        let mut other = rescanned(&connect);
+        other.set_tag(Tag::Title, String::from("Alive"));
+        lib.add_song(other).unwrap();
+        let (other, _) = lib.query_uri(&connect.location[0]).unwrap();
+        assert_ne!(other.uuid, connect.uuid);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removed_this_session() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let lacrimosa = song("/music/Kalafina/Lacrimosa.flac", "Lacrimosa");
+        lib.backup_songs = vec![lacrimosa.clone()];
+
+        let mut back = rescanned(&lacrimosa);
+        assert!(lib.restore_identity(&mut back));
+        assert_eq!(back.uuid, lacrimosa.uuid);
+        lib.library.push(back);
+
+        // So undoing the removal doesn't add it twice
+        assert!(lib.backup_songs.is_empty());
+        assert_eq!(lib.undo_remove_missing(), 0);
+        assert_eq!(lib.library.len(), 1);
+    }
+
+    #[test]
+    fn tombstones_are_capped() {
+        let mut tombstones = Tombstones::default();
+        let first = song("/music/0.flac", "0");
+        tombstones.bury(&first);
+        for i in 1..=TOMBSTONE_LIMIT {
+            tombstones.bury(&song(&format!("/music/{i}.flac"), &i.to_string()));
+        }
+        assert_eq!(tombstones.len(), TOMBSTONE_LIMIT);
+        assert!(!tombstones
+            .entries
+            .iter()
+            .any(|tomb| tomb.uuid == first.uuid));
+    }
+
+    #[test]
+    fn dangling_playlist_tracks() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let kept = song("/music/a.flac", "a");
+        lib.library = vec![kept.clone()];
+        let gone = Uuid::new_v4();
+
+        let mut playlist = Playlist::new();
+        playlist.set_tracks(vec![kept.uuid, gone, kept.uuid]);
+        let playlist_uuid = playlist.uuid;
+        lib.playlists.items.push(PlaylistFolderItem::List(playlist));
+
+        let report = lib.repair_playlists(false);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].playlist, playlist_uuid);
+        assert_eq!(report[0].uuids, [gone]);
+        let tracks =
+            |lib: &MusicLibrary| lib.playlists.query_uuid(&playlist_uuid).unwrap().tracks();
+        assert_eq!(tracks(&lib).len(), 3);
+
+        assert_eq!(lib.repair_playlists(true), report);
+        assert_eq!(tracks(&lib), [kept.uuid, kept.uuid]);
+        assert!(lib.repair_playlists(false).is_empty());
+    }
+}
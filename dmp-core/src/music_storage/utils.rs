@@ -23,6 +23,122 @@ pub(super) fn normalize(input_string: &str) -> String {
     normalized
 }
 
+/// Strips the Windows verbatim prefix (`\\?\`) from a path, turning
+/// `\\?\C:\Music` into `C:\Music` and `\\?\UNC\server\share` into
+/// `\\server\share`. Paths on other platforms are returned unchanged.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    #[cfg(target_family = "windows")]
+    {
+        use std::path::{Component, Prefix};
+
+        let mut components = path.components();
+        if let Some(Component::Prefix(prefix)) = components.next() {
+            let root = match prefix.kind() {
+                Prefix::VerbatimDisk(disk) => Some(format!("{}:\\", disk as char)),
+                Prefix::VerbatimUNC(server, share) => Some(format!(
+                    "\\\\{}\\{}\\",
+                    server.to_string_lossy(),
+                    share.to_string_lossy()
+                )),
+                _ => None,
+            };
+            if let Some(root) = root {
+                return PathBuf::from(root).join(components.as_path());
+            }
+        }
+    }
+
+    path.to_path_buf()
+}
+
+/// Turns a normalized path back into one which can be accessed even if it
+/// is longer than `MAX_PATH` on Windows. Paths on other platforms are
+/// returned unchanged.
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(target_family = "windows")]
+    {
+        use std::path::{Component, Prefix};
+
+        const MAX_PATH: usize = 260;
+        if path.as_os_str().len() >= MAX_PATH {
+            let mut components = path.components();
+            if let Some(Component::Prefix(prefix)) = components.next() {
+                let rest = components.as_path().to_string_lossy();
+                match prefix.kind() {
+                    Prefix::Disk(disk) => {
+                        return PathBuf::from(format!("\\\\?\\{}:{rest}", disk as char))
+                    }
+                    Prefix::UNC(server, share) => {
+                        return PathBuf::from(format!(
+                            "\\\\?\\UNC\\{}\\{}{rest}",
+                            server.to_string_lossy(),
+                            share.to_string_lossy()
+                        ))
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    path.to_path_buf()
+}
+
+/// Canonicalizes a path, returning it without any verbatim prefix
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    fs::canonicalize(path).map(|path| normalize_path(&path))
+}
+
+/// Returns the root of the volume a path lives on, such as `C:\` or
+/// `\\server\share\` on Windows. Elsewhere it's the mount point the path
+/// is on, found by where the device changes. A path in a directory which
+/// doesn't exist is on a volume of its own at the first missing directory,
+/// as that's all that's left of a drive which isn't mounted: the
+/// `/media/user/label` it was mounted at is removed along with it.
+#[cfg(target_family = "windows")]
+pub(super) fn volume_root(path: &Path) -> PathBuf {
+    let mut root = PathBuf::new();
+    for component in normalize_path(path).components() {
+        if let std::path::Component::Normal(_) = component {
+            break;
+        }
+        root.push(component);
+    }
+    root
+}
+
+#[cfg(not(target_family = "windows"))]
+pub(super) fn volume_root(path: &Path) -> PathBuf {
+    use std::os::unix::fs::MetadataExt;
+
+    // The file itself is often what's missing, so that's left out
+    let mut root = path.parent().unwrap_or(path);
+    let mut missing = None;
+    let device = loop {
+        match fs::metadata(root) {
+            Ok(metadata) => break metadata.dev(),
+            Err(_) => {
+                missing = Some(root);
+                match root.parent() {
+                    Some(parent) => root = parent,
+                    None => return root.to_path_buf(),
+                }
+            }
+        }
+    };
+    if let Some(missing) = missing {
+        return missing.to_path_buf();
+    }
+
+    while let Some(parent) = root.parent() {
+        match fs::metadata(parent) {
+            Ok(metadata) if metadata.dev() == device => root = parent,
+            _ => break,
+        }
+    }
+    root.to_path_buf()
+}
+
 /// Write any data structure which implements [serde::Serialize]
 /// out to a [cbor] encoded file compressed using [ciborium]
 pub(super) fn write_file<
@@ -88,7 +204,7 @@ pub fn find_images(song_path: &Path) -> Result<Vec<AlbumArt>, Box<dyn Error>> {
             continue;
         }
 
-        let image_uri = URI::Local(path.to_path_buf().canonicalize()?);
+        let image_uri = URI::Local(canonicalize(path)?);
 
         images.push(AlbumArt::External(image_uri));
     }
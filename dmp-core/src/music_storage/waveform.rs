@@ -0,0 +1,313 @@
+//! Generation and caching of the peak/RMS waveforms shown in the seekbar
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use symphonia::core::errors::Error as SymphoniaError;
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::decode::{decode, DecodeError};
+
+/// The number of mono frames summarized together before being merged into
+/// buckets, which keeps memory use flat no matter how long the file is
+const CHUNK_FRAMES: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum WaveformError {
+    #[error("Waveform generation was cancelled")]
+    Cancelled,
+    #[error("No decodable audio track was found")]
+    NoTrack,
+    #[error("At least one bucket is required")]
+    NoBuckets,
+    #[error("Decoding error: {0}")]
+    Decode(#[from] SymphoniaError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<DecodeError> for WaveformError {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::Cancelled => WaveformError::Cancelled,
+            DecodeError::NoTrack => WaveformError::NoTrack,
+            DecodeError::Decode(e) => WaveformError::Decode(e),
+            DecodeError::Io(e) => WaveformError::Io(e),
+        }
+    }
+}
+
+/// The peak and sum of squares of a run of frames
+#[derive(Debug, Default, Clone, Copy)]
+struct Chunk {
+    peak: f32,
+    sum_squares: f64,
+    frames: usize,
+}
+
+/// Decodes the audio file at `path`, downmixed to mono, and summarizes it into
+/// `buckets` evenly sized buckets. `range` limits the summary to part of the
+/// file, which is used for songs within a CUE sheet.
+///
+/// The result holds a `(peak, rms)` pair per bucket, each scaled to `0..=255`.
+/// `progress` is called with the fraction of the file decoded so far, and
+/// returning `false` from it cancels generation.
+pub fn generate(
+    path: &Path,
+    buckets: usize,
+    range: Option<(Duration, Duration)>,
+    progress: impl FnMut(f32) -> bool,
+) -> Result<Vec<u8>, WaveformError> {
+    if buckets == 0 {
+        return Err(WaveformError::NoBuckets);
+    }
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut current = Chunk::default();
+    decode(
+        path,
+        range,
+        |samples, channels, _| {
+            for frame in samples.chunks_exact(channels) {
+                let sample = frame.iter().sum::<f32>() / channels as f32;
+                current.peak = current.peak.max(sample.abs());
+                current.sum_squares += (sample as f64).powi(2);
+                current.frames += 1;
+                if current.frames == CHUNK_FRAMES {
+                    chunks.push(std::mem::take(&mut current));
+                }
+            }
+        },
+        progress,
+    )?;
+    if current.frames > 0 {
+        chunks.push(current);
+    }
+
+    Ok(merge_chunks(&chunks, buckets))
+}
+
+/// Merges the chunks into `buckets` buckets of `(peak, rms)` pairs
+fn merge_chunks(chunks: &[Chunk], buckets: usize) -> Vec<u8> {
+    let mut waveform = Vec::with_capacity(buckets * 2);
+    for bucket in 0..buckets {
+        let start = bucket * chunks.len() / buckets;
+        let end = ((bucket + 1) * chunks.len() / buckets).max(start + 1);
+
+        let merged = chunks
+            .get(start..end.min(chunks.len()))
+            .unwrap_or_default()
+            .iter()
+            .fold(Chunk::default(), |acc, chunk| Chunk {
+                peak: acc.peak.max(chunk.peak),
+                sum_squares: acc.sum_squares + chunk.sum_squares,
+                frames: acc.frames + chunk.frames,
+            });
+
+        let rms = match merged.frames {
+            0 => 0.0,
+            frames => (merged.sum_squares / frames as f64).sqrt() as f32,
+        };
+        waveform.push(scale(merged.peak));
+        waveform.push(scale(rms));
+    }
+    waveform
+}
+
+fn scale(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// An on-disk cache of generated waveforms
+///
+/// Entries are keyed by a hash of the file's location and the bucket count,
+/// and hold the modification time of the file they were generated from so
+/// they are regenerated once the file changes.
+#[derive(Debug, Clone)]
+pub struct WaveformCache {
+    dir: PathBuf,
+}
+
+impl WaveformCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WaveformCache { dir: dir.into() }
+    }
+
+    /// Returns the cached waveform for `path`, if it is still up to date
+    pub fn get(
+        &self,
+        path: &Path,
+        range: Option<(Duration, Duration)>,
+        buckets: usize,
+    ) -> Option<Vec<u8>> {
+        let mtime = modified(path)?;
+        let cached = fs::read(self.entry(path, range, buckets)).ok()?;
+        if cached.len() < 16 || cached[..16] != mtime {
+            return None;
+        }
+        Some(cached[16..].to_vec())
+    }
+
+    /// Stores a freshly generated waveform for `path`
+    pub fn insert(
+        &self,
+        path: &Path,
+        range: Option<(Duration, Duration)>,
+        buckets: usize,
+        waveform: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let Some(mtime) = modified(path) else {
+            return Ok(());
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(
+            self.entry(path, range, buckets),
+            [&mtime, waveform].concat(),
+        )
+    }
+
+    fn entry(&self, path: &Path, range: Option<(Duration, Duration)>, buckets: usize) -> PathBuf {
+        let mut key = path.as_os_str().as_encoded_bytes().to_vec();
+        if let Some((start, end)) = range {
+            key.extend(start.as_millis().to_le_bytes());
+            key.extend(end.as_millis().to_le_bytes());
+        }
+        self.dir
+            .join(format!("{:016x}_{buckets}.wave", xxh3_64(&key)))
+    }
+}
+
+/// The modification time of a file as bytes, for comparing against cache entries
+fn modified(path: &Path) -> Option<[u8; 16]> {
+    let mtime = fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Some(since_epoch.as_nanos().to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        f32::consts::TAU,
+        fs::{self, File},
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    use uuid::Uuid;
+
+    use super::{generate, WaveformCache, WaveformError, CHUNK_FRAMES};
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    /// Writes a 16 bit stereo WAV file from mono samples
+    fn write_wav(path: &Path, samples: &[f32]) {
+        let data_len = samples.len() as u32 * 4;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(SAMPLE_RATE.to_le_bytes());
+        wav.extend((SAMPLE_RATE * 4).to_le_bytes());
+        wav.extend(4u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for sample in samples {
+            let sample = (sample * i16::MAX as f32) as i16;
+            wav.extend(sample.to_le_bytes());
+            wav.extend(sample.to_le_bytes());
+        }
+        fs::write(path, wav).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{name}", Uuid::new_v4()))
+    }
+
+    /// About two seconds of a sine wave at half amplitude followed by about a
+    /// second of silence, lined up with the chunk size so buckets split evenly
+    fn sine_then_silence() -> Vec<f32> {
+        let sine = (0..CHUNK_FRAMES * 86)
+            .map(|i| 0.5 * (TAU * 441.0 * i as f32 / SAMPLE_RATE as f32).sin());
+        let silence = (0..CHUNK_FRAMES * 43).map(|_| 0.0);
+        sine.chain(silence).collect()
+    }
+
+    #[test]
+    fn sine_wave_buckets() {
+        let path = temp_path("sine.wav");
+        write_wav(&path, &sine_then_silence());
+
+        let mut reports = Vec::new();
+        let waveform = generate(&path, 3, None, |p| {
+            reports.push(p);
+            true
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(waveform.len(), 6);
+        for bucket in waveform[..4].chunks(2) {
+            // The peak of the sine is 0.5, and its RMS is 0.5 / sqrt(2)
+            assert!(bucket[0].abs_diff(128) <= 2, "peak was {}", bucket[0]);
+            assert!(bucket[1].abs_diff(90) <= 2, "rms was {}", bucket[1]);
+        }
+        assert_eq!(&waveform[4..], [0, 0]);
+
+        assert!(!reports.is_empty());
+        assert!(reports.windows(2).all(|w| w[0] <= w[1]));
+        assert!(*reports.last().unwrap() > 0.95);
+    }
+
+    #[test]
+    fn range_limits_summary() {
+        let path = temp_path("range.wav");
+        write_wav(&path, &sine_then_silence());
+
+        let range = Some((Duration::from_millis(2100), Duration::from_secs(3)));
+        let waveform = generate(&path, 4, range, |_| true).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(waveform.iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn cancellation() {
+        let path = temp_path("cancel.wav");
+        write_wav(&path, &sine_then_silence());
+
+        let res = generate(&path, 400, None, |p| p < 0.5);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(res, Err(WaveformError::Cancelled)));
+    }
+
+    #[test]
+    fn cache_invalidated_by_mtime() {
+        let path = temp_path("cached.wav");
+        write_wav(&path, &[0.0; 16]);
+        let cache = WaveformCache::new(temp_path("waveforms"));
+
+        cache.insert(&path, None, 4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(cache.get(&path, None, 4), Some(vec![1, 2, 3, 4]));
+        assert_eq!(cache.get(&path, None, 8), None);
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(cache.get(&path, None, 4), None);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{collections::HashSet, fmt::Debug};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum QueueState {
@@ -17,6 +17,15 @@ pub struct QueueItem<
     pub item: QueueItemType<T, U>,
     pub state: QueueState,
     pub by_human: bool,
+    /// Items with the same block were added together, like the tracks of
+    /// an album, and stay together in order when the queue is shuffled
+    pub block: Option<u32>,
+    /// The item was made lighter to keep the played history small, and has
+    /// to be filled in again before it's used
+    pub compacted: bool,
+    /// Given by the queue when the item is added to it, so two copies of
+    /// the same item can be told apart
+    pub id: u64,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -49,6 +58,9 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
             item,
             state: QueueState::NoState,
             by_human: false,
+            block: None,
+            compacted: false,
+            id: 0,
         }
     }
 }
@@ -62,9 +74,11 @@ pub struct Queue<
     pub played: Vec<QueueItem<T, U>>,
     pub loop_: bool,
     pub shuffle: Option<Vec<usize>>,
+    /// The id given to the next item added
+    next_id: u64,
 }
 
-// TODO: HAndle the First QueueState[looping] and shuffle
+// TODO: Handle shuffle
 impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator> Queue<T, U> {
     fn has_addhere(&self) -> bool {
         for item in &self.items {
@@ -75,6 +89,101 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
         false
     }
 
+    /// The index of the AddHere item, which items added by a person go after
+    fn addhere_index(&self) -> Option<usize> {
+        self.items
+            .iter()
+            .position(|item| item.state == QueueState::AddHere)
+    }
+
+    /// The index of the last item in the block the item at `index` is part
+    /// of, which is `index` itself for items outside of a block
+    fn block_end(&self, index: usize) -> usize {
+        let Some(block) = self.items.get(index).and_then(|item| item.block) else {
+            return index;
+        };
+        index
+            + self.items[index + 1..]
+                .iter()
+                .take_while(|item| item.block == Some(block))
+                .count()
+    }
+
+    /// A block id which isn't used by any item
+    fn new_block(&self) -> u32 {
+        self.items
+            .iter()
+            .chain(&self.played)
+            .filter_map(|item| item.block)
+            .max()
+            .map_or(0, |block| block + 1)
+    }
+
+    /// Gives `item` the next unused id
+    fn with_id(&mut self, mut item: QueueItem<T, U>) -> QueueItem<T, U> {
+        item.id = self.next_id;
+        self.next_id += 1;
+        item
+    }
+
+    /// Checks the bookkeeping every method relies on: there is at most one
+    /// AddHere item, and it is one of the upcoming items rather than a
+    /// played one. No item was added twice, and the shuffle order only
+    /// points at upcoming items.
+    pub fn check_invariants(&self) -> Result<(), QueueError> {
+        let add_heres = self
+            .items
+            .iter()
+            .filter(|item| item.state == QueueState::AddHere)
+            .count();
+        if add_heres > 1 {
+            return Err(QueueError::Inconsistent(format!(
+                "{add_heres} items are marked AddHere"
+            )));
+        }
+        if let Some(i) = self
+            .played
+            .iter()
+            .position(|item| item.state == QueueState::AddHere)
+        {
+            return Err(QueueError::Inconsistent(format!(
+                "played item {i} is marked AddHere"
+            )));
+        }
+        let mut ids = HashSet::new();
+        for item in self.played.iter().chain(&self.items) {
+            if item.id >= self.next_id {
+                return Err(QueueError::Inconsistent(format!(
+                    "item id {} hasn't been given out yet",
+                    item.id
+                )));
+            }
+            if !ids.insert(item.id) {
+                return Err(QueueError::Inconsistent(format!(
+                    "item id {} is in the queue twice",
+                    item.id
+                )));
+            }
+        }
+        let len = self.items.len();
+        if let Some(&index) = self.shuffle.iter().flatten().find(|&&i| i >= len) {
+            return Err(QueueError::Inconsistent(format!(
+                "shuffle index {index} is over len {len}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs [`Queue::check_invariants`] in debug builds, after every method
+    /// which changes the queue
+    fn debug_check(&self) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_invariants() {
+                panic!("{e}");
+            }
+        }
+    }
+
     #[allow(unused)]
     pub(crate) fn dbg_items(&self) {
         dbg!(
@@ -92,157 +201,115 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
             played: Vec::new(),
             loop_,
             shuffle,
+            next_id: 0,
         }
     }
 
+    /// Replaces the upcoming items with `tracks`, giving each of them a new
+    /// id
     pub fn set_items(&mut self, tracks: Vec<QueueItem<T, U>>) {
-        let mut tracks = tracks;
         self.items.clear();
-        self.items.append(&mut tracks);
+        for track in tracks {
+            let track = self.with_id(track);
+            self.items.push(track);
+        }
+        self.debug_check();
     }
 
-    /// Inserts an item after the AddHere item
+    /// Inserts an item after the AddHere item. Items which weren't added by
+    /// a person go at the end instead, leaving the AddHere item alone.
     pub fn add_item(&mut self, item: T, by_human: bool) {
-        let item = QueueItemType::from_single(item);
-        let mut i: usize = 0;
-
-        self.items = self
-            .items
-            .iter()
-            .enumerate()
-            .map(|(j, item_)| {
-                let mut item_ = item_.to_owned();
-                // get the index of the current AddHere item and give it to i
-                if item_.state == QueueState::AddHere {
-                    i = j;
-                    item_.state = QueueState::NoState;
-                }
-                item_
-            })
-            .collect::<Vec<QueueItem<T, U>>>();
-        let empty = self.items.is_empty();
-
-        if !empty {
-            self.items
-                .get_mut(i)
-                .expect("There should be an item at index {i}")
-                .state = QueueState::NoState;
-        }
-
-        if by_human {
-            self.items.insert(
-                i + if empty { 0 } else { 1 },
-                QueueItem {
-                    item,
-                    state: QueueState::AddHere,
-                    by_human,
-                },
-            );
-        } else {
-            self.items.push(QueueItem {
-                item,
-                state: QueueState::NoState,
-                by_human,
-            });
-        }
+        self.add_multi(vec![QueueItemType::from_single(item)], by_human);
     }
 
     /// Inserts an item after the currently playing item
     pub fn add_item_next(&mut self, item: T) {
-        let item = QueueItemType::from_single(item);
-        use QueueState::*;
-        let empty = self.items.is_empty();
-
-        self.items.insert(
-            if empty { 0 } else { 1 },
-            QueueItem {
-                item,
-                state: if (self.items.get(1).is_none()
-                    || !self.has_addhere() && self.items.get(1).is_some())
-                    || empty
-                {
-                    AddHere
-                } else {
-                    NoState
-                },
-                by_human: true,
-            },
-        )
+        self.add_multi_next(vec![QueueItemType::from_single(item)]);
     }
 
+    /// Inserts items in order after the AddHere item, the last of them
+    /// becoming the new AddHere item. Items which weren't added by a person
+    /// go at the end instead, leaving the AddHere item alone.
     pub fn add_multi(&mut self, items: Vec<QueueItemType<T, U>>, by_human: bool) {
-        let mut i: usize = 0;
+        if items.is_empty() {
+            return;
+        }
 
-        self.items = self
-            .items
-            .iter()
-            .enumerate()
-            .map(|(j, item_)| {
-                let mut item_ = item_.to_owned();
-                // get the index of the current AddHere item and give it to i
-                if item_.state == QueueState::AddHere {
-                    i = j;
-                    item_.state = QueueState::NoState;
-                }
-                item_
+        let new_items: Vec<QueueItem<T, U>> = items
+            .into_iter()
+            .map(|item| {
+                let mut item = QueueItem::from_item_type(item);
+                item.by_human = by_human;
+                self.with_id(item)
             })
-            .collect::<Vec<QueueItem<T, U>>>();
-
-        let empty = self.items.is_empty();
-        if !empty {
-            self.items
-                .get_mut(i)
-                .expect("There should be an item at index {i}")
-                .state = QueueState::NoState;
+            .collect();
+        if !by_human {
+            self.items.extend(new_items);
+            self.debug_check();
+            return;
         }
 
-        let len = items.len();
-        for item in items.into_iter().rev() {
-            if by_human {
-                self.items.insert(
-                    i + if empty { 0 } else { 1 },
-                    QueueItem {
-                        item,
-                        state: QueueState::NoState,
-                        by_human, // true
-                    },
-                );
-            } else {
-                self.items.push(QueueItem {
-                    item,
-                    state: QueueState::NoState,
-                    by_human, // false
-                });
+        // Without an AddHere item, human items go after the current one.
+        // Either way they never go in the middle of a block.
+        let at = match self.addhere_index() {
+            Some(i) => {
+                self.items[i].state = QueueState::NoState;
+                self.block_end(i) + 1
             }
-        }
-        self.items[i + len - if empty { 1 } else { 0 }].state = QueueState::AddHere;
+            None if self.items.is_empty() => 0,
+            None => self.block_end(0) + 1,
+        };
+        let len = new_items.len();
+        self.items.splice(at..at, new_items);
+        self.items[at + len - 1].state = QueueState::AddHere;
+        self.debug_check();
     }
 
     /// Add multiple Items after the currently playing Item
     pub fn add_multi_next(&mut self, items: Vec<QueueItemType<T, U>>) {
-        use QueueState::*;
-        let empty = self.items.is_empty();
-
-        let add_here = (self.items.get(1).is_none()
-            || !self.has_addhere() && self.items.get(1).is_some())
-            || empty;
+        self.insert_next(items, None);
+    }
 
-        let len = items.len();
+    /// Inserts items in order after the currently playing item as a block,
+    /// which stays together when the queue is shuffled and which nothing
+    /// added later is put in the middle of
+    pub fn add_block_next(&mut self, items: Vec<QueueItemType<T, U>>) {
+        let block = self.new_block();
+        self.insert_next(items, Some(block));
+    }
 
-        for item in items {
-            self.items.insert(
-                if empty { 0 } else { 1 },
-                QueueItem {
-                    item,
-                    state: NoState,
-                    by_human: true,
-                },
-            )
+    fn insert_next(&mut self, items: Vec<QueueItemType<T, U>>, block: Option<u32>) {
+        if items.is_empty() {
+            return;
         }
 
+        let at = self.items.len().min(1);
+        // Items added by a person after these still go after these, unless
+        // the AddHere item is already further along
+        let add_here = match self.addhere_index() {
+            Some(i) if i >= at => false,
+            Some(i) => {
+                self.items[i].state = QueueState::NoState;
+                true
+            }
+            None => true,
+        };
+
+        let items: Vec<QueueItem<T, U>> = items
+            .into_iter()
+            .map(|item| {
+                let mut item = QueueItem::from_item_type(item);
+                item.by_human = true;
+                item.block = block;
+                self.with_id(item)
+            })
+            .collect();
+        let len = items.len();
+        self.items.splice(at..at, items);
         if add_here {
-            self.items[len - if empty { 1 } else { 0 }].state = QueueState::AddHere;
+            self.items[at + len - 1].state = QueueState::AddHere;
         }
+        self.debug_check();
     }
 
     pub fn remove_item(&mut self, remove_index: usize) -> Result<QueueItem<T, U>, QueueError> {
@@ -253,7 +320,9 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
             if self.items.get(remove_index + 1).is_some() {
                 self.items[remove_index + 1].state = self.items[remove_index].state;
             }
-            Ok(self.items.remove(remove_index))
+            let item = self.items.remove(remove_index);
+            self.debug_check();
+            Ok(item)
         } else {
             Err(QueueError::EmptyQueue)
         }
@@ -274,19 +343,17 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
                 len: self.items.len(),
             });
         }
+        let mut new_item = self.with_id(QueueItem::from_item_type(new_item));
         if addhere {
-            let mut new_item = QueueItem::from_item_type(new_item);
             for item in &mut self.items {
                 if item.state == QueueState::AddHere {
                     item.state = QueueState::NoState
                 }
             }
             new_item.state = QueueState::AddHere;
-            self.items.insert(index, new_item);
-        } else {
-            let new_item = QueueItem::from_item_type(new_item);
-            self.items.insert(index, new_item);
         }
+        self.items.insert(index, new_item);
+        self.debug_check();
         Ok(())
     }
 
@@ -299,9 +366,10 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
         let empty = self.items.is_empty();
 
         if !empty && index < self.items.len() {
-            let i = self.items[index].clone();
-            self.items.retain(|item| *item == i);
-            self.items[0].state = AddHere;
+            // Only the item at `index` is kept, even if it's queued twice
+            let mut item = self.items.swap_remove(index);
+            item.state = AddHere;
+            self.items = vec![item];
         } else if empty {
             return Err(QueueError::EmptyQueue);
         } else {
@@ -322,69 +390,195 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
         self.played.clear();
     }
 
+    /// Moves every item before `index` to the played items, making the item
+    /// at `index` the current one
     pub fn move_to(&mut self, index: usize) -> Result<(), QueueError> {
         use QueueState::*;
 
-        let empty = self.items.is_empty();
-        let index = if !empty {
-            index
-        } else {
+        if self.items.is_empty() {
             return Err(QueueError::EmptyQueue);
-        };
+        }
+        if index >= self.items.len() {
+            return Err(QueueError::OutOfBounds {
+                index,
+                len: self.items.len(),
+            });
+        }
+        if let QueueItemType::Multi(_) = self.items[index].item {
+            unimplemented!(); //TODO: Add logic for multi items
+        }
+
+        // Comparing items would stop early at an earlier copy of the same
+        // item, so exactly `index` items are moved
+        for _ in 0..index {
+            let mut item = self.items.remove(0);
+            if item.state == AddHere {
+                self.items[0].state = AddHere;
+                item.state = NoState;
+            }
+            self.played.push(item);
+        }
+        self.debug_check();
+        Ok(())
+    }
 
-        if !empty && dbg!(index < self.items.len()) {
-            let to_item = self.items[index].clone();
+    /// Reorders every item after the current one with `shuffle`, which is
+    /// given the runs of items to reorder: a whole block, or a single item
+    /// outside of one. The current item stays first, along with the rest of
+    /// its block, and the AddHere position doesn't move unless it would end
+    /// up in the middle of a block. With `keep_human`, items added by a
+    /// person keep their places and only the rest are reordered.
+    pub fn shuffle_remaining(
+        &mut self,
+        keep_human: bool,
+        shuffle: impl FnOnce(&mut [Vec<QueueItem<T, U>>]),
+    ) {
+        if self.items.is_empty() {
+            return;
+        }
+        let start = self.block_end(0) + 1;
 
-            if let QueueItemType::Multi(_) = to_item.item {
-                unimplemented!(); //TODO: Add logic for multi items
+        // States belong to positions rather than items
+        let states: Vec<QueueState> = self.items[start..].iter().map(|item| item.state).collect();
+        // A block which something was put in the middle of is brought back
+        // together where it starts, or after the current item if it's part
+        // of the block
+        let current = self.items[0].block;
+        let mut rest_of_current = Vec::new();
+        let mut runs: Vec<Vec<QueueItem<T, U>>> = Vec::new();
+        for mut item in self.items.drain(start..) {
+            item.state = QueueState::NoState;
+            if item.block.is_some() && item.block == current {
+                rest_of_current.push(item);
+                continue;
             }
+            let block = runs
+                .iter_mut()
+                .find(|run| item.block.is_some() && run[0].block == item.block);
+            match block {
+                Some(run) => run.push(item),
+                None => runs.push(vec![item]),
+            }
+        }
+        self.items.extend(rest_of_current);
 
-            loop {
-                let empty = self.items.is_empty();
-                let item = self.items[0].item.to_owned();
+        let fixed = |run: &Vec<QueueItem<T, U>>| keep_human && run[0].by_human;
+        let mut moving: Vec<Vec<QueueItem<T, U>>> =
+            runs.iter().filter(|run| !fixed(run)).cloned().collect();
+        shuffle(&mut moving);
+        let mut moving = moving.into_iter();
+        for run in runs {
+            let run = match fixed(&run) {
+                true => run,
+                false => moving.next().unwrap(),
+            };
+            self.items.extend(run);
+        }
 
-                if item != to_item.item && !empty {
-                    if self.items[0].state == AddHere && self.items.get(1).is_some() {
-                        self.items[1].state = AddHere;
-                    }
-                    let item = self.items.remove(0);
-                    self.played.push(item);
-
-                // dbg!(&to_item.item, &self.items[ind].item);
-                } else if empty {
-                    return Err(QueueError::EmptyQueue);
-                } else {
-                    break;
+        for (i, state) in (start..).zip(states) {
+            if state == QueueState::AddHere {
+                let end = self.block_end(i);
+                self.items[end].state = state;
+            } else if self.items[i].state == QueueState::NoState {
+                self.items[i].state = state;
+            }
+        }
+        self.debug_check();
+    }
+
+    /// Replaces the items after the current one which weren't added by a
+    /// person with `items`, keeping everything that was
+    pub fn replace_auto_items(&mut self, items: Vec<QueueItemType<T, U>>) {
+        let mut kept: Vec<QueueItem<T, U>> = Vec::with_capacity(self.items.len());
+        for (i, item) in self.items.drain(..).enumerate() {
+            if i == 0 || item.by_human {
+                kept.push(item);
+            } else if item.state == QueueState::AddHere {
+                // Human items still go after whatever came before this one
+                if let Some(last) = kept.last_mut() {
+                    last.state = QueueState::AddHere;
                 }
             }
-        } else {
-            return Err(QueueError::EmptyQueue);
         }
-        Ok(())
+
+        self.items = kept;
+        for item in items {
+            let item = self.with_id(QueueItem::from_item_type(item));
+            self.items.push(item);
+        }
+        self.debug_check();
     }
 
     pub fn swap(&mut self, a: usize, b: usize) {
         self.items.swap(a, b)
     }
 
-    pub fn move_item(&mut self, from: usize, to: usize) {
-        let item = self.items[from].to_owned();
-        if from != to {
-            self.items.remove(from);
+    /// Moves the item at `from` so it ends up at `to`
+    pub fn move_item(&mut self, from: usize, to: usize) -> Result<(), QueueError> {
+        let len = self.items.len();
+        if let Some(index) = [from, to].into_iter().find(|&i| i >= len) {
+            return Err(QueueError::OutOfBounds { index, len });
         }
+
+        // Moving the item used to insert a copy when `from` and `to` matched
+        let item = self.items.remove(from);
         self.items.insert(to, item);
+        self.debug_check();
+        Ok(())
+    }
+
+    /// Starts the loop over once every item has played, moving the played
+    /// items back in the order they played. The first of them is marked
+    /// [`QueueState::First`], which is where the loop starts.
+    fn wrap_forward(&mut self) {
+        self.items = std::mem::take(&mut self.played);
+        for item in &mut self.items {
+            item.state = QueueState::NoState;
+        }
+        if let Some(first) = self.items.first_mut() {
+            first.state = QueueState::First;
+        }
+    }
+
+    /// Goes back from the start of the loop to its end, as though every
+    /// item but the last had just played
+    fn wrap_back(&mut self) {
+        let Some(last) = self.items.pop() else {
+            return;
+        };
+        let add_here = self.has_addhere() || last.state == QueueState::AddHere;
+        let mut rest = std::mem::replace(&mut self.items, vec![last]);
+        for item in &mut rest {
+            item.state = QueueState::NoState;
+        }
+        self.items[0].state = match add_here {
+            true => QueueState::AddHere,
+            false => QueueState::NoState,
+        };
+        match rest.first_mut() {
+            Some(first) => first.state = QueueState::First,
+            // The only item in the loop is both its start and end
+            None if !add_here => self.items[0].state = QueueState::First,
+            None => {}
+        }
+        self.played.append(&mut rest);
     }
 
+    /// Moves on to the next item. With `loop_` set, the played items come
+    /// back around once the last one is done.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<&QueueItem<T, U>, QueueError> {
         if self.items.is_empty() {
-            if self.loop_ {
-                unimplemented!() // TODO: add function to loop the queue
+            if self.loop_ && !self.played.is_empty() {
+                self.wrap_forward();
+                self.debug_check();
+                return Ok(&self.items[0]);
             } else {
                 return Err(QueueError::EmptyQueue);
             }
         }
 
+        let first = self.items[0].state == QueueState::First;
         if self.items[0].state == QueueState::AddHere || !self.has_addhere() {
             if let QueueItemType::Multi(_) = self.items[0].item {
                 unimplemented!(); // TODO: Handle Multi items here?
@@ -395,8 +589,18 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
                 self.items[1].state = QueueState::AddHere;
             }
         }
-        let item = self.items.remove(0);
+        let mut item = self.items.remove(0);
+        // The AddHere item was kept where it was, past the current one, and
+        // the start of the loop stays marked
+        item.state = match first {
+            true => QueueState::First,
+            false => QueueState::NoState,
+        };
         self.played.push(item);
+        if self.items.is_empty() && self.loop_ {
+            self.wrap_forward();
+        }
+        self.debug_check();
 
         if self.items.is_empty() {
             Err(QueueError::NoNext)
@@ -405,13 +609,26 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
         }
     }
 
+    /// Goes back to the item played before the current one. With `loop_`
+    /// set, going back from the start of the loop goes to the item it ends
+    /// with.
     pub fn prev(&mut self) -> Result<&QueueItem<T, U>, QueueError> {
-        if let Some(item) = self.played.pop() {
-            if item.state == QueueState::First && self.loop_ {
-                todo!()
-            }
+        let at_first = self
+            .items
+            .first()
+            .is_some_and(|item| item.state == QueueState::First);
+        if self.loop_ && !self.items.is_empty() && (at_first || self.played.is_empty()) {
+            self.wrap_back();
+            self.debug_check();
+            return Ok(&self.items[0]);
+        }
 
-            if let QueueItemType::Multi(_) = self.items[0].item {
+        if let Some(item) = self.played.pop() {
+            if let Some(QueueItem {
+                item: QueueItemType::Multi(_),
+                ..
+            }) = self.items.first()
+            {
                 unimplemented!(); // TODO: Handle Multi items here?
             }
             if let QueueItemType::Multi(_) = item.item {
@@ -419,6 +636,7 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
             }
 
             self.items.insert(0, item);
+            self.debug_check();
             Ok(&self.items[0])
         } else {
             Err(QueueError::EmptyPlayed)
@@ -441,6 +659,44 @@ impl<T: Debug + Clone + PartialEq, U: Debug + PartialEq + Clone + IntoIterator>
             self.played.remove(0);
         }
     }
+
+    /// Trims the played items like [`Queue::check_played`], but keeps the
+    /// oldest ones to go back to. Only the last `full` are kept whole, and
+    /// the ones before them are made lighter with `compact` and marked as
+    /// compacted, up to `cap` played items in all.
+    pub fn check_played_pinned(
+        &mut self,
+        full: usize,
+        cap: usize,
+        mut compact: impl FnMut(&mut T),
+    ) {
+        if self.played.len() > cap {
+            self.played.drain(..self.played.len() - cap);
+        }
+        let old = self.played.len().saturating_sub(full);
+        for item in self.played[..old].iter_mut().filter(|item| !item.compacted) {
+            if let QueueItemType::Single(single) = &mut item.item {
+                compact(single);
+                item.compacted = true;
+            }
+        }
+    }
+
+    /// Goes back `steps` items in the played history, like calling
+    /// [`Queue::prev`] that many times, so `1` is the previous item
+    pub fn back(&mut self, steps: usize) -> Result<&QueueItem<T, U>, QueueError> {
+        if steps == 0 || steps > self.played.len() {
+            return Err(QueueError::OutOfBounds {
+                index: steps,
+                len: self.played.len(),
+            });
+        }
+
+        for _ in 1..steps {
+            self.prev()?;
+        }
+        self.prev()
+    }
 }
 
 use thiserror::Error;
@@ -455,4 +711,447 @@ pub enum QueueError {
     EmptyPlayed,
     #[error("There is no item after this in the Queue")]
     NoNext,
+    #[error("The queue is inconsistent: {0}")]
+    Inconsistent(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::{Queue, QueueError, QueueItemType, QueueState};
+
+    fn queue(items: &[u32]) -> Queue<u32, Vec<u32>> {
+        let mut queue = Queue::new(false, None);
+        for item in items {
+            queue.add_item(*item, true);
+        }
+        queue
+    }
+
+    fn single(queue: &Queue<u32, Vec<u32>>) -> Vec<u32> {
+        queue
+            .items
+            .iter()
+            .map(|i| match i.item {
+                QueueItemType::Single(s) => s,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn played_history_is_trimmed() {
+        let mut queue = queue(&[1, 2, 3, 4, 5]);
+        for _ in 0..4 {
+            queue.next().unwrap();
+            queue.check_played(2);
+        }
+
+        assert_eq!(queue.played.len(), 2);
+        assert_eq!(queue.played[0].item, QueueItemType::Single(3));
+        assert_eq!(queue.played[1].item, QueueItemType::Single(4));
+        assert_eq!(single(&queue), [5]);
+    }
+
+    #[test]
+    fn pinned_history_is_compacted() {
+        let mut queue = queue(&[1, 2, 3, 4, 5, 6, 7]);
+        // Compacting stands in for dropping the song, leaving its uuid
+        let compact = |item: &mut u32| *item += 100;
+        for _ in 0..6 {
+            queue.next().unwrap();
+            queue.check_played_pinned(2, 5, compact);
+        }
+
+        let played: Vec<(QueueItemType<u32, Vec<u32>>, bool)> = queue
+            .played
+            .iter()
+            .map(|item| (item.item.clone(), item.compacted))
+            .collect();
+        assert_eq!(
+            played,
+            [
+                (102, true),
+                (103, true),
+                (104, true),
+                (5, false),
+                (6, false)
+            ]
+            .map(|(item, compacted)| (QueueItemType::Single(item), compacted))
+        );
+
+        // Going back past the last full item reaches the compacted ones,
+        // which stay marked until they're filled in
+        assert_eq!(queue.back(3).unwrap().item, QueueItemType::Single(104));
+        assert!(queue.items[0].compacted);
+        assert!(!queue.items[1].compacted);
+        assert_eq!(queue.prev().unwrap().item, QueueItemType::Single(103));
+        assert_eq!(single(&queue), [103, 104, 5, 6, 7]);
+
+        // Items already compacted aren't compacted again
+        queue.next().unwrap();
+        queue.check_played_pinned(0, 5, compact);
+        assert_eq!(queue.played[0].item, QueueItemType::Single(102));
+        assert_eq!(queue.played[1].item, QueueItemType::Single(103));
+        queue.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn jump_back_to_played() {
+        let mut queue = queue(&[1, 2, 3, 4]);
+        queue.next().unwrap();
+        queue.next().unwrap();
+        queue.next().unwrap();
+
+        assert_eq!(queue.back(2).unwrap().item, QueueItemType::Single(2));
+        assert_eq!(single(&queue), [2, 3, 4]);
+        assert_eq!(queue.played.len(), 1);
+
+        assert_eq!(
+            queue.back(2).unwrap_err(),
+            QueueError::OutOfBounds { index: 2, len: 1 }
+        );
+        assert!(queue.back(0).is_err());
+        assert_eq!(queue.back(1).unwrap().item, QueueItemType::Single(1));
+    }
+
+    #[test]
+    fn looping_cycles_through() {
+        let mut queue = queue(&[1, 2, 3]);
+        queue.loop_ = true;
+        let mut heard = vec![queue.current().unwrap().item.clone()];
+        for _ in 0..5 {
+            heard.push(queue.next().unwrap().item.clone());
+            queue.check_invariants().unwrap();
+        }
+        assert_eq!(heard, [1, 2, 3, 1, 2, 3].map(QueueItemType::Single));
+        // The loop starts over with everything in its first order
+        assert_eq!(queue.played.len(), 2);
+        assert_eq!(queue.played[0].state, QueueState::First);
+
+        assert_eq!(queue.next().unwrap().item, QueueItemType::Single(1));
+        assert_eq!(single(&queue), [1, 2, 3]);
+        assert!(queue.played.is_empty());
+        assert_eq!(queue.items[0].state, QueueState::First);
+
+        // Going back from the start goes around to the end, and on back
+        // through the loop
+        assert_eq!(queue.prev().unwrap().item, QueueItemType::Single(3));
+        assert_eq!(queue.prev().unwrap().item, QueueItemType::Single(2));
+        assert_eq!(queue.prev().unwrap().item, QueueItemType::Single(1));
+        assert_eq!(queue.prev().unwrap().item, QueueItemType::Single(3));
+        queue.check_invariants().unwrap();
+        assert_eq!(queue.next().unwrap().item, QueueItemType::Single(1));
+    }
+
+    #[test]
+    fn no_next_without_looping() {
+        let mut one = queue(&[1]);
+        assert_eq!(one.prev().unwrap_err(), QueueError::EmptyPlayed);
+
+        let mut queue = queue(&[1, 2, 3]);
+        queue.next().unwrap();
+        queue.next().unwrap();
+        assert_eq!(queue.next().unwrap_err(), QueueError::NoNext);
+        assert_eq!(queue.next().unwrap_err(), QueueError::EmptyQueue);
+        assert_eq!(queue.played.len(), 3);
+        assert!(queue.prev().is_ok());
+    }
+
+    #[test]
+    fn shuffle_remaining() {
+        let mut queue = queue(&[1, 2, 3]);
+        for item in [4, 5, 6] {
+            queue.add_item(item, false);
+        }
+        let add_here = queue
+            .items
+            .iter()
+            .position(|i| i.state == QueueState::AddHere);
+
+        queue.shuffle_remaining(false, |items| items.reverse());
+        assert_eq!(single(&queue), [1, 6, 5, 4, 3, 2]);
+        assert_eq!(
+            queue
+                .items
+                .iter()
+                .position(|i| i.state == QueueState::AddHere),
+            add_here
+        );
+
+        // Items added by a person stay where they are
+        let mut queue = self::queue(&[1, 2, 3]);
+        for item in [4, 5, 6] {
+            queue.add_item(item, false);
+        }
+        queue.shuffle_remaining(true, |items| items.reverse());
+        assert_eq!(single(&queue), [1, 2, 3, 6, 5, 4]);
+    }
+
+    #[test]
+    fn add_multi_keeps_order() {
+        let mut queue = queue(&[1, 2]);
+        queue.add_multi(
+            vec![QueueItemType::Single(3), QueueItemType::Single(4)],
+            true,
+        );
+        assert_eq!(single(&queue), [1, 2, 3, 4]);
+        assert_eq!(queue.items[3].state, QueueState::AddHere);
+
+        // Automatic items go at the end without moving the AddHere item
+        queue.add_multi(
+            vec![QueueItemType::Single(5), QueueItemType::Single(6)],
+            false,
+        );
+        queue.add_item(7, true);
+        assert_eq!(single(&queue), [1, 2, 3, 4, 7, 5, 6]);
+
+        queue.add_multi_next(vec![QueueItemType::Single(8), QueueItemType::Single(9)]);
+        assert_eq!(single(&queue), [1, 8, 9, 2, 3, 4, 7, 5, 6]);
+        queue.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn add_next_with_one_item() {
+        let mut queue = queue(&[1]);
+        queue.add_item_next(2);
+        queue.check_invariants().unwrap();
+        queue.add_item(3, true);
+        assert_eq!(single(&queue), [1, 2, 3]);
+    }
+
+    #[test]
+    fn replace_auto_items() {
+        let mut queue = queue(&[1, 2]);
+        for item in [3, 4] {
+            queue.add_item(item, false);
+        }
+        queue.next().unwrap();
+        queue.next().unwrap();
+        // The first automatic item now holds the AddHere
+        assert_eq!(single(&queue), [3, 4]);
+
+        queue.replace_auto_items(vec![QueueItemType::Single(5), QueueItemType::Single(6)]);
+        assert_eq!(single(&queue), [3, 5, 6]);
+        assert_eq!(queue.items[0].state, QueueState::AddHere);
+
+        queue.add_item(7, true);
+        queue.replace_auto_items(vec![QueueItemType::Single(8)]);
+        assert_eq!(single(&queue), [3, 7, 8]);
+    }
+
+    #[test]
+    fn album_block_stays_together() {
+        let block = |items: &[u32]| items.iter().copied().map(QueueItemType::Single).collect();
+        let mut queue = queue(&[1]);
+        for item in [2, 3, 4] {
+            queue.add_item(item, false);
+        }
+        queue.add_block_next(block(&[10, 11, 12]));
+        assert_eq!(single(&queue), [1, 10, 11, 12, 2, 3, 4]);
+
+        // Songs added by hand go after the album rather than into it
+        queue.add_item(5, true);
+        assert_eq!(single(&queue), [1, 10, 11, 12, 5, 2, 3, 4]);
+
+        // Shuffling moves the album as a whole
+        queue.shuffle_remaining(false, |runs| runs.reverse());
+        assert_eq!(single(&queue), [1, 4, 3, 2, 5, 10, 11, 12]);
+        queue.shuffle_remaining(false, |runs| runs.rotate_left(1));
+        assert_eq!(single(&queue), [1, 3, 2, 5, 10, 11, 12, 4]);
+
+        // Once it's playing, the rest of the album stays next, and songs
+        // picked to come up are only ever put after it
+        for _ in 0..4 {
+            queue.next().unwrap();
+        }
+        assert_eq!(single(&queue), [10, 11, 12, 4]);
+        queue.add_item(6, false);
+        queue.shuffle_remaining(false, |runs| runs.reverse());
+        assert_eq!(single(&queue), [10, 11, 12, 6, 4]);
+        queue.replace_auto_items(block(&[7, 8]));
+        assert_eq!(single(&queue), [10, 11, 12, 7, 8]);
+        queue.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn add_here_leaves_blocks_whole() {
+        let mut queue = queue(&[1, 2, 3]);
+        for item in [4, 5] {
+            queue.add_item(item, false);
+        }
+        queue.add_block_next(vec![
+            QueueItemType::Single(10),
+            QueueItemType::Single(11),
+            QueueItemType::Single(12),
+        ]);
+        assert_eq!(single(&queue), [1, 10, 11, 12, 2, 3, 4, 5]);
+        assert_eq!(queue.items[5].state, QueueState::AddHere);
+
+        // The AddHere position lands on the start of the album, so it moves
+        // to the album's end
+        queue.shuffle_remaining(false, |runs| runs.reverse());
+        assert_eq!(single(&queue), [1, 5, 4, 3, 2, 10, 11, 12]);
+        assert_eq!(queue.items[7].state, QueueState::AddHere);
+        queue.add_item(6, true);
+        assert_eq!(single(&queue), [1, 5, 4, 3, 2, 10, 11, 12, 6]);
+
+        // A song played next can go into the album, but shuffling puts the
+        // album back together
+        queue.move_item(8, 6).unwrap();
+        assert_eq!(single(&queue), [1, 5, 4, 3, 2, 10, 6, 11, 12]);
+        queue.shuffle_remaining(false, |_| ());
+        assert_eq!(single(&queue), [1, 5, 4, 3, 2, 10, 11, 12, 6]);
+        queue.check_invariants().unwrap();
+    }
+
+    /// Whether an item which wasn't added by a person sits between two items
+    /// of the same block
+    fn auto_inside_block(queue: &Queue<u32, Vec<u32>>) -> bool {
+        queue.items.iter().enumerate().any(|(i, item)| {
+            !item.by_human && {
+                let before = queue.items[..i].iter().filter_map(|item| item.block);
+                let after: Vec<u32> = queue.items[i + 1..]
+                    .iter()
+                    .filter_map(|item| item.block)
+                    .collect();
+                before.into_iter().any(|block| after.contains(&block))
+            }
+        })
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        AddItem(bool),
+        AddItemNext,
+        AddMulti(usize, bool),
+        AddMultiNext(usize),
+        AddBlockNext(usize),
+        Insert(usize, bool),
+        ReplaceAuto(usize),
+        Remove(usize),
+        Next,
+        Prev,
+        MoveTo(usize),
+        MoveItem(usize, usize),
+        Shuffle(bool, u64),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            any::<bool>().prop_map(Op::AddItem),
+            Just(Op::AddItemNext),
+            (0..4usize, any::<bool>()).prop_map(|(n, human)| Op::AddMulti(n, human)),
+            (0..4usize).prop_map(Op::AddMultiNext),
+            (0..4usize).prop_map(Op::AddBlockNext),
+            (any::<usize>(), any::<bool>()).prop_map(|(i, add_here)| Op::Insert(i, add_here)),
+            (0..4usize).prop_map(Op::ReplaceAuto),
+            any::<usize>().prop_map(Op::Remove),
+            Just(Op::Next),
+            Just(Op::Prev),
+            any::<usize>().prop_map(Op::MoveTo),
+            (any::<usize>(), any::<usize>()).prop_map(|(a, b)| Op::MoveItem(a, b)),
+            (any::<bool>(), any::<u64>()).prop_map(|(human, seed)| Op::Shuffle(human, seed)),
+        ]
+    }
+
+    fn all_items(queue: &Queue<u32, Vec<u32>>) -> Vec<u32> {
+        let mut items: Vec<u32> = queue
+            .played
+            .iter()
+            .chain(&queue.items)
+            .map(|i| match i.item {
+                QueueItemType::Single(s) => s,
+                _ => unreachable!(),
+            })
+            .collect();
+        items.sort();
+        items
+    }
+
+    proptest! {
+        #[test]
+        fn random_operations(loop_: bool, ops in prop::collection::vec(op(), 0..64)) {
+            let mut queue: Queue<u32, Vec<u32>> = Queue::new(loop_, None);
+            // Every item added is distinct, so any duplicate is a bug
+            let mut expected: Vec<u32> = Vec::new();
+            let mut next_id = 0;
+            let mut fresh = |n: usize, expected: &mut Vec<u32>| {
+                let ids: Vec<u32> = (next_id..next_id + n as u32).collect();
+                next_id += n as u32;
+                expected.extend(&ids);
+                ids
+            };
+
+            for op in ops {
+                let len = queue.items.len().max(1);
+                match op {
+                    Op::AddItem(human) => queue.add_item(fresh(1, &mut expected)[0], human),
+                    Op::AddItemNext => queue.add_item_next(fresh(1, &mut expected)[0]),
+                    Op::AddMulti(n, human) => queue.add_multi(
+                        fresh(n, &mut expected).into_iter().map(QueueItemType::Single).collect(),
+                        human,
+                    ),
+                    Op::AddMultiNext(n) => queue.add_multi_next(
+                        fresh(n, &mut expected).into_iter().map(QueueItemType::Single).collect(),
+                    ),
+                    Op::AddBlockNext(n) => queue.add_block_next(
+                        fresh(n, &mut expected).into_iter().map(QueueItemType::Single).collect(),
+                    ),
+                    Op::Insert(i, add_here) => {
+                        let item = QueueItemType::Single(fresh(1, &mut expected)[0]);
+                        queue.insert(i % (queue.items.len() + 1), item, add_here).unwrap();
+                    }
+                    Op::ReplaceAuto(n) => {
+                        for item in queue.items.iter().skip(1).filter(|item| !item.by_human) {
+                            let QueueItemType::Single(id) = item.item else { unreachable!() };
+                            expected.retain(|e| *e != id);
+                        }
+                        queue.replace_auto_items(
+                            fresh(n, &mut expected).into_iter().map(QueueItemType::Single).collect(),
+                        );
+                    }
+                    Op::Remove(i) => {
+                        if let Ok(removed) = queue.remove_item(i % len) {
+                            let QueueItemType::Single(id) = removed.item else { unreachable!() };
+                            expected.retain(|e| *e != id);
+                        }
+                    }
+                    Op::Next => _ = queue.next(),
+                    Op::Prev => _ = queue.prev(),
+                    Op::MoveTo(i) => _ = queue.move_to(i % len),
+                    Op::MoveItem(a, b) => _ = queue.move_item(a % len, b % len),
+                    Op::Shuffle(keep_human, seed) => {
+                        // Rotating by a seeded amount stands in for shuffling
+                        queue.shuffle_remaining(keep_human, |runs| {
+                            if !runs.is_empty() {
+                                let by = seed as usize % runs.len();
+                                runs.rotate_left(by);
+                            }
+                        });
+                        prop_assert!(!auto_inside_block(&queue));
+                    }
+                }
+
+                prop_assert_eq!(queue.check_invariants(), Ok(()));
+                let mut sorted = expected.clone();
+                sorted.sort();
+                prop_assert_eq!(all_items(&queue), sorted);
+            }
+
+            // The checks catch an item added twice and a shuffle order
+            // pointing past the upcoming items
+            if let Some(item) = queue.played.iter().chain(&queue.items).next().cloned() {
+                let mut copied = queue.clone();
+                copied.items.push(item);
+                prop_assert!(copied.check_invariants().is_err());
+            }
+            let mut shuffled = queue.clone();
+            shuffled.shuffle = Some((0..queue.items.len()).collect());
+            prop_assert_eq!(shuffled.check_invariants(), Ok(()));
+            shuffled.shuffle = Some(vec![queue.items.len()]);
+            prop_assert!(shuffled.check_invariants().is_err());
+        }
+    }
 }
@@ -1,15 +1,26 @@
-use std::{fs::OpenOptions, io::Write};
+use std::{fs::OpenOptions, io::Write, path::PathBuf, process::Command};
 
-use dmp_core::music_controller::{
-    controller::{ControllerHandle, PlayerLocation},
-    queue::QueueSong,
+use dmp_core::{
+    config::OutputMode,
+    music_controller::{
+        controller::{ControllerHandle, PlayerError, PlayerLocation},
+        output_mode::OutputSwitch,
+        queue::QueueSong,
+    },
+    music_storage::{
+        gain_analysis::AnalyzeScope,
+        library::{AlbumKey, Tag},
+        organize::OrganizeReport,
+        search::{QueueMode, SearchCandidate, SearchMatch},
+    },
 };
 use kushi::QueueItem;
+use serde::Serialize;
 use tauri::{AppHandle, Emitter, State, Wry};
 use tempfile::TempDir;
 use uuid::Uuid;
 
-use crate::wrappers::_Song;
+use crate::wrappers::{_Song, queue_updated};
 
 #[tauri::command]
 pub async fn add_song_to_queue(
@@ -19,34 +30,290 @@ pub async fn add_song_to_queue(
     location: PlayerLocation,
 ) -> Result<(), String> {
     dbg!(&location);
-    let (song, _) = ctrl_handle.lib_get_song(uuid).await;
+    let Some((song, _)) = ctrl_handle.lib_get_song(uuid).await else {
+        return Err(PlayerError::SongNotFound(uuid).to_string());
+    };
     match ctrl_handle
         .queue_append(QueueItem::from_item_type(kushi::QueueItemType::Single(
-            QueueSong { song, location },
+            QueueSong {
+                song,
+                location,
+                transition: None,
+            },
         )))
         .await
     {
         Ok(()) => (),
         Err(e) => return Err(e.to_string()),
     }
-    app.emit("queue_updated", ()).unwrap();
+    queue_updated(&app, &ctrl_handle).await;
     Ok(())
 }
 
+#[derive(Serialize, Clone)]
+pub enum PlayNowPayload {
+    Playing,
+    /// The song is explicit and filtered mode is on, so the user has to
+    /// confirm before it's played with `confirmed` set
+    NeedsConfirmation(Uuid),
+}
+
 #[tauri::command]
 pub async fn play_now(
     app: AppHandle<Wry>,
     ctrl_handle: State<'_, ControllerHandle>,
     uuid: Uuid,
     location: PlayerLocation,
-) -> Result<(), String> {
-    let song = match ctrl_handle.play_now(uuid, location).await {
-        Ok(song) => song,
-        Err(e) => return Err(e.to_string()),
+    confirmed: Option<bool>,
+    shuffle_remaining: Option<bool>,
+) -> Result<PlayNowPayload, String> {
+    // "Shuffle from here" in a playlist
+    let shuffle_remaining = shuffle_remaining.unwrap_or(false);
+    let res = match confirmed {
+        Some(true) => {
+            ctrl_handle
+                .play_now_confirmed(uuid, location, shuffle_remaining)
+                .await
+        }
+        _ => {
+            ctrl_handle
+                .play_now(uuid, location, shuffle_remaining)
+                .await
+        }
     };
-    app.emit("queue_updated", ()).unwrap();
-    app.emit("now_playing_change", _Song::from(&song)).unwrap();
-    app.emit("playing", ()).unwrap();
+    match res {
+        Ok(_) => (),
+        Err(PlayerError::NeedsConfirmation(uuid)) => {
+            return Ok(PlayNowPayload::NeedsConfirmation(uuid))
+        }
+        Err(e) => return Err(e.to_string()),
+    }
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(PlayNowPayload::Playing)
+}
+
+#[tauri::command]
+pub async fn play_album(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+    starting_track: Option<(u16, u16)>,
+) -> Result<(), String> {
+    ctrl_handle
+        .play_album(key, starting_track)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Plays an album from a cue sheet as one continuous file
+#[tauri::command]
+pub async fn play_cue_album(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+) -> Result<(), String> {
+    ctrl_handle
+        .play_cue_album(key)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Rates the songs in an album which weren't rated on their own
+#[tauri::command]
+pub async fn set_album_rating(
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+    rating: Option<u8>,
+) -> Result<(), String> {
+    ctrl_handle.lib_set_album_rating(key, rating).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+/// Favorites the songs in an album which weren't favorited on their own
+#[tauri::command]
+pub async fn set_album_favorite(
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+    favorited: bool,
+) -> Result<(), String> {
+    ctrl_handle.lib_set_album_favorite(key, favorited).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+/// Marks an album to always play without crossfading between its tracks,
+/// for live albums and mixes which aren't detected on their own
+#[tauri::command]
+pub async fn set_album_gapless(
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+    gapless: bool,
+) -> Result<(), String> {
+    ctrl_handle.lib_set_album_gapless(key, gapless).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+/// Switches between shared and exclusive output. Falling back to shared
+/// output is also sent as `output_mode_fallback` with the reason, for
+/// anything else showing the output.
+#[tauri::command]
+pub async fn set_output_mode(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    mode: OutputMode,
+) -> Result<OutputSwitch, String> {
+    let switch = ctrl_handle
+        .set_output_mode(mode)
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(reason) = &switch.fallback {
+        app.emit("output_mode_fallback", reason).unwrap();
+    }
+    Ok(switch)
+}
+
+/// Moves song files into folders named after their tags, or only shows
+/// where they would go with `dry_run`
+#[tauri::command]
+pub async fn organize_files(
+    ctrl_handle: State<'_, ControllerHandle>,
+    pattern: String,
+    scope: AnalyzeScope,
+    dry_run: bool,
+) -> Result<OrganizeReport, String> {
+    ctrl_handle
+        .lib_organize_files(pattern, scope, dry_run)
+        .await
+}
+
+#[tauri::command]
+pub async fn play_artist(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    artist: String,
+) -> Result<(), String> {
+    ctrl_handle
+        .play_artist(artist)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Queues every song matching a library filter, returning how many songs
+/// matched
+#[tauri::command]
+pub async fn queue_add_from_filter(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    query: String,
+    target_tags: Vec<Tag>,
+    sort: Vec<Tag>,
+    mode: QueueMode,
+) -> Result<usize, String> {
+    let matched = ctrl_handle
+        .queue_add_from_filter(query, target_tags, sort, mode)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(matched)
+}
+
+/// Adds the rest of the playing song's album to the queue, returning how
+/// many songs were added
+#[tauri::command]
+pub async fn enqueue_rest_of_album(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<usize, String> {
+    let added = ctrl_handle
+        .enqueue_rest_of_album()
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(added)
+}
+
+/// Queues an album to play right after the current song, where its tracks
+/// stay together even if the queue is shuffled, returning how many songs
+/// were added
+#[tauri::command]
+pub async fn enqueue_album_next(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+) -> Result<usize, String> {
+    let added = ctrl_handle
+        .enqueue_album_next(key)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(added)
+}
+
+/// Adds up to `count` more songs by the playing song's artist to the queue,
+/// returning how many songs were added
+#[tauri::command]
+pub async fn enqueue_more_by_artist(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    count: usize,
+) -> Result<usize, String> {
+    let added = ctrl_handle
+        .enqueue_more_by_artist(count)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(added)
+}
+
+#[derive(Serialize, Clone)]
+pub enum SearchPayload {
+    Found(_Song),
+    /// Shown as a list for the user to pick from
+    Ambiguous(Vec<SearchCandidate>),
+    NotFound,
+}
+
+#[tauri::command]
+pub async fn search_and_queue(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    query: String,
+    mode: QueueMode,
+) -> Result<SearchPayload, String> {
+    let found = ctrl_handle
+        .search_and_queue(query, mode)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(match found {
+        SearchMatch::Found(song) => {
+            queue_updated(&app, &ctrl_handle).await;
+            SearchPayload::Found(_Song::from(&*song))
+        }
+        SearchMatch::Ambiguous(candidates) => SearchPayload::Ambiguous(candidates),
+        SearchMatch::NotFound => SearchPayload::NotFound,
+    })
+}
+
+#[tauri::command]
+pub async fn play_genre(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    genre: String,
+) -> Result<(), String> {
+    ctrl_handle
+        .play_genre(genre)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
     Ok(())
 }
 
@@ -56,27 +323,47 @@ pub async fn display_album_art(
     temp_dir: State<'_, TempDir>,
     uuid: Uuid,
 ) -> Result<(), String> {
-    match ctrl_handle.lib_get_song(uuid.clone()).await.0.album_art(0) {
-        Ok(art) => {
-            let mut art = art.unwrap();
-            let path = temp_dir.path().join(format!(
-                "CoverArt_{uuid}.{}",
-                file_format::FileFormat::from_bytes(&art).extension()
-            ));
-            if !path.exists() {
-                // TODO: This can be optimised later
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .read(true)
-                    .open(path.clone())
-                    .unwrap();
-                file.write_all(&mut art).unwrap();
-            }
-            opener::open(path).unwrap();
-        }
-        Err(e) => return Err(e.to_string()),
+    let Some((source, mut art)) = ctrl_handle.lib_cover_art(uuid).await else {
+        return Err(String::from("The song has no art"));
+    };
+    // Art taken from another song on the album is kept apart, so it isn't
+    // shown once the song has art of its own
+    let name = match source == uuid {
+        true => format!("CoverArt_{uuid}"),
+        false => format!("CoverArt_{uuid}_{source}"),
     };
+    let path = temp_dir.path().join(format!(
+        "{name}.{}",
+        file_format::FileFormat::from_bytes(&art).extension()
+    ));
+    if !path.exists() {
+        // TODO: This can be optimised later
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(path.clone())
+            .unwrap();
+        file.write_all(&mut art).unwrap();
+    }
+    opener::open(path).unwrap();
     Ok(())
 }
+
+#[tauri::command]
+pub async fn reveal_in_file_manager(path: PathBuf) -> Result<(), String> {
+    let res = if cfg!(target_os = "windows") {
+        Command::new("explorer")
+            .arg(format!("/select,{}", path.display()))
+            .spawn()
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg("-R").arg(&path).spawn()
+    } else {
+        // There's no common way to select a file on Linux, so open its folder
+        Command::new("xdg-open")
+            .arg(path.parent().unwrap_or(&path))
+            .spawn()
+    };
+    res.map(|_| ()).map_err(|e| e.to_string())
+}
@@ -3,37 +3,83 @@
 use std::{
     fs,
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
     thread::{scope, spawn},
     time::Duration,
 };
 
 use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use dmp_core::{
-    config::{Config, ConfigLibrary},
+    config::{
+        data_dir::{DataDir, PORTABLE_ENV},
+        Config, ConfigLibraries, ConfigLibrary,
+    },
     music_controller::{
+        audio_device::DeviceNotification,
         connections::ConnectionsInput,
-        controller::{Controller, ControllerHandle, PlaybackInfo},
+        controller::{
+            Controller, ControllerHandle, PlaybackInfo, PlaybackState, PlayerNotification,
+            SkippedSong,
+        },
+        controller_state::PlaybackModes,
+        now_playing::NowPlayingTracker,
+        web_remote::WebRemote,
+    },
+    music_storage::{
+        art_store::ArtStore,
+        cancel::CancelToken,
+        library::{MusicLibrary, Song},
+        library_guard::{LibraryConflict, LibraryLock},
+        scan_exclusions::ExclusionRules,
+        scan_report::SCAN_REPORT_FILE,
     },
-    music_storage::library::{MusicLibrary, Song},
 };
 use futures::channel::oneshot;
 use parking_lot::RwLock;
-use tauri::{http::Response, Emitter, Manager, State, Wry};
+use tauri::{http::Response, AppHandle, Emitter, Manager, State, Wry};
 use uuid::Uuid;
-use wrappers::{_Song, stop};
+use wrappers::{queue_updated, stop, DevicePayload, NowPlayingPayload};
 
 use crate::wrappers::{
-    get_library, get_playlist, get_playlists, get_queue, get_song, import_playlist, next, pause,
-    play, prev, remove_from_queue, seek, set_volume,
+    add_radio_station, add_stream, analyze_gain, apply_filename_pattern, cancel_check_songs,
+    cancel_gain_analysis, cancel_verify_files, cancel_waveform, check_songs, clean_tags,
+    detect_linked_versions, drop_location, filename_presets, find_missing_track_matches,
+    flush_library, get_active_jobs, get_albums, get_art_cache_metrics, get_connection_status,
+    get_continue_listening, get_filtered_mode, get_flagged_songs, get_library, get_listen_counts,
+    get_missing_tracks, get_multi_location_songs, get_now_playing, get_on_this_day,
+    get_playback_modes, get_player_state, get_playlist, get_playlists, get_queue,
+    get_radio_stations, get_recent_scrobbles, get_scan_report, get_settings, get_song,
+    get_song_details, get_songs, get_ui_state, get_waveform, get_web_remote_url,
+    import_external_library, import_playlist, link_versions, move_in_queue, next, pause,
+    pin_auto_playlist, play, play_from_queue, play_played, prev, preview_exclusions, refresh_album,
+    refresh_auto_playlists, refresh_songs, remove_excluded, remove_from_queue, remove_missing,
+    repair_playlists, reread_song, resolve_library_conflict, resolve_missing_track,
+    retag_file_format, retract_and_resubmit, retry_scan_file, scan_library, seek, seek_preview,
+    set_explicit, set_filter_pin, set_filtered_mode, set_library_profile, set_playback_modes,
+    set_playlist_profile, set_playlist_sort_order, set_preferred_art, set_transition, set_trim,
+    set_ui_state, set_volume, shuffle_queue, undo_remove_missing, update_settings, verify_files,
+    volume_step, CheckJob, GainJob, QueueRevision, VerifyJob, WaveformJob, WebRemoteState,
+};
+use commands::{
+    add_song_to_queue, display_album_art, enqueue_album_next, enqueue_more_by_artist,
+    enqueue_rest_of_album, organize_files, play_album, play_artist, play_cue_album, play_genre,
+    play_now, queue_add_from_filter, reveal_in_file_manager, search_and_queue, set_album_favorite,
+    set_album_gapless, set_album_rating, set_output_mode,
 };
-use commands::{add_song_to_queue, display_album_art, play_now};
+use windows::{close_miniplayer, open_miniplayer, WindowManager};
 
 pub mod commands;
+pub mod windows;
 pub mod wrappers;
 
 const DEFAULT_IMAGE: &[u8] = include_bytes!("../icons/icon.png");
 
+/// How long closing waits for library jobs like scans to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let (rx, tx) = unbounded::<Config>();
@@ -41,7 +87,15 @@ pub fn run() {
     let (handle_rx, handle_tx) = unbounded::<ControllerHandle>();
     let (playback_info_rx, playback_info_tx) = bounded(1);
     let (next_rx, next_tx) = bounded(1);
-
+    let (device_rx, device_tx) = bounded(1);
+    let (conflict_rx, conflict_tx) = bounded(1);
+    let (modes_rx, modes_tx) = bounded(1);
+    let (skipped_rx, skipped_tx) = bounded(1);
+    let (now_playing_rx, now_playing_tx) = bounded(1);
+    let (remote_rx, remote_tx) = bounded::<Option<WebRemote>>(1);
+    let cancel = CancelToken::new();
+
+    let setup_cancel = cancel.clone();
     let _controller_thread = spawn(move || {
         let mut config = { tx.recv().unwrap() };
         let scan_path = { lib_tx.recv().unwrap() };
@@ -49,7 +103,11 @@ pub fn run() {
         let _lib = config.libraries.get_default().unwrap_or(&_temp_config);
 
         let save_path = if _lib.path == PathBuf::default() {
-            let p = scan_path.as_ref().unwrap().clone().canonicalize().unwrap();
+            // Portable libraries are kept with the rest of the data
+            let p = match &config.portable_dir {
+                Some(dir) => dir.clone(),
+                None => scan_path.as_ref().unwrap().clone().canonicalize().unwrap(),
+            };
 
             if cfg!(windows) {
                 p.join("library_windows.dlib")
@@ -66,6 +124,11 @@ pub fn run() {
             save_path.display()
         );
 
+        // Held for as long as the player runs, so the command line tool
+        // doesn't save over the library while it's open
+        let _lock = LibraryLock::acquire(&save_path)
+            .inspect_err(|e| println!("Couldn't lock the library: {e}"))
+            .ok();
         let mut library = MusicLibrary::init(save_path.clone(), _lib.uuid).unwrap();
 
         let scan_path = scan_path.unwrap_or_else(|| {
@@ -79,8 +142,14 @@ pub fn run() {
                 .clone()
         });
 
+        // Closing while the library is set up waits for it to be saved
+        let setup = setup_cancel.job();
         if config.libraries.get_default().is_err() {
-            library.scan_folder(&scan_path).unwrap();
+            // A new library has no exclusions set up yet
+            let report = library
+                .scan_folder(&scan_path, &ExclusionRules::default(), &setup_cancel)
+                .unwrap();
+            _ = report.write_file(config.path.with_file_name(SCAN_REPORT_FILE));
             config.push_library(ConfigLibrary::new(
                 save_path.clone(),
                 String::from("Library"),
@@ -95,20 +164,52 @@ pub fn run() {
         }
         println!("scan_path: {}", scan_path.display());
 
+        library.generate_auto_playlists(
+            config.auto_playlists.track_limit,
+            config.auto_playlists.top_genres,
+        );
         library.save(save_path).unwrap();
-
-        let (handle, input, playback_info, next_song_notification) = ControllerHandle::new(
+        drop(setup);
+
+        let web_remote_config = config.web_remote.clone();
+        let legacy_events = config.playback.legacy_player_events;
+        let (
+            handle,
+            input,
+            playback_info,
+            next_song_notification,
+            device_notification,
+            conflict_notification,
+            modes_notification,
+            skipped_notification,
+        ) = ControllerHandle::new(
             library,
             std::sync::Arc::new(RwLock::new(config)),
             Some(ConnectionsInput {
                 discord_rpc_client_id: std::option_env!("DISCORD_CLIENT_ID")
                     .map(|id| id.parse::<u64>().unwrap()),
             }),
+            setup_cancel,
         );
 
-        handle_rx.send(handle).unwrap();
         playback_info_rx.send(playback_info).unwrap();
-        next_rx.send(next_song_notification).unwrap();
+        next_rx
+            .send((next_song_notification, legacy_events))
+            .unwrap();
+        now_playing_rx.send(handle.now_playing_tracker()).unwrap();
+        device_rx.send(device_notification).unwrap();
+        conflict_rx.send(conflict_notification).unwrap();
+        modes_rx.send(modes_notification).unwrap();
+        skipped_rx.send(skipped_notification).unwrap();
+
+        let web_remote = match web_remote_config.enabled {
+            true => WebRemote::start(handle.clone(), &web_remote_config)
+                .inspect_err(|e| println!("{e}"))
+                .ok(),
+            false => None,
+        };
+        remote_rx.send(web_remote).unwrap();
+        handle_rx.send(handle).unwrap();
 
         let _controller = futures::executor::block_on(Controller::start(input)).unwrap();
     });
@@ -116,30 +217,129 @@ pub fn run() {
         .plugin(tauri_plugin_shell::init())
         .invoke_handler(tauri::generate_handler![
             get_config,
+            get_settings,
+            update_settings,
+            get_ui_state,
+            set_ui_state,
             create_new_library,
             get_library,
             play,
             pause,
             stop,
             set_volume,
+            volume_step,
+            get_player_state,
             next,
             prev,
             get_song,
+            get_songs,
             lib_already_created,
             get_queue,
             add_song_to_queue,
             play_now,
             import_playlist,
+            import_external_library,
+            organize_files,
             get_playlist,
             get_playlists,
             remove_from_queue,
+            move_in_queue,
+            play_from_queue,
             display_album_art,
+            enqueue_rest_of_album,
+            enqueue_more_by_artist,
+            enqueue_album_next,
             seek,
+            seek_preview,
+            refresh_auto_playlists,
+            pin_auto_playlist,
+            play_album,
+            play_cue_album,
+            play_artist,
+            play_genre,
+            get_recent_scrobbles,
+            retract_and_resubmit,
+            open_miniplayer,
+            close_miniplayer,
+            get_waveform,
+            cancel_waveform,
+            get_scan_report,
+            retry_scan_file,
+            scan_library,
+            get_active_jobs,
+            reveal_in_file_manager,
+            clean_tags,
+            apply_filename_pattern,
+            filename_presets,
+            analyze_gain,
+            cancel_gain_analysis,
+            verify_files,
+            cancel_verify_files,
+            check_songs,
+            cancel_check_songs,
+            get_flagged_songs,
+            get_multi_location_songs,
+            drop_location,
+            play_played,
+            get_connection_status,
+            get_art_cache_metrics,
+            get_albums,
+            get_listen_counts,
+            get_web_remote_url,
+            get_song_details,
+            get_continue_listening,
+            get_on_this_day,
+            reread_song,
+            refresh_songs,
+            refresh_album,
+            retag_file_format,
+            set_trim,
+            set_playlist_sort_order,
+            set_playlist_profile,
+            get_missing_tracks,
+            find_missing_track_matches,
+            resolve_missing_track,
+            set_library_profile,
+            resolve_library_conflict,
+            flush_library,
+            remove_missing,
+            undo_remove_missing,
+            preview_exclusions,
+            remove_excluded,
+            repair_playlists,
+            add_stream,
+            add_radio_station,
+            get_radio_stations,
+            get_filtered_mode,
+            set_filtered_mode,
+            set_filter_pin,
+            set_explicit,
+            detect_linked_versions,
+            link_versions,
+            search_and_queue,
+            queue_add_from_filter,
+            set_preferred_art,
+            shuffle_queue,
+            set_transition,
+            get_now_playing,
+            get_playback_modes,
+            set_playback_modes,
+            set_album_rating,
+            set_album_favorite,
+            set_album_gapless,
+            set_output_mode,
         ])
         .manage(ConfigRx(rx))
         .manage(LibRx(lib_rx))
         .manage(HandleTx(handle_tx))
-        .manage(tempfile::TempDir::new().unwrap())
+        .manage(art_cache())
+        .manage(WindowManager::default())
+        .manage(WaveformJob::default())
+        .manage(GainJob::default())
+        .manage(VerifyJob::default())
+        .manage(CheckJob::default())
+        .manage(QueueRevision::default())
+        .manage(WebRemoteState::default())
         .setup(|app| {
             let _app = app.handle().clone();
             let app = _app.clone();
@@ -147,18 +347,36 @@ pub fn run() {
             std::thread::Builder::new()
                 .name("PlaybackInfo handler".to_string())
                 .spawn(move || {
+                    // The remote can be restarted from the settings, so
+                    // it's looked up each time
+                    *app.state::<WebRemoteState>().0.write() = remote_tx.recv().unwrap();
+
                     let mut _info: Arc<RwLock<PlaybackInfo>> =
                         Arc::new(RwLock::new(PlaybackInfo::default()));
                     let mut _now_playing: Arc<RwLock<Option<Song>>> = Arc::new(RwLock::new(None));
 
+                    // The handle is managed once the frontend has loaded
+                    // the library, before anything can play
+                    let emit_queue_updated = || {
+                        if let Some(ctrl_handle) = app.try_state::<ControllerHandle>() {
+                            futures::executor::block_on(queue_updated(&app, &ctrl_handle));
+                        }
+                    };
+
                     scope(|s| {
                         let info = _info.clone();
                         s.spawn(|| {
                             let info = info;
+                            // Sent however the legacy events are set, as it has
+                            // the output and time listened which `now_playing`
+                            // doesn't
                             let playback_info = playback_info_tx.recv().unwrap();
                             while true {
                                 let i = playback_info.take();
                                 app.emit("playback_info", i.clone()).unwrap();
+                                if let Some(remote) = &*app.state::<WebRemoteState>().0.read() {
+                                    remote.set_playback(i.clone());
+                                }
                                 *info.write() = i;
                                 std::thread::sleep(Duration::from_millis(100));
                             }
@@ -167,13 +385,90 @@ pub fn run() {
                         let now_playing = _now_playing.clone();
                         s.spawn(|| {
                             let now_playing = now_playing;
-                            let next_song_notification = next_tx.recv().unwrap();
+                            let (next_song_notification, legacy_events) = next_tx.recv().unwrap();
+                            // Whether the song is playing only ever comes from
+                            // the player, so a song which failed to load
+                            // doesn't look like it's playing
+                            let emit_state = |state: PlaybackState| match state {
+                                _ if !legacy_events => {}
+                                PlaybackState::Playing => app.emit("playing", ()).unwrap(),
+                                PlaybackState::Paused | PlaybackState::Stopped => {
+                                    app.emit("paused", ()).unwrap()
+                                }
+                            };
+                            while true {
+                                match next_song_notification.recv().unwrap() {
+                                    PlayerNotification::SongChange(change, state) => {
+                                        if legacy_events {
+                                            app.emit(
+                                                "now_playing_change",
+                                                NowPlayingPayload::new(&change, state),
+                                            )
+                                            .unwrap();
+                                        }
+                                        emit_queue_updated();
+                                        emit_state(state);
+                                        if let Some(remote) =
+                                            &*app.state::<WebRemoteState>().0.read()
+                                        {
+                                            remote.set_now_playing(Song::clone(&change.song));
+                                        }
+                                        _ = now_playing.write().insert(Song::clone(&change.song));
+                                    }
+                                    PlayerNotification::State(state) => emit_state(state),
+                                    PlayerNotification::LoadFailed(failure) => {
+                                        app.emit("playback_error", &failure).unwrap();
+                                        emit_state(failure.state);
+                                    }
+                                }
+                            }
+                        });
+
+                        // Everything about what's playing in one event, sent
+                        // once for however many changes came in together
+                        s.spawn(|| {
+                            let tracker: NowPlayingTracker = now_playing_tx.recv().unwrap();
+                            let changes = tracker.changes();
+                            while changes.recv().is_ok() {
+                                app.emit("now_playing", tracker.get()).unwrap();
+                            }
+                        });
+
+                        s.spawn(|| {
+                            let device_notification: Receiver<DeviceNotification> =
+                                device_tx.recv().unwrap();
+                            while true {
+                                let notification = device_notification.recv().unwrap();
+                                app.emit("audio_device_change", DevicePayload::from(&notification))
+                                    .unwrap();
+                            }
+                        });
+
+                        s.spawn(|| {
+                            let conflict_notification: Receiver<LibraryConflict> =
+                                conflict_tx.recv().unwrap();
                             while true {
-                                let song = next_song_notification.recv().unwrap();
-                                app.emit("now_playing_change", _Song::from(&song)).unwrap();
-                                app.emit("queue_updated", ()).unwrap();
-                                app.emit("playing", ()).unwrap();
-                                _ = now_playing.write().insert(song);
+                                let conflict = conflict_notification.recv().unwrap();
+                                app.emit("library_conflict", conflict).unwrap();
+                            }
+                        });
+
+                        s.spawn(|| {
+                            let modes_notification: Receiver<PlaybackModes> =
+                                modes_tx.recv().unwrap();
+                            while true {
+                                let modes = modes_notification.recv().unwrap();
+                                app.emit("playback_modes_changed", modes).unwrap();
+                            }
+                        });
+
+                        s.spawn(|| {
+                            let skipped_notification: Receiver<SkippedSong> =
+                                skipped_tx.recv().unwrap();
+                            while true {
+                                let skipped = skipped_notification.recv().unwrap();
+                                app.emit("song_skipped", skipped.message()).unwrap();
+                                emit_queue_updated();
                             }
                         });
                     });
@@ -199,15 +494,13 @@ pub fn run() {
             } else {
                 futures::executor::block_on(async move {
                     let controller = ctx.app_handle().state::<ControllerHandle>();
-                    let song = controller
-                        .lib_get_song(Uuid::parse_str(query.as_str()).unwrap())
-                        .await
-                        .0;
-                    Some(
-                        song.album_art(0)
-                            .unwrap_or_else(|_| None)
-                            .unwrap_or(DEFAULT_IMAGE.to_vec()),
-                    )
+                    // Songs without art show another one's from their album,
+                    // and unknown songs the default
+                    let art = match Uuid::parse_str(query.as_str()) {
+                        Ok(uuid) => controller.lib_cover_art(uuid).await,
+                        Err(_) => None,
+                    };
+                    Some(art.map_or(DEFAULT_IMAGE.to_vec(), |(_, art)| art))
                 })
             };
 
@@ -223,24 +516,103 @@ pub fn run() {
         .build(tauri::generate_context!())
         .expect("error while building tauri application");
 
-    app.run(|_app_handle, event| match event {
-        tauri::RunEvent::ExitRequested { .. } => {
-            // api.prevent_exit();
-            //panic!("does this kill the player?")
+    app.run(move |_app_handle, event| match event {
+        // Closing the last window exits, so finish up while it can still
+        // show that
+        tauri::RunEvent::WindowEvent {
+            label,
+            event: tauri::WindowEvent::CloseRequested { api, .. },
+            ..
+        } if label == "main"
+            && _app_handle.webview_windows().len() == 1
+            && finish_up(_app_handle, &cancel) =>
+        {
+            api.prevent_close();
+        }
+        tauri::RunEvent::ExitRequested { api, .. } if finish_up(_app_handle, &cancel) => {
+            api.prevent_exit();
+        }
+        tauri::RunEvent::Exit => {
+            if let Some(remote) = _app_handle.state::<WebRemoteState>().0.write().take() {
+                remote.stop();
+            }
+            // Stats are only saved every few minutes, so don't lose them
+            if let Some(ctrl_handle) = _app_handle.try_state::<ControllerHandle>() {
+                futures::executor::block_on(ctrl_handle.lib_flush());
+            }
         }
         _ => {}
     });
 }
 
+/// Stops the library's long jobs and saves everything before exiting, which
+/// is done away from the event loop, then exits. Returns whether exiting
+/// has to wait for that, which it doesn't once it's done.
+fn finish_up(app: &AppHandle<Wry>, cancel: &CancelToken) -> bool {
+    static FINISHING: AtomicBool = AtomicBool::new(false);
+    if FINISHING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    _ = app.emit("finishing_up", ());
+    let (app, cancel) = (app.clone(), cancel.clone());
+    spawn(move || {
+        let stopped = match app.try_state::<ControllerHandle>() {
+            Some(ctrl_handle) => {
+                futures::executor::block_on(ctrl_handle.shutdown(SHUTDOWN_TIMEOUT))
+            }
+            // The library is still being set up, which saves it once its
+            // scan stops
+            None => {
+                cancel.cancel();
+                cancel.wait(SHUTDOWN_TIMEOUT)
+            }
+        };
+        if !stopped {
+            println!("Library jobs were still running when exiting");
+        }
+        app.exit(0);
+    });
+    true
+}
+
 struct ConfigRx(Sender<Config>);
 
 struct LibRx(Sender<Option<PathBuf>>);
 struct HandleTx(Receiver<ControllerHandle>);
 
+/// Where the config, controller state and caches are kept, which is next to
+/// the executable in portable mode
+pub(crate) fn data_dir() -> Option<&'static DataDir> {
+    static DATA_DIR: OnceLock<Option<DataDir>> = OnceLock::new();
+    DATA_DIR
+        .get_or_init(|| {
+            let exe = std::env::current_exe().ok();
+            let requested = DataDir::requested(std::env::args(), std::env::var_os(PORTABLE_ENV));
+            let installed = directories::ProjectDirs::from("", "Dangoware", "dmp")
+                .map(|dir| dir.config_dir().to_path_buf());
+            DataDir::resolve(exe.as_deref(), requested, installed)
+        })
+        .as_ref()
+}
+
+/// Where album art is copied to for opening it, and where embedded art is
+/// taken out to, which is removed on exit
+fn art_cache() -> tempfile::TempDir {
+    let temp = data_dir().map_or_else(std::env::temp_dir, DataDir::temp_dir);
+    _ = fs::create_dir_all(&temp);
+    let cache = tempfile::TempDir::new_in(temp).unwrap();
+    ArtStore::shared().spill_to(cache.path().join("embedded"));
+    cache
+}
+
+/// Loads the config and hands it to the controller, returning only the
+/// libraries so nothing secret is sent to the frontend. Settings are read and
+/// changed a section at a time with `get_settings` and `update_settings`.
 #[tauri::command]
-async fn get_config(state: State<'_, ConfigRx>) -> Result<Config, String> {
-    if let Some(dir) = directories::ProjectDirs::from("", "Dangoware", "dmp") {
-        let path = dir.config_dir();
+async fn get_config(state: State<'_, ConfigRx>) -> Result<ConfigLibraries, String> {
+    if let Some(data_dir) = data_dir() {
+        let path = data_dir.path();
         fs::create_dir_all(path)
             .or_else(|err| {
                 if err.kind() == std::io::ErrorKind::AlreadyExists {
@@ -251,24 +623,18 @@ async fn get_config(state: State<'_, ConfigRx>) -> Result<Config, String> {
             })
             .unwrap();
 
-        let config = if let Ok(mut c) = Config::read_file(PathBuf::from(path).join("config")) {
-            if c.state_path == PathBuf::default() {
-                c.state_path = PathBuf::from(path).join("state");
-            }
+        let config = if let Ok(c) = data_dir.read_config() {
             c
         } else {
-            let c = Config {
-                path: PathBuf::from(path).join("config"),
-                state_path: PathBuf::from(path).join("state"),
-                ..Default::default()
-            };
+            let c = data_dir.new_config();
             c.write_file().unwrap();
             c
         };
 
-        state.inner().0.send(config.clone()).unwrap();
+        let libraries = config.libraries.clone();
+        state.inner().0.send(config).unwrap();
 
-        Ok(config)
+        Ok(libraries)
     } else {
         panic!("No config dir for DMP")
     }
@@ -0,0 +1,169 @@
+//! Management of the auxiliary windows, like the mini player, which
+//! remembers where each of them was last placed
+
+use std::collections::BTreeMap;
+
+use dmp_core::config::{Config, ConfigWindow};
+use parking_lot::Mutex;
+use tauri::{
+    AppHandle, Manager, PhysicalPosition, PhysicalSize, State, WebviewUrl, WebviewWindowBuilder,
+    WindowEvent, Wry,
+};
+
+use crate::data_dir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuxWindow {
+    MiniPlayer,
+}
+
+impl AuxWindow {
+    pub fn label(&self) -> &'static str {
+        match self {
+            AuxWindow::MiniPlayer => "miniplayer",
+        }
+    }
+
+    fn url(&self) -> &'static str {
+        match self {
+            AuxWindow::MiniPlayer => "src/miniplayer/index.html",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AuxWindow::MiniPlayer => "Mini Player",
+        }
+    }
+
+    fn default_size(&self) -> (f64, f64) {
+        match self {
+            AuxWindow::MiniPlayer => (320.0, 120.0),
+        }
+    }
+
+    fn always_on_top(&self) -> bool {
+        match self {
+            AuxWindow::MiniPlayer => true,
+        }
+    }
+}
+
+/// Keeps track of which auxiliary windows are open and where they are
+#[derive(Default)]
+pub struct WindowManager {
+    open: Mutex<BTreeMap<String, ConfigWindow>>,
+}
+
+impl WindowManager {
+    /// Opens the window, or focuses it if it is already open
+    pub fn open(&self, app: &AppHandle<Wry>, window: AuxWindow) -> tauri::Result<()> {
+        if let Some(existing) = app.get_webview_window(window.label()) {
+            existing.show()?;
+            return existing.set_focus();
+        }
+
+        let (width, height) = window.default_size();
+        let webview =
+            WebviewWindowBuilder::new(app, window.label(), WebviewUrl::App(window.url().into()))
+                .title(window.title())
+                .inner_size(width, height)
+                .always_on_top(window.always_on_top())
+                .build()?;
+
+        // A window can't be brought back at no size, so one saved like that
+        // is opened where it would be otherwise
+        let saved = read_config()
+            .and_then(|config| config.windows.get(window.label()).copied())
+            .filter(|saved| saved.width > 0 && saved.height > 0);
+        let placement = match saved {
+            Some(saved) => {
+                webview.set_position(PhysicalPosition::new(saved.x, saved.y))?;
+                webview.set_size(PhysicalSize::new(saved.width, saved.height))?;
+                saved
+            }
+            None => {
+                let position = webview.outer_position()?;
+                let size = webview.inner_size()?;
+                ConfigWindow {
+                    x: position.x,
+                    y: position.y,
+                    width: size.width,
+                    height: size.height,
+                }
+            }
+        };
+        self.open
+            .lock()
+            .insert(window.label().to_string(), placement);
+
+        let app = app.clone();
+        webview.on_window_event(move |event| {
+            let manager = app.state::<WindowManager>();
+            let mut open = manager.open.lock();
+            match event {
+                WindowEvent::Moved(position) => {
+                    if let Some(placement) = open.get_mut(window.label()) {
+                        placement.x = position.x;
+                        placement.y = position.y;
+                    }
+                }
+                // Minimizing can resize the window to nothing
+                WindowEvent::Resized(size) if size.width > 0 && size.height > 0 => {
+                    if let Some(placement) = open.get_mut(window.label()) {
+                        placement.width = size.width;
+                        placement.height = size.height;
+                    }
+                }
+                WindowEvent::Destroyed => {
+                    if let Some(placement) = open.remove(window.label()) {
+                        save_placement(window, placement);
+                    }
+                }
+                _ => (),
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Closes the window if it is open
+    pub fn close(&self, app: &AppHandle<Wry>, window: AuxWindow) -> tauri::Result<()> {
+        match app.get_webview_window(window.label()) {
+            Some(webview) => webview.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_config() -> Option<Config> {
+    data_dir()?.read_config().ok()
+}
+
+fn save_placement(window: AuxWindow, placement: ConfigWindow) {
+    let Some(mut config) = read_config() else {
+        return;
+    };
+    config.windows.insert(window.label().to_string(), placement);
+    _ = config.write_file();
+}
+
+#[tauri::command]
+pub async fn open_miniplayer(
+    app: AppHandle<Wry>,
+    windows: State<'_, WindowManager>,
+) -> Result<(), String> {
+    windows
+        .open(&app, AuxWindow::MiniPlayer)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn close_miniplayer(
+    app: AppHandle<Wry>,
+    windows: State<'_, WindowManager>,
+) -> Result<(), String> {
+    windows
+        .close(&app, AuxWindow::MiniPlayer)
+        .map_err(|e| e.to_string())
+}
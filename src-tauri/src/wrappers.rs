@@ -1,45 +1,100 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+use chrono::{Datelike, Local};
 use crossbeam::channel::Sender;
 use dmp_core::{
-    music_controller::controller::{ControllerHandle, PlayerLocation},
-    music_storage::library::{Song, Tag, URI},
+    config::settings::{Settings, SettingsSection},
+    music_controller::{
+        audio_device::{DeviceAction, DeviceEvent, DeviceNotification},
+        connections::ConnectionStatus,
+        continue_listening::ContinueEntry,
+        controller::{
+            ControllerHandle, NowPlayingChange, PlaybackState, PlayerError, PlayerLocation,
+            PlayerState, QueuePosition, TrackChangeReason,
+        },
+        controller_state::{ControllerState, PlaybackModes, RepeatMode, ShuffleMode},
+        icy::StreamTitle,
+        jobs::{JobStatus, ScanOutcome},
+        now_playing::NowPlaying,
+        queue::{QueueAlbum, QueueInfo, QueueSong, Transition},
+        queue_log::QueueOp,
+        scrobbles::{ScrobbleCorrection, ScrobbleEntry, ScrobbleId},
+        web_remote::WebRemote,
+    },
+    music_storage::{
+        album_index::AlbumSummary,
+        art_store::{ArtCacheMetrics, ArtStore},
+        db_reader::extern_library::{ExternalSource, ImportSummary},
+        filename_pattern::FILENAME_PRESETS,
+        gain_analysis::{AnalyzeScope, GainAnalysis},
+        gain_staging::PlaybackProfile,
+        integrity::VerifyReport,
+        library::{AlbumKey, Service, Song, SongRef, Tag, URI},
+        library_guard::ConflictResolution,
+        play_history::OnThisDayYear,
+        playlist::{MissingResolution, MissingTrack, SortOrder},
+        scan_priority::MultiLocationSong,
+        scan_report::{ScanProgress, ScanReport},
+        search::SearchCandidate,
+        song_details::{RefreshReport, SongDetails},
+        song_links::LinkGroup,
+        song_problems::{FlaggedSong, ProblemReport},
+        tag_cleanup::{CleanRule, TagChange, TagCleanup},
+        tombstones::DanglingTracks,
+    },
 };
 use itertools::Itertools;
-use kushi::QueueItemType;
+use kushi::{QueueItem, QueueItemType};
+use parking_lot::RwLock;
 use serde::Serialize;
-use tauri::{AppHandle, Emitter, State, Wry};
+use tauri::{AppHandle, Emitter, Manager, State, Wry};
 use uuid::Uuid;
 
+pub use dmp_core::music_storage::library::SongPayload as _Song;
+
 pub struct ArtworkRx(pub Sender<Vec<u8>>);
 
+/// The id of the most recently requested waveform. Requesting another
+/// waveform or cancelling changes it, which stops older generations.
+#[derive(Default)]
+pub struct WaveformJob(AtomicU64);
+
+/// The id of the running gain analysis, changed to cancel it
+#[derive(Default)]
+pub struct GainJob(AtomicU64);
+
+/// The id of the running file verification, changed to cancel it
+#[derive(Default)]
+pub struct VerifyJob(AtomicU64);
+
+/// The id of the running song check, changed to cancel it
+#[derive(Default)]
+pub struct CheckJob(AtomicU64);
+
+/// The queue revision `queue_updated` was last sent for
+#[derive(Default)]
+pub struct QueueRevision(AtomicU64);
+
+/// The web remote, once the controller has started it
+#[derive(Default)]
+pub struct WebRemoteState(pub RwLock<Option<WebRemote>>);
+
 #[tauri::command]
-pub async fn play(
-    app: AppHandle<Wry>,
-    ctrl_handle: State<'_, ControllerHandle>,
-) -> Result<(), String> {
-    match ctrl_handle.play().await {
-        Ok(()) => {
-            app.emit("playing", ()).unwrap();
-            Ok(())
-        }
-        Err(e) => Err(e.to_string()),
-    }
+pub async fn play(ctrl_handle: State<'_, ControllerHandle>) -> Result<(), String> {
+    ctrl_handle.play().await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub async fn pause(
-    app: AppHandle<Wry>,
-    ctrl_handle: State<'_, ControllerHandle>,
-) -> Result<(), String> {
-    match ctrl_handle.pause().await {
-        Ok(()) => {
-            app.emit("paused", ()).unwrap();
-            Ok(())
-        }
-        Err(e) => Err(e.to_string()),
-    }
+pub async fn pause(ctrl_handle: State<'_, ControllerHandle>) -> Result<(), String> {
+    ctrl_handle.pause().await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -66,6 +121,107 @@ pub async fn set_volume(
     Ok(())
 }
 
+/// The state of the player as the bottom panel shows it, with the volume
+/// from 0 to 100 like the slider
+#[derive(Serialize, Clone)]
+pub struct PlayerStatePayload {
+    volume: f32,
+    /// The slider's max, below 100 when the volume is limited
+    max_volume: f32,
+    muted: bool,
+    modes: PlaybackModes,
+    now_playing: Option<_Song>,
+}
+
+impl From<PlayerState> for PlayerStatePayload {
+    fn from(state: PlayerState) -> Self {
+        PlayerStatePayload {
+            volume: state.volume * 100.0,
+            max_volume: state.max_volume * 100.0,
+            muted: state.muted,
+            modes: state.modes,
+            now_playing: state.now_playing.as_ref().map(_Song::from),
+        }
+    }
+}
+
+/// The state the player was saved with, for before the controller starts
+fn saved_state() -> Result<ControllerState, String> {
+    let data_dir = crate::data_dir().ok_or("No config dir for DMP")?;
+    // Before the config is first written, the state goes where a new one
+    // would put it
+    let path = data_dir
+        .read_config()
+        .unwrap_or_else(|_| data_dir.new_config())
+        .state_path;
+    match ControllerState::read_file(&path) {
+        Ok(state) => Ok(state),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ControllerState::new(path)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Gets the volume, playback modes and current song for the bottom panel to
+/// start with. Before the library is loaded this is what was last saved.
+#[tauri::command]
+pub async fn get_player_state(app: AppHandle<Wry>) -> Result<PlayerStatePayload, String> {
+    let state = match app.try_state::<ControllerHandle>() {
+        Some(ctrl_handle) => ctrl_handle.get_player_state().await,
+        None => PlayerState::new(&saved_state()?, None),
+    };
+    Ok(state.into())
+}
+
+/// Moves the volume by `delta_steps` notches, returning the new volume from
+/// 0 to 100. Before the library is loaded the saved volume is changed, which
+/// the player starts with.
+#[tauri::command]
+pub async fn volume_step(app: AppHandle<Wry>, delta_steps: i32) -> Result<f32, String> {
+    let volume = match app.try_state::<ControllerHandle>() {
+        Some(ctrl_handle) => ctrl_handle.volume_step(delta_steps).await,
+        None => {
+            let mut state = saved_state()?;
+            let volume = state.step_volume(delta_steps);
+            state.flush().map_err(|e| e.to_string())?;
+            volume
+        }
+    };
+    Ok(volume * 100.0)
+}
+
+/// What's playing right now, which `now_playing` is emitted with whenever
+/// it changes
+#[tauri::command]
+pub async fn get_now_playing(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<NowPlaying, String> {
+    Ok(ctrl_handle.now_playing())
+}
+
+#[tauri::command]
+pub async fn get_playback_modes(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<PlaybackModes, String> {
+    Ok(ctrl_handle.get_playback_modes().await)
+}
+
+/// Sets the shuffle and repeat modes. `playback_modes_changed` is emitted
+/// when they change, along with `queue_updated`.
+#[tauri::command]
+pub async fn set_playback_modes(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    shuffle: ShuffleMode,
+    repeat: RepeatMode,
+) -> Result<(), String> {
+    ctrl_handle
+        .set_playback_modes(PlaybackModes { shuffle, repeat })
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn get_volume(_ctrl_handle: State<'_, ControllerHandle>) -> Result<(), String> {
     Ok(())
@@ -76,13 +232,8 @@ pub async fn next(
     app: AppHandle<Wry>,
     ctrl_handle: State<'_, ControllerHandle>,
 ) -> Result<(), String> {
-    let song = match ctrl_handle.next().await {
-        Ok(s) => s,
-        Err(e) => return Err(e.to_string()),
-    };
-    app.emit("now_playing_change", _Song::from(&song)).unwrap();
-    app.emit("queue_updated", ()).unwrap();
-    app.emit("playing", ()).unwrap();
+    ctrl_handle.next().await.map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
     Ok(())
 }
 
@@ -91,13 +242,9 @@ pub async fn prev(
     app: AppHandle<Wry>,
     ctrl_handle: State<'_, ControllerHandle>,
 ) -> Result<(), String> {
-    let song = match ctrl_handle.prev().await {
-        Ok(s) => s,
-        Err(e) => return Err(e.to_string()),
-    };
+    ctrl_handle.prev().await.map_err(|e| e.to_string())?;
     println!("prev");
-    app.emit("now_playing_change", _Song::from(&song)).unwrap();
-    app.emit("queue_updated", ()).unwrap();
+    queue_updated(&app, &ctrl_handle).await;
     Ok(())
 }
 
@@ -106,71 +253,324 @@ pub async fn now_playing(_ctrl_handle: State<'_, ControllerHandle>) -> Result<()
     Ok(())
 }
 
+/// How long the queue takes to play, in seconds
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct QueueInfoPayload {
+    /// The songs after the current one
+    pub remaining: u64,
+    /// Counting the songs which haven't been queued up yet
+    pub total: u64,
+    /// Played since the queue was last replaced
+    pub elapsed: u64,
+}
+
+impl From<QueueInfo> for QueueInfoPayload {
+    fn from(info: QueueInfo) -> Self {
+        QueueInfoPayload {
+            remaining: info.remaining.as_secs(),
+            total: info.total().as_secs(),
+            elapsed: info.elapsed.as_secs(),
+        }
+    }
+}
+
+/// A song in the queue, where it's played from, whether it was played, and
+/// its own transition if it has one
+type QueueEntry = (_Song, PlayerLocation, bool, Option<Transition>);
+
+fn queue_entry(item: &QueueItem<QueueSong, QueueAlbum>, played: bool) -> QueueEntry {
+    let QueueItemType::Single(song) = &item.item else {
+        unreachable!("There should be no albums in the queue right now")
+    };
+    (
+        _Song::from(&song.song),
+        song.location,
+        played,
+        song.transition,
+    )
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QueuePayload {
+    pub songs: Vec<QueueEntry>,
+    pub info: QueueInfoPayload,
+    /// The revision of the queue the songs are from, to apply the changes
+    /// in later `queue_updated` events to
+    pub revision: u64,
+}
+
+/// A change to the upcoming songs, see [`QueueOp`]
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "op")]
+pub enum QueueOpPayload {
+    Inserted {
+        index: usize,
+        song: QueueEntry,
+    },
+    Removed {
+        index: usize,
+    },
+    Moved {
+        from: usize,
+        to: usize,
+    },
+    StateChanged {
+        index: usize,
+        by_human: bool,
+        transition: Option<Transition>,
+    },
+    Played {
+        count: usize,
+    },
+    Unplayed {
+        count: usize,
+    },
+    Cleared,
+}
+
+impl From<QueueOp> for QueueOpPayload {
+    fn from(op: QueueOp) -> Self {
+        match op {
+            QueueOp::Inserted { index, item } => QueueOpPayload::Inserted {
+                index,
+                song: queue_entry(&item, false),
+            },
+            QueueOp::Removed { index } => QueueOpPayload::Removed { index },
+            QueueOp::Moved { from, to } => QueueOpPayload::Moved { from, to },
+            QueueOp::StateChanged {
+                index,
+                by_human,
+                transition,
+            } => QueueOpPayload::StateChanged {
+                index,
+                by_human,
+                transition,
+            },
+            QueueOp::Played { count } => QueueOpPayload::Played { count },
+            QueueOp::Unplayed { count } => QueueOpPayload::Unplayed { count },
+            QueueOp::Cleared => QueueOpPayload::Cleared,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueUpdatedPayload {
+    #[serde(flatten)]
+    pub info: QueueInfoPayload,
+    /// The changes turning the queue at revision `since` into the queue at
+    /// `revision`, or `None` when the whole queue has to be fetched again
+    pub ops: Option<Vec<QueueOpPayload>>,
+    pub since: u64,
+    pub revision: u64,
+}
+
+/// Emits `queue_updated`, with how long the queue takes to play now and
+/// what changed since it was last emitted
+pub(crate) async fn queue_updated(app: &AppHandle<Wry>, ctrl_handle: &ControllerHandle) {
+    let seen = app.state::<QueueRevision>();
+    let since = seen.0.load(Ordering::SeqCst);
+    let (revision, ops) = ctrl_handle.queue_changes(since).await;
+    seen.0.store(revision, Ordering::SeqCst);
+
+    let payload = QueueUpdatedPayload {
+        info: QueueInfoPayload::from(ctrl_handle.queue_info().await),
+        ops: ops.map(|ops| ops.into_iter().map(QueueOpPayload::from).collect()),
+        since,
+        revision,
+    };
+    app.emit("queue_updated", payload).unwrap();
+}
+
+/// Returns the queue, with the current item first. When `history` is given,
+/// up to that many played items come before it, flagged as played.
 #[tauri::command]
 pub async fn get_queue(
     ctrl_handle: State<'_, ControllerHandle>,
-) -> Result<Vec<(_Song, PlayerLocation)>, String> {
-    Ok(ctrl_handle
-        .queue_get_all()
+    history: Option<usize>,
+) -> Result<QueuePayload, String> {
+    // The queue can change between fetching the played and upcoming songs,
+    // so fetch them again until they're from the same revision
+    loop {
+        let revision = ctrl_handle.queue_revision().await;
+        let played = match history {
+            Some(limit) => ctrl_handle.queue_get_played(limit).await,
+            None => Vec::new(),
+        };
+        let upcoming = ctrl_handle.queue_get_all().await;
+        if ctrl_handle.queue_revision().await != revision {
+            continue;
+        }
+
+        let songs = played
+            .iter()
+            .map(|item| queue_entry(item, true))
+            .chain(upcoming.iter().map(|item| queue_entry(item, false)))
+            .collect_vec();
+        return Ok(QueuePayload {
+            songs,
+            info: ctrl_handle.queue_info().await.into(),
+            revision,
+        });
+    }
+}
+
+#[tauri::command]
+pub async fn play_played(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    steps: usize,
+) -> Result<(), String> {
+    ctrl_handle
+        .play_played(steps)
         .await
-        .into_iter()
-        .map(|item| {
-            let QueueItemType::Single(song) = item.item else {
-                unreachable!("There should be no albums in the queue right now")
-            };
-            (_Song::from(&song.song), song.location)
-        })
-        .collect_vec())
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
 }
 
+/// Why a change to the queue wasn't made
+#[derive(Serialize, Debug, Clone)]
+pub enum QueueEditError {
+    /// The queue changed since `expected_revision`, so nothing was done.
+    /// This is the queue now, to show or try again against.
+    Conflict {
+        revision: u64,
+        songs: Vec<QueueEntry>,
+    },
+    Failed(String),
+}
+
+impl From<PlayerError> for QueueEditError {
+    fn from(error: PlayerError) -> Self {
+        match error {
+            PlayerError::QueueConflict(conflict) => QueueEditError::Conflict {
+                revision: conflict.revision,
+                songs: conflict
+                    .items
+                    .iter()
+                    .map(|item| queue_entry(item, false))
+                    .collect(),
+            },
+            error => QueueEditError::Failed(error.to_string()),
+        }
+    }
+}
+
+/// Removes the queue item at `index`. With `expected_revision`, the
+/// revision of the queue the index is from, nothing is removed if the queue
+/// has changed since.
 #[tauri::command]
 pub async fn remove_from_queue(
     app: AppHandle<Wry>,
     ctrl_handle: State<'_, ControllerHandle>,
     index: usize,
+    expected_revision: Option<u64>,
+) -> Result<(), QueueEditError> {
+    ctrl_handle.queue_remove(index, expected_revision).await?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Moves the queue item at `from` so it ends up at `to`, checked against
+/// `expected_revision` like [`remove_from_queue`]
+#[tauri::command]
+pub async fn move_in_queue(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    from: usize,
+    to: usize,
+    expected_revision: Option<u64>,
+) -> Result<(), QueueEditError> {
+    ctrl_handle.queue_move(from, to, expected_revision).await?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Plays the queue item at `index`, checked against `expected_revision`
+/// like [`remove_from_queue`]
+#[tauri::command]
+pub async fn play_from_queue(
+    ctrl_handle: State<'_, ControllerHandle>,
+    index: usize,
+    expected_revision: Option<u64>,
+) -> Result<(), QueueEditError> {
+    Ok(ctrl_handle
+        .play_queue_index(index, expected_revision)
+        .await?)
+}
+
+#[tauri::command]
+pub async fn shuffle_queue(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<(), String> {
+    ctrl_handle
+        .queue_shuffle_remaining()
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// Sets how the song before the queue item at `index` goes into it, or
+/// goes back to the profile's crossfade without a `transition`
+#[tauri::command]
+pub async fn set_transition(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    index: usize,
+    transition: Option<Transition>,
 ) -> Result<(), String> {
-    match ctrl_handle.queue_remove(index).await {
-        Ok(_) => {
-            app.emit("queue_updated", ()).unwrap();
-            Ok(())
+    ctrl_handle
+        .queue_set_transition(index, transition)
+        .await
+        .map_err(|e| e.to_string())?;
+    queue_updated(&app, &ctrl_handle).await;
+    Ok(())
+}
+
+/// The song which started playing, with the song's fields at the top level
+/// like every other song sent to the frontend
+#[derive(Serialize, Debug, Clone)]
+pub struct NowPlayingPayload {
+    #[serde(flatten)]
+    pub song: _Song,
+    pub position: QueuePosition,
+    /// The uuid of the song which played before it
+    pub previous: Option<Uuid>,
+    pub reason: TrackChangeReason,
+    /// The track a playing radio station named
+    pub stream_title: Option<StreamTitle>,
+    /// What the player was doing once the song loaded
+    pub state: PlaybackState,
+}
+
+impl NowPlayingPayload {
+    pub fn new(change: &NowPlayingChange, state: PlaybackState) -> Self {
+        NowPlayingPayload {
+            song: _Song::from(&*change.song),
+            position: change.position,
+            previous: change.previous.as_ref().map(|song| song.uuid),
+            reason: change.reason,
+            stream_title: change.stream_title.clone(),
+            state,
         }
-        Err(e) => Err(e.to_string()),
     }
 }
 
-//Grab Album art from custom protocol
 #[derive(Serialize, Debug, Clone)]
-pub struct _Song {
-    pub location: Vec<URI>,
-    pub uuid: Uuid,
-    pub plays: i32,
-    pub format: Option<String>,
-    pub duration: String,
-    #[serde(with = "ts_milliseconds_option")]
-    pub last_played: Option<DateTime<Utc>>,
-    #[serde(with = "ts_milliseconds_option")]
-    pub date_added: Option<DateTime<Utc>>,
-    #[serde(with = "ts_milliseconds_option")]
-    pub date_modified: Option<DateTime<Utc>>,
-    pub tags: BTreeMap<String, String>,
-}
-
-impl From<&Song> for _Song {
-    fn from(value: &Song) -> Self {
-        _Song {
-            location: value.location.clone(),
-            uuid: value.uuid.clone(),
-            plays: value.plays.clone(),
-            duration: value.duration.as_secs().to_string(),
-            format: value.format.clone().map(|format| format.to_string()),
-            last_played: value.last_played,
-            date_added: value.date_added,
-            date_modified: value.date_modified,
-            tags: value
-                .tags
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.clone()))
-                .collect(),
+pub struct DevicePayload {
+    pub message: String,
+    pub event: DeviceEvent,
+    pub action: DeviceAction,
+}
+
+impl From<&DeviceNotification> for DevicePayload {
+    fn from(value: &DeviceNotification) -> Self {
+        DevicePayload {
+            message: value.message(),
+            event: value.event.clone(),
+            action: value.action,
         }
     }
 }
@@ -247,18 +647,145 @@ pub async fn import_playlist(
     Ok(PlaylistPayload { uuid, name })
 }
 
+/// Asks for another player's library file and imports it, merging songs
+/// which are already in the library
+#[tauri::command]
+pub async fn import_external_library(
+    ctrl_handle: State<'_, ControllerHandle>,
+    source: ExternalSource,
+) -> Result<Option<ImportSummary>, String> {
+    let dialog = match source {
+        ExternalSource::ITunes => rfd::AsyncFileDialog::new()
+            .add_filter("iTunes Library", &["xml"])
+            .set_title("Import an iTunes Library"),
+        ExternalSource::Rhythmbox => rfd::AsyncFileDialog::new()
+            .add_filter("Rhythmbox Database", &["xml"])
+            .set_title("Import a Rhythmbox Library (rhythmdb.xml)"),
+        ExternalSource::Clementine => rfd::AsyncFileDialog::new()
+            .add_filter("Clementine or Strawberry Database", &["db"])
+            .set_title("Import a Clementine or Strawberry Library"),
+    };
+    let Some(file) = dialog.pick_file().await else {
+        return Ok(None);
+    };
+
+    let summary = ctrl_handle
+        .lib_import_external(source, PathBuf::from(file.path()))
+        .await?;
+    ctrl_handle.lib_save().await;
+    Ok(Some(summary))
+}
+
+#[tauri::command]
+pub async fn refresh_auto_playlists(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<PlaylistPayload>, String> {
+    let lists = ctrl_handle.playlist_generate_auto().await;
+    ctrl_handle.lib_save().await;
+
+    Ok(lists
+        .into_iter()
+        .map(|(uuid, name)| PlaylistPayload { uuid, name })
+        .collect_vec())
+}
+
+#[tauri::command]
+pub async fn pin_auto_playlist(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+) -> Result<(), String> {
+    ctrl_handle.playlist_pin_auto(uuid).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_playlist_sort_order(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    order: SortOrder,
+) -> Result<(), String> {
+    ctrl_handle.playlist_set_sort_order(uuid, order).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_playlist_profile(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    profile: Option<PlaybackProfile>,
+) -> Result<(), String> {
+    ctrl_handle.playlist_set_profile(uuid, profile).await?;
+    ctrl_handle.lib_save().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_missing_tracks(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+) -> Result<Vec<MissingTrack>, String> {
+    let playlist = ctrl_handle
+        .playlist_get(uuid)
+        .await
+        .map_err(|_| "Playlist not found".to_string())?;
+    Ok(playlist.missing)
+}
+
+#[tauri::command]
+pub async fn find_missing_track_matches(
+    ctrl_handle: State<'_, ControllerHandle>,
+    playlist: Uuid,
+    index: usize,
+) -> Result<Vec<SearchCandidate>, String> {
+    Ok(ctrl_handle.playlist_missing_matches(playlist, index).await)
+}
+
+#[tauri::command]
+pub async fn resolve_missing_track(
+    ctrl_handle: State<'_, ControllerHandle>,
+    playlist: Uuid,
+    index: usize,
+    to: MissingResolution,
+) -> Result<Uuid, String> {
+    let uuid = ctrl_handle
+        .playlist_resolve_missing(playlist, index, to)
+        .await?;
+    ctrl_handle.lib_save().await;
+    Ok(uuid)
+}
+
+#[tauri::command]
+pub async fn set_library_profile(
+    ctrl_handle: State<'_, ControllerHandle>,
+    profile: Option<PlaybackProfile>,
+) -> Result<(), String> {
+    ctrl_handle.library_set_profile(profile)
+}
+
 #[derive(Serialize, Clone)]
 pub struct PlaylistPayload {
     uuid: Uuid,
     name: String,
 }
 
+/// Why a song couldn't be fetched
+#[derive(Serialize, Debug, Clone)]
+pub enum SongError {
+    /// The song isn't in the library anymore, or the uuid is from another
+    /// library, so the frontend should forget it
+    NotFound(Uuid),
+}
+
 #[tauri::command]
 pub async fn get_song(
     ctrl_handle: State<'_, ControllerHandle>,
     uuid: Uuid,
-) -> Result<_Song, String> {
-    let song = ctrl_handle.lib_get_song(uuid).await.0;
+) -> Result<_Song, SongError> {
+    let Some((song, _)) = ctrl_handle.lib_get_song(uuid).await else {
+        return Err(SongError::NotFound(uuid));
+    };
     println!(
         "got song {}",
         &song.tags.get(&Tag::Title).unwrap_or(&String::new())
@@ -266,7 +793,734 @@ pub async fn get_song(
     Ok(_Song::from(&song))
 }
 
+/// A song as [`get_songs`] sends it
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SongRow {
+    Ref(SongRef),
+    Full(_Song),
+}
+
+/// Fetches the songs for many rows of a list at once, in the same order as
+/// `uuids`, with `null` for the ones which aren't in the library. Only the
+/// fields a row shows are sent unless `full` is set.
+#[tauri::command]
+pub async fn get_songs(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuids: Vec<Uuid>,
+    full: Option<bool>,
+) -> Result<Vec<Option<SongRow>>, String> {
+    let full = full.unwrap_or(false);
+    let songs = ctrl_handle.lib_get_songs(uuids).await?;
+    Ok(songs
+        .into_iter()
+        .map(|song| {
+            let song = song?;
+            Some(match full {
+                true => SongRow::Full(_Song::from(&*song)),
+                false => SongRow::Ref(SongRef::from(&*song)),
+            })
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn get_recent_scrobbles(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<ScrobbleEntry>, String> {
+    Ok(ctrl_handle.scrobbles_get())
+}
+
+#[tauri::command]
+pub async fn retract_and_resubmit(
+    ctrl_handle: State<'_, ControllerHandle>,
+    id: ScrobbleId,
+    corrected: ScrobbleCorrection,
+) -> Result<(), String> {
+    ctrl_handle
+        .scrobble_correct(id, corrected)
+        .map_err(|e| e.to_string())
+}
+
+/// Suggestions for the home screen, the most recently played first
+#[tauri::command]
+pub async fn get_continue_listening(
+    ctrl_handle: State<'_, ControllerHandle>,
+    limit: Option<usize>,
+) -> Result<Vec<ContinueEntry>, String> {
+    Ok(ctrl_handle
+        .lib_continue_listening(limit.unwrap_or(12))
+        .await)
+}
+
+/// The songs first or heavily played on this day in years before, for the
+/// home screen. Another day can be asked for with `month` and `day`.
+#[tauri::command]
+pub async fn get_on_this_day(
+    ctrl_handle: State<'_, ControllerHandle>,
+    month: Option<u32>,
+    day: Option<u32>,
+) -> Result<Vec<OnThisDayYear>, String> {
+    let today = Local::now();
+    Ok(ctrl_handle
+        .lib_on_this_day(month.unwrap_or(today.month()), day.unwrap_or(today.day()))
+        .await)
+}
+
+#[tauri::command]
+pub async fn get_song_details(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+) -> Result<SongDetails, String> {
+    ctrl_handle.lib_song_details(uuid).await
+}
+
+/// Emits `songs_updated` with songs which were changed, so only they are
+/// replaced rather than the whole library being fetched again
+fn songs_updated(app: &AppHandle<Wry>, songs: &[Song]) {
+    let songs = songs.iter().map(_Song::from).collect_vec();
+    app.emit("songs_updated", songs).unwrap();
+}
+
+/// Emits `songs_updated` with the song with `uuid` as it is now
+async fn song_updated(app: &AppHandle<Wry>, ctrl_handle: &ControllerHandle, uuid: Uuid) {
+    // Nothing to update if it was removed meanwhile
+    if let Some((song, _)) = ctrl_handle.lib_get_song(uuid).await {
+        songs_updated(app, &[song]);
+    }
+}
+
 #[tauri::command]
-pub async fn seek(ctrl_handle: State<'_, ControllerHandle>, time: i64) -> Result<(), String> {
-    ctrl_handle.seek(time).await.map_err(|e| e.to_string())
+pub async fn reread_song(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+) -> Result<_Song, String> {
+    let song = ctrl_handle.lib_reread_song(uuid).await?;
+    ctrl_handle.lib_save().await;
+    songs_updated(&app, std::slice::from_ref(&song));
+    Ok(_Song::from(&song))
+}
+
+/// Emits `songs_updated` with the songs which were read again, returning
+/// the ones which couldn't be and why
+async fn refreshed(
+    app: &AppHandle<Wry>,
+    ctrl_handle: &ControllerHandle,
+    report: RefreshReport,
+) -> Vec<(Uuid, String)> {
+    if !report.refreshed.is_empty() {
+        ctrl_handle.lib_save().await;
+        songs_updated(app, &report.refreshed);
+    }
+    report.failed
+}
+
+/// Reads songs from their files again, after their tags were fixed by
+/// another program
+#[tauri::command]
+pub async fn refresh_songs(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuids: Vec<Uuid>,
+) -> Result<Vec<(Uuid, String)>, String> {
+    let report = ctrl_handle.lib_refresh_songs(uuids).await;
+    Ok(refreshed(&app, &ctrl_handle, report).await)
+}
+
+#[tauri::command]
+pub async fn refresh_album(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: AlbumKey,
+) -> Result<Vec<(Uuid, String)>, String> {
+    let report = ctrl_handle.lib_refresh_album(key).await?;
+    Ok(refreshed(&app, &ctrl_handle, report).await)
+}
+
+#[tauri::command]
+pub async fn retag_file_format(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+) -> Result<(), String> {
+    ctrl_handle.lib_retag_file_format(uuid).await
+}
+
+/// Sets where a song starts and ends playing, in milliseconds. `None` plays
+/// all of it again.
+#[tauri::command]
+pub async fn set_trim(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    trim: Option<(u64, u64)>,
+) -> Result<(), String> {
+    let trim = trim.map(|(start, end)| (Duration::from_millis(start), Duration::from_millis(end)));
+    ctrl_handle.lib_set_trim(uuid, trim).await?;
+    ctrl_handle.lib_save().await;
+    song_updated(&app, &ctrl_handle, uuid).await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_preferred_art(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    index: Option<usize>,
+) -> Result<(), String> {
+    ctrl_handle.lib_set_preferred_art(uuid, index).await?;
+    ctrl_handle.lib_save().await;
+    song_updated(&app, &ctrl_handle, uuid).await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_connection_status(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<ConnectionStatus, String> {
+    Ok(ctrl_handle.connection_status())
+}
+
+/// How well the album art cache is doing, for debugging
+#[tauri::command]
+pub async fn get_albums(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Arc<Vec<AlbumSummary>>, String> {
+    Ok(ctrl_handle.lib_albums().await)
+}
+
+#[tauri::command]
+pub async fn get_art_cache_metrics() -> Result<ArtCacheMetrics, String> {
+    Ok(ArtStore::shared().metrics())
+}
+
+#[tauri::command]
+pub async fn get_settings(
+    ctrl_handle: State<'_, ControllerHandle>,
+    section: SettingsSection,
+) -> Result<Settings, String> {
+    Ok(ctrl_handle.settings(section))
+}
+
+#[derive(Serialize)]
+pub struct SettingsUpdate {
+    settings: Settings,
+    /// Some of the changes only apply once the player is restarted
+    needs_restart: bool,
+}
+
+/// Checks and saves changes to one section of the settings, returning the
+/// section as it is now
+#[tauri::command]
+pub async fn update_settings(
+    ctrl_handle: State<'_, ControllerHandle>,
+    remote: State<'_, WebRemoteState>,
+    section: SettingsSection,
+    patch: serde_json::Value,
+) -> Result<SettingsUpdate, String> {
+    let (settings, change) = ctrl_handle.update_settings(section, patch).await?;
+    if change.web_remote_changed {
+        let mut remote = remote.0.write();
+        if let Some(old) = remote.take() {
+            old.stop();
+        }
+        if let Settings::Connections(connections) = &settings {
+            if connections.web_remote.enabled {
+                *remote = Some(
+                    WebRemote::start(ctrl_handle.inner().clone(), &connections.web_remote)
+                        .map_err(|e| e.to_string())?,
+                );
+            }
+        }
+    }
+    Ok(SettingsUpdate {
+        settings,
+        needs_restart: change.needs_restart,
+    })
+}
+
+/// Everything the interface stored to be restored, fetched once it opens
+#[tauri::command]
+pub async fn get_ui_state(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<BTreeMap<String, serde_json::Value>, String> {
+    Ok(ctrl_handle.ui_state())
+}
+
+/// Stores one namespaced key of the interface's state, like
+/// `library.view`. Null removes the key.
+#[tauri::command]
+pub async fn set_ui_state(
+    ctrl_handle: State<'_, ControllerHandle>,
+    key: String,
+    value: serde_json::Value,
+) -> Result<(), String> {
+    ctrl_handle
+        .set_ui_state(&key, value)
+        .map_err(|e| e.to_string())
+}
+
+/// The address to open the web remote at, if it is running
+#[tauri::command]
+pub async fn get_web_remote_url(
+    remote: State<'_, WebRemoteState>,
+) -> Result<Option<String>, String> {
+    Ok(remote.0.read().as_ref().map(WebRemote::url))
+}
+
+/// How many times each song was listened to according to ListenBrainz, with
+/// `None` for songs whose count isn't known
+#[tauri::command]
+pub async fn get_listen_counts(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuids: Vec<Uuid>,
+) -> Result<Vec<Option<u64>>, String> {
+    Ok(ctrl_handle.listen_counts(uuids).await)
+}
+
+#[tauri::command]
+pub async fn resolve_library_conflict(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    resolution: ConflictResolution,
+) -> Result<(), String> {
+    ctrl_handle.lib_resolve_conflict(resolution).await?;
+    if resolution != ConflictResolution::Overwrite {
+        // The library was replaced by the one on disk
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(())
+}
+
+/// Removes songs whose files are missing, returning them. A dry run only
+/// finds them. Refuses to remove more than the configured share of the
+/// library, in case a drive or share is acting up.
+#[tauri::command]
+pub async fn remove_missing(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    dry_run: bool,
+) -> Result<Vec<_Song>, String> {
+    let removed = ctrl_handle
+        .lib_remove_missing(dry_run)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !dry_run && !removed.is_empty() {
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(removed.iter().map(_Song::from).collect())
+}
+
+/// Puts back the songs removed by the last `remove_missing`, returning how
+/// many were restored
+#[tauri::command]
+pub async fn undo_remove_missing(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<usize, String> {
+    let restored = ctrl_handle.lib_undo_remove_missing().await;
+    if restored > 0 {
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(restored)
+}
+
+/// Lists the songs in the library which its scan exclusions would have left
+/// out, so they can be removed with `remove_excluded`
+#[tauri::command]
+pub async fn preview_exclusions(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<_Song>, String> {
+    let excluded = ctrl_handle.lib_remove_excluded(true).await?;
+    Ok(excluded.iter().map(_Song::from).collect())
+}
+
+/// Removes the songs `preview_exclusions` lists, which `undo_remove_missing`
+/// puts back
+#[tauri::command]
+pub async fn remove_excluded(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<_Song>, String> {
+    let removed = ctrl_handle.lib_remove_excluded(false).await?;
+    if !removed.is_empty() {
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(removed.iter().map(_Song::from).collect())
+}
+
+/// Lists the songs in playlists which have been removed from the library,
+/// taking them out of the playlists if `prune`
+#[tauri::command]
+pub async fn repair_playlists(
+    ctrl_handle: State<'_, ControllerHandle>,
+    prune: bool,
+) -> Result<Vec<DanglingTracks>, String> {
+    Ok(ctrl_handle.lib_repair_playlists(prune).await)
+}
+
+/// Adds an internet radio station, or any other HTTP(S) audio, to the
+/// library by its URL
+#[tauri::command]
+pub async fn add_stream(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    url: String,
+) -> Result<Uuid, String> {
+    let uuid = ctrl_handle
+        .lib_add_remote_song(Service::InternetRadio, url)
+        .await?;
+    app.emit("library_loaded", ()).unwrap();
+    Ok(uuid)
+}
+
+/// Adds a named internet radio station, which is listed with the other
+/// stations and shows the tracks it plays
+#[tauri::command]
+pub async fn add_radio_station(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    name: String,
+    url: String,
+    genre: Option<String>,
+) -> Result<Uuid, String> {
+    let uuid = ctrl_handle.lib_add_radio_station(name, url, genre).await?;
+    app.emit("library_loaded", ()).unwrap();
+    Ok(uuid)
+}
+
+#[tauri::command]
+pub async fn get_radio_stations(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<_Song>, String> {
+    Ok(ctrl_handle
+        .lib_radio_stations()
+        .await
+        .iter()
+        .map(_Song::from)
+        .collect())
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ContentFilterPayload {
+    pub enabled: bool,
+    pub has_pin: bool,
+}
+
+#[tauri::command]
+pub async fn get_filtered_mode(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<ContentFilterPayload, String> {
+    let filter = ctrl_handle.content_filter();
+    Ok(ContentFilterPayload {
+        enabled: filter.enabled,
+        has_pin: filter.has_pin(),
+    })
+}
+
+/// Turns filtered mode on or off, which needs the PIN if one was set
+#[tauri::command]
+pub async fn set_filtered_mode(
+    ctrl_handle: State<'_, ControllerHandle>,
+    enabled: bool,
+    pin: Option<String>,
+) -> Result<(), String> {
+    ctrl_handle.set_filtered_mode(enabled, pin)
+}
+
+#[tauri::command]
+pub async fn set_filter_pin(
+    ctrl_handle: State<'_, ControllerHandle>,
+    current: Option<String>,
+    pin: Option<String>,
+) -> Result<(), String> {
+    ctrl_handle.set_filter_pin(current, pin)
+}
+
+/// Marks a song as explicit or not, `None` going by its tags again
+#[tauri::command]
+pub async fn set_explicit(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    explicit: Option<bool>,
+) -> Result<(), String> {
+    ctrl_handle.lib_set_explicit(uuid, explicit).await?;
+    ctrl_handle.lib_save().await;
+    song_updated(&app, &ctrl_handle, uuid).await;
+    Ok(())
+}
+
+/// Finds songs which look like other versions of another song, like its
+/// instrumental, for the user to confirm
+#[tauri::command]
+pub async fn detect_linked_versions(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<LinkGroup>, String> {
+    Ok(ctrl_handle.lib_detect_linked_versions().await)
+}
+
+/// Links the versions the user confirmed, returning how many were linked
+#[tauri::command]
+pub async fn link_versions(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    groups: Vec<LinkGroup>,
+) -> Result<usize, String> {
+    let linked = ctrl_handle.lib_link_versions(groups).await;
+    if linked > 0 {
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(linked)
+}
+
+/// Saves the library right away instead of waiting for the next scheduled save
+#[tauri::command]
+pub async fn flush_library(ctrl_handle: State<'_, ControllerHandle>) -> Result<(), String> {
+    ctrl_handle.lib_flush().await;
+    Ok(())
+}
+
+#[derive(Serialize, Clone)]
+pub struct WaveformProgress {
+    uuid: Uuid,
+    progress: f32,
+}
+
+#[tauri::command]
+pub async fn get_waveform(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    job: State<'_, WaveformJob>,
+    uuid: Uuid,
+    buckets: usize,
+) -> Result<Vec<u8>, String> {
+    let id = job.0.fetch_add(1, Ordering::SeqCst) + 1;
+    ctrl_handle
+        .lib_waveform(uuid, buckets, |progress| {
+            _ = app.emit("waveform_progress", WaveformProgress { uuid, progress });
+            job.0.load(Ordering::SeqCst) == id
+        })
+        .await
+}
+
+#[tauri::command]
+pub async fn cancel_waveform(job: State<'_, WaveformJob>) -> Result<(), String> {
+    job.0.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_scan_report(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<ScanReport, String> {
+    Ok(ctrl_handle.lib_scan_report().await)
+}
+
+#[tauri::command]
+pub async fn retry_scan_file(
+    ctrl_handle: State<'_, ControllerHandle>,
+    path: PathBuf,
+) -> Result<ScanReport, String> {
+    let report = ctrl_handle
+        .lib_retry_scan(path)
+        .await
+        .map_err(|e| e.message)?;
+    ctrl_handle.lib_save().await;
+    Ok(report)
+}
+
+#[derive(Serialize, Clone)]
+pub struct ScanProgressPayload {
+    job: Uuid,
+    #[serde(flatten)]
+    progress: ScanProgress,
+}
+
+/// Scans the library's folders for new songs. Scanning again while a scan
+/// runs gives back the running scan's job, whose progress comes in the same
+/// `scan_progress` events.
+#[tauri::command]
+pub async fn scan_library(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<ScanOutcome, String> {
+    let outcome = ctrl_handle
+        .lib_scan(|job, progress| {
+            _ = app.emit("scan_progress", ScanProgressPayload { job, progress });
+        })
+        .await?;
+    if matches!(&outcome, ScanOutcome::Finished { report, .. } if report.added > 0) {
+        app.emit("library_loaded", ()).unwrap();
+    }
+    Ok(outcome)
+}
+
+#[tauri::command]
+pub async fn get_active_jobs(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<JobStatus>, String> {
+    Ok(ctrl_handle.lib_active_jobs())
+}
+
+#[tauri::command]
+pub async fn clean_tags(
+    ctrl_handle: State<'_, ControllerHandle>,
+    rules: Vec<CleanRule>,
+    dry_run: bool,
+    write_back: bool,
+) -> Result<TagCleanup, String> {
+    let cleanup = ctrl_handle.lib_clean_tags(rules, dry_run, write_back).await;
+    if !dry_run {
+        ctrl_handle.lib_save().await;
+    }
+    Ok(cleanup)
+}
+
+#[tauri::command]
+pub async fn apply_filename_pattern(
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuids: Vec<Uuid>,
+    pattern: String,
+    overwrite: bool,
+    dry_run: bool,
+) -> Result<Vec<TagChange>, String> {
+    ctrl_handle
+        .lib_apply_filename_pattern(uuids, pattern, overwrite, dry_run)
+        .await
+}
+
+#[tauri::command]
+pub async fn filename_presets() -> Result<Vec<String>, String> {
+    Ok(FILENAME_PRESETS.map(String::from).to_vec())
+}
+
+#[tauri::command]
+pub async fn analyze_gain(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    job: State<'_, GainJob>,
+    scope: AnalyzeScope,
+    force: bool,
+    write_back: bool,
+) -> Result<GainAnalysis, String> {
+    let id = job.0.fetch_add(1, Ordering::SeqCst) + 1;
+    let analysis = ctrl_handle
+        .lib_analyze_gain(scope, force, write_back, |progress| {
+            _ = app.emit("gain_progress", progress);
+            job.0.load(Ordering::SeqCst) == id
+        })
+        .await?;
+    ctrl_handle.lib_save().await;
+    Ok(analysis)
+}
+
+#[tauri::command]
+pub async fn cancel_gain_analysis(job: State<'_, GainJob>) -> Result<(), String> {
+    job.0.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn verify_files(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    job: State<'_, VerifyJob>,
+    scope: AnalyzeScope,
+    deep: bool,
+) -> Result<VerifyReport, String> {
+    let id = job.0.fetch_add(1, Ordering::SeqCst) + 1;
+    let report = ctrl_handle
+        .lib_verify_files(scope, deep, |progress| {
+            _ = app.emit("verify_progress", progress);
+            job.0.load(Ordering::SeqCst) == id
+        })
+        .await?;
+    ctrl_handle.lib_save().await;
+    Ok(report)
+}
+
+#[tauri::command]
+pub async fn cancel_verify_files(job: State<'_, VerifyJob>) -> Result<(), String> {
+    job.0.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn check_songs(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    job: State<'_, CheckJob>,
+    scope: AnalyzeScope,
+) -> Result<ProblemReport, String> {
+    let id = job.0.fetch_add(1, Ordering::SeqCst) + 1;
+    let report = ctrl_handle
+        .lib_check_songs(scope, |progress| {
+            _ = app.emit("check_progress", progress);
+            job.0.load(Ordering::SeqCst) == id
+        })
+        .await?;
+    ctrl_handle.lib_save().await;
+    Ok(report)
+}
+
+#[tauri::command]
+pub async fn cancel_check_songs(job: State<'_, CheckJob>) -> Result<(), String> {
+    job.0.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_flagged_songs(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<FlaggedSong>, String> {
+    Ok(ctrl_handle.lib_flagged_songs().await)
+}
+
+#[tauri::command]
+pub async fn get_multi_location_songs(
+    ctrl_handle: State<'_, ControllerHandle>,
+) -> Result<Vec<MultiLocationSong>, String> {
+    Ok(ctrl_handle.lib_multi_location_songs().await)
+}
+
+/// Removes one copy of a song which is kept in more than one place. The file
+/// itself is left alone.
+#[tauri::command]
+pub async fn drop_location(
+    app: AppHandle<Wry>,
+    ctrl_handle: State<'_, ControllerHandle>,
+    uuid: Uuid,
+    uri: URI,
+) -> Result<(), String> {
+    ctrl_handle.lib_drop_location(uuid, uri).await?;
+    ctrl_handle.lib_save().await;
+    song_updated(&app, &ctrl_handle, uuid).await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn seek(
+    ctrl_handle: State<'_, ControllerHandle>,
+    time: i64,
+    epoch: u64,
+) -> Result<(), String> {
+    ctrl_handle
+        .seek(time, epoch)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Moves the reported position while the seekbar is dragged, without seeking
+#[tauri::command]
+pub async fn seek_preview(
+    ctrl_handle: State<'_, ControllerHandle>,
+    time: i64,
+    epoch: u64,
+) -> Result<(), String> {
+    ctrl_handle
+        .seek_preview(time, epoch)
+        .await
+        .map_err(|e| e.to_string())
 }
@@ -1,7 +1,7 @@
 import React, { createRef, useEffect, useRef, useState } from "react";
 import { convertFileSrc, invoke } from "@tauri-apps/api/core";
 import "./App.css";
-import { Config, playbackInfo } from "./types";
+import { ActiveProfile, ConfigLibraries, ConnectionsSettings as ConnectionsSettingsType, MASKED, MissingTrack, NegotiatedOutput, NowPlaying as NowPlayingState, OutputSwitch, PlaybackModes, PlayerState, QueueEditError, QueueEntry, QueueInfo, QueueOp, QueuePayload, QueuePosition, QueueUpdated, SearchCandidate, SettingsUpdate, TrackChangeReason, Transition, playbackInfo } from "./types";
 // import { EventEmitter } from "@tauri-apps/plugin-shell";
 // import { listen } from "@tauri-apps/api/event";
 // import { fetch } from "@tauri-apps/plugin-http";
@@ -11,10 +11,16 @@ const appWindow = getCurrentWebviewWindow();
 
 function App() {
   const library = useState<JSX.Element[]>([]);
-  const [queue, setQueue] = useState<JSX.Element[]>([]);
+  const [queue, setQueue] = useState<QueueEntry[]>([]);
+  // The revision of the queue shown, to apply changes to
+  const queueRevision = useRef<number | undefined>(undefined);
+  const [queueInfo, setQueueInfo] = useState<QueueInfo | undefined>(undefined);
   const [playing, setPlaying] = useState(false);
   const [playlists, setPlaylists] = useState<JSX.Element[]>([]);
   const [viewName, setViewName] = useState("Library");
+  const [conflict, setConflict] = useState(false);
+  const [toast, setToast] = useState<string | undefined>(undefined);
+  const [showSettings, setShowSettings] = useState(false);
 
   const [nowPlaying, setNowPlaying] = useState<JSX.Element>(
     <NowPlaying
@@ -26,54 +32,89 @@ function App() {
   );
 
   useEffect(() => {
-    const unlisten = appWindow.listen<any>("now_playing_change", ({ payload, }) => {
-        const displayArtwork = () => {
-          invoke('display_album_art', { uuid: payload.uuid }).then(() => {})
+    // Everything about the song comes together, and an older update
+    // arriving late is left out by its revision
+    let revision = -1;
+    const show = (now: NowPlayingState) => {
+      if (now.revision < revision) return;
+      revision = now.revision;
+      setPlaying(now.state == "Playing");
+      const song = now.song;
+      if (!song) return;
+
+      const displayArtwork = () => {
+        invoke('display_album_art', { uuid: song.uuid }).then(() => {})
+      }
+      // Radio stations show as "Station — Current Track"
+      const stream = now.stream_title;
+      setNowPlaying(
+        <NowPlaying
+          title={ stream ? `${song.title} — ${stream.title}` : song.title ?? "" }
+          album={ song.album ?? "" }
+          artist={ stream?.artist ?? song.artist ?? "" }
+          artwork={ <img src={convertFileSrc("abc") + "?" + song.uuid } id="nowPlayingArtwork" alt="Now Playing Artwork" key={song.uuid} onDoubleClick={ displayArtwork } /> }
+          position={ now.queue_position ?? undefined }
+          reason={ now.reason ?? undefined }
+        />
+      )
+    }
+    // The player only exists once the library has loaded
+    const refresh = () => invoke<NowPlayingState>('get_now_playing').then(show).catch(() => {});
+    refresh();
+    const unlisten = appWindow.listen<NowPlayingState>("now_playing", ({ payload }) => show(payload))
+    const unlistenLoaded = appWindow.listen<any>("library_loaded", (_) => refresh())
+    return () => {
+      unlisten.then((f) => f())
+      unlistenLoaded.then((f) => f())
+    }
+  }, []);
+
+  useEffect(() => {
+    const unlisten = appWindow.listen<QueueUpdated>("queue_updated", ({ payload }) => {
+        setQueueInfo(payload)
+        // Patch the queue when the changes start from what's shown, and
+        // fetch all of it otherwise
+        const ops = payload.ops
+        if (ops && queueRevision.current === payload.since) {
+          queueRevision.current = payload.revision
+          setQueue((queue) => applyQueueOps(queue, ops))
+          return
         }
-        // console.log(event);
-        setNowPlaying(
-          <NowPlaying
-            title={ payload.tags.TrackTitle }
-            album={ payload.tags.AlbumTitle }
-            artist={ payload.tags.TrackArtist }
-            artwork={ <img src={convertFileSrc("abc") + "?" + payload.uuid } id="nowPlayingArtwork" alt="Now Playing Artwork" key={payload.uuid} onDoubleClick={ displayArtwork } /> }
-          />
-        )
+        invoke<QueuePayload>('get_queue', { history: QUEUE_HISTORY }).then((res) => {
+          queueRevision.current = res.revision
+          setQueue(res.songs)
+        })
+    })
+    return () => { unlisten.then((f) => f()) }
+  }, []);
 
+  useEffect(() => {
+    const unlisten = appWindow.listen<any>("library_conflict", (_) => {
+      setConflict(true)
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
 
   useEffect(() => {
-    const unlisten = appWindow.listen<any>("queue_updated", (_) => {
-        // console.log(event);
-        invoke('get_queue').then((_songs) => {
-          let songs = _songs as any[]
-            setQueue(
-              songs.filter((_, i) => i != 0).map((song, i) =>
-                <QueueSong
-                  song={ song[0] }
-                  location={ song[1] as "Library" | {"Playlist" : string}}
-                  index={i+1}
-                  key={ song.uuid + '_' + Math.floor((Math.random() * 100_000) + 1) + '_' + Date.now() }
-                />
-              )
-            )
-        })
+    const unlisten = appWindow.listen<string>("song_skipped", ({ payload }) => {
+      setToast(payload)
+      setTimeout(() => setToast(undefined), 4000)
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
 
   useEffect(() => {
-    const unlisten = appWindow.listen<any>("playing", (_) => {
-        setPlaying(true)
+    const unlisten = appWindow.listen<any>("playback_error", ({ payload }) => {
+      setToast(`Couldn't play '${payload.title}' \u2014 ${payload.message}`)
+      setTimeout(() => setToast(undefined), 4000)
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
 
   useEffect(() => {
-    const unlisten = appWindow.listen<any>("paused", (_) => {
-        setPlaying(false)
+    // Shown until the player exits, once scans have stopped and it's saved
+    const unlisten = appWindow.listen<any>("finishing_up", (_) => {
+      setToast("Finishing up\u2026")
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
@@ -84,14 +125,18 @@ function App() {
 
   return (
     <main>
+      { conflict && <LibraryConflict setConflict={ setConflict } /> }
+      { toast && <div className="toast">{ toast }</div> }
+      { showSettings && <ConnectionsSettings close={ () => setShowSettings(false) } /> }
       <div className="container">
         <div className="leftSide">
           <PlaylistHead playlists={ playlists } setPlaylists={ setPlaylists } setViewName={ setViewName } setLibrary={ library[1] } />
+          <button onClick={ () => setShowSettings(!showSettings) }>Settings</button>
           <MainView lib_ref={ library } viewName={ viewName } />
         </div>
         <div className="rightSide">
           { nowPlaying }
-          <Queue songs={ queue } />
+          <Queue songs={ queue } info={ queueInfo } revision={ queueRevision.current } />
         </div>
       </div>
       <div className="bottom">
@@ -103,6 +148,92 @@ function App() {
 
 export default App;
 
+interface ConnectionsSettingsProps {
+  close: () => void,
+}
+
+function ConnectionsSettings({ close }: ConnectionsSettingsProps) {
+  const [settings, setSettings] = useState<ConnectionsSettingsType | undefined>(undefined);
+  // Only the fields which were changed are sent
+  const [patch, setPatch] = useState<Partial<ConnectionsSettingsType>>({});
+  const [message, setMessage] = useState<string | undefined>(undefined);
+
+  useEffect(() => {
+    invoke<ConnectionsSettingsType>('get_settings', { section: "Connections" }).then(setSettings)
+  }, []);
+  if (!settings) {
+    return null
+  }
+
+  const shown = { ...settings, ...patch };
+  const change = (field: Partial<ConnectionsSettingsType>) => setPatch({ ...patch, ...field });
+  // Clearing a secret's field removes it
+  const secret = (field: "listenbrainz_token" | "lastfm_api_key" | "lastfm_api_secret", label: string) => (
+    <label>
+      { label }
+      <input
+        type="password"
+        value={ shown[field] ?? "" }
+        onFocus={ () => shown[field] == MASKED && change({ [field]: "" }) }
+        onChange={ (e) => change({ [field]: e.target.value }) }
+      />
+    </label>
+  );
+  const save = () => {
+    invoke<SettingsUpdate<ConnectionsSettingsType>>('update_settings', { section: "Connections", patch: patch })
+      .then((res) => {
+        setSettings(res.settings)
+        setPatch({})
+        setMessage(res.needs_restart ? "Saved. Restart the player for every change to apply." : "Saved.")
+      })
+      .catch((e) => setMessage(`${e}`))
+  };
+
+  return (
+    <section className="settings">
+      <h2>Connections</h2>
+      { secret("listenbrainz_token", "ListenBrainz token") }
+      { secret("lastfm_api_key", "Last.fm API key") }
+      { secret("lastfm_api_secret", "Last.fm API secret") }
+      <label>
+        <input type="checkbox" checked={ !shown.disable_listen_counts } onChange={ (e) => change({ disable_listen_counts: !e.target.checked }) } />
+        Show listen counts from ListenBrainz
+      </label>
+      <label>
+        <input type="checkbox" checked={ shown.scrobble_radio } onChange={ (e) => change({ scrobble_radio: e.target.checked }) } />
+        Scrobble radio tracks
+      </label>
+      <label>
+        <input type="checkbox" checked={ shown.web_remote.enabled } onChange={ (e) => change({ web_remote: { ...shown.web_remote, enabled: e.target.checked } }) } />
+        Web remote on port
+        <input type="number" min={ 1024 } max={ 65535 } value={ shown.web_remote.port } onChange={ (e) => change({ web_remote: { ...shown.web_remote, port: +e.target.value } }) } />
+      </label>
+      { message && <p>{ message }</p> }
+      <button onClick={ save } disabled={ Object.keys(patch).length == 0 }>Save</button>
+      <button onClick={ close }>Close</button>
+    </section>
+  )
+}
+
+interface LibraryConflictProps {
+  setConflict: React.Dispatch<React.SetStateAction<boolean>>
+}
+
+function LibraryConflict({ setConflict }: LibraryConflictProps) {
+  const resolve = (resolution: string) => {
+    invoke('resolve_library_conflict', { resolution: resolution }).then(() => setConflict(false))
+  }
+
+  return (
+    <section className="libraryConflict">
+      <p>The library file was changed by another program. Nothing will be saved until this is resolved.</p>
+      <button onClick={ () => resolve("Reload") }>Reload</button>
+      <button onClick={ () => resolve("MergeStats") }>Reload and keep play counts</button>
+      <button onClick={ () => resolve("Overwrite") }>Overwrite</button>
+    </section>
+  )
+}
+
 interface PlaylistHeadProps {
   playlists: JSX.Element[]
   setPlaylists: React.Dispatch<React.SetStateAction<JSX.Element[]>>,
@@ -111,6 +242,58 @@ interface PlaylistHeadProps {
 }
 
 function PlaylistHead({ playlists, setPlaylists, setViewName, setLibrary }: PlaylistHeadProps) {
+  // The last view is only reopened once, when the playlists first arrive
+  const restored = useRef(false);
+
+  // Shows a playlist's songs, with the ones which couldn't be found when it
+  // was imported in their places
+  const showPlaylist = (uuid: string, name: string) => {
+    Promise.all([
+      invoke('get_playlist', { uuid: uuid }),
+      invoke('get_missing_tracks', { uuid: uuid }),
+    ]).then(([list, _missing]) => {
+      const missing = _missing as MissingTrack[];
+      const rows: JSX.Element[] = [];
+      const addMissing = (position: number) => {
+        missing.forEach((track, index) => {
+          if (track.position == position) {
+            rows.push(
+              <MissingSong
+                key={ 'missing_' + index }
+                playlist={ uuid }
+                index={ index }
+                track={ track }
+                onResolved={ () => showPlaylist(uuid, name) }
+              />
+            )
+          }
+        })
+      };
+
+      (list as any[]).forEach((song, position) => {
+        addMissing(position);
+        rows.push(
+          <Song
+            key={ song.uuid }
+            location={ song.location }
+            playerLocation={ {"Playlist" : uuid } }
+            uuid={ song.uuid }
+            plays={ song.plays }
+            duration={ song.duration }
+            tags={ song.tags }
+          />
+        )
+      });
+      // Missing tracks at the end, or past it
+      missing
+        .map((track) => track.position)
+        .filter((position, i, all) => position >= (list as any[]).length && all.indexOf(position) == i)
+        .forEach(addMissing);
+      setLibrary(rows)
+    })
+    setViewName( name )
+    invoke('set_ui_state', { key: "library.view", value: { playlist: uuid, name: name } }).catch(console.error)
+  }
 
   useEffect(() => {
     const unlisten = appWindow.listen<any[]>("playlists_gotten", (_res) => {
@@ -120,28 +303,20 @@ function PlaylistHead({ playlists, setPlaylists, setViewName, setLibrary }: Play
         setPlaylists([
           ...res.map( (item) => {
             return (
-              <button onClick={ () => {
-                invoke('get_playlist', { uuid: item.uuid }).then((list) => {
-                setLibrary([...(list as any[]).map((song) => {
-                  // console.log(song);
-                  return (
-                    <Song
-                      key={ song.uuid }
-                      location={ song.location }
-                      playerLocation={ {"Playlist" : item.uuid } }
-                      uuid={ song.uuid }
-                      plays={ song.plays }
-                      duration={ song.duration }
-                      tags={ song.tags }
-                    />
-                  )
-                  })])
-                })
-                setViewName( item.name )
-              } } key={ 'playlist_' + item.uuid }>{ item.name }</button>
+              <button onClick={ () => showPlaylist(item.uuid, item.name) } key={ 'playlist_' + item.uuid }>{ item.name }</button>
             )
           })
         ])
+
+        if (!restored.current) {
+          restored.current = true
+          invoke<Record<string, any>>('get_ui_state').then((state) => {
+            const view = state["library.view"];
+            if (view?.playlist && res.some((item) => item.uuid == view.playlist)) {
+              showPlaylist(view.playlist, view.name)
+            }
+          })
+        }
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
@@ -151,27 +326,7 @@ function PlaylistHead({ playlists, setPlaylists, setViewName, setLibrary }: Play
 
       setPlaylists([
         ...playlists,
-        <button onClick={ () => {
-          invoke('get_playlist', { uuid: res.uuid }).then((list) => {
-            console.log((list as any[]).length);
-
-            setLibrary([...(list as any[]).map((song) => {
-              // console.log(song);
-              return (
-                <Song
-                  key={ song.uuid }
-                  location={ song.location }
-                  playerLocation={ {"Playlist" : res.uuid } }
-                  uuid={ song.uuid }
-                  plays={ song.plays }
-                  duration={ song.duration }
-                  tags={ song.tags }
-                />
-              )
-            })])
-          })
-          setViewName( res.name )
-        } } key={ 'playlist_' + res.uuid }>{ res.name }</button>
+        <button onClick={ () => showPlaylist(res.uuid, res.name) } key={ 'playlist_' + res.uuid }>{ res.name }</button>
       ])
       console.log(res.name);
     })
@@ -180,6 +335,7 @@ function PlaylistHead({ playlists, setPlaylists, setViewName, setLibrary }: Play
     <section className="playlistHead">
       <button onClick={() => {
         setViewName("Library");
+        invoke('set_ui_state', { key: "library.view", value: null }).catch(console.error)
         invoke('get_library').then((lib) => {
           setLibrary([...(lib as any[]).map((song) => {
             console.log(song);
@@ -237,6 +393,23 @@ function MainView({ lib_ref, viewName }: MainViewProps) {
     return () => { unlisten.then((f) => f()) }
   }, []);
 
+  useEffect(() => {
+    // Only the songs which changed are replaced
+    const unlisten = appWindow.listen<any[]>("songs_updated", ({ payload }) => {
+      const updated = new Map(payload.map((song) => [song.uuid, song]));
+      setLibrary((library) => library.map((element) => {
+        const song = updated.get(element.key);
+        return song ? React.cloneElement(element, {
+          location: song.location,
+          plays: song.plays,
+          duration: song.duration,
+          tags: song.tags,
+        }) : element
+      }))
+    })
+    return () => { unlisten.then((f) => f()) }
+  }, []);
+
 
   return (
     <div className="mainView">
@@ -259,13 +432,56 @@ interface SongProps {
   tags: any
 }
 
+// Explicit songs in filtered mode only play once the user confirms it
+function playNow(uuid: string, location: any, shuffleRemaining = false) {
+  invoke('play_now', { uuid: uuid, location: location, shuffleRemaining }).then((res: any) => {
+    if (res.NeedsConfirmation && confirm("This song is explicit. Play it anyway?")) {
+      invoke('play_now', { uuid: uuid, location: location, confirmed: true, shuffleRemaining }).then(() => {})
+    }
+  })
+}
+
+// The refreshed songs come back through `songs_updated`
+function refresh(command: string, args: object) {
+  invoke<[string, string][]>(command, args).then((failed) => {
+    for (const [uuid, error] of failed) {
+      console.error(`Couldn't refresh ${uuid}: ${error}`);
+    }
+  }).catch((e) => console.error(e));
+}
+
 function Song(props: SongProps) {
   // console.log(props.tags);
+  const [menu, setMenu] = useState<{ x: number, y: number } | undefined>(undefined);
+  const inPlaylist = typeof props.playerLocation === "object";
 
   return(
     <div onDoubleClick={() => {
-      invoke("play_now", { uuid: props.uuid, location: props.playerLocation }).then(() => {})
-    }} className="song">
+      playNow(props.uuid, props.playerLocation)
+    }}
+      onContextMenu={ (e) => { e.preventDefault(); setMenu({ x: e.clientX, y: e.clientY }) } }
+      onMouseLeave={ () => setMenu(undefined) }
+      className="song">
+      { menu &&
+        <div className="contextMenu" style={{ left: menu.x, top: menu.y }}>
+          { inPlaylist &&
+            <button onClick={ () => { setMenu(undefined); playNow(props.uuid, props.playerLocation, true) } }>
+              Shuffle from here
+            </button>
+          }
+          <button onClick={ () => { setMenu(undefined); refresh('refresh_songs', { uuids: [props.uuid] }) } }>
+            Refresh from file
+          </button>
+          { props.tags.AlbumTitle &&
+            <button onClick={ () => {
+              setMenu(undefined);
+              refresh('refresh_album', { key: { title: props.tags.AlbumTitle, artist: props.tags.AlbumArtist ?? null } })
+            } }>
+              Refresh album from files
+            </button>
+          }
+        </div>
+      }
       <p className="artist unselectable">{ props.tags.TrackArtist }</p>
       <p className="title  unselectable">{ props.tags.TrackTitle }</p>
       <p className="album  unselectable">{ props.tags.AlbumTitle }</p>
@@ -277,6 +493,51 @@ function Song(props: SongProps) {
   )
 }
 
+interface MissingSongProps {
+  playlist: string,
+  index: number,
+  track: MissingTrack,
+  onResolved: () => void,
+}
+
+// A playlist track whose file couldn't be found, which can be pointed at a
+// song in the library
+function MissingSong({ playlist, index, track, onResolved }: MissingSongProps) {
+  const [matches, setMatches] = useState<SearchCandidate[] | undefined>(undefined);
+
+  const findMatches = () => {
+    invoke('find_missing_track_matches', { playlist: playlist, index: index }).then((res) => {
+      setMatches(res as SearchCandidate[])
+    })
+  }
+  const resolve = (uuid: string) => {
+    invoke('resolve_missing_track', { playlist: playlist, index: index, to: { Song: uuid } })
+      .then(onResolved)
+  }
+
+  return (
+    <div className="missingSong" title={ track.original_path }>
+      <div onClick={ findMatches } className="song">
+        <p className="artist unselectable"></p>
+        <p className="title  unselectable">{ track.display }</p>
+        <p className="album  unselectable">Missing</p>
+        <p className="duration  unselectable"></p>
+      </div>
+      { matches && (
+        <div className="missingMatches">
+          { matches.length == 0 && <p>No songs in the library match</p> }
+          { matches.map((match) => (
+            <button key={ match.uuid } onClick={ () => resolve(match.uuid) }>
+              { [match.artist, match.title].filter((tag) => tag).join(" - ") }
+              { match.album && ` (${match.album})` }
+            </button>
+          )) }
+        </div>
+      ) }
+    </div>
+  )
+}
+
 interface PlayBarProps {
   playing: boolean,
   setPlaying: React.Dispatch<React.SetStateAction<boolean>>
@@ -285,34 +546,95 @@ interface PlayBarProps {
 function PlayBar({ playing, setPlaying }: PlayBarProps) {
   const [position, setPosition] = useState(0);
   const [duration, setDuration] = useState(0);
+  const [listened, setListened] = useState(0);
   const [seekBarSize, setSeekBarSize] = useState(0);
+  const [epoch, setEpoch] = useState(0);
+  const [profile, setProfile] = useState<ActiveProfile | undefined>(undefined);
+  const [output, setOutput] = useState<NegotiatedOutput>({ mode: "Shared" });
+  const [outputMessage, setOutputMessage] = useState<string | undefined>(undefined);
+  const [modes, setModes] = useState<PlaybackModes>({ shuffle: "Off", repeat: "Off" });
+  const [volume, setVolume] = useState(0);
+  const [maxVolume, setMaxVolume] = useState(100);
+  const [dragging, setDragging] = useState(false);
   const seekBarRef = React.createRef<HTMLDivElement>();
 
   useEffect(() => {
-    const unlisten = appWindow.listen<any>("playback_info", ({ payload, }) => {
-      const info = payload as playbackInfo;
-      const pos_ = Array.isArray(info.position) ? info.position![0] : 0;
-      const dur_ = Array.isArray(info.duration) ? info.duration![0] : 0;
+    invoke('get_player_state').then((state) => {
+      const playerState = state as PlayerState;
+      setModes(playerState.modes);
+      setVolume(playerState.volume);
+      setMaxVolume(playerState.max_volume);
+    });
+    const unlisten = appWindow.listen<PlaybackModes>("playback_modes_changed", ({ payload }) => {
+      setModes(payload);
+    })
+    return () => { unlisten.then((f) => f()) }
+  }, []);
+
+  const nextRepeat = { Off: "All", All: "One", One: "Off" } as const;
+
+  useEffect(() => {
+    // The position only ever comes with the song it's in, so the seekbar
+    // never shows one song's position against another's length
+    let revision = -1;
+    const unlisten = appWindow.listen<NowPlayingState>("now_playing", ({ payload }) => {
+      if (payload.revision < revision) return;
+      revision = payload.revision;
+      const pos_ = Math.floor((payload.position_ms ?? 0) / 1000);
+      const dur_ = Math.floor((payload.duration_ms ?? 0) / 1000);
 
       setPosition(pos_);
       setDuration(dur_);
+      setEpoch(payload.epoch);
       let progress = ((dur_/pos_) * 100);
       setSeekBarSize(progress)
     })
     return () => { unlisten.then((f) => f()) }
   }, []);
 
-  const seek = (event: React.MouseEvent<HTMLDivElement>) => {
-    event.stopPropagation();
+  useEffect(() => {
+    const unlisten = appWindow.listen<playbackInfo>("playback_info", ({ payload }) => {
+      setListened(payload.listened[0]);
+      setProfile(payload.profile ?? undefined);
+      setOutput(payload.output);
+    })
+    return () => { unlisten.then((f) => f()) }
+  }, []);
+
+  // The time in milliseconds under the mouse on the seekbar
+  const seekTime = (clientX: number) => {
     let rect = seekBarRef.current!.getBoundingClientRect();
-    let val = ((event.clientX-rect.left) / (rect.width))*position;
+    let fraction = Math.min(Math.max((clientX - rect.left) / rect.width, 0), 1);
+    return Math.round(fraction * duration * 1000);
+  };
 
-    invoke('seek', { time: Math.round(val * 1000) }).then()
+  const startSeek = (event: React.MouseEvent<HTMLDivElement>) => {
+    event.stopPropagation();
+    setDragging(true);
+    invoke('seek_preview', { time: seekTime(event.clientX), epoch: epoch }).then()
   };
 
+  // Only the position is moved while dragging, the player seeks once on release
+  useEffect(() => {
+    if (!dragging) return;
+    const move = (event: MouseEvent) => {
+      invoke('seek_preview', { time: seekTime(event.clientX), epoch: epoch }).then()
+    };
+    const release = (event: MouseEvent) => {
+      setDragging(false);
+      invoke('seek', { time: seekTime(event.clientX), epoch: epoch }).then()
+    };
+    window.addEventListener('mousemove', move);
+    window.addEventListener('mouseup', release);
+    return () => {
+      window.removeEventListener('mousemove', move);
+      window.removeEventListener('mouseup', release);
+    }
+  }, [dragging, epoch, duration]);
+
   return (
     <section id="playBar" className="playBar unselectable">
-      <div className="seekBar" ref={ seekBarRef } onClick={ seek } onDrag={ seek }>
+      <div className="seekBar" ref={ seekBarRef } onMouseDown={ startSeek }>
         <div className="seekOverlay" id="seekOverlay" style={{ width: seekBarSize + '%' } }></div>
       </div>
       <div className="bottomSpaced">
@@ -326,12 +648,34 @@ function PlayBar({ playing, setPlaying }: PlayBarProps) {
           <button onClick={ () => invoke('next').then(() => {}) }>⏭</button>
         </div>
         <div className="bottomRight">
-          <button>🔀</button>
-          <button>🔁</button>
-          <input type="range" name="volume" id="volumeSlider" onChange={ (volume) => {
+          <button className={ modes.shuffle == "On" ? "modeOn" : "" } onClick={ () => {
+            invoke('set_playback_modes', { ...modes, shuffle: modes.shuffle == "On" ? "Off" : "On" }).then(() => {})
+          }}>🔀</button>
+          <button className={ modes.repeat != "Off" ? "modeOn" : "" } onClick={ () => {
+            invoke('set_playback_modes', { ...modes, repeat: nextRepeat[modes.repeat] }).then(() => {})
+          }}>{ modes.repeat == "One" ? '🔂' : '🔁' }</button>
+          { profile &&
+            <span className="modeOn" title={
+              `ReplayGain: ${profile.settings.replay_gain_mode}, preamp ${profile.settings.preamp} dB, ` +
+              `crossfade ${profile.settings.crossfade}ms`
+            }>{ profile.source == "Library" ? "Library profile" : "Playlist profile" }</span>
+          }
+          <button className={ output.mode == "Exclusive" ? "modeOn" : "" } title={ outputMessage } onClick={ () => {
+            invoke<OutputSwitch>('set_output_mode', { mode: output.mode == "Exclusive" ? "Shared" : "Exclusive" })
+              .then((res) => { setOutput(res.output); setOutputMessage(res.fallback ?? undefined) })
+              .catch((e) => setOutputMessage(`${e}`))
+          }}>{ output.mode }{ output.sample_rate ? ` ${output.sample_rate / 1000} kHz` : "" }{ outputMessage ? " ⚠" : "" }</button>
+          <input type="range" name="volume" id="volumeSlider" value={ volume } max={ maxVolume } onChange={ (volume) => {
+            setVolume(+volume.target.value);
             invoke('set_volume', { volume: volume.target.value }).then(() => {})
+          }} onWheel={ (event) => {
+            invoke('volume_step', { deltaSteps: event.deltaY < 0 ? 1 : -1 })
+              .then((volume) => setVolume(volume as number))
           }} />
-          <p id="timeDisplay">
+          <p id="timeDisplay" title={
+            `Listened ${Math.floor(listened / 60)}:${(listened % 60).toString().padStart(2, "0")} ` +
+            `of ${Math.floor(duration / 60)}:${(duration % 60).toString().padStart(2, "0")}`
+          }>
             { Math.floor(+duration / 60).toString().padStart(2, "0") }:
             { (+duration % 60).toString().padStart(2, "0") }/
             { Math.floor(+position / 60).toString().padStart(2, "0") }:
@@ -348,30 +692,124 @@ interface NowPlayingProps {
   title: string,
   artist: string,
   album: string,
-  artwork: JSX.Element
+  artwork: JSX.Element,
+  position?: QueuePosition,
+  reason?: TrackChangeReason,
 }
 
-function NowPlaying({ title, artist, album, artwork }: NowPlayingProps) {
+// How many songs "More by this artist" adds at a time
+const MORE_BY_ARTIST_COUNT = 10;
+
+function NowPlaying({ title, artist, album, artwork, position, reason }: NowPlayingProps) {
+  const [menu, setMenu] = useState<{ x: number, y: number } | undefined>(undefined);
+
+  const enqueue = (command: string, args = {}) => {
+    setMenu(undefined);
+    invoke(command, args).catch((e) => console.error(e));
+  };
+
   return (
-    <section className="nowPlaying">
+    <section
+      className={ reason ? `nowPlaying ${reason}` : "nowPlaying" }
+      onContextMenu={ (e) => { e.preventDefault(); setMenu({ x: e.clientX, y: e.clientY }) } }
+      onMouseLeave={ () => setMenu(undefined) }
+    >
       <div className="artworkWrapper unselectable">
         { artwork }
       </div>
       <h3>{ title }</h3>
       <p>{ artist }</p>
       <p>{ album }</p>
+      { position && <p>Track { position.index + 1 } of { position.total }</p> }
+      { menu &&
+        <div className="contextMenu" style={{ left: menu.x, top: menu.y }}>
+          <button onClick={ () => enqueue('enqueue_rest_of_album') }>Queue rest of album</button>
+          <button onClick={ () => enqueue('enqueue_more_by_artist', { count: MORE_BY_ARTIST_COUNT }) }>
+            Queue more by this artist
+          </button>
+          <button onClick={ () => enqueue('queue_add_from_filter', {
+            query: artist, targetTags: ["Artist"], sort: ["Album", "Disk", "Track"], mode: "Append"
+          }) }>
+            Queue everything by this artist
+          </button>
+        </div>
+      }
     </section>
   )
 }
 
+/** How many played songs are shown above the queue */
+const QUEUE_HISTORY = 20;
+
+/** The queue after `ops`, keeping the played songs before the upcoming ones */
+function applyQueueOps(queue: QueueEntry[], ops: QueueOp[]): QueueEntry[] {
+  const played = queue.filter((song) => song[2]);
+  const upcoming = queue.filter((song) => !song[2]);
+  for (const op of ops) {
+    switch (op.op) {
+      case "Inserted": upcoming.splice(op.index, 0, op.song); break
+      case "Removed": upcoming.splice(op.index, 1); break
+      case "Moved": upcoming.splice(op.to, 0, ...upcoming.splice(op.from, 1)); break
+      case "StateChanged":
+        upcoming[op.index] = [upcoming[op.index][0], upcoming[op.index][1], false, op.transition]
+        break
+      case "Played":
+        played.push(...upcoming.splice(0, op.count).map((song): QueueEntry => [song[0], song[1], true, song[3]]))
+        break
+      case "Unplayed":
+        upcoming.unshift(...played.splice(played.length - op.count).map((song): QueueEntry => [song[0], song[1], false, song[3]]))
+        break
+      case "Cleared": upcoming.length = 0; break
+    }
+  }
+  return played.slice(-QUEUE_HISTORY).concat(upcoming);
+}
+
 interface QueueProps {
-  songs: JSX.Element[],
+  songs: QueueEntry[],
+  info?: QueueInfo,
+  // The revision the songs are from, so clicks on them are only acted on
+  // if the queue hasn't moved on since
+  revision?: number,
 }
 
-function Queue({ songs }: QueueProps) {
+/** Seconds as "2 hr 14 min" */
+function formatRemaining(secs: number) {
+  const hours = Math.floor(secs / 3600);
+  const minutes = Math.floor(secs % 3600 / 60);
+  return hours > 0 ? `${hours} hr ${minutes} min` : `${minutes} min`;
+}
+
+function Queue({ songs, info, revision }: QueueProps) {
+  const played = songs.filter((song) => song[2]);
+  const upcoming = songs.filter((song) => !song[2]);
   return (
     <section className="Queue">
-      { songs }
+      <button className="queueShuffleButton" onClick={ () => invoke('shuffle_queue').then(() => {}) }>Shuffle Queue</button>
+      { info && info.total > 0 &&
+        <p className="queueRemaining" title={ `${formatRemaining(info.remaining)} queued` }>
+          { formatRemaining(info.total) } remaining
+        </p>
+      }
+      { played.map((song, i) =>
+        <QueueSong
+          song={ song[0] }
+          location={ song[1] }
+          index={ played.length - i }
+          played
+          key={ 'played_' + i + '_' + song[0].uuid }
+        />
+      ) }
+      { upcoming.slice(1).map((song, i) =>
+        <QueueSong
+          song={ song[0] }
+          location={ song[1] }
+          index={ i + 1 }
+          transition={ song[3] }
+          revision={ revision }
+          key={ 'upcoming_' + i + '_' + song[0].uuid }
+        />
+      ) }
     </section>
   )
 }
@@ -379,35 +817,56 @@ function Queue({ songs }: QueueProps) {
 interface QueueSongProps {
   song: any,
   location: "Library" | {"Playlist": string},
+  // For played songs, how many steps back in the history they are
   index: number,
+  played?: boolean,
+  // The item's own transition into it, instead of the profile's crossfade
+  transition?: Transition | null,
+  revision?: number,
 }
 
-function QueueSong({ song, location, index }: QueueSongProps) {
+function transitionLabel(transition: Transition): string {
+  switch (transition.kind) {
+    case "Crossfade": return `Fade ${transition.ms / 1000}s`
+    default: return transition.kind
+  }
+}
+
+function QueueSong({ song, location, index, played, transition, revision }: QueueSongProps) {
   // console.log(song.tags);
 
   let removeFromQueue = () => {
-    invoke('remove_from_queue', { index: index }).then(() => {})
+    if (played) { return }
+    // A conflict means the queue changed under the click, and the
+    // queue_updated event for that change shows the queue as it is now
+    invoke('remove_from_queue', { index: index, expectedRevision: revision })
+      .catch((e: QueueEditError) => console.log(e))
   }
 
   let playNow = () => {
-    invoke('play_now', { uuid: song.uuid, location: location }).then(() => {})
+    if (played) {
+      invoke('play_played', { steps: index }).then(() => {})
+    } else {
+      playNow(song.uuid, location)
+    }
   }
 
   return (
-    <div className="queueSong unselectable"  onAuxClickCapture={ removeFromQueue } onDoubleClickCapture={ playNow }>
+    <div className={ "queueSong unselectable" + (played ? " played" : "") } onAuxClickCapture={ removeFromQueue } onDoubleClickCapture={ playNow }>
       <img className="queueSongCoverArt" src={ convertFileSrc('abc') + '?' + song.uuid } key={ 'coverArt_' + song.uuid }/>
       <div className="queueSongTags">
         <p className="queueSongTitle">{ song.tags.TrackTitle }</p>
         <p className="queueSongArtist">{ song.tags.TrackArtist }</p>
       </div>
+      { transition && <span className="queueSongTransition">{ transitionLabel(transition) }</span> }
     </div>
   )
 }
 
 function getConfig(): any {
-  invoke('get_config').then( (_config) => {
-    let config = _config as Config;
-    if (config.libraries.libraries.length == 0) {
+  invoke('get_config').then( (_libraries) => {
+    let libraries = _libraries as ConfigLibraries;
+    if (libraries.libraries.length == 0) {
       invoke('create_new_library').then(() => {})
     } else {
       // console.log("else");
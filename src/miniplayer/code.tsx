@@ -0,0 +1,42 @@
+import { convertFileSrc, invoke } from "@tauri-apps/api/core";
+import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
+import { useEffect, useState } from "react";
+import ReactDOM from "react-dom/client";
+
+const appWindow = getCurrentWebviewWindow();
+
+ReactDOM.createRoot(document.getElementById("root") as HTMLElement).render(
+    <App />,
+);
+
+function App() {
+    const [song, setSong] = useState<any>(null);
+    const [playing, setPlaying] = useState(false);
+
+    useEffect(() => {
+        const unlisten = [
+            appWindow.listen<any>("now_playing_change", ({ payload }) => setSong(payload)),
+            appWindow.listen<any>("playing", () => setPlaying(true)),
+            appWindow.listen<any>("paused", () => setPlaying(false)),
+            appWindow.listen<any>("stop", () => setPlaying(false)),
+        ];
+        return () => { unlisten.forEach((u) => u.then((f) => f())) }
+    }, []);
+
+    return (
+        <div style={{ display: "flex", gap: "8px" }}>
+            <img
+                src={ convertFileSrc("abc") + "?" + (song ? song.uuid : "default") }
+                alt="Now Playing Artwork"
+                style={{ width: "80px", height: "80px" }}
+            />
+            <div>
+                <p>{ song ? song.tags.TrackTitle : "" }</p>
+                <p>{ song ? song.tags.TrackArtist : "" }</p>
+                <button onClick={ () => invoke('prev').then(() => {}) }>⏮</button>
+                <button onClick={ () => invoke(playing ? 'pause' : 'play').then(() => {}) }>{ playing ? '⏸' : '⏵' }</button>
+                <button onClick={ () => invoke('next').then(() => {}) }>⏭</button>
+            </div>
+        </div>
+    )
+}
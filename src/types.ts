@@ -2,7 +2,18 @@ export interface Configlibrary {
     name: string,
     path: string,
     uuid: string,
-    scan_folders?: string[]
+    scan_folders?: string[],
+    exclusions: ScanExclusions,
+    // Like `{artist} - {title}`, fills in scanned songs with no title or artist
+    filename_pattern?: string,
+}
+
+/** Files left out when scanning a library's folders */
+export interface ScanExclusions {
+    // Glob patterns like `**/Audiobooks/**`
+    patterns: string[],
+    min_duration_secs?: number,
+    genres: string[],
 }
 
 export interface ConfigLibraries {
@@ -20,7 +31,16 @@ export interface Config {
 }
 
 export interface ConfigConnections {
-    listenbrainz_token?: string
+    listenbrainz_token?: string,
+    lastfm_api_key?: string,
+    lastfm_api_secret?: string,
+}
+
+export interface ConnectionStatus {
+    listenbrainz_configured: boolean,
+    listenbrainz_active: boolean,
+    lastfm_configured: boolean,
+    discord_active: boolean,
 }
 
 export interface Song {
@@ -42,6 +62,17 @@ export interface Song {
     internal_tags: InternalTag[],
 }
 
+/** The fields a row of the song list shows, which `get_songs` sends
+ *  unless asked for the whole song */
+export interface SongRef {
+    uuid: string,
+    title: string | null,
+    artist: string | null,
+    album: string | null,
+    // In seconds
+    duration: number,
+}
+
 export enum InternalTag {
 
 }
@@ -65,4 +96,261 @@ export enum BannedType {
 export interface playbackInfo {
     position?: [number, number],
     duration?: [number, number],
-}
\ No newline at end of file
+    epoch: number,
+    profile?: ActiveProfile,
+    gain?: SongGain,
+    queue_position?: QueuePosition,
+    output: NegotiatedOutput,
+    /** Seconds and nanoseconds of the playing song listened to */
+    listened: [number, number],
+}
+
+export type OutputMode = "Shared" | "Exclusive";
+
+export interface NegotiatedOutput {
+    mode: OutputMode,
+    sample_rate?: number,
+}
+
+export interface OutputSwitch {
+    output: NegotiatedOutput,
+    fallback?: string,
+}
+
+export interface QueuePosition {
+    index: number,
+    total: number,
+    location: "Library" | "Album" | "File" | "Custom" | "Test" | { Playlist: string },
+}
+
+/** Everything about what's playing, sent as `now_playing` whenever any of it
+ * changes */
+export interface NowPlaying {
+    revision: number,
+    song: SongRef | null,
+    stream_title: { raw: string, artist: string | null, title: string } | null,
+    source: QueuePosition["location"] | null,
+    /** The name of the playlist the song is played from */
+    source_name: string | null,
+    state: "Playing" | "Paused" | "Stopped",
+    position_ms: number | null,
+    duration_ms: number | null,
+    epoch: number,
+    sample_rate: number | null,
+    modes: PlaybackModes,
+    /** The uuid to fetch the song's art with, if it has any */
+    art: string | null,
+    queue_position: QueuePosition | null,
+    /** Why the song started playing */
+    reason: TrackChangeReason | null,
+}
+
+/** Why a different song started playing */
+export type TrackChangeReason = "Natural" | "UserNext" | "UserPrev" | "UserJump" | "PlayNow" | "Recovered";
+
+/** How long the queue takes to play, in seconds */
+export interface QueueInfo {
+    remaining: number,
+    total: number,
+    elapsed: number,
+}
+
+/** A song in the queue, where it's played from, whether it was played,
+ *  and its own transition */
+export type QueueEntry = [any, "Library" | {"Playlist": string}, boolean, Transition | null];
+
+export interface QueuePayload {
+    songs: QueueEntry[],
+    info: QueueInfo,
+    revision: number,
+}
+
+/** Why a change to the queue wasn't made. On a conflict, the queue had
+ *  changed since `expected_revision`, and this is the queue now. */
+export type QueueEditError =
+    | { Conflict: { revision: number, songs: QueueEntry[] } }
+    | { Failed: string };
+
+/** A change to the upcoming songs, where index 0 is the current one */
+export type QueueOp =
+    | { op: "Inserted", index: number, song: QueueEntry }
+    | { op: "Removed", index: number }
+    | { op: "Moved", from: number, to: number }
+    | { op: "StateChanged", index: number, by_human: boolean, transition: Transition | null }
+    | { op: "Played", count: number }
+    | { op: "Unplayed", count: number }
+    | { op: "Cleared" };
+
+/** Sent when the queue changes. Without `ops`, the whole queue has to be
+ *  fetched again. */
+export interface QueueUpdated extends QueueInfo {
+    ops: QueueOp[] | null,
+    since: number,
+    revision: number,
+}
+
+/** How the song before a queue item goes into it */
+export type Transition =
+    | { kind: "Gapless" }
+    | { kind: "Cut" }
+    | { kind: "Crossfade", ms: number }
+
+export interface ActiveProfile {
+    source: "Library" | { Playlist: string },
+    settings: {
+        replay_gain_mode: "Off" | "Track" | "Album",
+        preamp: number,
+        crossfade: number,
+    },
+}
+
+/** How much the playing song's volume is changed */
+export interface SongGain {
+    db: number,
+    factor: number,
+    // The song would have clipped at `db`, so it's turned up less
+    peak_limited: boolean,
+}
+
+export interface PlaybackModes {
+    shuffle: "Off" | "On",
+    repeat: "Off" | "All" | "One",
+}
+
+export interface PlayerState {
+    // From 0 to 100, like the volume slider
+    volume: number,
+    // The slider's max, below 100 when the volume is limited
+    max_volume: number,
+    muted: boolean,
+    modes: PlaybackModes,
+    now_playing?: Song,
+}
+
+/** A playlist's songs which are no longer in the library */
+export interface DanglingTracks {
+    playlist: string,
+    title: string,
+    uuids: string[],
+}
+
+export interface FileProblem {
+    uuid: string,
+    path: string,
+    message: string,
+}
+
+/** What checking the library's files for damage found */
+export interface VerifyReport {
+    hashed: number,
+    unchanged: number,
+    // Files changed on purpose, like by editing their tags
+    retagged: [string, string][],
+    corrupt: FileProblem[],
+    failed: FileProblem[],
+    skipped: number,
+}
+
+export type SongProblem = "ZeroDuration" | "ImplausibleDuration" | "Silent";
+
+/** A song marked as silent or broken, kept out of automatic queueing */
+export interface FlaggedSong {
+    uuid: string,
+    title: string | null,
+    path: string,
+    problem: SongProblem,
+}
+
+/** A song kept in more than one place, like copies under scan folders of
+ * different priorities */
+export interface MultiLocationSong {
+    uuid: string,
+    title: string | null,
+    // In the order they're tried when playing
+    locations: URI[],
+}
+
+/** What checking songs for silence and broken lengths found */
+export interface ProblemReport {
+    checked: number,
+    flagged: FlaggedSong[],
+    // Songs which were marked before and are fine now
+    cleared: number,
+    failed: [string, string][],
+}
+
+/** What the album grid shows for an album */
+export interface AlbumSummary {
+    key: { title: string, artist: string | null },
+    duration_ms: number,
+    tracks: number,
+    // Keyed by disc number
+    disc_tracks: Record<string, number>,
+    year: number | null,
+    // The song whose art to show
+    art: string | null,
+}
+
+/** A suggestion for picking up where listening was left off */
+export interface ContinueEntry {
+    kind: "Album" | "Playlist" | "Song" | "Recent",
+    name: string,
+    // The song whose art to show
+    art: string,
+    // The song to play, and where in it to start
+    resume: string,
+    position: { secs: number, nanos: number },
+    location: "Library" | "Album" | { Playlist: string },
+    last_played?: number,
+}
+
+/** The songs first played, or played at least three times, on one day in
+ * an earlier year */
+export interface OnThisDayYear {
+    year: number,
+    songs: {
+        song: SongRef,
+        // How many times it was played that day
+        plays: number,
+        first_played: boolean,
+    }[],
+}
+
+/** A playlist track whose file couldn't be found when it was imported */
+export interface MissingTrack {
+    // How many of the playlist's tracks come before it
+    position: number,
+    original_path: string,
+    display: string,
+}
+
+/** A song which might be the one searched for */
+export interface SearchCandidate {
+    uuid: string,
+    title: string | null,
+    artist: string | null,
+    album: string | null,
+    // From 0 to 1
+    score: number,
+}
+
+export type SettingsSection = "Playback" | "Library" | "Connections" | "Interface"
+
+/** What secrets which are set are shown as. Sending it back keeps them. */
+export const MASKED = "********"
+
+export interface ConnectionsSettings {
+    section: "Connections",
+    listenbrainz_token?: string,
+    lastfm_api_key?: string,
+    lastfm_api_secret?: string,
+    disable_listen_counts: boolean,
+    scrobble_radio: boolean,
+    web_remote: { enabled: boolean, port: number },
+}
+
+export interface SettingsUpdate<T> {
+    settings: T,
+    // Some of the changes only apply after restarting
+    needs_restart: boolean,
+}
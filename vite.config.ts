@@ -12,6 +12,15 @@ export default defineConfig(async () => ({
   //
   // 1. prevent vite from obscuring rust errors
   clearScreen: false,
+  // Every window gets its own page
+  build: {
+    rollupOptions: {
+      input: {
+        main: "index.html",
+        miniplayer: "src/miniplayer/index.html",
+      },
+    },
+  },
   // 2. tauri expects a fixed port, fail if that port is not available
   server: {
     port: 1420,